@@ -0,0 +1,108 @@
+//! Compares the I/O engines on one large data file: appending it as an upload does, and
+//! reading it back with several concurrent readers as the readers of a popular version
+//! do. The file is 1 GiB unless `STREAM_DB_BENCH_BYTES` says otherwise, e.g.
+//!
+//! ```bash
+//! cargo bench --features blocking-io --bench io_engines
+//! STREAM_DB_BENCH_BYTES=67108864 cargo bench --features blocking-io --bench io_engines
+//! ```
+//!
+//! Without the `blocking-io` feature only the default `tokio` engine is measured.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use stream_db::persistence::io_engine::IoEngine;
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Bytes handed to the appender at once, about what an upload's body arrives in
+const APPEND_CHUNK_SIZE: usize = 64 * 1024;
+const CONCURRENT_READERS: usize = 4;
+
+fn engines() -> Vec<(&'static str, IoEngine)> {
+    vec![
+        ("tokio", IoEngine::Tokio),
+        #[cfg(feature = "blocking-io")]
+        ("blocking", IoEngine::Blocking),
+    ]
+}
+
+fn file_size() -> u64 {
+    std::env::var("STREAM_DB_BENCH_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(1024 * 1024 * 1024)
+}
+
+fn bench_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("stream-db-bench-{name}-{}", std::process::id()))
+}
+
+/// Append `size` bytes to a new file and sync it, as an upload and its commit do
+async fn write_file(engine: IoEngine, path: &PathBuf, size: u64) {
+    let mut appender = engine.appender(File::create(path).unwrap());
+    let mut written = 0;
+    while written < size {
+        let length = APPEND_CHUNK_SIZE.min((size - written) as usize);
+        appender.append(vec![b'x'; length]).await.unwrap();
+        written += length as u64;
+    }
+    appender.sync().await.unwrap();
+}
+
+fn write(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let size = file_size();
+    let mut group = criterion.benchmark_group("write");
+    group.sample_size(10).throughput(Throughput::Bytes(size));
+    for (name, engine) in engines() {
+        let path = bench_path(&format!("write-{name}"));
+        group.bench_function(BenchmarkId::from_parameter(name), |bencher| {
+            bencher
+                .to_async(&runtime)
+                .iter(|| write_file(engine, &path, size));
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+    group.finish();
+}
+
+fn concurrent_read(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let size = file_size();
+    let path = bench_path("read");
+    runtime.block_on(write_file(IoEngine::Tokio, &path, size));
+    let mut group = criterion.benchmark_group("concurrent_read");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(size * CONCURRENT_READERS as u64));
+    for (name, engine) in engines() {
+        group.bench_function(BenchmarkId::from_parameter(name), |bencher| {
+            bencher.to_async(&runtime).iter(|| async {
+                // Readers of one version share its reader, as they share its SharedFile
+                let reader = Arc::new(engine.reader(File::open(&path).unwrap()));
+                let readers = (0..CONCURRENT_READERS).map(|_| {
+                    let reader = reader.clone();
+                    tokio::spawn(async move {
+                        let mut buffer = vec![0u8; engine.read_chunk_size()];
+                        let mut offset = 0;
+                        while offset < size {
+                            let bytes_read = reader.read_at(offset, &mut buffer).await.unwrap();
+                            assert!(bytes_read > 0, "the file ended early");
+                            offset += bytes_read as u64;
+                        }
+                    })
+                });
+                for reader in readers.collect::<Vec<_>>() {
+                    reader.await.unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, write, concurrent_read);
+criterion_main!(benches);
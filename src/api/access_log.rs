@@ -0,0 +1,317 @@
+use crate::component::item_stream_component;
+use crate::logic::s3_objects;
+use crate::persistence::audit_log::AuditRecord;
+use crate::state::AppState;
+
+use super::read_item_stream_api::SERVED_FROM_HEADER;
+use super::request_id::{REQUEST_ID_HEADER, request_id};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{FromRequestParts, RawPathParams, Request, State},
+    http::{HeaderValue, Method, header},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+
+/// How a request's transfer ended
+#[derive(Clone, Copy, PartialEq)]
+enum Termination {
+    /// The response body was handed out to its end
+    Completed,
+    /// The client went away before the request or the response body ended
+    ClientAborted,
+    /// The response body failed half way, e.g. a read that broke off
+    Errored,
+}
+
+impl Termination {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::ClientAborted => "client_aborted",
+            Self::Errored => "errored",
+        }
+    }
+}
+
+/// What the request body handed to the handler, counted as it passes
+#[derive(Default)]
+struct Received {
+    bytes: AtomicU64,
+    /// Reading the body failed, which only happens when the client breaks the
+    /// connection or the framing off
+    aborted: AtomicBool,
+}
+
+/// One request, recorded once its response body ends
+struct Transfer {
+    state: AppState,
+    started: Instant,
+    method: Method,
+    path: String,
+    item_id: Option<String>,
+    version: Option<String>,
+    request_id: String,
+    status: u16,
+    /// Read source of a read, see `X-Served-From`
+    served_from: Option<String>,
+    received: Arc<Received>,
+}
+
+/// Log every request once its response body ended, however it ended, with the bytes
+/// that went each way and how long it took, and append the requests that change data
+/// to the audit log. The bodies are counted as they stream through, without being
+/// copied. Requests without an `X-Request-Id` are given one here, which the handlers
+/// find and echo.
+pub async fn log_transfers(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let (mut parts, body) = request.into_parts();
+    let request_id = request_id(&parts.headers);
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        parts.headers.insert(REQUEST_ID_HEADER, value);
+    }
+    let (item_id, version) = RawPathParams::from_request_parts(&mut parts, &())
+        .await
+        .map(|params| item_version(&params))
+        .unwrap_or_default();
+    let method = parts.method.clone();
+    let path = parts.uri.path().to_string();
+
+    let received = Arc::new(Received::default());
+    let body = Body::new(CountingBody {
+        inner: body,
+        received: received.clone(),
+    });
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let transfer = Transfer {
+        state,
+        started,
+        method,
+        path,
+        item_id,
+        version,
+        request_id,
+        status: response.status().as_u16(),
+        served_from: response
+            .headers()
+            .get(SERVED_FROM_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        received,
+    };
+    // Streamed bodies of a known length announce it in the header only
+    let announced = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    response.map(|body| {
+        let expected = body.size_hint().exact().or(announced);
+        Body::new(LoggedBody {
+            inner: body,
+            transfer: Some(transfer),
+            sent: 0,
+            expected,
+        })
+    })
+}
+
+/// The item and version a route addresses, from its path parameters. The S3 API's
+/// object keys name items as well.
+fn item_version(params: &RawPathParams) -> (Option<String>, Option<String>) {
+    let mut item_id = None;
+    let mut version = None;
+    let mut bucket = None;
+    let mut key = None;
+    for (name, value) in params {
+        match name {
+            "item_id" => item_id = Some(value.to_string()),
+            "version" => version = Some(value.to_string()),
+            "bucket" => bucket = Some(value),
+            "key" => key = Some(value),
+            _ => (),
+        }
+    }
+    if let (None, Some(bucket), Some(key)) = (&item_id, bucket, key) {
+        item_id = s3_objects::item_id(bucket, key).ok();
+    }
+    (item_id, version)
+}
+
+impl Transfer {
+    fn record(self, bytes_out: u64, termination: Termination) {
+        let duration_ms = self.started.elapsed().as_millis() as u64;
+        let bytes_in = self.received.bytes.load(Ordering::Relaxed);
+        let termination = if self.received.aborted.load(Ordering::Relaxed) {
+            Termination::ClientAborted
+        } else {
+            termination
+        };
+        let state = self.state;
+        state
+            .metrics
+            .transfer_duration_milliseconds
+            .observe(duration_ms);
+        state.metrics.transfer_received_bytes.observe(bytes_in);
+        state.metrics.transfer_sent_bytes.observe(bytes_out);
+
+        let record = AuditRecord {
+            at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            request_id: self.request_id,
+            method: self.method.to_string(),
+            path: self.path,
+            item_id: self.item_id,
+            version: self.version,
+            status: self.status,
+            bytes_in,
+            bytes_out,
+            duration_ms,
+            termination: termination.name(),
+        };
+        if state.config.live().access_log {
+            println!(
+                "access method={} path={} item_id={} version={} status={} bytes_in={} bytes_out={} duration_ms={} termination={} served_from={} request_id={}",
+                record.method,
+                record.path,
+                record.item_id.as_deref().unwrap_or("-"),
+                record.version.as_deref().unwrap_or("-"),
+                record.status,
+                record.bytes_in,
+                record.bytes_out,
+                record.duration_ms,
+                record.termination,
+                self.served_from.as_deref().unwrap_or("-"),
+                record.request_id,
+            );
+        }
+        let mutating = !matches!(self.method, Method::GET | Method::HEAD | Method::OPTIONS);
+        if mutating && item_stream_component::is_audited(&state) {
+            tokio::task::spawn_blocking(move || {
+                if let Err(error) = item_stream_component::append_audit_record(&state, &record) {
+                    println!(
+                        "Could not audit {} {} (request {}): {error}",
+                        record.method, record.path, record.request_id
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Request body counting the bytes the handler reads
+struct CountingBody {
+    inner: Body,
+    received: Arc<Received>,
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(context));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.received
+                        .bytes
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+            }
+            Some(Err(_)) => self.received.aborted.store(true, Ordering::Relaxed),
+            None => (),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Response body counting the bytes handed to the client, recording the transfer when
+/// it ends, fails, or is dropped because the client went away
+struct LoggedBody {
+    inner: Body,
+    /// Taken when the transfer is recorded
+    transfer: Option<Transfer>,
+    sent: u64,
+    /// Length of a body of known size, which the server stops polling once it was sent
+    expected: Option<u64>,
+}
+
+impl LoggedBody {
+    fn finish(&mut self, termination: Termination) {
+        if let Some(transfer) = self.transfer.take() {
+            transfer.record(self.sent, termination);
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(context));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.sent += data.len() as u64;
+                }
+                if self.inner.is_end_stream() {
+                    self.finish(Termination::Completed);
+                }
+            }
+            Some(Err(_)) => self.finish(Termination::Errored),
+            None => self.finish(Termination::Completed),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // Empty bodies and those of HEAD responses are never polled
+        let sent_all = self.inner.is_end_stream()
+            || self.expected.is_some_and(|expected| self.sent >= expected)
+            || self
+                .transfer
+                .as_ref()
+                .is_some_and(|transfer| transfer.method == Method::HEAD);
+        self.finish(if sent_all {
+            Termination::Completed
+        } else {
+            Termination::ClientAborted
+        });
+    }
+}
@@ -0,0 +1,760 @@
+use crate::component::item_stream_component;
+use crate::config::Config;
+use crate::logic::bulk_delete::BulkDeleteFilter;
+use crate::logic::consistency::constant_time_eq;
+use crate::logic::metadata_backfill::BackfillError;
+use crate::logic::property_transform::TransformError;
+use crate::logic::space_report::{self, GroupBy, SpaceReportOptions};
+use crate::persistence::cold_tier::StorageTier;
+use crate::persistence::fault_injection::FaultRule;
+use crate::persistence::file_persistence::{ResetVersionError, VersionState};
+use crate::persistence::transforms::TransformSpec;
+use crate::state::AppState;
+use crate::types::dto::{
+    BlockingVersionsDetails, DebugCaptureList, DebugCapturePatterns, DebugItemsRequest,
+    LogLevelRequest, StorageReport, TransformDeleted, TransformSaved, VersionConflictDetails,
+};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::search_api::search_error;
+use super::write_item_stream_api::write_error;
+
+use axum::{
+    Json,
+    body::Body,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+
+/// Body of `PUT /admin/usage`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageQuotaUpdate {
+    /// `null` removes the quota
+    pub quota_bytes: Option<u64>,
+}
+
+/// Query parameters of `GET /admin/space-report`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpaceReportQuery {
+    /// `item` (default) or `namespace`
+    pub group_by: Option<String>,
+    /// Largest groups reported
+    pub top: Option<usize>,
+    /// Smallest discrepancy flagged
+    pub threshold_bytes: Option<u64>,
+}
+
+/// Body of `POST /admin/items/{item_id}/reset-version`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResetVersionRequest {
+    /// The item's latest version is lowered to at most this
+    pub version: u64,
+    /// Delete the versions above `version` that still have data
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Body of `POST /admin/hooks/test`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookTestRequest {
+    pub hook: String,
+    pub item_id: String,
+    /// A committed version of the item
+    pub version: u64,
+}
+
+/// Query parameters of `GET /admin/debug-captures/{request_id}`
+#[derive(Deserialize, Default)]
+pub struct DebugCaptureQuery {
+    /// `raw` (the default) for the body as it arrived, `trace` for the splitter's decisions
+    pub artifact: Option<String>,
+}
+
+/// Admin endpoints require `Authorization: Bearer <STREAM_DB_ADMIN_TOKEN>` and are
+/// refused altogether when no token is configured
+pub fn authorize_admin(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = config.admin_token.as_deref() else {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Admin API is disabled, set STREAM_DB_ADMIN_TOKEN to enable it",
+        ));
+    };
+    match bearer_token(headers) {
+        Some(token) if is_token(token, Some(expected)) => Ok(()),
+        Some(_) => Err(ApiError::new(ErrorCode::Forbidden, "Invalid admin token")),
+        None => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Admin token required",
+        )),
+    }
+}
+
+/// The token of the request's `Authorization: Bearer <token>` header
+pub(super) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Whether `token` is the configured `expected` one, compared in constant time so the
+/// time a check takes does not give the configured token away
+pub(super) fn is_token(token: &str, expected: Option<&str>) -> bool {
+    expected.is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+pub async fn streams(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::stream_statuses(&state)).into_response()
+}
+
+/// Index the property names of every committed version from scratch, to recover from a
+/// lost or corrupt index or from versions changed behind the instance's back
+pub async fn rebuild_property_index(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::rebuild_property_index(&state).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => search_error(error),
+    }
+}
+
+/// Record the size and checksum of every committed version lacking them, which reads
+/// otherwise only do one version at a time as they happen to read them completely
+pub async fn backfill_metadata(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::backfill_metadata(&state).await {
+        Ok(report) => Json(report).into_response(),
+        Err(BackfillError::AlreadyRunning) => ApiError::new(
+            ErrorCode::Conflict,
+            "A metadata backfill is already running",
+        )
+        .into_response(),
+        Err(BackfillError::Failed(error)) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Where the bytes of the instance go, by item or namespace and category, streaming an
+/// NDJSON line per discrepancy found and the report at the end. Dropping the response
+/// stops the scan.
+pub async fn space_report(
+    state: AppState,
+    headers: HeaderMap,
+    query: SpaceReportQuery,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+    let group_by = match query.group_by.as_deref().map(GroupBy::parse) {
+        None => GroupBy::Item,
+        Some(Ok(group_by)) => group_by,
+        Some(Err(error)) => return ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    };
+    let top = query.top.unwrap_or(space_report::DEFAULT_TOP);
+    if top == 0 || top > space_report::MAX_TOP {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            format!("top must be from 1 to {}", space_report::MAX_TOP),
+        )
+        .into_response();
+    }
+    let options = SpaceReportOptions {
+        group_by,
+        top,
+        threshold_bytes: query
+            .threshold_bytes
+            .unwrap_or(space_report::DEFAULT_THRESHOLD_BYTES),
+    };
+
+    let lines = item_stream_component::space_report(state, options).map(|line| {
+        let mut line = serde_json::to_vec(&line).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, std::convert::Infallible>(line)
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Run a commit hook against a committed version right away with `HOOK_DRY_RUN=1`, to
+/// check its configuration. A failure is reported but the version is never flagged.
+pub async fn test_hook(state: AppState, headers: HeaderMap, request: HookTestRequest) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+    let HookTestRequest {
+        hook,
+        item_id,
+        version,
+    } = request;
+    if !item_stream_component::hook_names(&state).any(|name| name == hook) {
+        return ApiError::new(
+            ErrorCode::NotFound,
+            format!("No commit hook is named {hook}"),
+        )
+        .into_response();
+    }
+
+    let committed = match item_stream_component::version_state(&state, &item_id, version).await {
+        Ok(VersionState::Committed(committed)) => committed,
+        Ok(VersionState::InFlight { .. }) => {
+            return ApiError::new(
+                ErrorCode::Locked,
+                format!("Version {version} of item {item_id} is still being uploaded"),
+            )
+            .into_response();
+        }
+        Ok(VersionState::Interrupted(failed_upload)) => {
+            return ApiError::new(
+                ErrorCode::Aborted,
+                format!("Upload of version {version} of item {item_id} was interrupted"),
+            )
+            .with_details(failed_upload)
+            .into_response();
+        }
+        Ok(VersionState::Missing) => {
+            return ApiError::new(
+                ErrorCode::NotFound,
+                format!("Version {version} of item {item_id} was never committed"),
+            )
+            .into_response();
+        }
+        Err(error) => return ApiError::internal(error).into_response(),
+    };
+
+    match item_stream_component::test_hook(&state, &hook, &item_id, &committed).await {
+        Some(run) => Json(run).into_response(),
+        None => ApiError::new(
+            ErrorCode::NotFound,
+            format!("No commit hook is named {hook}"),
+        )
+        .into_response(),
+    }
+}
+
+/// Streams a graceful shutdown is waiting for, with their progress
+pub async fn drain(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::drain_report(&state)).into_response()
+}
+
+/// Cut off the streams a graceful shutdown is still waiting for
+pub async fn force_drain(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::force_drain(&state) {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => ApiError::new(ErrorCode::Conflict, error).into_response(),
+    }
+}
+
+/// Delete the committed versions matching a filter, streaming an NDJSON line per version
+/// as it is handled and a summary line at the end, so large deletions do not time out
+pub async fn bulk_delete(
+    state: AppState,
+    headers: HeaderMap,
+    filter: BulkDeleteFilter,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+    if let Err(error) = filter.validate() {
+        return ApiError::new(ErrorCode::BadRequest, error).into_response();
+    }
+
+    let matching = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            item_stream_component::bulk_delete_matching(&state, &filter)
+                .map(|matching| (matching, filter.dry_run))
+        })
+        .await
+        .map_err(|error| error.to_string())
+        .and_then(|matching| matching)
+    };
+    let (matching, dry_run) = match matching {
+        Ok(matching) => matching,
+        Err(error) => return ApiError::internal(error).into_response(),
+    };
+    let lines = item_stream_component::bulk_delete(state, matching, dry_run).map(|progress| {
+        let mut line = serde_json::to_vec(&progress).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, std::convert::Infallible>(line)
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+pub async fn kill_stream(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::kill_stream(
+        &state,
+        &item_id,
+        item_version,
+    ))
+    .into_response()
+}
+
+pub async fn reindex(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::reindex(&state, &item_id, item_version).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => ApiError::new(ErrorCode::Conflict, error).into_response(),
+    }
+}
+
+/// Check a committed version's data against its checksum. A mismatch quarantines the
+/// version and is answered like a read of it.
+pub async fn verify_version(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::verify_version(&state, &item_id, item_version).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => write_error(error).into_response(),
+    }
+}
+
+/// Serve a quarantined version again after it was repaired, which is verified first
+pub async fn unquarantine(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::unquarantine(&state, &item_id, item_version).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => write_error(error).into_response(),
+    }
+}
+
+/// Lower the latest version of an item a producer pushed to an implausible version
+pub async fn reset_version(
+    state: AppState,
+    item_id: String,
+    headers: HeaderMap,
+    request: ResetVersionRequest,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::reset_latest_version(
+        &state,
+        &item_id,
+        request.version,
+        request.force,
+    ) {
+        Ok(report) => Json(report).into_response(),
+        Err(ResetVersionError::NotFound(error)) => {
+            ApiError::new(ErrorCode::NotFound, error).into_response()
+        }
+        Err(ResetVersionError::Locked(error)) => {
+            ApiError::new(ErrorCode::Locked, error).into_response()
+        }
+        Err(ResetVersionError::NotBelow { requested, current }) => ApiError::new(
+            ErrorCode::Conflict,
+            format!("The latest version of item {item_id} is not above {requested}"),
+        )
+        .with_details(VersionConflictDetails { requested, current })
+        .into_response(),
+        Err(ResetVersionError::Blocked { message, versions }) => {
+            ApiError::new(ErrorCode::Conflict, message)
+                .with_details(BlockingVersionsDetails { versions })
+                .into_response()
+        }
+        Err(ResetVersionError::Immutable(immutable)) => {
+            ApiError::new(ErrorCode::Immutable, immutable.message())
+                .with_details(immutable)
+                .into_response()
+        }
+        Err(ResetVersionError::Failed(error)) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Move a committed version to the cold tier or back, regardless of its last access
+pub async fn move_version(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    tier: String,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+    let tier = match StorageTier::parse(&tier) {
+        Ok(tier) => tier,
+        Err(error) => return ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    };
+    if state.config.cold_dir.is_none() {
+        return ApiError::new(
+            ErrorCode::Conflict,
+            "Cold tier is disabled, set STREAM_DB_COLD_DIR to enable it",
+        )
+        .into_response();
+    }
+
+    match item_stream_component::move_version(&state, &item_id, item_version, tier).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => write_error(error).into_response(),
+    }
+}
+
+pub async fn failed_uploads(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::failed_uploads(&state)).into_response()
+}
+
+/// Bytes stored by the instance against its quota
+pub async fn usage(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::storage_usage(&state)).into_response()
+}
+
+/// Bytes stored against the quota, the file handles held open, where uploads and
+/// committed versions are kept and how the read sources are doing
+pub async fn storage(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(StorageReport {
+        usage: item_stream_component::storage_usage(&state),
+        file_handles: item_stream_component::file_handle_report(&state),
+        layout: item_stream_component::storage_layout(&state),
+        read_sources: item_stream_component::read_source_report(&state),
+    })
+    .into_response()
+}
+
+/// Change the storage quota, effective for the next chunk of every upload
+pub async fn set_quota(
+    state: AppState,
+    headers: HeaderMap,
+    update: StorageQuotaUpdate,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::set_storage_quota(
+        &state,
+        update.quota_bytes,
+    ))
+    .into_response()
+}
+
+/// What the startup warm-up did so far
+pub async fn warmup(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::warmup_progress(&state)).into_response()
+}
+
+/// The settings the instance runs with and where they came from, secrets redacted
+pub async fn config(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::config_settings(&state)).into_response()
+}
+
+/// Read the settings again and apply those that can change without a restart
+pub async fn reload_config(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::reload_config(&state) {
+        Ok(reload) => Json(reload).into_response(),
+        Err(error) => ApiError::new(
+            ErrorCode::Conflict,
+            format!("Config not reloaded, nothing changed: {error}"),
+        )
+        .into_response(),
+    }
+}
+
+/// What the background verification sweep did so far
+pub async fn verification(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::verification_status(&state)).into_response()
+}
+
+fn transform_error(error: TransformError) -> Response {
+    match error {
+        TransformError::Invalid(error) => ApiError::new(ErrorCode::BadRequest, error),
+        TransformError::NotFound(error) => ApiError::new(ErrorCode::NotFound, error),
+        TransformError::Failed(error) => ApiError::internal(error),
+    }
+    .into_response()
+}
+
+/// Every stored transform by name
+pub async fn transforms(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::transforms(&state) {
+        Ok(transforms) => Json(transforms).into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Store a transform under `name`, replacing an earlier one. The spec is validated here
+/// so that reads never fail on it.
+pub async fn set_transform(
+    state: AppState,
+    name: String,
+    headers: HeaderMap,
+    spec: TransformSpec,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::set_transform(&state, &name, spec.clone()) {
+        Ok(replaced) => Json(TransformSaved {
+            name,
+            replaced,
+            spec,
+        })
+        .into_response(),
+        Err(error) => transform_error(error),
+    }
+}
+
+pub async fn delete_transform(state: AppState, name: String, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::delete_transform(&state, &name) {
+        Ok(()) => Json(TransformDeleted {
+            name,
+            deleted: true,
+        })
+        .into_response(),
+        Err(error) => transform_error(error),
+    }
+}
+
+/// Fault injection has to be allowed by the instance's configuration
+fn authorize_faults(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    authorize_admin(&state.config, headers)?;
+    if !state.config.fault_injection {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "Fault injection is disabled, set STREAM_DB_FAULT_INJECTION to enable it",
+        ));
+    }
+    Ok(())
+}
+
+pub async fn fault_rules(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_faults(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    Json(item_stream_component::fault_rules(&state)).into_response()
+}
+
+/// Install the fault rule for an item pattern, replacing the previous one. A rule
+/// without any fault removes it.
+pub async fn set_fault_rule(state: AppState, headers: HeaderMap, rule: FaultRule) -> Response {
+    if let Err(rejection) = authorize_faults(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::set_fault_rule(&state, rule) {
+        Ok(rules) => Json(rules).into_response(),
+        Err(error) => ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    }
+}
+
+pub async fn clear_fault_rules(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_faults(&state, &headers) {
+        return rejection.into_response();
+    }
+
+    item_stream_component::clear_fault_rules(&state);
+    Json(item_stream_component::fault_rules(&state)).into_response()
+}
+
+/// The capture patterns and the captures taken so far
+pub async fn debug_captures(state: AppState, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::debug_captures(&state) {
+        Ok(captures) => Json(DebugCaptureList {
+            item_patterns: item_stream_component::debug_capture_patterns(&state),
+            captures,
+        })
+        .into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Capture the uploads of items matching the given patterns from now on
+pub async fn set_debug_capture_patterns(
+    state: AppState,
+    headers: HeaderMap,
+    patterns: DebugCapturePatterns,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    match item_stream_component::set_debug_capture_patterns(&state, patterns.item_patterns) {
+        Ok(item_patterns) => Json(DebugCapturePatterns { item_patterns }).into_response(),
+        Err(error) => ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    }
+}
+
+/// Log at another level, for a while or until changed again
+pub async fn set_log_level(
+    state: AppState,
+    headers: HeaderMap,
+    request: LogLevelRequest,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    let ttl = request.ttl_secs.map(Duration::from_secs);
+    Json(item_stream_component::set_log_level(
+        &state,
+        request.level,
+        ttl,
+    ))
+    .into_response()
+}
+
+/// Write debug lines for the streams of the items matching the given patterns, for a
+/// while or until changed again
+pub async fn set_debug_items(
+    state: AppState,
+    headers: HeaderMap,
+    request: DebugItemsRequest,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    let ttl = request.ttl_secs.map(Duration::from_secs);
+    match item_stream_component::set_debug_items(&state, request.item_patterns, ttl) {
+        Ok(settings) => Json(settings).into_response(),
+        Err(error) => ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    }
+}
+
+/// Stream the raw body of a capture back, or its trace
+pub async fn debug_capture(
+    state: AppState,
+    request_id: String,
+    query: DebugCaptureQuery,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+
+    let not_found = || {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("No debug capture for request {request_id}"),
+        )
+        .into_response()
+    };
+    match query.artifact.as_deref() {
+        None | Some("raw") => {
+            match item_stream_component::debug_capture_raw(&state, &request_id).await {
+                Ok(Some(file)) => (
+                    [(header::CONTENT_TYPE, "application/octet-stream")],
+                    Body::from_stream(ReaderStream::new(file)),
+                )
+                    .into_response(),
+                Ok(None) => not_found(),
+                Err(error) => ApiError::internal(error).into_response(),
+            }
+        }
+        Some("trace") => match item_stream_component::debug_capture_trace(&state, &request_id) {
+            Ok(Some(trace)) => Json(trace).into_response(),
+            Ok(None) => not_found(),
+            Err(error) => ApiError::internal(error).into_response(),
+        },
+        Some(other) => ApiError::new(
+            ErrorCode::BadRequest,
+            format!("Unknown capture artifact {other:?}, expected raw or trace"),
+        )
+        .into_response(),
+    }
+}
@@ -0,0 +1,202 @@
+use crate::persistence::cancellation::CancelReason;
+use crate::state::AppState;
+use crate::types::dto::ErrorBody;
+
+use super::request_id::{REQUEST_ID_HEADER, request_id};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+pub use crate::types::dto::ErrorCode;
+
+/// Header carrying the error code, so clients reading plain text errors can branch too
+pub const ERROR_CODE_HEADER: &str = "X-Error-Code";
+/// Rejection bodies of axum's extractors are short, anything longer is cut off
+const MAX_REJECTION_BYTES: usize = 64 * 1024;
+/// Sent in place of the message of an internal error, see [`ApiError::for_client`]
+pub const INTERNAL_ERROR_MESSAGE: &str =
+    "Internal error, the server log has the details under the request ID";
+
+/// Code of a stream cancelled for `reason`: unavailable when the instance is shutting
+/// down, aborted otherwise
+pub fn cancelled_code(reason: CancelReason) -> ErrorCode {
+    match reason {
+        CancelReason::Shutdown | CancelReason::Timeout => ErrorCode::Unavailable,
+        CancelReason::Killed | CancelReason::ClientGone => ErrorCode::Aborted,
+    }
+}
+
+/// A failed request, sent as an [`ErrorBody`]. Handlers return it and [`render_errors`]
+/// fills in the request ID and picks the format the client asked for.
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Value,
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: json!({}),
+            request_id: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).unwrap_or_else(|_| json!({}));
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// The error as clients get to see it. The message of an internal error may name
+    /// paths and OS errors of the instance, so it is replaced by a generic one unless
+    /// `STREAM_DB_EXPOSE_INTERNAL_ERRORS` is set; whoever sends it logs the original.
+    pub fn for_client(self, expose_internal_errors: bool) -> Self {
+        if self.code != ErrorCode::Internal || expose_internal_errors {
+            return self;
+        }
+        Self {
+            message: INTERNAL_ERROR_MESSAGE.to_string(),
+            details: json!({}),
+            ..self
+        }
+    }
+
+    /// Log an internal error with what the client does not get to see, under the
+    /// request's ID so a client quoting it can be helped
+    pub fn log_internal(&self, request_id: &str, request: &str) {
+        if self.code != ErrorCode::Internal {
+            return;
+        }
+        match &self.details {
+            Value::Object(details) if details.is_empty() => {
+                println!("Request {request_id} {request} failed: {}", self.message)
+            }
+            details => println!(
+                "Request {request_id} {request} failed: {} ({details})",
+                self.message
+            ),
+        }
+    }
+
+    /// Render as a response body, in JSON or as the bare message
+    fn render(&self, headers: &mut HeaderMap, plain_text: bool) -> Body {
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(
+            ERROR_CODE_HEADER,
+            HeaderValue::from_static(self.code.name()),
+        );
+        if let Some(value) = self
+            .request_id
+            .as_deref()
+            .and_then(|request_id| request_id.parse().ok())
+        {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
+        if plain_text {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+            return Body::from(self.message.clone());
+        }
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let body = serde_json::to_vec(&ErrorBody::from(self.clone()))
+            .unwrap_or_else(|_| format!("{{\"code\":\"{}\"}}", self.code.name()).into_bytes());
+        Body::from(body)
+    }
+}
+
+impl From<ApiError> for ErrorBody {
+    fn from(error: ApiError) -> Self {
+        Self {
+            code: error.code,
+            message: error.message,
+            details: error.details,
+            request_id: error.request_id,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = self.code.status();
+        *response.body_mut() = self.render(response.headers_mut(), false);
+        // Picked up by `render_errors` to add the request ID and negotiate the format
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Middleware giving every error response the [`ApiError`] shape: the request ID is
+/// added, errors of axum's extractors are wrapped, internal errors are logged and
+/// stripped of their details, and clients that accept `text/plain` but not JSON get the
+/// bare message.
+pub async fn render_errors(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request_id(request.headers());
+    let plain_text = prefers_plain_text(request.headers());
+    let described = format!("{} {}", request.method(), request.uri().path());
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let error = match parts.extensions.remove::<ApiError>() {
+        Some(error) => error,
+        None => {
+            let message = axum::body::to_bytes(body, MAX_REJECTION_BYTES)
+                .await
+                .ok()
+                .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+                .filter(|message| !message.is_empty())
+                .or_else(|| status.canonical_reason().map(str::to_string))
+                .unwrap_or_default();
+            ApiError::new(ErrorCode::from_status(status), message)
+        }
+    };
+    error.log_internal(&request_id, &described);
+    let error = ApiError {
+        request_id: error.request_id.clone().or(Some(request_id)),
+        ..error
+    }
+    .for_client(state.config.expose_internal_errors);
+    let body = error.render(&mut parts.headers, plain_text);
+    Response::from_parts(parts, body)
+}
+
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    accept.contains("text/plain") && !accept.contains("json")
+}
@@ -0,0 +1,92 @@
+use crate::component::item_stream_component;
+use crate::logic::download_manifest::{DEFAULT_PARTS, DownloadManifest, MAX_PARTS};
+use crate::persistence::file_persistence::VersionState;
+use crate::state::AppState;
+use crate::types::dto::InFlightProgress;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::Json;
+use serde::Deserialize;
+
+/// Query parameters accepted by the download manifest endpoint
+#[derive(Deserialize, Default)]
+pub struct DownloadManifestQuery {
+    /// Number of byte ranges to split the version into
+    pub parts: Option<u64>,
+}
+
+/// Byte ranges and their checksums for downloading a committed version over parallel
+/// connections, each part read with a `Range` request
+pub async fn download_manifest(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    query: DownloadManifestQuery,
+) -> Result<Json<DownloadManifest>, ApiError> {
+    let parts = query.parts.unwrap_or(DEFAULT_PARTS);
+    if parts == 0 || parts > MAX_PARTS {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("parts must be between 1 and {MAX_PARTS}"),
+        ));
+    }
+
+    let committed = match item_stream_component::version_state(&state, &item_id, item_version).await
+    {
+        Ok(VersionState::Committed(committed)) => committed,
+        Ok(VersionState::InFlight {
+            bytes_written,
+            bytes_durable,
+        }) => {
+            return Err(ApiError::new(
+                ErrorCode::Locked,
+                format!(
+                    "Version {item_version} of item {item_id} is still being uploaded, its parts are only known once it is committed"
+                ),
+            )
+            .with_details(InFlightProgress::uploading(
+                item_id,
+                item_version,
+                bytes_written,
+                bytes_durable,
+            )));
+        }
+        Ok(VersionState::Interrupted(failed_upload)) => {
+            return Err(ApiError::new(
+                ErrorCode::Aborted,
+                format!("Upload of version {item_version} of item {item_id} was interrupted"),
+            )
+            .with_details(failed_upload));
+        }
+        Ok(VersionState::Missing) => {
+            return Err(ApiError::new(
+                ErrorCode::NotFound,
+                format!("Version {item_version} of item {item_id} was never committed"),
+            ));
+        }
+        Err(error) => return Err(ApiError::internal(error)),
+    };
+    if committed.quarantined_at.is_some() {
+        return Err(ApiError::new(
+            ErrorCode::IntegrityFailure,
+            format!(
+                "Version {item_version} of item {item_id} is quarantined, its data no longer matches its checksum"
+            ),
+        ));
+    }
+
+    if let Some(content_encoding) = &committed.content_encoding {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!(
+                "Version {item_version} of item {item_id} is stored {content_encoding}-compressed, it cannot be downloaded in byte ranges"
+            ),
+        ));
+    }
+
+    item_stream_component::download_manifest(&state, &item_id, *committed, parts)
+        .await
+        .map(Json)
+        .map_err(ApiError::internal)
+}
@@ -0,0 +1,27 @@
+use crate::component::item_stream_component;
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Refuse every request of the routes it wraps once the instance is shutting down, the
+/// streams already running are left to finish
+pub async fn reject_new_streams(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if item_stream_component::is_draining(&state) {
+        return ApiError::new(
+            ErrorCode::Unavailable,
+            "This instance is shutting down, try another one",
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
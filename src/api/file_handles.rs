@@ -0,0 +1,34 @@
+use crate::component::item_stream_component;
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Seconds a client refused for lack of file handles is asked to wait
+const RETRY_AFTER_SECS: &str = "1";
+
+/// Refuse requests of the routes it wraps while `STREAM_DB_MAX_OPEN_FILES` leaves no room
+/// for another stream, rather than letting the process run out of handles halfway
+/// through a write
+pub async fn reject_without_file_handles(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(exhausted) = item_stream_component::reserve_file_handles(&state) {
+        println!("{}", exhausted.message);
+        return (
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS)],
+            ApiError::new(ErrorCode::Unavailable, exhausted.message.clone())
+                .with_details(exhausted),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,44 @@
+use crate::component::item_stream_component;
+use crate::logic::warmup::WarmupState;
+use crate::state::AppState;
+use crate::types::dto::{HealthState, HealthStatus, InstanceMode};
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+
+/// 200 once the instance listens; `status` is `warming` while the startup warm-up still
+/// runs, so load balancers may hold traffic back until it reports `ok`. A shutting down
+/// instance answers 503 `UNAVAILABLE` with `draining` in the details, so it is taken out
+/// of rotation. `mode` tells read-only replicas from the writing instance.
+pub async fn get_health(State(state): State<AppState>) -> Response {
+    if item_stream_component::is_draining(&state) {
+        return ApiError::new(ErrorCode::Unavailable, "This instance is shutting down")
+            .with_details(HealthStatus {
+                status: HealthState::Draining,
+                mode: mode(&state),
+            })
+            .into_response();
+    }
+    let status = match item_stream_component::warmup_progress(&state).state {
+        WarmupState::Warming => HealthState::Warming,
+        WarmupState::Disabled | WarmupState::Done => HealthState::Ok,
+    };
+    Json(HealthStatus {
+        status,
+        mode: mode(&state),
+    })
+    .into_response()
+}
+
+fn mode(state: &AppState) -> InstanceMode {
+    if state.config.read_only {
+        InstanceMode::ReadOnly
+    } else {
+        InstanceMode::ReadWrite
+    }
+}
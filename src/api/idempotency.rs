@@ -0,0 +1,126 @@
+use crate::component::item_stream_component;
+use crate::logic::idempotency::{self, MAX_RECORDED_BODY_BYTES};
+use crate::persistence::idempotency::RecordedResponse;
+use crate::state::AppState;
+use crate::types::dto::IdempotencyKeyDetails;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+/// Set on responses replayed for a key instead of running the request again
+pub const IDEMPOTENT_REPLAY_HEADER: &str = "X-Idempotent-Replay";
+
+/// Run a mutating request carrying an `Idempotency-Key` once: the response is recorded
+/// and replayed for every retry with the same key and credentials, while a retry
+/// arriving before the first attempt finished waits for its outcome. Server errors are
+/// not recorded, so those requests can be retried for real. Streamed responses are
+/// passed on without being recorded.
+pub async fn replay(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if request.method() == Method::GET {
+        return next.run(request).await;
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let key = match key
+        .to_str()
+        .map_err(|_| "Idempotency-Key may only contain visible ASCII characters".to_string())
+        .and_then(|key| idempotency::validate_key(key).map(|_| key))
+    {
+        Ok(key) => key,
+        Err(error) => return ApiError::new(ErrorCode::BadRequest, error).into_response(),
+    };
+    let credentials = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let key = idempotency::scoped_key(credentials, key);
+    let fingerprint = format!("{} {}", request.method(), request.uri());
+
+    let _turn = item_stream_component::idempotency_turn(&state, &key).await;
+    match item_stream_component::recorded_response(&state, &key) {
+        Ok(Some(recorded)) if recorded.request == fingerprint => return replayed(recorded),
+        Ok(Some(recorded)) => {
+            return ApiError::new(
+                ErrorCode::IdempotencyKeyReused,
+                format!(
+                    "Idempotency-Key was already used for {}, use a new key for a different request",
+                    recorded.request
+                ),
+            )
+            .with_details(IdempotencyKeyDetails {
+                request: recorded.request,
+            })
+            .into_response();
+        }
+        Ok(None) => {}
+        Err(error) => return ApiError::internal(error).into_response(),
+    }
+
+    let response = next.run(request).await;
+    let recordable = response
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size <= MAX_RECORDED_BODY_BYTES as u64);
+    if response.status().is_server_error() || !recordable {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_RECORDED_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(error) => return ApiError::internal(error.to_string()).into_response(),
+    };
+    let recorded = RecordedResponse {
+        request: fingerprint,
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .filter(|(name, _)| *name != header::CONTENT_LENGTH)
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: STANDARD.encode(&body),
+        recorded_at: 0,
+        used_at: 0,
+    };
+    let record = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            item_stream_component::record_response(&state, &key, recorded)
+        })
+        .await
+        .map_err(|error| error.to_string())
+        .and_then(|recorded| recorded)
+    };
+    // The request went through either way, a retry just runs it again
+    if let Err(error) = record {
+        println!("Could not record the response for an idempotency key: {error}");
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn replayed(recorded: RecordedResponse) -> Response {
+    let status = StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::OK);
+    let body = STANDARD.decode(&recorded.body).unwrap_or_default();
+    let mut response = (status, body).into_response();
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    for (name, value) in recorded.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(IDEMPOTENT_REPLAY_HEADER, HeaderValue::from_static("true"));
+    response
+}
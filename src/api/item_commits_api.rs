@@ -0,0 +1,38 @@
+use crate::component::item_stream_component;
+use crate::state::AppState;
+
+use async_stream::stream;
+use axum::response::{
+    IntoResponse, Response,
+    sse::{Event, KeepAlive, Sse},
+};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Server-sent events for every version of the item committed from now on: uploads to
+/// this instance and, with the data directory watch on, versions written by other
+/// processes. A subscriber falling behind receives a `lagged` event with the number of
+/// commits it missed.
+pub async fn watch_commits(state: AppState, item_id: String) -> Response {
+    let mut commits = item_stream_component::subscribe_commits(&state);
+    let events = stream! {
+        loop {
+            match commits.recv().await {
+                Ok(commit) if commit.item_id == item_id => {
+                    match Event::default().event("commit").json_data(&commit) {
+                        Ok(event) => yield Ok::<_, Infallible>(event),
+                        Err(error) => println!("Could not encode commit event: {error}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    yield Ok(Event::default().event("lagged").data(missed.to_string()));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,55 @@
+use crate::component::item_stream_component;
+use crate::persistence::file_persistence::VersionState;
+use crate::state::AppState;
+use crate::types::dto::{InFlightProgress, VersionListing};
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{Json, response::IntoResponse};
+
+/// Lists the receipts of all committed versions of an item, including who wrote each
+pub async fn list_versions(state: AppState, item_id: String) -> impl IntoResponse {
+    match item_stream_component::committed_versions(&state, &item_id) {
+        Ok(versions) if versions.is_empty() => ApiError::new(
+            ErrorCode::NotFound,
+            format!("Item {item_id} has no committed versions"),
+        )
+        .into_response(),
+        Ok(versions) => Json(VersionListing { item_id, versions }).into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Returns the receipt recorded when a version was committed, so a producer that lost
+/// the write response can find out whether its upload made it.
+pub async fn get_receipt(state: AppState, item_id: String, item_version: u64) -> impl IntoResponse {
+    match item_stream_component::version_state(&state, &item_id, item_version).await {
+        Ok(VersionState::Committed(receipt)) => Json(receipt).into_response(),
+        Ok(VersionState::InFlight {
+            bytes_written,
+            bytes_durable,
+        }) => ApiError::new(
+            ErrorCode::Locked,
+            format!("Version {item_version} of item {item_id} is still being uploaded"),
+        )
+        .with_details(InFlightProgress::uploading(
+            item_id,
+            item_version,
+            bytes_written,
+            bytes_durable,
+        ))
+        .into_response(),
+        Ok(VersionState::Interrupted(failed_upload)) => ApiError::new(
+            ErrorCode::Aborted,
+            format!("Upload of version {item_version} of item {item_id} was interrupted"),
+        )
+        .with_details(failed_upload)
+        .into_response(),
+        Ok(VersionState::Missing) => ApiError::new(
+            ErrorCode::NotFound,
+            format!("Version {item_version} of item {item_id} was never committed"),
+        )
+        .into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
@@ -0,0 +1,29 @@
+use crate::component::item_stream_component;
+use crate::logic::item_stream_logic::SettingsError;
+use crate::persistence::item_settings::ItemSettings;
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{Json, response::IntoResponse};
+
+pub async fn get_item_settings(state: AppState, item_id: String) -> impl IntoResponse {
+    match item_stream_component::load_item_settings(&state, &item_id) {
+        Ok(settings) => Json(settings).into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+pub async fn put_item_settings(
+    state: AppState,
+    item_id: String,
+    settings: ItemSettings,
+) -> impl IntoResponse {
+    match item_stream_component::store_item_settings(&state, &item_id, settings) {
+        Ok(settings) => Json(settings).into_response(),
+        Err(SettingsError::Invalid(error)) => {
+            ApiError::new(ErrorCode::BadRequest, error).into_response()
+        }
+        Err(SettingsError::Failed(error)) => ApiError::internal(error).into_response(),
+    }
+}
@@ -0,0 +1,33 @@
+use crate::component::item_stream_component;
+use crate::state::AppState;
+use crate::types::dto::ItemStatsResponse;
+
+use super::api_error::ApiError;
+
+use axum::{Json, response::IntoResponse};
+use serde::Deserialize;
+
+/// Query parameters accepted by the stats endpoint
+#[derive(Deserialize, Default)]
+pub struct ItemStatsQuery {
+    /// Also break the counters down by version
+    pub per_version: Option<bool>,
+}
+
+/// How often an item was read. Counters are applied when a read ends and persisted
+/// periodically, so a crash loses at most the last flush interval.
+pub async fn get_item_stats(
+    state: AppState,
+    item_id: String,
+    query: ItemStatsQuery,
+) -> impl IntoResponse {
+    match item_stream_component::item_stats(&state, &item_id) {
+        Ok(stats) => Json(ItemStatsResponse {
+            item_id,
+            total: stats.total(),
+            versions: query.per_version.unwrap_or(false).then_some(stats.versions),
+        })
+        .into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
@@ -0,0 +1,62 @@
+use crate::component::item_stream_component;
+use crate::persistence::file_persistence::{DeleteError, ImmutableVersion};
+use crate::state::AppState;
+use crate::types::dto::{BlockingTagsDetails, ImmutabilityRequest};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::write_item_stream_api::write_error;
+
+use axum::{Json, response::IntoResponse};
+use chrono::{DateTime, Utc};
+
+/// Deletes a committed version. Uploading the same version again afterwards starts a
+/// new generation, which readers can tell apart through the read endpoint's ETag.
+pub async fn delete_version(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+) -> impl IntoResponse {
+    match item_stream_component::delete_version(&state, &item_id, item_version) {
+        Ok(report) => Json(report).into_response(),
+        Err(DeleteError::NotFound(error)) => {
+            ApiError::new(ErrorCode::NotFound, error).into_response()
+        }
+        Err(DeleteError::Locked(error)) => ApiError::new(ErrorCode::Locked, error).into_response(),
+        Err(DeleteError::Tagged { message, tags }) => ApiError::new(ErrorCode::Conflict, message)
+            .with_details(BlockingTagsDetails { tags })
+            .into_response(),
+        Err(DeleteError::Immutable(immutable)) => {
+            ApiError::new(ErrorCode::Immutable, immutable.message())
+                .with_details(immutable)
+                .into_response()
+        }
+        Err(DeleteError::Failed(error)) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Keeps a committed version from being deleted or replaced until a later time than
+/// before. Shortening the window is refused for everyone, admins included.
+pub async fn put_immutability(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    request: ImmutabilityRequest,
+) -> Result<Json<ImmutableVersion>, ApiError> {
+    let until = DateTime::parse_from_rfc3339(&request.immutable_until)
+        .map_err(|_| {
+            ApiError::new(
+                ErrorCode::BadRequest,
+                "immutable_until must be an RFC 3339 timestamp",
+            )
+        })?
+        .with_timezone(&Utc);
+    if until <= Utc::now() {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "immutable_until must be in the future",
+        ));
+    }
+    item_stream_component::extend_immutability(&state, &item_id, item_version, until)
+        .map(Json)
+        .map_err(write_error)
+}
@@ -0,0 +1,216 @@
+use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::logic::item_ids;
+use crate::logic::item_stream_logic::ReadFormat;
+use crate::persistence::debug_capture::DebugCapture;
+use crate::state::AppState;
+use crate::types::dto::{InvalidParameterDetails, WriteReceipt};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::read_item_stream_api::{
+    ReadItemStreamQuery, await_consistency, property_filter, read_error, read_options,
+};
+use super::request_id::request_id;
+use super::write_item_stream_api::{
+    WriteItemStreamQuery, ingest_error, receipt_response, write_error, write_options,
+};
+
+use axum::{
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+/// Body of `POST /items/{item_id}/{version}/materialize`: how to read the source, as the
+/// read endpoint's query would, and where to write the result
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterializeRequest {
+    pub destination: Destination,
+    /// Names of the only properties to keep, by their stored names
+    pub properties: Option<Vec<String>>,
+    /// Name of a transform stored through `/admin/transforms`
+    pub transform: Option<String>,
+    /// Only `xml`, the destination is stored as XML
+    pub format: Option<String>,
+    /// `pretty` or `minified` to lay out the XML written
+    pub xml: Option<String>,
+    /// Skip the properties before this one (0-based)
+    pub from_property: Option<u64>,
+    /// `committed` to only copy bytes of the source already synced to disk
+    pub durability: Option<String>,
+    /// Reject the copy if a property value does not match its declared `type`
+    pub typed: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Destination {
+    pub item_id: String,
+    pub version: u64,
+}
+
+/// Read a version the way the request asks and write what comes out as a new version,
+/// chunk by chunk, so only a chunk and the property being reshaped are held in memory.
+/// The destination goes through the same checks as an upload. A client that disconnects,
+/// or a kill of the destination's stream, aborts the copy and leaves nothing behind.
+pub async fn materialize(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+    request: MaterializeRequest,
+) -> Response {
+    let request_id = request_id(&headers);
+    if let Err(error) = validate_destination(&state, &request.destination) {
+        return error.with_request_id(Some(request_id)).into_response();
+    }
+    if let Err(rejection) = await_consistency(&state, &item_id, &headers).await {
+        return rejection;
+    }
+    let query = ReadItemStreamQuery {
+        from_property: request.from_property,
+        durability: request.durability,
+        format: request.format,
+        transform: request.transform,
+        xml: request.xml,
+        ..ReadItemStreamQuery::default()
+    };
+    let mut options = match read_options(&query) {
+        Ok(options) => options,
+        Err(error) => return error.with_request_id(Some(request_id)).into_response(),
+    };
+    if options.format != ReadFormat::Xml {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "Only format=xml can be materialized, items are stored as XML",
+        )
+        .with_request_id(Some(request_id))
+        .into_response();
+    }
+    if let Some(properties) = request.properties {
+        match property_filter(properties) {
+            Ok(properties) => options.properties = Some(properties),
+            Err(error) => return error.with_request_id(Some(request_id)).into_response(),
+        }
+    }
+    // The source is opened first, so a missing one never claims the destination
+    let reader =
+        match ItemStreamComponent::new_reader(&state, item_id.clone(), item_version, options).await
+        {
+            Ok(reader) => reader,
+            Err(error) => return read_error(error),
+        };
+
+    let destination = request.destination;
+    let mut capture = item_stream_component::start_debug_capture(
+        &state,
+        &destination.item_id,
+        destination.version,
+        &request_id,
+    );
+    let write_query = WriteItemStreamQuery {
+        typed: request.typed,
+        dry_run: None,
+    };
+    let result = copy(
+        &state,
+        reader,
+        destination,
+        &headers,
+        &write_query,
+        &request_id,
+        &mut capture,
+    )
+    .await;
+    if let (Some(capture), Err(error)) = (capture.as_mut(), &result) {
+        capture.finish(error.code.name());
+    }
+    match result {
+        Ok(receipt) => {
+            println!(
+                "Materialized item {item_id} version {item_version} as item {} version {}",
+                receipt.item_id, receipt.committed.version
+            );
+            receipt_response(&state, receipt, false)
+        }
+        Err(error) => error.with_request_id(Some(request_id)).into_response(),
+    }
+}
+
+/// Tee the source into a writer for the destination
+async fn copy(
+    state: &AppState,
+    mut reader: ItemStreamComponent,
+    destination: Destination,
+    headers: &HeaderMap,
+    query: &WriteItemStreamQuery,
+    request_id: &str,
+    capture: &mut Option<DebugCapture>,
+) -> Result<WriteReceipt, ApiError> {
+    let mut options = write_options(state, headers, query, request_id)?;
+    let publish_group = options.publish_group.clone();
+    // The source is copied with the envelope it is read with, if any
+    if !headers.contains_key("x-wrap-root") {
+        options.wrap_root = false;
+    }
+    let writer = ItemStreamComponent::new_queued_writer(
+        state,
+        destination.item_id.clone(),
+        destination.version,
+        options,
+    )
+    .await
+    .map_err(write_error)?;
+    let mut ingest = writer.into_ingest(capture.take()).map_err(ingest_error)?;
+    ingest
+        .check_declared_size(reader.content_length())
+        .map_err(ingest_error)?;
+    loop {
+        match reader.read_chunk().await {
+            Ok(Some(chunk)) => ingest
+                .push_bytes(chunk.into())
+                .await
+                .map_err(ingest_error)?,
+            Ok(None) => break,
+            Err(error) => {
+                ingest.abort();
+                return Err(ApiError::new(
+                    ErrorCode::Aborted,
+                    format!("Reading the source failed: {error}"),
+                ));
+            }
+        }
+    }
+    let limits_applied = *ingest.limits();
+    let (committed, received) = ingest.finish().await.map_err(ingest_error)?;
+    Ok(WriteReceipt {
+        item_id: destination.item_id,
+        committed,
+        limits_applied,
+        received,
+        dry_run: None,
+        publish_group,
+    })
+}
+
+/// The destination is named in the body, so it gets the checks a path would
+fn validate_destination(state: &AppState, destination: &Destination) -> Result<(), ApiError> {
+    item_ids::validate_item_id(&destination.item_id).map_err(|error| {
+        ApiError::new(ErrorCode::BadRequest, error).with_details(InvalidParameterDetails {
+            parameter: "destination.item_id".to_string(),
+            value: destination.item_id.clone().into(),
+        })
+    })?;
+    let max_version = state.config.max_version;
+    if !(1..=max_version).contains(&destination.version) {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("Version must be a whole number from 1 to {max_version}"),
+        )
+        .with_details(InvalidParameterDetails {
+            parameter: "destination.version".to_string(),
+            value: destination.version.into(),
+        }));
+    }
+    Ok(())
+}
@@ -0,0 +1,10 @@
+use crate::state::AppState;
+
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
@@ -1,2 +1,34 @@
+pub mod access_log;
+pub mod admin_api;
+pub mod api_error;
+pub mod download_manifest_api;
+pub mod draining;
+pub mod file_handles;
+pub mod health_api;
+pub mod idempotency;
+pub mod item_commits_api;
+pub mod item_receipt_api;
+pub mod item_settings_api;
+pub mod item_stats_api;
+pub mod item_version_api;
+pub mod materialize_api;
+pub mod metrics_api;
+pub mod path_params;
+pub mod presign_api;
+pub mod publish_groups_api;
+pub mod read_auth;
 pub mod read_item_stream_api;
+pub mod read_item_ws_api;
+pub mod read_only;
+pub mod request_id;
+pub mod router;
+pub mod s3_api;
+pub mod s3_auth;
+pub mod search_api;
+pub mod selftest_api;
+pub mod stat_batch_api;
+pub mod version_tags_api;
+#[cfg(feature = "web-ui")]
+pub mod web_ui;
 pub mod write_item_stream_api;
+pub mod write_item_ws_api;
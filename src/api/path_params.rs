@@ -0,0 +1,128 @@
+use crate::logic::item_ids;
+use crate::state::AppState;
+use crate::types::dto::InvalidParameterDetails;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::extract::{FromRequestParts, Path};
+use axum::http::request::Parts;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Path parameters of a route, checked the same way on every route before the handler
+/// runs: every segment is trimmed and must not be empty, `item_id` must be a valid item
+/// ID and `version` a number from 1 to `STREAM_DB_MAX_VERSION`. `T` names the
+/// parameters of the route, e.g. [`VersionPath`].
+pub struct PathParams<T>(pub T);
+
+/// `/{item_id}`
+#[derive(Deserialize)]
+pub struct ItemPath {
+    pub item_id: String,
+}
+
+/// `/{item_id}/{version}`
+#[derive(Deserialize)]
+pub struct VersionPath {
+    pub item_id: String,
+    pub version: u64,
+}
+
+/// `/{item_id}/tag/{tag}` and `/{item_id}/version-tags/{tag}`
+#[derive(Deserialize)]
+pub struct TagPath {
+    pub item_id: String,
+    pub tag: String,
+}
+
+/// `/{item_id}/as-of/{timestamp}`
+#[derive(Deserialize)]
+pub struct TimestampPath {
+    pub item_id: String,
+    pub timestamp: String,
+}
+
+/// `/{item_id}/{version}/{tier}`
+#[derive(Deserialize)]
+pub struct TierPath {
+    pub item_id: String,
+    pub version: u64,
+    pub tier: String,
+}
+
+/// `/{name}` of the transforms
+#[derive(Deserialize)]
+pub struct NamePath {
+    pub name: String,
+}
+
+/// `/{group_id}` of the publish groups
+#[derive(Deserialize)]
+pub struct GroupPath {
+    pub group_id: String,
+}
+
+/// `/{request_id}` of the debug captures
+#[derive(Deserialize)]
+pub struct RequestIdPath {
+    pub request_id: String,
+}
+
+impl<T: DeserializeOwned + Send> FromRequestParts<AppState> for PathParams<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(raw) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| ApiError::new(ErrorCode::BadRequest, rejection.body_text()))?;
+
+        let mut params = Map::new();
+        for (name, value) in raw {
+            let value = value.trim();
+            let param = match name.as_str() {
+                "item_id" => {
+                    item_ids::validate_item_id(value)
+                        .map_err(|error| invalid(&name, value, error))?;
+                    Value::from(value)
+                }
+                "version" => Value::from(parse_version(value, state.config.max_version)?),
+                _ if value.is_empty() => {
+                    return Err(invalid(
+                        &name,
+                        value,
+                        format!("Path parameter {name} must not be empty"),
+                    ));
+                }
+                _ => Value::from(value),
+            };
+            params.insert(name, param);
+        }
+        serde_json::from_value(Value::Object(params))
+            .map(PathParams)
+            .map_err(|error| ApiError::internal(format!("Route parameters mismatch: {error}")))
+    }
+}
+
+fn parse_version(value: &str, max_version: u64) -> Result<u64, ApiError> {
+    match value.parse::<u64>() {
+        Ok(version) if (1..=max_version).contains(&version) => Ok(version),
+        _ => Err(invalid(
+            "version",
+            value,
+            format!("Version must be a whole number from 1 to {max_version}"),
+        )),
+    }
+}
+
+/// A malformed parameter `name`, given as `value`
+pub(crate) fn invalid(name: &str, value: &str, message: String) -> ApiError {
+    ApiError::new(ErrorCode::BadRequest, message).with_details(InvalidParameterDetails {
+        parameter: name.to_string(),
+        value: value.into(),
+    })
+}
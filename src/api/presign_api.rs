@@ -0,0 +1,67 @@
+use crate::component::item_stream_component;
+use crate::logic::byte_range::ByteRange;
+use crate::logic::presign::DEFAULT_EXPIRES_IN_SECS;
+use crate::persistence::file_persistence::VersionState;
+use crate::state::AppState;
+use crate::types::dto::{PresignRequest, PresignResponse};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::read_auth;
+
+use axum::{Json, http::HeaderMap};
+
+/// Hand out a URL reading one version without a token until it expires, e.g. for a
+/// third party that should not get the read token
+pub async fn presign(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    headers: HeaderMap,
+    request: PresignRequest,
+) -> Result<Json<PresignResponse>, ApiError> {
+    read_auth::authorize_presign(&state.config, &headers)?;
+    let expires_in_secs = request.expires_in_secs.unwrap_or(DEFAULT_EXPIRES_IN_SECS);
+    let max_secs = state.config.presign_max_secs;
+    if expires_in_secs == 0 || expires_in_secs > max_secs {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("expires_in_secs must be between 1 and {max_secs}"),
+        ));
+    }
+    let range = request
+        .range
+        .as_deref()
+        .map(ByteRange::parse)
+        .transpose()
+        .map_err(|error| ApiError::new(ErrorCode::BadRequest, error))?;
+
+    // Uploads in flight can be presigned, the URL follows them like any read
+    match item_stream_component::version_state(&state, &item_id, item_version).await {
+        Ok(VersionState::Committed(_) | VersionState::InFlight { .. }) => {}
+        Ok(VersionState::Interrupted(failed_upload)) => {
+            return Err(ApiError::new(
+                ErrorCode::Aborted,
+                format!("Upload of version {item_version} of item {item_id} was interrupted"),
+            )
+            .with_details(failed_upload));
+        }
+        Ok(VersionState::Missing) => {
+            return Err(ApiError::new(
+                ErrorCode::NotFound,
+                format!("Version {item_version} of item {item_id} was never committed"),
+            ));
+        }
+        Err(error) => return Err(ApiError::internal(error)),
+    }
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(expires_in_secs as i64);
+    let presigned =
+        item_stream_component::presign_read(&state, &item_id, item_version, expires_at, range);
+    Ok(Json(PresignResponse {
+        url: presigned.url,
+        expires_at: presigned
+            .expires_at
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        range: presigned.range.map(|range| range.to_string()),
+    }))
+}
@@ -0,0 +1,66 @@
+use crate::component::item_stream_component;
+use crate::logic::publish_groups::DEFAULT_TTL_SECS;
+use crate::persistence::publish_groups::PublishGroupError;
+use crate::state::AppState;
+use crate::types::dto::CreatePublishGroupRequest;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+
+/// Header naming the publish group an upload is staged in
+pub const PUBLISH_GROUP_HEADER: &str = "X-Publish-Group";
+
+fn publish_group_error(error: PublishGroupError) -> ApiError {
+    match error {
+        PublishGroupError::NotFound(error) => ApiError::new(ErrorCode::NotFound, error),
+        PublishGroupError::Locked(error) => ApiError::new(ErrorCode::Locked, error),
+        PublishGroupError::Conflict(error) => ApiError::new(ErrorCode::Conflict, error),
+        PublishGroupError::Failed(error) => ApiError::internal(error),
+    }
+}
+
+/// Open a group for uploads that are committed together, see
+/// [`publish_groups`](crate::persistence::publish_groups)
+pub async fn create_publish_group(state: AppState, request: CreatePublishGroupRequest) -> Response {
+    let ttl_secs = request.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    let max_secs = state.config.publish_group_max_ttl_secs;
+    if ttl_secs == 0 || ttl_secs > max_secs {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            format!("ttl_secs must be between 1 and {max_secs}"),
+        )
+        .into_response();
+    }
+    match item_stream_component::create_publish_group(&state, Duration::from_secs(ttl_secs)) {
+        Ok(group) => (StatusCode::CREATED, Json(group)).into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+pub async fn get_publish_group(state: AppState, group_id: String) -> Response {
+    match item_stream_component::publish_group(&state, &group_id) {
+        Ok(status) => Json(status).into_response(),
+        Err(error) => publish_group_error(error).into_response(),
+    }
+}
+
+/// Make every version staged in the group readable at once
+pub async fn commit_publish_group(state: AppState, group_id: String) -> Response {
+    match item_stream_component::commit_publish_group(&state, &group_id).await {
+        Ok(committed) => Json(committed).into_response(),
+        Err(error) => publish_group_error(error).into_response(),
+    }
+}
+
+pub async fn abort_publish_group(state: AppState, group_id: String) -> Response {
+    match item_stream_component::abort_publish_group(&state, &group_id).await {
+        Ok(aborted) => Json(aborted).into_response(),
+        Err(error) => publish_group_error(error).into_response(),
+    }
+}
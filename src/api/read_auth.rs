@@ -0,0 +1,122 @@
+use crate::component::item_stream_component;
+use crate::config::Config;
+use crate::logic::byte_range::ByteRange;
+use crate::state::AppState;
+
+use super::admin_api::{authorize_admin, bearer_token, is_token};
+use super::api_error::{ApiError, ErrorCode};
+use super::read_item_stream_api::ReadItemStreamQuery;
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Refuse requests of the routes it wraps that do not present the read token, if one
+/// is configured
+pub async fn require_read_token(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(error) = authorize_read(&state.config, request.headers()) {
+        return error.into_response();
+    }
+    next.run(request).await
+}
+
+/// With `STREAM_DB_READ_TOKEN` reads require `Authorization: Bearer <token>`, where the
+/// admin token is accepted too. Without one reads are open to anyone.
+pub fn authorize_read(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(read_token) = config.read_token.as_deref() else {
+        return Ok(());
+    };
+    match bearer_token(headers) {
+        Some(token)
+            if is_token(token, Some(read_token))
+                || is_token(token, config.admin_token.as_deref()) =>
+        {
+            Ok(())
+        }
+        Some(_) => Err(ApiError::new(ErrorCode::Forbidden, "Invalid read token")),
+        None => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Read token required",
+        )),
+    }
+}
+
+/// Presigning needs the read or the admin token, and is refused altogether when neither
+/// is configured
+pub fn authorize_presign(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let tokens = [config.read_token.as_deref(), config.admin_token.as_deref()];
+    if tokens.iter().all(Option::is_none) {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Presigning is disabled, set STREAM_DB_READ_TOKEN or STREAM_DB_ADMIN_TOKEN to enable it",
+        ));
+    }
+    match bearer_token(headers) {
+        Some(token) if tokens.iter().any(|expected| is_token(token, *expected)) => Ok(()),
+        Some(_) => Err(ApiError::new(ErrorCode::Forbidden, "Invalid token")),
+        None => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Read or admin token required",
+        )),
+    }
+}
+
+/// Authorize a read of `/read-item-stream/{item_id}/{version}`: by the signature of a
+/// presigned URL if it carries one, otherwise like every other read. The parameters of
+/// the presigned URL are taken out of `query`, and the byte range it restricts the read
+/// to is returned.
+pub fn authorize_version_read(
+    state: &AppState,
+    item_id: &str,
+    item_version: u64,
+    query: &mut ReadItemStreamQuery,
+    headers: &HeaderMap,
+) -> Result<Option<ByteRange>, ApiError> {
+    let expires = query.expires.take();
+    let range = query.range.take();
+    let Some(signature) = query.sig.take() else {
+        if expires.is_some() || range.is_some() {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "expires and range are only accepted along with the sig of a presigned URL",
+            ));
+        }
+        authorize_read(&state.config, headers)?;
+        return Ok(None);
+    };
+    item_stream_component::verify_presigned_read(
+        state,
+        item_id,
+        item_version,
+        expires,
+        range.as_deref(),
+        &signature,
+    )
+    .map_err(|error| ApiError::new(ErrorCode::Forbidden, error))
+}
+
+/// Whether a read asks for `unredacted=true`, which only readers presenting the admin
+/// token may, presigned URLs included
+pub fn authorize_unredacted(
+    config: &Config,
+    headers: &HeaderMap,
+    query: &ReadItemStreamQuery,
+) -> Result<bool, ApiError> {
+    if query.unredacted != Some(true) {
+        return Ok(false);
+    }
+    authorize_admin(config, headers).map_err(|_| {
+        ApiError::new(
+            ErrorCode::Forbidden,
+            "unredacted=true is only served to readers presenting the admin token",
+        )
+    })?;
+    Ok(true)
+}
@@ -1,38 +1,683 @@
 use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::log_control;
+use crate::logic::byte_range::ByteRange;
+use crate::logic::consistency::ConsistencyError;
+use crate::logic::item_stream_logic::{ReadError, ReadFormat, ReadOptions, ReadWait};
+use crate::logic::property_records::NDJSON_KEEPALIVE;
+use crate::logic::xml_layout::XmlLayout;
+use crate::persistence::file_persistence::{self, ReadCondition, ReadDurability};
+use crate::state::{AppState, StreamDb};
+use crate::types::dto::{
+    AsOfDetails, ByteRangeDetails, CancelledDetails, EncodedRangeDetails,
+    PropertyOutOfRangeDetails, SegmentOutOfRangeDetails, TagDetails, WaitTimedOutDetails,
+};
+
+use super::api_error::{ApiError, ErrorCode, cancelled_code};
+use super::read_auth;
 
 use async_stream::stream;
 use axum::{
     body::Body,
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
 };
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Handed to producers with their write receipt, reads presenting it are only served once
+/// the node has the written version
+pub const CONSISTENCY_TOKEN_HEADER: &str = "X-Consistency-Token";
+
+/// Names the read source a read's version was found in, also logged by the access log
+pub const SERVED_FROM_HEADER: &str = "X-Served-From";
+
+/// Sent to idle property-aligned readers, see `STREAM_DB_READ_KEEPALIVE_SECS`
+const KEEPALIVE_COMMENT: &[u8] = b"<!-- keepalive -->";
+/// Sent right away to readers asking for `prime=true`, before any data is available
+const PRIME_COMMENT: &[u8] = b"<!-- stream-start -->";
 
-pub fn init() -> Result<(), String> {
+/// Query parameters accepted by the read endpoint
+#[derive(Deserialize, Default)]
+pub struct ReadItemStreamQuery {
+    /// `property` to only receive chunks ending on complete property elements
+    pub align: Option<String>,
+    /// Start at this property (0-based), e.g. to resume after a crash
+    pub from_property: Option<u64>,
+    /// Only read this segment (0-based) of a version uploaded framed
+    pub segment: Option<u64>,
+    /// `committed` to only receive bytes already synced to disk, `written` (the default)
+    /// to receive them as soon as they are written
+    pub durability: Option<String>,
+    /// `ndjson` to receive every property as a line of JSON, `xml` (the default) for the
+    /// stored bytes
+    pub format: Option<String>,
+    /// Name of a transform stored through `/admin/transforms` to reshape the properties with
+    pub transform: Option<String>,
+    /// Comma-separated names of the only properties to send, by their stored names
+    pub properties: Option<String>,
+    /// `pretty` or `minified` to lay out the XML sent, `verbatim` by default
+    pub xml: Option<String>,
+    /// Send a comment (an empty line for NDJSON) before any data, so proxies that hold
+    /// headers back until the first body frame pass the response on at once
+    pub prime: Option<bool>,
+    /// Only respond once the version has at least this many bytes, or is finished
+    pub min_bytes: Option<u64>,
+    /// `finished` to only respond once the version is committed
+    pub wait_for: Option<String>,
+    /// Seconds to wait for `min_bytes` or `wait_for` before giving up, 30 by default
+    pub wait_timeout: Option<u64>,
+    /// Unix seconds a presigned URL expires at
+    pub expires: Option<i64>,
+    /// Bytes a presigned URL is restricted to, `START-END`
+    pub range: Option<String>,
+    /// Signature of a presigned URL, see `POST /items/{item_id}/{version}/presign`
+    pub sig: Option<String>,
+    /// `true` to receive the properties the redaction policy drops or masks, for readers
+    /// presenting the admin token
+    pub unredacted: Option<bool>,
+    /// `true` to receive what an upload interrupted by a crash left on disk instead of
+    /// `410 Gone`
+    pub allow_partial: Option<bool>,
+    /// `true` to receive the defaults of the item's schema in place of the declared
+    /// properties the version lacks
+    pub apply_defaults: Option<bool>,
+}
+
+/// How long a read waits for `min_bytes` or `wait_for` unless it says otherwise
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 30;
+
+pub fn init(state: &StreamDb) -> Result<(), String> {
     println!("Initializing read item stream api");
-    item_stream_component::init()?;
+    item_stream_component::init(state)?;
 
     Ok(())
 }
 
-pub async fn read_item_stream(item_id: String, item_version: u64) -> impl IntoResponse {
-    let mut component = match ItemStreamComponent::new_reader(item_id, item_version) {
-        Ok(component) => component,
-        Err(_) => return (StatusCode::NOT_FOUND, "Item not found").into_response(),
+/// Wait for the version named by the request's consistency token, if it carries one
+pub async fn await_consistency(
+    state: &AppState,
+    item_id: &str,
+    headers: &HeaderMap,
+) -> Result<(), Response> {
+    let Some(token) = headers.get(CONSISTENCY_TOKEN_HEADER) else {
+        return Ok(());
     };
+    let Ok(token) = token.to_str() else {
+        return Err(
+            ApiError::new(ErrorCode::BadRequest, "Consistency token is malformed").into_response(),
+        );
+    };
+    match item_stream_component::await_consistency_token(state, item_id, token).await {
+        Ok(()) => Ok(()),
+        Err(ConsistencyError::Invalid(error)) => {
+            Err(ApiError::new(ErrorCode::BadRequest, error).into_response())
+        }
+        Err(ConsistencyError::Unsatisfied {
+            message,
+            retry_after_secs,
+        }) => {
+            println!("{message}");
+            Err((
+                [(header::RETRY_AFTER, retry_after_secs.to_string())],
+                ApiError::new(ErrorCode::Unavailable, message),
+            )
+                .into_response())
+        }
+        Err(ConsistencyError::Failed(error)) => Err(ApiError::internal(error).into_response()),
+    }
+}
+
+/// Options of a read taken from its query
+pub fn read_options(query: &ReadItemStreamQuery) -> Result<ReadOptions, ApiError> {
+    // Taken out by the only route accepting them, see `read_auth::authorize_version_read`
+    if query.expires.is_some() || query.range.is_some() || query.sig.is_some() {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "Presigned URLs are only accepted by /read-item-stream/{item_id}/{version}",
+        ));
+    }
+    let align_to_properties = match query.align.as_deref() {
+        None => false,
+        Some("property") => true,
+        Some(other) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                format!("Unsupported align mode: {other}"),
+            ));
+        }
+    };
+    let durability = match query.durability.as_deref().map(ReadDurability::parse) {
+        None => ReadDurability::default(),
+        Some(Ok(durability)) => durability,
+        Some(Err(error)) => return Err(ApiError::new(ErrorCode::BadRequest, error)),
+    };
+    let format = match query.format.as_deref().map(ReadFormat::parse) {
+        None => ReadFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(error)) => return Err(ApiError::new(ErrorCode::BadRequest, error)),
+    };
+    let xml_layout = match query.xml.as_deref().map(XmlLayout::parse) {
+        None => XmlLayout::default(),
+        Some(Ok(xml_layout)) => xml_layout,
+        Some(Err(error)) => return Err(ApiError::new(ErrorCode::BadRequest, error)),
+    };
+    if format != ReadFormat::Xml && xml_layout != XmlLayout::Verbatim {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("xml={} only applies to format=xml", xml_layout.name()),
+        ));
+    }
+    let finished = match query.wait_for.as_deref() {
+        None => false,
+        Some("finished") => true,
+        Some(other) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                format!("Unsupported wait_for condition: {other}"),
+            ));
+        }
+    };
+    let condition = ReadCondition {
+        min_bytes: query.min_bytes,
+        finished,
+    };
+    let wait = match query.wait_timeout {
+        Some(_) if condition == ReadCondition::default() => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "wait_timeout needs min_bytes or wait_for",
+            ));
+        }
+        _ if condition == ReadCondition::default() => None,
+        timeout => Some(ReadWait {
+            condition,
+            timeout: Duration::from_secs(timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS)),
+        }),
+    };
+    let properties = query
+        .properties
+        .as_deref()
+        .map(|names| property_filter(names.split(',').map(str::to_string).collect()))
+        .transpose()?;
+    let apply_defaults = query.apply_defaults.unwrap_or(false);
+    if query.segment.is_some()
+        && (align_to_properties
+            || query.from_property.is_some()
+            || query.transform.is_some()
+            || properties.is_some()
+            || format != ReadFormat::Xml
+            || xml_layout != XmlLayout::Verbatim
+            || apply_defaults)
+    {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "A segment read only reads the stored bytes, without align, from_property, transform, properties, format, xml or apply_defaults",
+        ));
+    }
+    // Whether a property is missing is only known from the whole document
+    if apply_defaults && query.from_property.is_some() {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "apply_defaults reads the whole version and cannot be combined with from_property",
+        ));
+    }
+    Ok(ReadOptions {
+        align_to_properties,
+        from_property: query.from_property,
+        durability,
+        format,
+        transform: query.transform.clone(),
+        properties,
+        xml_layout,
+        wait,
+        byte_range: None,
+        segment: query.segment,
+        accept_encoding: None,
+        // Only granted once the reader was checked, see `read_auth::authorize_unredacted`
+        unredacted: false,
+        allow_partial: query.allow_partial.unwrap_or(false),
+        apply_defaults,
+    })
+}
+
+/// Names of the properties a read is narrowed down to, which must not be empty
+pub fn property_filter(names: Vec<String>) -> Result<Vec<String>, ApiError> {
+    let names: Vec<String> = names
+        .into_iter()
+        .map(|name| name.trim().to_string())
+        .collect();
+    if names.iter().any(String::is_empty) {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "properties must list property names, none of them empty",
+        ));
+    }
+    Ok(names)
+}
+
+/// The response for a reader that could not be started
+pub fn read_error(error: ReadError) -> Response {
+    match error {
+        ReadError::NotFound(error) => ApiError::new(ErrorCode::NotFound, error).into_response(),
+        ReadError::Interrupted(error) => ApiError::new(ErrorCode::Aborted, error).into_response(),
+        ReadError::Deleted(error) => ApiError::new(ErrorCode::Deleted, error).into_response(),
+        ReadError::Quarantined(failure) => {
+            ApiError::new(ErrorCode::IntegrityFailure, failure.message())
+                .with_details(*failure)
+                .into_response()
+        }
+        ReadError::PropertyOutOfRange {
+            requested,
+            property_count,
+        } => {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Property-Count", property_count.into());
+            let error = ApiError::new(
+                ErrorCode::RangeNotSatisfiable,
+                format!(
+                    "Property {requested} is out of range, the item has {property_count} properties"
+                ),
+            )
+            .with_details(PropertyOutOfRangeDetails {
+                requested,
+                property_count,
+            });
+            (headers, error).into_response()
+        }
+        ReadError::SegmentOutOfRange {
+            requested,
+            segment_count,
+        } => {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Segment-Count", segment_count.into());
+            let error = ApiError::new(
+                ErrorCode::RangeNotSatisfiable,
+                format!(
+                    "Segment {requested} is out of range, the version has {segment_count} segments"
+                ),
+            )
+            .with_details(SegmentOutOfRangeDetails {
+                requested,
+                segment_count,
+            });
+            (headers, error).into_response()
+        }
+        ReadError::SegmentOfUncommitted(error) => {
+            ApiError::new(ErrorCode::NotCommitted, error).into_response()
+        }
+        ReadError::ByteRangeNotSatisfiable { range, size } => (
+            [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            ApiError::new(
+                ErrorCode::RangeNotSatisfiable,
+                format!(
+                    "Byte range starting at {} starts past the end of the version, \
+                     which holds {size} bytes",
+                    range.start
+                ),
+            )
+            .with_details(ByteRangeDetails {
+                range: range.to_string(),
+                size,
+            }),
+        )
+            .into_response(),
+        ReadError::ByteRangeOfEncoded {
+            range,
+            encoding,
+            size,
+        } => {
+            let mut headers = HeaderMap::new();
+            if let Some(size) = size {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes */{size}").parse().unwrap(),
+                );
+            }
+            let error = ApiError::new(
+                ErrorCode::RangeNotSatisfiable,
+                format!(
+                    "The version is stored {}-compressed, byte ranges of it are not served. \
+                     Read it whole instead.",
+                    encoding.name()
+                ),
+            )
+            .with_details(EncodedRangeDetails {
+                range: range.to_string(),
+                content_encoding: encoding.name().to_string(),
+                size,
+            });
+            (headers, error).into_response()
+        }
+        ReadError::ByteRangeOfRedacted { range } => ApiError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "Byte range {range} is not served, the version holds properties that are \
+                 redacted for this reader. Read it whole instead."
+            ),
+        )
+        .into_response(),
+        ReadError::UnknownTransform(error) => {
+            ApiError::new(ErrorCode::BadRequest, error).into_response()
+        }
+        ReadError::WaitTimedOut {
+            condition,
+            waited,
+            bytes_available,
+        } => {
+            let wanted = match (condition.min_bytes, condition.finished) {
+                (Some(min_bytes), true) => format!("{min_bytes} bytes and the commit"),
+                (Some(min_bytes), false) => format!("{min_bytes} bytes"),
+                (None, _) => "the commit".to_string(),
+            };
+            ApiError::new(
+                ErrorCode::Timeout,
+                format!(
+                    "Waited {}s for {wanted}, {bytes_available} bytes are available",
+                    waited.as_secs()
+                ),
+            )
+            .with_details(WaitTimedOutDetails {
+                min_bytes: condition.min_bytes,
+                wait_for: condition.finished.then(|| "finished".to_string()),
+                waited_secs: waited.as_secs(),
+                bytes_available,
+            })
+            .into_response()
+        }
+        ReadError::Cancelled(reason) => ApiError::new(
+            cancelled_code(reason),
+            file_persistence::cancelled_message(reason),
+        )
+        .with_details(CancelledDetails {
+            reason: reason.name().to_string(),
+            bytes_received: None,
+        })
+        .into_response(),
+        ReadError::Unavailable(error) => {
+            ApiError::new(ErrorCode::Unavailable, error).into_response()
+        }
+        ReadError::Failed(error) => ApiError::internal(error).into_response(),
+    }
+}
 
+pub async fn read_item_stream(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    mut query: ReadItemStreamQuery,
+    headers: HeaderMap,
+) -> Response {
+    let byte_range = match read_auth::authorize_version_read(
+        &state,
+        &item_id,
+        item_version,
+        &mut query,
+        &headers,
+    ) {
+        Ok(byte_range) => byte_range,
+        Err(error) => return error.into_response(),
+    };
+    stream_version(state, item_id, item_version, query, headers, byte_range).await
+}
+
+/// Stream a version to a reader that was authorized already, restricted to `byte_range`
+/// by a presigned URL. A `Range` header in `headers` is served too.
+pub async fn stream_version(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    query: ReadItemStreamQuery,
+    headers: HeaderMap,
+    byte_range: Option<ByteRange>,
+) -> Response {
+    if let Err(rejection) = await_consistency(&state, &item_id, &headers).await {
+        return rejection;
+    }
+    let mut options = match read_options(&query) {
+        Ok(options) => options,
+        Err(error) => return error.into_response(),
+    };
+    let requested_range = requested_range(&headers);
+    if byte_range.is_some() && requested_range.is_some() {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "A presigned URL restricted to a byte range cannot be combined with a Range header",
+        )
+        .into_response();
+    }
+    if options.segment.is_some() && (byte_range.is_some() || requested_range.is_some()) {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "A segment read cannot be combined with a Range header or a presigned URL restricted to a byte range",
+        )
+        .into_response();
+    }
+    if (byte_range.is_some() || requested_range.is_some())
+        && (options.align_to_properties
+            || options.from_property.is_some()
+            || options.transform.is_some()
+            || options.properties.is_some()
+            || options.format != ReadFormat::Xml
+            || options.xml_layout != XmlLayout::Verbatim
+            || options.apply_defaults)
+    {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "A read restricted to a byte range, by a Range header or a presigned URL, only reads the stored bytes, without align, from_property, transform, properties, format, xml or apply_defaults",
+        )
+        .into_response();
+    }
+    options.byte_range = byte_range.or(requested_range);
+    options.unredacted = match read_auth::authorize_unredacted(&state.config, &headers, &query) {
+        Ok(unredacted) => unredacted,
+        Err(error) => return error.into_response(),
+    };
+    options.accept_encoding = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    if state.log.debug_enabled(&item_id) {
+        log_control::debug(
+            &item_id,
+            item_version,
+            format_args!(
+                "read requested with byte range {:?}, from property {:?}, aligned to properties {}",
+                options.byte_range, options.from_property, options.align_to_properties
+            ),
+        );
+    }
+    let align_to_properties = options.align_to_properties;
+    let format = options.format;
+    let xml_layout = options.xml_layout;
+    let ndjson = format == ReadFormat::Ndjson;
+    let prime = query.prime.unwrap_or(false);
+    if prime && !ndjson && query.from_property.is_none() {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "prime=true needs from_property or format=ndjson, a document read from its start may begin with an XML declaration",
+        )
+        .into_response();
+    }
+
+    // The status only depends on setting up the reader, never on data being available.
+    // The headers go out as soon as the handler returns, while the body is still waiting
+    // for its first chunk.
+    let mut component =
+        match ItemStreamComponent::new_reader(&state, item_id.clone(), item_version, options).await
+        {
+            Ok(component) => component,
+            Err(error) => return read_error(error),
+        };
+    let stream_start = Instant::now();
+    let stream_start_time = chrono::Utc::now();
+
+    let epoch = component.epoch();
+    let storage_tier = component.storage_tier();
+    let served_from = component.served_from();
+    let content_length = component.content_length();
+    let redactions = component.redactions();
+    let redacted = component.is_redacted();
+    let defaults_applied = component.defaults_applied();
+    let schema_version = component.schema_version();
+    let property_count = component.property_count();
+    let defaulted = component.is_defaulted();
+    let salvaged = component.is_salvaged();
+    let stored_encoding = component.stored_encoding();
+    let content_encoding = component.content_encoding();
+    let etag = epoch.map(|epoch| {
+        // Changes whenever the version is deleted and written again. A layout, a byte
+        // range or a segment sends other bytes than stored, so it gets a tag of its own,
+        // and so do NDJSON, reads starting at a property, transformed reads, the compressed
+        // bytes of a version stored compressed, redacted reads and reads with the defaults
+        // of a schema version.
+        let mut layout = match (xml_layout, byte_range, query.segment) {
+            (XmlLayout::Verbatim, None, None) => String::new(),
+            (XmlLayout::Verbatim, Some(range), _) => format!(".{range}"),
+            (XmlLayout::Verbatim, None, Some(segment)) => format!(".segment{segment}"),
+            (xml_layout, _, _) => format!(".{}", xml_layout.name()),
+        };
+        if ndjson {
+            layout.push_str(".ndjson");
+        }
+        if let Some(from_property) = query.from_property {
+            layout.push_str(&format!(".from{from_property}"));
+        }
+        if let Some(transform_digest) = component.transform_digest() {
+            layout.push_str(&format!(".t{transform_digest}"));
+        }
+        if let Some(content_encoding) = content_encoding {
+            layout.push_str(&format!(".{}", content_encoding.name()));
+        }
+        if redacted {
+            layout.push_str(".redacted");
+        }
+        if let Some(schema_version) = schema_version.filter(|_| defaulted) {
+            layout.push_str(&format!(".schema{schema_version}"));
+        }
+        format!("W/\"{item_version}.{epoch}{layout}\"")
+    });
+    // Only a committed version keeps sending the same bytes under its tag
+    if let Some(etag) = etag
+        .as_ref()
+        .filter(|_| component.committed_size().is_some())
+        && if_none_match(&headers, etag)
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag.clone())]).into_response();
+    }
+    // Only committed versions know where a range ends
+    let content_range = match (requested_range, content_length, component.committed_size()) {
+        (None, _, _) => None,
+        (Some(range), Some(length), Some(size)) => Some(format!(
+            "bytes {}-{}/{size}",
+            range.start,
+            range.start + length - 1
+        )),
+        (Some(_), _, _) => {
+            return ApiError::new(
+                ErrorCode::Locked,
+                format!(
+                    "Version {item_version} of item {item_id} is still being uploaded, Range requests are only served for committed versions"
+                ),
+            )
+            .into_response();
+        }
+    };
+    let aligned = align_to_properties
+        || query.from_property.is_some()
+        || query.transform.is_some()
+        || query.properties.is_some()
+        || ndjson
+        || xml_layout != XmlLayout::Verbatim
+        || defaulted;
+    let keepalive_bytes = if ndjson {
+        NDJSON_KEEPALIVE
+    } else {
+        KEEPALIVE_COMMENT
+    };
+    // Comments can only be slipped in between complete properties, an unaligned stream
+    // may be paused in the middle of a tag. A committed version is read without pauses
+    // and its declared length leaves no room for them.
+    let keepalive = state
+        .config
+        .read_keepalive_secs
+        .filter(|_| aligned && content_length.is_none())
+        .map(Duration::from_secs);
+    // A committed version is sent without waiting, and its declared length leaves no
+    // room for a comment
+    let prime = prime && content_length.is_none();
+    let prime_bytes = if ndjson {
+        NDJSON_KEEPALIVE
+    } else {
+        PRIME_COMMENT
+    };
+
+    let metrics = state.metrics.clone();
     // Use async-stream to yield chunks back to Axum
     let response_stream = stream! {
+        // Once something went out, keep-alives can follow
+        let mut started = prime;
+        let mut first_chunk = true;
+        let mut sent = 0u64;
+        if prime {
+            yield Ok(axum::body::Bytes::from_static(prime_bytes));
+        }
         loop {
-            match component.read_chunk().await {
+            // Keep polling the same read while keep-alives go out, so no chunk is lost
+            let next = {
+                let read = component.read_chunk();
+                tokio::pin!(read);
+                loop {
+                    // Nothing goes out before the first chunk, which may carry an XML declaration
+                    let Some(period) = keepalive.filter(|_| started) else {
+                        break read.await;
+                    };
+                    match tokio::time::timeout(period, &mut read).await {
+                        Ok(next) => break next,
+                        Err(_) => yield Ok(axum::body::Bytes::from_static(keepalive_bytes)),
+                    }
+                }
+            };
+            started = true;
+            match next {
                 Ok(Some(chunk)) => {
+                    if first_chunk {
+                        first_chunk = false;
+                        metrics.read_first_bytes.increment();
+                        metrics.read_time_to_first_byte_milliseconds
+                            .add(stream_start.elapsed().as_millis() as u64);
+                    }
+                    sent += chunk.len() as u64;
+                    if let Some(length) = content_length.filter(|length| sent > *length) {
+                        yield Err(length_mismatch(&item_id, item_version, sent, length));
+                        break;
+                    }
                     // Yielding chunk as-is - ensure it's sent immediately
                     yield Ok::<axum::body::Bytes, std::io::Error>(axum::body::Bytes::from(chunk));
                 }
                 Ok(None) => {
+                    // Ending early would leave the client waiting for the missing bytes,
+                    // failing the body closes the connection instead
+                    if let Some(length) = content_length.filter(|length| sent != *length) {
+                        yield Err(length_mismatch(&item_id, item_version, sent, length));
+                    }
                     break;
                 }
                 Err(e) => {
-                    println!("Read error: {}", e);
+                    // The status is out already, the client only sees the stream end early
+                    let code = if let Some(reason) = component.cancel_reason() {
+                        cancelled_code(reason)
+                    } else if component.is_too_slow() {
+                        ErrorCode::Conflict
+                    } else if component.is_aborted() {
+                        ErrorCode::Aborted
+                    } else {
+                        ErrorCode::Internal
+                    };
+                    println!(
+                        "Read of item {item_id} version {item_version} failed with {}: {e}",
+                        code.name()
+                    );
                     yield Err(std::io::Error::other(e));
                     break;
                 }
@@ -41,12 +686,207 @@ pub async fn read_item_stream(item_id: String, item_version: u64) -> impl IntoRe
     };
 
     let mut headers = HeaderMap::new();
-    // Explicitly set chunked transfer encoding to ensure streaming behavior
-    headers.insert("Transfer-Encoding", "chunked".parse().unwrap());
+    match content_length {
+        // Lets clients show progress and check they got everything
+        Some(length) => headers.insert("Content-Length", length.into()),
+        // Explicitly set chunked transfer encoding to ensure streaming behavior
+        None => headers.insert("Transfer-Encoding", "chunked".parse().unwrap()),
+    };
     // Disable buffering on both server and proxy
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
     headers.insert("Cache-Control", "no-cache".parse().unwrap());
     headers.insert("Pragma", "no-cache".parse().unwrap());
+    headers.insert("X-Item-Version", item_version.into());
+    headers.insert(
+        "X-Stream-Start",
+        stream_start_time
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .parse()
+            .unwrap(),
+    );
+    if prime {
+        headers.insert("X-Stream-Prime", "comment".parse().unwrap());
+    }
+    if let Some(content_type) = format.content_type() {
+        headers.insert("Content-Type", content_type.parse().unwrap());
+    }
+    if let Some(storage_tier) = storage_tier {
+        // Cold versions are slower to read, so clients can adjust their expectations
+        headers.insert("X-Storage-Tier", storage_tier.name().parse().unwrap());
+    }
+    if let Some(served_from) = served_from {
+        headers.insert(SERVED_FROM_HEADER, served_from.name().parse().unwrap());
+    }
+    if let Some(etag) = &etag {
+        headers.insert("ETag", etag.parse().unwrap());
+    }
+    if let Some(from_property) = query.from_property {
+        headers.insert("X-First-Property-Index", from_property.into());
+    }
+    if let Some(redactions) = redactions {
+        headers.insert("X-Redactions-Applied", redactions.into());
+    }
+    if let Some(defaults_applied) = defaults_applied {
+        headers.insert("X-Defaults-Applied", defaults_applied.into());
+    }
+    if let Some(schema_version) = schema_version {
+        headers.insert("X-Schema-Version", schema_version.into());
+    }
+    if let Some(property_count) = property_count {
+        // Of the version as stored, whichever part of it is read
+        headers.insert("X-Property-Count", property_count.into());
+    }
+    if salvaged {
+        // What an interrupted upload left, which ends where the upload's data does
+        headers.insert("X-Stream-State", "aborted".parse().unwrap());
+        headers.insert("X-Partial-Content", "true".parse().unwrap());
+    }
+    if let Some(range) = byte_range {
+        headers.insert("X-Byte-Range", range.to_string().parse().unwrap());
+    }
+    if let Some(segment) = query.segment {
+        headers.insert("X-Segment", segment.into());
+    }
+    if let Some(content_encoding) = content_encoding {
+        headers.insert("Content-Encoding", content_encoding.name().parse().unwrap());
+    }
+    if stored_encoding.is_some() {
+        // Sent compressed or decoded depending on the reader
+        headers.insert("Vary", "Accept-Encoding".parse().unwrap());
+        headers.insert("Accept-Ranges", "none".parse().unwrap());
+    } else if content_length.is_some() && !aligned && query.segment.is_none() {
+        headers.insert("Accept-Ranges", "bytes".parse().unwrap());
+    }
+    if let Some(content_range) = content_range {
+        headers.insert("Content-Range", content_range.parse().unwrap());
+        return (
+            StatusCode::PARTIAL_CONTENT,
+            headers,
+            Body::from_stream(response_stream),
+        )
+            .into_response();
+    }
+    if let Some(transform) = query.transform.as_deref() {
+        // Names are validated when the transform is stored, so they are valid header values
+        headers.insert("X-Transform", transform.parse().unwrap());
+    }
+    if xml_layout != XmlLayout::Verbatim {
+        headers.insert("X-Xml-Layout", xml_layout.name().parse().unwrap());
+    }
+    match keepalive {
+        Some(period) => headers.insert(
+            "X-Keepalive",
+            format!("comment; interval={}", period.as_secs())
+                .parse()
+                .unwrap(),
+        ),
+        None => headers.insert("X-Keepalive", "none".parse().unwrap()),
+    };
+    if aligned && !ndjson {
+        // A single property above the ceiling is passed through unaligned. That is only
+        // known once its bytes are streamed, after these headers went out, so the client
+        // is told where the ceiling is rather than whether it was hit.
+        headers.insert("X-Property-Align", "property".parse().unwrap());
+        headers.insert(
+            "X-Property-Align-Max-Bytes",
+            state.config.align_max_property_bytes.into(),
+        );
+    }
 
     (headers, Body::from_stream(response_stream)).into_response()
 }
+
+/// Whether an `If-None-Match` header lists `etag`, compared weakly as for a `GET`
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// The stored bytes a `Range: bytes=START-END` or `bytes=START-` header asks for. Other
+/// forms, such as several ranges or a suffix, are ignored and the whole version is sent,
+/// as HTTP allows.
+fn requested_range(headers: &HeaderMap) -> Option<ByteRange> {
+    let range = headers.get(header::RANGE)?.to_str().ok()?;
+    ByteRange::parse(range.trim().strip_prefix("bytes=")?).ok()
+}
+
+fn length_mismatch(item_id: &str, item_version: u64, sent: u64, length: u64) -> std::io::Error {
+    println!(
+        "Read of item {item_id} version {item_version} returned {sent} bytes instead of the declared {length}, closing the connection"
+    );
+    std::io::Error::other(format!(
+        "Read returned {sent} bytes instead of the declared {length}"
+    ))
+}
+
+/// Streams the version that was the latest one at `timestamp`, an RFC 3339 timestamp
+/// with an offset, e.g. `2024-06-04T14:00:00+02:00`
+pub async fn read_item_stream_as_of(
+    state: AppState,
+    item_id: String,
+    timestamp: String,
+    query: ReadItemStreamQuery,
+    headers: HeaderMap,
+) -> Response {
+    let at = match DateTime::parse_from_rfc3339(&timestamp) {
+        Ok(at) => at.with_timezone(&Utc),
+        Err(error) => {
+            return ApiError::new(
+                ErrorCode::BadRequest,
+                format!("Invalid timestamp {timestamp:?}, expected RFC 3339: {error}"),
+            )
+            .into_response();
+        }
+    };
+    // Resolved once the node has caught up, like tags
+    if let Err(rejection) = await_consistency(&state, &item_id, &headers).await {
+        return rejection;
+    }
+    match item_stream_component::resolve_as_of(&state, &item_id, at) {
+        Ok(Some(item_version)) => {
+            read_item_stream(state, item_id, item_version, query, headers).await
+        }
+        Ok(None) => ApiError::new(
+            ErrorCode::NotFound,
+            format!("Item {item_id} has no version committed at or before {timestamp}"),
+        )
+        .with_details(AsOfDetails {
+            as_of: at.to_rfc3339(),
+        })
+        .into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Reads the version a tag points at. The tag is resolved once, so the response is a
+/// single complete version even if the tag moves while it streams.
+pub async fn read_tagged_item_stream(
+    state: AppState,
+    item_id: String,
+    tag: String,
+    query: ReadItemStreamQuery,
+    headers: HeaderMap,
+) -> Response {
+    // The tag is only resolved once the node has caught up, so it is at least as recent
+    // as what the producer saw
+    if let Err(rejection) = await_consistency(&state, &item_id, &headers).await {
+        return rejection;
+    }
+    match item_stream_component::resolve_version_tag(&state, &item_id, &tag) {
+        Ok(Some(item_version)) => {
+            read_item_stream(state, item_id, item_version, query, headers).await
+        }
+        Ok(None) => ApiError::new(
+            ErrorCode::NotFound,
+            format!("Item {item_id} has no tag {tag:?}"),
+        )
+        .with_details(TagDetails { tag })
+        .into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
@@ -0,0 +1,220 @@
+use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::persistence::file_persistence::VersionState;
+use crate::state::AppState;
+use crate::types::dto::{ErrorBody, ReadInfo, ReadSummary};
+
+use super::api_error::{ApiError, ErrorCode, cancelled_code};
+use super::read_auth;
+use super::read_item_stream_api::{
+    ReadItemStreamQuery, await_consistency, read_error, read_options,
+};
+use super::request_id::request_id;
+
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::{Semaphore, oneshot};
+
+/// Credits granted by a single message, larger grants are cut down to it
+const MAX_CREDIT_GRANT: u64 = 1_000_000;
+
+/// Sent by the client to allow the server that many more data frames
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadCredits {
+    pub credits: u64,
+}
+
+/// Stream a version over a WebSocket: an info frame, one binary frame per chunk read
+/// while the client has credits left, and a summary frame. The reader is opened before
+/// the upgrade, so a missing version is refused with a plain HTTP error.
+pub async fn read_item_ws(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    query: ReadItemStreamQuery,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if let Err(rejection) = await_consistency(&state, &item_id, &headers).await {
+        return rejection;
+    }
+    let mut options = match read_options(&query) {
+        Ok(options) => options,
+        Err(error) => return error.into_response(),
+    };
+    // The info frame has no way to tell the version was not committed
+    if options.allow_partial {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            "allow_partial is only accepted by /read-item-stream/{item_id}/{version}",
+        )
+        .into_response();
+    }
+    options.unredacted = match read_auth::authorize_unredacted(&state.config, &headers, &query) {
+        Ok(unredacted) => unredacted,
+        Err(error) => return error.into_response(),
+    };
+    let content_type = options.format.content_type().unwrap_or("application/xml");
+    let component =
+        match ItemStreamComponent::new_reader(&state, item_id.clone(), item_version, options).await
+        {
+            Ok(component) => component,
+            Err(error) => return read_error(error),
+        };
+    let (finished, size) =
+        match item_stream_component::version_state(&state, &item_id, item_version).await {
+            Ok(VersionState::Committed(committed)) => (true, committed.size.unwrap_or(0)),
+            Ok(VersionState::InFlight { bytes_written, .. }) => (false, bytes_written),
+            Ok(_) => (false, 0),
+            Err(error) => return ApiError::internal(error).into_response(),
+        };
+    let info = ReadInfo {
+        item_id,
+        version: item_version,
+        finished,
+        content_type: content_type.to_string(),
+        size,
+        redactions_applied: component.redactions(),
+        defaults_applied: component.defaults_applied(),
+        schema_version: component.schema_version(),
+    };
+
+    let request_id = request_id(&headers);
+    let expose_internal_errors = state.config.expose_internal_errors;
+    upgrade.on_upgrade(move |socket| {
+        stream(socket, component, info, request_id, expose_internal_errors)
+    })
+}
+
+async fn stream(
+    socket: WebSocket,
+    mut component: ItemStreamComponent,
+    info: ReadInfo,
+    request_id: String,
+    expose_internal_errors: bool,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    if send_json(&mut sender, &info).await.is_err() {
+        return;
+    }
+
+    // Credits arrive independently of the data going out, the reader is only polled once
+    // one is available
+    let credits = Arc::new(Semaphore::new(0));
+    let (closed_sender, mut closed) = oneshot::channel::<()>();
+    let control = {
+        let credits = credits.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = receiver.next().await {
+                match message {
+                    Message::Text(text) => match serde_json::from_str::<ReadCredits>(&text) {
+                        Ok(grant) => {
+                            credits.add_permits(grant.credits.min(MAX_CREDIT_GRANT) as usize)
+                        }
+                        Err(error) => println!("Ignoring WebSocket read control frame: {error}"),
+                    },
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            // Dropping the sender tells the reading side the client is gone
+            drop(closed_sender);
+        })
+    };
+
+    let mut hasher = Sha256::new();
+    let mut total_bytes = 0u64;
+    let outcome = loop {
+        tokio::select! {
+            permit = credits.acquire() => match permit {
+                Ok(permit) => permit.forget(),
+                Err(_) => break None,
+            },
+            _ = &mut closed => break None,
+        }
+        // A read cut short here is never resumed, the client is gone
+        let next = tokio::select! {
+            next = component.read_chunk() => next,
+            _ = &mut closed => break None,
+        };
+        match next {
+            Ok(Some(chunk)) => {
+                hasher.update(&chunk);
+                total_bytes += chunk.len() as u64;
+                if sender.send(Message::Binary(chunk.into())).await.is_err() {
+                    break None;
+                }
+            }
+            Ok(None) => break Some(Ok(())),
+            Err(error) => break Some(Err(error)),
+        }
+    };
+    control.abort();
+
+    let close = match outcome {
+        // Dropping the component releases the reader like a disconnected HTTP read
+        None => {
+            println!(
+                "WebSocket read of item {} version {} closed after {total_bytes} bytes",
+                info.item_id, info.version
+            );
+            return;
+        }
+        Some(Ok(())) => {
+            let summary = ReadSummary {
+                total_bytes,
+                sha256: format!("{:x}", hasher.finalize()),
+            };
+            let _ = send_json(&mut sender, &summary).await;
+            close_code::NORMAL
+        }
+        Some(Err(error)) => {
+            let code = if let Some(reason) = component.cancel_reason() {
+                cancelled_code(reason)
+            } else if component.is_too_slow() {
+                ErrorCode::Conflict
+            } else if component.is_aborted() {
+                ErrorCode::Aborted
+            } else {
+                ErrorCode::Internal
+            };
+            println!(
+                "WebSocket read {request_id} of item {} version {} failed with {}: {error}",
+                info.item_id,
+                info.version,
+                code.name()
+            );
+            let error = ApiError::new(code, error)
+                .with_request_id(Some(request_id))
+                .for_client(expose_internal_errors);
+            let _ = send_json(&mut sender, &ErrorBody::from(error)).await;
+            if code == ErrorCode::Unavailable {
+                close_code::AWAY
+            } else {
+                close_code::ERROR
+            }
+        }
+    };
+    let _ = sender
+        .send(Message::Close(Some(CloseFrame {
+            code: close,
+            reason: "".into(),
+        })))
+        .await;
+}
+
+async fn send_json(
+    sender: &mut SplitSink<WebSocket, Message>,
+    value: &impl Serialize,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(value).unwrap_or_default();
+    sender.send(Message::Text(text.into())).await
+}
@@ -0,0 +1,43 @@
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Refuse every request of the routes it wraps on a read-only instance
+pub async fn reject_writes(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config.read_only {
+        return read_only_error().into_response();
+    }
+    next.run(request).await
+}
+
+/// Refuse everything but `GET` and `HEAD` requests of the routes it wraps on a read-only
+/// instance
+pub async fn reject_mutations(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config.read_only && request.method() != Method::GET && request.method() != Method::HEAD
+    {
+        return read_only_error().into_response();
+    }
+    next.run(request).await
+}
+
+fn read_only_error() -> ApiError {
+    ApiError::new(
+        ErrorCode::ReadOnly,
+        "This instance is read-only, send changes to the writing instance",
+    )
+}
@@ -0,0 +1,15 @@
+use axum::http::HeaderMap;
+
+/// Header carrying the ID a client assigned to its request, echoed back on the response
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The client supplied request ID, or a freshly generated one
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
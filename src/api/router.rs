@@ -0,0 +1,760 @@
+use crate::api::path_params::{
+    GroupPath, ItemPath, NamePath, PathParams, RequestIdPath, TagPath, TierPath, TimestampPath,
+    VersionPath,
+};
+use crate::api::{
+    access_log, admin_api, api_error, download_manifest_api, draining, file_handles, health_api,
+    idempotency, item_commits_api, item_receipt_api, item_settings_api, item_stats_api,
+    item_version_api, materialize_api, metrics_api, presign_api, publish_groups_api, read_auth,
+    read_item_stream_api, read_item_ws_api, read_only, s3_api, s3_auth, search_api, selftest_api,
+    stat_batch_api, version_tags_api, write_item_stream_api, write_item_ws_api,
+};
+use crate::state::AppState;
+use crate::types::dto::{CreatePublishGroupRequest, PresignRequest};
+
+use std::collections::HashMap;
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, Request},
+    middleware,
+    routing::{delete, get, post, put},
+};
+
+/// Endpoints that only read, safe to expose publicly
+pub fn read_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/read-item-stream/{item_id}/tag/{tag}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<TagPath>,
+                 Query(query): Query<read_item_stream_api::ReadItemStreamQuery>,
+                 headers: HeaderMap| async move {
+                    read_item_stream_api::read_tagged_item_stream(
+                        state, path.item_id, path.tag, query, headers,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/read-item-stream/{item_id}/as-of/{timestamp}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<TimestampPath>,
+                 Query(query): Query<read_item_stream_api::ReadItemStreamQuery>,
+                 headers: HeaderMap| async move {
+                    read_item_stream_api::read_item_stream_as_of(
+                        state, path.item_id, path.timestamp, query, headers,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/read-item-ws/{item_id}/{version}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Query(query): Query<read_item_stream_api::ReadItemStreamQuery>,
+                 headers: HeaderMap,
+                 upgrade: WebSocketUpgrade| async move {
+                    read_item_ws_api::read_item_ws(
+                        state, path.item_id, path.version, query, headers, upgrade,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/{version}/receipt",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<VersionPath>| async move {
+                    item_receipt_api::get_receipt(state, path.item_id, path.version).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/{version}/download-manifest",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Query(query): Query<download_manifest_api::DownloadManifestQuery>| async move {
+                    download_manifest_api::download_manifest(
+                        state,
+                        path.item_id,
+                        path.version,
+                        query,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/items/stat-batch",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap, Json(requests)| async move {
+                    stat_batch_api::stat_batch(state, headers, requests).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/versions",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<ItemPath>| async move {
+                    item_receipt_api::list_versions(state, path.item_id).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/settings",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<ItemPath>| async move {
+                    item_settings_api::get_item_settings(state, path.item_id).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/stats",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<ItemPath>,
+                 Query(query): Query<item_stats_api::ItemStatsQuery>| async move {
+                    item_stats_api::get_item_stats(state, path.item_id, query).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/version-tags",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<ItemPath>| async move {
+                    version_tags_api::list_version_tags(state, path.item_id).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/commits",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<ItemPath>| async move {
+                    item_commits_api::watch_commits(state, path.item_id).await
+                },
+            ),
+        )
+        .route(
+            "/search/properties",
+            get(
+                |State(state): State<AppState>,
+                 Query(query): Query<search_api::PropertySearchQuery>| async move {
+                    search_api::search_properties(state, query).await
+                },
+            ),
+        )
+        .route(
+            "/search/property-names",
+            get(
+                |State(state): State<AppState>,
+                 Query(query): Query<search_api::PropertyNamesQuery>| async move {
+                    search_api::search_property_names(state, query).await
+                },
+            ),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            read_auth::require_read_token,
+        ))
+        // Presigned reads present a signature instead of the read token, the handler
+        // checks either
+        .route(
+            "/read-item-stream/{item_id}/{version}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Query(query): Query<read_item_stream_api::ReadItemStreamQuery>,
+                 headers: HeaderMap| async move {
+                    read_item_stream_api::read_item_stream(
+                        state, path.item_id, path.version, query, headers,
+                    )
+                    .await
+                },
+            ),
+        )
+        // Health keeps answering while draining, to take the instance out of rotation
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            file_handles::reject_without_file_handles,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            draining::reject_new_streams,
+        ))
+        .route("/health", get(health_api::get_health))
+        .route(
+            "/items/{item_id}/{version}/presign",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap,
+                 request: Option<Json<PresignRequest>>| async move {
+                    let Json(request) = request.unwrap_or_default();
+                    presign_api::presign(state, path.item_id, path.version, headers, request)
+                        .await
+                },
+            ),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_error::render_errors,
+        ))
+        .with_state(state)
+}
+
+/// Endpoints that upload, change or delete items, refused by read-only instances
+pub fn write_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/items/{item_id}/{version}",
+            delete(
+                |State(state): State<AppState>, PathParams(path): PathParams<VersionPath>| async move {
+                    item_version_api::delete_version(state, path.item_id, path.version).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/{version}/immutability",
+            put(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Json(request)| async move {
+                    item_version_api::put_immutability(state, path.item_id, path.version, request)
+                        .await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/settings",
+            put(
+                |State(state): State<AppState>, PathParams(path): PathParams<ItemPath>, Json(settings)| async move {
+                    item_settings_api::put_item_settings(state, path.item_id, settings).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/version-tags/{tag}",
+            put(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<TagPath>,
+                 Json(target)| async move {
+                    version_tags_api::put_version_tag(state, path.item_id, path.tag, target).await
+                },
+            )
+            .delete(
+                |State(state): State<AppState>, PathParams(path): PathParams<TagPath>| async move {
+                    version_tags_api::delete_version_tag(state, path.item_id, path.tag).await
+                },
+            ),
+        )
+        .route(
+            "/publish-groups",
+            post(
+                |State(state): State<AppState>,
+                 request: Option<Json<CreatePublishGroupRequest>>| async move {
+                    let Json(request) = request.unwrap_or_default();
+                    publish_groups_api::create_publish_group(state, request).await
+                },
+            ),
+        )
+        .route(
+            "/publish-groups/{group_id}",
+            get(
+                |State(state): State<AppState>, PathParams(path): PathParams<GroupPath>| async move {
+                    publish_groups_api::get_publish_group(state, path.group_id).await
+                },
+            ),
+        )
+        .route(
+            "/publish-groups/{group_id}/commit",
+            post(
+                |State(state): State<AppState>, PathParams(path): PathParams<GroupPath>| async move {
+                    publish_groups_api::commit_publish_group(state, path.group_id).await
+                },
+            ),
+        )
+        .route(
+            "/publish-groups/{group_id}/abort",
+            post(
+                |State(state): State<AppState>, PathParams(path): PathParams<GroupPath>| async move {
+                    publish_groups_api::abort_publish_group(state, path.group_id).await
+                },
+            ),
+        )
+        // Uploads are streamed and never replayed, so they are added after the layer
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::replay,
+        ))
+        .route(
+            "/items",
+            post(
+                |State(state): State<AppState>,
+                 Query(query): Query<write_item_stream_api::WriteItemStreamQuery>,
+                 request: Request<Body>| async move {
+                    write_item_stream_api::create_item(state, query, request).await
+                },
+            ),
+        )
+        .route(
+            "/write-item-stream/{item_id}/{version}",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Query(query): Query<write_item_stream_api::WriteItemStreamQuery>,
+                 request: Request<Body>| async move {
+                    write_item_stream_api::write_item_stream(
+                        state, path.item_id, path.version, query, request,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/{version}/materialize",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap,
+                 Json(request)| async move {
+                    materialize_api::materialize(
+                        state, path.item_id, path.version, headers, request,
+                    )
+                    .await
+                },
+            ),
+        )
+        .route(
+            "/write-item-ws/{item_id}/{version}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 Query(query): Query<write_item_stream_api::WriteItemStreamQuery>,
+                 headers: HeaderMap,
+                 upgrade: WebSocketUpgrade| async move {
+                    write_item_ws_api::write_item_ws(
+                        state, path.item_id, path.version, query, headers, upgrade,
+                    )
+                    .await
+                },
+            ),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            file_handles::reject_without_file_handles,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            read_only::reject_writes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            draining::reject_new_streams,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_error::render_errors,
+        ))
+        .with_state(state)
+}
+
+/// Operational endpoints, meant for an internal listener. Read-only instances only
+/// serve the `GET` ones.
+pub fn admin_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/admin/streams/{item_id}/{version}/kill",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap| async move {
+                    admin_api::kill_stream(state, path.item_id, path.version, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/bulk-delete",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap, Json(filter)| async move {
+                    admin_api::bulk_delete(state, headers, filter).await
+                },
+            ),
+        )
+        .route(
+            "/admin/selftest",
+            post(
+                |State(state): State<AppState>,
+                 Query(query): Query<selftest_api::SelfTestQuery>,
+                 headers: HeaderMap| async move {
+                    selftest_api::selftest(state, query, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/reindex/{item_id}/{version}",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap| async move {
+                    admin_api::reindex(state, path.item_id, path.version, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/verify/{item_id}/{version}",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap| async move {
+                    admin_api::verify_version(state, path.item_id, path.version, headers).await
+                },
+            ),
+        )
+        .route(
+            "/items/{item_id}/{version}/unquarantine",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<VersionPath>,
+                 headers: HeaderMap| async move {
+                    admin_api::unquarantine(state, path.item_id, path.version, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/items/{item_id}/reset-version",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<ItemPath>,
+                 headers: HeaderMap,
+                 Json(request)| async move {
+                    admin_api::reset_version(state, path.item_id, headers, request).await
+                },
+            ),
+        )
+        .route(
+            "/admin/rebuild-property-index",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::rebuild_property_index(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/space-report",
+            get(
+                |State(state): State<AppState>,
+                 Query(query): Query<admin_api::SpaceReportQuery>,
+                 headers: HeaderMap| async move {
+                    admin_api::space_report(state, headers, query).await
+                },
+            ),
+        )
+        .route(
+            "/admin/backfill-metadata",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::backfill_metadata(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/hooks/test",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap, Json(request)| async move {
+                    admin_api::test_hook(state, headers, request).await
+                },
+            ),
+        )
+        .route(
+            "/admin/drain",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::drain(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/drain/force",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::force_drain(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/streams",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::streams(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/tier/{item_id}/{version}/{tier}",
+            post(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<TierPath>,
+                 headers: HeaderMap| async move {
+                    admin_api::move_version(state, path.item_id, path.version, path.tier, headers)
+                        .await
+                },
+            ),
+        )
+        .route(
+            "/admin/failed-uploads",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::failed_uploads(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/usage",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::usage(state, headers).await
+                },
+            )
+            .put(
+                |State(state): State<AppState>, headers: HeaderMap, Json(update)| async move {
+                    admin_api::set_quota(state, headers, update).await
+                },
+            ),
+        )
+        .route(
+            "/admin/storage",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::storage(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/warmup",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::warmup(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/config",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::config(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/config/reload",
+            post(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::reload_config(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/verification",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::verification(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/debug-captures",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::debug_captures(state, headers).await
+                },
+            )
+            .put(
+                |State(state): State<AppState>, headers: HeaderMap, Json(patterns)| async move {
+                    admin_api::set_debug_capture_patterns(state, headers, patterns).await
+                },
+            ),
+        )
+        .route(
+            "/admin/log-level",
+            put(
+                |State(state): State<AppState>, headers: HeaderMap, Json(request)| async move {
+                    admin_api::set_log_level(state, headers, request).await
+                },
+            ),
+        )
+        .route(
+            "/admin/debug-items",
+            put(
+                |State(state): State<AppState>, headers: HeaderMap, Json(request)| async move {
+                    admin_api::set_debug_items(state, headers, request).await
+                },
+            ),
+        )
+        .route(
+            "/admin/debug-captures/{request_id}",
+            get(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<RequestIdPath>,
+                 Query(query): Query<admin_api::DebugCaptureQuery>,
+                 headers: HeaderMap| async move {
+                    admin_api::debug_capture(state, path.request_id, query, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/transforms",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::transforms(state, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/transforms/{name}",
+            put(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<NamePath>,
+                 headers: HeaderMap,
+                 Json(spec)| async move {
+                    admin_api::set_transform(state, path.name, headers, spec).await
+                },
+            )
+            .delete(
+                |State(state): State<AppState>,
+                 PathParams(path): PathParams<NamePath>,
+                 headers: HeaderMap| async move {
+                    admin_api::delete_transform(state, path.name, headers).await
+                },
+            ),
+        )
+        .route(
+            "/admin/faults",
+            get(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::fault_rules(state, headers).await
+                },
+            )
+            .post(
+                |State(state): State<AppState>, headers: HeaderMap, Json(rule)| async move {
+                    admin_api::set_fault_rule(state, headers, rule).await
+                },
+            )
+            .delete(
+                |State(state): State<AppState>, headers: HeaderMap| async move {
+                    admin_api::clear_fault_rules(state, headers).await
+                },
+            ),
+        )
+        .route("/metrics", get(metrics_api::get_metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::replay,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            read_only::reject_mutations,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_error::render_errors,
+        ))
+        .with_state(state)
+}
+
+/// The S3-compatible API, served on its own listener since its paths are bucket names
+pub fn s3_app(state: AppState) -> Router {
+    let list = get(
+        |State(state): State<AppState>,
+         Path(bucket): Path<String>,
+         Query(query): Query<HashMap<String, String>>| async move {
+            s3_api::list_objects(state, bucket, query).await
+        },
+    )
+    .fallback(s3_api::not_implemented);
+    Router::new()
+        // Path-style clients list a bucket with a trailing slash
+        .route("/{bucket}", list.clone())
+        .route("/{bucket}/", list)
+        .route(
+            "/{bucket}/{*key}",
+            put(
+                |State(state): State<AppState>,
+                 Path((bucket, key)): Path<(String, String)>,
+                 Query(query): Query<HashMap<String, String>>,
+                 request: Request<Body>| async move {
+                    s3_api::put_object(state, bucket, key, query, request).await
+                },
+            )
+            .get(
+                |State(state): State<AppState>,
+                 Path((bucket, key)): Path<(String, String)>,
+                 Query(query): Query<HashMap<String, String>>,
+                 headers: HeaderMap| async move {
+                    s3_api::get_object(state, bucket, key, query, headers).await
+                },
+            )
+            .head(
+                |State(state): State<AppState>,
+                 Path((bucket, key)): Path<(String, String)>,
+                 Query(query): Query<HashMap<String, String>>| async move {
+                    s3_api::head_object(state, bucket, key, query).await
+                },
+            )
+            .delete(
+                |State(state): State<AppState>,
+                 Path((bucket, key)): Path<(String, String)>,
+                 Query(query): Query<HashMap<String, String>>| async move {
+                    s3_api::delete_object(state, bucket, key, query).await
+                },
+            )
+            .fallback(s3_api::not_implemented),
+        )
+        .fallback(s3_api::not_implemented)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            file_handles::reject_without_file_handles,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            read_only::reject_mutations,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            draining::reject_new_streams,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            s3_auth::require_signature,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            s3_api::render_s3_errors,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            access_log::log_transfers,
+        ))
+        .with_state(state)
+}
+
+/// All endpoints of one instance on a single router
+pub fn app(state: AppState) -> Router {
+    let app = read_routes(state.clone())
+        .merge(write_routes(state.clone()))
+        .merge(admin_routes(state.clone()));
+    #[cfg(feature = "web-ui")]
+    let app = app.merge(crate::api::web_ui::routes());
+    app.layer(middleware::from_fn_with_state(
+        state,
+        access_log::log_transfers,
+    ))
+}
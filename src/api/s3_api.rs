@@ -0,0 +1,648 @@
+use crate::component::item_stream_component;
+use crate::logic::aws_chunked::AwsChunkedDecoder;
+use crate::logic::s3_objects::{self, ListRequest, MAX_LIST_KEYS, ObjectListing};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ERROR_CODE_HEADER, ErrorCode, INTERNAL_ERROR_MESSAGE};
+use super::read_item_stream_api::{self, ReadItemStreamQuery};
+use super::request_id::{REQUEST_ID_HEADER, request_id};
+use super::s3_auth::uri_encode;
+use super::write_item_stream_api::{self, WriteItemStreamQuery};
+
+use async_stream::stream;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use quick_xml::escape::escape;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Header S3 clients read the request ID from
+const AMZ_REQUEST_ID_HEADER: &str = "x-amz-request-id";
+
+/// Prefix of the headers carrying user metadata
+const USER_METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// Most bytes of user metadata an object may carry, names and values together, as S3
+/// allows
+const MAX_USER_METADATA_BYTES: usize = 2048;
+
+/// Query parameter the AWS SDKs add to name the operation, which changes nothing
+const OPERATION_PARAMETER: &str = "x-id";
+
+/// Parameters of `ListObjectsV2` that are understood
+const LIST_PARAMETERS: [&str; 9] = [
+    "list-type",
+    "prefix",
+    "delimiter",
+    "max-keys",
+    "continuation-token",
+    "start-after",
+    "encoding-type",
+    "fetch-owner",
+    OPERATION_PARAMETER,
+];
+
+const XML_NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// A failed S3 request, sent as an S3 `<Error>` document by [`render_s3_errors`]
+#[derive(Clone, Debug)]
+pub struct S3Error {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl S3Error {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn not_implemented(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", message)
+    }
+
+    fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    fn render(&self, resource: &str, request_id: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message><Resource>{}</Resource><RequestId>{}</RequestId></Error>",
+            self.code,
+            escape(self.message.as_str()),
+            escape(resource),
+            escape(request_id)
+        )
+    }
+}
+
+/// The S3 error closest to an error of the native API
+impl From<ApiError> for S3Error {
+    fn from(error: ApiError) -> Self {
+        let (status, code) = match error.code {
+            ErrorCode::NotFound | ErrorCode::Deleted => (StatusCode::NOT_FOUND, "NoSuchKey"),
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidXml
+            | ErrorCode::LimitExceeded
+            | ErrorCode::TypeMismatch
+            | ErrorCode::DuplicateProperty
+            | ErrorCode::NotCommitted
+            | ErrorCode::ExpectationFailed
+            | ErrorCode::UnsupportedMediaType
+            | ErrorCode::IdempotencyKeyReused => (StatusCode::BAD_REQUEST, "InvalidArgument"),
+            ErrorCode::Unauthorized
+            | ErrorCode::Forbidden
+            | ErrorCode::ReadOnly
+            | ErrorCode::Immutable => (StatusCode::FORBIDDEN, "AccessDenied"),
+            ErrorCode::VersionConflict
+            | ErrorCode::VersionSuperseded
+            | ErrorCode::Locked
+            | ErrorCode::InFlightLimit
+            | ErrorCode::Conflict
+            | ErrorCode::Aborted => (StatusCode::CONFLICT, "OperationAborted"),
+            ErrorCode::PayloadTooLarge => (StatusCode::BAD_REQUEST, "EntityTooLarge"),
+            ErrorCode::QuotaExceeded => (StatusCode::FORBIDDEN, "QuotaExceeded"),
+            ErrorCode::RangeNotSatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange"),
+            ErrorCode::QueueFull => (StatusCode::SERVICE_UNAVAILABLE, "SlowDown"),
+            ErrorCode::Unavailable | ErrorCode::Timeout => {
+                (StatusCode::SERVICE_UNAVAILABLE, "ServiceUnavailable")
+            }
+            ErrorCode::NotImplemented => (StatusCode::NOT_IMPLEMENTED, "NotImplemented"),
+            ErrorCode::IntegrityFailure | ErrorCode::Internal => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "InternalError")
+            }
+        };
+        Self::new(status, code, error.message)
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let mut response = self.status.into_response();
+        // Rendered by `render_s3_errors`, which knows the request ID and resource
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Middleware giving every response of the S3 API an `x-amz-request-id`, and every error
+/// the shape of an S3 `<Error>` document, whether it was raised as an [`S3Error`] or as
+/// an [`ApiError`] of the native API the handlers go through. Internal errors are logged
+/// and stripped of their details like on the native API, see [`ApiError::for_client`].
+pub async fn render_s3_errors(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request_id(request.headers());
+    let described = format!("{} {}", request.method(), request.uri().path());
+    // The handlers look the ID up again, and have to find the same one
+    if let Ok(value) = request_id.parse() {
+        request.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let resource = request.uri().path().to_string();
+    let head = request.method() == Method::HEAD;
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(AMZ_REQUEST_ID_HEADER, value.clone());
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let mut error = match (
+        parts.extensions.remove::<S3Error>(),
+        parts.extensions.remove::<ApiError>(),
+    ) {
+        (Some(error), _) => error,
+        (None, Some(error)) => S3Error::from(error),
+        // Rejections of axum's extractors
+        (None, None) => {
+            let message = axum::body::to_bytes(body, 64 * 1024)
+                .await
+                .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+                .unwrap_or_default();
+            S3Error::from(ApiError::new(ErrorCode::from_status(status), message))
+        }
+    };
+    if error.code == "InternalError" {
+        println!("Request {request_id} {described} failed: {}", error.message);
+        if !state.config.expose_internal_errors {
+            error.message = INTERNAL_ERROR_MESSAGE.to_string();
+        }
+    }
+    parts.status = error.status;
+    parts.headers.remove(ERROR_CODE_HEADER);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml"),
+    );
+    // Responses to HEAD have no body to explain themselves in
+    let body = if head {
+        Body::empty()
+    } else {
+        Body::from(error.render(&resource, &request_id))
+    };
+    Response::from_parts(parts, body)
+}
+
+/// `PutObject`: store the body as the next version of the object's item. The body has to
+/// be property XML like any other upload.
+pub async fn put_object(
+    state: AppState,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+    request: Request<Body>,
+) -> Result<Response, S3Error> {
+    reject_parameters(&query, &[OPERATION_PARAMETER])?;
+    if request.headers().contains_key("x-amz-copy-source") {
+        return Err(S3Error::not_implemented("CopyObject is not supported"));
+    }
+    let item_id = item_id(&bucket, &key)?;
+    let user_metadata = user_metadata(request.headers())?;
+    let request_id = request_id(request.headers());
+    let mut options = write_item_stream_api::write_options(
+        &state,
+        request.headers(),
+        &WriteItemStreamQuery::default(),
+        &request_id,
+    )?;
+    options.user_metadata = user_metadata;
+    let item_version =
+        item_stream_component::s3_next_version(&state, &item_id).map_err(ApiError::internal)?;
+
+    let sha256_mismatch = Arc::new(AtomicBool::new(false));
+    let request = decode_body(request, sha256_mismatch.clone())?;
+    let receipt = match write_item_stream_api::upload_version(
+        &state,
+        item_id,
+        item_version,
+        options,
+        request,
+        &request_id,
+    )
+    .await
+    {
+        Ok(receipt) => receipt,
+        Err(_) if sha256_mismatch.load(Ordering::Relaxed) => {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "XAmzContentSHA256Mismatch",
+                "The body does not match the x-amz-content-sha256 header",
+            ));
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut headers = HeaderMap::new();
+    insert_version_headers(&mut headers, &receipt.committed);
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// `GetObject`: stream the object's latest version, `Range` requests included
+pub async fn get_object(
+    state: AppState,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+    headers: HeaderMap,
+) -> Result<Response, S3Error> {
+    reject_parameters(&query, &[OPERATION_PARAMETER])?;
+    let item_id = item_id(&bucket, &key)?;
+    let version = latest(&state, &item_id, &key)?;
+    let mut response = read_item_stream_api::stream_version(
+        state,
+        item_id,
+        version.version,
+        ReadItemStreamQuery::default(),
+        headers,
+        None,
+    )
+    .await;
+    if response.status().is_success() {
+        insert_version_headers(response.headers_mut(), &version);
+    }
+    Ok(response)
+}
+
+/// `HeadObject`: the headers `GetObject` would answer with, taken from the metadata
+pub async fn head_object(
+    state: AppState,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+) -> Result<Response, S3Error> {
+    reject_parameters(&query, &[OPERATION_PARAMETER])?;
+    let item_id = item_id(&bucket, &key)?;
+    let version = latest(&state, &item_id, &key)?;
+    let mut headers = HeaderMap::new();
+    insert_version_headers(&mut headers, &version);
+    if let Some(size) = version.size {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// `DeleteObject`: hide the object from the S3 API. Like S3, deleting a key that does not
+/// exist succeeds.
+pub async fn delete_object(
+    state: AppState,
+    bucket: String,
+    key: String,
+    query: HashMap<String, String>,
+) -> Result<Response, S3Error> {
+    reject_parameters(&query, &[OPERATION_PARAMETER])?;
+    let item_id = item_id(&bucket, &key)?;
+    item_stream_component::delete_s3_object(&state, &item_id)
+        .await
+        .map_err(|error| S3Error::from(write_item_stream_api::write_error(error)))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `ListObjectsV2`, the only bucket operation there is. Buckets are never created, they
+/// hold whatever objects were put into them.
+pub async fn list_objects(
+    state: AppState,
+    bucket: String,
+    query: HashMap<String, String>,
+) -> Result<Response, S3Error> {
+    if query.get("list-type").map(String::as_str) != Some("2") {
+        return Err(S3Error::not_implemented(
+            "Only ListObjectsV2 (list-type=2) is supported on buckets",
+        ));
+    }
+    reject_parameters(&query, &LIST_PARAMETERS)?;
+    s3_objects::validate_bucket(&bucket).map_err(invalid_bucket)?;
+    let url_encoded = match query.get("encoding-type").map(String::as_str) {
+        None => false,
+        Some("url") => true,
+        Some(_) => return Err(S3Error::invalid_argument("encoding-type must be url")),
+    };
+    let max_keys = match query.get("max-keys") {
+        None => MAX_LIST_KEYS,
+        Some(max_keys) => max_keys
+            .parse::<usize>()
+            .map_err(|_| S3Error::invalid_argument("max-keys must be a number"))?
+            .min(MAX_LIST_KEYS),
+    };
+    let continuation_token = query.get("continuation-token");
+    let resumed_after = match continuation_token {
+        None => None,
+        Some(token) => Some(
+            URL_SAFE_NO_PAD
+                .decode(token)
+                .ok()
+                .and_then(|key| String::from_utf8(key).ok())
+                .ok_or_else(|| S3Error::invalid_argument("The continuation token is invalid"))?,
+        ),
+    };
+    let start_after = query.get("start-after");
+    let request = ListRequest {
+        prefix: query.get("prefix").cloned().unwrap_or_default(),
+        delimiter: query.get("delimiter").cloned(),
+        start_after: resumed_after.clone().max(start_after.cloned()),
+        max_keys,
+    };
+    let prefix = request.prefix.clone();
+    let delimiter = request.delimiter.clone();
+    let listing = if max_keys == 0 {
+        ObjectListing::default()
+    } else {
+        item_stream_component::list_s3_objects(&state, &bucket, request)
+            .await
+            .map_err(ApiError::internal)?
+    };
+
+    let encode = |value: &str| {
+        if url_encoded {
+            uri_encode(value, false)
+        } else {
+            escape(value).into_owned()
+        }
+    };
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListBucketResult xmlns=\"{XML_NAMESPACE}\"><Name>{bucket}</Name><Prefix>{}</Prefix>",
+        encode(&prefix)
+    );
+    if let Some(delimiter) = &delimiter {
+        xml.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+    }
+    if url_encoded {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    if let Some(token) = continuation_token {
+        xml.push_str(&format!(
+            "<ContinuationToken>{}</ContinuationToken>",
+            escape(token.as_str())
+        ));
+    }
+    if let Some(start_after) = start_after {
+        xml.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+    }
+    xml.push_str(&format!(
+        "<MaxKeys>{max_keys}</MaxKeys><KeyCount>{}</KeyCount><IsTruncated>{}</IsTruncated>",
+        listing.objects.len() + listing.common_prefixes.len(),
+        listing.next_start_after.is_some()
+    ));
+    if let Some(next) = &listing.next_start_after {
+        xml.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            URL_SAFE_NO_PAD.encode(next)
+        ));
+    }
+    for object in &listing.objects {
+        xml.push_str(&format!("<Contents><Key>{}</Key>", encode(&object.key)));
+        if let Some(last_modified) = committed_at(&object.version) {
+            xml.push_str(&format!(
+                "<LastModified>{}</LastModified>",
+                last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            ));
+        }
+        if let Some(sha256) = &object.version.sha256 {
+            xml.push_str(&format!("<ETag>&quot;{sha256}&quot;</ETag>"));
+        }
+        xml.push_str(&format!(
+            "<Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            object.version.size.unwrap_or(0)
+        ));
+    }
+    for common_prefix in &listing.common_prefixes {
+        xml.push_str(&format!(
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            encode(common_prefix)
+        ));
+    }
+    xml.push_str("</ListBucketResult>");
+    Ok(([(header::CONTENT_TYPE, "application/xml")], xml).into_response())
+}
+
+/// Everything else S3 can do
+pub async fn not_implemented() -> S3Error {
+    S3Error::not_implemented("This operation is not supported by stream-db's S3 API")
+}
+
+fn reject_parameters(query: &HashMap<String, String>, allowed: &[&str]) -> Result<(), S3Error> {
+    match query
+        .keys()
+        .find(|parameter| !allowed.contains(&parameter.as_str()))
+    {
+        Some(parameter) => Err(S3Error::not_implemented(format!(
+            "The query parameter {parameter} is not supported"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn invalid_bucket(message: String) -> S3Error {
+    S3Error::new(StatusCode::BAD_REQUEST, "InvalidBucketName", message)
+}
+
+fn item_id(bucket: &str, key: &str) -> Result<String, S3Error> {
+    s3_objects::validate_bucket(bucket).map_err(invalid_bucket)?;
+    s3_objects::item_id(bucket, key)
+        .map_err(|message| S3Error::new(StatusCode::BAD_REQUEST, "KeyTooLongError", message))
+}
+
+fn latest(state: &AppState, item_id: &str, key: &str) -> Result<VersionMetadata, S3Error> {
+    item_stream_component::s3_object(state, item_id)
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| {
+            S3Error::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchKey",
+                format!("The key {key} does not exist"),
+            )
+        })
+}
+
+/// The `x-amz-meta-*` headers, without their prefix
+fn user_metadata(headers: &HeaderMap) -> Result<BTreeMap<String, String>, S3Error> {
+    let mut user_metadata = BTreeMap::new();
+    let mut size = 0;
+    for (name, value) in headers {
+        let Some(name) = name.as_str().strip_prefix(USER_METADATA_PREFIX) else {
+            continue;
+        };
+        let value = value.to_str().map_err(|_| {
+            S3Error::invalid_argument(format!(
+                "The value of {USER_METADATA_PREFIX}{name} must be ASCII"
+            ))
+        })?;
+        size += name.len() + value.len();
+        user_metadata.insert(name.to_string(), value.to_string());
+    }
+    if size > MAX_USER_METADATA_BYTES {
+        return Err(S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "MetadataTooLarge",
+            format!("User metadata must be at most {MAX_USER_METADATA_BYTES} bytes"),
+        ));
+    }
+    Ok(user_metadata)
+}
+
+/// The upload as the write endpoint expects it: XML, with the `aws-chunked` encoding
+/// streaming clients use taken off, and checked against `x-amz-content-sha256`.
+/// `sha256_mismatch` is set when the check fails the body.
+fn decode_body(
+    request: Request<Body>,
+    sha256_mismatch: Arc<AtomicBool>,
+) -> Result<Request<Body>, S3Error> {
+    let (mut parts, body) = request.into_parts();
+    let content_sha256 = parts
+        .headers
+        .get("x-amz-content-sha256")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let chunked = content_sha256.starts_with("streaming-")
+        || parts
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|encoding| encoding.contains("aws-chunked"));
+    // Signed payloads are hashed, `UNSIGNED-PAYLOAD` and streaming ones are not
+    let expected_sha256 = (content_sha256.len() == 64
+        && content_sha256.bytes().all(|byte| byte.is_ascii_hexdigit()))
+    .then_some(content_sha256);
+
+    if chunked {
+        // Encodings of the payload within the chunks, such as gzip, are left to the upload
+        let payload_encoding = parts
+            .headers
+            .remove(header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok().map(str::to_string))
+            .map(|encodings| {
+                encodings
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|encoding| {
+                        !encoding.is_empty() && !encoding.eq_ignore_ascii_case("aws-chunked")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .filter(|encodings| !encodings.is_empty());
+        if let Some(Ok(payload_encoding)) = payload_encoding.map(HeaderValue::try_from) {
+            parts
+                .headers
+                .insert(header::CONTENT_ENCODING, payload_encoding);
+        }
+        parts.headers.remove(header::CONTENT_LENGTH);
+        if let Some(decoded_length) = parts.headers.remove("x-amz-decoded-content-length") {
+            parts.headers.insert(header::CONTENT_LENGTH, decoded_length);
+        }
+    }
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml"),
+    );
+
+    let mut data = body.into_data_stream();
+    let body = Body::from_stream(stream! {
+        let mut decoder = chunked.then(AwsChunkedDecoder::default);
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = data.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    yield Err(std::io::Error::other(error));
+                    return;
+                }
+            };
+            let payload = match decoder.as_mut() {
+                Some(decoder) => match decoder.push(&chunk) {
+                    Ok(payload) => Bytes::from(payload),
+                    Err(error) => {
+                        yield Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error));
+                        return;
+                    }
+                },
+                None => chunk,
+            };
+            hasher.update(&payload);
+            if !payload.is_empty() {
+                yield Ok(payload);
+            }
+        }
+        if decoder.is_some_and(|decoder| !decoder.is_complete()) {
+            yield Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The body ended before its last chunk",
+            ));
+            return;
+        }
+        // Failing the body keeps the upload from being committed
+        if let Some(expected) = expected_sha256
+            && format!("{:x}", hasher.finalize()) != expected
+        {
+            sha256_mismatch.store(true, Ordering::Relaxed);
+            yield Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The body does not match x-amz-content-sha256",
+            ));
+        }
+    });
+    Ok(Request::from_parts(parts, body))
+}
+
+fn committed_at(version: &VersionMetadata) -> Option<DateTime<Utc>> {
+    version
+        .committed_at
+        .as_deref()
+        .and_then(|committed_at| DateTime::parse_from_rfc3339(committed_at).ok())
+        .map(|committed_at| committed_at.with_timezone(&Utc))
+}
+
+/// `ETag`, `Last-Modified`, the version and user metadata of an object
+fn insert_version_headers(headers: &mut HeaderMap, version: &VersionMetadata) {
+    if let Some(Ok(etag)) = version
+        .sha256
+        .as_ref()
+        .map(|sha256| HeaderValue::from_str(&format!("\"{sha256}\"")))
+    {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(Ok(last_modified)) = committed_at(version).map(|committed_at| {
+        HeaderValue::from_str(&committed_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+    }) {
+        headers.insert(header::LAST_MODIFIED, last_modified);
+    }
+    headers.insert("x-amz-version-id", HeaderValue::from(version.version));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml"),
+    );
+    for (name, value) in version.user_metadata.iter().flatten() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(format!("{USER_METADATA_PREFIX}{name}").as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::S3Credentials;
+use crate::logic::consistency::{constant_time_eq, hmac_sha256};
+use crate::state::AppState;
+
+use super::s3_api::S3Error;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Furthest a request's `x-amz-date` may be off the server's clock, as S3 allows
+const MAX_CLOCK_SKEW_MINUTES: i64 = 15;
+
+/// Refuse requests that are not signed with Signature Version 4 by the configured access
+/// key, if one is configured. Without one the S3 API is open to anyone reaching it.
+pub async fn require_signature(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(credentials) = &state.config.s3_credentials {
+        let uri = request.uri();
+        if let Err(error) = verify(
+            credentials,
+            request.method().as_str(),
+            uri.path(),
+            uri.query().unwrap_or_default(),
+            request.headers(),
+        ) {
+            return error.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Check the `Authorization` header of a request. The payload is covered by the
+/// signature through `x-amz-content-sha256`, which the upload checks against the body.
+fn verify(
+    credentials: &S3Credentials,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &HeaderMap,
+) -> Result<(), S3Error> {
+    let Some(authorization) = header(headers, "authorization") else {
+        let message = if query.contains("X-Amz-Signature=") {
+            "Presigned URLs are not supported, sign the request in its Authorization header"
+        } else {
+            "Requests must be signed with the configured access key"
+        };
+        return Err(S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", message));
+    };
+    let malformed = |message: &str| {
+        S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "AuthorizationHeaderMalformed",
+            message,
+        )
+    };
+    let fields = authorization
+        .strip_prefix(ALGORITHM)
+        .ok_or_else(|| malformed("Only AWS4-HMAC-SHA256 signatures are supported"))?;
+    let mut credential = None;
+    let mut signed_headers = None;
+    let mut signature = None;
+    for field in fields.split(',') {
+        match field.trim().split_once('=') {
+            Some(("Credential", value)) => credential = Some(value),
+            Some(("SignedHeaders", value)) => signed_headers = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => (),
+        }
+    }
+    let (Some(credential), Some(signed_headers), Some(signature)) =
+        (credential, signed_headers, signature)
+    else {
+        return Err(malformed(
+            "The Authorization header needs a Credential, SignedHeaders and Signature",
+        ));
+    };
+    // `{access key}/{date}/{region}/{service}/aws4_request`
+    let (access_key, scope) = credential
+        .split_once('/')
+        .ok_or_else(|| malformed("The Credential has no scope"))?;
+    if access_key != credentials.access_key {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key is not known to this instance",
+        ));
+    }
+    let scope_parts: Vec<&str> = scope.split('/').collect();
+    let [scope_date, region, service, "aws4_request"] = scope_parts[..] else {
+        return Err(malformed("The Credential scope is malformed"));
+    };
+
+    let request_time = header(headers, "x-amz-date")
+        .ok_or_else(|| malformed("Signed requests need an x-amz-date header"))?;
+    let signed_at = NaiveDateTime::parse_from_str(request_time, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| malformed("x-amz-date is not a date like 20240101T000000Z"))?
+        .and_utc();
+    if (Utc::now() - signed_at).num_minutes().abs() > MAX_CLOCK_SKEW_MINUTES {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "RequestTimeTooSkewed",
+            format!(
+                "The request was signed at {request_time}, more than {MAX_CLOCK_SKEW_MINUTES} minutes away from the server's time"
+            ),
+        ));
+    }
+    if !request_time.starts_with(scope_date) {
+        return Err(malformed(
+            "The date of the Credential scope does not match x-amz-date",
+        ));
+    }
+    let payload_hash = header(headers, "x-amz-content-sha256").ok_or_else(|| {
+        S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "Signed requests need an x-amz-content-sha256 header",
+        )
+    })?;
+
+    let mut canonical_headers = String::new();
+    for name in signed_headers.split(';') {
+        let values: Vec<String> = headers
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                String::from_utf8_lossy(value.as_bytes())
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        canonical_headers.push_str(&format!("{name}:{}\n", values.join(",")));
+    }
+    let canonical_request = format!(
+        "{method}\n{path}\n{}\n{canonical_headers}\n{signed_headers}\n{payload_hash}",
+        canonical_query(query)
+    );
+    let string_to_sign = format!(
+        "{ALGORITHM}\n{request_time}\n{scope}\n{:x}",
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let signing_key = [scope_date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_key).into_bytes(),
+        |key, part| hmac_sha256(&key, part.as_bytes()).to_vec(),
+    );
+    let expected = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature does not match the one computed with the secret key",
+        ));
+    }
+    Ok(())
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The query parameters sorted by name and value, each encoded the way SigV4 encodes
+/// them, whichever way the client encoded them on the wire
+fn canonical_query(query: &str) -> String {
+    let mut parameters: Vec<(String, String)> = query
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (
+                uri_encode(&percent_decode(name), true),
+                uri_encode(&percent_decode(value), true),
+            )
+        })
+        .collect();
+    parameters.sort();
+    parameters
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Percent-encode everything but the unreserved characters, and `/` unless `encode_slash`
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        let escaped = (byte == b'%')
+            .then(|| {
+                let hex = [bytes.clone().next()?, bytes.clone().nth(1)?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()
+            })
+            .flatten();
+        match escaped {
+            Some(escaped) => {
+                decoded.push(escaped);
+                bytes.nth(1);
+            }
+            None => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,92 @@
+use crate::component::item_stream_component;
+use crate::logic::property_search::{MAX_SEARCH_LIMIT, SearchError};
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    Json,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+/// Results per page unless `limit` asks for another number
+const DEFAULT_LIMIT: usize = 100;
+
+/// Query parameters of `GET /search/properties`
+#[derive(Deserialize)]
+pub struct PropertySearchQuery {
+    pub name: String,
+    /// Only match the latest committed version of each item
+    #[serde(default)]
+    pub latest_only: bool,
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+/// Query parameters of `GET /search/property-names`
+#[derive(Deserialize)]
+pub struct PropertyNamesQuery {
+    #[serde(default)]
+    pub prefix: String,
+    pub limit: Option<usize>,
+}
+
+pub(crate) fn search_error(error: SearchError) -> Response {
+    let message = error.message();
+    match error {
+        SearchError::Disabled => ApiError::new(ErrorCode::Forbidden, message).into_response(),
+        SearchError::NotReady => (
+            [(header::RETRY_AFTER, "5")],
+            ApiError::new(ErrorCode::Unavailable, message),
+        )
+            .into_response(),
+        SearchError::InvalidCursor(_) => {
+            ApiError::new(ErrorCode::BadRequest, message).into_response()
+        }
+        SearchError::Failed(_) => ApiError::internal(message).into_response(),
+    }
+}
+
+fn limit(requested: Option<usize>) -> Result<usize, ApiError> {
+    match requested.unwrap_or(DEFAULT_LIMIT) {
+        limit @ 1..=MAX_SEARCH_LIMIT => Ok(limit),
+        _ => Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("limit must be between 1 and {MAX_SEARCH_LIMIT}"),
+        )),
+    }
+}
+
+/// Items with a committed version holding a property called `name`, paged in the order
+/// of item ID and version
+pub async fn search_properties(state: AppState, query: PropertySearchQuery) -> Response {
+    let limit = match limit(query.limit) {
+        Ok(limit) => limit,
+        Err(rejection) => return rejection.into_response(),
+    };
+    match item_stream_component::search_properties(
+        &state,
+        &query.name,
+        query.latest_only,
+        limit,
+        query.cursor.as_deref(),
+    ) {
+        Ok(page) => Json(page).into_response(),
+        Err(error) => search_error(error),
+    }
+}
+
+/// Property names starting with `prefix`, in order, for autocompletion
+pub async fn search_property_names(state: AppState, query: PropertyNamesQuery) -> Response {
+    let limit = match limit(query.limit) {
+        Ok(limit) => limit,
+        Err(rejection) => return rejection.into_response(),
+    };
+    match item_stream_component::search_property_names(&state, &query.prefix, limit) {
+        Ok(page) => Json(page).into_response(),
+        Err(error) => search_error(error),
+    }
+}
@@ -0,0 +1,237 @@
+use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::logic::item_stream_logic::{ReadOptions, WriteOptions};
+use crate::state::AppState;
+
+use super::admin_api::authorize_admin;
+use super::api_error::{ApiError, ErrorCode};
+use super::request_id::request_id;
+use super::write_item_stream_api::{ingest_error, write_error};
+
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+
+/// Item the self-test writes its versions to, each run deletes its version again
+const SELFTEST_ITEM_ID: &str = "stream-db-selftest";
+const DEFAULT_SIZE_MB: u64 = 64;
+const MIB: u64 = 1024 * 1024;
+/// Random bytes per property, hex encoded into twice as many
+const PROPERTY_RANDOM_BYTES: usize = 2048;
+/// Properties handed to the upload at a time, about 1 MiB
+const PROPERTIES_PER_CHUNK: usize = 256;
+
+/// Query parameters of `POST /admin/selftest`
+#[derive(Deserialize, Default)]
+pub struct SelfTestQuery {
+    pub size_mb: Option<u64>,
+}
+
+/// Outcome of a self-test round trip
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    pub item_id: &'static str,
+    pub version: u64,
+    /// Bytes uploaded, before the envelope is added
+    pub bytes_sent: u64,
+    /// Bytes stored according to the receipt
+    pub bytes_written: u64,
+    pub write_secs: f64,
+    pub write_mib_per_sec: f64,
+    pub bytes_read: u64,
+    pub read_secs: f64,
+    pub read_mib_per_sec: f64,
+    /// Checksum of the receipt
+    pub written_sha256: String,
+    /// Checksum of the bytes read back
+    pub read_sha256: String,
+    /// Whether everything written was read back unchanged
+    pub passed: bool,
+    /// Whether the version could be deleted again afterwards
+    pub cleaned_up: bool,
+}
+
+/// Write `size_mb` MiB of random properties through the same upload path as
+/// `POST /write-item-stream`, read them back like `GET /read-item-stream` and compare
+/// the checksums, to validate the storage of a new deployment. Answers `500` with the
+/// report if the round trip did not return what was written.
+pub async fn selftest(state: AppState, query: SelfTestQuery, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize_admin(&state.config, &headers) {
+        return rejection.into_response();
+    }
+    let size_mb = query.size_mb.unwrap_or(DEFAULT_SIZE_MB);
+    if size_mb == 0 || size_mb > state.config.selftest_max_mb {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            format!(
+                "size_mb must be between 1 and {} (STREAM_DB_SELFTEST_MAX_MB)",
+                state.config.selftest_max_mb
+            ),
+        )
+        .into_response();
+    }
+
+    // Later than every earlier run, so the version never conflicts with one
+    let item_version = chrono::Utc::now().timestamp_millis() as u64;
+    println!(
+        "Running self-test with {size_mb} MiB as version {item_version} of {SELFTEST_ITEM_ID}"
+    );
+    let report = round_trip(&state, item_version, size_mb * MIB, request_id(&headers)).await;
+
+    // Cleaned up whatever happened, a failed upload has nothing to delete
+    let cleaned_up = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            item_stream_component::delete_version(&state, SELFTEST_ITEM_ID, item_version).is_ok()
+        })
+        .await
+        .unwrap_or(false)
+    };
+
+    match report {
+        Ok(mut report) => {
+            report.cleaned_up = cleaned_up;
+            println!(
+                "Self-test {}: wrote {} bytes at {:.1} MiB/s, read {} bytes at {:.1} MiB/s",
+                if report.passed { "passed" } else { "FAILED" },
+                report.bytes_written,
+                report.write_mib_per_sec,
+                report.bytes_read,
+                report.read_mib_per_sec
+            );
+            let status = if report.passed {
+                StatusCode::OK
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            (status, Json(report)).into_response()
+        }
+        Err(error) => {
+            println!("Self-test failed: {}", error.message);
+            error.into_response()
+        }
+    }
+}
+
+async fn round_trip(
+    state: &AppState,
+    item_version: u64,
+    size: u64,
+    request_id: String,
+) -> Result<SelfTestReport, ApiError> {
+    let mut options = WriteOptions::new(&state.config);
+    options.request_id = Some(request_id);
+    let mut ingest =
+        ItemStreamComponent::new_writer(state, SELFTEST_ITEM_ID.to_string(), item_version, options)
+            .await
+            .map_err(write_error)?
+            .into_ingest(None)
+            .map_err(ingest_error)?;
+    let mut payload = RandomProperties::new();
+
+    let write_started = Instant::now();
+    while ingest.received_bytes() < size {
+        ingest
+            .push_bytes(payload.next_chunk().into())
+            .await
+            .map_err(ingest_error)?;
+    }
+    let bytes_sent = ingest.received_bytes();
+    let (committed, _) = ingest.finish().await.map_err(ingest_error)?;
+    let write_secs = write_started.elapsed().as_secs_f64();
+
+    let read_started = Instant::now();
+    let mut reader = ItemStreamComponent::new_reader(
+        state,
+        SELFTEST_ITEM_ID.to_string(),
+        item_version,
+        // Compared with what was written, which the redaction policy must not change
+        ReadOptions {
+            unredacted: true,
+            ..ReadOptions::default()
+        },
+    )
+    .await
+    .map_err(|_| ApiError::internal("The version just written could not be opened"))?;
+    let mut hasher = Sha256::new();
+    let mut bytes_read = 0u64;
+    while let Some(chunk) = reader.read_chunk().await.map_err(ApiError::internal)? {
+        hasher.update(&chunk);
+        bytes_read += chunk.len() as u64;
+    }
+    let read_secs = read_started.elapsed().as_secs_f64();
+
+    let bytes_written = committed.size.unwrap_or(0);
+    let written_sha256 = committed.sha256.unwrap_or_default();
+    let read_sha256 = format!("{:x}", hasher.finalize());
+    Ok(SelfTestReport {
+        item_id: SELFTEST_ITEM_ID,
+        version: item_version,
+        bytes_sent,
+        bytes_written,
+        write_secs,
+        write_mib_per_sec: mib_per_sec(bytes_written, write_secs),
+        bytes_read,
+        read_secs,
+        read_mib_per_sec: mib_per_sec(bytes_read, read_secs),
+        passed: bytes_read == bytes_written && written_sha256 == read_sha256,
+        written_sha256,
+        read_sha256,
+        cleaned_up: false,
+    })
+}
+
+fn mib_per_sec(bytes: u64, secs: f64) -> f64 {
+    if secs > 0.0 {
+        bytes as f64 / MIB as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Properties holding hex encoded pseudo-random bytes, seeded randomly per run
+struct RandomProperties {
+    state: u64,
+    count: u64,
+}
+
+impl RandomProperties {
+    fn new() -> Self {
+        // xorshift must not start from zero
+        let seed = uuid::Uuid::new_v4().as_u128() as u64 | 1;
+        Self {
+            state: seed,
+            count: 0,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn next_chunk(&mut self) -> Vec<u8> {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        let mut chunk = Vec::with_capacity(PROPERTIES_PER_CHUNK * (PROPERTY_RANDOM_BYTES * 2 + 64));
+        for _ in 0..PROPERTIES_PER_CHUNK {
+            chunk.extend_from_slice(
+                format!(r#"<property for="p{}"><string>"#, self.count).as_bytes(),
+            );
+            for _ in 0..PROPERTY_RANDOM_BYTES / 8 {
+                for byte in self.next_u64().to_le_bytes() {
+                    chunk.push(HEX[usize::from(byte >> 4)]);
+                    chunk.push(HEX[usize::from(byte & 0x0f)]);
+                }
+            }
+            chunk.extend_from_slice(b"</string></property>");
+            self.count += 1;
+        }
+        chunk
+    }
+}
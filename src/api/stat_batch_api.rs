@@ -0,0 +1,145 @@
+use crate::component::item_stream_component;
+use crate::logic::item_ids;
+use crate::persistence::file_persistence::{StatRequest, VersionStat};
+use crate::state::AppState;
+use crate::types::dto::{ErrorBody, StatBatchEntry, StatRefusal};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::path_params;
+
+use async_stream::stream;
+use axum::{
+    Json,
+    body::Body,
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use std::iter::Peekable;
+
+const NDJSON: &str = "application/x-ndjson";
+
+/// Whether, where and how big many versions are, for tools comparing a mirror against the
+/// instance without a `HEAD` request per version. Answers with a stat per request in the
+/// same order, as a JSON array, or as a line of JSON each while they are looked up when
+/// the client accepts `application/x-ndjson`. A request naming an invalid item ID is
+/// answered with its error in its place, the others are stat-ed regardless.
+pub async fn stat_batch(
+    state: AppState,
+    headers: HeaderMap,
+    requests: Vec<StatRequest>,
+) -> Response {
+    let max = state.config.stat_batch_max;
+    if requests.len() > max {
+        return ApiError::new(
+            ErrorCode::BadRequest,
+            format!(
+                "A batch may stat at most {max} versions, {} were asked for",
+                requests.len()
+            ),
+        )
+        .into_response();
+    }
+    let ndjson = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(NDJSON));
+
+    let mut entries = Entries::new(&requests);
+    let valid = requests
+        .into_iter()
+        .filter(|request| item_ids::validate_item_id(&request.item_id).is_ok())
+        .collect();
+    let mut chunks = Box::pin(item_stream_component::stat_versions(&state, valid));
+    if !ndjson {
+        let mut stats = Vec::new();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => stats.extend(entries.place(chunk)),
+                Err(error) => return ApiError::internal(error).into_response(),
+            }
+        }
+        stats.extend(entries.rest());
+        return Json(stats).into_response();
+    }
+
+    // The status is only sent once the first chunk was looked up, so a batch failing
+    // right away still gets an error response. A later failure cuts the body short.
+    let first = match chunks.next().await.transpose() {
+        Ok(first) => first,
+        Err(error) => return ApiError::internal(error).into_response(),
+    };
+    let lines = stream! {
+        let mut chunk = first;
+        while let Some(stats) = chunk {
+            yield Ok(ndjson_lines(entries.place(stats)));
+            chunk = match chunks.next().await.transpose() {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    println!("Stat batch failed: {error}");
+                    yield Err(std::io::Error::other(error));
+                    return;
+                }
+            };
+        }
+        yield Ok(ndjson_lines(entries.rest()));
+    };
+    ([(header::CONTENT_TYPE, NDJSON)], Body::from_stream(lines)).into_response()
+}
+
+fn ndjson_lines(entries: Vec<StatBatchEntry>) -> Vec<u8> {
+    let mut lines = Vec::new();
+    for entry in entries {
+        lines.extend(serde_json::to_vec(&entry).unwrap_or_default());
+        lines.push(b'\n');
+    }
+    lines
+}
+
+/// Puts the stats of the valid requests, which are stat-ed on their own, back in request
+/// order between the refusals of the invalid ones
+struct Entries {
+    /// A refusal for every invalid request, nothing for the valid ones
+    slots: Peekable<std::vec::IntoIter<Option<StatRefusal>>>,
+}
+
+impl Entries {
+    fn new(requests: &[StatRequest]) -> Self {
+        let slots: Vec<Option<StatRefusal>> = requests
+            .iter()
+            .map(|request| {
+                let message = item_ids::validate_item_id(&request.item_id).err()?;
+                let error = path_params::invalid("item_id", &request.item_id, message);
+                Some(StatRefusal {
+                    item_id: request.item_id.clone(),
+                    status: error.code.status().as_u16(),
+                    error: ErrorBody::from(error),
+                })
+            })
+            .collect();
+        Self {
+            slots: slots.into_iter().peekable(),
+        }
+    }
+
+    /// `stats` of the next valid requests, with the refusals before each of them
+    fn place(&mut self, stats: Vec<VersionStat>) -> Vec<StatBatchEntry> {
+        let mut entries = Vec::with_capacity(stats.len());
+        for stat in stats {
+            entries.extend(self.refusals());
+            self.slots.next();
+            entries.push(StatBatchEntry::Stat(stat));
+        }
+        entries
+    }
+
+    /// The refusals after the last valid request
+    fn rest(&mut self) -> Vec<StatBatchEntry> {
+        self.refusals().collect()
+    }
+
+    fn refusals(&mut self) -> impl Iterator<Item = StatBatchEntry> + '_ {
+        std::iter::from_fn(|| self.slots.next_if(Option::is_some).flatten())
+            .map(StatBatchEntry::Refused)
+    }
+}
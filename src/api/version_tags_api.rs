@@ -0,0 +1,60 @@
+use crate::component::item_stream_component;
+use crate::logic::version_tags::TagError;
+use crate::state::AppState;
+
+use super::api_error::{ApiError, ErrorCode};
+
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+/// Body of `PUT /items/{item_id}/version-tags/{tag}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagTarget {
+    pub version: u64,
+}
+
+fn tag_error_response(error: TagError) -> Response {
+    match error {
+        TagError::Invalid(error) => ApiError::new(ErrorCode::BadRequest, error),
+        TagError::NotCommitted(error) => ApiError::new(ErrorCode::NotCommitted, error),
+        TagError::NotFound(error) => ApiError::new(ErrorCode::NotFound, error),
+        TagError::Failed(error) => ApiError::internal(error),
+    }
+    .into_response()
+}
+
+pub async fn list_version_tags(state: AppState, item_id: String) -> impl IntoResponse {
+    match item_stream_component::version_tags(&state, &item_id) {
+        Ok(tags) => Json(tags).into_response(),
+        Err(error) => ApiError::internal(error).into_response(),
+    }
+}
+
+/// Points a tag at a committed version, readers resolve it on every request so moving
+/// it takes effect for the next read
+pub async fn put_version_tag(
+    state: AppState,
+    item_id: String,
+    tag: String,
+    target: TagTarget,
+) -> impl IntoResponse {
+    match item_stream_component::set_version_tag(&state, &item_id, &tag, target.version) {
+        Ok(tags) => Json(tags).into_response(),
+        Err(error) => tag_error_response(error),
+    }
+}
+
+pub async fn delete_version_tag(
+    state: AppState,
+    item_id: String,
+    tag: String,
+) -> impl IntoResponse {
+    match item_stream_component::remove_version_tag(&state, &item_id, &tag) {
+        Ok(tags) => Json(tags).into_response(),
+        Err(error) => tag_error_response(error),
+    }
+}
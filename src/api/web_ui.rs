@@ -0,0 +1,46 @@
+use axum::{
+    Router,
+    response::{IntoResponse, Redirect},
+    routing::get,
+};
+
+/// The browser UI, built into the binary so it needs nothing at runtime
+const INDEX_HTML: &str = include_str!("web_ui/index.html");
+const APP_JS: &str = include_str!("web_ui/app.js");
+const APP_CSS: &str = include_str!("web_ui/app.css");
+
+/// Only scripts, styles and requests of the instance itself, so the page cannot be made
+/// to load or send anything elsewhere
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; img-src 'self' data:";
+
+/// `GET /ui` and its assets. They hold no data: the page asks for the read or admin
+/// token and calls the public endpoints with it, which check it as for any other client.
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/ui", get(|| async { Redirect::permanent("/ui/") }))
+        .route(
+            "/ui/",
+            get(|| async { asset("text/html; charset=utf-8", INDEX_HTML) }),
+        )
+        .route(
+            "/ui/app.js",
+            get(|| async { asset("text/javascript; charset=utf-8", APP_JS) }),
+        )
+        .route(
+            "/ui/app.css",
+            get(|| async { asset("text/css; charset=utf-8", APP_CSS) }),
+        )
+}
+
+fn asset(content_type: &'static str, body: &'static str) -> impl IntoResponse {
+    (
+        [
+            ("Content-Type", content_type),
+            // Served from the binary, so a new build is the only thing changing them
+            ("Cache-Control", "no-cache"),
+            ("Content-Security-Policy", CONTENT_SECURITY_POLICY),
+            ("X-Content-Type-Options", "nosniff"),
+        ],
+        body,
+    )
+}
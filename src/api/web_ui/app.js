@@ -0,0 +1,432 @@
+"use strict";
+
+// Every endpoint the UI calls, all of them public ones checking the token like for any
+// other client
+const ENDPOINTS = {
+  streams: () => "/admin/streams",
+  spaceReport: () => "/admin/space-report?group_by=item&top=10000&threshold_bytes=" + Number.MAX_SAFE_INTEGER,
+  searchProperties: (name, latestOnly) =>
+    "/search/properties?limit=1000&name=" + encodeURIComponent(name) + "&latest_only=" + latestOnly,
+  versions: (item) => "/items/" + encodeURIComponent(item) + "/versions",
+  versionTags: (item) => "/items/" + encodeURIComponent(item) + "/version-tags",
+  settings: (item) => "/items/" + encodeURIComponent(item) + "/settings",
+  stats: (item) => "/items/" + encodeURIComponent(item) + "/stats",
+  commits: (item) => "/items/" + encodeURIComponent(item) + "/commits",
+  receipt: (item, version) => "/items/" + encodeURIComponent(item) + "/" + version + "/receipt",
+  version: (item, version) => "/items/" + encodeURIComponent(item) + "/" + version,
+  immutability: (item, version) => "/items/" + encodeURIComponent(item) + "/" + version + "/immutability",
+  read: (item, version) => "/read-item-stream/" + encodeURIComponent(item) + "/" + version,
+};
+
+const $ = (id) => document.getElementById(id);
+
+let token = sessionStorage.getItem("stream-db-token") || "";
+let admin = false;
+let currentItem = null;
+let listed = [];
+// Stops the tail or commit watch running in the details pane
+let running = null;
+
+function headers(extra) {
+  const result = Object.assign({}, extra);
+  if (token) {
+    result.Authorization = "Bearer " + token;
+  }
+  return result;
+}
+
+async function request(url, options) {
+  options = options || {};
+  const response = await fetch(url, Object.assign({}, options, { headers: headers(options.headers) }));
+  if (!response.ok) {
+    let message = response.status + " " + response.statusText;
+    try {
+      const error = await response.json();
+      message += ": " + (error.message || JSON.stringify(error));
+    } catch (ignored) {
+      // Not every error carries a JSON body
+    }
+    const error = new Error(message);
+    error.status = response.status;
+    throw error;
+  }
+  return response;
+}
+
+async function getJson(url) {
+  return (await request(url)).json();
+}
+
+function status(id, message, failed) {
+  const element = $(id);
+  element.textContent = message || "";
+  element.classList.toggle("error", Boolean(failed));
+}
+
+function badge(text, kind) {
+  const element = document.createElement("span");
+  element.className = "badge " + (kind || text);
+  element.textContent = text;
+  return element;
+}
+
+function button(text, onClick) {
+  const element = document.createElement("button");
+  element.type = "button";
+  element.textContent = text;
+  element.addEventListener("click", onClick);
+  return element;
+}
+
+// Lines of a streamed response as they arrive
+async function* lines(response) {
+  const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+  let pending = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) {
+      break;
+    }
+    pending += value;
+    const complete = pending.split("\n");
+    pending = complete.pop();
+    yield* complete;
+  }
+  if (pending) {
+    yield pending;
+  }
+}
+
+// Admin endpoints answer with 200 for the admin token only
+async function checkScope() {
+  admin = false;
+  if (token) {
+    try {
+      await request(ENDPOINTS.streams());
+      admin = true;
+    } catch (ignored) {
+      // A read token, or a wrong one the read endpoints will refuse
+    }
+  }
+  document.body.classList.toggle("admin", admin);
+  const scope = admin ? "admin" : token ? "read" : "no token";
+  $("scope").textContent = scope;
+  $("scope").className = "badge " + scope;
+  if (currentItem) {
+    openItem(currentItem);
+  }
+}
+
+function showItems(items, note) {
+  listed = items;
+  renderItems();
+  status("items-status", note);
+}
+
+function renderItems() {
+  const filter = $("filter").value.trim().toLowerCase();
+  const list = $("item-list");
+  list.replaceChildren();
+  for (const entry of listed) {
+    if (filter && !entry.item.toLowerCase().includes(filter)) {
+      continue;
+    }
+    const element = document.createElement("li");
+    element.textContent = entry.item;
+    if (entry.note) {
+      element.append(" ", badge(entry.note, entry.kind || ""));
+    }
+    element.addEventListener("click", () => openItem(entry.item));
+    list.append(element);
+  }
+}
+
+async function searchItems(name, latestOnly) {
+  status("items-status", "Searching…");
+  try {
+    const page = await getJson(ENDPOINTS.searchProperties(name, latestOnly));
+    const items = page.matches.map((match) => ({ item: match.item_id, note: "v" + match.version, kind: "tag" }));
+    showItems(items, items.length + " matches" + (page.next_cursor ? ", more not shown" : ""));
+  } catch (error) {
+    status("items-status", error.message, true);
+  }
+}
+
+// The space report lists every item with files on disk, its last line holds the groups
+async function listAllItems() {
+  status("items-status", "Scanning the data directory…");
+  try {
+    const response = await request(ENDPOINTS.spaceReport());
+    for await (const line of lines(response)) {
+      if (!line) {
+        continue;
+      }
+      const message = JSON.parse(line);
+      if (message.progress) {
+        status("items-status", "Scanned " + message.progress.items_scanned + " of " + message.progress.items_total + " items…");
+      } else if (message.error) {
+        throw new Error(message.error);
+      } else if (message.report) {
+        const items = message.report.groups.map((group) => ({ item: group.key, note: formatBytes(group.total_bytes), kind: "size" }));
+        showItems(items, message.report.groups_total + " items");
+      }
+    }
+  } catch (error) {
+    status("items-status", error.message, true);
+  }
+}
+
+async function listStreams() {
+  try {
+    const streams = await getJson(ENDPOINTS.streams());
+    showItems(
+      streams.map((stream) => ({ item: stream.item_id, note: "v" + stream.version + " " + stream.state, kind: stream.state })),
+      streams.length + " versions open",
+    );
+  } catch (error) {
+    status("items-status", error.message, true);
+  }
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit += 1;
+  }
+  return (unit ? bytes.toFixed(1) : bytes) + " " + units[unit];
+}
+
+async function openItem(item) {
+  currentItem = item;
+  $("item").hidden = false;
+  $("item-title").textContent = item;
+  status("item-status", "Loading…");
+  const rows = $("versions");
+  try {
+    const [versions, tags, streams] = await Promise.all([
+      getJson(ENDPOINTS.versions(item)).catch((error) => {
+        if (error.status === 404) {
+          return { versions: [] };
+        }
+        throw error;
+      }),
+      getJson(ENDPOINTS.versionTags(item)).catch(() => ({ tags: {} })),
+      admin ? getJson(ENDPOINTS.streams()).catch(() => []) : Promise.resolve([]),
+    ]);
+    rows.replaceChildren();
+    const open = streams.filter((stream) => stream.item_id === item && stream.state !== "committed");
+    for (const stream of open) {
+      rows.append(versionRow(item, { version: stream.version, size: stream.size }, [badge(stream.state)], false));
+    }
+    for (const receipt of versions.versions.slice().reverse()) {
+      rows.append(versionRow(item, receipt, versionBadges(receipt, tags.tags), true));
+    }
+    status("item-status", versions.versions.length + " committed versions" + (open.length ? ", " + open.length + " open" : ""));
+  } catch (error) {
+    rows.replaceChildren();
+    status("item-status", error.message, true);
+  }
+}
+
+function versionBadges(receipt, tags) {
+  const badges = [badge("committed")];
+  if (receipt.quarantined_at) {
+    badges.push(badge("quarantined"));
+  }
+  if (receipt.immutable_until && Date.parse(receipt.immutable_until) > Date.now()) {
+    badges.push(badge("immutable"));
+  }
+  if (receipt.hook_failed) {
+    badges.push(badge("hook failed", "hook-failed"));
+  }
+  for (const [tag, version] of Object.entries(tags)) {
+    if (version === receipt.version) {
+      badges.push(badge(tag, "tag"));
+    }
+  }
+  return badges;
+}
+
+function versionRow(item, receipt, badges, committed) {
+  const row = document.createElement("tr");
+  const cells = [
+    String(receipt.version),
+    badges,
+    receipt.size === undefined || receipt.size === null ? "" : formatBytes(receipt.size),
+    receipt.property_count === undefined || receipt.property_count === null ? "" : String(receipt.property_count),
+    receipt.committed_at ? new Date(receipt.committed_at).toLocaleString() : "",
+  ];
+  for (const content of cells) {
+    const cell = document.createElement("td");
+    cell.append(...[].concat(content));
+    row.append(cell);
+  }
+  const actions = document.createElement("td");
+  actions.className = "actions";
+  if (committed) {
+    actions.append(button("Receipt", () => showJson("Receipt of " + item + " v" + receipt.version, ENDPOINTS.receipt(item, receipt.version))));
+  }
+  actions.append(button(committed ? "Read" : "Tail", () => tail(item, receipt.version)));
+  if (committed && admin) {
+    actions.append(button("Pin", () => pin(item, receipt.version)));
+    actions.append(button("Delete", () => deleteVersion(item, receipt.version)));
+  }
+  row.append(actions);
+  return row;
+}
+
+function openDetails(title) {
+  stopRunning();
+  $("details").hidden = false;
+  $("details-title").textContent = title;
+  $("details-body").textContent = "";
+  status("details-status", "Loading…");
+}
+
+function stopRunning() {
+  if (running) {
+    running.abort();
+    running = null;
+  }
+  $("stop-tail").hidden = true;
+}
+
+async function showJson(title, url) {
+  openDetails(title);
+  try {
+    const body = await getJson(url);
+    $("details-body").textContent = JSON.stringify(body, null, 2);
+    status("details-status", "");
+  } catch (error) {
+    status("details-status", error.message, true);
+  }
+}
+
+// Streams the version as it is written, an in-flight one until its upload ends
+async function tail(item, version) {
+  openDetails(item + " v" + version);
+  const controller = new AbortController();
+  running = controller;
+  $("stop-tail").hidden = false;
+  const body = $("details-body");
+  let received = 0;
+  try {
+    const response = await request(ENDPOINTS.read(item, version), { signal: controller.signal });
+    status("details-status", "Streaming…");
+    const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+    for (;;) {
+      const { value, done } = await reader.read();
+      if (done) {
+        break;
+      }
+      received += value.length;
+      body.append(value);
+      body.scrollTop = body.scrollHeight;
+      status("details-status", "Streaming, " + received + " characters so far…");
+    }
+    status("details-status", "Complete, " + received + " characters");
+  } catch (error) {
+    status("details-status", controller.signal.aborted ? "Stopped" : error.message, !controller.signal.aborted);
+  } finally {
+    if (running === controller) {
+      running = null;
+      $("stop-tail").hidden = true;
+    }
+  }
+}
+
+// Server-sent events of the item's commits, read through fetch to send the token
+async function watchCommits(item) {
+  openDetails("Commits of " + item);
+  const controller = new AbortController();
+  running = controller;
+  $("stop-tail").hidden = false;
+  const body = $("details-body");
+  try {
+    const response = await request(ENDPOINTS.commits(item), { signal: controller.signal });
+    status("details-status", "Waiting for commits…");
+    let event = "message";
+    for await (const line of lines(response)) {
+      if (line.startsWith("event:")) {
+        event = line.slice(6).trim();
+      } else if (line.startsWith("data:")) {
+        body.append(new Date().toLocaleTimeString() + " " + event + " " + line.slice(5).trim() + "\n");
+        if (event === "commit" && currentItem === item) {
+          openItem(item);
+        }
+      } else if (!line) {
+        event = "message";
+      }
+    }
+    status("details-status", "The server ended the watch");
+  } catch (error) {
+    status("details-status", controller.signal.aborted ? "Stopped" : error.message, !controller.signal.aborted);
+  } finally {
+    if (running === controller) {
+      running = null;
+      $("stop-tail").hidden = true;
+    }
+  }
+}
+
+async function pin(item, version) {
+  const until = prompt("Keep " + item + " v" + version + " immutable until (RFC 3339)", new Date(Date.now() + 30 * 86400000).toISOString());
+  if (!until) {
+    return;
+  }
+  try {
+    await request(ENDPOINTS.immutability(item, version), {
+      method: "PUT",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ immutable_until: until }),
+    });
+    openItem(item);
+  } catch (error) {
+    status("item-status", error.message, true);
+  }
+}
+
+async function deleteVersion(item, version) {
+  if (!confirm("Delete " + item + " v" + version + "? This cannot be undone.")) {
+    return;
+  }
+  try {
+    await request(ENDPOINTS.version(item, version), { method: "DELETE" });
+    openItem(item);
+  } catch (error) {
+    status("item-status", error.message, true);
+  }
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+  $("token").value = token;
+  $("token-form").addEventListener("submit", (event) => {
+    event.preventDefault();
+    token = $("token").value.trim();
+    sessionStorage.setItem("stream-db-token", token);
+    checkScope();
+  });
+  $("open-form").addEventListener("submit", (event) => {
+    event.preventDefault();
+    openItem($("open-item").value.trim());
+  });
+  $("search-form").addEventListener("submit", (event) => {
+    event.preventDefault();
+    searchItems($("search-name").value.trim(), $("search-latest").checked);
+  });
+  $("list-items").addEventListener("click", listAllItems);
+  $("list-streams").addEventListener("click", listStreams);
+  $("filter").addEventListener("input", renderItems);
+  $("refresh-item").addEventListener("click", () => openItem(currentItem));
+  $("watch-commits").addEventListener("click", () => watchCommits(currentItem));
+  $("show-settings").addEventListener("click", () => showJson("Settings of " + currentItem, ENDPOINTS.settings(currentItem)));
+  $("show-stats").addEventListener("click", () => showJson("Stats of " + currentItem, ENDPOINTS.stats(currentItem)));
+  $("stop-tail").addEventListener("click", stopRunning);
+  $("close-details").addEventListener("click", () => {
+    stopRunning();
+    $("details").hidden = true;
+  });
+  checkScope();
+});
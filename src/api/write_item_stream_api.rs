@@ -1,24 +1,229 @@
 use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::log_control;
+use crate::logic::content_encoding::ContentEncoding;
+use crate::logic::framing::FRAMED_CONTENT_TYPE;
+use crate::logic::item_ids;
+use crate::logic::item_stream_logic::WriteOptions;
+use crate::logic::property_dedupe::DedupeMode;
+use crate::logic::stream_ingest::IngestError;
+use crate::logic::upload_completeness::CompletenessCheck;
+use crate::persistence::cancellation::CancelReason;
+use crate::persistence::debug_capture::DebugCapture;
+use crate::persistence::file_persistence::WriteError;
+use crate::persistence::item_metadata::Provenance;
+use crate::persistence::item_settings::Canonicalization;
+use crate::state::{AppState, StreamDb};
+use crate::types::dto::{
+    BytesReceivedDetails, CancelledDetails, ContentSniffDetails, InvalidUtf8Details,
+    VersionConflictDetails, VersionJumpDetails, WriteQueueDetails, WriteReceipt,
+};
+
+use super::admin_api::authorize_admin;
+use super::api_error::{ApiError, ErrorCode, cancelled_code};
+use super::publish_groups_api::PUBLISH_GROUP_HEADER;
+use super::read_item_stream_api::CONSISTENCY_TOKEN_HEADER;
+use super::request_id::{REQUEST_ID_HEADER, request_id};
 
 use axum::{
+    Json,
     body::Body,
-    http::{Request, StatusCode},
-    response::IntoResponse,
+    http::{HeaderMap, HeaderValue, Request, StatusCode, header},
+    response::{IntoResponse, Response},
 };
-use futures::StreamExt;
+use chrono::{DateTime, Utc};
+use http_body::{Body as HttpBody, Frame};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Set on the response to the upload that created the item
+pub const ITEM_CREATED_HEADER: &str = "X-Item-Created";
+
+/// Set on the response to an upload whose first bytes did not look like XML, see
+/// `STREAM_DB_CONTENT_SNIFF`
+pub const CONTENT_SNIFF_WARNING_HEADER: &str = "X-Content-Sniff-Warning";
+
+/// Namespaces the ID generated by `POST /items`
+pub const ID_PREFIX_HEADER: &str = "X-Id-Prefix";
+
+/// Size of the body in bytes, for producers that cannot announce it in `Content-Length`
+pub const EXPECTED_SIZE_HEADER: &str = "X-Expected-Size";
+
+/// Number of properties in the body, complete ones only
+pub const EXPECTED_PROPERTIES_HEADER: &str = "X-Expected-Properties";
+
+/// Trailer of a chunked body confirming it ended where the producer meant it to
+pub const STREAM_COMPLETE_TRAILER: &str = "X-Stream-Complete";
 
-pub fn init() -> Result<(), String> {
+/// Longest `X-Producer` and `X-Producer-Version` accepted, in characters
+const MAX_PRODUCER_LENGTH: usize = 128;
+
+/// Longer `X-Commit-Message`s are cut to this many characters
+const MAX_COMMIT_MESSAGE_LENGTH: usize = 1024;
+
+/// Query parameters accepted by the write endpoint
+#[derive(Deserialize, Default)]
+pub struct WriteItemStreamQuery {
+    /// Reject the upload if a property value does not match its declared `type`
+    pub typed: Option<bool>,
+    /// Check the upload as if it were written, without storing anything
+    pub dry_run: Option<bool>,
+}
+
+pub fn init(state: &StreamDb) -> Result<(), String> {
     println!("Initializing write item stream api");
-    item_stream_component::init()?;
+    item_stream_component::init(state)?;
 
     Ok(())
 }
 
+/// Where an upload is stored
+enum UploadTarget {
+    Version {
+        item_id: String,
+        item_version: u64,
+    },
+    /// Version 1 of a new item whose ID is generated, namespaced by `prefix`
+    NewItem {
+        prefix: Option<String>,
+    },
+}
+
 pub async fn write_item_stream(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    query: WriteItemStreamQuery,
+    input: Request<Body>,
+) -> Response {
+    let request_id = request_id(input.headers());
+    let uploaded = match write_options(&state, input.headers(), &query, &request_id) {
+        Ok(options) => {
+            upload_version(&state, item_id, item_version, options, input, &request_id).await
+        }
+        Err(error) => Err(error),
+    };
+    match uploaded {
+        Ok(receipt) => receipt_response(&state, receipt, false),
+        Err(error) => error.with_request_id(Some(request_id)).into_response(),
+    }
+}
+
+/// Upload a version through the pipeline of the write endpoint, for the endpoints that
+/// answer with something else than its receipt response
+pub async fn upload_version(
+    state: &AppState,
     item_id: String,
     item_version: u64,
+    options: WriteOptions,
+    input: Request<Body>,
+    request_id: &str,
+) -> Result<WriteReceipt, ApiError> {
+    if state.log.debug_enabled(&item_id) {
+        let header = |name| {
+            input
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("none")
+        };
+        log_control::debug(
+            &item_id,
+            item_version,
+            format_args!(
+                "upload requested as {request_id}, Content-Length {}, Content-Encoding {}, dry run {}",
+                header(header::CONTENT_LENGTH),
+                header(header::CONTENT_ENCODING),
+                options.dry_run
+            ),
+        );
+    }
+    // A dry run leaves nothing behind, not even a capture
+    let mut capture = if options.dry_run {
+        None
+    } else {
+        item_stream_component::start_debug_capture(state, &item_id, item_version, request_id)
+    };
+    let target = UploadTarget::Version {
+        item_id,
+        item_version,
+    };
+    let result = write(
+        state.clone(),
+        target,
+        options,
+        input,
+        request_id,
+        &mut capture,
+    )
+    .await;
+    // Left over when the upload was refused before its ingest took the capture over
+    if let (Some(capture), Err(error)) = (capture.as_mut(), &result) {
+        capture.finish(error.code.name());
+    }
+    result
+}
+
+/// Upload version 1 of a new item under an ID the server generates, for producers without
+/// a natural key. The ID is reported in the receipt and the `Location` header.
+pub async fn create_item(
+    state: AppState,
+    query: WriteItemStreamQuery,
     input: Request<Body>,
-) -> impl IntoResponse {
+) -> Response {
+    let request_id = request_id(input.headers());
+    let prefix = match input.headers().get(ID_PREFIX_HEADER).map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(prefix)) => match item_ids::validate_prefix(prefix) {
+            Ok(()) => Some(prefix.to_string()),
+            Err(error) => {
+                return ApiError::new(ErrorCode::BadRequest, error)
+                    .with_request_id(Some(request_id))
+                    .into_response();
+            }
+        },
+        Some(Err(_)) => {
+            return ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Id-Prefix may only contain ASCII letters, digits and dashes",
+            )
+            .with_request_id(Some(request_id))
+            .into_response();
+        }
+    };
+    let options = match write_options(&state, input.headers(), &query, &request_id) {
+        Ok(options) => options,
+        Err(error) => return error.with_request_id(Some(request_id)).into_response(),
+    };
+    // Captured once the ID is known
+    let mut capture = None;
+    let target = UploadTarget::NewItem { prefix };
+    let result = write(
+        state.clone(),
+        target,
+        options,
+        input,
+        &request_id,
+        &mut capture,
+    )
+    .await;
+    if let (Some(capture), Err(error)) = (capture.as_mut(), &result) {
+        capture.finish(error.code.name());
+    }
+    match result {
+        Ok(receipt) => receipt_response(&state, receipt, true),
+        Err(error) => error.with_request_id(Some(request_id)).into_response(),
+    }
+}
+
+async fn write(
+    state: AppState,
+    target: UploadTarget,
+    mut options: WriteOptions,
+    input: Request<Body>,
+    request_id: &str,
+    capture: &mut Option<DebugCapture>,
+) -> Result<WriteReceipt, ApiError> {
     // Validate content type is XML
     let content_type = input
         .headers()
@@ -26,93 +231,505 @@ pub async fn write_item_stream(
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
 
-    if !content_type.contains("xml") {
-        return (
-            StatusCode::BAD_REQUEST,
-            "Content-Type must be application/xml or text/xml",
-        )
-            .into_response();
+    options.framed = content_type.starts_with(FRAMED_CONTENT_TYPE);
+    if !content_type.contains("xml") && !options.framed {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("Content-Type must be application/xml, text/xml or {FRAMED_CONTENT_TYPE}"),
+        ));
     }
 
-    let mut input_stream = input.into_body().into_data_stream();
+    // Everything that can reject the upload happens before the body is first polled,
+    // which is when hyper answers `Expect: 100-continue`. A client waiting for it learns
+    // about a conflict or an oversized item without sending the payload.
+    if let Some(expect) = input.headers().get(header::EXPECT)
+        && !expect.as_bytes().eq_ignore_ascii_case(b"100-continue")
+    {
+        return Err(ApiError::new(
+            ErrorCode::ExpectationFailed,
+            "Only Expect: 100-continue is supported",
+        ));
+    }
+    options.content_encoding = ContentEncoding::from_header(
+        input
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok()),
+    )
+    .map_err(|error| ApiError::new(ErrorCode::BadRequest, error))?;
+    // The segments are offsets into the stored bytes, which have to be the payloads as
+    // received
+    if options.framed
+        && (options.content_encoding.is_some()
+            || options.dedupe.is_some()
+            || options.canonicalize == Some(Canonicalization::SortByName))
+    {
+        return Err(ApiError::new(
+            ErrorCode::BadRequest,
+            "Framed uploads cannot be compressed, deduplicated or canonicalized",
+        ));
+    }
+    let declared_size = input
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut completeness_check = CompletenessCheck {
+        content_length: declared_size,
+        expected_size: count_header(input.headers(), EXPECTED_SIZE_HEADER)?,
+        expected_properties: count_header(input.headers(), EXPECTED_PROPERTIES_HEADER)?,
+        trailer: false,
+        allow_unconfirmed: state.config.allow_unconfirmed_uploads,
+    };
+    let dry_run = options.dry_run;
+    let publish_group = options.publish_group.clone();
 
-    let mut component = match ItemStreamComponent::new_writer(item_id.clone(), item_version) {
-        Ok(component) => component,
-        Err(error) => return (StatusCode::CONFLICT, error).into_response(),
+    let (item_id, component) = match target {
+        UploadTarget::Version {
+            item_id,
+            item_version,
+        } => {
+            let component = ItemStreamComponent::new_queued_writer(
+                &state,
+                item_id.clone(),
+                item_version,
+                options,
+            )
+            .await
+            .map_err(write_error)?;
+            (item_id, component)
+        }
+        UploadTarget::NewItem { prefix } => {
+            let (item_id, component) =
+                ItemStreamComponent::new_item_writer(&state, prefix.as_deref(), options)
+                    .await
+                    .map_err(write_error)?;
+            if !dry_run {
+                *capture =
+                    item_stream_component::start_debug_capture(&state, &item_id, 1, request_id);
+            }
+            (item_id, component)
+        }
     };
+    let mut ingest = component
+        .into_ingest(capture.take())
+        .map_err(ingest_error)?;
+    ingest
+        .check_declared_size(declared_size.or(completeness_check.expected_size))
+        .map_err(ingest_error)?;
 
-    // Buffer to accumulate partial XML chunks
-    let mut xml_buffer = String::new();
-    let mut property_count = 0;
-
-    while let Some(chunk) = input_stream.next().await {
-        match chunk {
-            Ok(bytes) => {
-                // Convert bytes to string, handling UTF-8
-                if let Ok(chunk_str) = std::str::from_utf8(&bytes) {
-                    xml_buffer.push_str(chunk_str);
-
-                    // Try to parse complete property elements from the buffer
-                    // We look for complete <property>...</property> elements
-                    while let Some(end_tag_pos) = xml_buffer.find("</property>") {
-                        // Extract complete property element
-                        let property_element = &xml_buffer[..=end_tag_pos];
-
-                        // Write the property to the file without validation
-                        // This ensures all XML is written as-is
-                        if let Err(error) = component
-                            .write_chunk(property_element.as_bytes().to_vec())
-                            .await
-                        {
-                            return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
-                        }
-
-                        property_count += 1;
-                        // Remove the processed element from buffer
-                        xml_buffer.drain(..=end_tag_pos);
-                    }
-                } else {
-                    return (StatusCode::BAD_REQUEST, "Invalid UTF-8 in XML data").into_response();
+    let mut body = input.into_body();
+    // A kill or a forced drain stops the upload while it waits for its client too
+    let cancellation = ingest.cancellation();
+    loop {
+        // Frame by frame rather than as a data stream, which would hide the trailers
+        let next_frame = std::future::poll_fn(|context| Pin::new(&mut body).poll_frame(context));
+        let frame = tokio::select! {
+            frame = next_frame => frame,
+            reason = cancellation.cancelled() => return Err(ingest_error(ingest.cancel(reason))),
+        };
+        match frame.map(|frame| frame.map(Frame::into_data)) {
+            Some(Ok(Ok(bytes))) => ingest.push_bytes(bytes).await.map_err(ingest_error)?,
+            Some(Ok(Err(frame))) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    completeness_check.trailer = trailers
+                        .get(STREAM_COMPLETE_TRAILER)
+                        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
                 }
             }
-            Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+            Some(Err(_)) => return Err(ingest_error(ingest.cancel(CancelReason::ClientGone))),
+            None => break,
         }
     }
+    ingest.check_completeness(completeness_check);
+
+    let limits_applied = *ingest.limits();
+    let (committed, received) = ingest.finish().await.map_err(ingest_error)?;
+    Ok(WriteReceipt {
+        item_id,
+        committed,
+        limits_applied,
+        received,
+        dry_run: dry_run.then_some(true),
+        publish_group,
+    })
+}
 
-    // Handle any remaining data in buffer (incomplete property at end of stream)
-    if !xml_buffer.is_empty() {
-        // Write any remaining data as-is
-        if let Err(error) = component.write_chunk(xml_buffer.as_bytes().to_vec()).await {
-            return (StatusCode::INTERNAL_SERVER_ERROR, error).into_response();
+/// A header holding a count, e.g. of bytes or properties
+fn count_header(headers: &HeaderMap, name: &str) -> Result<Option<u64>, ApiError> {
+    headers
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| {
+                    ApiError::new(
+                        ErrorCode::BadRequest,
+                        format!("{name} must be a non-negative integer"),
+                    )
+                })
+        })
+        .transpose()
+}
+
+/// The response to a committed upload, `generated` when the server picked its item ID
+pub fn receipt_response(state: &AppState, receipt: WriteReceipt, generated: bool) -> Response {
+    let mut headers = HeaderMap::new();
+    if let Some(warning) = receipt.received.content_sniff_warning.as_deref()
+        && let Ok(value) = warning.parse()
+    {
+        headers.insert(CONTENT_SNIFF_WARNING_HEADER, value);
+    }
+    if receipt.dry_run == Some(true) {
+        // Nothing was created, and there is nothing to read or point at
+        if let Some(request_id) = receipt.committed.request_id.as_deref()
+            && let Ok(value) = request_id.parse()
+        {
+            headers.insert(REQUEST_ID_HEADER, value);
         }
-        // Count as a property if it looks like a property element
-        if xml_buffer.contains("<property") {
-            property_count += 1;
+        return (StatusCode::OK, headers, Json(receipt)).into_response();
+    }
+    if receipt.publish_group.is_some() {
+        // Staged, readable once the group is committed
+        if let Some(request_id) = receipt.committed.request_id.as_deref()
+            && let Ok(value) = request_id.parse()
+        {
+            headers.insert(REQUEST_ID_HEADER, value);
         }
+        return (StatusCode::ACCEPTED, headers, Json(receipt)).into_response();
+    }
+    // A new item is reported as created, further versions as a plain success
+    let status = if receipt.committed.first_version == Some(true) {
+        headers.insert(ITEM_CREATED_HEADER, HeaderValue::from_static("true"));
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    if let Some(request_id) = receipt.committed.request_id.as_deref()
+        && let Ok(value) = request_id.parse()
+    {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+    if generated && let Ok(value) = format!("/read-item-stream/{}/1", receipt.item_id).parse() {
+        headers.insert(header::LOCATION, value);
+    }
+    let token =
+        item_stream_component::consistency_token(state, &receipt.item_id, &receipt.committed);
+    if let Ok(value) = token.parse() {
+        headers.insert(CONSISTENCY_TOKEN_HEADER, value);
     }
+    (status, headers, Json(receipt)).into_response()
+}
 
-    // Check if we received any valid properties
-    if property_count == 0 {
-        return (
-            StatusCode::BAD_REQUEST,
-            "No valid property elements found in XML",
-        )
-            .into_response();
+/// Options of an upload taken from its request headers and query
+pub fn write_options(
+    state: &StreamDb,
+    headers: &HeaderMap,
+    query: &WriteItemStreamQuery,
+    request_id: &str,
+) -> Result<WriteOptions, ApiError> {
+    let mut options = WriteOptions {
+        request_id: Some(request_id.to_string()),
+        validate_types: query.typed.unwrap_or(false),
+        dry_run: query.dry_run.unwrap_or(false),
+        ..WriteOptions::new(&state.config)
+    };
+    // Nothing replicates versions, an upload asking to wait for replicas must not be
+    // acknowledged as if they held it
+    if headers.contains_key("x-require-replicas") {
+        return Err(ApiError::new(
+            ErrorCode::NotImplemented,
+            "X-Require-Replicas is not supported, this instance has no replicas to wait for",
+        ));
+    }
+    match headers.get("x-wrap-root").map(|v| v.to_str()) {
+        None => {}
+        Some(Ok("item")) => options.wrap_root = true,
+        Some(Ok("none")) => options.wrap_root = false,
+        Some(_) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Wrap-Root must be either item or none",
+            ));
+        }
+    }
+
+    match headers.get("x-canonicalize").map(|v| v.to_str()) {
+        None => {}
+        Some(Ok(name)) => match Canonicalization::parse(name) {
+            Ok(canonicalize) => options.canonicalize = Some(canonicalize),
+            Err(error) => return Err(ApiError::new(ErrorCode::BadRequest, error)),
+        },
+        Some(Err(_)) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Canonicalize must be either sort-by-name or none",
+            ));
+        }
+    }
+
+    match headers.get("x-dedupe-properties").map(|v| v.to_str()) {
+        None => {}
+        Some(Ok(name)) => match DedupeMode::parse(name) {
+            Ok(dedupe) => options.dedupe = Some(dedupe),
+            Err(error) => return Err(ApiError::new(ErrorCode::BadRequest, error)),
+        },
+        Some(Err(_)) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Dedupe-Properties must be last, first or reject",
+            ));
+        }
+    }
+
+    match headers.get("x-allow-version-jump").map(|v| v.to_str()) {
+        None | Some(Ok("false")) => {}
+        // Deliberately bypassing the version jump policy is left to operators
+        Some(Ok("true")) => {
+            authorize_admin(&state.config, headers)?;
+            options.allow_version_jump = true;
+        }
+        Some(_) => {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Allow-Version-Jump must be either true or false",
+            ));
+        }
+    }
+
+    if let Some(value) = headers.get("x-write-queue") {
+        let wait = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().strip_prefix("wait="))
+            .and_then(parse_wait)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::BadRequest,
+                    "X-Write-Queue must be wait= followed by a duration like 30s or 500ms",
+                )
+            })?;
+        let max_wait = Duration::from_secs(state.config.write_queue_max_wait_secs);
+        if wait > max_wait {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                format!(
+                    "X-Write-Queue may wait at most {}s, see STREAM_DB_WRITE_QUEUE_MAX_WAIT_SECS",
+                    max_wait.as_secs()
+                ),
+            ));
+        }
+        options.queue_wait = Some(wait);
+    }
+
+    if let Some(value) = headers.get(PUBLISH_GROUP_HEADER) {
+        let group_id = value.to_str().map(str::trim).unwrap_or_default();
+        if group_id.is_empty() {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Publish-Group must be the ID of a publish group",
+            ));
+        }
+        if options.dry_run {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "dry_run cannot be combined with X-Publish-Group",
+            ));
+        }
+        options.publish_group = Some(group_id.to_string());
     }
 
-    match component.finalize() {
-        Ok(_) => (
-            StatusCode::OK,
+    if let Some(value) = headers.get("x-immutable-until") {
+        let until = value
+            .to_str()
+            .ok()
+            .and_then(|value| DateTime::parse_from_rfc3339(value.trim()).ok())
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::BadRequest,
+                    "X-Immutable-Until must be an RFC 3339 timestamp",
+                )
+            })?
+            .with_timezone(&Utc);
+        if until <= Utc::now() {
+            return Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Immutable-Until must be in the future",
+            ));
+        }
+        options.immutable_until = Some(until);
+    }
+
+    options.provenance = provenance(state, headers)?;
+    Ok(options)
+}
+
+/// A wait of `X-Write-Queue`: seconds, with or without an `s`, or milliseconds with `ms`
+fn parse_wait(value: &str) -> Option<Duration> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    let secs = value.strip_suffix('s').unwrap_or(value);
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+/// Who is writing, from the `X-Producer`, `X-Producer-Version` and `X-Commit-Message`
+/// headers and the admin token if one was sent
+fn provenance(state: &StreamDb, headers: &HeaderMap) -> Result<Provenance, ApiError> {
+    let mut provenance = Provenance {
+        producer: producer_header(headers, "X-Producer")?,
+        producer_version: producer_header(headers, "X-Producer-Version")?,
+        authenticated_as: authorize_admin(&state.config, headers)
+            .is_ok()
+            .then(|| "admin".to_string()),
+        ..Default::default()
+    };
+    if let Some(message) = sanitized_header(headers, "X-Commit-Message") {
+        match message.char_indices().nth(MAX_COMMIT_MESSAGE_LENGTH) {
+            Some((cut, _)) => {
+                provenance.commit_message = Some(message[..cut].trim_end().to_string());
+                provenance.commit_message_truncated = Some(true);
+            }
+            None => provenance.commit_message = Some(message),
+        }
+    }
+    Ok(provenance)
+}
+
+fn producer_header(headers: &HeaderMap, name: &str) -> Result<Option<String>, ApiError> {
+    match sanitized_header(headers, name) {
+        Some(value) if value.chars().count() > MAX_PRODUCER_LENGTH => Err(ApiError::new(
+            ErrorCode::BadRequest,
+            format!("{name} may be at most {MAX_PRODUCER_LENGTH} characters"),
+        )),
+        value => Ok(value),
+    }
+}
+
+/// The header's value with control characters replaced by spaces, `None` when it is
+/// missing or blank. Header values are bytes, anything that is not UTF-8 is replaced too.
+fn sanitized_header(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?;
+    let value: String = String::from_utf8_lossy(value.as_bytes())
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+pub fn write_error(error: WriteError) -> ApiError {
+    let message = error.message();
+    match error {
+        WriteError::Locked(_) => ApiError::new(ErrorCode::Locked, message),
+        WriteError::InFlightLimit(limit) => {
+            ApiError::new(ErrorCode::InFlightLimit, message).with_details(limit)
+        }
+        WriteError::QueueFull(_) => ApiError::new(ErrorCode::QueueFull, message),
+        WriteError::QueueTimeout {
+            position,
+            depth,
+            waited_ms,
+        } => ApiError::new(ErrorCode::InFlightLimit, message).with_details(WriteQueueDetails {
+            queue_position: position,
+            queue_depth: depth,
+            waited_ms,
+        }),
+        WriteError::Unavailable(_) => ApiError::new(ErrorCode::Unavailable, message),
+        WriteError::Cancelled(reason) => ApiError::new(cancelled_code(reason), message)
+            .with_details(CancelledDetails {
+                reason: reason.name().to_string(),
+                bytes_received: None,
+            }),
+        WriteError::VersionConflict { requested, current } => {
+            ApiError::new(ErrorCode::VersionConflict, message)
+                .with_details(VersionConflictDetails { requested, current })
+        }
+        WriteError::Superseded { requested, current } => {
+            ApiError::new(ErrorCode::VersionSuperseded, message)
+                .with_details(VersionConflictDetails { requested, current })
+        }
+        WriteError::VersionJump {
+            requested,
+            current,
+            max_jump,
+        } => ApiError::new(
+            ErrorCode::LimitExceeded,
             format!(
-                "Stream processed successfully. {} properties written.",
-                property_count
+                "{message}, which looks like a mistake. Send X-Allow-Version-Jump: true with \
+                 the admin token to write it anyway"
             ),
         )
-            .into_response(),
-        Err(error) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Write error: {error}"),
-        )
-            .into_response(),
+        .with_details(VersionJumpDetails {
+            limit: "max_version_jump".to_string(),
+            max: max_jump,
+            actual: requested - current,
+            requested,
+            current,
+        }),
+        WriteError::NotFound(_) => ApiError::new(ErrorCode::NotFound, message),
+        WriteError::Quarantined(failure) => {
+            ApiError::new(ErrorCode::IntegrityFailure, message).with_details(*failure)
+        }
+        WriteError::Immutable(immutable) => {
+            ApiError::new(ErrorCode::Immutable, message).with_details(immutable)
+        }
+        WriteError::Failed(_) => ApiError::internal(message),
+    }
+}
+
+/// The response to an upload refused by its [`StreamIngest`]
+pub fn ingest_error(error: IngestError) -> ApiError {
+    let message = error.message();
+    match error {
+        IngestError::TooLarge(violation) => {
+            ApiError::new(ErrorCode::PayloadTooLarge, message).with_details(violation)
+        }
+        IngestError::LimitExceeded(violation) => {
+            ApiError::new(ErrorCode::LimitExceeded, message).with_details(violation)
+        }
+        IngestError::QuotaExceeded(exceeded) => {
+            ApiError::new(ErrorCode::QuotaExceeded, message).with_details(exceeded)
+        }
+        IngestError::InvalidUtf8 { byte_offset } => ApiError::new(ErrorCode::InvalidXml, message)
+            .with_details(InvalidUtf8Details { byte_offset }),
+        IngestError::NoProperties { bytes_received } => {
+            ApiError::new(ErrorCode::InvalidXml, message)
+                .with_details(BytesReceivedDetails { bytes_received })
+        }
+        IngestError::TypeMismatch(type_violations) => {
+            ApiError::new(ErrorCode::TypeMismatch, message).with_details(type_violations)
+        }
+        IngestError::ContentMismatch(mismatch) => {
+            ApiError::new(ErrorCode::UnsupportedMediaType, message).with_details(
+                ContentSniffDetails {
+                    detected: mismatch.content.name().to_string(),
+                    byte_offset: mismatch.byte_offset,
+                    first_bytes: mismatch.first_bytes,
+                },
+            )
+        }
+        IngestError::DuplicateProperty { duplicate, .. } => {
+            ApiError::new(ErrorCode::DuplicateProperty, message).with_details(duplicate)
+        }
+        IngestError::Interrupted { bytes_received, .. } => {
+            ApiError::new(ErrorCode::BadRequest, message)
+                .with_details(BytesReceivedDetails { bytes_received })
+        }
+        IngestError::Aborted(_) => ApiError::new(ErrorCode::Aborted, message),
+        IngestError::Cancelled {
+            reason,
+            bytes_received,
+        } => ApiError::new(cancelled_code(reason), message).with_details(CancelledDetails {
+            reason: reason.name().to_string(),
+            bytes_received: Some(bytes_received),
+        }),
+        IngestError::Superseded {
+            requested, current, ..
+        } => ApiError::new(ErrorCode::VersionSuperseded, message)
+            .with_details(VersionConflictDetails { requested, current }),
+        IngestError::Failed(_) => ApiError::internal(message),
     }
 }
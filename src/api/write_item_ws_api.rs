@@ -0,0 +1,207 @@
+use crate::component::item_stream_component::{self, ItemStreamComponent};
+use crate::logic::stream_ingest::StreamIngest;
+use crate::persistence::io_engine::FsyncPolicy;
+use crate::state::AppState;
+use crate::types::dto::{ErrorBody, UploadAck, WriteReceipt, WsWriteReceipt};
+
+use super::api_error::{ApiError, ErrorCode};
+use super::request_id::request_id;
+use super::write_item_stream_api::{
+    WriteItemStreamQuery, ingest_error, write_error, write_options,
+};
+
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+/// Text frames a client sends to end an upload
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum UploadControl {
+    Commit,
+    Abort,
+}
+
+/// Upload a version over a WebSocket, acknowledging every binary frame. The writer is
+/// opened before the upgrade, so a conflicting or locked version is refused with a plain
+/// HTTP error.
+pub async fn write_item_ws(
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    query: WriteItemStreamQuery,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let request_id = request_id(&headers);
+    let options = match write_options(&state, &headers, &query, &request_id) {
+        // Refused rather than written for real
+        Ok(options) if options.dry_run => {
+            return ApiError::new(
+                ErrorCode::BadRequest,
+                "dry_run is only supported by POST /write-item-stream",
+            )
+            .with_request_id(Some(request_id))
+            .into_response();
+        }
+        // A staged version has no consistency token to send in the receipt
+        Ok(options) if options.publish_group.is_some() => {
+            return ApiError::new(
+                ErrorCode::BadRequest,
+                "X-Publish-Group is only supported by POST /write-item-stream",
+            )
+            .with_request_id(Some(request_id))
+            .into_response();
+        }
+        Ok(options) => options,
+        Err(error) => return error.with_request_id(Some(request_id)).into_response(),
+    };
+    let component = match ItemStreamComponent::new_queued_writer(
+        &state,
+        item_id.clone(),
+        item_version,
+        options,
+    )
+    .await
+    {
+        Ok(component) => component,
+        Err(error) => {
+            return write_error(error)
+                .with_request_id(Some(request_id))
+                .into_response();
+        }
+    };
+    let capture =
+        item_stream_component::start_debug_capture(&state, &item_id, item_version, &request_id);
+    let ingest = match component.into_ingest(capture) {
+        Ok(ingest) => ingest,
+        Err(error) => {
+            return ingest_error(error)
+                .with_request_id(Some(request_id))
+                .into_response();
+        }
+    };
+
+    upgrade
+        .max_message_size(state.config.ws_max_frame_bytes)
+        .on_upgrade(move |socket| upload(state, socket, ingest, item_id, item_version, request_id))
+}
+
+async fn upload(
+    state: AppState,
+    mut socket: WebSocket,
+    mut ingest: StreamIngest,
+    item_id: String,
+    item_version: u64,
+    request_id: String,
+) {
+    // `None` when the client went away without committing, which drops and so aborts the
+    // writer just like a disconnected HTTP upload
+    let result: Result<Option<WriteReceipt>, ApiError> = loop {
+        // Frames are only read once the previous one is written and acknowledged, so a
+        // client sending faster than the disk keeps up is held back by its socket
+        let Some(Ok(message)) = socket.recv().await else {
+            break Ok(None);
+        };
+        match message {
+            Message::Binary(bytes) => {
+                if let Err(error) = ingest.push_bytes(bytes).await {
+                    break Err(ingest_error(error));
+                }
+                let ack = UploadAck {
+                    ack: ingest.received_bytes(),
+                    durable: match state.config.fsync_policy {
+                        FsyncPolicy::Chunk => ingest.written_bytes(),
+                        FsyncPolicy::Commit => 0,
+                    },
+                };
+                if send_json(&mut socket, &ack).await.is_err() {
+                    break Ok(None);
+                }
+            }
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(UploadControl::Commit) => {
+                    let limits_applied = *ingest.limits();
+                    break match ingest.finish().await {
+                        Ok((committed, received)) => Ok(Some(WriteReceipt {
+                            item_id: item_id.clone(),
+                            committed,
+                            limits_applied,
+                            received,
+                            dry_run: None,
+                            publish_group: None,
+                        })),
+                        Err(error) => Err(ingest_error(error)),
+                    };
+                }
+                Ok(UploadControl::Abort) => {
+                    ingest.abort();
+                    break Err(ApiError::new(
+                        ErrorCode::Aborted,
+                        "Upload aborted by the client",
+                    ));
+                }
+                Err(error) => {
+                    let error = ingest.interrupted(format!("Unknown control frame: {error}"));
+                    break Err(ingest_error(error));
+                }
+            },
+            Message::Close(_) => break Ok(None),
+            // Pings are answered by axum
+            Message::Ping(_) | Message::Pong(_) => {}
+        }
+    };
+
+    let close = match result {
+        Ok(Some(receipt)) => {
+            let consistency_token = item_stream_component::consistency_token(
+                &state,
+                &receipt.item_id,
+                &receipt.committed,
+            );
+            let reply = WsWriteReceipt {
+                receipt,
+                consistency_token,
+            };
+            let _ = send_json(&mut socket, &reply).await;
+            close_code::NORMAL
+        }
+        Ok(None) => {
+            println!(
+                "WebSocket upload of item {item_id} version {item_version} closed before it committed"
+            );
+            return;
+        }
+        Err(error) => {
+            println!(
+                "WebSocket upload {request_id} of item {item_id} version {item_version} failed with {}: {}",
+                error.code.name(),
+                error.message
+            );
+            let code = error.code;
+            let error = error
+                .with_request_id(Some(request_id))
+                .for_client(state.config.expose_internal_errors);
+            let _ = send_json(&mut socket, &ErrorBody::from(error)).await;
+            if code == ErrorCode::Internal {
+                close_code::ERROR
+            } else {
+                close_code::POLICY
+            }
+        }
+    };
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close,
+            reason: "".into(),
+        })))
+        .await;
+}
+
+async fn send_json(socket: &mut WebSocket, value: &impl Serialize) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(value).unwrap_or_default();
+    socket.send(Message::Text(text.into())).await
+}
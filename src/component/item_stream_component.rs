@@ -1,37 +1,690 @@
-use crate::logic::item_stream_logic::{self, ItemStreamLogic};
+use crate::config::{ConfigReload, Setting};
+use crate::log_control::{LogLevel, LogSettings};
+use crate::logic::block_reindex::ReindexReport;
+use crate::logic::bulk_delete::{BulkDeleteFilter, BulkDeleteProgress};
+use crate::logic::byte_range::ByteRange;
+use crate::logic::commit_events::CommitEvent;
+use crate::logic::commit_hooks::HookRun;
+use crate::logic::consistency::ConsistencyError;
+use crate::logic::content_encoding::ContentEncoding;
+use crate::logic::download_manifest::DownloadManifest;
+use crate::logic::drain::{DrainOutcome, DrainReport, ForceReport};
+use crate::logic::item_stream_logic::{
+    self, ItemStreamLogic, ReadError, ReadOptions, SettingsError, WriteOptions,
+};
+use crate::logic::metadata_backfill::{BackfillError, BackfillReport};
+use crate::logic::presign::PresignedUrl;
+use crate::logic::property_search::{
+    PropertyNamesPage, PropertySearchPage, RebuildReport, SearchError,
+};
+use crate::logic::property_transform::TransformError;
+use crate::logic::publish_groups::PublishGroupCommit;
+use crate::logic::s3_objects::{ListRequest, ObjectListing};
+use crate::logic::space_report::{SpaceReportLine, SpaceReportOptions};
+use crate::logic::storage_quota::StorageUsageReport;
+use crate::logic::stream_ingest::{IngestError, StreamIngest};
+use crate::logic::verification_sweep::{self, SweepStatus};
+use crate::logic::version_tags::TagError;
+use crate::logic::warmup::{self, WarmupProgress};
+use crate::logic::{
+    commit_hooks, data_dir_watch, item_filter, maintenance, property_search, read_stats, replica,
+    tiering,
+};
+use crate::persistence::audit_log::AuditRecord;
+use crate::persistence::cancellation::CancelReason;
+use crate::persistence::cold_tier::{MoveReport, StorageTier};
+use crate::persistence::debug_capture::{CaptureTrace, DebugCapture};
+use crate::persistence::fault_injection::FaultRule;
+use crate::persistence::file_handles::{FileHandleReport, HandlesExhausted};
+use crate::persistence::file_persistence::{
+    DeleteError, DeleteReport, FailedUpload, ImmutableVersion, KillReport, ResetVersionError,
+    ResetVersionReport, StatRequest, StreamStatus, VersionStat, VersionState, WriteError,
+};
+use crate::persistence::idempotency::RecordedResponse;
+use crate::persistence::integrity::VerifyReport;
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::item_settings::ItemSettings;
+use crate::persistence::item_stats::ItemStats;
+use crate::persistence::publish_groups::{PublishGroup, PublishGroupError, PublishGroupStatus};
+use crate::persistence::read_sources::{ReadSource, ReadSourceReport};
+use crate::persistence::storage::StorageLayout;
+use crate::persistence::store_format::FormatStatus;
+use crate::persistence::transforms::TransformSpec;
+use crate::persistence::version_tags::VersionTags;
+use crate::state::{AppState, StreamDb};
 
-pub fn init() -> Result<(), String> {
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::fs::File as TokioFile;
+use tokio::sync::{OwnedMutexGuard, broadcast};
+
+pub fn init(state: &StreamDb) -> Result<(), String> {
     println!("Initializing item stream component");
-    item_stream_logic::init()?;
+    item_stream_logic::init(state)?;
     Ok(())
 }
 
+/// The data directory's format and the migrations starting the instance would run
+pub fn store_format(state: &StreamDb) -> Result<FormatStatus, String> {
+    item_stream_logic::store_format(state)
+}
+
+/// Start the instance's periodic housekeeping, needs a running tokio runtime. Read-only
+/// instances only warm up and follow the writing instance, everything else writes.
+pub fn start_background_tasks(state: &AppState) {
+    if state.config.read_only {
+        replica::start(state.clone());
+    } else {
+        maintenance::start(state.clone());
+        read_stats::start(state.clone());
+        commit_hooks::start(state.clone());
+        tiering::start(state.clone());
+        verification_sweep::start(state.clone());
+    }
+    data_dir_watch::start(state.clone());
+    item_filter::start(state.clone());
+    warmup::start(state.clone());
+    property_search::start(state.clone());
+}
+
 pub struct ItemStreamComponent {
     logic: ItemStreamLogic,
 }
 
 impl ItemStreamComponent {
-    pub fn new_reader(item_id: String, item_version: u64) -> Result<Self, String> {
+    pub async fn new_reader(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: ReadOptions,
+    ) -> Result<Self, ReadError> {
         Ok(Self {
-            logic: ItemStreamLogic::new_reader(item_id, item_version)?,
+            logic: ItemStreamLogic::new_reader(state, item_id, item_version, options).await?,
         })
     }
 
-    pub fn new_writer(item_id: String, item_version: u64) -> Result<Self, String> {
+    pub async fn new_writer(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
         Ok(Self {
-            logic: ItemStreamLogic::new_writer(item_id, item_version)?,
+            logic: ItemStreamLogic::new_writer(state, item_id, item_version, options).await?,
         })
     }
 
-    pub async fn write_chunk(&mut self, input_bytes: Vec<u8>) -> Result<(), String> {
-        self.logic.write_chunk(input_bytes).await
+    /// A writer that waits for the item in its write queue, with `X-Write-Queue`
+    pub async fn new_queued_writer(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
+        Ok(Self {
+            logic: ItemStreamLogic::new_queued_writer(state, item_id, item_version, options)
+                .await?,
+        })
+    }
+
+    /// A writer for version 1 of a new item, returned with the item's generated ID
+    pub async fn new_item_writer(
+        state: &AppState,
+        prefix: Option<&str>,
+        options: WriteOptions,
+    ) -> Result<(String, Self), WriteError> {
+        let (item_id, logic) = ItemStreamLogic::new_item_writer(state, prefix, options).await?;
+        Ok((item_id, Self { logic }))
     }
 
     pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
         self.logic.read_chunk().await
     }
 
-    pub fn finalize(&mut self) -> Result<(), String> {
-        self.logic.finalize()
+    pub fn epoch(&self) -> Option<u64> {
+        self.logic.epoch()
+    }
+
+    pub fn storage_tier(&self) -> Option<StorageTier> {
+        self.logic.storage_tier()
+    }
+
+    pub fn served_from(&self) -> Option<ReadSource> {
+        self.logic.served_from()
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.logic.content_length()
+    }
+
+    pub fn committed_size(&self) -> Option<u64> {
+        self.logic.committed_size()
+    }
+
+    pub fn property_count(&self) -> Option<u64> {
+        self.logic.property_count()
+    }
+
+    pub fn redactions(&self) -> Option<u64> {
+        self.logic.redactions()
+    }
+
+    pub fn is_salvaged(&self) -> bool {
+        self.logic.is_salvaged()
+    }
+
+    pub fn is_redacted(&self) -> bool {
+        self.logic.is_redacted()
     }
+
+    pub fn transform_digest(&self) -> Option<&str> {
+        self.logic.transform_digest()
+    }
+
+    pub fn defaults_applied(&self) -> Option<u64> {
+        self.logic.defaults_applied()
+    }
+
+    pub fn schema_version(&self) -> Option<u64> {
+        self.logic.schema_version()
+    }
+
+    pub fn is_defaulted(&self) -> bool {
+        self.logic.is_defaulted()
+    }
+
+    pub fn stored_encoding(&self) -> Option<ContentEncoding> {
+        self.logic.stored_encoding()
+    }
+
+    pub fn content_encoding(&self) -> Option<ContentEncoding> {
+        self.logic.content_encoding()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.logic.is_aborted()
+    }
+
+    /// Why the stream was cancelled, if it was
+    pub fn cancel_reason(&self) -> Option<CancelReason> {
+        self.logic.cancel_reason()
+    }
+
+    pub fn is_too_slow(&self) -> bool {
+        self.logic.is_too_slow()
+    }
+
+    /// Feed a writer through the pipeline shared by every upload endpoint. A reader has
+    /// nothing to feed and is refused.
+    pub fn into_ingest(self, capture: Option<DebugCapture>) -> Result<StreamIngest, IngestError> {
+        StreamIngest::new(self.logic, capture)
+    }
+}
+
+pub fn load_item_settings(state: &StreamDb, item_id: &str) -> Result<ItemSettings, String> {
+    item_stream_logic::load_item_settings(state, item_id)
+}
+
+pub fn store_item_settings(
+    state: &StreamDb,
+    item_id: &str,
+    settings: ItemSettings,
+) -> Result<ItemSettings, SettingsError> {
+    item_stream_logic::store_item_settings(state, item_id, settings)
+}
+
+pub async fn version_state(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VersionState, String> {
+    item_stream_logic::version_state(state, item_id, item_version).await
+}
+
+pub fn stat_versions(
+    state: &StreamDb,
+    requests: Vec<StatRequest>,
+) -> impl Stream<Item = Result<Vec<VersionStat>, String>> + use<> {
+    item_stream_logic::stat_versions(state, requests)
+}
+
+pub async fn reindex(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<ReindexReport, String> {
+    item_stream_logic::reindex(state, item_id, item_version).await
+}
+
+pub fn delete_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<DeleteReport, DeleteError> {
+    item_stream_logic::delete_version(state, item_id, item_version)
+}
+
+pub fn extend_immutability(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    until: DateTime<Utc>,
+) -> Result<ImmutableVersion, WriteError> {
+    item_stream_logic::extend_immutability(state, item_id, item_version, until)
+}
+
+pub fn reset_latest_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    force: bool,
+) -> Result<ResetVersionReport, ResetVersionError> {
+    item_stream_logic::reset_latest_version(state, item_id, item_version, force)
+}
+
+pub fn bulk_delete_matching(
+    state: &StreamDb,
+    filter: &BulkDeleteFilter,
+) -> Result<Vec<(String, u64)>, String> {
+    item_stream_logic::bulk_delete_matching(state, filter)
+}
+
+pub fn bulk_delete(
+    state: AppState,
+    matching: Vec<(String, u64)>,
+    dry_run: bool,
+) -> impl Stream<Item = BulkDeleteProgress> {
+    item_stream_logic::bulk_delete(state, matching, dry_run)
+}
+
+pub async fn move_version(
+    state: &AppState,
+    item_id: &str,
+    item_version: u64,
+    tier: StorageTier,
+) -> Result<MoveReport, WriteError> {
+    item_stream_logic::move_version(state, item_id, item_version, tier).await
+}
+
+pub async fn verify_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VerifyReport, WriteError> {
+    item_stream_logic::verify_version(state, item_id, item_version).await
+}
+
+pub async fn unquarantine(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VerifyReport, WriteError> {
+    item_stream_logic::unquarantine(state, item_id, item_version).await
+}
+
+pub fn item_stats(state: &StreamDb, item_id: &str) -> Result<ItemStats, String> {
+    item_stream_logic::item_stats(state, item_id)
+}
+
+pub fn version_tags(state: &StreamDb, item_id: &str) -> Result<VersionTags, String> {
+    item_stream_logic::version_tags(state, item_id)
+}
+
+pub fn resolve_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+) -> Result<Option<u64>, String> {
+    item_stream_logic::resolve_version_tag(state, item_id, tag)
+}
+
+pub fn committed_versions(state: &StreamDb, item_id: &str) -> Result<Vec<VersionMetadata>, String> {
+    item_stream_logic::committed_versions(state, item_id)
+}
+
+pub fn resolve_as_of(
+    state: &StreamDb,
+    item_id: &str,
+    at: DateTime<Utc>,
+) -> Result<Option<u64>, String> {
+    item_stream_logic::resolve_as_of(state, item_id, at)
+}
+
+pub fn set_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+    item_version: u64,
+) -> Result<VersionTags, TagError> {
+    item_stream_logic::set_version_tag(state, item_id, tag, item_version)
+}
+
+pub fn remove_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+) -> Result<VersionTags, TagError> {
+    item_stream_logic::remove_version_tag(state, item_id, tag)
+}
+
+pub fn failed_uploads(state: &StreamDb) -> Vec<FailedUpload> {
+    item_stream_logic::failed_uploads(state)
+}
+
+pub fn create_publish_group(state: &StreamDb, ttl: Duration) -> Result<PublishGroup, String> {
+    item_stream_logic::create_publish_group(state, ttl)
+}
+
+pub fn publish_group(
+    state: &StreamDb,
+    group_id: &str,
+) -> Result<PublishGroupStatus, PublishGroupError> {
+    item_stream_logic::publish_group(state, group_id)
+}
+
+pub async fn commit_publish_group(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupCommit, PublishGroupError> {
+    item_stream_logic::commit_publish_group(state, group_id).await
+}
+
+pub async fn abort_publish_group(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupStatus, PublishGroupError> {
+    item_stream_logic::abort_publish_group(state, group_id).await
+}
+
+pub fn subscribe_commits(state: &StreamDb) -> broadcast::Receiver<CommitEvent> {
+    item_stream_logic::subscribe_commits(state)
+}
+
+pub fn stream_statuses(state: &StreamDb) -> Vec<StreamStatus> {
+    item_stream_logic::stream_statuses(state)
+}
+
+pub fn kill_stream(state: &StreamDb, item_id: &str, item_version: u64) -> KillReport {
+    item_stream_logic::kill_stream(state, item_id, item_version)
+}
+
+pub fn storage_usage(state: &StreamDb) -> StorageUsageReport {
+    item_stream_logic::storage_usage(state)
+}
+
+pub fn set_storage_quota(state: &StreamDb, quota_bytes: Option<u64>) -> StorageUsageReport {
+    item_stream_logic::set_storage_quota(state, quota_bytes)
+}
+
+pub fn reserve_file_handles(state: &StreamDb) -> Result<(), HandlesExhausted> {
+    item_stream_logic::reserve_file_handles(state)
+}
+
+pub fn file_handle_report(state: &StreamDb) -> FileHandleReport {
+    item_stream_logic::file_handle_report(state)
+}
+
+pub fn storage_layout(state: &StreamDb) -> StorageLayout {
+    item_stream_logic::storage_layout(state)
+}
+
+pub fn read_source_report(state: &StreamDb) -> ReadSourceReport {
+    item_stream_logic::read_source_report(state)
+}
+
+pub fn check_open_file_limits(state: &StreamDb) {
+    item_stream_logic::check_open_file_limits(state)
+}
+
+pub fn warmup_progress(state: &StreamDb) -> WarmupProgress {
+    item_stream_logic::warmup_progress(state)
+}
+
+pub fn config_settings(state: &StreamDb) -> BTreeMap<&'static str, Setting> {
+    item_stream_logic::config_settings(state)
+}
+
+pub fn reload_config(state: &StreamDb) -> Result<ConfigReload, String> {
+    item_stream_logic::reload_config(state)
+}
+
+pub fn verification_status(state: &StreamDb) -> SweepStatus {
+    item_stream_logic::verification_status(state)
+}
+
+pub fn search_properties(
+    state: &StreamDb,
+    name: &str,
+    latest_only: bool,
+    limit: usize,
+    cursor: Option<&str>,
+) -> Result<PropertySearchPage, SearchError> {
+    item_stream_logic::search_properties(state, name, latest_only, limit, cursor)
+}
+
+pub fn search_property_names(
+    state: &StreamDb,
+    prefix: &str,
+    limit: usize,
+) -> Result<PropertyNamesPage, SearchError> {
+    item_stream_logic::search_property_names(state, prefix, limit)
+}
+
+pub async fn rebuild_property_index(state: &AppState) -> Result<RebuildReport, SearchError> {
+    item_stream_logic::rebuild_property_index(state).await
+}
+
+pub async fn backfill_metadata(state: &AppState) -> Result<BackfillReport, BackfillError> {
+    item_stream_logic::backfill_metadata(state).await
+}
+
+pub fn space_report(
+    state: AppState,
+    options: SpaceReportOptions,
+) -> impl Stream<Item = SpaceReportLine> {
+    item_stream_logic::space_report(state, options)
+}
+
+pub fn hook_names(state: &StreamDb) -> impl Iterator<Item = &str> {
+    item_stream_logic::hook_names(state)
+}
+
+pub async fn test_hook(
+    state: &AppState,
+    hook: &str,
+    item_id: &str,
+    committed: &VersionMetadata,
+) -> Option<HookRun> {
+    item_stream_logic::test_hook(state, hook, item_id, committed).await
+}
+
+pub fn is_draining(state: &StreamDb) -> bool {
+    item_stream_logic::is_draining(state)
+}
+
+pub fn drain_report(state: &StreamDb) -> DrainReport {
+    item_stream_logic::drain_report(state)
+}
+
+pub fn force_drain(state: &StreamDb) -> Result<ForceReport, String> {
+    item_stream_logic::force_drain(state)
+}
+
+/// Wait for SIGTERM or SIGINT and drain the streams in flight, returns once the process
+/// may exit
+pub async fn drain_on_shutdown(state: &AppState) -> DrainOutcome {
+    item_stream_logic::drain_on_shutdown(state).await
+}
+
+pub fn fault_rules(state: &StreamDb) -> Vec<FaultRule> {
+    item_stream_logic::fault_rules(state)
+}
+
+pub fn set_fault_rule(state: &StreamDb, rule: FaultRule) -> Result<Vec<FaultRule>, String> {
+    item_stream_logic::set_fault_rule(state, rule)
+}
+
+pub fn clear_fault_rules(state: &StreamDb) {
+    item_stream_logic::clear_fault_rules(state)
+}
+
+pub fn start_debug_capture(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    request_id: &str,
+) -> Option<DebugCapture> {
+    item_stream_logic::start_debug_capture(state, item_id, item_version, request_id)
+}
+
+pub fn debug_capture_patterns(state: &StreamDb) -> Vec<String> {
+    item_stream_logic::debug_capture_patterns(state)
+}
+
+pub fn set_debug_capture_patterns(
+    state: &StreamDb,
+    patterns: Vec<String>,
+) -> Result<Vec<String>, String> {
+    item_stream_logic::set_debug_capture_patterns(state, patterns)
+}
+
+pub fn set_log_level(state: &StreamDb, level: LogLevel, ttl: Option<Duration>) -> LogSettings {
+    item_stream_logic::set_log_level(state, level, ttl)
+}
+
+pub fn set_debug_items(
+    state: &StreamDb,
+    patterns: Vec<String>,
+    ttl: Option<Duration>,
+) -> Result<LogSettings, String> {
+    item_stream_logic::set_debug_items(state, patterns, ttl)
+}
+
+pub fn debug_captures(state: &StreamDb) -> Result<Vec<CaptureTrace>, String> {
+    item_stream_logic::debug_captures(state)
+}
+
+pub fn debug_capture_trace(
+    state: &StreamDb,
+    request_id: &str,
+) -> Result<Option<CaptureTrace>, String> {
+    item_stream_logic::debug_capture_trace(state, request_id)
+}
+
+pub async fn debug_capture_raw(
+    state: &StreamDb,
+    request_id: &str,
+) -> Result<Option<TokioFile>, String> {
+    item_stream_logic::debug_capture_raw(state, request_id).await
+}
+
+pub fn transforms(state: &StreamDb) -> Result<BTreeMap<String, TransformSpec>, String> {
+    item_stream_logic::transforms(state)
+}
+
+pub fn set_transform(
+    state: &StreamDb,
+    name: &str,
+    spec: TransformSpec,
+) -> Result<bool, TransformError> {
+    item_stream_logic::set_transform(state, name, spec)
+}
+
+pub fn delete_transform(state: &StreamDb, name: &str) -> Result<(), TransformError> {
+    item_stream_logic::delete_transform(state, name)
+}
+
+pub fn consistency_token(state: &StreamDb, item_id: &str, committed: &VersionMetadata) -> String {
+    item_stream_logic::consistency_token(state, item_id, committed)
+}
+
+pub async fn await_consistency_token(
+    state: &StreamDb,
+    item_id: &str,
+    token: &str,
+) -> Result<(), ConsistencyError> {
+    item_stream_logic::await_consistency_token(state, item_id, token).await
+}
+
+pub async fn download_manifest(
+    state: &AppState,
+    item_id: &str,
+    committed: VersionMetadata,
+    parts: u64,
+) -> Result<DownloadManifest, String> {
+    item_stream_logic::download_manifest(state, item_id, committed, parts).await
+}
+
+pub fn s3_object(state: &StreamDb, item_id: &str) -> Result<Option<VersionMetadata>, String> {
+    item_stream_logic::s3_object(state, item_id)
+}
+
+pub fn s3_next_version(state: &StreamDb, item_id: &str) -> Result<u64, String> {
+    item_stream_logic::s3_next_version(state, item_id)
+}
+
+pub async fn delete_s3_object(state: &AppState, item_id: &str) -> Result<bool, WriteError> {
+    item_stream_logic::delete_s3_object(state, item_id).await
+}
+
+pub async fn list_s3_objects(
+    state: &AppState,
+    bucket: &str,
+    request: ListRequest,
+) -> Result<ObjectListing, String> {
+    item_stream_logic::list_s3_objects(state, bucket, request).await
+}
+
+pub fn presign_read(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires_at: DateTime<Utc>,
+    range: Option<ByteRange>,
+) -> PresignedUrl {
+    item_stream_logic::presign_read(state, item_id, item_version, expires_at, range)
+}
+
+pub fn verify_presigned_read(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires: Option<i64>,
+    range: Option<&str>,
+    signature: &str,
+) -> Result<Option<ByteRange>, String> {
+    item_stream_logic::verify_presigned_read(
+        state,
+        item_id,
+        item_version,
+        expires,
+        range,
+        signature,
+    )
+}
+
+pub async fn idempotency_turn(state: &StreamDb, key: &str) -> OwnedMutexGuard<()> {
+    item_stream_logic::idempotency_turn(state, key).await
+}
+
+pub fn recorded_response(state: &StreamDb, key: &str) -> Result<Option<RecordedResponse>, String> {
+    item_stream_logic::recorded_response(state, key)
+}
+
+pub fn record_response(
+    state: &StreamDb,
+    key: &str,
+    response: RecordedResponse,
+) -> Result<(), String> {
+    item_stream_logic::record_response(state, key, response)
+}
+
+pub fn is_audited(state: &StreamDb) -> bool {
+    item_stream_logic::is_audited(state)
+}
+
+pub fn append_audit_record(state: &StreamDb, record: &AuditRecord) -> Result<(), String> {
+    item_stream_logic::append_audit_record(state, record)
 }
@@ -0,0 +1,995 @@
+use crate::log_control::LogLevel;
+use crate::logic::commit_hooks::{self, HookSpec};
+use crate::logic::content_sniff::ContentSniffMode;
+use crate::logic::data_dir_watch::WatchMode;
+use crate::logic::extra_elements;
+use crate::logic::item_ids::{self, IdScheme};
+use crate::logic::property_redaction::{self, RedactionPolicy};
+use crate::metrics::MetricDatasets;
+use crate::persistence::io_engine::{FsyncPolicy, IoEngine};
+use crate::persistence::io_scheduler::IoSchedulingPolicy;
+use crate::persistence::read_sources::{ReadSource, SourcePolicy};
+use crate::persistence::shared_file::SlowReaderPolicy;
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Settings of one stream-db instance, usually read from `STREAM_DB_*` environment
+/// variables and the config file at startup.
+pub struct Config {
+    /// Directory holding the instance's data, metadata and index files
+    pub data_dir: String,
+    /// Directory uploads are written to until they commit, the data directory when
+    /// unset. On the same filesystem commits rename the data file into place, otherwise
+    /// they copy it.
+    pub inflight_dir: Option<String>,
+    /// Serve reads only, from a data directory another instance writes to
+    pub read_only: bool,
+    /// How often a read-only instance checks the data directory for versions that were
+    /// deleted, replaced or moved by the writing instance
+    pub replica_refresh_secs: u64,
+    /// Watch the data directory for versions committed by other processes, see
+    /// [`WatchMode`]
+    pub watch_mode: WatchMode,
+    /// Quiet time after a change before the watch looks at the changed items
+    pub watch_debounce_ms: u64,
+    /// How often the polling watch checks the metadata files
+    pub watch_poll_secs: u64,
+    /// Rule out lookups of items that are not stored before touching the disk, see
+    /// [`ItemFilter`](crate::persistence::item_filter::ItemFilter)
+    pub item_filter: bool,
+    /// Share of the items not stored the item filter lets through anyway
+    pub item_filter_false_positive_rate: f64,
+    /// Memory the item filter may take, at the expense of its false positive rate
+    pub item_filter_max_bytes: u64,
+    /// How often the item filter is built again, to forget deleted items
+    pub item_filter_rebuild_secs: u64,
+    /// How data files are read and written, see [`IoEngine`]
+    pub io_engine: IoEngine,
+    /// When uploads are synced to disk, see [`FsyncPolicy`]
+    pub fsync_policy: FsyncPolicy,
+    /// How uploads take turns on the blocking pool, see [`IoSchedulingPolicy`]
+    pub io_scheduling: IoSchedulingPolicy,
+    /// Operations of small uploads and metadata updates running at once
+    pub io_fast_slots: usize,
+    /// Operations of large uploads running at once
+    pub io_heavy_slots: usize,
+    /// Uploads move from the fast to the heavy lane once they have written this much
+    pub io_small_item_bytes: u64,
+    /// Largest partial property the property-aligned read mode buffers before it gives up
+    /// and passes the bytes through unaligned.
+    pub align_max_property_bytes: usize,
+    /// Wrap every newly written item in an `<item>` root element unless the request says
+    /// otherwise (`STREAM_DB_WRAP_ROOT=item|none`).
+    pub wrap_root: bool,
+    /// Largest upload whose properties can be sorted at commit, which rewrites the data
+    /// file in memory
+    pub canonicalize_max_bytes: u64,
+    /// Store gzip-compressed uploads as received instead of decoding them first, to save
+    /// the CPU. Readers that do not accept gzip get them decoded on the way out.
+    pub store_gzip_uploads: bool,
+    /// Commit uploads whose body ended without a `Content-Length`, end frame, expected
+    /// size or property count or trailer confirming it complete, as they all were before
+    /// that was checked
+    pub allow_unconfirmed_uploads: bool,
+    /// What happens to uploads whose first bytes do not look like the XML they were
+    /// declared as, see [`ContentSniffMode`]
+    pub content_sniff: ContentSniffMode,
+    /// Deleting a tagged version removes its tags instead of being refused
+    pub cascade_tag_deletes: bool,
+    /// Send an XML comment to property-aligned readers after this many seconds without
+    /// data, so proxies do not close connections to slow writers. Off when unset.
+    pub read_keepalive_secs: Option<u64>,
+    /// Chunks a read fetches ahead of the client, so disk reads overlap with sending.
+    /// Reads fetch each chunk only once the previous one was sent when 0.
+    pub read_prefetch_depth: usize,
+    /// How often per-item read counters are written to their stats files
+    pub stats_flush_secs: u64,
+    /// Directory committed versions are moved to once they have not been read for
+    /// `cold_after_secs`, tiering is off when either is unset
+    pub cold_dir: Option<String>,
+    pub cold_after_secs: Option<u64>,
+    /// Move a cold version back to the data directory when it is read
+    pub cold_promote_on_read: bool,
+    /// Allow failures to be injected into uploads and reads through `/admin/faults`,
+    /// meant for resilience testing only
+    pub fault_injection: bool,
+    /// Send clients the full message of internal errors, paths and OS errors included,
+    /// instead of a generic one. Meant for development, the log always has it.
+    pub expose_internal_errors: bool,
+    /// A debug capture of an upload keeps at most this many of its raw bytes
+    pub debug_capture_max_bytes: u64,
+    /// No new captures are taken while this many exist
+    pub debug_capture_max_count: usize,
+    /// Uploads are rejected once the committed and in-flight bytes would exceed this
+    pub quota_bytes: Option<u64>,
+    /// ... and once the in-flight bytes alone would exceed this, for an in-flight
+    /// directory on a volume of its own
+    pub inflight_quota_bytes: Option<u64>,
+    /// Names this node in the consistency tokens it issues
+    pub node_id: String,
+    /// Signs consistency tokens, nodes that accept each other's tokens share it
+    pub secret: Option<String>,
+    /// How long a read presenting a consistency token waits for its version to arrive
+    pub consistency_wait_ms: u64,
+    /// How `POST /items` generates the IDs of new items, see [`IdScheme`]
+    pub id_scheme: IdScheme,
+    /// Put in front of every generated item ID
+    pub id_prefix: Option<String>,
+    /// Largest frame accepted by the WebSocket upload endpoint
+    pub ws_max_frame_bytes: usize,
+    /// Versions `POST /admin/bulk-delete` deletes at a time
+    pub bulk_delete_concurrency: usize,
+    /// Most versions one `POST /items/stat-batch` may ask about
+    pub stat_batch_max: usize,
+    /// Files and items `GET /admin/space-report` inspects per second, unlimited when 0
+    pub space_report_files_per_second: u64,
+    /// Most frames one framed upload may hold, see
+    /// [`FRAMED_CONTENT_TYPE`](crate::logic::framing::FRAMED_CONTENT_TYPE)
+    pub max_segments: usize,
+    /// Largest round trip `POST /admin/selftest` may be asked for, in MiB
+    pub selftest_max_mb: u64,
+    /// How long the response to a request carrying an `Idempotency-Key` is replayed
+    pub idempotency_ttl_secs: u64,
+    /// Recorded responses kept at most, the least recently used ones are dropped first
+    pub idempotency_max_keys: usize,
+    /// Highest version number accepted in paths, versions start at 1
+    pub max_version: u64,
+    /// Refuse uploads of versions more than this above the item's latest version, so a
+    /// producer sending e.g. a timestamp does not lock the item out of its versioning.
+    /// Any increase is allowed when unset.
+    pub max_version_jump: Option<u64>,
+    /// Top-level elements other than properties that uploads may carry, e.g. `summary`,
+    /// copied into the receipt of their version
+    pub extra_elements: Vec<String>,
+    /// Extra elements larger than this are stored but not copied into the receipt
+    pub extra_element_max_bytes: usize,
+    /// Keep an index of the property names of every committed version for `/search`
+    pub property_name_index: bool,
+    /// How often a graceful shutdown logs the streams it is still waiting for
+    pub drain_report_secs: u64,
+    /// Cut the remaining streams off once a graceful shutdown took this long, never by default
+    pub drain_timeout_secs: Option<u64>,
+    /// Bearer token granting access to the `/admin` endpoints, which are disabled without one
+    pub admin_token: Option<String>,
+    /// Bearer token reads have to present, besides the admin token. Reads are open to
+    /// anyone when unset.
+    pub read_token: Option<String>,
+    /// Longest expiry a presigned read URL may be given
+    pub presign_max_secs: u64,
+    /// Build a block index in the background after every commit
+    pub reindex_on_commit: bool,
+    /// Committed versions smaller than this are not worth a block index
+    pub reindex_min_bytes: u64,
+    /// A block index records a boundary at least every this many properties
+    pub reindex_block_properties: u64,
+    /// ... and at least every this many bytes
+    pub reindex_block_bytes: u64,
+    /// Items whose latest version is opened at startup, before anyone reads them
+    pub warmup_items: Vec<String>,
+    /// Also warm up this many of the most read items according to their stats files
+    pub warmup_top_n: Option<usize>,
+    /// Bytes read ahead from the start of every warmed up version
+    pub warmup_readahead_bytes: u64,
+    /// Warm-up reads ahead at most this many bytes in total
+    pub warmup_max_bytes: u64,
+    /// ... and gives up after this many seconds
+    pub warmup_max_secs: u64,
+    /// File handles the persistence layer may keep open. Idle versions are closed when
+    /// it comes close, and new streams are refused once it is reached. Unlimited when
+    /// unset.
+    pub max_open_files: Option<u64>,
+    /// Megabytes an upload writes between two checkpoints of its journal, which are all
+    /// an upload interrupted by a crash keeps. No journal is written when 0.
+    pub journal_interval_mb: u64,
+    /// Megabytes a reader of an in-flight upload may fall behind what it could read
+    /// before `slow_reader_policy` applies, unlimited when unset
+    pub slow_reader_max_lag_mb: Option<u64>,
+    /// What happens to readers falling further behind, see [`SlowReaderPolicy`]
+    pub slow_reader_policy: SlowReaderPolicy,
+    /// Where reads look for their version, in this order unless `read_source_policy`
+    /// reorders them
+    pub read_sources: Vec<ReadSource>,
+    pub read_source_policy: SourcePolicy,
+    /// Milliseconds a read may spend trying sources before it gives up with 503
+    pub read_source_budget_ms: u64,
+    /// Milliseconds a version no read source had is answered with 404 without looking
+    /// again, unless a commit or the data directory watch changes its item first
+    pub negative_cache_ttl_ms: u64,
+    /// Programs run after every commit of this instance, from the JSON file named by
+    /// `STREAM_DB_COMMIT_HOOKS_FILE`
+    pub commit_hooks: Vec<HookSpec>,
+    /// Hooks run at the same time across all hooks
+    pub hook_workers: usize,
+    /// Hook runs waiting for a worker, further ones are dropped
+    pub hook_queue: usize,
+    /// Uploads of one item that may run at once, each of a different version. With more
+    /// than one, a version overtaken by a newer one committing first fails its commit.
+    pub max_in_flight_per_item: usize,
+    /// Uploads that may run at once across all items, unbounded when unset
+    pub max_in_flight_uploads: Option<usize>,
+    /// Uploads sent with `X-Write-Queue` that may wait for one item, further ones get 429
+    pub write_queue_max_depth: usize,
+    /// Uploads that may wait across all items
+    pub write_queue_max_total: usize,
+    /// Longest wait an `X-Write-Queue` header may ask for
+    pub write_queue_max_wait_secs: u64,
+    /// Longest time a publish group may stay open before it is aborted
+    pub publish_group_max_ttl_secs: u64,
+    /// Address of a second listener serving the S3-compatible API, off when unset
+    pub s3_addr: Option<String>,
+    /// The key S3 requests have to be signed with. The S3 API is open to anyone when
+    /// unset.
+    pub s3_credentials: Option<S3Credentials>,
+    /// Verify committed versions against their checksums in the background
+    pub verify_sweep: bool,
+    /// Pause between two cycles of the sweep
+    pub verify_interval_secs: u64,
+    /// Share of the versions due that one cycle verifies, 1 to 100
+    pub verify_sample_percent: u64,
+    /// Versions verified within this many days are not due again
+    pub verify_skip_days: u64,
+    /// File every upload, change and deletion is appended to as a JSON line, none when
+    /// unset
+    pub audit_log_file: Option<String>,
+    /// Properties reads drop or mask unless an admin asks for them unredacted
+    pub redaction: RedactionPolicy,
+    /// Level the log starts out at, see [`crate::log_control`]
+    pub log_level: LogLevel,
+    /// Items whose streams write debug lines whatever the level
+    pub debug_items: Vec<String>,
+    /// Unknown settings are only warned about, with `--allow-unknown-config`
+    pub allow_unknown: bool,
+    /// Settings `POST /admin/config/reload` can change while the instance runs
+    live: RwLock<LiveSettings>,
+    /// Item ID prefixes the per-dataset metrics are broken down by, also changed by
+    /// `POST /admin/config/reload`
+    metric_datasets: RwLock<MetricDatasets>,
+    /// Every setting the instance runs with and where it came from
+    settings: RwLock<BTreeMap<&'static str, Setting>>,
+}
+
+/// The settings taking effect without a restart, read through [`Config::live`]
+#[derive(Clone, Copy)]
+pub struct LiveSettings {
+    /// Largest single property element accepted by the write path, unlimited when unset
+    pub max_property_bytes: Option<u64>,
+    /// Largest number of properties accepted in a single version, unlimited when unset
+    pub max_properties_per_item: Option<u64>,
+    /// Largest upload accepted for a single version in bytes, unlimited when unset
+    pub max_item_bytes: Option<u64>,
+    /// Read throughput the background reindexer is limited to, so it does not starve
+    /// foreground reads and writes
+    pub reindex_bytes_per_second: u64,
+    /// Read throughput `POST /admin/backfill-metadata` is limited to, unlimited when 0
+    pub backfill_bytes_per_second: u64,
+    /// Bytes the verification sweep reads per second, unlimited when 0
+    pub verify_bytes_per_second: u64,
+    /// How long the leftovers of uploads interrupted by a crash are kept before they are
+    /// purged, forever when unset
+    pub failed_upload_retention_secs: Option<u64>,
+    /// Debug captures are purged once they are this old
+    pub debug_capture_retention_secs: u64,
+    /// Log a line for every request once its response ended
+    pub access_log: bool,
+}
+
+impl Config {
+    /// Read the settings from `STREAM_DB_*` environment variables and the TOML file named
+    /// by `STREAM_DB_CONFIG_FILE`, refusing unknown ones
+    pub fn from_env() -> Result<Self, String> {
+        Self::load(false)
+    }
+
+    /// Like [`Config::from_env`], only warning about unknown settings when
+    /// `allow_unknown` is set
+    pub fn load(allow_unknown: bool) -> Result<Self, String> {
+        let mut loader = Loader::new()?;
+        let config = Self {
+            data_dir: loader.string_or("STREAM_DB_DATA_DIR", "tmp_outputs"),
+            inflight_dir: loader.string("STREAM_DB_INFLIGHT_DIR"),
+            read_only: loader.or("STREAM_DB_READ_ONLY", false)?,
+            replica_refresh_secs: loader.or("STREAM_DB_REPLICA_REFRESH_SECS", 2)?,
+            watch_mode: WatchMode::parse(&loader.string_or("STREAM_DB_WATCH", "off"))
+                .map_err(|error| format!("Invalid value for STREAM_DB_WATCH: {error}"))?,
+            watch_debounce_ms: loader.or("STREAM_DB_WATCH_DEBOUNCE_MS", 200)?,
+            watch_poll_secs: loader.or("STREAM_DB_WATCH_POLL_SECS", 5)?,
+            item_filter: loader.or("STREAM_DB_ITEM_FILTER", false)?,
+            item_filter_false_positive_rate: loader
+                .or("STREAM_DB_ITEM_FILTER_FALSE_POSITIVE_RATE", 0.01)?,
+            item_filter_max_bytes: loader
+                .or("STREAM_DB_ITEM_FILTER_MAX_BYTES", 64 * 1024 * 1024)?,
+            item_filter_rebuild_secs: loader.or("STREAM_DB_ITEM_FILTER_REBUILD_SECS", 3600)?,
+            io_engine: IoEngine::parse(&loader.string_or("STREAM_DB_IO_ENGINE", "tokio"))
+                .map_err(|error| format!("Invalid value for STREAM_DB_IO_ENGINE: {error}"))?,
+            fsync_policy: FsyncPolicy::parse(&loader.string_or("STREAM_DB_FSYNC", "chunk"))
+                .map_err(|error| format!("Invalid value for STREAM_DB_FSYNC: {error}"))?,
+            io_scheduling: IoSchedulingPolicy::parse(
+                &loader.string_or("STREAM_DB_IO_SCHEDULING", "fair"),
+            )
+            .map_err(|error| format!("Invalid value for STREAM_DB_IO_SCHEDULING: {error}"))?,
+            io_fast_slots: loader.or("STREAM_DB_IO_FAST_SLOTS", 16)?,
+            io_heavy_slots: loader.or("STREAM_DB_IO_HEAVY_SLOTS", 4)?,
+            io_small_item_bytes: loader.or("STREAM_DB_IO_SMALL_ITEM_BYTES", 8 * 1024 * 1024)?,
+            align_max_property_bytes: loader
+                .or("STREAM_DB_ALIGN_MAX_PROPERTY_BYTES", 16 * 1024 * 1024)?,
+            wrap_root: match loader.string_or("STREAM_DB_WRAP_ROOT", "none").as_str() {
+                "none" => false,
+                "item" => true,
+                other => {
+                    return Err(format!("Invalid value for STREAM_DB_WRAP_ROOT: {other:?}"));
+                }
+            },
+            canonicalize_max_bytes: loader
+                .or("STREAM_DB_CANONICALIZE_MAX_BYTES", 64 * 1024 * 1024)?,
+            store_gzip_uploads: loader.or("STREAM_DB_STORE_GZIP_UPLOADS", false)?,
+            allow_unconfirmed_uploads: loader.or("STREAM_DB_ALLOW_UNCONFIRMED_UPLOADS", false)?,
+            content_sniff: ContentSniffMode::parse(
+                &loader.string_or("STREAM_DB_CONTENT_SNIFF", "warn"),
+            )
+            .map_err(|error| format!("Invalid value for STREAM_DB_CONTENT_SNIFF: {error}"))?,
+            cascade_tag_deletes: loader.or("STREAM_DB_CASCADE_TAG_DELETES", false)?,
+            read_keepalive_secs: loader.opt("STREAM_DB_READ_KEEPALIVE_SECS")?,
+            read_prefetch_depth: loader.or("STREAM_DB_READ_PREFETCH_DEPTH", 4)?,
+            stats_flush_secs: loader.or("STREAM_DB_STATS_FLUSH_SECS", 30)?,
+            cold_dir: loader.string("STREAM_DB_COLD_DIR"),
+            cold_after_secs: loader.opt("STREAM_DB_COLD_AFTER_SECS")?,
+            cold_promote_on_read: loader.or("STREAM_DB_COLD_PROMOTE_ON_READ", false)?,
+            fault_injection: loader.or("STREAM_DB_FAULT_INJECTION", false)?,
+            expose_internal_errors: loader.or("STREAM_DB_EXPOSE_INTERNAL_ERRORS", false)?,
+            debug_capture_max_bytes: loader
+                .or("STREAM_DB_DEBUG_CAPTURE_MAX_BYTES", 16 * 1024 * 1024)?,
+            debug_capture_max_count: loader.or("STREAM_DB_DEBUG_CAPTURE_MAX_COUNT", 20)?,
+            quota_bytes: loader.opt("STREAM_DB_QUOTA_BYTES")?,
+            inflight_quota_bytes: loader.opt("STREAM_DB_INFLIGHT_QUOTA_BYTES")?,
+            node_id: loader.string_or("STREAM_DB_NODE_ID", "local"),
+            secret: loader.string("STREAM_DB_SECRET"),
+            consistency_wait_ms: loader.or("STREAM_DB_CONSISTENCY_WAIT_MS", 2000)?,
+            id_scheme: IdScheme::parse(&loader.string_or("STREAM_DB_ID_SCHEME", "uuid7"))
+                .map_err(|error| format!("Invalid value for STREAM_DB_ID_SCHEME: {error}"))?,
+            id_prefix: match loader.string("STREAM_DB_ID_PREFIX") {
+                Some(prefix) => {
+                    item_ids::validate_prefix(&prefix).map_err(|error| {
+                        format!("Invalid value for STREAM_DB_ID_PREFIX: {error}")
+                    })?;
+                    Some(prefix)
+                }
+                None => None,
+            },
+            ws_max_frame_bytes: loader.or("STREAM_DB_WS_MAX_FRAME_BYTES", 16 * 1024 * 1024)?,
+            bulk_delete_concurrency: loader.or("STREAM_DB_BULK_DELETE_CONCURRENCY", 4)?,
+            space_report_files_per_second: loader
+                .or("STREAM_DB_SPACE_REPORT_FILES_PER_SECOND", 10_000)?,
+            stat_batch_max: loader.or("STREAM_DB_STAT_BATCH_MAX", 10_000)?,
+            max_segments: loader.or("STREAM_DB_MAX_SEGMENTS", 10_000)?,
+            selftest_max_mb: loader.or("STREAM_DB_SELFTEST_MAX_MB", 1024)?,
+            idempotency_ttl_secs: loader.or("STREAM_DB_IDEMPOTENCY_TTL_SECS", 86400)?,
+            idempotency_max_keys: loader.or("STREAM_DB_IDEMPOTENCY_MAX_KEYS", 1000)?,
+            // The largest integer JSON clients represent exactly
+            max_version: loader.or("STREAM_DB_MAX_VERSION", (1u64 << 53) - 1)?,
+            max_version_jump: loader.opt("STREAM_DB_MAX_VERSION_JUMP")?,
+            extra_elements: loader
+                .list("STREAM_DB_EXTRA_ELEMENTS")
+                .into_iter()
+                .map(|name| {
+                    extra_elements::validate_name(&name)
+                        .map(|_| name)
+                        .map_err(|error| {
+                            format!("Invalid value for STREAM_DB_EXTRA_ELEMENTS: {error}")
+                        })
+                })
+                .collect::<Result<_, _>>()?,
+            extra_element_max_bytes: loader.or("STREAM_DB_EXTRA_ELEMENT_MAX_BYTES", 64 * 1024)?,
+            property_name_index: loader.or("STREAM_DB_PROPERTY_NAME_INDEX", false)?,
+            drain_report_secs: loader.or("STREAM_DB_DRAIN_REPORT_SECS", 5)?,
+            drain_timeout_secs: loader.opt("STREAM_DB_DRAIN_TIMEOUT_SECS")?,
+            admin_token: loader.string("STREAM_DB_ADMIN_TOKEN"),
+            read_token: loader.string("STREAM_DB_READ_TOKEN"),
+            presign_max_secs: loader.or("STREAM_DB_PRESIGN_MAX_SECS", 7 * 86400)?,
+            reindex_on_commit: loader.or("STREAM_DB_REINDEX_ON_COMMIT", true)?,
+            reindex_min_bytes: loader.or("STREAM_DB_REINDEX_MIN_BYTES", 64 * 1024 * 1024)?,
+            reindex_block_properties: loader.or("STREAM_DB_REINDEX_BLOCK_PROPERTIES", 1024)?,
+            reindex_block_bytes: loader.or("STREAM_DB_REINDEX_BLOCK_BYTES", 1024 * 1024)?,
+            warmup_items: loader.list("STREAM_DB_WARMUP_ITEMS"),
+            warmup_top_n: loader.opt("STREAM_DB_WARMUP_TOP_N")?,
+            warmup_readahead_bytes: loader.or("STREAM_DB_WARMUP_READAHEAD_BYTES", 0)?,
+            warmup_max_bytes: loader.or("STREAM_DB_WARMUP_MAX_BYTES", 256 * 1024 * 1024)?,
+            warmup_max_secs: loader.or("STREAM_DB_WARMUP_MAX_SECS", 60)?,
+            max_open_files: loader.opt("STREAM_DB_MAX_OPEN_FILES")?,
+            journal_interval_mb: loader.or("STREAM_DB_JOURNAL_INTERVAL_MB", 0)?,
+            slow_reader_max_lag_mb: loader.opt("STREAM_DB_SLOW_READER_MAX_LAG_MB")?,
+            slow_reader_policy: SlowReaderPolicy::parse(
+                &loader.string_or("STREAM_DB_SLOW_READER_POLICY", "disk"),
+            )
+            .map_err(|error| format!("Invalid value for STREAM_DB_SLOW_READER_POLICY: {error}"))?,
+            read_sources: ReadSource::parse_list(
+                &loader.string_or("STREAM_DB_READ_SOURCES", "registry,hot,cold"),
+            )
+            .map_err(|error| format!("Invalid value for STREAM_DB_READ_SOURCES: {error}"))?,
+            read_source_policy: SourcePolicy::parse(
+                &loader.string_or("STREAM_DB_READ_SOURCE_POLICY", "ordered"),
+            )
+            .map_err(|error| format!("Invalid value for STREAM_DB_READ_SOURCE_POLICY: {error}"))?,
+            read_source_budget_ms: loader.or("STREAM_DB_READ_SOURCE_BUDGET_MS", 5000)?,
+            negative_cache_ttl_ms: loader.or("STREAM_DB_NEGATIVE_CACHE_TTL_MS", 1000)?,
+            commit_hooks: commit_hooks::load_specs(
+                loader.string("STREAM_DB_COMMIT_HOOKS_FILE").as_deref(),
+            )?,
+            hook_workers: loader.or("STREAM_DB_HOOK_WORKERS", 4)?,
+            hook_queue: loader.or("STREAM_DB_HOOK_QUEUE", 1024)?,
+            max_in_flight_per_item: loader.or("STREAM_DB_MAX_IN_FLIGHT_PER_ITEM", 1)?,
+            max_in_flight_uploads: loader.opt("STREAM_DB_MAX_IN_FLIGHT_UPLOADS")?,
+            write_queue_max_depth: loader.or("STREAM_DB_WRITE_QUEUE_MAX_DEPTH", 8)?,
+            write_queue_max_total: loader.or("STREAM_DB_WRITE_QUEUE_MAX_TOTAL", 1024)?,
+            write_queue_max_wait_secs: loader.or("STREAM_DB_WRITE_QUEUE_MAX_WAIT_SECS", 60)?,
+            publish_group_max_ttl_secs: loader.or("STREAM_DB_PUBLISH_GROUP_MAX_TTL_SECS", 86400)?,
+            s3_addr: loader.string("STREAM_DB_S3_ADDR"),
+            s3_credentials: s3_credentials(&mut loader)?,
+            verify_sweep: loader.or("STREAM_DB_VERIFY_SWEEP", false)?,
+            verify_interval_secs: loader.or("STREAM_DB_VERIFY_INTERVAL_SECS", 3600)?,
+            verify_sample_percent: loader.or("STREAM_DB_VERIFY_SAMPLE_PERCENT", 100)?,
+            verify_skip_days: loader.or("STREAM_DB_VERIFY_SKIP_DAYS", 7)?,
+            audit_log_file: loader.string("STREAM_DB_AUDIT_LOG_FILE"),
+            redaction: RedactionPolicy::new(
+                loader.list("STREAM_DB_REDACT_DROP"),
+                loader.list("STREAM_DB_REDACT_MASK"),
+                loader.string_or(
+                    "STREAM_DB_REDACT_PLACEHOLDER",
+                    property_redaction::DEFAULT_PLACEHOLDER,
+                ),
+            ),
+            log_level: LogLevel::parse(&loader.string_or("STREAM_DB_LOG_LEVEL", "info"))
+                .map_err(|error| format!("Invalid value for STREAM_DB_LOG_LEVEL: {error}"))?,
+            debug_items: loader.list("STREAM_DB_DEBUG_ITEMS"),
+            allow_unknown,
+            live: RwLock::new(LiveSettings {
+                max_property_bytes: loader.opt("STREAM_DB_MAX_PROPERTY_BYTES")?,
+                max_properties_per_item: loader.opt("STREAM_DB_MAX_PROPERTIES_PER_ITEM")?,
+                max_item_bytes: loader.opt("STREAM_DB_MAX_ITEM_BYTES")?,
+                reindex_bytes_per_second: loader
+                    .or("STREAM_DB_REINDEX_BYTES_PER_SECOND", 32 * 1024 * 1024)?,
+                backfill_bytes_per_second: loader
+                    .or("STREAM_DB_BACKFILL_BYTES_PER_SECOND", 32 * 1024 * 1024)?,
+                verify_bytes_per_second: loader
+                    .or("STREAM_DB_VERIFY_BYTES_PER_SECOND", 8 * 1024 * 1024)?,
+                failed_upload_retention_secs: loader
+                    .opt("STREAM_DB_FAILED_UPLOAD_RETENTION_SECS")?,
+                debug_capture_retention_secs: loader
+                    .or("STREAM_DB_DEBUG_CAPTURE_RETENTION_SECS", 86400)?,
+                access_log: loader.or("STREAM_DB_ACCESS_LOG", false)?,
+            }),
+            metric_datasets: RwLock::new(
+                MetricDatasets::parse(
+                    &loader.list("STREAM_DB_METRIC_DATASETS"),
+                    loader.or("STREAM_DB_METRIC_LABEL_VALUES_MAX", 32)?,
+                )
+                .map_err(|error| format!("Invalid value for STREAM_DB_METRIC_DATASETS: {error}"))?,
+            ),
+            settings: RwLock::default(),
+        };
+        config.validate()?;
+        *config.settings.write().unwrap() = loader.finish(allow_unknown)?;
+        Ok(config)
+    }
+
+    /// Checks across settings, each of which parsed on its own
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.verify_sample_percent) {
+            return Err(format!(
+                "STREAM_DB_VERIFY_SAMPLE_PERCENT must be between 1 and 100, not {}",
+                self.verify_sample_percent
+            ));
+        }
+        if !(self.item_filter_false_positive_rate > 0.0
+            && self.item_filter_false_positive_rate < 1.0)
+        {
+            return Err(format!(
+                "STREAM_DB_ITEM_FILTER_FALSE_POSITIVE_RATE must be between 0 and 1, not {}",
+                self.item_filter_false_positive_rate
+            ));
+        }
+        // Reads through the S3 API would bypass the read token
+        if self.s3_addr.is_some() && self.read_token.is_some() && self.s3_credentials.is_none() {
+            return Err(
+                "STREAM_DB_S3_ADDR needs STREAM_DB_S3_ACCESS_KEY and STREAM_DB_S3_SECRET_KEY when STREAM_DB_READ_TOKEN is set"
+                    .to_string(),
+            );
+        }
+        // Uploads would show up as files of the data directory
+        if let Some(inflight_dir) = &self.inflight_dir
+            && resolve(inflight_dir).starts_with(resolve(&self.data_dir))
+        {
+            return Err(format!(
+                "STREAM_DB_INFLIGHT_DIR {inflight_dir:?} must not be inside STREAM_DB_DATA_DIR {:?}",
+                self.data_dir
+            ));
+        }
+        let nonzero = [
+            (
+                "STREAM_DB_REPLICA_REFRESH_SECS",
+                Some(self.replica_refresh_secs),
+            ),
+            ("STREAM_DB_WATCH_POLL_SECS", Some(self.watch_poll_secs)),
+            (
+                "STREAM_DB_ITEM_FILTER_MAX_BYTES",
+                Some(self.item_filter_max_bytes),
+            ),
+            (
+                "STREAM_DB_ITEM_FILTER_REBUILD_SECS",
+                Some(self.item_filter_rebuild_secs),
+            ),
+            ("STREAM_DB_READ_KEEPALIVE_SECS", self.read_keepalive_secs),
+            ("STREAM_DB_PRESIGN_MAX_SECS", Some(self.presign_max_secs)),
+            (
+                "STREAM_DB_PUBLISH_GROUP_MAX_TTL_SECS",
+                Some(self.publish_group_max_ttl_secs),
+            ),
+            ("STREAM_DB_DRAIN_REPORT_SECS", Some(self.drain_report_secs)),
+            ("STREAM_DB_DRAIN_TIMEOUT_SECS", self.drain_timeout_secs),
+            (
+                "STREAM_DB_VERIFY_INTERVAL_SECS",
+                Some(self.verify_interval_secs),
+            ),
+            ("STREAM_DB_IO_FAST_SLOTS", Some(self.io_fast_slots as u64)),
+            ("STREAM_DB_IO_HEAVY_SLOTS", Some(self.io_heavy_slots as u64)),
+            ("STREAM_DB_HOOK_WORKERS", Some(self.hook_workers as u64)),
+            (
+                "STREAM_DB_MAX_IN_FLIGHT_PER_ITEM",
+                Some(self.max_in_flight_per_item as u64),
+            ),
+            (
+                "STREAM_DB_MAX_IN_FLIGHT_UPLOADS",
+                self.max_in_flight_uploads.map(|max| max as u64),
+            ),
+            (
+                "STREAM_DB_WRITE_QUEUE_MAX_DEPTH",
+                Some(self.write_queue_max_depth as u64),
+            ),
+            (
+                "STREAM_DB_WRITE_QUEUE_MAX_TOTAL",
+                Some(self.write_queue_max_total as u64),
+            ),
+            (
+                "STREAM_DB_BULK_DELETE_CONCURRENCY",
+                Some(self.bulk_delete_concurrency as u64),
+            ),
+            ("STREAM_DB_STAT_BATCH_MAX", Some(self.stat_batch_max as u64)),
+            (
+                "STREAM_DB_METRIC_LABEL_VALUES_MAX",
+                Some(self.metric_datasets.read().unwrap().max_label_values as u64),
+            ),
+            ("STREAM_DB_MAX_SEGMENTS", Some(self.max_segments as u64)),
+            (
+                "STREAM_DB_READ_SOURCE_BUDGET_MS",
+                Some(self.read_source_budget_ms),
+            ),
+        ];
+        if let Some((name, _)) = nonzero.iter().find(|(_, value)| *value == Some(0)) {
+            return Err(format!("{name} must not be 0"));
+        }
+        Ok(())
+    }
+
+    /// The settings that may change while the instance runs, as they are now
+    pub fn live(&self) -> LiveSettings {
+        *self.live.read().unwrap()
+    }
+
+    /// The live settings of a config not shared yet, to change them before it is
+    pub fn live_mut(&mut self) -> &mut LiveSettings {
+        self.live.get_mut().unwrap()
+    }
+
+    /// The datasets of the per-dataset metrics, as they are now
+    pub fn metric_datasets(&self) -> MetricDatasets {
+        self.metric_datasets.read().unwrap().clone()
+    }
+
+    /// Every setting, secrets redacted
+    pub fn settings(&self) -> BTreeMap<&'static str, Setting> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Log the settings that differ from their defaults, secrets redacted
+    pub fn log_settings(&self) {
+        let settings = self.settings();
+        let defaults = settings
+            .values()
+            .filter(|setting| setting.source == SettingSource::Default)
+            .count();
+        for (name, setting) in &settings {
+            if setting.source != SettingSource::Default {
+                println!(
+                    "Config: {name}={} (from {})",
+                    setting.value.as_deref().unwrap_or_default(),
+                    setting.source.name()
+                );
+            }
+        }
+        println!("Config: {defaults} more settings at their defaults");
+    }
+
+    /// Read the settings again and apply the changed ones that take effect without a
+    /// restart. Nothing is applied when any setting is invalid.
+    pub fn reload(&self) -> Result<ConfigReload, String> {
+        let reloaded = Self::load(self.allow_unknown)?;
+        let fresh = reloaded.settings.into_inner().unwrap();
+        let mut settings = self.settings.write().unwrap();
+        let mut report = ConfigReload::default();
+        for (name, setting) in fresh {
+            let Some(current) = settings.get_mut(name) else {
+                continue;
+            };
+            if current.raw == setting.raw {
+                continue;
+            }
+            let change = SettingChange {
+                name,
+                old: current.value.clone(),
+                new: setting.value.clone(),
+            };
+            if setting.reloadable {
+                *current = setting;
+                report.applied.push(change);
+            } else {
+                report.restart_required.push(change);
+            }
+        }
+        *self.live.write().unwrap() = *reloaded.live.read().unwrap();
+        *self.metric_datasets.write().unwrap() = reloaded.metric_datasets.into_inner().unwrap();
+        Ok(report)
+    }
+}
+
+/// Where a setting came from
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingSource {
+    Env,
+    File,
+    Default,
+    /// Changed through the admin API while the instance runs
+    Admin,
+}
+
+impl SettingSource {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Env => "env",
+            Self::File => "file",
+            Self::Default => "default",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// One setting as the instance runs with it, see `GET /admin/config`
+#[derive(Serialize, Clone)]
+pub struct Setting {
+    /// `null` when unset, redacted for secrets
+    pub value: Option<String>,
+    pub source: SettingSource,
+    /// Changed by `POST /admin/config/reload`, the others only by a restart
+    pub reloadable: bool,
+    /// When a value set through the admin API runs out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    /// The value as read, compared on reload
+    #[serde(skip)]
+    raw: Option<String>,
+}
+
+impl Setting {
+    /// A setting changed through the admin API to `value` until `expires_at`
+    pub fn overridden(value: String, expires_at: Option<String>) -> Self {
+        Self {
+            value: Some(value.clone()),
+            source: SettingSource::Admin,
+            reloadable: false,
+            expires_at,
+            raw: Some(value),
+        }
+    }
+}
+
+/// A setting that changed on reload, secrets redacted
+#[derive(Serialize)]
+pub struct SettingChange {
+    pub name: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// What `POST /admin/config/reload` found changed
+#[derive(Serialize, Default)]
+pub struct ConfigReload {
+    /// In effect right away
+    pub applied: Vec<SettingChange>,
+    /// Left as they were until the next restart
+    pub restart_required: Vec<SettingChange>,
+}
+
+/// Access key ID and secret key of the S3 API
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+const ENV_PREFIX: &str = "STREAM_DB_";
+
+/// Names the TOML file settings are read from, besides the environment
+const CONFIG_FILE: &str = "STREAM_DB_CONFIG_FILE";
+
+/// Settings shown as `<redacted>`
+const SECRETS: &[&str] = &[
+    "STREAM_DB_ADMIN_TOKEN",
+    "STREAM_DB_READ_TOKEN",
+    "STREAM_DB_SECRET",
+    "STREAM_DB_S3_SECRET_KEY",
+];
+
+/// Settings kept in [`LiveSettings`]
+const RELOADABLE: &[&str] = &[
+    "STREAM_DB_MAX_PROPERTY_BYTES",
+    "STREAM_DB_MAX_PROPERTIES_PER_ITEM",
+    "STREAM_DB_MAX_ITEM_BYTES",
+    "STREAM_DB_REINDEX_BYTES_PER_SECOND",
+    "STREAM_DB_BACKFILL_BYTES_PER_SECOND",
+    "STREAM_DB_VERIFY_BYTES_PER_SECOND",
+    "STREAM_DB_FAILED_UPLOAD_RETENTION_SECS",
+    "STREAM_DB_DEBUG_CAPTURE_RETENTION_SECS",
+    "STREAM_DB_ACCESS_LOG",
+    "STREAM_DB_METRIC_DATASETS",
+    "STREAM_DB_METRIC_LABEL_VALUES_MAX",
+];
+
+/// Reads every setting from the environment or else the config file, and remembers
+/// which it read so the ones left over can be refused as unknown
+struct Loader {
+    /// Values of the config file, by their key in it
+    file: BTreeMap<String, String>,
+    file_path: Option<String>,
+    settings: BTreeMap<&'static str, Setting>,
+}
+
+impl Loader {
+    fn new() -> Result<Self, String> {
+        let mut loader = Self {
+            file: BTreeMap::new(),
+            file_path: None,
+            settings: BTreeMap::new(),
+        };
+        // Only the environment can name the file
+        let file_path = std::env::var(CONFIG_FILE)
+            .ok()
+            .filter(|path| !path.is_empty());
+        loader.record(
+            CONFIG_FILE,
+            file_path.clone(),
+            if file_path.is_some() {
+                SettingSource::Env
+            } else {
+                SettingSource::Default
+            },
+        );
+        if let Some(path) = file_path {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|error| format!("Could not read {path}: {error}"))?;
+            let table: toml::Table = toml::from_str(&text)
+                .map_err(|error| format!("Could not parse {path}: {error}"))?;
+            for (key, value) in table {
+                let value = file_value(&value).ok_or_else(|| {
+                    format!("{key} in {path} must be a string, number, boolean or list of them")
+                })?;
+                loader.file.insert(key, value);
+            }
+            loader.file_path = Some(path);
+        }
+        Ok(loader)
+    }
+
+    /// The setting's value from the environment, or else from the config file, where
+    /// `STREAM_DB_CHUNK_SIZE` is written `chunk_size`
+    fn raw(&self, name: &str) -> Option<(String, SettingSource)> {
+        if let Ok(value) = std::env::var(name) {
+            return Some((value, SettingSource::Env));
+        }
+        self.file
+            .get(&file_key(name))
+            .map(|value| (value.clone(), SettingSource::File))
+    }
+
+    fn record(&mut self, name: &'static str, raw: Option<String>, source: SettingSource) {
+        let value = match &raw {
+            Some(_) if SECRETS.contains(&name) => Some("<redacted>".to_string()),
+            raw => raw.clone(),
+        };
+        self.settings.insert(
+            name,
+            Setting {
+                value,
+                source,
+                reloadable: RELOADABLE.contains(&name),
+                expires_at: None,
+                raw,
+            },
+        );
+    }
+
+    /// A string setting, unset when empty
+    fn string(&mut self, name: &'static str) -> Option<String> {
+        match self.raw(name).filter(|(value, _)| !value.is_empty()) {
+            Some((value, source)) => {
+                self.record(name, Some(value.clone()), source);
+                Some(value)
+            }
+            None => {
+                self.record(name, None, SettingSource::Default);
+                None
+            }
+        }
+    }
+
+    fn string_or(&mut self, name: &'static str, default: &str) -> String {
+        self.string(name).unwrap_or_else(|| {
+            self.record(name, Some(default.to_string()), SettingSource::Default);
+            default.to_string()
+        })
+    }
+
+    fn or<T: FromStr + ToString>(&mut self, name: &'static str, default: T) -> Result<T, String> {
+        match self.opt(name)? {
+            Some(value) => Ok(value),
+            None => {
+                self.record(name, Some(default.to_string()), SettingSource::Default);
+                Ok(default)
+            }
+        }
+    }
+
+    fn opt<T: FromStr>(&mut self, name: &'static str) -> Result<Option<T>, String> {
+        match self.raw(name) {
+            Some((value, source)) => {
+                let parsed = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {name}: {value:?}"))?;
+                self.record(name, Some(value), source);
+                Ok(Some(parsed))
+            }
+            None => {
+                self.record(name, None, SettingSource::Default);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Comma separated values, empty when unset
+    fn list(&mut self, name: &'static str) -> Vec<String> {
+        self.string(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// The settings read, once no unknown ones are left in the environment or the
+    /// config file
+    fn finish(self, allow_unknown: bool) -> Result<BTreeMap<&'static str, Setting>, String> {
+        let mut unknown: Vec<String> = std::env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .filter(|name| {
+                name.starts_with(ENV_PREFIX) && !self.settings.contains_key(name.as_str())
+            })
+            .map(|name| self.describe_unknown(&name, &name))
+            .collect();
+        if let Some(path) = &self.file_path {
+            unknown.extend(
+                self.file
+                    .keys()
+                    .filter(|key| {
+                        let name = format!("{ENV_PREFIX}{}", key.to_uppercase());
+                        key.to_lowercase() != **key || !self.settings.contains_key(name.as_str())
+                    })
+                    .map(|key| {
+                        let name = format!("{ENV_PREFIX}{}", key.to_uppercase());
+                        format!("{} in {path}", self.describe_unknown(key, &name))
+                    }),
+            );
+        }
+        if unknown.is_empty() {
+            return Ok(self.settings);
+        }
+        if !allow_unknown {
+            return Err(format!(
+                "Unknown settings {}, start with --allow-unknown-config to ignore them",
+                unknown.join(", ")
+            ));
+        }
+        for unknown in unknown {
+            println!("WARNING: Ignoring unknown setting {unknown}");
+        }
+        Ok(self.settings)
+    }
+
+    /// `shown` quoted, with the known setting closest to `name` if it looks like a typo
+    fn describe_unknown(&self, shown: &str, name: &str) -> String {
+        let closest = self
+            .settings
+            .keys()
+            .map(|known| (edit_distance(name, known), known))
+            .min()
+            .filter(|(distance, _)| *distance <= 2);
+        match closest {
+            Some((_, known)) if shown == name => format!("{shown:?} (did you mean {known}?)"),
+            Some((_, known)) => format!("{shown:?} (did you mean {}?)", file_key(known)),
+            None => format!("{shown:?}"),
+        }
+    }
+}
+
+/// Key of a setting in the config file
+fn file_key(name: &str) -> String {
+    name.strip_prefix(ENV_PREFIX).unwrap_or(name).to_lowercase()
+}
+
+/// A value of the config file as it would be written in the environment
+fn file_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::Array(_) => None,
+                value => file_value(value),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Levenshtein distance, to suggest the setting a typo meant
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The path as absolute as it can be made, symlinks resolved if it exists
+fn resolve(path: &str) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| PathBuf::from(path))
+}
+
+fn s3_credentials(loader: &mut Loader) -> Result<Option<S3Credentials>, String> {
+    let access_key = loader.string("STREAM_DB_S3_ACCESS_KEY");
+    let secret_key = loader.string("STREAM_DB_S3_SECRET_KEY");
+    match (access_key, secret_key) {
+        (Some(access_key), Some(secret_key)) => Ok(Some(S3Credentials {
+            access_key,
+            secret_key,
+        })),
+        (None, None) => Ok(None),
+        _ => Err(
+            "STREAM_DB_S3_ACCESS_KEY and STREAM_DB_S3_SECRET_KEY must be set together".to_string(),
+        ),
+    }
+}
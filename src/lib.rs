@@ -0,0 +1,11 @@
+pub mod api;
+pub mod component;
+pub mod config;
+pub mod log_control;
+pub mod logic;
+pub mod metrics;
+pub mod persistence;
+pub mod state;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod types;
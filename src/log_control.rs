@@ -0,0 +1,287 @@
+//! Verbosity of the log. Everything the instance logs is written at level `info`, and
+//! debug lines about single streams (chunk sizes and offsets, wakeups of readers, lock
+//! acquisitions) only at level `debug` or for the items matching a debug pattern. Both
+//! start out as configured and can be changed at runtime through the admin API, for a
+//! while or until the next change. Each instance keeps its own, see [`LogControl`].
+
+use crate::persistence::fault_injection::matches_pattern;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Info,
+    /// Debug lines of every stream
+    Debug,
+}
+
+impl LogLevel {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            other => Err(format!(
+                "Unknown log level {other:?}, expected info or debug"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+/// A value set through the admin API instead of the configured one
+struct Override<T> {
+    value: T,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl<T> Override<T> {
+    fn new(value: T, ttl: Option<Duration>) -> Self {
+        Self {
+            value,
+            expires_at: ttl.map(|ttl| {
+                chrono::Duration::from_std(ttl)
+                    .ok()
+                    .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            }),
+        }
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+struct LogState {
+    level: LogLevel,
+    debug_items: Vec<String>,
+    level_override: Option<Override<LogLevel>>,
+    debug_items_override: Option<Override<Vec<String>>>,
+}
+
+impl LogState {
+    fn level(&self) -> LogLevel {
+        self.level_override
+            .as_ref()
+            .map_or(self.level, |level| level.value)
+    }
+
+    fn debug_items(&self) -> &[String] {
+        self.debug_items_override
+            .as_ref()
+            .map_or(&self.debug_items, |items| &items.value)
+    }
+
+    fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        self.level_override
+            .as_ref()
+            .is_some_and(|level| level.is_expired(now))
+            || self
+                .debug_items_override
+                .as_ref()
+                .is_some_and(|items| items.is_expired(now))
+    }
+
+    /// Go back to the configured values of the overrides whose time ran out
+    fn expire(&mut self, now: DateTime<Utc>) {
+        if self
+            .level_override
+            .take_if(|level| level.is_expired(now))
+            .is_some()
+        {
+            println!("Log level override expired, back to {}", self.level.name());
+        }
+        if self
+            .debug_items_override
+            .take_if(|items| items.is_expired(now))
+            .is_some()
+        {
+            println!(
+                "Debug item patterns expired, back to {:?}",
+                self.debug_items
+            );
+        }
+    }
+
+    fn is_debugging(&self) -> bool {
+        self.level() == LogLevel::Debug || !self.debug_items().is_empty()
+    }
+}
+
+/// The log settings in effect, the body of `PUT /admin/log-level` and
+/// `PUT /admin/debug-items` responses
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogSettings {
+    pub level: LogLevel,
+    pub debug_items: Vec<String>,
+    /// Set while the level was changed through the admin API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level_override: Option<OverrideStatus>,
+    /// Set while the debug item patterns were changed through the admin API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_items_override: Option<OverrideStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OverrideStatus {
+    /// When the configured value applies again, never when unset
+    pub expires_at: Option<String>,
+}
+
+impl<T> From<&Override<T>> for OverrideStatus {
+    fn from(value: &Override<T>) -> Self {
+        Self {
+            expires_at: value.expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        }
+    }
+}
+
+/// Target of the debug lines about streams, the only events written through `tracing`
+pub const DEBUG_TARGET: &str = "stream_db::streams";
+
+/// Swaps the filter of the subscriber writing the debug lines
+type FilterReload = Box<dyn Fn(Targets) + Send + Sync>;
+
+/// The log level and debug item patterns of one instance. The instance decides which
+/// streams write debug lines; the lines go to the `tracing` subscriber, whose filter the
+/// instance reloads when it starts or stops debugging if one was attached with
+/// [`reload_filter_with`](LogControl::reload_filter_with).
+pub struct LogControl {
+    /// Set while debug lines are written for any stream, so the streams of an instance
+    /// that is not debugging only pay for one atomic load
+    debugging: AtomicBool,
+    state: RwLock<LogState>,
+    reload: OnceLock<FilterReload>,
+}
+
+impl LogControl {
+    /// Start out with the configured level and debug item patterns
+    pub fn new(level: LogLevel, debug_items: Vec<String>) -> Self {
+        let state = LogState {
+            level,
+            debug_items,
+            level_override: None,
+            debug_items_override: None,
+        };
+        Self {
+            debugging: AtomicBool::new(state.is_debugging()),
+            state: RwLock::new(state),
+            reload: OnceLock::new(),
+        }
+    }
+
+    /// Let the instance change the filter of the subscriber writing the debug lines
+    /// through `reload`, which is handed the filter in effect right away
+    pub fn reload_filter_with(&self, reload: impl Fn(Targets) + Send + Sync + 'static) {
+        reload(self.filter());
+        let _ = self.reload.set(Box::new(reload));
+    }
+
+    /// The filter letting through the debug lines while the instance writes any
+    pub fn filter(&self) -> Targets {
+        let level = if self.debugging.load(Ordering::Acquire) {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::OFF
+        };
+        Targets::new().with_target(DEBUG_TARGET, level)
+    }
+
+    /// Apply a change of `state`, which is still locked by the caller
+    fn changed(&self, state: &LogState) {
+        let debugging = state.is_debugging();
+        if self.debugging.swap(debugging, Ordering::AcqRel) != debugging
+            && let Some(reload) = self.reload.get()
+        {
+            reload(self.filter());
+        }
+    }
+
+    /// Log at `level` instead of the configured level, for `ttl` or until the next change
+    pub fn set_level(&self, level: LogLevel, ttl: Option<Duration>) -> LogSettings {
+        let mut state = self.state.write().unwrap();
+        state.level_override = Some(Override::new(level, ttl));
+        self.changed(&state);
+        describe(&state)
+    }
+
+    /// Write debug lines for the items matching any of `patterns`, where `*` matches any
+    /// run of characters, instead of the configured ones, for `ttl` or until the next
+    /// change. An empty list stops them.
+    pub fn set_debug_items(
+        &self,
+        patterns: Vec<String>,
+        ttl: Option<Duration>,
+    ) -> Result<LogSettings, String> {
+        if patterns.iter().any(|pattern| pattern.is_empty()) {
+            return Err("Item patterns must not be empty".to_string());
+        }
+        let mut state = self.state.write().unwrap();
+        state.debug_items_override = Some(Override::new(patterns, ttl));
+        self.changed(&state);
+        Ok(describe(&state))
+    }
+
+    pub fn settings(&self) -> LogSettings {
+        self.expire_overrides();
+        describe(&self.state.read().unwrap())
+    }
+
+    /// Drop the overrides whose time ran out, only taking the write lock when there are
+    /// any
+    fn expire_overrides(&self) {
+        let now = Utc::now();
+        if !self.state.read().unwrap().has_expired(now) {
+            return;
+        }
+        let mut state = self.state.write().unwrap();
+        state.expire(now);
+        self.changed(&state);
+    }
+
+    /// Whether debug lines are written for a stream of `item_id`. Streams decide once,
+    /// when they start, so a change applies to the streams started after it.
+    pub fn debug_enabled(&self, item_id: &str) -> bool {
+        if !self.debugging.load(Ordering::Acquire) {
+            return false;
+        }
+        self.expire_overrides();
+        let state = self.state.read().unwrap();
+        state.level() == LogLevel::Debug
+            || state
+                .debug_items()
+                .iter()
+                .any(|pattern| matches_pattern(pattern.as_bytes(), item_id.as_bytes()))
+    }
+}
+
+fn describe(state: &LogState) -> LogSettings {
+    LogSettings {
+        level: state.level(),
+        debug_items: state.debug_items().to_vec(),
+        level_override: state.level_override.as_ref().map(OverrideStatus::from),
+        debug_items_override: state
+            .debug_items_override
+            .as_ref()
+            .map(OverrideStatus::from),
+    }
+}
+
+/// Write a debug line about a stream of `item_id`, for which
+/// [`LogControl::debug_enabled`] was checked
+pub fn debug(item_id: &str, item_version: u64, message: Arguments) {
+    tracing::debug!(target: DEBUG_TARGET, item_id, item_version, "{message}");
+}
@@ -0,0 +1,97 @@
+/// Longest chunk header or trailer line accepted, in bytes
+const MAX_LINE_BYTES: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Reading the size line of the next chunk
+    Size,
+    /// This many bytes of the chunk's data are still to come
+    Data(u64),
+    /// Reading the line break after a chunk's data
+    DataEnd,
+    /// Reading the trailers after the last, empty chunk
+    Trailer,
+    Done,
+}
+
+/// Decodes a body sent with `Content-Encoding: aws-chunked`, which S3 clients use for
+/// streaming uploads: every chunk is preceded by its size in hex, possibly followed by
+/// `;chunk-signature=...`, and the last, empty chunk by trailing headers such as a
+/// checksum. Chunk signatures and trailers are skipped, not checked.
+pub struct AwsChunkedDecoder {
+    state: State,
+    /// Start of a line whose end has not arrived yet
+    line: Vec<u8>,
+}
+
+impl Default for AwsChunkedDecoder {
+    fn default() -> Self {
+        Self {
+            state: State::Size,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl AwsChunkedDecoder {
+    /// The payload bytes of the next piece of the body, which may end anywhere
+    pub fn push(&mut self, mut input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut payload = Vec::new();
+        while !input.is_empty() {
+            match self.state {
+                State::Data(remaining) => {
+                    let taken = remaining.min(input.len() as u64);
+                    payload.extend_from_slice(&input[..taken as usize]);
+                    input = &input[taken as usize..];
+                    self.state = match remaining - taken {
+                        0 => State::DataEnd,
+                        remaining => State::Data(remaining),
+                    };
+                }
+                State::Done => return Err("The body goes on after its last chunk".to_string()),
+                State::Size | State::DataEnd | State::Trailer => {
+                    let end = input.iter().position(|byte| *byte == b'\n');
+                    let taken = end.map_or(input.len(), |end| end + 1);
+                    self.line.extend_from_slice(&input[..taken]);
+                    input = &input[taken..];
+                    if self.line.len() > MAX_LINE_BYTES {
+                        return Err(format!(
+                            "Chunk header is longer than {MAX_LINE_BYTES} bytes"
+                        ));
+                    }
+                    if end.is_some() {
+                        let line = std::mem::take(&mut self.line);
+                        let line = line
+                            .strip_suffix(b"\r\n")
+                            .ok_or("Chunk header does not end with CRLF")?;
+                        self.state = self.next_state(line)?;
+                    }
+                }
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Whether the body ended with its last chunk and trailers
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    fn next_state(&self, line: &[u8]) -> Result<State, String> {
+        match self.state {
+            State::Size => {
+                let line = String::from_utf8_lossy(line);
+                let size = line.split(';').next().unwrap_or_default().trim();
+                match u64::from_str_radix(size, 16) {
+                    Ok(0) => Ok(State::Trailer),
+                    Ok(size) => Ok(State::Data(size)),
+                    Err(_) => Err(format!("Invalid chunk size {size:?}")),
+                }
+            }
+            State::DataEnd if line.is_empty() => Ok(State::Size),
+            State::DataEnd => Err("Chunk holds more data than its size".to_string()),
+            State::Trailer if line.is_empty() => Ok(State::Done),
+            state => Ok(state),
+        }
+    }
+}
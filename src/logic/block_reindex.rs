@@ -0,0 +1,303 @@
+use crate::logic::property_alignment::PropertyBoundaryScanner;
+use crate::persistence::block_index::{BlockIndex, BlockIndexEntry};
+use crate::persistence::file_persistence::{self, VersionState};
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tokio::time::{Duration, Instant};
+
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReindexOutcome {
+    Indexed,
+    UpToDate,
+    BelowThreshold,
+    /// Stored compressed, its properties are only found by decoding it
+    Compressed,
+}
+
+/// Summary of a reindex run
+#[derive(Serialize)]
+pub struct ReindexReport {
+    pub item_id: String,
+    pub version: u64,
+    pub outcome: ReindexOutcome,
+    pub size: u64,
+    pub property_count: Option<u64>,
+    pub blocks: Option<usize>,
+}
+
+/// Reindex a freshly committed version without holding up the writer
+pub fn schedule(state: AppState, item_id: String, item_version: u64) {
+    // Block indexes are kept next to the data files, other backends go without
+    if !state.config.reindex_on_commit || state.backend.is_some() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(error) = reindex(&state, &item_id, item_version).await {
+            println!("Reindexing item {item_id} version {item_version} failed: {error}");
+        }
+    });
+}
+
+/// Build the block index of a committed version by scanning its data file once.
+///
+/// Running it again for an already indexed version is a no-op, so it is safe to trigger
+/// both after commit and by hand.
+pub async fn reindex(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<ReindexReport, String> {
+    let config = &state.config;
+    let storage = &state.storage;
+    let _permit = state
+        .reindex_permits
+        .acquire()
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let (committed, mut data_file, size) =
+        file_persistence::open_committed(storage, item_id, item_version).await?;
+    // Its indexes moved along with it and are read from there
+    if committed.location.is_some() {
+        return Err(format!(
+            "Version {item_version} of item {item_id} is in the cold tier"
+        ));
+    }
+    let mut report = ReindexReport {
+        item_id: item_id.to_string(),
+        version: item_version,
+        outcome: ReindexOutcome::BelowThreshold,
+        size,
+        property_count: None,
+        blocks: None,
+    };
+
+    if committed.content_encoding.is_some() {
+        state.metrics.reindex_skipped.increment();
+        report.outcome = ReindexOutcome::Compressed;
+        return Ok(report);
+    }
+    if size < config.reindex_min_bytes {
+        state.metrics.reindex_skipped.increment();
+        return Ok(report);
+    }
+    if let Some(existing) = file_persistence::load_block_index(storage, item_id, item_version)?
+        && existing.size == size
+        && existing.block_properties == config.reindex_block_properties
+        && existing.block_bytes == config.reindex_block_bytes
+    {
+        state.metrics.reindex_skipped.increment();
+        report.outcome = ReindexOutcome::UpToDate;
+        report.property_count = Some(existing.property_count);
+        report.blocks = Some(existing.blocks.len());
+        return Ok(report);
+    }
+
+    let mut scanner = PropertyBoundaryScanner::new();
+    let mut blocks = vec![BlockIndexEntry {
+        property: 0,
+        offset: 0,
+    }];
+    let mut property_count = 0u64;
+    let mut scanned = 0u64;
+    let mut buffer = vec![0u8; SCAN_CHUNK_SIZE];
+    let started = Instant::now();
+
+    while scanned < size {
+        let to_read = SCAN_CHUNK_SIZE.min((size - scanned) as usize);
+        let bytes_read = data_file
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(|error| format!("Data file read error: {error}"))?;
+        if bytes_read == 0 {
+            return Err("Data file is shorter than when the scan started".to_string());
+        }
+
+        for boundary in scanner.scan(&buffer[..bytes_read]) {
+            property_count += 1;
+            let last = blocks[blocks.len() - 1];
+            if property_count - last.property >= config.reindex_block_properties
+                || boundary - last.offset >= config.reindex_block_bytes
+            {
+                blocks.push(BlockIndexEntry {
+                    property: property_count,
+                    offset: boundary,
+                });
+            }
+        }
+        scanned += bytes_read as u64;
+        state.metrics.reindex_bytes_scanned.add(bytes_read as u64);
+
+        // Stay below the configured throughput by sleeping off any lead we have on it
+        let bytes_per_second = config.live().reindex_bytes_per_second;
+        if bytes_per_second > 0 {
+            let due = Duration::from_secs_f64(scanned as f64 / bytes_per_second as f64);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                tokio::time::sleep(due - elapsed).await;
+            }
+        }
+    }
+
+    // The version may have been deleted, and maybe written again, during the scan
+    match file_persistence::load_version_state(storage, item_id, item_version).await? {
+        VersionState::Committed(current) if current.epoch == committed.epoch => (),
+        _ => return Err("Version was replaced while it was being indexed".to_string()),
+    }
+
+    let block_index = BlockIndex {
+        size,
+        property_count,
+        block_properties: config.reindex_block_properties,
+        block_bytes: config.reindex_block_bytes,
+        blocks,
+    };
+    file_persistence::store_block_index(storage, item_id, item_version, &block_index)?;
+    state.metrics.reindex_completed.increment();
+    println!(
+        "Indexed item {item_id} version {item_version}: {} blocks over {property_count} properties",
+        block_index.blocks.len()
+    );
+
+    report.outcome = ReindexOutcome::Indexed;
+    report.property_count = Some(property_count);
+    report.blocks = Some(block_index.blocks.len());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::item_stream_logic::{ItemStreamLogic, ReadError, ReadOptions, WriteOptions};
+    use crate::persistence::file_persistence::tests::TestItem;
+
+    const PROPERTY_VALUE_BYTES: usize = 1024;
+
+    /// An item of an instance that indexes versions from 64 KiB on, in blocks of 16
+    /// properties, and only when asked to
+    fn test_item(name: &str) -> TestItem {
+        TestItem::with_config(name, |config| {
+            config.reindex_on_commit = false;
+            config.reindex_min_bytes = 64 * 1024;
+            config.reindex_block_properties = 16;
+        })
+    }
+
+    /// Commit a version just above the reindex threshold, then drop its property index
+    /// as if it was written before versions had one
+    async fn write_legacy_item(item: &TestItem) -> u64 {
+        let options = WriteOptions::new(&item.state.config);
+        let mut writer = ItemStreamLogic::new_writer(&item.state, item.id.clone(), 1, options)
+            .await
+            .unwrap();
+        let value = "x".repeat(PROPERTY_VALUE_BYTES);
+        let mut properties = 0;
+        while (properties * PROPERTY_VALUE_BYTES) as u64 <= item.state.config.reindex_min_bytes {
+            let element = format!("<property name=\"p{properties}\">{value}</property>");
+            writer.write_property(element.into_bytes()).await.unwrap();
+            properties += 1;
+        }
+        writer.finalize().await.unwrap();
+        std::fs::remove_file(item.storage().path(&format!("{}_1.index.jsonl", item.id))).unwrap();
+        properties as u64
+    }
+
+    async fn read_from(item: &TestItem, from_property: u64) -> Vec<u8> {
+        let options = ReadOptions {
+            from_property: Some(from_property),
+            ..ReadOptions::default()
+        };
+        let mut reader =
+            match ItemStreamLogic::new_reader(&item.state, item.id.clone(), 1, options).await {
+                Ok(reader) => reader,
+                Err(ReadError::PropertyOutOfRange { .. }) => panic!("property out of range"),
+                Err(ReadError::WaitTimedOut { .. }) => panic!("read wait timed out"),
+                Err(ReadError::Quarantined(failure)) => panic!("{}", failure.message()),
+                Err(ReadError::ByteRangeNotSatisfiable { range, size }) => {
+                    panic!("range {range} past the end of {size} bytes")
+                }
+                Err(ReadError::ByteRangeOfEncoded { range, .. }) => {
+                    panic!("range {range} of a compressed version")
+                }
+                Err(ReadError::ByteRangeOfRedacted { range }) => {
+                    panic!("range {range} of a redacted read")
+                }
+                Err(ReadError::SegmentOutOfRange { requested, .. }) => {
+                    panic!("segment {requested} out of range")
+                }
+                Err(ReadError::Cancelled(reason)) => panic!("read cancelled: {}", reason.name()),
+                Err(
+                    ReadError::NotFound(error)
+                    | ReadError::Interrupted(error)
+                    | ReadError::SegmentOfUncommitted(error)
+                    | ReadError::UnknownTransform(error)
+                    | ReadError::Unavailable(error)
+                    | ReadError::Deleted(error)
+                    | ReadError::Failed(error),
+                ) => panic!("{error}"),
+            };
+        let mut bytes = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await.unwrap() {
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn a_reindexed_legacy_item_is_read_through_its_block_index() {
+        let item = test_item("reindex");
+        let metrics = &item.state.metrics;
+        let properties = write_legacy_item(&item).await;
+        let from_property = properties - 3;
+
+        let scanned = read_from(&item, from_property).await;
+        assert_eq!(metrics.property_seeks_scan.get(), 1);
+        assert_eq!(metrics.property_seeks_block_index.get(), 0);
+
+        let report = reindex(&item.state, &item.id, 1).await.unwrap();
+        assert!(matches!(report.outcome, ReindexOutcome::Indexed));
+        assert_eq!(report.property_count, Some(properties));
+        assert_eq!(metrics.reindex_completed.get(), 1);
+        let again = reindex(&item.state, &item.id, 1).await.unwrap();
+        assert!(matches!(again.outcome, ReindexOutcome::UpToDate));
+        assert_eq!(metrics.reindex_skipped.get(), 1);
+
+        let sought = read_from(&item, from_property).await;
+        assert_eq!(metrics.property_seeks_block_index.get(), 1);
+        assert_eq!(metrics.property_seeks_scan.get(), 1);
+        assert_eq!(sought.len(), scanned.len());
+        assert!(
+            sought == scanned,
+            "the block index seek returned other bytes"
+        );
+        assert!(sought.starts_with(format!("<property name=\"p{from_property}\">").as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn a_version_below_the_threshold_is_not_indexed() {
+        let item = test_item("reindex-small");
+        let options = WriteOptions::new(&item.state.config);
+        let mut writer = ItemStreamLogic::new_writer(&item.state, item.id.clone(), 1, options)
+            .await
+            .unwrap();
+        writer
+            .write_property(b"<property/>".to_vec())
+            .await
+            .unwrap();
+        writer.finalize().await.unwrap();
+
+        let report = reindex(&item.state, &item.id, 1).await.unwrap();
+        assert!(matches!(report.outcome, ReindexOutcome::BelowThreshold));
+        assert!(
+            file_persistence::load_block_index(item.storage(), &item.id, 1)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(item.state.metrics.reindex_skipped.get(), 1);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::logic::version_tags;
+use crate::persistence::file_persistence::{DeleteError, ImmutableVersion};
+use crate::persistence::{cold_tier, file_persistence};
+use crate::state::{AppState, StreamDb};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+/// Which committed versions `POST /admin/bulk-delete` deletes, versions have to match
+/// every filter given
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkDeleteFilter {
+    pub item_id_prefix: Option<String>,
+    /// Only versions committed at least this many seconds ago. Versions committed before
+    /// commit times were recorded never match.
+    pub older_than: Option<u64>,
+    /// Report what would be deleted without deleting anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl BulkDeleteFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.item_id_prefix.as_deref().is_none_or(str::is_empty) && self.older_than.is_none() {
+            return Err(
+                "Give an item_id_prefix or older_than, deleting every version is not supported"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkDeleteAction {
+    Deleted,
+    /// Would have been deleted, only with `dry_run`
+    WouldDelete,
+    /// Left alone because it is tagged, immutable, being read or written, or already gone
+    Skipped,
+    Failed,
+}
+
+/// What happened to one matching version
+#[derive(Serialize)]
+pub struct BulkDeleteResult {
+    pub item_id: String,
+    pub version: u64,
+    pub action: BulkDeleteAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct BulkDeleteSummary {
+    pub matched: usize,
+    pub deleted: usize,
+    pub would_delete: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub dry_run: bool,
+}
+
+/// One line of the progress report: a result per matching version, then the summary
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BulkDeleteProgress {
+    Version(BulkDeleteResult),
+    Summary { summary: BulkDeleteSummary },
+}
+
+/// Committed versions matching the filter, by item
+pub fn matching_versions(
+    state: &StreamDb,
+    filter: &BulkDeleteFilter,
+) -> Result<Vec<(String, u64)>, String> {
+    let cutoff = match filter.older_than {
+        Some(older_than) => Some(
+            chrono::Utc::now()
+                - chrono::Duration::from_std(std::time::Duration::from_secs(older_than))
+                    .map_err(|error| error.to_string())?,
+        ),
+        None => None,
+    };
+    let prefix = filter.item_id_prefix.as_deref().unwrap_or("");
+
+    let mut matching = Vec::new();
+    for item_id in cold_tier::item_ids(&state.storage)? {
+        if !item_id.starts_with(prefix) {
+            continue;
+        }
+        let metadata = file_persistence::load_item_metadata(&state.storage, &item_id)?;
+        for (version, committed) in &metadata.versions {
+            let old_enough = cutoff.is_none_or(|cutoff| {
+                committed
+                    .committed_at
+                    .as_deref()
+                    .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+                    .is_some_and(|committed_at| committed_at < cutoff)
+            });
+            if old_enough {
+                matching.push((item_id.clone(), *version));
+            }
+        }
+    }
+    Ok(matching)
+}
+
+/// Delete the matching versions, at most `STREAM_DB_BULK_DELETE_CONCURRENCY` at a time so
+/// foreground requests keep getting their share of the disk, yielding the outcome of
+/// each as it is known and a summary at the end
+pub fn bulk_delete(
+    state: AppState,
+    matching: Vec<(String, u64)>,
+    dry_run: bool,
+) -> impl Stream<Item = BulkDeleteProgress> {
+    let concurrency = state.config.bulk_delete_concurrency.max(1);
+    let mut summary = BulkDeleteSummary {
+        matched: matching.len(),
+        dry_run,
+        ..Default::default()
+    };
+    let mut results = futures::stream::iter(matching)
+        .map(move |(item_id, version)| {
+            let state = state.clone();
+            async move {
+                let task_item_id = item_id.clone();
+                tokio::task::spawn_blocking(move || {
+                    delete_one(&state, &task_item_id, version, dry_run)
+                })
+                .await
+                .unwrap_or_else(|error| BulkDeleteResult {
+                    item_id,
+                    version,
+                    action: BulkDeleteAction::Failed,
+                    reason: Some(error.to_string()),
+                })
+            }
+        })
+        .buffer_unordered(concurrency);
+
+    stream! {
+        while let Some(result) = results.next().await {
+            match result.action {
+                BulkDeleteAction::Deleted => summary.deleted += 1,
+                BulkDeleteAction::WouldDelete => summary.would_delete += 1,
+                BulkDeleteAction::Skipped => summary.skipped += 1,
+                BulkDeleteAction::Failed => summary.failed += 1,
+            }
+            yield BulkDeleteProgress::Version(result);
+        }
+        println!(
+            "Bulk delete of {} versions: {} deleted, {} skipped, {} failed{}",
+            summary.matched,
+            summary.deleted,
+            summary.skipped,
+            summary.failed,
+            if summary.dry_run { " (dry run)" } else { "" }
+        );
+        yield BulkDeleteProgress::Summary { summary };
+    }
+}
+
+/// Tagged and immutable versions and versions being read are skipped, the ones being read
+/// even when a plain delete would go ahead
+fn delete_one(state: &StreamDb, item_id: &str, version: u64, dry_run: bool) -> BulkDeleteResult {
+    let result = |action, reason: Option<String>| BulkDeleteResult {
+        item_id: item_id.to_string(),
+        version,
+        action,
+        reason,
+    };
+
+    let tags = match version_tags::list(state, item_id) {
+        Ok(tags) => tags.tags_of(version),
+        Err(error) => return result(BulkDeleteAction::Failed, Some(error)),
+    };
+    if !tags.is_empty() {
+        return result(
+            BulkDeleteAction::Skipped,
+            Some(format!("Tagged as {}", tags.join(", "))),
+        );
+    }
+    // Checked up front for dry runs, a delete refuses them anyway
+    let immutable = file_persistence::load_item_metadata(&state.storage, item_id).map(|metadata| {
+        metadata
+            .versions
+            .get(&version)
+            .and_then(|committed| ImmutableVersion::of(item_id, committed, chrono::Utc::now()))
+    });
+    match immutable {
+        Ok(Some(immutable)) => {
+            return result(BulkDeleteAction::Skipped, Some(immutable.message()));
+        }
+        Ok(None) => {}
+        Err(error) => return result(BulkDeleteAction::Failed, Some(error)),
+    }
+    let readers = file_persistence::reader_count(&state.storage, item_id, version);
+    if readers > 0 {
+        return result(
+            BulkDeleteAction::Skipped,
+            Some(format!("{readers} active readers")),
+        );
+    }
+    if dry_run {
+        return result(BulkDeleteAction::WouldDelete, None);
+    }
+
+    match version_tags::delete_version(state, item_id, version) {
+        // A reader attached since it was counted
+        Ok(report) if report.deferred => result(
+            BulkDeleteAction::Deleted,
+            Some(format!(
+                "files removed once {} active readers finish",
+                report.readers_attached
+            )),
+        ),
+        Ok(_) => result(BulkDeleteAction::Deleted, None),
+        Err(DeleteError::NotFound(error))
+        | Err(DeleteError::Locked(error))
+        | Err(DeleteError::Tagged { message: error, .. }) => {
+            result(BulkDeleteAction::Skipped, Some(error))
+        }
+        Err(DeleteError::Immutable(immutable)) => {
+            result(BulkDeleteAction::Skipped, Some(immutable.message()))
+        }
+        Err(DeleteError::Failed(error)) => result(BulkDeleteAction::Failed, Some(error)),
+    }
+}
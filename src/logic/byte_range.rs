@@ -0,0 +1,90 @@
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+use std::fmt;
+
+/// Stored bytes a read is restricted to, from `start` to `end` inclusive like an HTTP
+/// byte range
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// `START-END`, e.g. `0-1023` for the first KiB, or `START-` for everything from
+    /// `START` on
+    pub fn parse(range: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid byte range {range:?}, expected START-END");
+        let (start, end) = range.trim().split_once('-').ok_or_else(invalid)?;
+        let start: u64 = start.parse().map_err(|_| invalid())?;
+        let end: u64 = match end {
+            "" => u64::MAX,
+            end => end.parse().map_err(|_| invalid())?,
+        };
+        if end < start {
+            return Err(format!(
+                "Invalid byte range {range:?}, the end comes before the start"
+            ));
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Bytes of a version of `size` bytes within the range
+    pub fn len_within(&self, size: u64) -> u64 {
+        size.min(self.end.saturating_add(1))
+            .saturating_sub(self.start)
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            // Open-ended, up to the end of the version
+            u64::MAX => write!(formatter, "{}-", self.start),
+            end => write!(formatter, "{}-{end}", self.start),
+        }
+    }
+}
+
+/// Reader that ends once the last byte of a range was returned, for an inner reader
+/// positioned at the range's start. An upload does not have to commit for the read to
+/// end, only to reach the end of the range.
+pub struct ByteRangeReader {
+    inner: Box<dyn ItemStreamReader>,
+    remaining: u64,
+}
+
+impl ByteRangeReader {
+    pub fn new(inner: Box<dyn ItemStreamReader>, range: ByteRange) -> Self {
+        Self {
+            inner,
+            remaining: (range.end - range.start).saturating_add(1),
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for ByteRangeReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let Some(mut chunk) = self.inner.read_chunk().await? else {
+            return Ok(None);
+        };
+        if chunk.len() as u64 > self.remaining {
+            chunk.truncate(self.remaining as usize);
+        }
+        self.remaining -= chunk.len() as u64;
+        Ok(Some(chunk))
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
@@ -0,0 +1,73 @@
+use crate::persistence::item_metadata::{Provenance, VersionMetadata};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events a subscriber may fall behind by before it misses some
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Who committed a version
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitOrigin {
+    /// An upload to this instance
+    Local,
+    /// Another process that wrote to the data directory, found by the data directory watch
+    External,
+}
+
+/// A version that became readable
+#[derive(Serialize, Clone, Debug)]
+pub struct CommitEvent {
+    pub item_id: String,
+    pub version: u64,
+    pub epoch: u64,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub committed_at: Option<String>,
+    /// Whether the commit created the item, see [`VersionMetadata::first_version`]
+    pub first_version: bool,
+    pub origin: CommitOrigin,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+impl CommitEvent {
+    pub fn new(item_id: &str, committed: &VersionMetadata, origin: CommitOrigin) -> Self {
+        Self {
+            item_id: item_id.to_string(),
+            version: committed.version,
+            epoch: committed.epoch.unwrap_or(0),
+            size: committed.size,
+            sha256: committed.sha256.clone(),
+            committed_at: committed.committed_at.clone(),
+            first_version: committed.first_version.unwrap_or(false),
+            origin,
+            provenance: committed.provenance.clone(),
+        }
+    }
+}
+
+/// Fans commits out to everyone following them, events published while nobody listens
+/// are dropped
+pub struct CommitEvents {
+    sender: broadcast::Sender<CommitEvent>,
+}
+
+impl Default for CommitEvents {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl CommitEvents {
+    pub fn publish(&self, event: CommitEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CommitEvent> {
+        self.sender.subscribe()
+    }
+}
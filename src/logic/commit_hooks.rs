@@ -0,0 +1,457 @@
+use crate::metrics::Metrics;
+use crate::persistence::file_persistence::{self, data_file_name, version_file_path};
+use crate::persistence::integrity::IntegrityFailure;
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::{AppState, StreamDb};
+
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::{Mutex, Semaphore, mpsc};
+
+/// Bytes of a hook's stdout and of its stderr that are kept, the end of the output
+const OUTPUT_MAX_BYTES: usize = 4096;
+
+/// What happens to a version once one of its hooks failed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailurePolicy {
+    /// Only log the failure
+    #[default]
+    Log,
+    /// Also mark the version with `hook_failed` and the hook's name in `failed_hooks`
+    Flag,
+}
+
+/// What a hook is run for
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// Every commit of this instance
+    #[default]
+    Commit,
+    /// Every version the verification sweep quarantined, to alert someone
+    Quarantine,
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Commit => "commit",
+            Self::Quarantine => "quarantine",
+        }
+    }
+}
+
+/// An external program run after every commit, or every quarantine, from
+/// `STREAM_DB_COMMIT_HOOKS_FILE`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HookSpec {
+    pub name: String,
+    #[serde(default)]
+    pub event: HookEvent,
+    /// Program and its arguments, run without a shell
+    pub command: Vec<String>,
+    /// The program is killed once it ran this long, which fails the hook
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Runs of the hook at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub on_failure: HookFailurePolicy,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+/// The hooks listed in the JSON file at `path`, none without one
+pub fn load_specs(path: Option<&str>) -> Result<Vec<HookSpec>, String> {
+    let Some(path) = path else {
+        return Ok(Vec::new());
+    };
+    let bytes = std::fs::read(path)
+        .map_err(|error| format!("Could not read hooks from {path}: {error}"))?;
+    let specs: Vec<HookSpec> = serde_json::from_slice(&bytes)
+        .map_err(|error| format!("Hooks in {path} are invalid: {error}"))?;
+    for (position, spec) in specs.iter().enumerate() {
+        let valid_name = !spec.name.is_empty()
+            && spec
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "Hook name {:?} in {path} may only contain ASCII letters, digits, dashes and underscores",
+                spec.name
+            ));
+        }
+        if specs[..position]
+            .iter()
+            .any(|other| other.name == spec.name)
+        {
+            return Err(format!("Hook {} is listed twice in {path}", spec.name));
+        }
+        if spec.command.first().is_none_or(String::is_empty) {
+            return Err(format!("Hook {} in {path} has no command", spec.name));
+        }
+        // The version is out of service already, there is nothing left to flag
+        if spec.event == HookEvent::Quarantine && spec.on_failure == HookFailurePolicy::Flag {
+            return Err(format!(
+                "Hook {} in {path} runs for quarantines, which cannot be flagged on failure",
+                spec.name
+            ));
+        }
+        if spec.timeout_secs == 0 || spec.concurrency == 0 {
+            return Err(format!(
+                "Hook {} in {path} needs a timeout_secs and a concurrency of at least 1",
+                spec.name
+            ));
+        }
+    }
+    Ok(specs)
+}
+
+/// The committed version a hook runs for, handed to it in environment variables
+#[derive(Clone, Debug)]
+pub struct HookContext {
+    pub item_id: String,
+    pub version: u64,
+    pub epoch: u64,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub path: String,
+    /// What the data was found as, for quarantine hooks
+    pub failure: Option<IntegrityFailure>,
+}
+
+impl HookContext {
+    pub fn new(state: &StreamDb, item_id: &str, committed: &VersionMetadata) -> Self {
+        let path = version_file_path(
+            &state.storage,
+            committed.location.as_deref(),
+            &data_file_name(item_id, committed.version),
+        );
+        Self {
+            item_id: item_id.to_string(),
+            version: committed.version,
+            epoch: committed.epoch.unwrap_or(0),
+            size: committed.size,
+            sha256: committed.sha256.clone(),
+            // Hooks may change their working directory
+            path: std::path::absolute(&path)
+                .map(|absolute| absolute.to_string_lossy().into_owned())
+                .unwrap_or(path),
+            failure: None,
+        }
+    }
+}
+
+/// How one run of a hook went
+#[derive(Serialize, Debug)]
+pub struct HookRun {
+    pub hook: String,
+    pub item_id: String,
+    pub version: u64,
+    pub dry_run: bool,
+    pub succeeded: bool,
+    /// `None` when the program did not exit by itself
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// Why the program could not be run at all
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    /// The end of what the program wrote, at most 4 KiB of each
+    pub stdout: String,
+    pub stderr: String,
+}
+
+struct Hook {
+    spec: HookSpec,
+    permits: Semaphore,
+}
+
+struct HookJob {
+    hook: usize,
+    context: HookContext,
+}
+
+/// Runs the configured hooks after every commit of this instance on a pool of
+/// `STREAM_DB_HOOK_WORKERS` workers, so commits never wait for them. Runs that do not fit
+/// into the queue of `STREAM_DB_HOOK_QUEUE` are dropped and count as failed.
+pub struct CommitHooks {
+    hooks: Vec<Hook>,
+    sender: mpsc::Sender<HookJob>,
+    receiver: Arc<Mutex<mpsc::Receiver<HookJob>>>,
+}
+
+impl CommitHooks {
+    pub fn new(specs: Vec<HookSpec>, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue.max(1));
+        Self {
+            hooks: specs
+                .into_iter()
+                .map(|spec| Hook {
+                    permits: Semaphore::new(spec.concurrency),
+                    spec,
+                })
+                .collect(),
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    pub fn specs(&self) -> impl Iterator<Item = &HookSpec> {
+        self.hooks.iter().map(|hook| &hook.spec)
+    }
+}
+
+/// Start the workers running queued hooks
+pub fn start(state: AppState) {
+    if state.commit_hooks.hooks.is_empty() {
+        return;
+    }
+    let names: Vec<&str> = state
+        .commit_hooks
+        .specs()
+        .map(|spec| spec.name.as_str())
+        .collect();
+    println!(
+        "Running commit hooks {} on {} workers",
+        names.join(", "),
+        state.config.hook_workers
+    );
+    for _ in 0..state.config.hook_workers.max(1) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                // Workers take turns waiting for the next job
+                let job = state.commit_hooks.receiver.lock().await.recv().await;
+                let Some(job) = job else {
+                    return;
+                };
+                let hook = &state.commit_hooks.hooks[job.hook];
+                let Ok(_permit) = hook.permits.acquire().await else {
+                    return;
+                };
+                let run = run(&state.metrics, &hook.spec, &job.context, false).await;
+                if !run.succeeded {
+                    handle_failure(&state, &hook.spec, &job.context).await;
+                }
+            }
+        });
+    }
+}
+
+/// Queue every commit hook for a version this instance committed
+pub fn dispatch(state: &AppState, item_id: &str, committed: &VersionMetadata) {
+    if state.commit_hooks.hooks.is_empty() {
+        return;
+    }
+    let context = HookContext::new(state, item_id, committed);
+    enqueue(state, HookEvent::Commit, context);
+}
+
+/// Queue every quarantine hook for a version found not matching its receipt
+pub fn dispatch_quarantine(
+    state: &AppState,
+    item_id: &str,
+    committed: &VersionMetadata,
+    failure: IntegrityFailure,
+) {
+    if state.commit_hooks.hooks.is_empty() {
+        return;
+    }
+    let context = HookContext {
+        failure: Some(failure),
+        ..HookContext::new(state, item_id, committed)
+    };
+    enqueue(state, HookEvent::Quarantine, context);
+}
+
+fn enqueue(state: &AppState, event: HookEvent, context: HookContext) {
+    let hooks = state.commit_hooks.hooks.iter().enumerate();
+    for (position, hook) in hooks.filter(|(_, hook)| hook.spec.event == event) {
+        let job = HookJob {
+            hook: position,
+            context: context.clone(),
+        };
+        if state.commit_hooks.sender.try_send(job).is_err() {
+            state.metrics.hook_runs_dropped.increment();
+            println!(
+                "Hook {} for item {} version {} was dropped, the hook queue is full",
+                hook.spec.name, context.item_id, context.version
+            );
+            let state = state.clone();
+            let spec = hook.spec.clone();
+            let context = context.clone();
+            tokio::spawn(async move { handle_failure(&state, &spec, &context).await });
+        }
+    }
+}
+
+/// Run `name` against a committed version right away, without applying its failure
+/// policy. `None` if no hook has that name.
+pub async fn test(state: &AppState, name: &str, context: HookContext) -> Option<HookRun> {
+    let spec = state.commit_hooks.specs().find(|spec| spec.name == name)?;
+    Some(run(&state.metrics, spec, &context, true).await)
+}
+
+/// Run a hook's program and log what it did, which is all that is kept of it
+async fn run(metrics: &Metrics, spec: &HookSpec, context: &HookContext, dry_run: bool) -> HookRun {
+    let started = Instant::now();
+    let mut command = Command::new(&spec.command[0]);
+    command
+        .args(&spec.command[1..])
+        .env("HOOK_NAME", &spec.name)
+        .env("HOOK_DRY_RUN", if dry_run { "1" } else { "0" })
+        .env("HOOK_EVENT", spec.event.name())
+        .env("ITEM_ID", &context.item_id)
+        .env("VERSION", context.version.to_string())
+        .env("EPOCH", context.epoch.to_string())
+        .env("PATH_TO_FILE", &context.path)
+        .env(
+            "SIZE",
+            context
+                .size
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+        )
+        .env("SHA256", context.sha256.as_deref().unwrap_or_default())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Dropping the wait on timeout kills the program
+        .kill_on_drop(true);
+    if let Some(failure) = &context.failure {
+        command
+            .env(
+                "QUARANTINE_CHECK",
+                failure.detected_by.as_deref().unwrap_or_default(),
+            )
+            .env(
+                "FOUND_SIZE",
+                failure
+                    .found_size
+                    .map(|size| size.to_string())
+                    .unwrap_or_default(),
+            )
+            .env(
+                "FOUND_SHA256",
+                failure.found_sha256.as_deref().unwrap_or_default(),
+            );
+    }
+
+    let mut hook_run = HookRun {
+        hook: spec.name.clone(),
+        item_id: context.item_id.clone(),
+        version: context.version,
+        dry_run,
+        succeeded: false,
+        exit_code: None,
+        timed_out: false,
+        error: None,
+        duration_ms: 0,
+        stdout: String::new(),
+        stderr: String::new(),
+    };
+    let timeout = Duration::from_secs(spec.timeout_secs);
+    match command.spawn() {
+        Err(error) => {
+            hook_run.error = Some(format!("Could not start {}: {error}", spec.command[0]))
+        }
+        Ok(child) => match tokio::time::timeout(timeout, child.wait_with_output()).await {
+            Err(_) => hook_run.timed_out = true,
+            Ok(Err(error)) => {
+                hook_run.error = Some(format!("Waiting for the hook failed: {error}"))
+            }
+            Ok(Ok(output)) => {
+                hook_run.exit_code = output.status.code();
+                hook_run.succeeded = output.status.success();
+                hook_run.stdout = output_tail(&output.stdout);
+                hook_run.stderr = output_tail(&output.stderr);
+            }
+        },
+    }
+    hook_run.duration_ms = started.elapsed().as_millis() as u64;
+    log_run(metrics, &hook_run, spec.timeout_secs);
+    hook_run
+}
+
+fn output_tail(output: &[u8]) -> String {
+    let tail = &output[output.len().saturating_sub(OUTPUT_MAX_BYTES)..];
+    String::from_utf8_lossy(tail).trim_end().to_string()
+}
+
+fn log_run(metrics: &Metrics, hook_run: &HookRun, timeout_secs: u64) {
+    metrics.hook_runs.increment();
+    let HookRun {
+        hook,
+        item_id,
+        version,
+        ..
+    } = hook_run;
+    let dry_run = if hook_run.dry_run { " (dry run)" } else { "" };
+    let outcome = match (&hook_run.error, hook_run.timed_out, hook_run.exit_code) {
+        (Some(error), _, _) => error.clone(),
+        (None, true, _) => format!("killed after the timeout of {timeout_secs}s"),
+        (None, false, Some(code)) => format!("exited with {code}"),
+        (None, false, None) => "was killed by a signal".to_string(),
+    };
+    if !hook_run.succeeded {
+        metrics.hook_failures.increment();
+    }
+    if hook_run.timed_out {
+        metrics.hook_timeouts.increment();
+    }
+    println!(
+        "Hook {hook}{dry_run} for item {item_id} version {version} {outcome} after {}ms",
+        hook_run.duration_ms
+    );
+    for (stream, output) in [("stdout", &hook_run.stdout), ("stderr", &hook_run.stderr)] {
+        for line in output.lines() {
+            println!("  Hook {hook} {stream}: {line}");
+        }
+    }
+}
+
+/// Apply the failure policy of a hook that failed for a version
+async fn handle_failure(state: &AppState, spec: &HookSpec, context: &HookContext) {
+    if spec.on_failure != HookFailurePolicy::Flag {
+        return;
+    }
+    let storage = state.storage.clone();
+    let name = spec.name.clone();
+    let context = context.clone();
+    let flagged = tokio::task::spawn_blocking(move || {
+        file_persistence::flag_hook_failure(
+            &storage,
+            &context.item_id,
+            context.version,
+            context.epoch,
+            &name,
+        )
+        .map_err(|error| {
+            format!(
+                "Could not flag item {} version {} for its failed hook {name}: {}",
+                context.item_id,
+                context.version,
+                error.message()
+            )
+        })
+    })
+    .await;
+    match flagged {
+        Ok(Ok(_)) => (),
+        Ok(Err(error)) => println!("{error}"),
+        Err(error) => println!("Flagging a failed hook panicked: {error}"),
+    }
+}
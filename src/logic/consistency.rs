@@ -0,0 +1,187 @@
+use crate::persistence::file_persistence::{self, VersionState};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::StreamDb;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::time::{Duration, Instant};
+
+/// How often a node waiting for a version checks whether it arrived
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const HMAC_BLOCK_BYTES: usize = 64;
+
+/// What a producer knows it wrote: reads presenting the token are only served once the
+/// node has the version too, so they never see an older state of the item
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConsistencyToken {
+    pub item_id: String,
+    pub version: u64,
+    pub committed_at: Option<String>,
+    /// Node the version was committed on
+    pub origin: String,
+}
+
+/// Why a read presenting a token is not served
+pub enum ConsistencyError {
+    /// The token was not issued by a node sharing this node's secret, or is malformed
+    Invalid(String),
+    /// The version did not arrive within the wait budget
+    Unsatisfied {
+        message: String,
+        retry_after_secs: u64,
+    },
+    Failed(String),
+}
+
+/// Issues and checks the tokens of one node. Tokens, and presigned URLs, are signed with
+/// `STREAM_DB_SECRET`, without one a random secret is used and tokens stop being
+/// accepted once the process restarts.
+pub struct ConsistencyTokens {
+    node_id: String,
+    secret: Vec<u8>,
+}
+
+impl ConsistencyTokens {
+    pub fn new(node_id: String, secret: Option<&str>) -> Self {
+        let secret = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                println!(
+                    "No STREAM_DB_SECRET configured, consistency tokens and presigned URLs are only valid until the next restart"
+                );
+                [
+                    uuid::Uuid::new_v4().into_bytes(),
+                    uuid::Uuid::new_v4().into_bytes(),
+                ]
+                .concat()
+            }
+        };
+        Self { node_id, secret }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// `{payload}.{signature}`, both base64url encoded
+    pub fn encode(&self, token: &ConsistencyToken) -> String {
+        let payload = serde_json::to_vec(token).unwrap_or_default();
+        let signature = self.sign(&payload);
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(&payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    pub fn decode(&self, encoded: &str) -> Result<ConsistencyToken, String> {
+        let (payload, signature) = encoded
+            .trim()
+            .split_once('.')
+            .ok_or("Consistency token is malformed")?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| "Consistency token is malformed")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "Consistency token is malformed")?;
+        if !self.verify(&payload, &signature) {
+            return Err("Consistency token signature is invalid".to_string());
+        }
+        serde_json::from_slice(&payload)
+            .map_err(|error| format!("Consistency token is malformed: {error}"))
+    }
+
+    /// HMAC-SHA256 of `message` with the node's secret
+    pub fn sign(&self, message: &[u8]) -> [u8; 32] {
+        hmac_sha256(&self.secret, message)
+    }
+
+    /// Whether `signature` is the node's signature of `message`
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        constant_time_eq(signature, &self.sign(message))
+    }
+}
+
+/// The token for a version this node just committed
+pub fn issue(state: &StreamDb, item_id: &str, committed: &VersionMetadata) -> String {
+    state.consistency.encode(&ConsistencyToken {
+        item_id: item_id.to_string(),
+        version: committed.version,
+        committed_at: committed.committed_at.clone(),
+        origin: state.consistency.node_id().to_string(),
+    })
+}
+
+/// Wait until the version a token names is committed on this node, for at most
+/// `STREAM_DB_CONSISTENCY_WAIT_MS`. Tokens issued by this node are satisfied right away.
+pub async fn satisfy(
+    state: &StreamDb,
+    item_id: &str,
+    encoded: &str,
+) -> Result<(), ConsistencyError> {
+    let token = state
+        .consistency
+        .decode(encoded)
+        .map_err(ConsistencyError::Invalid)?;
+    if token.item_id != item_id {
+        return Err(ConsistencyError::Invalid(format!(
+            "Consistency token is for item {}, not {item_id}",
+            token.item_id
+        )));
+    }
+    if token.origin == state.consistency.node_id() {
+        return Ok(());
+    }
+
+    let wait = Duration::from_millis(state.config.consistency_wait_ms);
+    let deadline = Instant::now() + wait;
+    loop {
+        match file_persistence::load_version_state(&state.storage, item_id, token.version).await {
+            Ok(VersionState::Committed(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(error) => return Err(ConsistencyError::Failed(error)),
+        }
+        if Instant::now() >= deadline {
+            return Err(ConsistencyError::Unsatisfied {
+                message: format!(
+                    "Item {item_id} version {} committed on node {} has not reached node {} yet",
+                    token.version,
+                    token.origin,
+                    state.consistency.node_id()
+                ),
+                retry_after_secs: state.config.consistency_wait_ms.div_ceil(1000).max(1),
+            });
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Whether `a` and `b` are equal, compared without an early exit so the time taken does
+/// not give away how much of a secret a guess got right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// HMAC-SHA256 of `message` with `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; HMAC_BLOCK_BYTES];
+    if key.len() > HMAC_BLOCK_BYTES {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
@@ -0,0 +1,147 @@
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+use flate2::write::MultiGzDecoder;
+use std::io::Write;
+
+/// A `Content-Encoding` uploads may be sent with, and versions stored with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentEncoding {
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// The encoding of a body sent with `Content-Encoding: header`, `None` for an
+    /// unencoded one
+    pub fn from_header(header: Option<&str>) -> Result<Option<Self>, String> {
+        match header
+            .map(|header| header.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("" | "identity") => Ok(None),
+            Some(name) => Self::parse(name).map(Some),
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            other => Err(format!(
+                "Content-Encoding {other:?} is not supported, bodies may only be sent as gzip"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Whether a reader sending `Accept-Encoding: header` takes this encoding. Codings
+    /// with `q=0` are refused, and so is everything without the header.
+    pub fn accepted_by(&self, header: Option<&str>) -> bool {
+        let Some(header) = header else {
+            return false;
+        };
+        let mut wildcard = false;
+        for coding in header.split(',') {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let refused = parts.any(|parameter| {
+                parameter
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.trim().parse::<f32>().ok())
+                    .is_some_and(|quality| quality == 0.0)
+            });
+            match Self::parse(&name) {
+                // Named codings take precedence over the wildcard
+                Ok(encoding) if encoding == *self => return !refused,
+                _ if name == "*" => wildcard = !refused,
+                _ => (),
+            }
+        }
+        wildcard
+    }
+
+    pub fn decoder(&self) -> Decoder {
+        match self {
+            Self::Gzip => Decoder {
+                gzip: MultiGzDecoder::new(Vec::new()),
+            },
+        }
+    }
+}
+
+/// Decodes an encoded body piece by piece, the pieces may end anywhere. Bodies made of
+/// several gzip members, as concatenating gzip files gives, are decoded as one.
+pub struct Decoder {
+    gzip: MultiGzDecoder<Vec<u8>>,
+}
+
+impl Decoder {
+    /// The decoded bytes the next piece of the body completes
+    pub fn push(&mut self, input: &[u8]) -> Result<Vec<u8>, String> {
+        self.gzip
+            .write_all(input)
+            .map_err(|error| format!("Body is not valid gzip: {error}"))?;
+        Ok(std::mem::take(self.gzip.get_mut()))
+    }
+
+    /// The body ended: the decoded bytes still held back, or an error if it was cut off
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        self.gzip
+            .try_finish()
+            .map_err(|error| format!("Body ends in the middle of its gzip stream: {error}"))?;
+        Ok(std::mem::take(self.gzip.get_mut()))
+    }
+}
+
+/// Reader decoding a version stored compressed, for readers that do not accept its
+/// encoding and reads that need the properties. Decoded offsets are not known up
+/// front, so it neither seeks nor hands out the indexes of the stored bytes, and reads
+/// by property skip over the decoded stream instead.
+pub struct DecodingReader {
+    inner: Box<dyn ItemStreamReader>,
+    decoder: Decoder,
+    finished: bool,
+}
+
+impl DecodingReader {
+    pub fn new(inner: Box<dyn ItemStreamReader>, encoding: ContentEncoding) -> Self {
+        Self {
+            inner,
+            decoder: encoding.decoder(),
+            finished: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for DecodingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.finished {
+            // A chunk may only hold part of a gzip block, which decodes to nothing yet
+            let decoded = match self.inner.read_chunk().await? {
+                Some(chunk) => self.decoder.push(&chunk)?,
+                None => {
+                    self.finished = true;
+                    self.decoder.finish()?
+                }
+            };
+            if !decoded.is_empty() {
+                return Ok(Some(decoded));
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
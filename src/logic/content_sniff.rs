@@ -0,0 +1,161 @@
+/// Body bytes held back at most while looking for the first one that is not whitespace
+const MAX_HELD_BYTES: usize = 1024;
+
+/// Byte order mark some producers put in front of their XML
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// What happens to an upload whose first bytes contradict its XML `Content-Type`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentSniffMode {
+    /// The body is not looked at
+    Off,
+    /// The upload goes through, its receipt and the log carry a warning
+    Warn,
+    /// The upload is refused with `UNSUPPORTED_MEDIA_TYPE`
+    Strict,
+}
+
+impl ContentSniffMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "strict" => Ok(Self::Strict),
+            other => Err(format!("Unknown content sniff mode {other:?}")),
+        }
+    }
+}
+
+/// What a body declared as XML looks like instead
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SniffedContent {
+    Json,
+    Gzip,
+    /// Anything else that does not start with `<`
+    Unknown,
+}
+
+impl SniffedContent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Gzip => "gzip",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// First bytes of a body that contradict its declared type
+#[derive(Clone, Debug)]
+pub struct SniffMismatch {
+    pub content: SniffedContent,
+    /// Body offset of the first byte that is not whitespace, in decoded bytes
+    pub byte_offset: u64,
+    /// Up to 8 bytes from there, in hex
+    pub first_bytes: String,
+    pub message: String,
+}
+
+/// Looks at the first bytes of an upload's body before they go any further. The bytes
+/// are held back until they tell what the body is, then handed on unchanged along with
+/// the verdict, so the sniff never changes what is stored.
+pub struct ContentSniffer {
+    held: Vec<u8>,
+    strict: bool,
+    /// The body was sent with a `Content-Encoding`, and these are its decoded bytes
+    decoded: bool,
+}
+
+impl ContentSniffer {
+    /// A sniffer for `mode`, none when sniffing is off
+    pub fn new(mode: ContentSniffMode, decoded: bool) -> Option<Self> {
+        (mode != ContentSniffMode::Off).then(|| Self {
+            held: Vec::new(),
+            strict: mode == ContentSniffMode::Strict,
+            decoded,
+        })
+    }
+
+    /// Whether a mismatch refuses the upload
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Take the next bytes of the body. `None` while they are held back, otherwise
+    /// every byte held back so far, in order, with the mismatch if there is one.
+    pub fn push(&mut self, bytes: Vec<u8>) -> Option<(Vec<u8>, Option<SniffMismatch>)> {
+        if self.held.is_empty() {
+            self.held = bytes;
+        } else {
+            self.held.extend_from_slice(&bytes);
+        }
+        let verdict = self.verdict(false)?;
+        Some((std::mem::take(&mut self.held), verdict))
+    }
+
+    /// The body ended, hand on what is held back with the verdict on it
+    pub fn finish(&mut self) -> (Vec<u8>, Option<SniffMismatch>) {
+        let verdict = self.verdict(true).flatten();
+        (std::mem::take(&mut self.held), verdict)
+    }
+
+    /// `None` until the held bytes tell, `Some(None)` for a body that looks like XML
+    fn verdict(&self, complete: bool) -> Option<Option<SniffMismatch>> {
+        let bytes = self.held.as_slice();
+        let undecided = !complete && bytes.len() < MAX_HELD_BYTES;
+        let start = if bytes.starts_with(UTF8_BOM) {
+            UTF8_BOM.len()
+        } else if UTF8_BOM.starts_with(bytes) && undecided {
+            return None;
+        } else {
+            0
+        };
+        let Some(offset) = bytes[start..]
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .map(|position| start + position)
+        else {
+            // Only whitespace so far, the splitter has the last word on an empty body
+            return if undecided { None } else { Some(None) };
+        };
+        let content = match bytes[offset..] {
+            [b'<', ..] => return Some(None),
+            [b'{' | b'[', ..] => SniffedContent::Json,
+            [0x1f, 0x8b, ..] => SniffedContent::Gzip,
+            [0x1f] if undecided => return None,
+            _ => SniffedContent::Unknown,
+        };
+        let first_bytes = bytes[offset..]
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let message = match content {
+            SniffedContent::Json => format!(
+                "The body was declared as XML but starts with `{}` at byte {offset}, which looks like JSON; send XML or declare the right Content-Type",
+                bytes[offset] as char
+            ),
+            SniffedContent::Gzip if self.decoded => {
+                "The body still starts with the gzip magic bytes 1f 8b once its Content-Encoding was decoded, it looks compressed twice".to_string()
+            }
+            SniffedContent::Gzip => {
+                "The body was declared as XML but starts with the gzip magic bytes 1f 8b; send compressed bodies with Content-Encoding: gzip".to_string()
+            }
+            SniffedContent::Unknown if bytes[offset].is_ascii_graphic() => format!(
+                "The body was declared as XML but starts with `{}` at byte {offset} instead of `<`",
+                bytes[offset] as char
+            ),
+            SniffedContent::Unknown => format!(
+                "The body was declared as XML but starts with the byte 0x{:02x} at byte {offset} instead of `<`",
+                bytes[offset]
+            ),
+        };
+        Some(Some(SniffMismatch {
+            content,
+            byte_offset: offset as u64,
+            first_bytes,
+            message,
+        }))
+    }
+}
@@ -0,0 +1,277 @@
+use crate::logic::commit_events::{CommitEvent, CommitOrigin};
+use crate::persistence::{cold_tier, file_persistence};
+use crate::state::{AppState, StreamDb};
+
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+/// How the data directory is watched for versions committed by other processes, e.g. a
+/// second instance or a copy made with rsync
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchMode {
+    Off,
+    /// Filesystem notifications, falls back to polling if they cannot be set up
+    Notify,
+    /// Compare the modification times of the metadata files periodically, for
+    /// filesystems such as NFS that do not deliver notifications reliably
+    Poll,
+}
+
+impl WatchMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "off" => Ok(Self::Off),
+            "notify" => Ok(Self::Notify),
+            "poll" => Ok(Self::Poll),
+            other => Err(format!("Unknown watch mode {other:?}")),
+        }
+    }
+}
+
+/// What the watch last saw of an item's metadata
+struct KnownItem {
+    modified: Option<SystemTime>,
+    /// Epoch of every committed version
+    epochs: BTreeMap<u64, u64>,
+}
+
+type KnownItems = HashMap<String, KnownItem>;
+
+/// Watch the data directory and publish the versions committed behind this instance's
+/// back, after updating the registry to match the metadata
+pub fn start(state: AppState) {
+    let mode = state.config.watch_mode;
+    if mode == WatchMode::Off {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let all_items = blocking(&state, |state| -> Result<BTreeSet<String>, String> {
+            Ok(cold_tier::item_ids(&state.storage)?.into_iter().collect())
+        })
+        .await;
+        let mut known = KnownItems::new();
+        match all_items {
+            Ok(item_ids) => {
+                // Everything found now was there before this instance started
+                known = check(&state, known, item_ids, false, false).await;
+            }
+            Err(error) => println!("Data directory watch could not list items: {error}"),
+        }
+
+        if mode == WatchMode::Notify {
+            match notifications(&state.storage.data_dir) {
+                Ok((watcher, changes)) => {
+                    file_persistence::set_metadata_watched(&state.storage, true);
+                    known = follow_notifications(&state, known, changes).await;
+                    drop(watcher);
+                    file_persistence::set_metadata_watched(&state.storage, false);
+                    println!("Data directory notifications stopped, polling instead");
+                }
+                Err(error) => println!(
+                    "Could not watch {} for changes, polling instead: {error}",
+                    state.storage.data_dir
+                ),
+            }
+        }
+        poll(&state, known).await;
+    });
+}
+
+/// Items whose metadata files changed, as reported by the filesystem
+fn notifications(
+    data_dir: &str,
+) -> Result<(notify::RecommendedWatcher, mpsc::UnboundedReceiver<String>), String> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                for path in event.paths {
+                    if let Some(item_id) = path
+                        .file_name()
+                        .and_then(|file_name| file_name.to_str())
+                        .and_then(file_persistence::metadata_item_id)
+                    {
+                        let _ = sender.send(item_id.to_string());
+                    }
+                }
+            }
+            Err(error) => println!("Data directory watch error: {error}"),
+        })
+        .map_err(|error| error.to_string())?;
+    watcher
+        .watch(std::path::Path::new(data_dir), RecursiveMode::NonRecursive)
+        .map_err(|error| error.to_string())?;
+    Ok((watcher, receiver))
+}
+
+/// Check the items reported by the filesystem, waiting for `STREAM_DB_WATCH_DEBOUNCE_MS`
+/// of quiet after a change so a burst of writes to one file is handled once. Returns once
+/// the notifications stop.
+async fn follow_notifications(
+    state: &AppState,
+    mut known: KnownItems,
+    mut changes: mpsc::UnboundedReceiver<String>,
+) -> KnownItems {
+    let debounce = Duration::from_millis(state.config.watch_debounce_ms);
+    while let Some(item_id) = changes.recv().await {
+        let mut changed = BTreeSet::from([item_id]);
+        while let Ok(Some(item_id)) = tokio::time::timeout(debounce, changes.recv()).await {
+            changed.insert(item_id);
+        }
+        known = check(state, known, changed, false, true).await;
+    }
+    known
+}
+
+async fn poll(state: &AppState, mut known: KnownItems) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(state.config.watch_poll_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let listed = blocking(state, |state| cold_tier::item_ids(&state.storage)).await;
+        let mut item_ids: BTreeSet<String> = match listed {
+            Ok(item_ids) => item_ids.into_iter().collect(),
+            Err(error) => {
+                println!("Data directory watch could not list items: {error}");
+                continue;
+            }
+        };
+        // Items whose metadata disappeared are checked too
+        item_ids.extend(known.keys().cloned());
+        known = check(state, known, item_ids, true, true).await;
+    }
+}
+
+/// Compare the metadata of `item_ids` with what was seen before and, with `publish`,
+/// publish the versions this process neither uploaded nor saw before. With
+/// `skip_unmodified` items whose metadata file has not changed since are not loaded.
+async fn check(
+    state: &AppState,
+    known: KnownItems,
+    item_ids: BTreeSet<String>,
+    skip_unmodified: bool,
+    publish: bool,
+) -> KnownItems {
+    let checked = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut known = known;
+            let events = check_items(&state, &mut known, item_ids, skip_unmodified, publish);
+            (known, events)
+        })
+        .await
+    };
+    let (known, events) = match checked {
+        Ok(checked) => checked,
+        Err(error) => {
+            println!("Data directory watch failed: {error}");
+            return KnownItems::new();
+        }
+    };
+    for event in events {
+        println!(
+            "Found item {} version {} committed by another process",
+            event.item_id, event.version
+        );
+        state.commit_events.publish(event);
+    }
+    known
+}
+
+fn check_items(
+    state: &StreamDb,
+    known: &mut KnownItems,
+    item_ids: BTreeSet<String>,
+    skip_unmodified: bool,
+    publish: bool,
+) -> Vec<CommitEvent> {
+    let storage = &state.storage;
+    let mut events = Vec::new();
+    let mut changed = false;
+    for item_id in item_ids {
+        let modified = file_persistence::metadata_modified(storage, &item_id);
+        if skip_unmodified
+            && known
+                .get(&item_id)
+                .is_some_and(|item| item.modified == modified)
+        {
+            continue;
+        }
+        let metadata = match file_persistence::load_item_metadata(storage, &item_id) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                println!("Data directory watch could not load item {item_id}: {error}");
+                continue;
+            }
+        };
+        changed = true;
+        file_persistence::forget_cached_versions(storage, &item_id);
+        let report = file_persistence::refresh_item_registry(storage, &item_id, &metadata);
+        if report.replaced > 0 || report.moved > 0 || report.quarantined > 0 {
+            println!(
+                "Data directory watch dropped {} replaced, {} moved and {} quarantined versions of item {item_id}",
+                report.replaced, report.moved, report.quarantined
+            );
+        }
+
+        let previous = known.remove(&item_id);
+        let mut latest_is_new = false;
+        for (item_version, committed) in &metadata.versions {
+            let epoch = committed.epoch.unwrap_or(0);
+            let seen = previous
+                .as_ref()
+                .is_some_and(|item| item.epochs.get(item_version) == Some(&epoch));
+            if !publish
+                || seen
+                || file_persistence::is_registered(storage, &item_id, *item_version, epoch)
+            {
+                continue;
+            }
+            latest_is_new |= metadata.latest_version == Some(*item_version);
+            events.push(CommitEvent::new(
+                &item_id,
+                committed,
+                CommitOrigin::External,
+            ));
+        }
+        // Open the new latest version right away, so its first reader finds it registered
+        if latest_is_new && let Err(error) = file_persistence::preload_latest(storage, &item_id) {
+            println!("Data directory watch could not open item {item_id}: {error}");
+        }
+
+        if modified.is_some() {
+            known.insert(
+                item_id,
+                KnownItem {
+                    modified,
+                    epochs: metadata
+                        .versions
+                        .iter()
+                        .map(|(item_version, committed)| {
+                            (*item_version, committed.epoch.unwrap_or(0))
+                        })
+                        .collect(),
+                },
+            );
+        }
+    }
+    if changed && let Err(error) = file_persistence::count_committed_bytes(storage) {
+        println!("Data directory watch could not recount committed bytes: {error}");
+    }
+    events
+}
+
+async fn blocking<T: Send + 'static>(
+    state: &AppState,
+    task: impl FnOnce(&StreamDb) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || task(&state))
+        .await
+        .map_err(|error| error.to_string())
+        .and_then(|result| result)
+}
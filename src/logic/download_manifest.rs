@@ -0,0 +1,312 @@
+use crate::persistence::block_index::BlockIndex;
+use crate::persistence::file_persistence::{
+    self, block_index_file_name, data_file_name, metadata_path, version_file_path,
+};
+use crate::persistence::integrity::{self, FileDigest};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::part_checksums::{PartChecksum, PartChecksums};
+use crate::persistence::storage::Storage;
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Instant;
+
+/// Parts a manifest is split into unless the client asks for a number
+pub const DEFAULT_PARTS: u64 = 8;
+
+/// Most parts a manifest may be split into
+pub const MAX_PARTS: u64 = 256;
+
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How to download a committed version over several connections, one byte range read
+/// per part, and check each part and the reassembled whole
+#[derive(Serialize)]
+pub struct DownloadManifest {
+    pub item_id: String,
+    pub version: u64,
+    pub epoch: u64,
+    pub size: u64,
+    /// SHA-256 of the whole version, the parts put back together in order
+    pub sha256: String,
+    /// Whether the parts start on property boundaries, taken from the version's index
+    pub property_aligned: bool,
+    pub parts: Vec<ManifestPart>,
+}
+
+#[derive(Serialize)]
+pub struct ManifestPart {
+    pub start: u64,
+    /// Last byte of the part, inclusive like an HTTP byte range
+    pub end: u64,
+    pub length: u64,
+    /// Value of the `Range` header reading the part
+    pub range: String,
+    pub sha256: String,
+}
+
+/// Split the committed version `committed` into up to `parts` byte ranges and checksum
+/// them. The checksums are computed by streaming the data file the first time and then
+/// kept in a sidecar, so asking again only reads the sidecar.
+pub async fn manifest(
+    state: &AppState,
+    item_id: &str,
+    committed: VersionMetadata,
+    parts: u64,
+) -> Result<DownloadManifest, String> {
+    let state = state.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || build(&state, &item_id, &committed, parts))
+        .await
+        .map_err(|error| error.to_string())?
+}
+
+fn build(
+    state: &StreamDb,
+    item_id: &str,
+    committed: &VersionMetadata,
+    parts: u64,
+) -> Result<DownloadManifest, String> {
+    let storage = &state.storage;
+    let item_version = committed.version;
+    let epoch = committed.epoch.unwrap_or(0);
+    let path = version_file_path(
+        storage,
+        committed.location.as_deref(),
+        &data_file_name(item_id, item_version),
+    );
+    let mut file = File::open(&path).map_err(|error| format!("Open of {path} failed: {error}"))?;
+    let size = file
+        .metadata()
+        .map_err(|error| format!("Stat of {path} failed: {error}"))?
+        .len();
+    if let Some(expected) = committed.size.filter(|expected| *expected != size) {
+        return Err(format!(
+            "Data file of item {item_id} version {item_version} holds {size} bytes instead of the committed {expected}"
+        ));
+    }
+
+    let (starts, property_aligned) = match boundaries(storage, item_id, committed, size, parts) {
+        Some(starts) => (starts, true),
+        None => (even_boundaries(size, parts), false),
+    };
+    let ranges: Vec<(u64, u64)> = starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&size]))
+        .map(|(start, next)| (*start, next - start))
+        .collect();
+
+    let mut cached = file_persistence::load_part_checksums(storage, item_id, item_version)
+        .unwrap_or_else(|error| {
+            println!("Ignoring part checksums of item {item_id} version {item_version}: {error}");
+            None
+        })
+        .filter(|cached| cached.describes(epoch, size))
+        .unwrap_or_else(|| PartChecksums::new(epoch, size));
+    let known_sha256 = committed.sha256.clone().or_else(|| cached.sha256.clone());
+    let missing = ranges
+        .iter()
+        .filter(|(start, length)| cached.find(*start, *length).is_none())
+        .count();
+
+    let started = Instant::now();
+    let sha256 = match known_sha256 {
+        Some(sha256) => {
+            for (start, length) in ranges.iter().copied() {
+                if cached.find(start, length).is_none() {
+                    let sha256 = hash_range(&mut file, start, length, None)?;
+                    cached.insert(PartChecksum {
+                        start,
+                        length,
+                        sha256,
+                    });
+                }
+            }
+            sha256
+        }
+        // The parts cover the whole file, so hashing them in order hashes it too
+        None => {
+            let mut whole = Sha256::new();
+            for (start, length) in ranges.iter().copied() {
+                let sha256 = hash_range(&mut file, start, length, Some(&mut whole))?;
+                cached.insert(PartChecksum {
+                    start,
+                    length,
+                    sha256,
+                });
+            }
+            let sha256 = format!("{:x}", whole.finalize());
+            cached.sha256 = Some(sha256.clone());
+            record_digest(state, item_id, committed, size, &sha256);
+            sha256
+        }
+    };
+    if missing > 0 && !storage.read_only {
+        println!(
+            "Computed the checksums of {missing} parts of item {item_id} version {item_version} in {}ms",
+            started.elapsed().as_millis()
+        );
+        if let Err(error) =
+            file_persistence::store_part_checksums(storage, item_id, item_version, &cached)
+        {
+            println!(
+                "Could not store part checksums of item {item_id} version {item_version}: {error}"
+            );
+        }
+    }
+
+    let parts = ranges
+        .into_iter()
+        .map(|(start, length)| {
+            let end = start + length - 1;
+            ManifestPart {
+                start,
+                end,
+                length,
+                range: format!("bytes={start}-{end}"),
+                sha256: cached.find(start, length).unwrap_or_default().to_string(),
+            }
+        })
+        .collect();
+    Ok(DownloadManifest {
+        item_id: item_id.to_string(),
+        version: item_version,
+        epoch,
+        size,
+        sha256,
+        property_aligned,
+        parts,
+    })
+}
+
+/// Starts of `parts` ranges of about the same size, fewer if the version has fewer bytes
+fn even_boundaries(size: u64, parts: u64) -> Vec<u64> {
+    let parts = parts.min(size);
+    (0..parts)
+        .map(|part| (u128::from(size) * u128::from(part) / u128::from(parts)) as u64)
+        .collect()
+}
+
+/// Starts of up to `parts` ranges about the same size that begin on property
+/// boundaries, `None` if the version has no index to find them in. A version with fewer
+/// properties than `parts` gets fewer ranges.
+fn boundaries(
+    storage: &Storage,
+    item_id: &str,
+    committed: &VersionMetadata,
+    size: u64,
+    parts: u64,
+) -> Option<Vec<u64>> {
+    if size == 0 {
+        return None;
+    }
+    // The block index is small even for huge items, but only good enough when it has
+    // a boundary for every part
+    let block_index = BlockIndex::load(&version_file_path(
+        storage,
+        committed.location.as_deref(),
+        &block_index_file_name(item_id, committed.version),
+    ))
+    .ok()
+    .flatten()
+    .filter(|block_index| block_index.size == size);
+    let mut offsets: Vec<u64> = match block_index {
+        Some(block_index) if block_index.blocks.len() as u64 >= parts => block_index
+            .blocks
+            .iter()
+            .map(|block| block.offset)
+            .collect(),
+        _ => file_persistence::load_committed_property_index(storage, item_id, committed)
+            .ok()
+            .flatten()?
+            .entries
+            .iter()
+            .map(|entry| entry.offset)
+            .collect(),
+    };
+    offsets.retain(|offset| *offset > 0 && *offset < size);
+    offsets.sort_unstable();
+    offsets.dedup();
+    if offsets.is_empty() {
+        return None;
+    }
+
+    let mut starts = vec![0];
+    for target in even_boundaries(size, parts).into_iter().skip(1) {
+        // The property boundary nearest to the even split
+        let after = offsets.partition_point(|offset| *offset < target);
+        let nearest = [after.checked_sub(1), Some(after)]
+            .into_iter()
+            .flatten()
+            .filter_map(|position| offsets.get(position).copied())
+            .min_by_key(|offset| offset.abs_diff(target))?;
+        if nearest > *starts.last().unwrap() {
+            starts.push(nearest);
+        }
+    }
+    Some(starts)
+}
+
+/// SHA-256 of `length` bytes of `file` from `start`, read in chunks so the range is
+/// never held in memory. The bytes are fed to `whole` as well.
+fn hash_range(
+    file: &mut File,
+    start: u64,
+    length: u64,
+    mut whole: Option<&mut Sha256>,
+) -> Result<String, String> {
+    file.seek(SeekFrom::Start(start))
+        .map_err(|error| format!("Seek in data file failed: {error}"))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let wanted = buffer.len().min(remaining as usize);
+        let bytes_read = file
+            .read(&mut buffer[..wanted])
+            .map_err(|error| format!("Read of data file failed: {error}"))?;
+        if bytes_read == 0 {
+            return Err("Data file ended before the part did".to_string());
+        }
+        hasher.update(&buffer[..bytes_read]);
+        if let Some(whole) = whole.as_deref_mut() {
+            whole.update(&buffer[..bytes_read]);
+        }
+        remaining -= bytes_read as u64;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Record the checksum of a version committed without one, as a complete read would
+fn record_digest(
+    state: &StreamDb,
+    item_id: &str,
+    committed: &VersionMetadata,
+    size: u64,
+    sha256: &str,
+) {
+    if state.storage.read_only || !integrity::lacks_digest(committed) {
+        return;
+    }
+    let found = FileDigest {
+        size,
+        sha256: sha256.to_string(),
+    };
+    if let Err(error) = integrity::backfill_digest(
+        &state.storage.metrics,
+        &metadata_path(&state.storage, item_id),
+        item_id,
+        committed.version,
+        committed.epoch.unwrap_or(0),
+        &found,
+    ) {
+        println!(
+            "Could not record the checksum of item {item_id} version {}: {}",
+            committed.version,
+            error.message()
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use crate::logic::read_stats;
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::file_persistence::{self, KillOutcome};
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// How long streams cut off by a forced drain get to notice before the process exits
+const FORCE_GRACE: Duration = Duration::from_secs(2);
+
+/// Direction of a stream followed by the drain
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamKind {
+    Write,
+    Read,
+}
+
+/// Where the instance is in shutting down
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DrainState {
+    /// Not shutting down
+    Serving,
+    /// Refusing new streams and waiting for the running ones to finish
+    Draining,
+    /// The remaining streams were cut off
+    Forced,
+}
+
+/// How the drain ended, once the process may exit
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DrainOutcome {
+    /// Every stream finished
+    Drained,
+    /// The remaining streams were cut off, by an admin, a second signal or the timeout
+    Forced,
+}
+
+/// Streams in flight on the instance, so a shutdown can wait for them and tell operators
+/// what it is waiting for
+#[derive(Default)]
+pub struct Drain {
+    streams: Arc<Mutex<BTreeMap<u64, Arc<StreamProgress>>>>,
+    next_id: AtomicU64,
+    /// When the shutdown started, and as RFC 3339
+    started: Mutex<Option<(Instant, String)>>,
+    forced: AtomicBool,
+    /// Woken when a stream ends or the drain is forced
+    changed: Arc<Notify>,
+}
+
+impl Drain {
+    /// Whether new streams are refused
+    pub fn is_draining(&self) -> bool {
+        self.started.lock().unwrap().is_some()
+    }
+
+    pub fn state(&self) -> DrainState {
+        if self.forced.load(Ordering::Acquire) {
+            DrainState::Forced
+        } else if self.is_draining() {
+            DrainState::Draining
+        } else {
+            DrainState::Serving
+        }
+    }
+
+    /// Follow a stream until the returned handle is dropped, cutting it off through
+    /// `cancellation` if the drain is forced
+    pub fn track(
+        &self,
+        kind: StreamKind,
+        item_id: &str,
+        item_version: u64,
+        cancellation: Cancellation,
+    ) -> TrackedStream {
+        let progress = Arc::new(StreamProgress {
+            kind,
+            item_id: item_id.to_string(),
+            version: item_version,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            expected_bytes: OnceLock::new(),
+            cancellation,
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.lock().unwrap().insert(id, progress.clone());
+        TrackedStream {
+            id,
+            progress,
+            streams: self.streams.clone(),
+            changed: self.changed.clone(),
+        }
+    }
+
+    /// Every stream in flight with its progress so far
+    pub fn report(&self) -> DrainReport {
+        let now = Instant::now();
+        let draining_secs = self
+            .started
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(started, _)| now.duration_since(*started).as_secs_f64());
+        let started_at = self
+            .started
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, started_at)| started_at.clone());
+        let streams: Vec<StreamReport> = self
+            .streams
+            .lock()
+            .unwrap()
+            .values()
+            .map(|progress| progress.report(now))
+            .collect();
+        DrainReport {
+            state: self.state(),
+            started_at,
+            draining_secs,
+            writes: streams
+                .iter()
+                .filter(|stream| stream.kind == StreamKind::Write)
+                .count(),
+            reads: streams
+                .iter()
+                .filter(|stream| stream.kind == StreamKind::Read)
+                .count(),
+            streams,
+        }
+    }
+
+    /// Refuse new streams from now on, the ones in flight are left to finish
+    pub fn begin(&self) {
+        self.started
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| (Instant::now(), read_stats::now()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.streams.lock().unwrap().is_empty()
+    }
+}
+
+/// What is known about one stream in flight
+pub struct StreamProgress {
+    kind: StreamKind,
+    item_id: String,
+    version: u64,
+    started: Instant,
+    bytes: AtomicU64,
+    /// Bytes the stream will transfer in total, when announced up front
+    expected_bytes: OnceLock<u64>,
+    cancellation: Cancellation,
+}
+
+impl StreamProgress {
+    fn report(&self, now: Instant) -> StreamReport {
+        let elapsed_secs = now.duration_since(self.started).as_secs_f64();
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let bytes_per_second = if elapsed_secs > 0.0 {
+            bytes as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        let expected_bytes = self.expected_bytes.get().copied();
+        let estimated_remaining_secs = expected_bytes
+            .filter(|_| bytes_per_second > 0.0)
+            .map(|expected| expected.saturating_sub(bytes) as f64 / bytes_per_second);
+        StreamReport {
+            kind: self.kind,
+            item_id: self.item_id.clone(),
+            version: self.version,
+            bytes,
+            expected_bytes,
+            elapsed_secs,
+            bytes_per_second,
+            estimated_remaining_secs,
+        }
+    }
+}
+
+/// Registration of a stream with the drain, removed when dropped
+pub struct TrackedStream {
+    id: u64,
+    progress: Arc<StreamProgress>,
+    streams: Arc<Mutex<BTreeMap<u64, Arc<StreamProgress>>>>,
+    changed: Arc<Notify>,
+}
+
+impl TrackedStream {
+    pub fn add_bytes(&self, bytes: u64) {
+        self.progress.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The stream's total size, once known. Only the first call counts.
+    pub fn expect_bytes(&self, bytes: u64) {
+        let _ = self.progress.expected_bytes.set(bytes);
+    }
+}
+
+impl Drop for TrackedStream {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+        self.changed.notify_one();
+    }
+}
+
+/// One stream in the drain report
+#[derive(Serialize)]
+pub struct StreamReport {
+    pub kind: StreamKind,
+    pub item_id: String,
+    pub version: u64,
+    /// Bytes received from the uploader or sent to the reader so far
+    pub bytes: u64,
+    /// Total the stream will transfer, if announced: the upload's `Content-Length` or
+    /// the size of the committed version being read
+    pub expected_bytes: Option<u64>,
+    pub elapsed_secs: f64,
+    /// Average since the stream started
+    pub bytes_per_second: f64,
+    /// At the average rate so far, only for streams with `expected_bytes`
+    pub estimated_remaining_secs: Option<f64>,
+}
+
+/// Streams in flight, served by `GET /admin/drain` and logged while shutting down
+#[derive(Serialize)]
+pub struct DrainReport {
+    pub state: DrainState,
+    /// RFC 3339 timestamp of the shutdown request
+    pub started_at: Option<String>,
+    pub draining_secs: Option<f64>,
+    pub writes: usize,
+    pub reads: usize,
+    pub streams: Vec<StreamReport>,
+}
+
+/// What a forced drain cut off
+#[derive(Serialize)]
+pub struct ForceReport {
+    pub streams_cut_off: usize,
+    /// Uploads killed and cleaned up, see `POST /admin/streams/.../kill`
+    pub uploads_killed: usize,
+}
+
+/// Cut off every stream still in flight for `reason`: every stream is cancelled and
+/// fails wherever it is, uploads are killed, their partial data removed and their
+/// readers woken. Only possible while shutting down.
+pub fn force(state: &StreamDb, reason: CancelReason) -> Result<ForceReport, String> {
+    if !state.drain.is_draining() {
+        return Err("The instance is not shutting down, there is nothing to drain".to_string());
+    }
+    state.drain.forced.store(true, Ordering::Release);
+    let streams: Vec<Arc<StreamProgress>> = state
+        .drain
+        .streams
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    let mut report = ForceReport {
+        streams_cut_off: streams.len(),
+        uploads_killed: 0,
+    };
+    // Every stream is cancelled before any upload is killed, so readers waiting for an
+    // upload fail for the reason rather than for the upload going away
+    for progress in &streams {
+        progress.cancellation.cancel(reason);
+    }
+    for progress in &streams {
+        if progress.kind == StreamKind::Write {
+            let killed = file_persistence::kill_stream(
+                &state.storage,
+                &progress.item_id,
+                progress.version,
+                reason,
+            );
+            if killed.outcome == KillOutcome::Killed {
+                report.uploads_killed += 1;
+            }
+        }
+    }
+    println!(
+        "Drain forced, cut off {} streams and killed {} uploads",
+        report.streams_cut_off, report.uploads_killed
+    );
+    state.drain.changed.notify_one();
+    Ok(report)
+}
+
+/// Wait for a shutdown signal, then drain: new streams are refused while the running
+/// ones finish, with a summary logged every `STREAM_DB_DRAIN_REPORT_SECS`. A second
+/// signal, `POST /admin/drain/force` or `STREAM_DB_DRAIN_TIMEOUT_SECS` cut the remaining
+/// streams off. Returns once the process may exit.
+pub async fn drain_on_shutdown(state: &AppState) -> DrainOutcome {
+    let mut signals = ShutdownSignals::new();
+    signals.recv().await;
+    state.drain.begin();
+    // Uploads waiting for their item would only start once the drain cut them off
+    state.storage.write_queue.close();
+    println!(
+        "Shutdown requested, draining {} streams. Signal again or POST /admin/drain/force to cut them off.",
+        state.drain.streams.lock().unwrap().len()
+    );
+
+    let report_every = Duration::from_secs(state.config.drain_report_secs.max(1));
+    let deadline = state
+        .config
+        .drain_timeout_secs
+        .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+    let mut next_report = Instant::now() + report_every;
+    let outcome = loop {
+        if state.drain.is_empty() {
+            break DrainOutcome::Drained;
+        }
+        if state.drain.state() == DrainState::Forced {
+            // Streams cut off fail right away, a stuck client is not waited for
+            let grace = tokio::time::sleep(FORCE_GRACE);
+            tokio::pin!(grace);
+            while !state.drain.is_empty() {
+                tokio::select! {
+                    _ = state.drain.changed.notified() => {}
+                    _ = &mut grace => break,
+                }
+            }
+            break DrainOutcome::Forced;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            println!("Drain timed out");
+            let _ = force(state, CancelReason::Timeout);
+            continue;
+        }
+        let wake_at = deadline.map_or(next_report, |deadline| deadline.min(next_report));
+        tokio::select! {
+            _ = state.drain.changed.notified() => {}
+            _ = tokio::time::sleep_until(wake_at) => {}
+            _ = signals.recv() => {
+                println!("Second shutdown signal");
+                let _ = force(state, CancelReason::Shutdown);
+            }
+        }
+        if Instant::now() >= next_report {
+            log_report(&state.drain.report());
+            next_report = Instant::now() + report_every;
+        }
+    };
+
+    if !state.config.read_only {
+        read_stats::flush(state);
+    }
+    println!("Drain finished, shutting down");
+    outcome
+}
+
+fn log_report(report: &DrainReport) {
+    println!(
+        "Draining for {:.0}s: {} writes and {} reads in flight",
+        report.draining_secs.unwrap_or(0.0),
+        report.writes,
+        report.reads
+    );
+    for stream in &report.streams {
+        let remaining = match stream.estimated_remaining_secs {
+            Some(secs) => format!(", about {secs:.0}s left"),
+            None => String::new(),
+        };
+        println!(
+            "  {:?} of item {} version {}: {} bytes at {:.0} bytes/s{remaining}",
+            stream.kind, stream.item_id, stream.version, stream.bytes, stream.bytes_per_second
+        );
+    }
+}
+
+/// SIGINT and, on unix, SIGTERM. Listened to for the whole shutdown, so a second signal
+/// is seen instead of killing the process.
+struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+}
+
+impl ShutdownSignals {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .map_err(|error| println!("Could not listen for SIGTERM: {error}"))
+                .ok(),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(terminate) = self.terminate.as_mut() {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,159 @@
+use crate::logic::property_element::PROPERTY_START_TAG;
+
+use std::collections::BTreeMap;
+
+/// Check a name listed in `STREAM_DB_EXTRA_ELEMENTS`
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let mut characters = name.chars();
+    let starts_well = characters
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_');
+    if !starts_well
+        || !characters.all(|character| {
+            character.is_ascii_alphanumeric() || matches!(character, '_' | '-' | '.' | ':')
+        })
+    {
+        return Err(format!("{name:?} is not an element name"));
+    }
+    if name == "property" {
+        return Err("Properties cannot be extra elements".to_string());
+    }
+    Ok(())
+}
+
+/// What an upload's extra elements left for its receipt
+#[derive(Default)]
+pub struct ExtraElementCopies {
+    /// Raw XML of every element copied, by name
+    pub copied: BTreeMap<String, String>,
+    /// Names of the elements that were too large to be copied
+    pub truncated: Vec<String>,
+}
+
+/// Finds the top-level elements other than properties that an upload may carry, named
+/// by `STREAM_DB_EXTRA_ELEMENTS`, e.g. a `<summary>` after the last property. They are
+/// written in place like any other bytes without being counted as properties, and a copy
+/// of each is kept for the version's receipt unless it grows past `max_bytes`. Of two
+/// elements with the same name, the later one is kept.
+///
+/// Elements are only looked for outside of properties, so a `<summary>` nested in a
+/// property value stays part of the property.
+pub struct ExtraElements {
+    names: Vec<String>,
+    max_bytes: usize,
+    /// The element being received
+    open: Option<OpenElement>,
+    copies: ExtraElementCopies,
+}
+
+struct OpenElement {
+    name: String,
+    /// Closes the element, `/>` for an empty element
+    end_tag: String,
+    /// Bytes received so far, dropped once they exceed the limit
+    copy: Option<String>,
+}
+
+impl ExtraElements {
+    pub fn new(names: &[String], max_bytes: usize) -> Self {
+        Self {
+            names: names.to_vec(),
+            max_bytes,
+            open: None,
+            copies: ExtraElementCopies::default(),
+        }
+    }
+
+    /// Whether the splitter is inside an extra element
+    pub fn is_open(&self) -> bool {
+        self.open.is_some()
+    }
+
+    /// Offset of an extra element starting in `buffer` before the next property, which
+    /// the splitter is inside from there on. Waits for the whole start tag to arrive.
+    pub fn open(&mut self, buffer: &str) -> Option<usize> {
+        if self.names.is_empty() {
+            return None;
+        }
+        let before_property = buffer.find(PROPERTY_START_TAG).unwrap_or(buffer.len());
+        let mut search_from = 0;
+        while let Some(found) = buffer[search_from..before_property].find('<') {
+            let start = search_from + found;
+            search_from = start + 1;
+            let tag = &buffer[start + 1..];
+            let Some(name) = self.names.iter().find(|name| {
+                tag.strip_prefix(name.as_str()).is_some_and(|rest| {
+                    rest.starts_with(|next: char| {
+                        next == '>' || next == '/' || next.is_whitespace()
+                    })
+                })
+            }) else {
+                continue;
+            };
+            let tag_end = tag.find('>')?;
+            let end_tag = if tag[..tag_end].ends_with('/') {
+                "/>".to_string()
+            } else {
+                format!("</{name}>")
+            };
+            self.open = Some(OpenElement {
+                name: name.clone(),
+                end_tag,
+                copy: Some(String::new()),
+            });
+            return Some(start);
+        }
+        None
+    }
+
+    /// How many bytes at the start of `buffer` belong to the open element and can be
+    /// written. Once its end tag is among them, the element is closed. Bytes that may be
+    /// the start of the end tag are held back.
+    pub fn take(&mut self, buffer: &str) -> usize {
+        let Some(open) = self.open.as_mut() else {
+            return 0;
+        };
+        let (taken, closed) = match buffer.find(&open.end_tag) {
+            Some(position) => (position + open.end_tag.len(), true),
+            None => {
+                let mut taken = buffer.len().saturating_sub(open.end_tag.len() - 1);
+                while !buffer.is_char_boundary(taken) {
+                    taken -= 1;
+                }
+                (taken, false)
+            }
+        };
+        if let Some(copy) = open.copy.as_mut() {
+            if copy.len() + taken > self.max_bytes {
+                open.copy = None;
+            } else {
+                copy.push_str(&buffer[..taken]);
+            }
+        }
+        if closed {
+            let open = self.open.take().expect("checked above");
+            self.copies.truncated.retain(|name| *name != open.name);
+            match open.copy {
+                Some(copy) => {
+                    self.copies.copied.insert(open.name, copy);
+                }
+                None => {
+                    self.copies.copied.remove(&open.name);
+                    self.copies.truncated.push(open.name);
+                }
+            }
+        }
+        taken
+    }
+
+    /// The body ended inside an element, which is not copied
+    pub fn close_unterminated(&mut self) {
+        if let Some(open) = self.open.take() {
+            println!("Extra element <{}> was never closed", open.name);
+        }
+    }
+
+    pub fn take_copies(&mut self) -> ExtraElementCopies {
+        std::mem::take(&mut self.copies)
+    }
+}
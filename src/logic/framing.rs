@@ -0,0 +1,109 @@
+/// `Content-Type` of an upload whose body is a sequence of length-prefixed frames
+pub const FRAMED_CONTENT_TYPE: &str = "application/x-streamdb-framed";
+
+/// Bytes of the length in front of every frame, a big-endian `u32`
+const LENGTH_BYTES: usize = 4;
+
+/// Strips the framing off an upload sent as [`FRAMED_CONTENT_TYPE`]: every frame is its
+/// payload's length as a big-endian `u32` followed by the payload. The payloads are
+/// stored back to back, and where each frame ended is kept as the version's segments, so
+/// producers can mark logical records that do not coincide with properties and readers
+/// can ask for one of them with `?segment=`. An empty frame ends the body, confirming
+/// that it arrived complete.
+pub struct FrameDecoder {
+    /// Length bytes of the next frame received so far
+    length: Vec<u8>,
+    /// Payload bytes of the current frame still to come
+    remaining: u32,
+    payload_bytes: u64,
+    /// Payload offsets where the frames start, followed by where the last complete one
+    /// ends
+    boundaries: Vec<u64>,
+    max_frames: usize,
+    /// The empty frame ending the body was received
+    ended: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frames: usize) -> Self {
+        Self {
+            length: Vec::with_capacity(LENGTH_BYTES),
+            remaining: 0,
+            payload_bytes: 0,
+            boundaries: vec![0],
+            max_frames,
+            ended: false,
+        }
+    }
+
+    /// The payload bytes of the next piece of the body, which may end anywhere
+    pub fn push(&mut self, mut input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut payload = Vec::with_capacity(input.len());
+        while !input.is_empty() {
+            if self.ended {
+                return Err("The body goes on after the empty frame ending it".to_string());
+            }
+            if self.remaining == 0 {
+                let taken = (LENGTH_BYTES - self.length.len()).min(input.len());
+                self.length.extend_from_slice(&input[..taken]);
+                input = &input[taken..];
+                if self.length.len() < LENGTH_BYTES {
+                    break;
+                }
+                let frame = self.boundaries.len();
+                let length = u32::from_be_bytes([
+                    self.length[0],
+                    self.length[1],
+                    self.length[2],
+                    self.length[3],
+                ]);
+                self.length.clear();
+                if length == 0 {
+                    self.ended = true;
+                    continue;
+                }
+                if frame > self.max_frames {
+                    return Err(format!(
+                        "The body holds more than {} frames",
+                        self.max_frames
+                    ));
+                }
+                self.remaining = length;
+                continue;
+            }
+            let taken = (self.remaining as usize).min(input.len());
+            payload.extend_from_slice(&input[..taken]);
+            input = &input[taken..];
+            self.remaining -= taken as u32;
+            self.payload_bytes += taken as u64;
+            if self.remaining == 0 {
+                self.boundaries.push(self.payload_bytes);
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Whether the body ended with the empty frame
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+
+    /// The body ended. Returns the payload offsets where the frames start, followed by
+    /// where the last one ends, unless the body ended inside a frame.
+    pub fn finish(&mut self) -> Result<Vec<u64>, String> {
+        if self.remaining > 0 {
+            return Err(format!(
+                "The body ended {} bytes short of the end of frame {}",
+                self.remaining,
+                self.boundaries.len()
+            ));
+        }
+        if !self.length.is_empty() {
+            return Err(format!(
+                "The body ended inside the length of frame {}",
+                self.boundaries.len()
+            ));
+        }
+        Ok(std::mem::take(&mut self.boundaries))
+    }
+}
@@ -0,0 +1,138 @@
+use crate::persistence::idempotency::{self, RecordedResponse, RecordedResponses};
+use crate::persistence::storage::Storage;
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::OwnedMutexGuard;
+
+/// Keys are client supplied, longer ones are refused
+pub const MAX_KEY_LENGTH: usize = 255;
+/// Larger responses are passed on without being recorded
+pub const MAX_RECORDED_BODY_BYTES: usize = 64 * 1024;
+
+/// Responses recorded for requests carrying an `Idempotency-Key`, so a retry of a
+/// request that already went through gets the same answer instead of running again.
+/// Recorded responses are kept in the data directory for `STREAM_DB_IDEMPOTENCY_TTL_SECS`,
+/// at most `STREAM_DB_IDEMPOTENCY_MAX_KEYS` of them.
+pub struct IdempotencyKeys {
+    ttl_secs: u64,
+    max_keys: usize,
+    /// Loaded from the data directory on first use
+    responses: Mutex<Option<RecordedResponses>>,
+    /// Keys a request is running for, a duplicate waits for the first one's outcome
+    running: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl_secs: u64, max_keys: usize) -> Self {
+        Self {
+            ttl_secs,
+            max_keys,
+            responses: Mutex::new(None),
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until no other request with the same key is running and hold the key until
+    /// the returned guard is dropped
+    pub async fn turn(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut running = self.running.lock().unwrap();
+            // Locks nobody holds or waits for any more
+            running.retain(|_, lock| Arc::strong_count(lock) > 1);
+            running.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    /// The response recorded for `key`, unless it expired
+    pub fn recorded(
+        &self,
+        storage: &Storage,
+        key: &str,
+    ) -> Result<Option<RecordedResponse>, String> {
+        let mut responses = self.responses.lock().unwrap();
+        let responses = loaded(&mut responses, storage)?;
+        let now = now();
+        let Some(response) = responses.get_mut(key) else {
+            return Ok(None);
+        };
+        if response.recorded_at + self.ttl_secs <= now {
+            responses.remove(key);
+            return Ok(None);
+        }
+        response.used_at = now;
+        Ok(Some(response.clone()))
+    }
+
+    /// Record the response for `key`, dropping expired keys and, once the store is full,
+    /// the least recently used ones
+    pub fn record(
+        &self,
+        storage: &Storage,
+        key: &str,
+        mut response: RecordedResponse,
+    ) -> Result<(), String> {
+        let mut responses = self.responses.lock().unwrap();
+        let responses = loaded(&mut responses, storage)?;
+        let now = now();
+        response.recorded_at = now;
+        response.used_at = now;
+        responses.insert(key.to_string(), response);
+        responses.retain(|_, response| response.recorded_at + self.ttl_secs > now);
+        if responses.len() > self.max_keys {
+            let mut used: Vec<(u64, String)> = responses
+                .iter()
+                .map(|(key, response)| (response.used_at, key.clone()))
+                .collect();
+            used.sort();
+            for (_, key) in used.into_iter().take(responses.len() - self.max_keys) {
+                responses.remove(&key);
+            }
+        }
+        idempotency::store(storage, responses)
+    }
+}
+
+/// Keys of different clients never meet: they are scoped by the credentials presented,
+/// which are only kept hashed
+pub fn scoped_key(credentials: Option<&str>, key: &str) -> String {
+    match credentials {
+        Some(credentials) => {
+            let digest = format!("{:x}", Sha256::digest(credentials.as_bytes()));
+            format!("{}:{key}", &digest[..16])
+        }
+        None => format!("anonymous:{key}"),
+    }
+}
+
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(format!(
+            "Idempotency-Key must be 1 to {MAX_KEY_LENGTH} characters long"
+        ));
+    }
+    if !key.chars().all(|character| character.is_ascii_graphic()) {
+        return Err("Idempotency-Key may only contain visible ASCII characters".to_string());
+    }
+    Ok(())
+}
+
+fn loaded<'a>(
+    responses: &'a mut Option<RecordedResponses>,
+    storage: &Storage,
+) -> Result<&'a mut RecordedResponses, String> {
+    if responses.is_none() {
+        *responses = Some(idempotency::load(storage)?);
+    }
+    Ok(responses.get_or_insert_default())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,203 @@
+use quick_xml::escape::escape;
+
+/// Root element written around the stored properties when envelope wrapping is enabled.
+///
+/// The opening tag is written in front of the first property and the closing tag is only
+/// appended when the writer commits, so the file stays a plain append-only stream that
+/// readers can follow while it is being written: an in-flight read simply has not seen
+/// `</item>` yet. Readers serve the stored bytes as-is, which means items written before
+/// wrapping was enabled keep coming back unwrapped.
+///
+/// An XML declaration may only start a document, so one the body opens with is kept in
+/// front of the opening tag, and a byte order mark in front of the body is dropped.
+///
+/// All byte offsets kept about the stored file are offsets into the wrapped file, so the
+/// opening tag shifts every property by its length.
+pub struct ItemEnvelope {
+    opening_tag: Option<Vec<u8>>,
+    /// The start of the body, held back until it is known where its declaration ends
+    held: Vec<u8>,
+}
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+const DECLARATION_START: &[u8] = b"<?xml";
+/// Declarations are short, a body whose first bytes do not end one by then has none
+const MAX_DECLARATION_BYTES: usize = 1024;
+
+impl ItemEnvelope {
+    pub const CLOSING_TAG: &'static [u8] = b"</item>";
+
+    pub fn new(item_id: &str, item_version: u64) -> Self {
+        Self {
+            opening_tag: Some(
+                format!(
+                    r#"<item id="{}" version="{item_version}">"#,
+                    escape(item_id)
+                )
+                .into_bytes(),
+            ),
+            held: Vec::new(),
+        }
+    }
+
+    /// The bytes to store for the next `chunk` of the body: the first ones get the
+    /// opening tag, the others are stored as they are. Returns nothing while the body's
+    /// first bytes might still turn out to be a declaration.
+    pub fn wrap(&mut self, chunk: Vec<u8>) -> Vec<u8> {
+        if self.opening_tag.is_none() {
+            return chunk;
+        }
+        self.held.extend(chunk);
+        match split_prolog(&self.held) {
+            Some((bom, declaration_end)) => self.open(bom, declaration_end),
+            None => Vec::new(),
+        }
+    }
+
+    /// The bytes to store in front of the closing tag once the body ended: what was held
+    /// back, behind the opening tag if no property was written, e.g. for an empty body
+    pub fn finish(&mut self) -> Vec<u8> {
+        if self.opening_tag.is_none() {
+            return Vec::new();
+        }
+        let (bom, declaration_end) = split_prolog(&self.held).unwrap_or_else(|| {
+            let bom = if self.held.starts_with(BOM) {
+                BOM.len()
+            } else {
+                0
+            };
+            (bom, bom)
+        });
+        self.open(bom, declaration_end)
+    }
+
+    fn open(&mut self, bom: usize, declaration_end: usize) -> Vec<u8> {
+        let opening_tag = self.opening_tag.take().unwrap_or_default();
+        let held = std::mem::take(&mut self.held);
+        let mut wrapped = Vec::with_capacity(held.len() + opening_tag.len());
+        wrapped.extend_from_slice(&held[bom..declaration_end]);
+        wrapped.extend(opening_tag);
+        wrapped.extend_from_slice(&held[declaration_end..]);
+        wrapped
+    }
+}
+
+/// Where the byte order mark and the XML declaration the body starts with end, `None`
+/// while `start` is too short to tell
+fn split_prolog(start: &[u8]) -> Option<(usize, usize)> {
+    let bom = if start.starts_with(BOM) {
+        BOM.len()
+    } else if BOM.starts_with(start) {
+        return None;
+    } else {
+        0
+    };
+    let rest = &start[bom..];
+    if rest.len() <= DECLARATION_START.len() {
+        // `<?xml` followed by anything but whitespace is a processing instruction
+        return if DECLARATION_START.starts_with(rest) {
+            None
+        } else {
+            Some((bom, bom))
+        };
+    }
+    if !rest.starts_with(DECLARATION_START) || !rest[DECLARATION_START.len()].is_ascii_whitespace()
+    {
+        return Some((bom, bom));
+    }
+    match rest.windows(2).position(|window| window == b"?>") {
+        Some(end) => Some((bom, bom + end + 2)),
+        None if rest.len() < MAX_DECLARATION_BYTES => None,
+        None => Some((bom, bom)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quick_xml::Reader;
+    use quick_xml::events::Event;
+
+    const DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+
+    /// Wrap `body` sent in pieces of `piece` bytes, as an upload's chunks arrive
+    fn wrap_in_pieces(body: &[u8], piece: usize) -> Vec<u8> {
+        let mut envelope = ItemEnvelope::new("item", 1);
+        let mut stored = Vec::new();
+        for chunk in body.chunks(piece) {
+            stored.extend(envelope.wrap(chunk.to_vec()));
+        }
+        stored.extend(envelope.finish());
+        stored.extend_from_slice(ItemEnvelope::CLOSING_TAG);
+        stored
+    }
+
+    /// The events of `document`, failing on anything the parser refuses
+    fn parse(document: &[u8]) -> Vec<String> {
+        let mut reader = Reader::from_reader(document);
+        let mut buffer = Vec::new();
+        let mut events = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buffer) {
+                Ok(Event::Eof) => return events,
+                Ok(Event::Decl(_)) => events.push("declaration".to_string()),
+                Ok(Event::Start(start)) => {
+                    events.push(String::from_utf8_lossy(start.name().as_ref()).into_owned())
+                }
+                Ok(_) => {}
+                Err(error) => panic!("{error}: {}", String::from_utf8_lossy(document)),
+            }
+            buffer.clear();
+        }
+    }
+
+    #[test]
+    fn a_declaration_stays_in_front_of_the_opening_tag_however_the_body_is_split() {
+        let body = format!(
+            "\u{feff}{DECLARATION}\n<properties><property name=\"a\">1</property></properties>"
+        );
+        for piece in 1..=body.len() {
+            let stored = wrap_in_pieces(body.as_bytes(), piece);
+            let expected = format!(
+                "{DECLARATION}<item id=\"item\" version=\"1\">\n<properties><property name=\"a\">1</property></properties></item>"
+            );
+            assert_eq!(
+                String::from_utf8(stored.clone()).unwrap(),
+                expected,
+                "{piece}"
+            );
+            assert_eq!(
+                parse(&stored),
+                ["declaration", "item", "properties", "property"],
+                "{piece}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_body_without_a_declaration_is_wrapped_as_it_is() {
+        for body in [
+            "<properties/>",
+            "<?xml-stylesheet href=\"a\"?><properties/>",
+            "\u{feff}<properties/>",
+            "<",
+        ] {
+            let stored = wrap_in_pieces(body.as_bytes(), 1);
+            let body = body.trim_start_matches('\u{feff}');
+            assert_eq!(
+                String::from_utf8(stored).unwrap(),
+                format!("<item id=\"item\" version=\"1\">{body}</item>")
+            );
+        }
+    }
+
+    #[test]
+    fn an_empty_body_is_an_empty_item() {
+        for body in ["", "\u{feff}", DECLARATION] {
+            let stored = wrap_in_pieces(body.as_bytes(), 1);
+            let events = parse(&stored);
+            assert_eq!(events.last().map(String::as_str), Some("item"), "{body:?}");
+        }
+    }
+}
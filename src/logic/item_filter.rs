@@ -0,0 +1,55 @@
+use crate::persistence::cold_tier;
+use crate::state::AppState;
+
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Build the item filter from the data directory, and again every
+/// `STREAM_DB_ITEM_FILTER_REBUILD_SECS` or as soon as more items were created than it was
+/// sized for. Lookups go to the disk as usual until the first build finished.
+pub fn start(state: AppState) {
+    if !state.config.item_filter {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let interval = Duration::from_secs(state.config.item_filter_rebuild_secs);
+        loop {
+            let started = Instant::now();
+            state.storage.item_filter.start_build();
+            let storage = state.storage.clone();
+            let item_ids = tokio::task::spawn_blocking(move || cold_tier::item_ids(&storage))
+                .await
+                .map_err(|error| error.to_string())
+                .and_then(|result| result);
+            match item_ids {
+                Ok(item_ids) => {
+                    let build = state.storage.item_filter.rebuild(
+                        &item_ids,
+                        state.config.item_filter_false_positive_rate,
+                        state.config.item_filter_max_bytes,
+                    );
+                    println!(
+                        "Item filter holds {} items in {} KiB with {} hashes, built in {} ms",
+                        build.items,
+                        build.bytes / 1024,
+                        build.hashes,
+                        started.elapsed().as_millis()
+                    );
+                    if build.over_budget {
+                        println!(
+                            "Item filter is capped by STREAM_DB_ITEM_FILTER_MAX_BYTES, more unknown items than configured get through"
+                        );
+                    }
+                }
+                Err(error) => println!("Item filter could not list items: {error}"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = state.storage.item_filter.filled_up() => {
+                    println!("Item filter holds more items than it was sized for, rebuilding");
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,105 @@
+use uuid::Uuid;
+
+/// Longest prefix a generated item ID may be namespaced with
+pub const MAX_ID_PREFIX_LENGTH: usize = 64;
+
+/// Longest item ID accepted, in bytes. Item IDs end up in file names such as
+/// `{item_id}_{version}.index.jsonl`, which most filesystems limit to 255 bytes.
+pub const MAX_ITEM_ID_BYTES: usize = 200;
+
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How `POST /items` mints the IDs of new items (`STREAM_DB_ID_SCHEME`). Both schemes
+/// start with the creation time, so generated IDs sort in the order they were issued.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IdScheme {
+    /// A lowercase UUIDv7, e.g. `01928c6e-7d4a-7b3e-9f0a-2c5d8e1f4a6b`
+    Uuid7,
+    /// A ULID, 26 Crockford base32 characters, e.g. `01JA8RWZ5D6Q3V8G4T2M9XKCNE`
+    Ulid,
+}
+
+impl IdScheme {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "uuid7" => Ok(Self::Uuid7),
+            "ulid" => Ok(Self::Ulid),
+            other => Err(format!(
+                "Unknown item ID scheme {other:?}, expected uuid7 or ulid"
+            )),
+        }
+    }
+
+    /// A new ID, `prefixes` that are set are put in front of it separated by dashes
+    pub fn generate(&self, prefixes: &[Option<&str>]) -> String {
+        let unique = match self {
+            Self::Uuid7 => Uuid::now_v7().to_string(),
+            Self::Ulid => ulid(),
+        };
+        prefixes
+            .iter()
+            .flatten()
+            .copied()
+            .chain([unique.as_str()])
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+/// Item IDs name the item's files in the data directory, so they may not be empty, too
+/// long for a file name, or hold path separators or control characters
+pub fn validate_item_id(item_id: &str) -> Result<(), String> {
+    if item_id.is_empty() {
+        return Err("Item ID must not be empty".to_string());
+    }
+    if item_id.len() > MAX_ITEM_ID_BYTES {
+        return Err(format!(
+            "Item ID must be at most {MAX_ITEM_ID_BYTES} bytes long"
+        ));
+    }
+    if item_id == "." || item_id == ".." {
+        return Err("Item ID must not be . or ..".to_string());
+    }
+    if item_id
+        .chars()
+        .any(|character| character == '/' || character == '\\' || character.is_control())
+    {
+        return Err(
+            "Item ID must not contain slashes, backslashes or control characters".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Generated IDs end up in file names and URL paths, so prefixes are limited to ASCII
+/// letters, digits and dashes
+pub fn validate_prefix(prefix: &str) -> Result<(), String> {
+    if prefix.is_empty() || prefix.len() > MAX_ID_PREFIX_LENGTH {
+        return Err(format!(
+            "Item ID prefix must be 1 to {MAX_ID_PREFIX_LENGTH} characters long"
+        ));
+    }
+    if !prefix
+        .chars()
+        .all(|character| character.is_ascii_alphanumeric() || character == '-')
+    {
+        return Err("Item ID prefix may only contain ASCII letters, digits and dashes".to_string());
+    }
+    Ok(())
+}
+
+/// 48 bits of Unix milliseconds followed by 80 random bits
+fn ulid() -> String {
+    let millis = chrono::Utc::now().timestamp_millis() as u128;
+    // Ten bytes of a v4 UUID untouched by its version and variant bits
+    let random = Uuid::new_v4().into_bytes();
+    let random = random[..6]
+        .iter()
+        .chain(&random[10..14])
+        .fold(0u128, |bits, &byte| bits << 8 | byte as u128);
+    let value = millis << 80 | random;
+    (0..26)
+        .rev()
+        .map(|digit| CROCKFORD_BASE32[(value >> (digit * 5) & 0x1f) as usize] as char)
+        .collect()
+}
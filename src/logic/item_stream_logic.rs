@@ -1,57 +1,2433 @@
-use crate::persistence::file_persistence::{self, FileReader, FileWriter};
-use crate::persistence::item_persistence::{ItemStreamReader, ItemStreamWriter};
+use crate::config::{Config, ConfigReload, Setting};
+use crate::log_control::{LogLevel, LogSettings};
+use crate::logic::block_reindex::{self, ReindexReport};
+use crate::logic::bulk_delete::{self, BulkDeleteFilter, BulkDeleteProgress};
+use crate::logic::byte_range::{ByteRange, ByteRangeReader};
+use crate::logic::commit_events::{CommitEvent, CommitOrigin};
+use crate::logic::commit_hooks::{self, HookContext, HookRun};
+use crate::logic::consistency::{self, ConsistencyError};
+use crate::logic::content_encoding::{ContentEncoding, DecodingReader};
+use crate::logic::content_sniff::ContentSniffer;
+use crate::logic::download_manifest::{self, DownloadManifest};
+use crate::logic::drain::{
+    self, DrainOutcome, DrainReport, ForceReport, StreamKind, TrackedStream,
+};
+use crate::logic::extra_elements::{ExtraElementCopies, ExtraElements};
+use crate::logic::framing::FrameDecoder;
+use crate::logic::item_envelope::ItemEnvelope;
+use crate::logic::metadata_backfill::{self, BackfillError, BackfillReport};
+use crate::logic::presign::{self, PresignedUrl};
+use crate::logic::property_alignment::PropertyAlignedReader;
+use crate::logic::property_dedupe::{DedupeMode, DuplicateProperty, PropertyDedupe};
+use crate::logic::property_element::{property_name, property_start};
+use crate::logic::property_records::NdjsonReader;
+use crate::logic::property_redaction::RedactingReader;
+use crate::logic::property_search::{
+    self, PropertyNamesPage, PropertySearchPage, RebuildReport, SearchError,
+};
+use crate::logic::property_seek::{
+    DeferredSkipReader, PrefixedReader, PropertySkip, skip_properties,
+};
+use crate::logic::property_transform::{TransformError, TransformingReader};
+use crate::logic::property_types::{TypeViolations, check_property_type};
+use crate::logic::publish_groups::PublishGroupCommit;
+use crate::logic::read_prefetch::PrefetchingReader;
+use crate::logic::s3_objects::{self, ListRequest, ObjectListing};
+use crate::logic::schema_defaults::{self, DefaultingReader, SchemaDefaults};
+use crate::logic::space_report::{self, SpaceReportLine, SpaceReportOptions};
+use crate::logic::storage_quota::{self, QuotaExceeded, StorageUsageReport};
+use crate::logic::verification_sweep::SweepStatus;
+use crate::logic::version_tags::{self, TagError};
+use crate::logic::warmup::WarmupProgress;
+use crate::logic::write_limits::WriteLimits;
+use crate::logic::xml_layout::{XmlLayout, XmlLayoutReader};
+use crate::logic::{publish_groups, read_stats, tiering};
+use crate::metrics::Metrics;
+use crate::persistence::audit_log::AuditRecord;
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::cold_tier::{MoveReport, StorageTier};
+use crate::persistence::debug_capture::{self, CaptureTrace, DebugCapture};
+use crate::persistence::discard_writer::DiscardWriter;
+use crate::persistence::fault_injection::FaultRule;
+use crate::persistence::file_handles::{self, FileHandleReport, HandlesExhausted, STREAM_HANDLES};
+use crate::persistence::file_persistence::{
+    self, DeleteError, DeleteReport, FailedUpload, FileReader, FileWriter, ImmutableVersion,
+    KillReport, OpenError, ReadCondition, ReadDurability, ResetVersionError, ResetVersionReport,
+    StatRequest, StreamStatus, VersionStat, VersionState, WaitOutcome, WriteError,
+};
+use crate::persistence::idempotency::RecordedResponse;
+use crate::persistence::integrity::{self, IntegrityCheck, IntegrityFailure, VerifyReport};
+use crate::persistence::item_metadata::{Provenance, VersionMetadata};
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamReader, ItemStreamWriter};
+use crate::persistence::item_settings::{self, Canonicalization, ItemSettings};
+use crate::persistence::item_stats::{ItemStats, VersionStats};
+use crate::persistence::property_index::{PropertyIndex, PropertyIndexEntry};
+use crate::persistence::publish_groups::{PublishGroup, PublishGroupError, PublishGroupStatus};
+use crate::persistence::read_sources::{ReadSource, ReadSourceReport};
+use crate::persistence::shared_file::ItemClaim;
+use crate::persistence::storage::StorageLayout;
+use crate::persistence::store_format::{self, FormatStatus};
+use crate::persistence::transforms::{self, TransformSpec};
+use crate::persistence::version_tags::VersionTags;
+use crate::state::{AppState, StreamDb};
 
-pub fn init() -> Result<(), String> {
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use tokio::fs::File as TokioFile;
+use tokio::sync::{OwnedMutexGuard, broadcast};
+
+/// Generated IDs tried for a new item before giving up, one collision is already absurd
+const NEW_ITEM_ATTEMPTS: usize = 5;
+
+pub fn init(state: &StreamDb) -> Result<(), String> {
     println!("Initializing item stream logic");
-    file_persistence::init()?;
+    file_persistence::init(&state.storage)?;
     Ok(())
 }
 
+pub fn store_format(state: &StreamDb) -> Result<FormatStatus, String> {
+    store_format::status(&state.storage)
+}
+
+/// Per-request options controlling how an item is streamed back to a reader.
+#[derive(Default)]
+pub struct ReadOptions {
+    /// Only yield chunks that end on a complete property element
+    pub align_to_properties: bool,
+    /// Start streaming at this property (0-based) instead of at the beginning, implies
+    /// `align_to_properties`
+    pub from_property: Option<u64>,
+    /// How far to follow an upload that is still in flight
+    pub durability: ReadDurability,
+    pub format: ReadFormat,
+    /// Name of a stored transform to reshape the properties with, implies
+    /// `align_to_properties`
+    pub transform: Option<String>,
+    /// Only send the properties stored under these names, applied before any `transform`
+    /// renames them, implies `align_to_properties`
+    pub properties: Option<Vec<String>>,
+    /// Layout of the XML sent, applied after any transform, implies `align_to_properties`
+    pub xml_layout: XmlLayout,
+    /// Hold the read back until the version has enough data
+    pub wait: Option<ReadWait>,
+    /// Only read these stored bytes, for presigned URLs restricted to a range and `Range`
+    /// requests. Cannot be combined with anything reading by property or changing the
+    /// bytes.
+    pub byte_range: Option<ByteRange>,
+    /// Only read this segment (0-based) of a framed upload, the stored bytes between two
+    /// of the version's `segments`. Same restrictions as `byte_range`, which it sets.
+    pub segment: Option<u64>,
+    /// The reader's `Accept-Encoding`. A version stored compressed is sent as stored if
+    /// it accepts the encoding and the read sends the stored bytes, and decoded otherwise.
+    pub accept_encoding: Option<String>,
+    /// Send the properties the redaction policy drops or masks as stored, for readers
+    /// holding the admin token
+    pub unredacted: bool,
+    /// Read what an upload interrupted by a crash left on disk, instead of failing with
+    /// [`ReadError::Interrupted`]
+    pub allow_partial: bool,
+    /// Send the defaults of the item's schema in place of the declared properties the
+    /// version lacks, see [`DefaultingReader`]
+    pub apply_defaults: bool,
+}
+
+/// What a read waits for before it starts, and for how long at most
+#[derive(Clone, Copy)]
+pub struct ReadWait {
+    pub condition: ReadCondition,
+    pub timeout: Duration,
+}
+
+/// What a reader receives
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum ReadFormat {
+    /// The stored bytes as they are
+    #[default]
+    Xml,
+    /// One JSON object per complete property and line
+    Ndjson,
+}
+
+impl ReadFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "xml" => Ok(Self::Xml),
+            "ndjson" => Ok(Self::Ndjson),
+            other => Err(format!("Unsupported format {other:?}")),
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&'static str> {
+        match self {
+            Self::Xml => None,
+            Self::Ndjson => Some("application/x-ndjson"),
+        }
+    }
+}
+
+/// Why a reader could not be started
+pub enum ReadError {
+    NotFound(String),
+    /// The version's upload was interrupted by a crash and never committed
+    Interrupted(String),
+    /// The version was deleted while readers were streaming it, whose reads go on
+    Deleted(String),
+    PropertyOutOfRange {
+        requested: u64,
+        property_count: u64,
+    },
+    /// The segment asked for is not one of the version's, which has none unless it was
+    /// uploaded framed
+    SegmentOutOfRange {
+        requested: u64,
+        segment_count: u64,
+    },
+    /// A segment was asked of a version still being uploaded, whose segments are only
+    /// known once it commits
+    SegmentOfUncommitted(String),
+    /// No transform of that name is stored
+    UnknownTransform(String),
+    /// The version's data no longer matches its checksum
+    Quarantined(Box<IntegrityFailure>),
+    /// The byte range of the read starts past the end of the committed version
+    ByteRangeNotSatisfiable {
+        range: ByteRange,
+        size: u64,
+    },
+    /// A byte range was asked of a version stored compressed, which is only read whole
+    ByteRangeOfEncoded {
+        range: ByteRange,
+        encoding: ContentEncoding,
+        /// Size of the stored bytes, if the version is committed
+        size: Option<u64>,
+    },
+    /// A byte range was asked of a version holding properties the redaction policy
+    /// drops or masks, or one that may still get some
+    ByteRangeOfRedacted {
+        range: ByteRange,
+    },
+    /// The condition of a waiting read was not met in time
+    WaitTimedOut {
+        condition: ReadCondition,
+        waited: Duration,
+        bytes_available: u64,
+    },
+    /// The read was cancelled before it started, see [`Cancellation`]
+    Cancelled(CancelReason),
+    /// No read source served the version in time
+    Unavailable(String),
+    Failed(String),
+}
+
+impl From<OpenError> for ReadError {
+    fn from(error: OpenError) -> Self {
+        match error {
+            OpenError::NotFound(error) => Self::NotFound(error),
+            OpenError::Interrupted(error) => Self::Interrupted(error),
+            OpenError::Quarantined(failure) => Self::Quarantined(failure),
+            OpenError::Unavailable(error) => Self::Unavailable(error),
+            OpenError::Deleted(error) => Self::Deleted(error),
+            OpenError::Failed(error) => Self::Failed(error),
+        }
+    }
+}
+
+/// Per-request options controlling how an item is stored by a writer.
+#[derive(Clone)]
+pub struct WriteOptions {
+    /// Wrap the stored properties in an `<item>` root element, see [`ItemEnvelope`]
+    pub wrap_root: bool,
+    /// ID of the request performing the write, recorded in the version's receipt
+    pub request_id: Option<String>,
+    /// Check property values against their declared `type`, also enabled through the
+    /// item's settings
+    pub validate_types: bool,
+    /// Order to store the properties in, the item's settings decide when unset
+    pub canonicalize: Option<Canonicalization>,
+    /// What to do with properties whose name was already written, nothing by default
+    pub dedupe: Option<DedupeMode>,
+    /// Write the version however far it is above the latest one, see
+    /// `STREAM_DB_MAX_VERSION_JUMP`
+    pub allow_version_jump: bool,
+    /// Who is writing, recorded in the version's receipt
+    pub provenance: Provenance,
+    /// `x-amz-meta-*` headers of an upload through the S3 API
+    pub user_metadata: BTreeMap<String, String>,
+    /// Encoding the body is sent with, decoded before it is split into properties
+    pub content_encoding: Option<ContentEncoding>,
+    /// Store an encoded body as received rather than decoded, see
+    /// `STREAM_DB_STORE_GZIP_UPLOADS`. Uploads whose properties are wrapped, deduplicated
+    /// or reordered are rewritten anyway and stored decoded.
+    pub store_encoded: bool,
+    /// Wait this long for another upload of the item to finish rather than failing
+    /// right away, see [`ItemStreamLogic::new_queued_writer`]
+    pub queue_wait: Option<Duration>,
+    /// Check the upload as if it were written, without storing anything, see
+    /// [`DiscardWriter`]
+    pub dry_run: bool,
+    /// Stage the version in this publish group on commit, see
+    /// [`publish_groups`](crate::logic::publish_groups)
+    pub publish_group: Option<String>,
+    /// Keep the version from being deleted or replaced until then, at least as long as
+    /// the item's `immutable_for_secs` setting asks
+    pub immutable_until: Option<DateTime<Utc>>,
+    /// The body is a sequence of frames, see [`FrameDecoder`]. Framed uploads are
+    /// stored in the order received, whatever the item's settings ask.
+    pub framed: bool,
+}
+
+impl WriteOptions {
+    /// The instance's defaults
+    pub fn new(config: &Config) -> Self {
+        Self {
+            wrap_root: config.wrap_root,
+            request_id: None,
+            validate_types: false,
+            canonicalize: None,
+            dedupe: None,
+            allow_version_jump: false,
+            provenance: Provenance::default(),
+            user_metadata: BTreeMap::new(),
+            content_encoding: None,
+            store_encoded: config.store_gzip_uploads,
+            queue_wait: None,
+            dry_run: false,
+            publish_group: None,
+            immutable_until: None,
+            framed: false,
+        }
+    }
+}
+
+/// A reader of a version, with what opening it found out about the version
+struct OpenedVersion {
+    reader: Box<dyn ItemStreamReader>,
+    /// The reader reads what an interrupted upload left, see `ReadOptions::allow_partial`
+    salvaged: bool,
+    epoch: Option<u64>,
+    /// Bytes read ahead for the reader, shown by `/admin/streams`
+    prefetched: Arc<AtomicU64>,
+    committed_size: Option<u64>,
+    property_count: Option<u64>,
+    content_encoding: Option<String>,
+    storage_tier: Option<StorageTier>,
+    served_from: Option<ReadSource>,
+}
+
+impl OpenedVersion {
+    /// A reader of another backend, which tells nothing about the version up front
+    fn from_backend(reader: Box<dyn ItemStreamReader>) -> Self {
+        Self {
+            reader,
+            salvaged: false,
+            epoch: None,
+            prefetched: Arc::default(),
+            committed_size: None,
+            property_count: None,
+            content_encoding: None,
+            storage_tier: None,
+            served_from: None,
+        }
+    }
+}
+
 pub struct ItemStreamLogic {
+    state: AppState,
+    item_id: String,
+    item_version: u64,
+    epoch: Option<u64>,
+    /// Where the version a reader follows is stored
+    storage_tier: Option<StorageTier>,
+    /// Which read source a reader found its version in
+    served_from: Option<ReadSource>,
     reader: Option<Box<dyn ItemStreamReader>>,
     writer: Option<Box<dyn ItemStreamWriter>>,
+    envelope: Option<ItemEnvelope>,
+    limits: Option<WriteLimits>,
+    property_index: PropertyIndex,
+    bytes_written: u64,
+    request_id: Option<String>,
+    provenance: Provenance,
+    user_metadata: BTreeMap<String, String>,
+    /// Collects type violations when the writer validates types
+    type_violations: Option<TypeViolations>,
+    canonicalize: Canonicalization,
+    /// Names written so far, when duplicate properties are deduplicated
+    dedupe: Option<PropertyDedupe>,
+    /// What a reader did, added to the item's read stats once it is dropped
+    read_stats: Option<VersionStats>,
+    /// Bytes a reader will return, known up front for a plain read of a committed version
+    content_length: Option<u64>,
+    /// Size of the committed version a reader reads
+    committed_size: Option<u64>,
+    /// Digest of the transform a reader reshapes the properties with, see
+    /// [`TransformSpec::digest`]
+    transform_digest: Option<String>,
+    /// Complete properties of the committed version a reader reads, if they were counted
+    property_count: Option<u64>,
+    /// Progress reported to a graceful shutdown waiting for the stream
+    tracked: TrackedStream,
+    /// Stops the stream, shared with the drain and the upload's file, see
+    /// [`ItemStreamLogic::cancel`]
+    cancellation: Cancellation,
+    /// Extra elements of the upload, recorded in its receipt
+    extra_elements: ExtraElementCopies,
+    /// Encoding of the upload's body
+    upload_encoding: Option<ContentEncoding>,
+    /// Encoding the data is stored with. Written through `write_encoded`, while the
+    /// decoded properties only advance `bytes_written`.
+    stored_encoding: Option<ContentEncoding>,
+    /// Stored bytes written, when the data is stored encoded
+    encoded_bytes_written: u64,
+    /// Whether a reader decodes the stored bytes
+    decoded: bool,
+    /// Properties the redaction policy drops or masks for a reader, known up front for
+    /// committed versions with a property index. `None` for other versions, and when no
+    /// policy applies to the reader.
+    redactions: Option<u64>,
+    /// Whether a reader drops or masks properties on the way out
+    redacted: bool,
+    /// Schema defaults a reader sends, known up front for committed versions with a
+    /// property index and for items without any default. `None` for other versions, and
+    /// when the reader did not ask for defaults.
+    defaults_applied: Option<u64>,
+    /// Version of the schema a reader takes its defaults from
+    schema_version: Option<u64>,
+    /// Whether a reader adds defaults on the way out
+    defaulted: bool,
+    /// The writer is a dry run, whose commit changes nothing
+    dry_run: bool,
+    /// The writer stages its version in a publish group, which commits it later
+    staged: bool,
+    /// The reader reads what an interrupted upload left, see `ReadOptions::allow_partial`
+    salvaged: bool,
+    /// Deadline the upload asked its version to be immutable until
+    immutable_until: Option<DateTime<Utc>>,
+    /// Seconds from its commit the item's settings keep every version immutable for
+    immutable_for_secs: Option<u64>,
+    /// Most frames the body of a framed upload may hold, `None` unless it is framed
+    max_segments: Option<usize>,
+    /// Where the segments of a framed upload start in the stored data, followed by
+    /// where the last one ends
+    segments: Option<Vec<u64>>,
 }
 
 impl ItemStreamLogic {
-    pub fn new_reader(item_id: String, item_version: u64) -> Result<Self, String> {
-        let reader = FileReader::new(item_id, item_version)?;
+    pub async fn new_reader(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        mut options: ReadOptions,
+    ) -> Result<Self, ReadError> {
+        if let Some(segment) = options.segment {
+            options.byte_range = Some(segment_range(state, &item_id, item_version, segment)?);
+        }
+        let mut transform = match &options.transform {
+            Some(name) => Some(
+                transforms::load(&state.storage, name)
+                    .map_err(ReadError::Failed)?
+                    .ok_or_else(|| {
+                        ReadError::UnknownTransform(format!("Unknown transform {name:?}"))
+                    })?,
+            ),
+            None => None,
+        };
+        // A property filter is a transform allowing just those properties
+        if let Some(properties) = &options.properties {
+            transform
+                .get_or_insert_with(TransformSpec::default)
+                .keep_only(properties);
+        }
+        let transformed = transform.is_some();
+        let transform_digest = transform.as_ref().map(TransformSpec::digest);
+        let cancellation = Cancellation::new(state.metrics.clone());
+        let (opened, tracked) = match &state.backend {
+            Some(backend) => {
+                if options.wait.is_some() {
+                    return Err(ReadError::Failed(
+                        "Waiting reads need the data directory, not another backend".to_string(),
+                    ));
+                }
+                let reader = backend
+                    .open_reader(&item_id, item_version)
+                    .await
+                    .map_err(ReadError::from)?;
+                let tracked = state.drain.track(
+                    StreamKind::Read,
+                    &item_id,
+                    item_version,
+                    cancellation.clone(),
+                );
+                (OpenedVersion::from_backend(reader), tracked)
+            }
+            None => {
+                Self::open_file_reader(state, &item_id, item_version, &options, &cancellation)
+                    .await?
+            }
+        };
+        let salvaged = opened.salvaged;
+        let prefetched = opened.prefetched.clone();
+        let committed_size = opened.committed_size;
+        let property_count = opened.property_count.filter(|_| !salvaged);
+        if salvaged {
+            println!(
+                "Reading the {} bytes the interrupted upload of item {item_id} version {item_version} left",
+                committed_size.unwrap_or(0)
+            );
+        }
+        let stored_encoding = opened
+            .content_encoding
+            .as_deref()
+            .map(ContentEncoding::parse)
+            .transpose()
+            .map_err(ReadError::Failed)?;
+        if let (Some(encoding), Some(range)) = (stored_encoding, options.byte_range) {
+            // Offsets into the decoded bytes cannot be found without decoding everything
+            // before them, and ranges of the stored bytes are no use to anybody
+            return Err(ReadError::ByteRangeOfEncoded {
+                range,
+                encoding,
+                size: committed_size,
+            });
+        }
+        let mut redaction = Some(&state.config.redaction)
+            .filter(|policy| !options.unredacted && !policy.is_empty());
+        // Defaults come from the item's schema as it is now, whenever the version was
+        // written
+        let mut defaults = if options.apply_defaults {
+            item_settings::load(&state.storage, &item_id)
+                .map_err(ReadError::Failed)?
+                .schema
+                .map(|schema| SchemaDefaults::new(&schema))
+        } else {
+            None
+        };
+        if let (Some(defaults), Some(properties)) = (&mut defaults, &options.properties) {
+            defaults.keep_only(properties);
+        }
+        let schema_version = defaults.as_ref().map(SchemaDefaults::version);
+        // Committed versions count what the policy applies to and the defaults they need
+        // from their property index, and those without any are read as if there was no
+        // policy or schema
+        let index = if redaction.is_some() || defaults.is_some() {
+            opened.reader.property_index().map_err(ReadError::Failed)?
+        } else {
+            None
+        };
+        let redactions = match (redaction, &index) {
+            (Some(policy), Some(index)) => {
+                Some(policy.count(index, options.from_property.unwrap_or(0)))
+            }
+            _ => None,
+        };
+        if redactions == Some(0) {
+            redaction = None;
+        }
+        let defaults_applied = match (&defaults, &index) {
+            (Some(defaults), Some(index)) => Some(defaults.count(index)),
+            (Some(_), None) => None,
+            (None, _) => options.apply_defaults.then_some(0),
+        };
+        if defaults_applied == Some(0) {
+            defaults = None;
+        }
+        if let (Some(_), Some(range)) = (redaction, options.byte_range) {
+            return Err(ReadError::ByteRangeOfRedacted { range });
+        }
+        let as_stored = redaction.is_none()
+            && defaults.is_none()
+            && transform.is_none()
+            && options.format == ReadFormat::Xml
+            && options.xml_layout == XmlLayout::Verbatim
+            && !options.align_to_properties
+            && options.from_property.is_none();
+        let decoding = stored_encoding.filter(|encoding| {
+            !as_stored || !encoding.accepted_by(options.accept_encoding.as_deref())
+        });
+        if matches!(opened.storage_tier, Some(StorageTier::Cold)) {
+            tiering::promote_after_read(state, &item_id, item_version);
+        }
+        let mut reader = state.faults.wrap_reader(&item_id, opened.reader);
+        if let Some(encoding) = decoding {
+            reader = Box::new(DecodingReader::new(reader, encoding));
+        }
+        let mut start_offset = 0;
+        if let Some(from_property) = options.from_property {
+            (reader, start_offset) = Self::start_at_property(
+                &state.metrics,
+                reader,
+                from_property,
+                committed_size.is_none(),
+            )
+            .await?;
+        }
+        if let Some(range) = options.byte_range {
+            if let Some(size) = committed_size.filter(|size| range.start >= *size) {
+                return Err(ReadError::ByteRangeNotSatisfiable { range, size });
+            }
+            reader.seek(range.start).map_err(ReadError::Failed)?;
+            reader = Box::new(ByteRangeReader::new(reader, range));
+            start_offset = range.start;
+        }
+        if let Some(defaults) = defaults.clone() {
+            // Ahead of the redaction, so a dropped property does not look missing
+            reader = Box::new(DefaultingReader::new(
+                reader,
+                defaults,
+                state.config.align_max_property_bytes,
+                state.metrics.clone(),
+            ));
+        }
+        if let Some(policy) = redaction {
+            // Ahead of any transform, which could rename a property out of the policy's
+            // reach, and of the output formats, which all start from the stored elements
+            reader = Box::new(RedactingReader::new(
+                reader,
+                policy.clone(),
+                state.config.align_max_property_bytes,
+            ));
+        }
+        // Transforms, layouts and NDJSON change the bytes on the way out, alignment only
+        // cuts them into different chunks
+        let content_length = committed_size
+            .filter(|_| {
+                decoding.is_none()
+                    && redaction.is_none()
+                    && defaults.is_none()
+                    && transform.is_none()
+                    && options.format == ReadFormat::Xml
+                    && options.xml_layout == XmlLayout::Verbatim
+            })
+            .map(|size| match options.byte_range {
+                Some(range) => range.len_within(size),
+                None => size.saturating_sub(start_offset),
+            });
+        if let Some(transform) = transform {
+            // Transformed output is cut at property boundaries anyway
+            reader = Box::new(TransformingReader::new(
+                reader,
+                transform.into(),
+                state.config.align_max_property_bytes,
+            ));
+        }
+        if options.format == ReadFormat::Ndjson {
+            // Lines only hold complete properties, so the output is aligned anyway
+            reader = Box::new(NdjsonReader::new(
+                reader,
+                state.config.align_max_property_bytes,
+            ));
+        } else if options.xml_layout != XmlLayout::Verbatim {
+            // Laid out property by property, so the output is aligned anyway
+            reader = Box::new(XmlLayoutReader::new(
+                reader,
+                options.xml_layout,
+                state.config.align_max_property_bytes,
+            ));
+        } else if !transformed && (options.align_to_properties || options.from_property.is_some()) {
+            reader = Box::new(PropertyAlignedReader::new(
+                reader,
+                state.config.align_max_property_bytes,
+            ));
+        }
+        if state.config.read_prefetch_depth > 0 {
+            // Last, so the chunks queued are the ones going out
+            reader = Box::new(PrefetchingReader::new(
+                reader,
+                state.config.read_prefetch_depth,
+                prefetched,
+            ));
+        }
+        if let Some(content_length) = content_length {
+            tracked.expect_bytes(content_length);
+        }
         Ok(ItemStreamLogic {
-            reader: Some(Box::new(reader)),
+            state: state.clone(),
+            item_id,
+            item_version,
+            // Leftovers of an upload are no generation of the version
+            epoch: opened.epoch.filter(|_| !salvaged),
+            storage_tier: opened.storage_tier,
+            served_from: opened.served_from,
+            reader: Some(reader),
             writer: None,
+            envelope: None,
+            limits: None,
+            property_index: PropertyIndex::default(),
+            bytes_written: 0,
+            request_id: None,
+            provenance: Provenance::default(),
+            user_metadata: BTreeMap::new(),
+            type_violations: None,
+            canonicalize: Canonicalization::None,
+            dedupe: None,
+            // Counted for committed and in-flight versions only
+            read_stats: (!salvaged).then(|| VersionStats {
+                reads_started: 1,
+                last_accessed: Some(read_stats::now()),
+                ..Default::default()
+            }),
+            content_length,
+            committed_size,
+            transform_digest,
+            property_count,
+            tracked,
+            cancellation,
+            extra_elements: ExtraElementCopies::default(),
+            upload_encoding: None,
+            dry_run: false,
+            staged: false,
+            salvaged,
+            stored_encoding,
+            encoded_bytes_written: 0,
+            decoded: decoding.is_some(),
+            redactions,
+            redacted: redaction.is_some(),
+            defaults_applied,
+            schema_version,
+            defaulted: defaults.is_some(),
+            immutable_until: None,
+            immutable_for_secs: None,
+            max_segments: None,
+            segments: None,
         })
     }
 
-    pub fn new_writer(item_id: String, item_version: u64) -> Result<Self, String> {
-        let writer = FileWriter::new(&item_id, &item_version)?;
+    /// Open a reader of the version in the data directory, waiting for the condition of
+    /// a waiting read. The read is tracked from before it waits, so a forced drain cuts
+    /// the wait off too.
+    async fn open_file_reader(
+        state: &AppState,
+        item_id: &str,
+        item_version: u64,
+        options: &ReadOptions,
+        cancellation: &Cancellation,
+    ) -> Result<(OpenedVersion, TrackedStream), ReadError> {
+        // Opening a version from disk loads its metadata, which may mean waiting out a
+        // rewrite, so it is opened on the blocking pool
+        let open = {
+            let state = state.clone();
+            let item_id = item_id.to_string();
+            let durability = options.durability;
+            let allow_partial = options.allow_partial;
+            move || match FileReader::new(
+                &state.storage,
+                &state.faults,
+                item_id.clone(),
+                item_version,
+                durability,
+            ) {
+                Err(OpenError::Interrupted(_)) if allow_partial => {
+                    FileReader::salvage(&state.storage, item_id, item_version)
+                }
+                opened => opened,
+            }
+        };
+        let file_reader = tokio::task::spawn_blocking(open)
+            .await
+            .map_err(|error| ReadError::Failed(error.to_string()))?
+            .map_err(ReadError::from)?
+            .with_cancellation(cancellation.clone());
+        let tracked = state.drain.track(
+            StreamKind::Read,
+            item_id,
+            item_version,
+            cancellation.clone(),
+        );
+        if file_reader.opened_from_disk() {
+            state.metrics.reader_disk_opens.increment();
+        } else {
+            state.metrics.reader_registry_hits.increment();
+        }
+        if let Some(wait) = options.wait {
+            match file_reader.wait_until(wait.condition, wait.timeout).await {
+                WaitOutcome::Met => {}
+                WaitOutcome::TimedOut { bytes_available } => {
+                    return Err(ReadError::WaitTimedOut {
+                        condition: wait.condition,
+                        waited: wait.timeout,
+                        bytes_available,
+                    });
+                }
+                WaitOutcome::Failed(error) => return Err(ReadError::Interrupted(error)),
+                WaitOutcome::Cancelled(reason) => return Err(ReadError::Cancelled(reason)),
+            }
+        }
+        let opened = OpenedVersion {
+            salvaged: file_reader.is_salvage(),
+            epoch: Some(file_reader.epoch()),
+            prefetched: file_reader.prefetch_attached(),
+            committed_size: file_reader.committed_size(),
+            property_count: file_reader.property_count(),
+            content_encoding: file_reader.content_encoding().map(str::to_string),
+            storage_tier: Some(if file_reader.is_cold() {
+                StorageTier::Cold
+            } else {
+                StorageTier::Hot
+            }),
+            served_from: Some(file_reader.served_from()),
+            reader: Box::new(file_reader),
+        };
+        Ok((opened, tracked))
+    }
+
+    /// Position `reader` at the start of property `from_property`, returning the offset
+    /// it starts at. Committed versions seek through their block index, which is small
+    /// enough to load for every read, or else their property index; anything else skips
+    /// over the preceding properties, `in_flight` versions only once the read starts.
+    async fn start_at_property(
+        metrics: &Metrics,
+        mut reader: Box<dyn ItemStreamReader>,
+        from_property: u64,
+        in_flight: bool,
+    ) -> Result<(Box<dyn ItemStreamReader>, u64), ReadError> {
+        let mut skip = from_property;
+        let mut skip_from = 0;
+        if let Some(block_index) = reader.block_index().map_err(ReadError::Failed)? {
+            let block = block_index
+                .locate(from_property)
+                .filter(|_| from_property < block_index.property_count)
+                .ok_or(ReadError::PropertyOutOfRange {
+                    requested: from_property,
+                    property_count: block_index.property_count,
+                })?;
+            reader.seek(block.offset).map_err(ReadError::Failed)?;
+            skip = from_property - block.property;
+            skip_from = block.offset;
+            metrics.property_seeks_block_index.increment();
+        } else if let Some(index) = reader.property_index().map_err(ReadError::Failed)? {
+            let Some(offset) = index.offset_of(from_property) else {
+                return Err(ReadError::PropertyOutOfRange {
+                    requested: from_property,
+                    property_count: index.len(),
+                });
+            };
+            reader.seek(offset).map_err(ReadError::Failed)?;
+            metrics.property_seeks_property_index.increment();
+            return Ok((reader, offset));
+        } else {
+            metrics.property_seeks_scan.increment();
+            if in_flight {
+                // The properties may take a while to arrive, the response must not wait
+                // for them. Nothing is declared about the length of an in-flight read.
+                return Ok((Box::new(DeferredSkipReader::new(reader, from_property)), 0));
+            }
+        }
+
+        match skip_properties(reader.as_mut(), skip)
+            .await
+            .map_err(ReadError::Failed)?
+        {
+            PropertySkip::Reached { remainder, skipped } => Ok((
+                Box::new(PrefixedReader::new(remainder, reader)),
+                skip_from + skipped,
+            )),
+            PropertySkip::EndOfStream { property_count } => Err(ReadError::PropertyOutOfRange {
+                requested: from_property,
+                property_count: property_count + (from_property - skip),
+            }),
+        }
+    }
+
+    pub async fn new_writer(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
+        let cancellation = Cancellation::new(state.metrics.clone());
+        let tracked = state.drain.track(
+            StreamKind::Write,
+            &item_id,
+            item_version,
+            cancellation.clone(),
+        );
+        Self::open_writer(
+            state,
+            item_id,
+            item_version,
+            options,
+            None,
+            tracked,
+            cancellation,
+        )
+        .await
+    }
+
+    /// Open a writer like [`Self::new_writer`], but while another upload writes the item
+    /// wait up to `options.queue_wait` in the item's write queue for it to commit or
+    /// abort. The version is checked once the item is free, against what the uploads
+    /// before it committed.
+    pub async fn new_queued_writer(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: WriteOptions,
+    ) -> Result<Self, WriteError> {
+        // Tracked while it waits, so a forced drain cuts the wait off too
+        let cancellation = Cancellation::new(state.metrics.clone());
+        let tracked = state.drain.track(
+            StreamKind::Write,
+            &item_id,
+            item_version,
+            cancellation.clone(),
+        );
+        // Dry runs never claim the item
+        let claim = match options.queue_wait.filter(|_| !options.dry_run) {
+            Some(wait) => Some(
+                file_persistence::claim_item_queued(
+                    &state.storage,
+                    &item_id,
+                    item_version,
+                    wait,
+                    &cancellation,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+        Self::open_writer(
+            state,
+            item_id,
+            item_version,
+            options,
+            claim,
+            tracked,
+            cancellation,
+        )
+        .await
+    }
+
+    async fn open_writer(
+        state: &AppState,
+        item_id: String,
+        item_version: u64,
+        options: WriteOptions,
+        claim: Option<ItemClaim>,
+        tracked: TrackedStream,
+        cancellation: Cancellation,
+    ) -> Result<Self, WriteError> {
+        let settings = item_settings::load(&state.storage, &item_id)?;
+        let mut limits = WriteLimits::resolve(&settings, &state.config);
+        let validate_types = options.validate_types || settings.validate_types == Some(true);
+        // Reordering would tear the segments of a framed upload apart
+        let canonicalize = options
+            .canonicalize
+            .or(settings.canonicalize)
+            .filter(|_| !options.framed)
+            .unwrap_or(Canonicalization::None);
+        if canonicalize != Canonicalization::None {
+            // Sorting rewrites the whole file in memory
+            limits.max_canonicalize_bytes = Some(state.config.canonicalize_max_bytes);
+        }
+        let max_version_jump = state
+            .config
+            .max_version_jump
+            .filter(|_| !options.allow_version_jump);
+        // Rewriting the properties needs them decoded
+        let stored_encoding = options.content_encoding.filter(|_| {
+            options.store_encoded
+                && !options.wrap_root
+                && options.dedupe.is_none()
+                && canonicalize == Canonicalization::None
+        });
+        // Checking the version takes the item's metadata lock, which may mean waiting out
+        // a rewrite, so the writer is opened on the blocking pool
+        let dry_run = options.dry_run;
+        let publish_group = options.publish_group.clone();
+        let open = {
+            let state = state.clone();
+            let item_id = item_id.clone();
+            let cancellation = cancellation.clone();
+            move || -> Result<Box<dyn ItemStreamWriter>, WriteError> {
+                if dry_run {
+                    return Ok(Box::new(DiscardWriter::new(
+                        &state.storage,
+                        &item_id,
+                        item_version,
+                        max_version_jump,
+                        stored_encoding.map(|encoding| encoding.name()),
+                    )?));
+                }
+                if let Some(backend) = &state.backend {
+                    if publish_group.is_some() {
+                        return Err(WriteError::Failed(
+                            "Publish groups need the data directory, not another backend"
+                                .to_string(),
+                        ));
+                    }
+                    let writer = backend.open_writer(&item_id, item_version)?;
+                    return Ok(state.faults.wrap_writer(&item_id, writer));
+                }
+                let writer = FileWriter::new(
+                    &state.storage,
+                    &item_id,
+                    &item_version,
+                    max_version_jump,
+                    claim,
+                    publish_group.as_deref(),
+                    &cancellation,
+                )?;
+                if let Some(encoding) = stored_encoding {
+                    writer.store_encoded(encoding.name());
+                }
+                Ok(state.faults.wrap_writer(&item_id, Box::new(writer)))
+            }
+        };
+        let writer = tokio::task::spawn_blocking(open)
+            .await
+            .map_err(|error| WriteError::Failed(error.to_string()))??;
+        let envelope = options
+            .wrap_root
+            .then(|| ItemEnvelope::new(&item_id, item_version));
         Ok(ItemStreamLogic {
+            state: state.clone(),
+            item_id,
+            item_version,
+            epoch: None,
+            storage_tier: None,
+            served_from: None,
             reader: None,
-            writer: Some(Box::new(writer)),
+            writer: Some(writer),
+            envelope,
+            limits: Some(limits),
+            property_index: PropertyIndex::default(),
+            bytes_written: 0,
+            request_id: options.request_id,
+            provenance: options.provenance,
+            user_metadata: options.user_metadata,
+            type_violations: validate_types.then(TypeViolations::default),
+            canonicalize,
+            dedupe: options.dedupe.map(PropertyDedupe::new),
+            read_stats: None,
+            content_length: None,
+            committed_size: None,
+            transform_digest: None,
+            property_count: None,
+            tracked,
+            cancellation,
+            extra_elements: ExtraElementCopies::default(),
+            upload_encoding: options.content_encoding,
+            stored_encoding,
+            encoded_bytes_written: 0,
+            decoded: false,
+            redactions: None,
+            redacted: false,
+            defaults_applied: None,
+            schema_version: None,
+            defaulted: false,
+            dry_run: options.dry_run,
+            staged: options.publish_group.is_some(),
+            salvaged: false,
+            immutable_until: options.immutable_until,
+            immutable_for_secs: settings.immutable_for_secs,
+            max_segments: options.framed.then_some(state.config.max_segments),
+            segments: None,
         })
     }
 
+    /// Open a writer for version 1 of a new item with a generated ID, namespaced by
+    /// `prefix` after the instance's own prefix. An ID that is already taken, on disk or
+    /// by an upload racing this one, is replaced by a fresh one.
+    pub async fn new_item_writer(
+        state: &AppState,
+        prefix: Option<&str>,
+        options: WriteOptions,
+    ) -> Result<(String, Self), WriteError> {
+        for _ in 0..NEW_ITEM_ATTEMPTS {
+            let item_id = state
+                .config
+                .id_scheme
+                .generate(&[state.config.id_prefix.as_deref(), prefix]);
+            if file_persistence::item_exists(&state.storage, &item_id) {
+                println!("Generated item ID {item_id} is taken, generating another one");
+                continue;
+            }
+            match Self::new_writer(state, item_id.clone(), 1, options.clone()).await {
+                Ok(writer) => return Ok((item_id, writer)),
+                Err(WriteError::Locked(_) | WriteError::VersionConflict { .. }) => {
+                    println!("Generated item ID {item_id} is taken, generating another one");
+                }
+                Err(WriteError::InFlightLimit(limit)) if !limit.instance_full() => {
+                    println!("Generated item ID {item_id} is taken, generating another one");
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Err(WriteError::Failed(format!(
+            "No free item ID found in {NEW_ITEM_ATTEMPTS} attempts"
+        )))
+    }
+
+    pub fn item_id(&self) -> &str {
+        &self.item_id
+    }
+
+    pub fn item_version(&self) -> u64 {
+        self.item_version
+    }
+
+    /// The metrics of the instance the stream runs on
+    pub fn metrics(&self) -> &Metrics {
+        &self.state.metrics
+    }
+
+    /// Generation of the version a reader follows
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
+    /// Where the version a reader follows is stored
+    pub fn storage_tier(&self) -> Option<StorageTier> {
+        self.storage_tier
+    }
+
+    /// Which read source the version a reader follows was found in
+    pub fn served_from(&self) -> Option<ReadSource> {
+        self.served_from
+    }
+
+    /// Bytes the reader will return in total, if the version was committed when it was
+    /// opened and is read as stored
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// Properties the redaction policy drops or masks for a reader, if known up front
+    pub fn redactions(&self) -> Option<u64> {
+        self.redactions
+    }
+
+    /// Whether a reader drops or masks properties, so sends other bytes than stored
+    pub fn is_redacted(&self) -> bool {
+        self.redacted
+    }
+
+    /// Schema defaults a reader sends, if known up front
+    pub fn defaults_applied(&self) -> Option<u64> {
+        self.defaults_applied
+    }
+
+    /// Version of the schema a reader takes its defaults from, if the item has one
+    pub fn schema_version(&self) -> Option<u64> {
+        self.schema_version
+    }
+
+    /// Whether a reader adds defaults, so sends other bytes than stored
+    pub fn is_defaulted(&self) -> bool {
+        self.defaulted
+    }
+
+    /// Whether a reader reads what an interrupted upload left rather than a version
+    pub fn is_salvaged(&self) -> bool {
+        self.salvaged
+    }
+
+    /// Size of the version a reader reads, if it was committed when it was opened
+    pub fn committed_size(&self) -> Option<u64> {
+        self.committed_size
+    }
+
+    /// Digest of the transform a reader applies, if any
+    pub fn transform_digest(&self) -> Option<&str> {
+        self.transform_digest.as_deref()
+    }
+
+    /// Complete properties of the committed version a reader reads, `None` for versions
+    /// committed before they were counted and not backfilled since
+    pub fn property_count(&self) -> Option<u64> {
+        self.property_count
+    }
+
+    /// Encoding of the body an upload is sent with
+    pub fn upload_encoding(&self) -> Option<ContentEncoding> {
+        self.upload_encoding
+    }
+
+    /// Encoding the version is stored with, if it is stored compressed
+    pub fn stored_encoding(&self) -> Option<ContentEncoding> {
+        self.stored_encoding
+    }
+
+    /// Encoding of the bytes a reader returns, the stored one unless they are decoded
+    pub fn content_encoding(&self) -> Option<ContentEncoding> {
+        self.stored_encoding.filter(|_| !self.decoded)
+    }
+
+    /// Whether the upload or the version being read was torn down by someone else, e.g.
+    /// killed through the admin API or deleted
+    pub fn is_aborted(&self) -> bool {
+        self.writer
+            .as_ref()
+            .is_some_and(|writer| writer.is_aborted())
+            || self
+                .reader
+                .as_ref()
+                .is_some_and(|reader| reader.is_aborted())
+    }
+
+    /// Count body bytes received by an upload, `declared_size` once it is announced
+    pub fn track_received(&self, bytes: u64, declared_size: Option<u64>) {
+        if let Some(declared_size) = declared_size {
+            self.tracked.expect_bytes(declared_size);
+        }
+        self.tracked.add_bytes(bytes);
+    }
+
+    /// Why the stream was cancelled, if it was
+    pub fn cancel_reason(&self) -> Option<CancelReason> {
+        self.cancellation.reason()
+    }
+
+    /// Stops the stream, for whoever waits on something else than the stream itself
+    pub fn cancellation(&self) -> Cancellation {
+        self.cancellation.clone()
+    }
+
+    /// Stop the stream for `reason`. An upload is aborted right away, a read fails on
+    /// its next chunk.
+    pub fn cancel(&mut self, reason: CancelReason) {
+        self.cancellation.cancel(reason);
+        self.abort();
+    }
+
+    /// Whether the read fell too far behind the upload it follows and was failed
+    pub fn is_too_slow(&self) -> bool {
+        self.reader
+            .as_ref()
+            .is_some_and(|reader| reader.is_too_slow())
+    }
+
+    /// A splitter for the extra elements uploads to this instance may carry
+    pub fn extra_elements(&self) -> ExtraElements {
+        ExtraElements::new(
+            &self.state.config.extra_elements,
+            self.state.config.extra_element_max_bytes,
+        )
+    }
+
+    /// Looks at the first bytes of the upload's body, none when sniffing is off
+    pub fn content_sniffer(&self) -> Option<ContentSniffer> {
+        ContentSniffer::new(
+            self.state.config.content_sniff,
+            self.upload_encoding.is_some(),
+        )
+    }
+
+    /// Strips the framing off the body of a framed upload
+    pub fn frame_decoder(&self) -> Option<FrameDecoder> {
+        self.max_segments.map(FrameDecoder::new)
+    }
+
+    /// Keep the segments of a framed upload for its receipt, `boundaries` being where
+    /// they start in the body once its framing is stripped, followed by where the last
+    /// one ends. Called once the whole body is written.
+    pub fn record_segments(&mut self, boundaries: Vec<u64>) {
+        // Whatever was written in front of the body, the opening tag of the envelope
+        let shift = self.bytes_written - boundaries.last().copied().unwrap_or(0);
+        self.segments = Some(
+            boundaries
+                .into_iter()
+                .map(|boundary| boundary + shift)
+                .collect(),
+        );
+    }
+
+    /// Keep the extra elements of the upload for its receipt
+    pub fn record_extra_elements(&mut self, extra_elements: ExtraElementCopies) {
+        self.extra_elements = extra_elements;
+    }
+
+    /// Limits the caller has to enforce while feeding this writer
+    pub fn limits(&self) -> Option<&WriteLimits> {
+        self.limits.as_ref()
+    }
+
+    /// Check that `requested_bytes` more, not yet handed to the writer, fit into the
+    /// instance's storage quota
+    pub fn check_quota(&self, requested_bytes: u64) -> Result<(), QuotaExceeded> {
+        storage_quota::check(&self.state, requested_bytes)
+    }
+
     pub async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
         if let Some(ref mut writer) = self.writer {
-            writer.write_chunk(chunk).await
+            let chunk = match self.envelope.as_mut() {
+                Some(envelope) => envelope.wrap(chunk),
+                None => chunk,
+            };
+            let chunk_len = chunk.len() as u64;
+            if !chunk.is_empty() && self.stored_encoding.is_none() {
+                writer.write_chunk(chunk).await?;
+            }
+            self.bytes_written += chunk_len;
+            Ok(())
         } else {
             Err("Writer not initialized".into())
         }
     }
 
+    /// Write the body of an upload stored encoded as received, its decoded bytes go
+    /// through `write_chunk` and `write_property` to be checked and indexed
+    pub async fn write_encoded(&mut self, chunk: Vec<u8>) -> Result<(), String> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Err("Writer not initialized".into());
+        };
+        let chunk_len = chunk.len() as u64;
+        writer.write_chunk(chunk).await?;
+        self.encoded_bytes_written += chunk_len;
+        Ok(())
+    }
+
+    /// Type violations found so far, if the writer validates types
+    pub fn type_violations(&self) -> Option<&TypeViolations> {
+        self.type_violations.as_ref()
+    }
+
+    /// The duplicate property an upload rejecting duplicates failed on
+    pub fn duplicate_property(&self) -> Option<&DuplicateProperty> {
+        self.dedupe.as_ref().and_then(PropertyDedupe::duplicate)
+    }
+
+    /// The newer version whose commit failed this upload's, see
+    /// [`ItemStreamWriter::superseded_by`]
+    pub fn superseded_by(&self) -> Option<u64> {
+        self.writer.as_ref()?.superseded_by()
+    }
+
+    /// Write one property element (possibly preceded by whitespace) and record it in
+    /// the version's property index
+    pub async fn write_property(&mut self, element: Vec<u8>) -> Result<(), String> {
+        let start = property_start(&element).unwrap_or(0);
+        let length = (element.len() - start) as u64;
+        let text = std::str::from_utf8(&element[start..]).ok();
+        let name = text.and_then(property_name);
+        if let Some(dedupe) = self.dedupe.as_mut()
+            && !dedupe.admit(name.as_deref(), self.property_index.entries.len())?
+        {
+            return Ok(());
+        }
+        let value_type = match text.map(check_property_type) {
+            Some(Ok(value_type)) => value_type.map(|value_type| value_type.name().to_string()),
+            Some(Err(violation)) => {
+                if let Some(type_violations) = self.type_violations.as_mut() {
+                    type_violations.push(violation);
+                }
+                None
+            }
+            None => None,
+        };
+
+        self.write_chunk(element).await?;
+        self.property_index.push(PropertyIndexEntry {
+            offset: self.bytes_written - length,
+            length,
+            name,
+            value_type,
+        });
+        Ok(())
+    }
+
     pub async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(reason) = self.cancellation.reason() {
+            return Err(file_persistence::cancelled_message(reason));
+        }
         if let Some(ref mut reader) = self.reader {
-            reader.read_chunk().await
+            let chunk = reader.read_chunk().await?;
+            if let Some(chunk) = &chunk {
+                self.tracked.add_bytes(chunk.len() as u64);
+            }
+            if let Some(read_stats) = self.read_stats.as_mut() {
+                match &chunk {
+                    Some(chunk) => read_stats.bytes_served += chunk.len() as u64,
+                    None => read_stats.reads_completed = 1,
+                }
+            }
+            Ok(chunk)
         } else {
             Err("Reader not initialized".into())
         }
     }
 
-    pub fn finalize(&mut self) -> Result<(), String> {
+    /// Names of the commit-time transforms of the upload, in the order they apply
+    fn commit_transforms(&self) -> Vec<String> {
+        let mut transforms = Vec::new();
+        if self.envelope.is_some() {
+            transforms.push("wrap_root".to_string());
+        }
+        if self.dedupe.as_ref().map(PropertyDedupe::mode) == Some(DedupeMode::Last) {
+            transforms.push("dedupe_last".to_string());
+        }
+        if self.canonicalize == Canonicalization::SortByName {
+            transforms.push("sort_by_name".to_string());
+        }
+        transforms
+    }
+
+    /// Commit the version, returning its receipt
+    pub async fn finalize(&mut self) -> Result<VersionMetadata, String> {
+        let transforms = self.commit_transforms();
         if let Some(ref mut writer) = self.writer {
-            writer.commit().map_err(|error| {
-                format!("Error while persisting the update, item is not written: {error}")
-            })
+            if let Some(envelope) = self.envelope.as_mut() {
+                let mut rest = envelope.finish();
+                rest.extend_from_slice(ItemEnvelope::CLOSING_TAG);
+                self.bytes_written += rest.len() as u64;
+                writer.write_chunk(rest).await?;
+            }
+            // The transforms rewriting the data all go into one plan, so however many the
+            // upload asked for it is rewritten in a single pass, or not at all
+            let property_count = self.property_index.entries.len();
+            let mut order: Vec<usize> = (0..property_count).collect();
+            if let Some(dedupe) = &self.dedupe {
+                let mut is_superseded = vec![false; property_count];
+                for &position in dedupe.superseded() {
+                    is_superseded[position] = true;
+                }
+                order.retain(|&position| !is_superseded[position]);
+            }
+            if self.canonicalize == Canonicalization::SortByName {
+                let entries = &self.property_index.entries;
+                // Stable, so properties sharing a name keep their upload order
+                order.sort_by(|&a, &b| {
+                    let (a, b) = (&entries[a].name, &entries[b].name);
+                    // Unnamed properties go last
+                    (a.is_none(), a).cmp(&(b.is_none(), b))
+                });
+            }
+            if order.iter().copied().ne(0..property_count) {
+                self.property_index = writer
+                    .rewrite_properties(&self.property_index, &order)
+                    .await?;
+            }
+            // Its offsets are into the decoded bytes, of no use to seek in the stored ones
+            if self.stored_encoding.is_none() {
+                writer.store_property_index(&self.property_index)?;
+            }
+            let bytes_received = match self.stored_encoding {
+                Some(_) => self.encoded_bytes_written,
+                None => self.bytes_written,
+            };
+            let committed = writer
+                .commit(&CommitDetails {
+                    property_count: self.property_index.len(),
+                    request_id: self.request_id.clone(),
+                    bytes_received,
+                    extra_elements: std::mem::take(&mut self.extra_elements.copied),
+                    extra_elements_truncated: std::mem::take(&mut self.extra_elements.truncated),
+                    provenance: std::mem::take(&mut self.provenance),
+                    user_metadata: std::mem::take(&mut self.user_metadata),
+                    immutable_until: immutable_until(self.immutable_until, self.immutable_for_secs)
+                        .map(|until| until.to_rfc3339()),
+                    segments: self.segments.take(),
+                    transforms,
+                })
+                .await
+                .map_err(|error| {
+                    format!("Error while persisting the update, item is not written: {error}")
+                })?;
+            // Staged versions are announced once their group is committed
+            if self.dry_run || self.staged {
+                return Ok(committed);
+            }
+            announce_commit(&self.state, &self.item_id, &committed);
+            Ok(committed)
         } else {
-            Ok(())
+            Err("Writer not initialized".into())
         }
     }
+
+    /// Give up on the upload, cleaning up everything it wrote
+    pub fn abort(&mut self) {
+        if let Some(ref mut writer) = self.writer {
+            writer.abort();
+        }
+    }
+}
+
+/// Stored bytes of segment `segment` of a committed version
+fn segment_range(
+    state: &AppState,
+    item_id: &str,
+    item_version: u64,
+    segment: u64,
+) -> Result<ByteRange, ReadError> {
+    let version = match file_persistence::version_state(&state.storage, item_id, item_version)
+        .map_err(ReadError::Failed)?
+    {
+        VersionState::Committed(version) => version,
+        VersionState::InFlight { .. } => {
+            return Err(ReadError::SegmentOfUncommitted(format!(
+                "Version {item_version} of item {item_id} is still being uploaded, segments are only served for committed versions"
+            )));
+        }
+        VersionState::Interrupted(_) => {
+            return Err(ReadError::Interrupted(format!(
+                "Upload of version {item_version} of item {item_id} was interrupted before it was committed"
+            )));
+        }
+        VersionState::Missing => {
+            return Err(ReadError::NotFound("Item not found".to_string()));
+        }
+    };
+    let boundaries = version.segments.unwrap_or_default();
+    let mut starts = boundaries.iter().skip(segment as usize);
+    match (starts.next(), starts.next()) {
+        (Some(&start), Some(&next)) => Ok(ByteRange {
+            start,
+            end: next - 1,
+        }),
+        _ => Err(ReadError::SegmentOutOfRange {
+            requested: segment,
+            segment_count: boundaries.len().saturating_sub(1) as u64,
+        }),
+    }
+}
+
+/// Tell the log, the commit hooks, the block reindex and commit event subscribers
+/// about a version that was just committed
+pub(crate) fn announce_commit(state: &AppState, item_id: &str, committed: &VersionMetadata) {
+    log_commit(item_id, committed);
+    state
+        .metrics
+        .record_commit(item_id, committed.size.unwrap_or(0));
+    commit_hooks::dispatch(state, item_id, committed);
+    block_reindex::schedule(state.clone(), item_id.to_string(), committed.version);
+    state
+        .commit_events
+        .publish(CommitEvent::new(item_id, committed, CommitOrigin::Local));
+}
+
+/// Leave a record of who wrote each version in the log
+fn log_commit(item_id: &str, committed: &VersionMetadata) {
+    let provenance = &committed.provenance;
+    let mut writer = match (&provenance.producer, &provenance.producer_version) {
+        (Some(producer), Some(producer_version)) => format!("{producer} {producer_version}"),
+        (Some(producer), None) => producer.clone(),
+        _ => "an unnamed producer".to_string(),
+    };
+    if let Some(authenticated_as) = &provenance.authenticated_as {
+        writer.push_str(&format!(" authenticated as {authenticated_as}"));
+    }
+    match &provenance.commit_message {
+        Some(message) => println!(
+            "Committed item {item_id} version {} by {writer}: {message}",
+            committed.version
+        ),
+        None => println!(
+            "Committed item {item_id} version {} by {writer}",
+            committed.version
+        ),
+    }
+}
+
+impl Drop for ItemStreamLogic {
+    fn drop(&mut self) {
+        if let Some(read_stats) = self.read_stats.take() {
+            self.state
+                .metrics
+                .record_read(&self.item_id, read_stats.bytes_served);
+            self.state
+                .read_stats
+                .record(&self.item_id, self.item_version, &read_stats);
+        }
+    }
+}
+
+pub fn load_item_settings(state: &StreamDb, item_id: &str) -> Result<ItemSettings, String> {
+    item_settings::load(&state.storage, item_id)
+}
+
+/// Why an item's settings could not be stored
+pub enum SettingsError {
+    Invalid(String),
+    Failed(String),
+}
+
+/// Replace the settings of an item, returning them as stored. A schema is checked first
+/// and gets its version from the one it replaces, which it keeps unless its properties
+/// changed.
+pub fn store_item_settings(
+    state: &StreamDb,
+    item_id: &str,
+    mut settings: ItemSettings,
+) -> Result<ItemSettings, SettingsError> {
+    if let Some(schema) = &mut settings.schema {
+        schema_defaults::validate(schema).map_err(SettingsError::Invalid)?;
+        let previous = item_settings::load(&state.storage, item_id)
+            .map_err(SettingsError::Failed)?
+            .schema;
+        schema.version = match previous {
+            Some(previous) if previous.properties == schema.properties => previous.version,
+            Some(previous) => previous.version + 1,
+            None => 1,
+        };
+    }
+    item_settings::store(&state.storage, item_id, &settings).map_err(SettingsError::Failed)?;
+    Ok(settings)
+}
+
+pub async fn version_state(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VersionState, String> {
+    file_persistence::load_version_state(&state.storage, item_id, item_version).await
+}
+
+/// Versions stat-ed on the blocking pool at a time, so a large batch neither holds one
+/// blocking thread for long nor is stat-ed completely before the first results go out
+const STAT_CHUNK: usize = 256;
+
+/// Stat many versions without opening any reader, in request order, a chunk of results at
+/// a time
+pub fn stat_versions(
+    state: &StreamDb,
+    requests: Vec<StatRequest>,
+) -> impl Stream<Item = Result<Vec<VersionStat>, String>> + use<> {
+    let storage = state.storage.clone();
+    let chunks: Vec<Vec<StatRequest>> = requests.chunks(STAT_CHUNK).map(<[_]>::to_vec).collect();
+    futures::stream::iter(chunks).then(move |chunk| {
+        let storage = storage.clone();
+        async move {
+            tokio::task::spawn_blocking(move || file_persistence::stat_versions(&storage, &chunk))
+                .await
+                .map_err(|error| error.to_string())?
+        }
+    })
+}
+
+pub async fn reindex(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<ReindexReport, String> {
+    block_reindex::reindex(state, item_id, item_version).await
+}
+
+pub fn delete_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<DeleteReport, DeleteError> {
+    version_tags::delete_version(state, item_id, item_version)
+}
+
+/// The later of the deadline an upload asked for and the one its item's settings set
+/// from now. A setting too large to add up to a date never runs out.
+fn immutable_until(asked: Option<DateTime<Utc>>, for_secs: Option<u64>) -> Option<DateTime<Utc>> {
+    let by_settings = for_secs.map(|secs| {
+        i64::try_from(secs)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .and_then(|secs| Utc::now().checked_add_signed(secs))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    });
+    asked.max(by_settings)
+}
+
+/// Push a committed version's immutability out to `until`, which can never be brought
+/// forward again
+pub fn extend_immutability(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    until: DateTime<Utc>,
+) -> Result<ImmutableVersion, WriteError> {
+    file_persistence::extend_immutability(&state.storage, item_id, item_version, until)
+}
+
+pub fn reset_latest_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    force: bool,
+) -> Result<ResetVersionReport, ResetVersionError> {
+    version_tags::reset_latest_version(state, item_id, item_version, force)
+}
+
+pub fn bulk_delete_matching(
+    state: &StreamDb,
+    filter: &BulkDeleteFilter,
+) -> Result<Vec<(String, u64)>, String> {
+    bulk_delete::matching_versions(state, filter)
+}
+
+pub fn bulk_delete(
+    state: AppState,
+    matching: Vec<(String, u64)>,
+    dry_run: bool,
+) -> impl Stream<Item = BulkDeleteProgress> {
+    bulk_delete::bulk_delete(state, matching, dry_run)
+}
+
+pub async fn move_version(
+    state: &AppState,
+    item_id: &str,
+    item_version: u64,
+    tier: StorageTier,
+) -> Result<MoveReport, WriteError> {
+    tiering::move_version(state, item_id, item_version, tier, false).await
+}
+
+/// Check a committed version's data against its checksum, quarantining it on a mismatch
+pub async fn verify_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VerifyReport, WriteError> {
+    let storage = state.storage.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || {
+        integrity::verify_version(&storage, &item_id, item_version, IntegrityCheck::Verify)
+    })
+    .await
+    .map_err(|error| WriteError::Failed(error.to_string()))?
+}
+
+/// Serve a quarantined version again once its data matches its checksum
+pub async fn unquarantine(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VerifyReport, WriteError> {
+    let storage = state.storage.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || integrity::unquarantine(&storage, &item_id, item_version))
+        .await
+        .map_err(|error| WriteError::Failed(error.to_string()))?
+}
+
+pub fn item_stats(state: &StreamDb, item_id: &str) -> Result<ItemStats, String> {
+    read_stats::item_stats(state, item_id)
+}
+
+pub fn version_tags(state: &StreamDb, item_id: &str) -> Result<VersionTags, String> {
+    version_tags::list(state, item_id)
+}
+
+pub fn resolve_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+) -> Result<Option<u64>, String> {
+    version_tags::resolve(state, item_id, tag)
+}
+
+/// Receipts of the item's committed versions, oldest first
+pub fn committed_versions(state: &StreamDb, item_id: &str) -> Result<Vec<VersionMetadata>, String> {
+    Ok(
+        file_persistence::load_item_metadata(&state.storage, item_id)?
+            .versions
+            .into_values()
+            .collect(),
+    )
+}
+
+/// The version of an item that was the latest one at `at`, from a single read of its
+/// metadata
+pub fn resolve_as_of(
+    state: &StreamDb,
+    item_id: &str,
+    at: DateTime<Utc>,
+) -> Result<Option<u64>, String> {
+    Ok(file_persistence::load_item_metadata(&state.storage, item_id)?.version_as_of(at))
+}
+
+pub fn set_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+    item_version: u64,
+) -> Result<VersionTags, TagError> {
+    version_tags::set(state, item_id, tag, item_version)
+}
+
+pub fn remove_version_tag(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+) -> Result<VersionTags, TagError> {
+    version_tags::remove(state, item_id, tag)
+}
+
+pub fn failed_uploads(state: &StreamDb) -> Vec<FailedUpload> {
+    file_persistence::failed_uploads(&state.storage)
+}
+
+pub fn create_publish_group(state: &StreamDb, ttl: Duration) -> Result<PublishGroup, String> {
+    publish_groups::create(state, ttl)
+}
+
+pub fn publish_group(
+    state: &StreamDb,
+    group_id: &str,
+) -> Result<PublishGroupStatus, PublishGroupError> {
+    publish_groups::status(state, group_id)
+}
+
+pub async fn commit_publish_group(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupCommit, PublishGroupError> {
+    publish_groups::commit(state, group_id).await
+}
+
+pub async fn abort_publish_group(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupStatus, PublishGroupError> {
+    publish_groups::abort(state, group_id).await
+}
+
+/// Every commit from now on, see [`CommitEvents`](crate::logic::commit_events::CommitEvents)
+pub fn subscribe_commits(state: &StreamDb) -> broadcast::Receiver<CommitEvent> {
+    state.commit_events.subscribe()
+}
+
+pub fn stream_statuses(state: &StreamDb) -> Vec<StreamStatus> {
+    file_persistence::stream_statuses(&state.storage)
+}
+
+pub fn kill_stream(state: &StreamDb, item_id: &str, item_version: u64) -> KillReport {
+    file_persistence::kill_stream(&state.storage, item_id, item_version, CancelReason::Killed)
+}
+
+pub fn storage_usage(state: &StreamDb) -> StorageUsageReport {
+    storage_quota::usage(state)
+}
+
+pub fn set_storage_quota(state: &StreamDb, quota_bytes: Option<u64>) -> StorageUsageReport {
+    println!("Setting the storage quota to {quota_bytes:?} bytes");
+    state.quota.set(quota_bytes);
+    storage_quota::usage(state)
+}
+
+/// Make room for the file handles of a new stream, see `STREAM_DB_MAX_OPEN_FILES`
+pub fn reserve_file_handles(state: &StreamDb) -> Result<(), HandlesExhausted> {
+    file_persistence::make_room_for_stream(&state.storage).inspect_err(|_| {
+        state.metrics.streams_rejected_open_files.increment();
+    })
+}
+
+pub fn file_handle_report(state: &StreamDb) -> FileHandleReport {
+    file_persistence::file_handle_report(&state.storage)
+}
+
+pub fn storage_layout(state: &StreamDb) -> StorageLayout {
+    state.storage.layout()
+}
+
+pub fn read_source_report(state: &StreamDb) -> ReadSourceReport {
+    state.storage.read_sources.report()
+}
+
+/// Raise the soft `RLIMIT_NOFILE` as far as allowed at startup, and warn when the
+/// configured limits do not fit into it
+pub fn check_open_file_limits(state: &StreamDb) {
+    let soft_limit = match file_handles::raise_nofile_limit() {
+        Ok(soft_limit) => soft_limit,
+        Err(error) => {
+            println!("{error}");
+            match file_handles::nofile_limits() {
+                Some((soft_limit, _)) => soft_limit,
+                None => return,
+            }
+        }
+    };
+    println!("RLIMIT_NOFILE allows {soft_limit} open files");
+    let config = &state.config;
+    if let Some(max_open_files) = config.max_open_files
+        && max_open_files >= soft_limit
+    {
+        println!(
+            "WARNING: STREAM_DB_MAX_OPEN_FILES={max_open_files} leaves no room below RLIMIT_NOFILE={soft_limit} for sockets and short-lived handles, requests may still fail with EMFILE"
+        );
+    }
+    // As many uploads as there are I/O slots, plus the versions kept open by the warm-up
+    let uploads = (config.io_fast_slots + config.io_heavy_slots) as u64;
+    let warmed_up = (config.warmup_items.len() + config.warmup_top_n.unwrap_or(0)) as u64;
+    let needed = uploads * STREAM_HANDLES + warmed_up;
+    let available = config.max_open_files.unwrap_or(soft_limit).min(soft_limit);
+    if needed > available {
+        println!(
+            "WARNING: Uploads filling the {uploads} I/O slots (STREAM_DB_IO_FAST_SLOTS + STREAM_DB_IO_HEAVY_SLOTS) and {warmed_up} warmed up versions need {needed} file handles, only {available} are available"
+        );
+    }
+}
+
+pub fn warmup_progress(state: &StreamDb) -> WarmupProgress {
+    state.warmup.progress()
+}
+
+pub fn config_settings(state: &StreamDb) -> BTreeMap<&'static str, Setting> {
+    let mut settings = state.config.settings();
+    // Changed through the admin API, until they expire
+    let log = state.log.settings();
+    if let Some(status) = log.level_override {
+        settings.insert(
+            "STREAM_DB_LOG_LEVEL",
+            Setting::overridden(log.level.name().to_string(), status.expires_at),
+        );
+    }
+    if let Some(status) = log.debug_items_override {
+        settings.insert(
+            "STREAM_DB_DEBUG_ITEMS",
+            Setting::overridden(log.debug_items.join(","), status.expires_at),
+        );
+    }
+    settings
+}
+
+pub fn reload_config(state: &StreamDb) -> Result<ConfigReload, String> {
+    let reload = state.config.reload()?;
+    state.metrics.configure(state.config.metric_datasets());
+    for change in &reload.applied {
+        println!(
+            "Config reloaded: {}={}",
+            change.name,
+            change.new.as_deref().unwrap_or_default()
+        );
+    }
+    for change in &reload.restart_required {
+        println!(
+            "Config: {} changed to {}, which only takes effect after a restart",
+            change.name,
+            change.new.as_deref().unwrap_or_default()
+        );
+    }
+    Ok(reload)
+}
+
+pub fn verification_status(state: &StreamDb) -> SweepStatus {
+    state.verification.status()
+}
+
+pub fn search_properties(
+    state: &StreamDb,
+    name: &str,
+    latest_only: bool,
+    limit: usize,
+    cursor: Option<&str>,
+) -> Result<PropertySearchPage, SearchError> {
+    property_search::search_properties(state, name, latest_only, limit, cursor)
+}
+
+pub fn search_property_names(
+    state: &StreamDb,
+    prefix: &str,
+    limit: usize,
+) -> Result<PropertyNamesPage, SearchError> {
+    property_search::search_property_names(state, prefix, limit)
+}
+
+pub async fn rebuild_property_index(state: &AppState) -> Result<RebuildReport, SearchError> {
+    property_search::rebuild(state).await
+}
+
+pub async fn backfill_metadata(state: &AppState) -> Result<BackfillReport, BackfillError> {
+    metadata_backfill::backfill(state).await
+}
+
+pub fn space_report(
+    state: AppState,
+    options: SpaceReportOptions,
+) -> impl Stream<Item = SpaceReportLine> {
+    space_report::space_report(state, options)
+}
+
+pub fn hook_names(state: &StreamDb) -> impl Iterator<Item = &str> {
+    state.commit_hooks.specs().map(|spec| spec.name.as_str())
+}
+
+pub async fn test_hook(
+    state: &AppState,
+    hook: &str,
+    item_id: &str,
+    committed: &VersionMetadata,
+) -> Option<HookRun> {
+    let context = HookContext::new(state, item_id, committed);
+    commit_hooks::test(state, hook, context).await
+}
+
+pub fn is_draining(state: &StreamDb) -> bool {
+    state.drain.is_draining()
+}
+
+pub fn drain_report(state: &StreamDb) -> DrainReport {
+    state.drain.report()
+}
+
+pub fn force_drain(state: &StreamDb) -> Result<ForceReport, String> {
+    drain::force(state, CancelReason::Shutdown)
+}
+
+pub async fn drain_on_shutdown(state: &AppState) -> DrainOutcome {
+    drain::drain_on_shutdown(state).await
+}
+
+pub fn fault_rules(state: &StreamDb) -> Vec<FaultRule> {
+    state.faults.rules()
+}
+
+/// Install or, if it injects nothing, remove the fault rule for a pattern. Streams
+/// opened from now on pick it up, open ones keep the rule they started with.
+pub fn set_fault_rule(state: &StreamDb, rule: FaultRule) -> Result<Vec<FaultRule>, String> {
+    println!(
+        "Setting fault rule for items matching {:?}",
+        rule.item_pattern
+    );
+    state.faults.set_rule(rule)?;
+    Ok(state.faults.rules())
+}
+
+pub fn clear_fault_rules(state: &StreamDb) {
+    println!("Clearing all fault rules");
+    state.faults.clear()
+}
+
+/// Start capturing an upload for debugging if its item matches a capture pattern
+pub fn start_debug_capture(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    request_id: &str,
+) -> Option<DebugCapture> {
+    state
+        .debug_captures
+        .start(&state.storage, item_id, item_version, request_id)
+}
+
+pub fn debug_capture_patterns(state: &StreamDb) -> Vec<String> {
+    state.debug_captures.patterns()
+}
+
+pub fn set_debug_capture_patterns(
+    state: &StreamDb,
+    patterns: Vec<String>,
+) -> Result<Vec<String>, String> {
+    println!("Capturing the uploads of items matching {patterns:?}");
+    state.debug_captures.set_patterns(patterns)?;
+    Ok(state.debug_captures.patterns())
+}
+
+pub fn set_log_level(state: &StreamDb, level: LogLevel, ttl: Option<Duration>) -> LogSettings {
+    println!("Logging at level {} {}", level.name(), describe_ttl(ttl));
+    state.log.set_level(level, ttl)
+}
+
+pub fn set_debug_items(
+    state: &StreamDb,
+    patterns: Vec<String>,
+    ttl: Option<Duration>,
+) -> Result<LogSettings, String> {
+    let settings = state.log.set_debug_items(patterns, ttl)?;
+    println!(
+        "Writing debug lines for the items matching {:?} {}",
+        settings.debug_items,
+        describe_ttl(ttl)
+    );
+    Ok(settings)
+}
+
+fn describe_ttl(ttl: Option<Duration>) -> String {
+    match ttl {
+        Some(ttl) => format!("for {} seconds", ttl.as_secs()),
+        None => "until changed again".to_string(),
+    }
+}
+
+pub fn debug_captures(state: &StreamDb) -> Result<Vec<CaptureTrace>, String> {
+    debug_capture::list(&state.storage)
+}
+
+pub fn debug_capture_trace(
+    state: &StreamDb,
+    request_id: &str,
+) -> Result<Option<CaptureTrace>, String> {
+    debug_capture::load_trace(&state.storage, request_id)
+}
+
+pub async fn debug_capture_raw(
+    state: &StreamDb,
+    request_id: &str,
+) -> Result<Option<TokioFile>, String> {
+    debug_capture::open_raw(&state.storage, request_id).await
+}
+
+pub fn transforms(state: &StreamDb) -> Result<BTreeMap<String, TransformSpec>, String> {
+    transforms::load_all(&state.storage)
+}
+
+/// Store a named transform, returning whether it replaced one. Reads started from now on
+/// use it, running ones keep the transform they started with.
+pub fn set_transform(
+    state: &StreamDb,
+    name: &str,
+    spec: TransformSpec,
+) -> Result<bool, TransformError> {
+    transforms::validate_name(name).map_err(TransformError::Invalid)?;
+    spec.validate()
+        .map_err(|error| TransformError::Invalid(format!("Invalid transform {name}: {error}")))?;
+    println!("Storing transform {name}");
+    transforms::store(&state.storage, name, Some(spec)).map_err(TransformError::Failed)
+}
+
+pub fn delete_transform(state: &StreamDb, name: &str) -> Result<(), TransformError> {
+    println!("Deleting transform {name}");
+    match transforms::store(&state.storage, name, None) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(TransformError::NotFound(format!(
+            "Unknown transform {name:?}"
+        ))),
+        Err(error) => Err(TransformError::Failed(error)),
+    }
+}
+
+pub fn consistency_token(state: &StreamDb, item_id: &str, committed: &VersionMetadata) -> String {
+    consistency::issue(state, item_id, committed)
+}
+
+pub async fn await_consistency_token(
+    state: &StreamDb,
+    item_id: &str,
+    token: &str,
+) -> Result<(), ConsistencyError> {
+    consistency::satisfy(state, item_id, token).await
+}
+
+pub async fn download_manifest(
+    state: &AppState,
+    item_id: &str,
+    committed: VersionMetadata,
+    parts: u64,
+) -> Result<DownloadManifest, String> {
+    download_manifest::manifest(state, item_id, committed, parts).await
+}
+
+pub fn s3_object(state: &StreamDb, item_id: &str) -> Result<Option<VersionMetadata>, String> {
+    s3_objects::latest(state, item_id)
+}
+
+pub fn s3_next_version(state: &StreamDb, item_id: &str) -> Result<u64, String> {
+    s3_objects::next_version(state, item_id)
+}
+
+pub async fn delete_s3_object(state: &AppState, item_id: &str) -> Result<bool, WriteError> {
+    s3_objects::delete(state, item_id).await
+}
+
+pub async fn list_s3_objects(
+    state: &AppState,
+    bucket: &str,
+    request: ListRequest,
+) -> Result<ObjectListing, String> {
+    s3_objects::list(state, bucket, request).await
+}
+
+pub fn presign_read(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires_at: DateTime<Utc>,
+    range: Option<ByteRange>,
+) -> PresignedUrl {
+    presign::presign(state, item_id, item_version, expires_at, range)
+}
+
+pub fn verify_presigned_read(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires: Option<i64>,
+    range: Option<&str>,
+    signature: &str,
+) -> Result<Option<ByteRange>, String> {
+    presign::verify(state, item_id, item_version, expires, range, signature)
+}
+
+pub async fn idempotency_turn(state: &StreamDb, key: &str) -> OwnedMutexGuard<()> {
+    state.idempotency.turn(key).await
+}
+
+pub fn recorded_response(state: &StreamDb, key: &str) -> Result<Option<RecordedResponse>, String> {
+    state.idempotency.recorded(&state.storage, key)
+}
+
+pub fn record_response(
+    state: &StreamDb,
+    key: &str,
+    response: RecordedResponse,
+) -> Result<(), String> {
+    state.idempotency.record(&state.storage, key, response)
+}
+
+pub fn is_audited(state: &StreamDb) -> bool {
+    state.audit_log.is_enabled()
+}
+
+pub fn append_audit_record(state: &StreamDb, record: &AuditRecord) -> Result<(), String> {
+    state.audit_log.append(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::file_persistence::tests::TestItem;
+
+    const PROPERTIES: usize = 40;
+
+    fn property(index: usize) -> Vec<u8> {
+        format!("\n  <property name=\"p{index}\">value {index}</property>").into_bytes()
+    }
+
+    async fn write_item(item: &TestItem, commit: bool) -> ItemStreamLogic {
+        let options = WriteOptions {
+            wrap_root: true,
+            request_id: Some("request-1".to_string()),
+            ..WriteOptions::new(&item.state.config)
+        };
+        let mut writer = ItemStreamLogic::new_writer(&item.state, item.id.clone(), 1, options)
+            .await
+            .unwrap();
+        for index in 0..PROPERTIES {
+            writer.write_property(property(index)).await.unwrap();
+        }
+        if commit {
+            writer.finalize().await.unwrap();
+        }
+        writer
+    }
+
+    async fn read_from(item: &TestItem, from_property: Option<u64>) -> Result<Vec<u8>, String> {
+        let options = ReadOptions {
+            from_property,
+            ..ReadOptions::default()
+        };
+        let mut reader =
+            match ItemStreamLogic::new_reader(&item.state, item.id.clone(), 1, options).await {
+                Ok(reader) => reader,
+                Err(ReadError::PropertyOutOfRange {
+                    requested,
+                    property_count,
+                }) => return Err(format!("{requested} of {property_count}")),
+                Err(ReadError::WaitTimedOut { .. }) => panic!("read wait timed out"),
+                Err(ReadError::Quarantined(failure)) => panic!("{}", failure.message()),
+                Err(ReadError::ByteRangeNotSatisfiable { range, size }) => {
+                    panic!("range {range} past the end of {size} bytes")
+                }
+                Err(ReadError::ByteRangeOfEncoded { range, .. }) => {
+                    panic!("range {range} of a compressed version")
+                }
+                Err(ReadError::ByteRangeOfRedacted { range }) => {
+                    panic!("range {range} of a redacted read")
+                }
+                Err(ReadError::SegmentOutOfRange { requested, .. }) => {
+                    panic!("segment {requested} out of range")
+                }
+                Err(ReadError::Cancelled(reason)) => panic!("read cancelled: {}", reason.name()),
+                Err(
+                    ReadError::NotFound(error)
+                    | ReadError::Interrupted(error)
+                    | ReadError::SegmentOfUncommitted(error)
+                    | ReadError::UnknownTransform(error)
+                    | ReadError::Unavailable(error)
+                    | ReadError::Deleted(error)
+                    | ReadError::Failed(error),
+                ) => panic!("{error}"),
+            };
+        let mut bytes = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await? {
+            bytes.extend(chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// A resumed read is the full read from the start tag of the property resumed at
+    fn assert_resumes(full: &[u8], resumed: &[u8], from_property: usize) {
+        let start = String::from_utf8_lossy(full)
+            .find(&format!("<property name=\"p{from_property}\">"))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(resumed),
+            String::from_utf8_lossy(&full[start..]),
+            "from_property={from_property}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_read_resumed_through_the_property_index_has_no_gap_or_duplicate() {
+        let item = TestItem::new("resume-indexed");
+        write_item(&item, true).await;
+        let full = read_from(&item, None).await.unwrap();
+        for from_property in [0, 1, 17, PROPERTIES - 1] {
+            let resumed = read_from(&item, Some(from_property as u64)).await.unwrap();
+            assert_resumes(&full, &resumed, from_property);
+        }
+        assert_eq!(
+            read_from(&item, Some(PROPERTIES as u64)).await,
+            Err(format!("{PROPERTIES} of {PROPERTIES}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_read_resumed_by_skipping_properties_has_no_gap_or_duplicate() {
+        let item = TestItem::new("resume-skipped");
+        let mut writer = write_item(&item, false).await;
+        writer
+            .write_chunk(b"\n</properties>".to_vec())
+            .await
+            .unwrap();
+
+        // The upload is still in flight, so there is no index to seek through yet. It
+        // commits while the readers skip, so they see the end of the stream. The skip
+        // happens on the first read, so a missing property fails the stream, not the open.
+        let commit = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            writer.finalize().await.unwrap();
+        };
+        let (full, resumed, beyond, _) = tokio::join!(
+            read_from(&item, None),
+            read_from(&item, Some(23)),
+            read_from(&item, Some(PROPERTIES as u64 + 5)),
+            commit
+        );
+        assert_resumes(&full.unwrap(), &resumed.unwrap(), 23);
+        assert_eq!(
+            beyond,
+            Err(format!(
+                "Property {} is out of range, the item has {PROPERTIES} properties",
+                PROPERTIES + 5
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn the_receipt_of_a_committed_version_can_be_fetched_later() {
+        use sha2::{Digest, Sha256};
+
+        let item = TestItem::new("receipt");
+        assert!(matches!(
+            version_state(&item.state, &item.id, 1).await.unwrap(),
+            VersionState::Missing
+        ));
+
+        let mut writer = write_item(&item, false).await;
+        let VersionState::InFlight {
+            bytes_written,
+            bytes_durable,
+        } = version_state(&item.state, &item.id, 1).await.unwrap()
+        else {
+            panic!("the upload is not reported in flight");
+        };
+        assert!(bytes_written > 0);
+        // Every chunk is synced before it is acknowledged by default
+        assert_eq!(bytes_durable, bytes_written);
+
+        let returned = writer.finalize().await.unwrap();
+        let VersionState::Committed(receipt) =
+            version_state(&item.state, &item.id, 1).await.unwrap()
+        else {
+            panic!("the version is not reported committed");
+        };
+        let stored = std::fs::read(item.data_path(1)).unwrap();
+        assert_eq!(receipt.version, 1);
+        assert_eq!(receipt.size, Some(stored.len() as u64));
+        assert_eq!(receipt.property_count, Some(PROPERTIES as u64));
+        let sha256: String = Sha256::digest(&stored)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(receipt.sha256, Some(sha256));
+        assert_eq!(receipt.request_id.as_deref(), Some("request-1"));
+        let committed_at = receipt.committed_at.clone().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(&committed_at).is_ok());
+        assert_eq!(
+            serde_json::to_value(&receipt).unwrap(),
+            serde_json::to_value(&returned).unwrap()
+        );
+
+        // Another version of the item was never committed
+        assert!(matches!(
+            version_state(&item.state, &item.id, 2).await.unwrap(),
+            VersionState::Missing
+        ));
+    }
+
+    async fn read_all(reader: &mut ItemStreamLogic) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await? {
+            bytes.extend(chunk);
+        }
+        Ok(bytes)
+    }
+
+    async fn open(item: &TestItem) -> ItemStreamLogic {
+        match ItemStreamLogic::new_reader(&item.state, item.id.clone(), 1, ReadOptions::default())
+            .await
+        {
+            Ok(reader) => reader,
+            Err(_) => panic!("version 1 of {} cannot be read", item.id),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reader_never_mixes_the_bytes_of_two_generations() {
+        // Larger than one read chunk, so the reader is still attached mid-file, as long as
+        // it does not read ahead to the end
+        let item = TestItem::with_config("generations", |config| {
+            config.read_prefetch_depth = 0;
+        });
+        let first = format!("<property>{}</property>", "1".repeat(20_000));
+        let second = format!("<property>{}</property>", "2".repeat(20_000));
+
+        let mut writer = ItemStreamLogic::new_writer(
+            &item.state,
+            item.id.clone(),
+            1,
+            WriteOptions::new(&item.state.config),
+        )
+        .await
+        .unwrap();
+        writer
+            .write_property(first.clone().into_bytes())
+            .await
+            .unwrap();
+        writer.finalize().await.unwrap();
+        drop(writer);
+
+        let mut attached = open(&item).await;
+        let first_epoch = attached.epoch().unwrap();
+        let head = attached.read_chunk().await.unwrap().unwrap();
+        assert!(first.as_bytes().starts_with(&head));
+
+        // Delete and rewrite the version while the reader is in the middle of it
+        assert!(delete_version(&item.state, &item.id, 1).is_ok());
+        let mut writer = ItemStreamLogic::new_writer(
+            &item.state,
+            item.id.clone(),
+            1,
+            WriteOptions::new(&item.state.config),
+        )
+        .await
+        .unwrap();
+        writer
+            .write_property(second.clone().into_bytes())
+            .await
+            .unwrap();
+
+        // The reader of the deleted generation reads it to its end, and only it
+        let rest = read_all(&mut attached).await.unwrap();
+        assert_eq!([head, rest].concat(), first.as_bytes());
+
+        // Readers of the new generation see only its bytes, in flight and once committed
+        let mut following = open(&item).await;
+        writer.finalize().await.unwrap();
+        assert_eq!(following.epoch(), Some(first_epoch + 1));
+        assert_eq!(read_all(&mut following).await.unwrap(), second.as_bytes());
+        let mut committed = open(&item).await;
+        assert_eq!(committed.epoch(), Some(first_epoch + 1));
+        assert_eq!(read_all(&mut committed).await.unwrap(), second.as_bytes());
+    }
 }
@@ -0,0 +1,65 @@
+use crate::logic::publish_groups;
+use crate::persistence::{debug_capture, file_persistence};
+use crate::state::AppState;
+
+use tokio::time::Duration;
+
+/// How often housekeeping runs at most
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often deferred deletes are retried when nothing wakes them, e.g. because a file
+/// could not be removed while its handle was being closed
+const DEFERRED_DELETE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically purge whatever the configured retention no longer covers. The retention
+/// is read again on every run, so a config reload applies to the next one.
+pub fn start(state: AppState) {
+    start_deferred_deletes(state.clone());
+    tokio::spawn(async move {
+        loop {
+            let live = state.config.live();
+            let failed_upload_retention =
+                live.failed_upload_retention_secs.map(Duration::from_secs);
+            let capture_retention = Duration::from_secs(live.debug_capture_retention_secs);
+            let period = failed_upload_retention.map_or(capture_retention, |retention| {
+                retention.min(capture_retention)
+            });
+            if let Some(retention) = failed_upload_retention {
+                let purged = file_persistence::purge_failed_uploads(&state.storage, retention);
+                if purged > 0 {
+                    println!(
+                        "Purged {purged} interrupted uploads older than {}s",
+                        retention.as_secs()
+                    );
+                }
+            }
+            publish_groups::abort_expired(&state);
+            let purged = debug_capture::purge(&state.storage, capture_retention);
+            if purged > 0 {
+                println!(
+                    "Purged {purged} debug captures older than {}s",
+                    capture_retention.as_secs()
+                );
+            }
+            tokio::time::sleep(period.clamp(Duration::from_secs(1), MAX_INTERVAL)).await;
+        }
+    });
+}
+
+/// Remove the files of deleted or moved versions as soon as their last reader let go of
+/// them, see [`DeferredDeletes`](crate::persistence::deferred_deletes::DeferredDeletes)
+fn start_deferred_deletes(state: AppState) {
+    tokio::spawn(async move {
+        let deferred_deletes = &state.storage.deferred_deletes;
+        loop {
+            let _ = tokio::time::timeout(
+                DEFERRED_DELETE_INTERVAL,
+                deferred_deletes.released.notified(),
+            )
+            .await;
+            let storage = state.storage.clone();
+            let _ = tokio::task::spawn_blocking(move || storage.deferred_deletes.remove_released())
+                .await;
+        }
+    });
+}
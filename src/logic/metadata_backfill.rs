@@ -0,0 +1,288 @@
+use crate::logic::content_sniff::{ContentSniffMode, ContentSniffer};
+use crate::logic::property_alignment::PropertyBoundaryScanner;
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence::{
+    self, data_file_name, metadata_path, version_file_path,
+};
+use crate::persistence::integrity::{self, FileDigest};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Summary of a backfill run
+#[derive(Serialize)]
+pub struct BackfillReport {
+    pub items: usize,
+    /// Committed versions without a size or checksum that were hashed
+    pub versions_checked: usize,
+    /// ... of which had them recorded
+    pub versions_backfilled: usize,
+    /// Committed versions without a property count that had it recorded. Versions
+    /// stored compressed or holding something other than XML are left without one.
+    pub property_counts_backfilled: usize,
+    pub bytes_hashed: u64,
+    pub errors: Vec<String>,
+    pub duration_ms: u64,
+}
+
+pub enum BackfillError {
+    AlreadyRunning,
+    Failed(String),
+}
+
+/// Hash every committed version that lacks a size or checksum and record them, like a
+/// complete read of each would, and count the properties of those that lack a count in
+/// the same read. Reads are limited to `STREAM_DB_BACKFILL_BYTES_PER_SECOND`
+/// so foreground traffic is not starved, and only one backfill runs at a time.
+pub async fn backfill(state: &AppState) -> Result<BackfillReport, BackfillError> {
+    let _permit = state
+        .backfill_permits
+        .try_acquire()
+        .map_err(|_| BackfillError::AlreadyRunning)?;
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || backfill_all(&state))
+        .await
+        .map_err(|error| BackfillError::Failed(error.to_string()))?
+}
+
+fn backfill_all(state: &StreamDb) -> Result<BackfillReport, BackfillError> {
+    let storage = &state.storage;
+    let mut throttle = Throttle::new(state.config.live().backfill_bytes_per_second);
+    let mut report = BackfillReport {
+        items: 0,
+        versions_checked: 0,
+        versions_backfilled: 0,
+        property_counts_backfilled: 0,
+        bytes_hashed: 0,
+        errors: Vec::new(),
+        duration_ms: 0,
+    };
+
+    for item_id in cold_tier::item_ids(storage).map_err(BackfillError::Failed)? {
+        let metadata = file_persistence::load_item_metadata(storage, &item_id)
+            .map_err(BackfillError::Failed)?;
+        report.items += 1;
+        let lacking = metadata.versions.values().filter(|version| {
+            (integrity::lacks_digest(version) || lacks_property_count(version))
+                && version.quarantined_at.is_none()
+        });
+        for version in lacking {
+            let lacks_digest = integrity::lacks_digest(version);
+            if lacks_digest {
+                report.versions_checked += 1;
+            }
+            let path = metadata_path(storage, &item_id);
+            let epoch = version.epoch.unwrap_or(0);
+            let backfilled = read(
+                state,
+                &item_id,
+                version,
+                lacks_property_count(version),
+                &mut throttle,
+            )
+            .and_then(|(found, property_count)| {
+                report.bytes_hashed += found.size;
+                let digest = lacks_digest
+                    && integrity::backfill_digest(
+                        &storage.metrics,
+                        &path,
+                        &item_id,
+                        version.version,
+                        epoch,
+                        &found,
+                    )
+                    .map_err(|error| error.message())?;
+                let Some(property_count) = property_count else {
+                    return Ok((digest, false));
+                };
+                let recorded = integrity::backfill_property_count(
+                    &path,
+                    &item_id,
+                    version.version,
+                    epoch,
+                    property_count,
+                )
+                .map_err(|error| error.message())?;
+                // Readers of a version already open get it too
+                if let Some(shared_file) = storage.registry.get(&item_id, version.version)
+                    && recorded
+                    && shared_file.epoch == epoch
+                {
+                    shared_file.set_property_count(property_count);
+                }
+                Ok((digest, recorded))
+            });
+            match backfilled {
+                Ok((digest, property_count)) => {
+                    report.versions_backfilled += usize::from(digest);
+                    report.property_counts_backfilled += usize::from(property_count);
+                }
+                Err(error) => report.errors.push(format!(
+                    "Item {item_id} version {}: {error}",
+                    version.version
+                )),
+            }
+        }
+    }
+
+    report.duration_ms = throttle.started.elapsed().as_millis() as u64;
+    println!(
+        "Backfilled the size and checksum of {} of {} versions committed without them and the property count of {} in {}ms, {} failed",
+        report.versions_backfilled,
+        report.versions_checked,
+        report.property_counts_backfilled,
+        report.duration_ms,
+        report.errors.len()
+    );
+    Ok(report)
+}
+
+/// Whether `version` was committed before its properties were counted. Versions stored
+/// compressed are left alone, their properties are only found by decoding them.
+fn lacks_property_count(version: &VersionMetadata) -> bool {
+    version.property_count.is_none() && version.content_encoding.is_none()
+}
+
+/// Size and SHA-256 of a committed version's data file, read no faster than `throttle`
+pub fn hash(
+    state: &StreamDb,
+    item_id: &str,
+    version: &VersionMetadata,
+    throttle: &mut Throttle,
+) -> Result<FileDigest, String> {
+    read(state, item_id, version, false, throttle).map(|(found, _)| found)
+}
+
+/// Size and SHA-256 of a committed version's data file, read no faster than `throttle`,
+/// and with `count_properties` the complete properties it holds. Those are `None` for
+/// data that does not start like XML, which holds no properties to count.
+fn read(
+    state: &StreamDb,
+    item_id: &str,
+    version: &VersionMetadata,
+    count_properties: bool,
+    throttle: &mut Throttle,
+) -> Result<(FileDigest, Option<u64>), String> {
+    let path = version_file_path(
+        &state.storage,
+        version.location.as_deref(),
+        &data_file_name(item_id, version.version),
+    );
+    let mut file = File::open(&path).map_err(|error| format!("Open of {path} failed: {error}"))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_CHUNK_SIZE];
+    let mut size = 0u64;
+    // Counting stops once the data turns out not to be XML
+    let mut counter = count_properties.then(PropertyCounter::new);
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .map_err(|error| format!("Read of {path} failed: {error}"))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        size += bytes_read as u64;
+        throttle.consumed(bytes_read as u64);
+        if let Some(active) = counter.as_mut()
+            && !active.push(&buffer[..bytes_read])
+        {
+            counter = None;
+        }
+    }
+    let found = FileDigest {
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+    };
+    Ok((found, counter.and_then(PropertyCounter::finish)))
+}
+
+/// Counts the complete properties of stored data as it is read, as long as it starts
+/// like XML, told the way uploads are sniffed
+struct PropertyCounter {
+    sniffer: Option<ContentSniffer>,
+    scanner: PropertyBoundaryScanner,
+    properties: u64,
+}
+
+impl PropertyCounter {
+    fn new() -> Self {
+        Self {
+            sniffer: ContentSniffer::new(ContentSniffMode::Warn, false),
+            scanner: PropertyBoundaryScanner::new(),
+            properties: 0,
+        }
+    }
+
+    /// Take the next bytes, `false` once they show the data is not XML
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        let Some(sniffer) = self.sniffer.as_mut() else {
+            self.count(bytes);
+            return true;
+        };
+        match sniffer.push(bytes.to_vec()) {
+            None => true,
+            Some((_, Some(_))) => false,
+            Some((held, None)) => {
+                self.sniffer = None;
+                self.count(&held);
+                true
+            }
+        }
+    }
+
+    /// The properties counted, `None` when the data never started like XML
+    fn finish(mut self) -> Option<u64> {
+        if let Some(mut sniffer) = self.sniffer.take() {
+            match sniffer.finish() {
+                (held, None) if held.iter().any(|byte| !byte.is_ascii_whitespace()) => {
+                    self.count(&held)
+                }
+                _ => return None,
+            }
+        }
+        Some(self.properties)
+    }
+
+    fn count(&mut self, bytes: &[u8]) {
+        self.properties += self.scanner.scan(bytes).len() as u64;
+    }
+}
+
+/// Keeps the bytes read, or the files inspected, over a whole run below a throughput,
+/// unlimited when 0
+pub struct Throttle {
+    bytes_per_second: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Sleep off any lead on the throughput after reading `bytes` more
+    pub fn consumed(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.bytes_per_second == 0 {
+            return;
+        }
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_second as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
@@ -1 +1,47 @@
+pub mod aws_chunked;
+pub mod block_reindex;
+pub mod bulk_delete;
+pub mod byte_range;
+pub mod commit_events;
+pub mod commit_hooks;
+pub mod consistency;
+pub mod content_encoding;
+pub mod content_sniff;
+pub mod data_dir_watch;
+pub mod download_manifest;
+pub mod drain;
+pub mod extra_elements;
+pub mod framing;
+pub mod idempotency;
+pub mod item_envelope;
+pub mod item_filter;
+pub mod item_ids;
 pub mod item_stream_logic;
+pub mod maintenance;
+pub mod metadata_backfill;
+pub mod presign;
+pub mod property_alignment;
+pub mod property_dedupe;
+pub mod property_element;
+pub mod property_records;
+pub mod property_redaction;
+pub mod property_search;
+pub mod property_seek;
+pub mod property_transform;
+pub mod property_types;
+pub mod publish_groups;
+pub mod read_prefetch;
+pub mod read_stats;
+pub mod replica;
+pub mod s3_objects;
+pub mod schema_defaults;
+pub mod space_report;
+pub mod storage_quota;
+pub mod stream_ingest;
+pub mod tiering;
+pub mod upload_completeness;
+pub mod verification_sweep;
+pub mod version_tags;
+pub mod warmup;
+pub mod write_limits;
+pub mod xml_layout;
@@ -0,0 +1,102 @@
+use crate::logic::byte_range::ByteRange;
+use crate::state::StreamDb;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+
+/// Expiry of a presigned URL unless the caller asks for another one
+pub const DEFAULT_EXPIRES_IN_SECS: u64 = 3600;
+
+/// A link reading one version without any token, until it expires
+pub struct PresignedUrl {
+    /// Path and query, relative to the instance
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+    pub range: Option<ByteRange>,
+}
+
+/// Sign a read of `item_version` of `item_id` that is valid until `expires_at`, and only
+/// for `range` if given. The signature covers the read's path, the expiry and the range,
+/// with the secret consistency tokens are signed with, so the URL is accepted by every
+/// node sharing `STREAM_DB_SECRET`.
+pub fn presign(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires_at: DateTime<Utc>,
+    range: Option<ByteRange>,
+) -> PresignedUrl {
+    let expires = expires_at.timestamp();
+    let signature = state
+        .consistency
+        .sign(&signed_message(item_id, item_version, expires, range));
+    let mut url = format!(
+        "/read-item-stream/{}/{item_version}?expires={expires}",
+        encode_path_segment(item_id)
+    );
+    if let Some(range) = range {
+        url.push_str(&format!("&range={range}"));
+    }
+    url.push_str(&format!("&sig={}", URL_SAFE_NO_PAD.encode(signature)));
+    PresignedUrl {
+        url,
+        expires_at,
+        range,
+    }
+}
+
+/// Check the `expires`, `range` and `sig` query parameters of a presigned read, returning
+/// the range the read is restricted to. A URL whose parameters were changed in any way is
+/// refused like one that was never signed.
+pub fn verify(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    expires: Option<i64>,
+    range: Option<&str>,
+    signature: &str,
+) -> Result<Option<ByteRange>, String> {
+    let invalid = || "Presigned URL signature is invalid".to_string();
+    let expires = expires.ok_or("Presigned URL has no expiry")?;
+    let range = range
+        .map(ByteRange::parse)
+        .transpose()
+        .map_err(|_| invalid())?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    let message = signed_message(item_id, item_version, expires, range);
+    if !state.consistency.verify(&message, &signature) {
+        return Err(invalid());
+    }
+    if Utc::now().timestamp() >= expires {
+        let expired_at = DateTime::<Utc>::from_timestamp(expires, 0)
+            .map(|expired_at| expired_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .unwrap_or_else(|| expires.to_string());
+        return Err(format!("Presigned URL expired at {expired_at}"));
+    }
+    Ok(range)
+}
+
+fn signed_message(
+    item_id: &str,
+    item_version: u64,
+    expires: i64,
+    range: Option<ByteRange>,
+) -> Vec<u8> {
+    let range = range.map(|range| range.to_string()).unwrap_or_default();
+    format!("GET /read-item-stream/{item_id}/{item_version}\n{expires}\n{range}").into_bytes()
+}
+
+/// Percent-encode everything but the characters URLs leave alone, item IDs may hold
+/// spaces, `?`, `#` or `%`
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
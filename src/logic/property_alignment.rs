@@ -0,0 +1,502 @@
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+
+const PROPERTY_ELEMENT: &[u8] = b"property";
+const MAX_TRACKED_NAME_LENGTH: usize = 64;
+
+enum ScanState {
+    Text,
+    TagStart,
+    StartTagName,
+    StartTag { quote: Option<u8>, slash: bool },
+    EndTagName,
+    EndTag,
+    Bang,
+    Comment { dashes: u8 },
+    CData { brackets: u8 },
+    Declaration,
+    ProcessingInstruction { question: bool },
+}
+
+/// Incremental scanner that finds where `<property>` elements end in a byte stream
+/// which may be split at arbitrary positions.
+///
+/// It only understands enough XML to skip over attribute values, comments, CDATA sections
+/// and processing instructions, so a `</property>` inside any of those is not mistaken
+/// for a boundary.
+pub struct PropertyBoundaryScanner {
+    state: ScanState,
+    name: Vec<u8>,
+    markup: Vec<u8>,
+    position: u64,
+}
+
+impl PropertyBoundaryScanner {
+    pub fn new() -> Self {
+        Self {
+            state: ScanState::Text,
+            name: Vec::new(),
+            markup: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Feeds the next bytes of the stream and returns the stream offsets just past every
+    /// property element that was closed within them.
+    pub fn scan(&mut self, bytes: &[u8]) -> Vec<u64> {
+        let mut boundaries = Vec::new();
+
+        for &byte in bytes {
+            self.position += 1;
+            self.state = match std::mem::replace(&mut self.state, ScanState::Text) {
+                ScanState::Text => match byte {
+                    b'<' => ScanState::TagStart,
+                    _ => ScanState::Text,
+                },
+                ScanState::TagStart => match byte {
+                    b'/' => {
+                        self.name.clear();
+                        ScanState::EndTagName
+                    }
+                    b'!' => {
+                        self.markup.clear();
+                        ScanState::Bang
+                    }
+                    b'?' => ScanState::ProcessingInstruction { question: false },
+                    _ => {
+                        self.name.clear();
+                        self.push_name_byte(byte);
+                        ScanState::StartTagName
+                    }
+                },
+                ScanState::StartTagName => match byte {
+                    b'>' => ScanState::Text,
+                    b'/' => ScanState::StartTag {
+                        quote: None,
+                        slash: true,
+                    },
+                    _ if byte.is_ascii_whitespace() => ScanState::StartTag {
+                        quote: None,
+                        slash: false,
+                    },
+                    _ => {
+                        self.push_name_byte(byte);
+                        ScanState::StartTagName
+                    }
+                },
+                ScanState::StartTag {
+                    quote: Some(quote),
+                    slash,
+                } => ScanState::StartTag {
+                    quote: (byte != quote).then_some(quote),
+                    slash,
+                },
+                ScanState::StartTag { quote: None, slash } => match byte {
+                    b'"' | b'\'' => ScanState::StartTag {
+                        quote: Some(byte),
+                        slash: false,
+                    },
+                    b'/' => ScanState::StartTag {
+                        quote: None,
+                        slash: true,
+                    },
+                    b'>' => {
+                        if slash && self.name == PROPERTY_ELEMENT {
+                            boundaries.push(self.position);
+                        }
+                        ScanState::Text
+                    }
+                    _ => ScanState::StartTag {
+                        quote: None,
+                        slash: slash && byte.is_ascii_whitespace(),
+                    },
+                },
+                ScanState::EndTagName => match byte {
+                    b'>' => {
+                        if self.name == PROPERTY_ELEMENT {
+                            boundaries.push(self.position);
+                        }
+                        ScanState::Text
+                    }
+                    _ if byte.is_ascii_whitespace() => ScanState::EndTag,
+                    _ => {
+                        self.push_name_byte(byte);
+                        ScanState::EndTagName
+                    }
+                },
+                ScanState::EndTag => match byte {
+                    b'>' => {
+                        if self.name == PROPERTY_ELEMENT {
+                            boundaries.push(self.position);
+                        }
+                        ScanState::Text
+                    }
+                    _ => ScanState::EndTag,
+                },
+                ScanState::Bang => {
+                    self.markup.push(byte);
+                    if self.markup == b"--" {
+                        ScanState::Comment { dashes: 0 }
+                    } else if self.markup == b"[CDATA[" {
+                        ScanState::CData { brackets: 0 }
+                    } else if b"--".starts_with(&self.markup)
+                        || b"[CDATA[".starts_with(&self.markup)
+                    {
+                        ScanState::Bang
+                    } else if byte == b'>' {
+                        ScanState::Text
+                    } else {
+                        ScanState::Declaration
+                    }
+                }
+                ScanState::Comment { dashes } => match byte {
+                    b'-' => ScanState::Comment {
+                        dashes: (dashes + 1).min(2),
+                    },
+                    b'>' if dashes == 2 => ScanState::Text,
+                    _ => ScanState::Comment { dashes: 0 },
+                },
+                ScanState::CData { brackets } => match byte {
+                    b']' => ScanState::CData {
+                        brackets: (brackets + 1).min(2),
+                    },
+                    b'>' if brackets == 2 => ScanState::Text,
+                    _ => ScanState::CData { brackets: 0 },
+                },
+                ScanState::Declaration => match byte {
+                    b'>' => ScanState::Text,
+                    _ => ScanState::Declaration,
+                },
+                ScanState::ProcessingInstruction { question } => match byte {
+                    b'>' if question => ScanState::Text,
+                    _ => ScanState::ProcessingInstruction {
+                        question: byte == b'?',
+                    },
+                },
+            };
+        }
+
+        boundaries
+    }
+
+    fn push_name_byte(&mut self, byte: u8) {
+        // Names longer than anything we compare against only need to stay "not a match"
+        if self.name.len() <= MAX_TRACKED_NAME_LENGTH {
+            self.name.push(byte);
+        }
+    }
+}
+
+impl Default for PropertyBoundaryScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Re-chunks a byte stream so that every emitted chunk ends right after a complete
+/// property element. Bytes after the last boundary are held back until the next one
+/// arrives, or until the stream ends.
+pub struct PropertyAligner {
+    scanner: PropertyBoundaryScanner,
+    pending: Vec<u8>,
+    pending_start: u64,
+    max_pending_bytes: usize,
+}
+
+impl PropertyAligner {
+    pub fn new(max_pending_bytes: usize) -> Self {
+        Self {
+            scanner: PropertyBoundaryScanner::new(),
+            pending: Vec::new(),
+            pending_start: 0,
+            max_pending_bytes,
+        }
+    }
+
+    /// Takes the next raw chunk and returns everything that can now be emitted aligned.
+    ///
+    /// When a single property grows past `max_pending_bytes` the held back bytes are
+    /// released unaligned instead of buffering without bound.
+    pub fn push(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        let boundaries = self.scanner.scan(chunk);
+        self.pending.extend_from_slice(chunk);
+
+        let mut output = match boundaries.last() {
+            Some(&boundary) => {
+                let remainder = self
+                    .pending
+                    .split_off((boundary - self.pending_start) as usize);
+                self.pending_start = boundary;
+                std::mem::replace(&mut self.pending, remainder)
+            }
+            None => Vec::new(),
+        };
+
+        if self.pending.len() > self.max_pending_bytes {
+            println!(
+                "Property exceeds the alignment ceiling of {} bytes, passing {} bytes through unaligned",
+                self.max_pending_bytes,
+                self.pending.len()
+            );
+            self.pending_start += self.pending.len() as u64;
+            output.append(&mut self.pending);
+        }
+
+        (!output.is_empty()).then_some(output)
+    }
+
+    /// Releases whatever is still held back once the underlying stream has ended.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        self.pending_start += self.pending.len() as u64;
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+/// Splits a byte stream into segments that each end right after a complete property
+/// element, holding whatever preceded the property and the property itself. Unlike
+/// [`PropertyAligner`] it never hands out a property in pieces, so it fails once a single
+/// property grows past `max_pending_bytes`.
+pub struct PropertySplitter {
+    scanner: PropertyBoundaryScanner,
+    pending: Vec<u8>,
+    pending_start: u64,
+    max_pending_bytes: usize,
+}
+
+impl PropertySplitter {
+    pub fn new(max_pending_bytes: usize) -> Self {
+        Self {
+            scanner: PropertyBoundaryScanner::new(),
+            pending: Vec::new(),
+            pending_start: 0,
+            max_pending_bytes,
+        }
+    }
+
+    /// Takes the next raw chunk and returns the segments completed by it
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let boundaries = self.scanner.scan(chunk);
+        self.pending.extend_from_slice(chunk);
+
+        let mut segments = Vec::with_capacity(boundaries.len());
+        let mut consumed = 0;
+        for boundary in boundaries {
+            let end = (boundary - self.pending_start) as usize;
+            segments.push(self.pending[consumed..end].to_vec());
+            consumed = end;
+        }
+        self.pending.drain(..consumed);
+        self.pending_start += consumed as u64;
+
+        if self.pending.len() > self.max_pending_bytes {
+            return Err(format!(
+                "Property exceeds the limit of {} bytes",
+                self.max_pending_bytes
+            ));
+        }
+        Ok(segments)
+    }
+
+    /// Bytes after the last complete property
+    pub fn remainder(&self) -> &[u8] {
+        &self.pending
+    }
+}
+
+/// Reader wrapper that only yields data ending on property element boundaries.
+pub struct PropertyAlignedReader {
+    inner: Box<dyn ItemStreamReader>,
+    aligner: PropertyAligner,
+    inner_finished: bool,
+}
+
+impl PropertyAlignedReader {
+    pub fn new(inner: Box<dyn ItemStreamReader>, max_pending_bytes: usize) -> Self {
+        Self {
+            inner,
+            aligner: PropertyAligner::new(max_pending_bytes),
+            inner_finished: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for PropertyAlignedReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    if let Some(aligned) = self.aligner.push(&chunk) {
+                        return Ok(Some(aligned));
+                    }
+                }
+                None => {
+                    self.inner_finished = true;
+                    return Ok(self.aligner.finish());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Properties with a `</property>` or `>` hidden in every construct the scanner has
+    /// to skip over, followed by the offsets just past each property
+    fn tricky_document() -> (Vec<u8>, Vec<u64>) {
+        let properties: [&str; 7] = [
+            r#"<property name="a>b" note='</property>'>1</property>"#,
+            r#"<property name="comment"><!-- </property> -- > --></property>"#,
+            r#"<property name="cdata"><![CDATA[</property> ]] ]>]]></property>"#,
+            r#"<property name="pi"><?process </property> ? > ?></property>"#,
+            r#"<property/>"#,
+            r#"<property name="empty" />"#,
+            r#"<propertyish>x</propertyish><property name="last"><value>2</value></property >"#,
+        ];
+        let mut document = b"<?xml version=\"1.0\"?><!DOCTYPE properties><properties>".to_vec();
+        let mut boundaries = Vec::new();
+        for property in properties {
+            document.extend_from_slice(property.as_bytes());
+            boundaries.push(document.len() as u64);
+        }
+        document.extend_from_slice(b"</properties>");
+        (document, boundaries)
+    }
+
+    /// Feed `document` split at `splits`, returning the chunks yielded while streaming and
+    /// the one flushed at the end
+    fn align(document: &[u8], splits: &[usize], ceiling: usize) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        let mut aligner = PropertyAligner::new(ceiling);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([document.len()].iter()) {
+            chunks.extend(aligner.push(&document[start..end]));
+            start = end;
+        }
+        (chunks, aligner.finish())
+    }
+
+    fn assert_aligned(document: &[u8], boundaries: &[u64], splits: &[usize]) {
+        let (chunks, rest) = align(document, splits, usize::MAX);
+        let mut end = 0;
+        for chunk in &chunks {
+            end += chunk.len() as u64;
+            assert!(
+                boundaries.contains(&end),
+                "chunk ends at {end}, not on a boundary, splits {splits:?}"
+            );
+        }
+        let mut received = chunks.concat();
+        received.extend(rest.unwrap_or_default());
+        assert_eq!(received, document, "splits {splits:?}");
+    }
+
+    #[test]
+    fn the_scanner_finds_every_boundary_and_nothing_else() {
+        let (document, boundaries) = tricky_document();
+        assert_eq!(PropertyBoundaryScanner::new().scan(&document), boundaries);
+    }
+
+    #[test]
+    fn chunks_end_on_boundaries_wherever_the_data_is_split_once() {
+        let (document, boundaries) = tricky_document();
+        for split in 0..=document.len() {
+            assert_aligned(&document, &boundaries, &[split]);
+        }
+    }
+
+    #[test]
+    fn chunks_end_on_boundaries_when_the_data_arrives_byte_by_byte() {
+        let (document, boundaries) = tricky_document();
+        let splits: Vec<usize> = (1..document.len()).collect();
+        assert_aligned(&document, &boundaries, &splits);
+
+        let mut scanner = PropertyBoundaryScanner::new();
+        let found: Vec<u64> = document
+            .iter()
+            .flat_map(|byte| scanner.scan(std::slice::from_ref(byte)))
+            .collect();
+        assert_eq!(found, boundaries);
+    }
+
+    #[test]
+    fn chunks_end_on_boundaries_for_every_pair_of_splits() {
+        let (document, boundaries) = tricky_document();
+        for first in (0..document.len()).step_by(3) {
+            for second in (first..document.len()).step_by(7) {
+                assert_aligned(&document, &boundaries, &[first, second]);
+            }
+        }
+    }
+
+    #[test]
+    fn the_tail_after_the_last_property_is_only_flushed_at_the_end() {
+        let (chunks, rest) = align(b"<property/><prop", &[], usize::MAX);
+        assert_eq!(chunks, [b"<property/>".to_vec()]);
+        assert_eq!(rest.as_deref(), Some(&b"<prop"[..]));
+    }
+
+    #[test]
+    fn a_property_above_the_ceiling_is_passed_through_unaligned() {
+        let large = format!("<property name=\"large\">{}</property>", "x".repeat(100));
+        let document = format!("<property/>{large}<property/>");
+        let splits: Vec<usize> = (10..document.len()).step_by(10).collect();
+        let (chunks, rest) = align(document.as_bytes(), &splits, 32);
+
+        // The bytes held back never grow far past the ceiling, so some chunk ends inside
+        // the large property, and nothing is lost or reordered
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 32 + 10));
+        let ends: Vec<usize> = chunks
+            .iter()
+            .scan(0, |end, chunk| {
+                *end += chunk.len();
+                Some(*end)
+            })
+            .collect();
+        assert!(ends.iter().any(|end| *end > 11 && *end < 11 + large.len()));
+        let mut received = chunks.concat();
+        received.extend(rest.unwrap_or_default());
+        assert_eq!(received, document.as_bytes());
+
+        // Once the large property is through, the stream is aligned again
+        assert_eq!(ends.last(), Some(&document.len()));
+    }
+
+    struct ChunkedReader(std::collections::VecDeque<Vec<u8>>);
+
+    #[async_trait]
+    impl ItemStreamReader for ChunkedReader {
+        async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    #[tokio::test]
+    async fn the_reader_yields_aligned_chunks_of_its_inner_reader() {
+        let (document, boundaries) = tricky_document();
+        let inner = ChunkedReader(document.chunks(5).map(<[u8]>::to_vec).collect());
+        let mut reader = PropertyAlignedReader::new(Box::new(inner), usize::MAX);
+        let mut received = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await.unwrap() {
+            assert!(!chunk.is_empty());
+            received.extend(chunk);
+            assert!(
+                boundaries.contains(&(received.len() as u64)) || received.len() == document.len()
+            );
+        }
+        assert_eq!(received, document);
+        assert_eq!(reader.read_chunk().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// What an upload does with a property whose name it already wrote
+/// (`X-Dedupe-Properties`)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DedupeMode {
+    /// Keep the first occurrence, later ones are never written
+    First,
+    /// Keep the last occurrence, earlier ones are cut out of the data file at commit
+    Last,
+    /// Fail the upload at the first duplicate
+    Reject,
+}
+
+impl DedupeMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!(
+                "Unknown property deduplication {other:?}, expected last, first or reject"
+            )),
+        }
+    }
+}
+
+/// The duplicate a `reject` upload failed on, properties are counted from 0 in upload
+/// order
+#[derive(Serialize, Clone, Debug)]
+pub struct DuplicateProperty {
+    pub name: String,
+    pub first_property: u64,
+    pub duplicate_property: u64,
+}
+
+/// Names seen by an upload so far. Only the names are kept, never the values, so memory
+/// grows with the number of distinct names rather than with the upload.
+pub struct PropertyDedupe {
+    mode: DedupeMode,
+    /// Upload position and index position of the occurrence kept so far, by name
+    seen: HashMap<String, (u64, usize)>,
+    /// Properties received so far, duplicates included
+    received: u64,
+    /// Index positions of the occurrences a later one replaced, in `last` mode
+    superseded: Vec<usize>,
+    duplicate: Option<DuplicateProperty>,
+}
+
+impl PropertyDedupe {
+    pub fn new(mode: DedupeMode) -> Self {
+        Self {
+            mode,
+            seen: HashMap::new(),
+            received: 0,
+            superseded: Vec::new(),
+            duplicate: None,
+        }
+    }
+
+    /// Decide about the next property, which would become entry `index_position` of the
+    /// property index. Returns whether to write it. Unnamed properties are always written.
+    pub fn admit(&mut self, name: Option<&str>, index_position: usize) -> Result<bool, String> {
+        let position = self.received;
+        self.received += 1;
+        let Some(name) = name else {
+            return Ok(true);
+        };
+        let Some(&(first_position, kept_position)) = self.seen.get(name) else {
+            self.seen
+                .insert(name.to_string(), (position, index_position));
+            return Ok(true);
+        };
+
+        match self.mode {
+            DedupeMode::First => Ok(false),
+            DedupeMode::Last => {
+                self.superseded.push(kept_position);
+                self.seen
+                    .insert(name.to_string(), (position, index_position));
+                Ok(true)
+            }
+            DedupeMode::Reject => {
+                let message = format!(
+                    "Property {name:?} appears more than once, as property {first_position} and again as property {position}"
+                );
+                self.duplicate = Some(DuplicateProperty {
+                    name: name.to_string(),
+                    first_property: first_position,
+                    duplicate_property: position,
+                });
+                Err(message)
+            }
+        }
+    }
+
+    pub fn mode(&self) -> DedupeMode {
+        self.mode
+    }
+
+    /// Index positions to cut out of the data file before it is committed
+    pub fn superseded(&self) -> &[usize] {
+        &self.superseded
+    }
+
+    /// The duplicate a `reject` upload failed on
+    pub fn duplicate(&self) -> Option<&DuplicateProperty> {
+        self.duplicate.as_ref()
+    }
+}
@@ -0,0 +1,30 @@
+use quick_xml::Reader;
+use quick_xml::events::Event;
+
+pub const PROPERTY_START_TAG: &str = "<property";
+pub const PROPERTY_END_TAG: &str = "</property>";
+
+/// Name of a property element, taken from its `for` or `name` attribute
+pub fn property_name(element: &str) -> Option<String> {
+    let mut reader = Reader::from_str(element);
+    match reader.read_event() {
+        Ok(Event::Start(tag)) | Ok(Event::Empty(tag)) => tag
+            .attributes()
+            .flatten()
+            .find(|attribute| matches!(attribute.key.as_ref(), b"for" | b"name"))
+            .and_then(|attribute| {
+                attribute
+                    .unescape_value()
+                    .ok()
+                    .map(|value| value.into_owned())
+            }),
+        _ => None,
+    }
+}
+
+/// Offset of the property start tag within `bytes`, skipping whatever precedes it
+pub fn property_start(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(PROPERTY_START_TAG.len())
+        .position(|window| window == PROPERTY_START_TAG.as_bytes())
+}
@@ -0,0 +1,189 @@
+use crate::logic::property_alignment::PropertySplitter;
+use crate::logic::property_element::{property_name, property_start};
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+use quick_xml::Reader;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+use serde::Serialize;
+
+/// Sent to idle NDJSON readers instead of an XML comment, parsers skip blank lines
+pub const NDJSON_KEEPALIVE: &[u8] = b"\n";
+
+/// One property element as a JSON object. Text content is unescaped into `value`,
+/// content with nested elements is passed on as XML in `raw`.
+#[derive(Serialize, Default, Debug)]
+pub struct PropertyRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+}
+
+impl PropertyRecord {
+    /// Parse one complete property element
+    pub fn parse(element: &[u8]) -> Self {
+        let element = String::from_utf8_lossy(element);
+        let mut record = Self {
+            name: property_name(&element),
+            ..Self::default()
+        };
+        let mut reader = Reader::from_str(&element);
+        let mut value = String::new();
+        let mut content_start = None;
+        let mut depth = 0usize;
+        let mut nested = false;
+
+        loop {
+            let position = reader.buffer_position() as usize;
+            match reader.read_event() {
+                Ok(Event::Empty(_)) if content_start.is_none() => {
+                    record.value = Some(String::new());
+                    return record;
+                }
+                Ok(Event::Start(_)) if content_start.is_none() => {
+                    content_start = Some(reader.buffer_position() as usize);
+                    depth = 1;
+                }
+                Ok(Event::Start(_)) => {
+                    nested = true;
+                    depth += 1;
+                }
+                Ok(Event::Empty(_)) => nested = true,
+                Ok(Event::End(_)) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let content = &element[content_start.unwrap_or(position)..position];
+                        if nested {
+                            record.raw = Some(content.to_string());
+                        } else {
+                            record.value = Some(value);
+                        }
+                        return record;
+                    }
+                }
+                Ok(Event::Text(text)) if !nested => {
+                    value.push_str(&text.decode().unwrap_or_default());
+                }
+                Ok(Event::CData(cdata)) if !nested => {
+                    value.push_str(&cdata.decode().unwrap_or_default());
+                }
+                Ok(Event::GeneralRef(reference)) if !nested => match reference.resolve_char_ref() {
+                    Ok(Some(character)) => value.push(character),
+                    _ => {
+                        let name = reference.decode().unwrap_or_default();
+                        match resolve_predefined_entity(&name) {
+                            Some(resolved) => value.push_str(resolved),
+                            None => value.push_str(&format!("&{name};")),
+                        }
+                    }
+                },
+                Ok(Event::Eof) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+
+        // Malformed elements are passed on as they are rather than dropped
+        record.raw = Some(element[content_start.unwrap_or(0)..].to_string());
+        record
+    }
+}
+
+/// Incremental parser turning a byte stream, split at arbitrary positions, into the
+/// property elements it contains. Only complete elements come out; bytes of a property
+/// that has not been closed yet are held back.
+pub struct PropertyRecordParser {
+    splitter: PropertySplitter,
+}
+
+impl PropertyRecordParser {
+    pub fn new(max_pending_bytes: usize) -> Self {
+        Self {
+            splitter: PropertySplitter::new(max_pending_bytes),
+        }
+    }
+
+    /// Takes the next raw chunk and returns the properties completed by it. Fails once a
+    /// single property grows past `max_pending_bytes`, since it can only be emitted whole.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<PropertyRecord>, String> {
+        let segments = self
+            .splitter
+            .push(chunk)
+            .map_err(|error| format!("{error} for property records"))?;
+        // Whitespace and envelope tags between properties are skipped
+        Ok(segments
+            .iter()
+            .filter_map(|segment| {
+                property_start(segment).map(|start| PropertyRecord::parse(&segment[start..]))
+            })
+            .collect())
+    }
+
+    /// Bytes of a property that was never closed, dropped when the stream ends
+    pub fn pending_bytes(&self) -> usize {
+        let remainder = self.splitter.remainder();
+        property_start(remainder).map_or(0, |start| remainder.len() - start)
+    }
+}
+
+/// Reader wrapper that yields every complete property as a line of JSON. Bytes of a
+/// property cut off by the end of the stream are dropped, so every line parses.
+pub struct NdjsonReader {
+    inner: Box<dyn ItemStreamReader>,
+    parser: PropertyRecordParser,
+    inner_finished: bool,
+}
+
+impl NdjsonReader {
+    pub fn new(inner: Box<dyn ItemStreamReader>, max_property_bytes: usize) -> Self {
+        Self {
+            inner,
+            parser: PropertyRecordParser::new(max_property_bytes),
+            inner_finished: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for NdjsonReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    let records = self.parser.push(&chunk)?;
+                    if records.is_empty() {
+                        continue;
+                    }
+                    let mut lines = Vec::new();
+                    for record in records {
+                        serde_json::to_writer(&mut lines, &record)
+                            .map_err(|error| format!("JSON serialization error: {error}"))?;
+                        lines.push(b'\n');
+                    }
+                    return Ok(Some(lines));
+                }
+                None => {
+                    self.inner_finished = true;
+                    let dropped = self.parser.pending_bytes();
+                    if dropped > 0 {
+                        println!(
+                            "Dropped {dropped} bytes of an unfinished property at the end of an NDJSON read"
+                        );
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
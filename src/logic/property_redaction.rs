@@ -0,0 +1,175 @@
+use crate::logic::property_alignment::PropertySplitter;
+use crate::logic::property_element::{property_name, property_start};
+use crate::persistence::fault_injection::matches_pattern;
+use crate::persistence::item_persistence::ItemStreamReader;
+use crate::persistence::property_index::PropertyIndex;
+
+use async_trait::async_trait;
+use quick_xml::Reader;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+
+/// What masked values are replaced with unless `STREAM_DB_REDACT_PLACEHOLDER` says otherwise
+pub const DEFAULT_PLACEHOLDER: &str = "[REDACTED]";
+
+/// What happens to a property the redaction policy names
+#[derive(Clone, Copy, PartialEq)]
+pub enum RedactionAction {
+    /// Leave the whole element out
+    Drop,
+    /// Send the element with its value replaced by the placeholder
+    Mask,
+}
+
+/// Properties that never leave the server as stored, unless an admin asks for them with
+/// `unredacted=true`. Patterns are matched against the stored names, exactly or with `*`
+/// standing for any run of characters. A name matching both lists is dropped.
+#[derive(Clone, Default)]
+pub struct RedactionPolicy {
+    drop: Vec<String>,
+    mask: Vec<String>,
+    placeholder: String,
+}
+
+impl RedactionPolicy {
+    pub fn new(drop: Vec<String>, mask: Vec<String>, placeholder: String) -> Self {
+        Self {
+            drop,
+            mask,
+            placeholder,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.mask.is_empty()
+    }
+
+    /// What the policy does to properties stored as `name`, if anything
+    pub fn action(&self, name: &str) -> Option<RedactionAction> {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern.as_bytes(), name.as_bytes()))
+        };
+        if matches(&self.drop) {
+            Some(RedactionAction::Drop)
+        } else if matches(&self.mask) {
+            Some(RedactionAction::Mask)
+        } else {
+            None
+        }
+    }
+
+    /// Properties of a committed version from `from_property` on the policy applies to,
+    /// counted from its property index
+    pub fn count(&self, index: &PropertyIndex, from_property: u64) -> u64 {
+        index
+            .entries
+            .iter()
+            .skip(from_property as usize)
+            .filter_map(|entry| entry.name.as_deref())
+            .filter(|name| self.action(name).is_some())
+            .count() as u64
+    }
+}
+
+/// Reader wrapper dropping or masking the properties a [`RedactionPolicy`] names as they
+/// stream by. It sits right behind the stored bytes, before any transform can rename a
+/// property or any output format rewrites it, so every read built on
+/// `ItemStreamLogic::new_reader` goes through it.
+pub struct RedactingReader {
+    inner: Box<dyn ItemStreamReader>,
+    policy: RedactionPolicy,
+    splitter: PropertySplitter,
+    inner_finished: bool,
+}
+
+impl RedactingReader {
+    pub fn new(
+        inner: Box<dyn ItemStreamReader>,
+        policy: RedactionPolicy,
+        max_property_bytes: usize,
+    ) -> Self {
+        Self {
+            inner,
+            policy,
+            splitter: PropertySplitter::new(max_property_bytes),
+            inner_finished: false,
+        }
+    }
+
+    fn redact_segment(&self, segment: &[u8], output: &mut Vec<u8>) {
+        let start = property_start(segment).unwrap_or(segment.len());
+        let (prefix, element) = segment.split_at(start);
+        let element_text = String::from_utf8_lossy(element);
+        let action = property_name(&element_text).and_then(|name| self.policy.action(&name));
+        match action {
+            Some(RedactionAction::Drop) => {
+                // Whitespace around a dropped property would only pile up
+                if !prefix.iter().all(u8::is_ascii_whitespace) {
+                    output.extend_from_slice(prefix);
+                }
+            }
+            Some(RedactionAction::Mask) => {
+                output.extend_from_slice(prefix);
+                output.extend_from_slice(&mask_property(&element_text, &self.policy.placeholder));
+            }
+            None => output.extend_from_slice(segment),
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for RedactingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    let segments = self
+                        .splitter
+                        .push(&chunk)
+                        .map_err(|error| format!("{error} for redaction"))?;
+                    let mut output = Vec::new();
+                    for segment in segments {
+                        self.redact_segment(&segment, &mut output);
+                    }
+                    if !output.is_empty() {
+                        return Ok(Some(output));
+                    }
+                }
+                None => {
+                    self.inner_finished = true;
+                    let remainder = self.splitter.remainder().to_vec();
+                    return Ok((!remainder.is_empty()).then_some(remainder));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+/// `element` with its content replaced by a string holding `placeholder`. The start tag is
+/// kept as stored, so the property keeps its name; the type goes, as the placeholder
+/// would not be a valid value of most of them.
+fn mask_property(element: &str, placeholder: &str) -> Vec<u8> {
+    let mut reader = Reader::from_str(element);
+    let Ok(Event::Start(tag)) = reader.read_event() else {
+        // An empty element has no value to hide
+        return element.as_bytes().to_vec();
+    };
+    let start_tag = &element[..reader.buffer_position() as usize];
+    format!(
+        "{start_tag}<string>{}</string></{}>",
+        escape(placeholder),
+        String::from_utf8_lossy(tag.name().as_ref())
+    )
+    .into_bytes()
+}
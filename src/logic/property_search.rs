@@ -0,0 +1,451 @@
+use crate::logic::commit_events::CommitEvent;
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence::{self, VersionState};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::property_names::{self, LogStamp, NameIndexRecord, PropertyNameIndex};
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use tokio::sync::{Notify, broadcast};
+use tokio::time::{Duration, Instant};
+
+/// Matches returned per page at most
+pub const MAX_SEARCH_LIMIT: usize = 1000;
+
+/// Why a search cannot be answered
+pub enum SearchError {
+    /// `STREAM_DB_PROPERTY_NAME_INDEX` is not set
+    Disabled,
+    /// The index is still being loaded or built at startup
+    NotReady,
+    InvalidCursor(String),
+    Failed(String),
+}
+
+impl SearchError {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Disabled => {
+                "Property search is disabled, set STREAM_DB_PROPERTY_NAME_INDEX=true to enable it"
+                    .to_string()
+            }
+            Self::NotReady => "The property name index is still being built".to_string(),
+            Self::InvalidCursor(cursor) => format!("Invalid cursor {cursor:?}"),
+            Self::Failed(error) => error.clone(),
+        }
+    }
+}
+
+/// The index of property names across items, kept up to date in the background: commits
+/// and deletes only queue the version, so they never wait for it
+#[derive(Default)]
+pub struct PropertyNames {
+    /// `None` until it is loaded at startup
+    index: RwLock<Option<PropertyNameIndex>>,
+    /// Versions committed or deleted since they were last indexed
+    pending: Mutex<BTreeSet<(String, u64)>>,
+    /// Commit events were missed, only a rebuild brings the index up to date again
+    missed_commits: AtomicBool,
+    queued: Notify,
+    /// Held while the log is written, so rebuilds and updates do not interleave
+    maintenance: tokio::sync::Mutex<()>,
+    /// What the log looked like when a read-only instance last loaded it
+    loaded_stamp: Mutex<Option<LogStamp>>,
+}
+
+/// A committed version holding the searched property
+#[derive(Serialize, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct PropertyMatch {
+    pub item_id: String,
+    pub version: u64,
+}
+
+/// One page of `GET /search/properties`
+#[derive(Serialize)]
+pub struct PropertySearchPage {
+    pub name: String,
+    pub latest_only: bool,
+    pub matches: Vec<PropertyMatch>,
+    /// Pass as `cursor` for the next page, `None` on the last one
+    pub next_cursor: Option<String>,
+}
+
+/// A property name and how many committed versions hold it
+#[derive(Serialize)]
+pub struct PropertyNameCount {
+    pub name: String,
+    pub versions: usize,
+}
+
+/// Answer of `GET /search/property-names`
+#[derive(Serialize)]
+pub struct PropertyNamesPage {
+    pub prefix: String,
+    pub names: Vec<PropertyNameCount>,
+    /// More names start with the prefix than were returned
+    pub truncated: bool,
+}
+
+/// Summary of `POST /admin/rebuild-property-index`
+#[derive(Serialize)]
+pub struct RebuildReport {
+    pub items: usize,
+    pub versions: usize,
+    pub names: usize,
+    /// Versions whose property index could not be read, left out of the index
+    pub errors: Vec<String>,
+    pub duration_ms: u64,
+}
+
+/// Queue a version that was committed or deleted, it is indexed or dropped from the
+/// index in the background
+pub fn touch(state: &StreamDb, item_id: &str, item_version: u64) {
+    if !state.config.property_name_index || state.config.read_only {
+        return;
+    }
+    state
+        .property_names
+        .pending
+        .lock()
+        .unwrap()
+        .insert((item_id.to_string(), item_version));
+    state.property_names.queued.notify_one();
+}
+
+/// Load the index, building it if there is none yet, and keep it up to date: from the
+/// commits of this instance and of the data directory watch on the writing instance, by
+/// reloading it when the writing instance changed it on read-only ones
+pub fn start(state: AppState) {
+    if !state.config.property_name_index {
+        return;
+    }
+    // Subscribed before loading, so commits made meanwhile are not missed
+    let commits = state.commit_events.subscribe();
+
+    tokio::spawn(async move {
+        if state.config.read_only {
+            follow_writer(state).await;
+            return;
+        }
+
+        let loaded = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                if !property_names::exists(&state.storage) {
+                    return Ok(None);
+                }
+                let (mut index, torn) = property_names::load(&state.storage)?;
+                if torn {
+                    property_names::rewrite(&state.storage, &mut index)?;
+                }
+                Ok(Some(index))
+            })
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|loaded| loaded)
+        };
+        match loaded {
+            Ok(Some(index)) => {
+                println!(
+                    "Loaded the property name index: {} names in {} versions",
+                    index.name_count(),
+                    index.version_count()
+                );
+                *state.property_names.index.write().unwrap() = Some(index);
+            }
+            Ok(None) => {
+                println!("Building the property name index");
+                log_rebuild(rebuild(&state).await);
+            }
+            Err(error) => {
+                println!("Could not load the property name index, rebuilding it: {error}");
+                log_rebuild(rebuild(&state).await);
+            }
+        }
+
+        tokio::spawn(forward_commits(state.clone(), commits));
+        loop {
+            state.property_names.queued.notified().await;
+            if state
+                .property_names
+                .missed_commits
+                .swap(false, Ordering::AcqRel)
+            {
+                println!("Commits were missed by the property name index, rebuilding it");
+                log_rebuild(rebuild(&state).await);
+            }
+            let pending = std::mem::take(&mut *state.property_names.pending.lock().unwrap());
+            if pending.is_empty() {
+                continue;
+            }
+            let _maintenance = state.property_names.maintenance.lock().await;
+            let state = state.clone();
+            let updated = tokio::task::spawn_blocking(move || update(&state, pending)).await;
+            if let Ok(Err(error)) | Err(error) = updated.map_err(|error| error.to_string()) {
+                println!("Updating the property name index failed: {error}");
+            }
+        }
+    });
+}
+
+async fn forward_commits(state: AppState, mut commits: broadcast::Receiver<CommitEvent>) {
+    loop {
+        match commits.recv().await {
+            Ok(event) => touch(&state, &event.item_id, event.version),
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                state
+                    .property_names
+                    .missed_commits
+                    .store(true, Ordering::Release);
+                state.property_names.queued.notify_one();
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Read-only instances never write the log, they load it again whenever the writing
+/// instance changed it
+async fn follow_writer(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        state.config.replica_refresh_secs.max(1),
+    ));
+    loop {
+        interval.tick().await;
+        let storage = state.storage.clone();
+        let stamp = property_names::stamp(&storage);
+        if stamp.is_some() && *state.property_names.loaded_stamp.lock().unwrap() == stamp {
+            continue;
+        }
+        let loaded = tokio::task::spawn_blocking(move || property_names::load(&storage)).await;
+        match loaded
+            .map_err(|error| error.to_string())
+            .and_then(|loaded| loaded)
+        {
+            Ok((index, _)) => {
+                *state.property_names.index.write().unwrap() = Some(index);
+                *state.property_names.loaded_stamp.lock().unwrap() = stamp;
+            }
+            // A compaction renamed the log while it was read, it is read again next time
+            Err(error) => println!("Could not load the property name index: {error}"),
+        }
+    }
+}
+
+/// Index the names of every committed version from scratch and replace the log with it
+pub async fn rebuild(state: &AppState) -> Result<RebuildReport, SearchError> {
+    if !state.config.property_name_index {
+        return Err(SearchError::Disabled);
+    }
+    let _maintenance = state.property_names.maintenance.lock().await;
+    let started = Instant::now();
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut index = PropertyNameIndex::default();
+        let mut report = RebuildReport {
+            items: 0,
+            versions: 0,
+            names: 0,
+            errors: Vec::new(),
+            duration_ms: 0,
+        };
+        for item_id in cold_tier::item_ids(&state.storage).map_err(SearchError::Failed)? {
+            let metadata = file_persistence::load_item_metadata(&state.storage, &item_id)
+                .map_err(SearchError::Failed)?;
+            report.items += 1;
+            for committed in metadata.versions.values() {
+                match names_of(&state, &item_id, committed) {
+                    Ok(names) => index.apply(NameIndexRecord::Set {
+                        item_id: item_id.clone(),
+                        version: committed.version,
+                        names,
+                    }),
+                    Err(error) => report.errors.push(format!(
+                        "Item {item_id} version {}: {error}",
+                        committed.version
+                    )),
+                }
+            }
+        }
+        property_names::rewrite(&state.storage, &mut index).map_err(SearchError::Failed)?;
+        report.versions = index.version_count();
+        report.names = index.name_count();
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        *state.property_names.index.write().unwrap() = Some(index);
+        Ok(report)
+    })
+    .await
+    .map_err(|error| SearchError::Failed(error.to_string()))?
+}
+
+fn log_rebuild(rebuilt: Result<RebuildReport, SearchError>) {
+    match rebuilt {
+        Ok(report) => println!(
+            "Built the property name index in {}ms: {} names in {} versions of {} items, {} versions could not be read",
+            report.duration_ms,
+            report.names,
+            report.versions,
+            report.items,
+            report.errors.len()
+        ),
+        Err(error) => println!(
+            "Building the property name index failed: {}",
+            error.message()
+        ),
+    }
+}
+
+/// Bring the queued versions up to date with their metadata, whether they were committed
+/// or deleted since. Only versions that changed are appended to the log.
+fn update(state: &StreamDb, pending: BTreeSet<(String, u64)>) -> Result<(), String> {
+    let mut records = Vec::new();
+    {
+        let index = state.property_names.index.read().unwrap();
+        let Some(index) = index.as_ref() else {
+            return Ok(());
+        };
+        for (item_id, item_version) in pending {
+            let indexed = index.names_of(&item_id, item_version);
+            match file_persistence::version_state(&state.storage, &item_id, item_version)? {
+                VersionState::Committed(committed) => {
+                    let names = match names_of(state, &item_id, &committed) {
+                        Ok(names) => names,
+                        Err(error) => {
+                            println!(
+                                "Could not index the property names of item {item_id} version {item_version}: {error}"
+                            );
+                            continue;
+                        }
+                    };
+                    if indexed != Some(names.as_slice()) {
+                        records.push(NameIndexRecord::Set {
+                            item_id,
+                            version: item_version,
+                            names,
+                        });
+                    }
+                }
+                _ if indexed.is_some() => records.push(NameIndexRecord::Remove {
+                    item_id,
+                    version: item_version,
+                }),
+                _ => (),
+            }
+        }
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    property_names::append(&state.storage, &records)?;
+    let mut index = state.property_names.index.write().unwrap();
+    let Some(index) = index.as_mut() else {
+        return Ok(());
+    };
+    for record in records {
+        index.apply(record);
+    }
+    if index.needs_compaction() {
+        property_names::rewrite(&state.storage, index)?;
+    }
+    Ok(())
+}
+
+/// Distinct property names of a committed version, in order
+fn names_of(
+    state: &StreamDb,
+    item_id: &str,
+    committed: &VersionMetadata,
+) -> Result<Vec<String>, String> {
+    let property_index =
+        file_persistence::load_committed_property_index(&state.storage, item_id, committed)?;
+    let names: BTreeSet<String> = property_index
+        .into_iter()
+        .flat_map(|property_index| property_index.entries)
+        .filter_map(|entry| entry.name)
+        .collect();
+    Ok(names.into_iter().collect())
+}
+
+/// Committed versions holding a property called `name`, ordered by item and version.
+/// `cursor` is the `next_cursor` of the previous page.
+pub fn search_properties(
+    state: &StreamDb,
+    name: &str,
+    latest_only: bool,
+    limit: usize,
+    cursor: Option<&str>,
+) -> Result<PropertySearchPage, SearchError> {
+    if !state.config.property_name_index {
+        return Err(SearchError::Disabled);
+    }
+    let after = cursor.map(parse_cursor).transpose()?;
+    let index = state.property_names.index.read().unwrap();
+    let index = index.as_ref().ok_or(SearchError::NotReady)?;
+
+    let mut matches: Vec<PropertyMatch> = index
+        .versions_with(name, after.as_ref())
+        .filter(|(item_id, version)| {
+            !latest_only || index.latest_version(item_id) == Some(*version)
+        })
+        .take(limit + 1)
+        .map(|(item_id, version)| PropertyMatch {
+            item_id: item_id.clone(),
+            version: *version,
+        })
+        .collect();
+    let next_cursor = if matches.len() > limit {
+        matches.truncate(limit);
+        matches
+            .last()
+            .map(|last| format!("{}:{}", last.version, last.item_id))
+    } else {
+        None
+    };
+    Ok(PropertySearchPage {
+        name: name.to_string(),
+        latest_only,
+        matches,
+        next_cursor,
+    })
+}
+
+/// Property names starting with `prefix`, for autocompletion
+pub fn search_property_names(
+    state: &StreamDb,
+    prefix: &str,
+    limit: usize,
+) -> Result<PropertyNamesPage, SearchError> {
+    if !state.config.property_name_index {
+        return Err(SearchError::Disabled);
+    }
+    let index = state.property_names.index.read().unwrap();
+    let index = index.as_ref().ok_or(SearchError::NotReady)?;
+
+    let mut names: Vec<PropertyNameCount> = index
+        .names_with_prefix(prefix)
+        .take(limit + 1)
+        .map(|(name, versions)| PropertyNameCount {
+            name: name.to_string(),
+            versions,
+        })
+        .collect();
+    let truncated = names.len() > limit;
+    names.truncate(limit);
+    Ok(PropertyNamesPage {
+        prefix: prefix.to_string(),
+        names,
+        truncated,
+    })
+}
+
+/// Cursors are `{version}:{item_id}`, the version first since item IDs may hold colons
+fn parse_cursor(cursor: &str) -> Result<(String, u64), SearchError> {
+    cursor
+        .split_once(':')
+        .and_then(|(version, item_id)| Some((item_id.to_string(), version.parse().ok()?)))
+        .ok_or_else(|| SearchError::InvalidCursor(cursor.to_string()))
+}
@@ -0,0 +1,154 @@
+use crate::logic::property_alignment::PropertyBoundaryScanner;
+use crate::logic::property_element::property_start;
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+
+/// How far past the skipped properties we look for the next property start tag before
+/// assuming it exists
+const MAX_LOOKAHEAD_BYTES: usize = 64 * 1024;
+
+pub enum PropertySkip {
+    /// The requested property exists, `remainder` holds the bytes already read from
+    /// its start tag onwards, found `skipped` bytes after where the read started
+    Reached { remainder: Vec<u8>, skipped: u64 },
+    /// The stream ended before the requested property
+    EndOfStream { property_count: u64 },
+}
+
+/// Read through `reader` discarding everything up to property number `count` (0-based)
+/// without keeping more than the current chunk in memory.
+pub async fn skip_properties(
+    reader: &mut dyn ItemStreamReader,
+    count: u64,
+) -> Result<PropertySkip, String> {
+    let mut scanner = PropertyBoundaryScanner::new();
+    let mut properties_seen = 0u64;
+    let mut bytes_seen = 0u64;
+
+    let (mut remainder, mut skipped) = loop {
+        let Some(chunk) = reader.read_chunk().await? else {
+            return Ok(PropertySkip::EndOfStream {
+                property_count: properties_seen,
+            });
+        };
+        if count == 0 {
+            break (chunk, 0);
+        }
+
+        let boundaries = scanner.scan(&chunk);
+        let still_to_skip = (count - properties_seen) as usize;
+        if boundaries.len() >= still_to_skip {
+            let boundary = boundaries[still_to_skip - 1];
+            break (chunk[(boundary - bytes_seen) as usize..].to_vec(), boundary);
+        }
+        properties_seen += boundaries.len() as u64;
+        bytes_seen += chunk.len() as u64;
+    };
+
+    // Only whitespace or the closing envelope may follow the last property, so make sure
+    // the property we are about to start at actually exists. Like a seek through the
+    // property index, the stream then starts right at its start tag.
+    loop {
+        if let Some(start) = property_start(&remainder) {
+            remainder.drain(..start);
+            skipped += start as u64;
+            break;
+        }
+        if remainder.len() >= MAX_LOOKAHEAD_BYTES {
+            break;
+        }
+        match reader.read_chunk().await? {
+            Some(chunk) => remainder.extend_from_slice(&chunk),
+            None => {
+                return Ok(PropertySkip::EndOfStream {
+                    property_count: count,
+                });
+            }
+        }
+    }
+
+    Ok(PropertySkip::Reached { remainder, skipped })
+}
+
+/// Reader skipping to property `from_property` on its first read rather than up front,
+/// for versions still being written whose properties may be long in coming. A property
+/// that never arrives fails the stream, the status is out by then.
+pub struct DeferredSkipReader {
+    inner: Box<dyn ItemStreamReader>,
+    from_property: u64,
+    skipped: bool,
+}
+
+impl DeferredSkipReader {
+    pub fn new(inner: Box<dyn ItemStreamReader>, from_property: u64) -> Self {
+        Self {
+            inner,
+            from_property,
+            skipped: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for DeferredSkipReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if !self.skipped {
+            self.skipped = true;
+            match skip_properties(self.inner.as_mut(), self.from_property).await? {
+                PropertySkip::Reached { remainder, .. } if !remainder.is_empty() => {
+                    return Ok(Some(remainder));
+                }
+                PropertySkip::Reached { .. } => {}
+                PropertySkip::EndOfStream { property_count } => {
+                    return Err(format!(
+                        "Property {} is out of range, the item has {property_count} properties",
+                        self.from_property
+                    ));
+                }
+            }
+        }
+        self.inner.read_chunk().await
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+/// Reader that first yields bytes which were already taken from the inner reader
+pub struct PrefixedReader {
+    prefix: Option<Vec<u8>>,
+    inner: Box<dyn ItemStreamReader>,
+}
+
+impl PrefixedReader {
+    pub fn new(prefix: Vec<u8>, inner: Box<dyn ItemStreamReader>) -> Self {
+        Self {
+            prefix: (!prefix.is_empty()).then_some(prefix),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for PrefixedReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match self.prefix.take() {
+            Some(prefix) => Ok(Some(prefix)),
+            None => self.inner.read_chunk().await,
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
@@ -0,0 +1,248 @@
+use crate::logic::item_envelope::ItemEnvelope;
+use crate::logic::property_alignment::PropertySplitter;
+use crate::logic::property_element::{property_name, property_start};
+use crate::persistence::item_persistence::ItemStreamReader;
+use crate::persistence::transforms::TransformSpec;
+
+use async_trait::async_trait;
+use quick_xml::Reader;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use std::collections::{HashMap, HashSet};
+
+/// Why a transform could not be changed
+pub enum TransformError {
+    Invalid(String),
+    NotFound(String),
+    Failed(String),
+}
+
+/// A [`TransformSpec`] prepared for lookups while an item streams through it
+pub struct PropertyTransform {
+    rename: HashMap<String, String>,
+    drop: HashSet<String>,
+    allow: Option<HashSet<String>>,
+    envelope: Option<String>,
+}
+
+impl From<TransformSpec> for PropertyTransform {
+    fn from(spec: TransformSpec) -> Self {
+        Self {
+            rename: spec.rename.into_iter().collect(),
+            drop: spec.drop.into_iter().collect(),
+            allow: spec.allow.map(|allow| allow.into_iter().collect()),
+            envelope: spec.envelope,
+        }
+    }
+}
+
+impl PropertyTransform {
+    fn keeps(&self, name: Option<&str>) -> bool {
+        match (&self.allow, name) {
+            (Some(allow), Some(name)) => allow.contains(name),
+            (Some(_), None) => false,
+            (None, Some(name)) => !self.drop.contains(name),
+            (None, None) => true,
+        }
+    }
+}
+
+/// Reader wrapper applying a transform to every complete property as it streams by.
+/// Only the property currently being read is held in memory, so committed and in-flight
+/// versions go through it alike. Bytes between properties are passed on, except for the
+/// stored `<item>` envelope when the transform brings its own. That one encloses the
+/// body's own root element, so only an XML declaration is sent in front of it.
+pub struct TransformingReader {
+    inner: Box<dyn ItemStreamReader>,
+    transform: PropertyTransform,
+    splitter: PropertySplitter,
+    inner_finished: bool,
+    envelope_opened: bool,
+}
+
+impl TransformingReader {
+    pub fn new(
+        inner: Box<dyn ItemStreamReader>,
+        transform: PropertyTransform,
+        max_property_bytes: usize,
+    ) -> Self {
+        Self {
+            inner,
+            transform,
+            splitter: PropertySplitter::new(max_property_bytes),
+            inner_finished: false,
+            envelope_opened: false,
+        }
+    }
+
+    /// Bytes found between properties, without the stored envelope if it is replaced
+    fn between_properties(&self, bytes: &[u8]) -> Vec<u8> {
+        if self.transform.envelope.is_some() {
+            strip_item_envelope(bytes)
+        } else {
+            bytes.to_vec()
+        }
+    }
+
+    /// Appends `bytes` found between properties, opening the transform's envelope in
+    /// front of them unless that already happened
+    fn pass_on(&mut self, bytes: &[u8], output: &mut Vec<u8>) {
+        let Some(envelope) = self
+            .transform
+            .envelope
+            .as_ref()
+            .filter(|_| !self.envelope_opened)
+        else {
+            output.extend_from_slice(bytes);
+            return;
+        };
+        let declaration_end = declaration_end(bytes);
+        output.extend_from_slice(&bytes[..declaration_end]);
+        output.extend_from_slice(format!("<{envelope}>").as_bytes());
+        output.extend_from_slice(&bytes[declaration_end..]);
+        self.envelope_opened = true;
+    }
+
+    fn transform_segment(&mut self, segment: &[u8], output: &mut Vec<u8>) {
+        let start = property_start(segment).unwrap_or(segment.len());
+        let prefix = self.between_properties(&segment[..start]);
+        let element = &segment[start..];
+        let element_text = String::from_utf8_lossy(element);
+        let name = property_name(&element_text);
+
+        if !self.transform.keeps(name.as_deref()) {
+            // Whitespace around a dropped property would only pile up
+            if !prefix.iter().all(u8::is_ascii_whitespace) {
+                self.pass_on(&prefix, output);
+            }
+            return;
+        }
+        self.pass_on(&prefix, output);
+        match name.and_then(|name| self.transform.rename.get(&name)) {
+            Some(new_name) => output.extend_from_slice(&rename_property(&element_text, new_name)),
+            None => output.extend_from_slice(element),
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for TransformingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    let segments = self
+                        .splitter
+                        .push(&chunk)
+                        .map_err(|error| format!("{error} for transforms"))?;
+                    let mut output = Vec::new();
+                    for segment in segments {
+                        self.transform_segment(&segment, &mut output);
+                    }
+                    if !output.is_empty() {
+                        return Ok(Some(output));
+                    }
+                }
+                None => {
+                    // Only a version read to its end gets the closing tag, a read cut short
+                    // ends in an error before getting here
+                    self.inner_finished = true;
+                    let mut output = Vec::new();
+                    let remainder = self.between_properties(self.splitter.remainder());
+                    self.pass_on(&remainder, &mut output);
+                    if let Some(envelope) = &self.transform.envelope {
+                        output.extend_from_slice(format!("</{envelope}>").as_bytes());
+                    }
+                    return Ok((!output.is_empty()).then_some(output));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+/// `element` with its `for` and `name` attributes set to `new_name`. Only the start tag
+/// is rebuilt, the content is passed on as stored.
+fn rename_property(element: &str, new_name: &str) -> Vec<u8> {
+    let mut reader = Reader::from_str(element);
+    let (tag, empty) = match reader.read_event() {
+        Ok(Event::Start(tag)) => (tag, false),
+        Ok(Event::Empty(tag)) => (tag, true),
+        _ => return element.as_bytes().to_vec(),
+    };
+    let content = &element[reader.buffer_position() as usize..];
+
+    let mut renamed = format!("<{}", String::from_utf8_lossy(tag.name().as_ref()));
+    for attribute in tag.attributes().flatten() {
+        let key = String::from_utf8_lossy(attribute.key.as_ref()).into_owned();
+        let value = if matches!(key.as_str(), "for" | "name") {
+            new_name.to_string()
+        } else {
+            match attribute.unescape_value() {
+                Ok(value) => value.into_owned(),
+                Err(_) => String::from_utf8_lossy(&attribute.value).into_owned(),
+            }
+        };
+        renamed.push_str(&format!(r#" {key}="{}""#, escape(&value)));
+    }
+    renamed.push_str(if empty { "/>" } else { ">" });
+    renamed.push_str(content);
+    renamed.into_bytes()
+}
+
+/// Length of the XML declaration `bytes` start with, after a byte order mark if there is
+/// one, or 0 when they start with none
+fn declaration_end(bytes: &[u8]) -> usize {
+    let start = if bytes.starts_with(b"\xEF\xBB\xBF") {
+        3
+    } else {
+        0
+    };
+    if !bytes[start..].starts_with(b"<?xml") {
+        return 0;
+    }
+    bytes[start..]
+        .windows(2)
+        .position(|pair| pair == b"?>")
+        .map_or(0, |end| start + end + 2)
+}
+
+/// `bytes` without the `<item ...>` and `</item>` tags of the stored envelope
+fn strip_item_envelope(bytes: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    while let Some(start) = rest.iter().position(|byte| *byte == b'<') {
+        output.extend_from_slice(&rest[..start]);
+        rest = &rest[start..];
+        let is_opening_tag = rest.starts_with(b"<item")
+            && rest
+                .get(5)
+                .is_some_and(|byte| byte.is_ascii_whitespace() || *byte == b'>');
+        let tag_length = if rest.starts_with(ItemEnvelope::CLOSING_TAG) {
+            Some(ItemEnvelope::CLOSING_TAG.len())
+        } else if is_opening_tag {
+            rest.iter()
+                .position(|byte| *byte == b'>')
+                .map(|end| end + 1)
+        } else {
+            None
+        };
+        match tag_length {
+            Some(length) => rest = &rest[length..],
+            None => {
+                output.push(b'<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.extend_from_slice(rest);
+    output
+}
@@ -0,0 +1,281 @@
+use crate::logic::property_element::{property_name, property_start};
+
+use quick_xml::Reader;
+use quick_xml::escape::unescape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::QName;
+use serde::Serialize;
+
+/// Longest value snippet quoted back in a violation
+const MAX_SNIPPET_CHARS: usize = 64;
+/// Violations listed in a rejection, the rest are only counted
+const MAX_LISTED_VIOLATIONS: usize = 100;
+
+/// Value types a property can declare through its `type` attribute:
+///
+/// ```xml
+/// <property name="count" type="int">42</property>
+/// ```
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PropertyType {
+    Int,
+    Float,
+    Bool,
+    Iso8601,
+    String,
+}
+
+impl PropertyType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(Self::Int),
+            "float" => Some(Self::Float),
+            "bool" => Some(Self::Bool),
+            "iso8601" => Some(Self::Iso8601),
+            "string" => Some(Self::String),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Bool => "bool",
+            Self::Iso8601 => "iso8601",
+            Self::String => "string",
+        }
+    }
+
+    /// Whether `value`, the unescaped text of a property, is a valid value of this type.
+    /// Surrounding whitespace is ignored for everything but strings.
+    pub fn accepts(&self, value: &str) -> bool {
+        let value = value.trim();
+        match self {
+            Self::Int => value.parse::<i64>().is_ok(),
+            // JSON has no representation for infinities and NaN
+            Self::Float => value.parse::<f64>().is_ok_and(f64::is_finite),
+            Self::Bool => matches!(value, "true" | "false"),
+            Self::Iso8601 => {
+                chrono::DateTime::parse_from_rfc3339(value).is_ok()
+                    || chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").is_ok()
+                    || chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+            }
+            Self::String => true,
+        }
+    }
+}
+
+/// A property whose declared type does not fit its value
+#[derive(Serialize, Clone)]
+pub struct TypeViolation {
+    pub property: Option<String>,
+    pub declared_type: String,
+    /// The start of the offending value
+    pub value: String,
+    pub reason: String,
+}
+
+impl TypeViolation {
+    fn new(property: Option<String>, declared_type: &str, value: &str, reason: &str) -> Self {
+        let mut snippet: String = value.chars().take(MAX_SNIPPET_CHARS).collect();
+        if snippet.len() < value.len() {
+            snippet.push('…');
+        }
+        Self {
+            property,
+            declared_type: declared_type.to_string(),
+            value: snippet,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Every type violation of an upload, of which only the first ones are listed
+#[derive(Serialize, Default, Clone)]
+pub struct TypeViolations {
+    pub count: u64,
+    pub violations: Vec<TypeViolation>,
+}
+
+impl TypeViolations {
+    pub fn push(&mut self, violation: TypeViolation) {
+        self.count += 1;
+        if self.violations.len() < MAX_LISTED_VIOLATIONS {
+            self.violations.push(violation);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// Check the value of a complete property element against its declared type, returning
+/// the type of a valid property and `None` for properties that declare none
+pub fn check_property_type(element: &str) -> Result<Option<PropertyType>, TypeViolation> {
+    let element = &element[property_start(element.as_bytes()).unwrap_or(0)..];
+    let mut reader = Reader::from_str(element);
+    let (declared_type, is_empty) = match reader.read_event() {
+        Ok(Event::Start(tag)) => (type_attribute(&tag), false),
+        Ok(Event::Empty(tag)) => (type_attribute(&tag), true),
+        _ => return Ok(None),
+    };
+    let Some(declared_type) = declared_type else {
+        return Ok(None);
+    };
+
+    let name = property_name(element);
+    let Some(property_type) = PropertyType::parse(&declared_type) else {
+        return Err(TypeViolation::new(
+            name,
+            &declared_type,
+            "",
+            "unknown type, expected int, float, bool, iso8601 or string",
+        ));
+    };
+
+    let value = if is_empty {
+        String::new()
+    } else {
+        let raw = match reader.read_text(QName(b"property")) {
+            Ok(raw) => raw.into_owned(),
+            Err(_) => {
+                return Err(TypeViolation::new(
+                    name,
+                    &declared_type,
+                    element,
+                    "property is not a complete element",
+                ));
+            }
+        };
+        unescape(&raw)
+            .map(|value| value.into_owned())
+            .unwrap_or(raw)
+    };
+
+    if !property_type.accepts(&value) {
+        return Err(TypeViolation::new(
+            name,
+            &declared_type,
+            &value,
+            "value does not match the declared type",
+        ));
+    }
+    Ok(Some(property_type))
+}
+
+fn type_attribute(tag: &BytesStart) -> Option<String> {
+    tag.attributes()
+        .flatten()
+        .find(|attribute| attribute.key.as_ref() == b"type")
+        .and_then(|attribute| {
+            attribute
+                .unescape_value()
+                .ok()
+                .map(|value| value.into_owned())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn property(declared_type: &str, value: &str) -> String {
+        format!(r#"<property name="p" type="{declared_type}">{value}</property>"#)
+    }
+
+    #[test]
+    fn every_type_accepts_its_values_and_rejects_others() {
+        let cases = [
+            (
+                "int",
+                &["42", "-7", " 0 "][..],
+                &["4.2", "42x", "", "99999999999999999999"][..],
+            ),
+            ("float", &["4.2", "-1e3", "7"], &["NaN", "inf", "1,5", ""]),
+            (
+                "bool",
+                &["true", "false", " true\n"],
+                &["True", "1", "yes", ""],
+            ),
+            (
+                "iso8601",
+                &[
+                    "2024-01-15T10:30:00Z",
+                    "2024-01-15T10:30:00.5+02:00",
+                    "2024-01-15T10:30:00",
+                    "2024-01-15",
+                ],
+                &["2024-13-01", "15.01.2024", "10:30", ""],
+            ),
+            ("string", &["anything", "", " 42 "], &[]),
+        ];
+        for (declared_type, valid, invalid) in cases {
+            for value in valid {
+                let checked = check_property_type(&property(declared_type, value));
+                assert!(
+                    matches!(checked, Ok(Some(property_type)) if property_type.name() == declared_type),
+                    "{declared_type} {value:?}"
+                );
+            }
+            for value in invalid {
+                let Err(violation) = check_property_type(&property(declared_type, value)) else {
+                    panic!("{declared_type} accepted {value:?}");
+                };
+                assert_eq!(violation.property.as_deref(), Some("p"));
+                assert_eq!(violation.declared_type, declared_type);
+                assert_eq!(violation.value, value.trim_matches(' '));
+            }
+        }
+    }
+
+    #[test]
+    fn values_are_unescaped_and_untyped_properties_pass() {
+        assert!(matches!(
+            check_property_type(&property("int", "&#52;2")),
+            Ok(Some(PropertyType::Int))
+        ));
+        assert!(matches!(
+            check_property_type(r#"<property name="p">anything</property>"#),
+            Ok(None)
+        ));
+        assert!(check_property_type(r#"<property name="p" type="bool"/>"#).is_err());
+        assert!(matches!(
+            check_property_type(r#"<property name="p" type="string"/>"#),
+            Ok(Some(PropertyType::String))
+        ));
+    }
+
+    #[test]
+    fn an_unknown_type_is_a_violation() {
+        let Err(violation) = check_property_type(&property("integer", "42")) else {
+            panic!("an unknown type was accepted");
+        };
+        assert_eq!(violation.declared_type, "integer");
+        assert!(
+            violation.reason.contains("unknown type"),
+            "{}",
+            violation.reason
+        );
+    }
+
+    #[test]
+    fn long_values_are_cut_short_in_violations() {
+        let value = "x".repeat(1000);
+        let Err(violation) = check_property_type(&property("int", &value)) else {
+            panic!("a long value was accepted");
+        };
+        assert_eq!(
+            violation.value,
+            format!("{}…", "x".repeat(MAX_SNIPPET_CHARS))
+        );
+
+        let mut violations = TypeViolations::default();
+        for _ in 0..MAX_LISTED_VIOLATIONS + 5 {
+            violations.push(violation.clone());
+        }
+        assert_eq!(violations.count, MAX_LISTED_VIOLATIONS as u64 + 5);
+        assert_eq!(violations.violations.len(), MAX_LISTED_VIOLATIONS);
+    }
+}
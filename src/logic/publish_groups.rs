@@ -0,0 +1,103 @@
+use crate::logic::item_stream_logic::announce_commit;
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::publish_groups::{
+    self, PublishGroup, PublishGroupError, PublishGroupStatus,
+};
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long a group stays open when its creator does not say
+pub const DEFAULT_TTL_SECS: u64 = 3600;
+
+/// The versions a group committed, by item ID
+#[derive(Serialize)]
+pub struct PublishGroupCommit {
+    pub id: String,
+    pub versions: Vec<PublishedVersion>,
+}
+
+/// Receipt of a version a group committed
+#[derive(Serialize)]
+pub struct PublishedVersion {
+    pub item_id: String,
+    #[serde(flatten)]
+    pub committed: VersionMetadata,
+}
+
+/// Open a group that uploads sent with `X-Publish-Group` are staged in until it is
+/// committed, or aborted once `ttl` passed
+pub fn create(state: &StreamDb, ttl: Duration) -> Result<PublishGroup, String> {
+    let group = publish_groups::create(&state.storage, ttl)?;
+    println!(
+        "Opened publish group {} until {}",
+        group.id, group.expires_at
+    );
+    Ok(group)
+}
+
+pub fn status(state: &StreamDb, group_id: &str) -> Result<PublishGroupStatus, PublishGroupError> {
+    publish_groups::status(&state.storage, group_id)
+}
+
+/// Commit every version staged in the group at once, then announce them like any other
+/// commit, in the order of their item IDs
+pub async fn commit(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupCommit, PublishGroupError> {
+    let storage = state.storage.clone();
+    let id = group_id.to_string();
+    let promoted = tokio::task::spawn_blocking(move || publish_groups::promote(&storage, &id))
+        .await
+        .map_err(|error| PublishGroupError::Failed(error.to_string()))??;
+    println!(
+        "Committed publish group {group_id}, {} versions",
+        promoted.len()
+    );
+    for (item_id, committed) in &promoted {
+        announce_commit(state, item_id, committed);
+    }
+    Ok(PublishGroupCommit {
+        id: group_id.to_string(),
+        versions: promoted
+            .into_iter()
+            .map(|(item_id, committed)| PublishedVersion { item_id, committed })
+            .collect(),
+    })
+}
+
+/// Discard the group and the data of every version staged in it
+pub async fn abort(
+    state: &AppState,
+    group_id: &str,
+) -> Result<PublishGroupStatus, PublishGroupError> {
+    let storage = state.storage.clone();
+    let id = group_id.to_string();
+    let aborted = tokio::task::spawn_blocking(move || publish_groups::abort(&storage, &id))
+        .await
+        .map_err(|error| PublishGroupError::Failed(error.to_string()))??;
+    println!(
+        "Aborted publish group {group_id}, discarded {} staged versions",
+        aborted.staged.len()
+    );
+    Ok(aborted)
+}
+
+/// Abort the groups whose time ran out, run by the periodic housekeeping
+pub fn abort_expired(state: &StreamDb) {
+    match publish_groups::abort_expired(&state.storage) {
+        Ok(aborted) => {
+            for group in aborted {
+                println!(
+                    "Publish group {} expired at {}, discarded {} staged versions",
+                    group.group.id,
+                    group.group.expires_at,
+                    group.staged.len()
+                );
+            }
+        }
+        Err(error) => println!("Could not abort expired publish groups: {error}"),
+    }
+}
@@ -0,0 +1,99 @@
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::mpsc;
+
+/// What the reader behind the queue ran into once it failed, kept for after the error
+/// was taken out of the queue
+#[derive(Default)]
+struct Failure {
+    aborted: AtomicBool,
+    too_slow: AtomicBool,
+}
+
+/// Reader wrapper reading ahead of its consumer in a task of its own, so the next chunk
+/// is read from disk while the previous one is still being sent. Chunks and the error
+/// ending the read come out in the order they were read. The task waits for an upload
+/// like any reader does, holds on to one more chunk once `depth` are queued until the
+/// consumer takes one, and stops once the consumer is dropped.
+pub struct PrefetchingReader {
+    chunks: mpsc::Receiver<Result<Option<Vec<u8>>, String>>,
+    /// Bytes in the queue, reported with the version in `/admin/streams`
+    queued: Arc<AtomicU64>,
+    failure: Arc<Failure>,
+    finished: bool,
+}
+
+impl PrefetchingReader {
+    pub fn new(mut inner: Box<dyn ItemStreamReader>, depth: usize, queued: Arc<AtomicU64>) -> Self {
+        let (sender, chunks) = mpsc::channel(depth);
+        let failure = Arc::new(Failure::default());
+        let task_queued = queued.clone();
+        let task_failure = failure.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = tokio::select! {
+                    // A reader waiting for an upload would otherwise only notice the
+                    // consumer is gone once the next chunk arrives
+                    _ = sender.closed() => return,
+                    next = inner.read_chunk() => next,
+                };
+                let last = !matches!(next, Ok(Some(_)));
+                match &next {
+                    Ok(Some(chunk)) => {
+                        task_queued.fetch_add(chunk.len() as u64, Ordering::AcqRel);
+                    }
+                    Ok(None) => {}
+                    Err(_) => {
+                        task_failure
+                            .aborted
+                            .store(inner.is_aborted(), Ordering::Release);
+                        task_failure
+                            .too_slow
+                            .store(inner.is_too_slow(), Ordering::Release);
+                    }
+                }
+                if sender.send(next).await.is_err() || last {
+                    return;
+                }
+            }
+        });
+        Self {
+            chunks,
+            queued,
+            failure,
+            finished: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for PrefetchingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.finished {
+            return Ok(None);
+        }
+        match self.chunks.recv().await {
+            Some(Ok(Some(chunk))) => {
+                self.queued.fetch_sub(chunk.len() as u64, Ordering::AcqRel);
+                Ok(Some(chunk))
+            }
+            Some(Ok(None)) => {
+                self.finished = true;
+                Ok(None)
+            }
+            Some(Err(error)) => Err(error),
+            None => Err("The prefetching read stopped unexpectedly".to_string()),
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.failure.aborted.load(Ordering::Acquire)
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.failure.too_slow.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,114 @@
+use crate::persistence::item_stats::{self, ItemStats, VersionStats};
+use crate::state::{AppState, StreamDb};
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+/// Independent locks the pending counters are spread over, so readers of different
+/// items rarely contend
+const SHARDS: usize = 16;
+
+type PendingStats = HashMap<(String, u64), VersionStats>;
+
+/// Read counters collected since the last flush to the stats sidecars
+pub struct ReadStats {
+    shards: Vec<Mutex<PendingStats>>,
+}
+
+impl Default for ReadStats {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl ReadStats {
+    fn shard(&self, item_id: &str) -> &Mutex<PendingStats> {
+        let mut hasher = DefaultHasher::new();
+        item_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// Apply what one read did, called once per read rather than per chunk
+    pub fn record(&self, item_id: &str, item_version: u64, read: &VersionStats) {
+        self.shard(item_id)
+            .lock()
+            .unwrap()
+            .entry((item_id.to_string(), item_version))
+            .or_default()
+            .merge(read);
+    }
+
+    /// Pending counters of one item, not yet flushed
+    fn pending(&self, item_id: &str) -> ItemStats {
+        let mut stats = ItemStats::default();
+        for ((pending_item_id, version), pending) in self.shard(item_id).lock().unwrap().iter() {
+            if pending_item_id == item_id {
+                stats.versions.insert(*version, pending.clone());
+            }
+        }
+        stats
+    }
+
+    fn take_all(&self) -> PendingStats {
+        let mut all = HashMap::new();
+        for shard in &self.shards {
+            all.extend(std::mem::take(&mut *shard.lock().unwrap()));
+        }
+        all
+    }
+}
+
+/// Timestamp recorded as `last_accessed`
+pub fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Everything known about the reads of an item: the flushed counters plus the pending
+/// ones
+pub fn item_stats(state: &StreamDb, item_id: &str) -> Result<ItemStats, String> {
+    let mut stats = item_stats::load(&state.storage, item_id)?;
+    for (version, pending) in state.read_stats.pending(item_id).versions {
+        stats.versions.entry(version).or_default().merge(&pending);
+    }
+    Ok(stats)
+}
+
+/// Add the pending counters to the stats sidecars. Counters that cannot be written are
+/// put back to be retried on the next flush.
+pub fn flush(state: &StreamDb) {
+    let mut by_item: HashMap<String, Vec<(u64, VersionStats)>> = HashMap::new();
+    for ((item_id, version), pending) in state.read_stats.take_all() {
+        by_item.entry(item_id).or_default().push((version, pending));
+    }
+
+    for (item_id, pending) in by_item {
+        let flushed = item_stats::load(&state.storage, &item_id).and_then(|mut stats| {
+            for (version, read) in &pending {
+                stats.versions.entry(*version).or_default().merge(read);
+            }
+            item_stats::store(&state.storage, &item_id, &stats)
+        });
+        if let Err(error) = flushed {
+            println!("Could not flush read stats of item {item_id}: {error}");
+            for (version, read) in &pending {
+                state.read_stats.record(&item_id, *version, read);
+            }
+        }
+    }
+}
+
+/// Periodically flush the read counters
+pub fn start(state: AppState) {
+    let period = Duration::from_secs(state.config.stats_flush_secs.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            flush(&state);
+        }
+    });
+}
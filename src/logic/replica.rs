@@ -0,0 +1,43 @@
+use crate::persistence::file_persistence;
+use crate::state::AppState;
+
+use tokio::time::Duration;
+
+/// Periodically catch up with what the writing instance changed in the shared data
+/// directory, only on read-only instances. New commits need no refresh, they are found
+/// in the metadata as soon as a reader asks for them.
+pub fn start(state: AppState) {
+    if !state.config.read_only {
+        return;
+    }
+    let period = Duration::from_secs(state.config.replica_refresh_secs.max(1));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let storage = state.storage.clone();
+            let report = match tokio::task::spawn_blocking(move || {
+                file_persistence::refresh_registry(&storage)
+            })
+            .await
+            {
+                Ok(Ok(report)) => report,
+                Ok(Err(error)) => {
+                    println!("Replica refresh failed: {error}");
+                    continue;
+                }
+                Err(error) => {
+                    println!("Replica refresh failed: {error}");
+                    continue;
+                }
+            };
+            if report.replaced > 0 || report.moved > 0 || report.quarantined > 0 {
+                println!(
+                    "Replica refresh dropped {} replaced, {} moved and {} quarantined versions",
+                    report.replaced, report.moved, report.quarantined
+                );
+            }
+        }
+    });
+}
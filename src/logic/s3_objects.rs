@@ -0,0 +1,207 @@
+use crate::logic::item_ids;
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence::{self, WriteError};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::{AppState, StreamDb};
+
+/// Separates the bucket from the key in the ID of an item written through the S3 API,
+/// bucket names never contain it
+const BUCKET_SEPARATOR: char = ':';
+
+/// Most keys a listing returns at once, as S3 does
+pub const MAX_LIST_KEYS: usize = 1000;
+
+/// S3 bucket names are 3 to 63 lowercase letters, digits, dots and dashes, starting and
+/// ending with a letter or digit
+pub fn validate_bucket(bucket: &str) -> Result<(), String> {
+    let valid = (3..=63).contains(&bucket.len())
+        && bucket.chars().all(|character| {
+            character.is_ascii_lowercase()
+                || character.is_ascii_digit()
+                || character == '.'
+                || character == '-'
+        })
+        && !bucket.starts_with(['.', '-'])
+        && !bucket.ends_with(['.', '-']);
+    if !valid {
+        return Err(format!(
+            "Bucket name {bucket:?} must be 3 to 63 lowercase letters, digits, dots and dashes"
+        ));
+    }
+    Ok(())
+}
+
+/// The item an object is stored as, `{bucket}:{key}`. Bytes of the key that may not
+/// appear in item IDs, `/` among them, are percent-encoded, and so is `%` itself.
+pub fn item_id(bucket: &str, key: &str) -> Result<String, String> {
+    validate_bucket(bucket)?;
+    if key.is_empty() {
+        return Err("Object key must not be empty".to_string());
+    }
+    let mut item_id = format!("{bucket}{BUCKET_SEPARATOR}");
+    for character in key.chars() {
+        match character {
+            '%' | '/' | '\\' => item_id.push_str(&format!("%{:02X}", character as u32)),
+            character if character.is_control() => {
+                let mut bytes = [0; 4];
+                for byte in character.encode_utf8(&mut bytes).bytes() {
+                    item_id.push_str(&format!("%{byte:02X}"));
+                }
+            }
+            character => item_id.push(character),
+        }
+    }
+    item_ids::validate_item_id(&item_id)?;
+    Ok(item_id)
+}
+
+/// The key of the object stored as `item_id` in `bucket`, `None` for items of other
+/// buckets and items not written through the S3 API
+pub fn object_key(bucket: &str, item_id: &str) -> Option<String> {
+    let encoded = item_id
+        .strip_prefix(bucket)?
+        .strip_prefix(BUCKET_SEPARATOR)?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The version an object is served from: the item's latest committed version, unless it
+/// was deleted through the S3 API
+pub fn latest(state: &StreamDb, item_id: &str) -> Result<Option<VersionMetadata>, String> {
+    let metadata = file_persistence::load_item_metadata(&state.storage, item_id)?;
+    Ok(metadata
+        .versions
+        .into_values()
+        .next_back()
+        .filter(|version| version.s3_deleted_at.is_none()))
+}
+
+/// The version an upload of the object is written as, one past the item's latest
+pub fn next_version(state: &StreamDb, item_id: &str) -> Result<u64, String> {
+    let metadata = file_persistence::load_item_metadata(&state.storage, item_id)?;
+    Ok(metadata.latest_version.unwrap_or(0) + 1)
+}
+
+/// Mark the latest version of the object as deleted, the data is kept and the other
+/// endpoints go on serving it. Returns whether there was an object to delete.
+pub async fn delete(state: &AppState, item_id: &str) -> Result<bool, WriteError> {
+    let state = state.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || {
+        let Some(latest) = latest(&state, &item_id).map_err(WriteError::Failed)? else {
+            return Ok(false);
+        };
+        file_persistence::mark_s3_deleted(
+            &state.storage,
+            &item_id,
+            latest.version,
+            latest.epoch.unwrap_or(0),
+        )
+    })
+    .await
+    .map_err(|error| WriteError::Failed(error.to_string()))?
+}
+
+/// What `ListObjectsV2` asks for
+pub struct ListRequest {
+    pub prefix: String,
+    /// Groups the keys containing it after `prefix` under their common prefix
+    pub delimiter: Option<String>,
+    /// Only keys after this one are listed
+    pub start_after: Option<String>,
+    pub max_keys: usize,
+}
+
+pub struct ListedObject {
+    pub key: String,
+    pub version: VersionMetadata,
+}
+
+#[derive(Default)]
+pub struct ObjectListing {
+    pub objects: Vec<ListedObject>,
+    /// Prefixes up to and including the delimiter, each listed once
+    pub common_prefixes: Vec<String>,
+    /// The last key or common prefix listed when more follow
+    pub next_start_after: Option<String>,
+}
+
+/// The objects of `bucket` in key order, read from the metadata of every item of the
+/// bucket, so listing takes as long as the data directory is large
+pub async fn list(
+    state: &AppState,
+    bucket: &str,
+    request: ListRequest,
+) -> Result<ObjectListing, String> {
+    let state = state.clone();
+    let bucket = bucket.to_string();
+    tokio::task::spawn_blocking(move || list_blocking(&state, &bucket, &request))
+        .await
+        .map_err(|error| error.to_string())?
+}
+
+fn list_blocking(
+    state: &StreamDb,
+    bucket: &str,
+    request: &ListRequest,
+) -> Result<ObjectListing, String> {
+    let delimiter = request
+        .delimiter
+        .as_deref()
+        .filter(|delimiter| !delimiter.is_empty());
+    let start_after = request.start_after.as_deref().unwrap_or_default();
+    let mut keys: Vec<(String, String)> = cold_tier::item_ids(&state.storage)?
+        .into_iter()
+        .filter_map(|item_id| Some((object_key(bucket, &item_id)?, item_id)))
+        .filter(|(key, _)| key.starts_with(&request.prefix) && key.as_str() > start_after)
+        .collect();
+    // Item IDs sort by the encoded keys, listings by the keys themselves
+    keys.sort();
+
+    let mut listing = ObjectListing::default();
+    for (key, item_id) in keys {
+        let common_prefix = delimiter.and_then(|delimiter| {
+            let after_prefix = &key[request.prefix.len()..];
+            after_prefix
+                .find(delimiter)
+                .map(|end| key[..request.prefix.len() + end + delimiter.len()].to_string())
+        });
+        // A page may end on a common prefix, whose keys all sort after it
+        if let Some(common_prefix) = &common_prefix
+            && (listing.common_prefixes.last() == Some(common_prefix)
+                || common_prefix.as_str() == start_after)
+        {
+            continue;
+        }
+        if listing.objects.len() + listing.common_prefixes.len() >= request.max_keys {
+            listing.next_start_after = listing
+                .objects
+                .last()
+                .map(|object| object.key.clone())
+                .into_iter()
+                .chain(listing.common_prefixes.last().cloned())
+                .max();
+            break;
+        }
+        // Deleted objects are left out, and so are prefixes only they have
+        let Some(version) = latest(state, &item_id)? else {
+            continue;
+        };
+        match common_prefix {
+            Some(common_prefix) => listing.common_prefixes.push(common_prefix),
+            None => listing.objects.push(ListedObject { key, version }),
+        }
+    }
+    Ok(listing)
+}
@@ -0,0 +1,214 @@
+use crate::logic::item_envelope::ItemEnvelope;
+use crate::logic::property_alignment::PropertySplitter;
+use crate::logic::property_element::{property_name, property_start};
+use crate::logic::property_types::PropertyType;
+use crate::metrics::Metrics;
+use crate::persistence::item_persistence::ItemStreamReader;
+use crate::persistence::item_settings::ItemSchema;
+use crate::persistence::property_index::PropertyIndex;
+
+use async_trait::async_trait;
+use quick_xml::escape::escape;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Check a schema before it is stored: names are unique and not empty, types are known
+/// and defaults are valid values of their property's type
+pub fn validate(schema: &ItemSchema) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for property in &schema.properties {
+        if property.name.trim().is_empty() {
+            return Err("Schema property names must not be empty".to_string());
+        }
+        if !names.insert(property.name.as_str()) {
+            return Err(format!(
+                "Schema property {:?} is declared twice",
+                property.name
+            ));
+        }
+        let value_type = match property.value_type.as_deref() {
+            Some(name) => Some(PropertyType::parse(name).ok_or_else(|| {
+                format!(
+                    "Schema property {:?} has the unknown type {name:?}, expected int, float, bool, iso8601 or string",
+                    property.name
+                )
+            })?),
+            None => None,
+        };
+        if let (Some(value_type), Some(default)) = (value_type, &property.default)
+            && !value_type.accepts(default)
+        {
+            return Err(format!(
+                "Default {default:?} of schema property {:?} is no valid {}",
+                property.name,
+                value_type.name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The declared defaults of a schema, in declaration order
+#[derive(Clone)]
+pub struct SchemaDefaults {
+    version: u64,
+    /// Name, type and value of every property declaring a default
+    defaults: Vec<(String, Option<String>, String)>,
+}
+
+impl SchemaDefaults {
+    pub fn new(schema: &ItemSchema) -> Self {
+        Self {
+            version: schema.version,
+            defaults: schema
+                .properties
+                .iter()
+                .filter_map(|property| {
+                    let default = property.default.clone()?;
+                    Some((property.name.clone(), property.value_type.clone(), default))
+                })
+                .collect(),
+        }
+    }
+
+    /// Version of the schema the defaults come from
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Only keep the defaults of these properties, for reads narrowed down to them
+    pub fn keep_only(&mut self, names: &[String]) {
+        self.defaults.retain(|(name, _, _)| names.contains(name));
+    }
+
+    /// Defaults a committed version needs, counted from its property index
+    pub fn count(&self, index: &PropertyIndex) -> u64 {
+        let present: HashSet<&str> = index
+            .entries
+            .iter()
+            .filter_map(|entry| entry.name.as_deref())
+            .collect();
+        self.defaults
+            .iter()
+            .filter(|(name, _, _)| !present.contains(name.as_str()))
+            .count() as u64
+    }
+
+    /// Elements for the defaults whose property is not `present`, and how many there are
+    fn elements(&self, present: &HashSet<String>) -> (Vec<u8>, u64) {
+        let mut elements = String::new();
+        let mut count = 0;
+        for (name, value_type, default) in &self.defaults {
+            if present.contains(name) {
+                continue;
+            }
+            let type_attribute = value_type
+                .as_deref()
+                .map(|value_type| format!(r#" type="{}""#, escape(value_type)))
+                .unwrap_or_default();
+            elements.push_str(&format!(
+                r#"<property name="{}"{type_attribute} source="default">{}</property>"#,
+                escape(name.as_str()),
+                escape(default.as_str())
+            ));
+            count += 1;
+        }
+        (elements.into_bytes(), count)
+    }
+}
+
+/// Reader wrapper appending a `<property source="default">` element for every property
+/// with a declared default that the stored document lacks. The stored properties pass
+/// through as they complete, only their names are kept; the defaults follow the last of
+/// them, inside the `<item>` envelope if the version has one. A property stored empty is
+/// present, so it gets no default.
+pub struct DefaultingReader {
+    inner: Box<dyn ItemStreamReader>,
+    defaults: SchemaDefaults,
+    splitter: PropertySplitter,
+    present: HashSet<String>,
+    inner_finished: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl DefaultingReader {
+    pub fn new(
+        inner: Box<dyn ItemStreamReader>,
+        defaults: SchemaDefaults,
+        max_property_bytes: usize,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            inner,
+            defaults,
+            splitter: PropertySplitter::new(max_property_bytes),
+            present: HashSet::new(),
+            inner_finished: false,
+            metrics,
+        }
+    }
+
+    /// What follows the last property, with the defaults in front of a closing `</item>`
+    fn finish(&mut self) -> Vec<u8> {
+        let remainder = self.splitter.remainder();
+        let (elements, count) = self.defaults.elements(&self.present);
+        self.metrics.read_defaults_applied.add(count);
+        let insert_at = remainder
+            .windows(ItemEnvelope::CLOSING_TAG.len())
+            .rposition(|window| window == ItemEnvelope::CLOSING_TAG)
+            .unwrap_or(remainder.len());
+        let mut output = Vec::with_capacity(remainder.len() + elements.len());
+        output.extend_from_slice(&remainder[..insert_at]);
+        output.extend_from_slice(&elements);
+        output.extend_from_slice(&remainder[insert_at..]);
+        output
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for DefaultingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    let segments = self
+                        .splitter
+                        .push(&chunk)
+                        .map_err(|error| format!("{error} for schema defaults"))?;
+                    let mut output = Vec::new();
+                    for segment in segments {
+                        if let Some(start) = property_start(&segment)
+                            && let Some(name) =
+                                property_name(&String::from_utf8_lossy(&segment[start..]))
+                        {
+                            self.present.insert(name);
+                        }
+                        output.extend_from_slice(&segment);
+                    }
+                    if !output.is_empty() {
+                        return Ok(Some(output));
+                    }
+                }
+                None => {
+                    self.inner_finished = true;
+                    // An aborted upload did not end, nothing is missing from it yet
+                    if self.inner.is_aborted() {
+                        let remainder = self.splitter.remainder().to_vec();
+                        return Ok((!remainder.is_empty()).then_some(remainder));
+                    }
+                    let output = self.finish();
+                    return Ok((!output.is_empty()).then_some(output));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
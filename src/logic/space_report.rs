@@ -0,0 +1,450 @@
+use crate::logic::metadata_backfill::Throttle;
+use crate::persistence::debug_capture::CAPTURE_DIR;
+use crate::persistence::space_usage::{self, FileKind, SpaceBreakdown, SpaceCategory};
+use crate::persistence::{cold_tier, file_persistence};
+use crate::state::{AppState, StreamDb};
+
+use async_stream::stream;
+use futures::Stream;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// Groups reported when `top` is not given
+pub const DEFAULT_TOP: usize = 50;
+/// Most groups a report may hold
+pub const MAX_TOP: usize = 10_000;
+/// Discrepancies smaller than this are not flagged unless the request says otherwise
+pub const DEFAULT_THRESHOLD_BYTES: u64 = 1024 * 1024;
+/// Items whose metadata is read between two progress lines
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// What the groups of a space report are
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    Item,
+    /// The part of the item ID before its first dash, the prefix of IDs generated with
+    /// `X-Id-Prefix`, or the whole ID when it has none
+    Namespace,
+}
+
+impl GroupBy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "item" => Ok(Self::Item),
+            "namespace" => Ok(Self::Namespace),
+            other => Err(format!(
+                "Unknown group_by {other:?}, expected item or namespace"
+            )),
+        }
+    }
+
+    fn key<'a>(&self, item_id: &'a str) -> &'a str {
+        match self {
+            Self::Item => item_id,
+            Self::Namespace => item_id
+                .split_once('-')
+                .map_or(item_id, |(prefix, _)| prefix),
+        }
+    }
+}
+
+pub struct SpaceReportOptions {
+    pub group_by: GroupBy,
+    pub top: usize,
+    /// Discrepancies of fewer bytes are not flagged
+    pub threshold_bytes: u64,
+}
+
+/// Something the bookkeeping and the filesystem disagree on, a potential leak
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// A file of a version that is neither committed, being uploaded nor interrupted,
+    /// e.g. a copy left behind by a move between tiers
+    Orphaned {
+        path: String,
+        item_id: String,
+        version: u64,
+        bytes: u64,
+    },
+    /// A committed version whose data file is not the size recorded at commit, 0 when it
+    /// is missing
+    SizeMismatch {
+        item_id: String,
+        version: u64,
+        recorded_bytes: u64,
+        found_bytes: u64,
+    },
+    /// The running total the quota is checked against differs from the files found
+    AccountingDrift {
+        category: SpaceCategory,
+        accounted_bytes: u64,
+        found_bytes: u64,
+    },
+}
+
+/// The bytes of one item, or of the items of one namespace
+#[derive(Serialize, Clone)]
+pub struct SpaceGroup {
+    pub key: String,
+    pub total_bytes: u64,
+    pub bytes: SpaceBreakdown,
+}
+
+/// The running totals the quota is checked against, see `GET /admin/usage`
+#[derive(Serialize, Clone, Copy)]
+pub struct AccountedBytes {
+    pub committed: u64,
+    pub in_flight: u64,
+    /// Sizes recorded in the metadata of every committed version, which the committed
+    /// total is recounted from at startup
+    pub recorded_committed: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SpaceReport {
+    pub group_by: GroupBy,
+    /// The largest groups, at most `top`
+    pub groups: Vec<SpaceGroup>,
+    pub groups_total: usize,
+    /// Bytes of every file found, by category
+    pub found: SpaceBreakdown,
+    /// ... of which belong to no item, e.g. debug captures
+    pub unattributed: SpaceBreakdown,
+    pub found_bytes: u64,
+    /// Bytes the filesystem allocated for the files found
+    pub allocated_bytes: u64,
+    pub accounted: AccountedBytes,
+    pub files_scanned: u64,
+    pub discrepancies: u64,
+    pub dirs: Vec<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct SpaceProgress {
+    pub items_scanned: usize,
+    pub items_total: usize,
+}
+
+/// One line of `GET /admin/space-report`: progress and discrepancies as they are found,
+/// then the report
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SpaceReportLine {
+    Progress { progress: SpaceProgress },
+    Discrepancy { discrepancy: Discrepancy },
+    Report { report: Box<SpaceReport> },
+    Error { error: String },
+}
+
+/// A committed version as its metadata records it
+struct RecordedVersion {
+    size: Option<u64>,
+    /// Directory of its files
+    dir: String,
+}
+
+/// Walk the bookkeeping of every item and the files of every directory of the instance,
+/// attributing each file to its item and category. The scan runs on the blocking pool,
+/// inspects at most `STREAM_DB_SPACE_REPORT_FILES_PER_SECOND` files and items a second,
+/// and stops as soon as the stream is dropped, i.e. the client went away.
+pub fn space_report(
+    state: AppState,
+    options: SpaceReportOptions,
+) -> impl Stream<Item = SpaceReportLine> {
+    let (sender, mut receiver) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        match scan(&state, &options, &sender) {
+            Ok(Some(mut report)) => {
+                report.duration_ms = started.elapsed().as_millis() as u64;
+                println!(
+                    "Space report: {} files, {} bytes, {} discrepancies, in {} ms",
+                    report.files_scanned,
+                    report.found_bytes,
+                    report.discrepancies,
+                    report.duration_ms
+                );
+                let _ = sender.blocking_send(SpaceReportLine::Report {
+                    report: Box::new(report),
+                });
+            }
+            Ok(None) => println!(
+                "Space report cancelled after {} ms, its client went away",
+                started.elapsed().as_millis()
+            ),
+            Err(error) => {
+                println!("Space report failed: {error}");
+                let _ = sender.blocking_send(SpaceReportLine::Error { error });
+            }
+        }
+    });
+    stream! {
+        while let Some(line) = receiver.recv().await {
+            yield line;
+        }
+    }
+}
+
+/// `None` once the client is gone
+fn scan(
+    state: &StreamDb,
+    options: &SpaceReportOptions,
+    sender: &mpsc::Sender<SpaceReportLine>,
+) -> Result<Option<SpaceReport>, String> {
+    let storage = &state.storage;
+    let mut throttle = Throttle::new(state.config.space_report_files_per_second);
+    let mut discrepancies = 0;
+    let mut flag = |discrepancy: Discrepancy| {
+        discrepancies += 1;
+        sender
+            .blocking_send(SpaceReportLine::Discrepancy { discrepancy })
+            .is_ok()
+    };
+
+    // The bookkeeping first, which the files are attributed by
+    let item_ids = cold_tier::item_ids(storage)?;
+    let mut recorded: HashMap<(String, u64), RecordedVersion> = HashMap::new();
+    let mut recorded_committed = 0;
+    for (scanned, item_id) in item_ids.iter().enumerate() {
+        if scanned % PROGRESS_INTERVAL == 0 {
+            let progress = SpaceProgress {
+                items_scanned: scanned,
+                items_total: item_ids.len(),
+            };
+            if sender
+                .blocking_send(SpaceReportLine::Progress { progress })
+                .is_err()
+            {
+                return Ok(None);
+            }
+        }
+        throttle.consumed(1);
+        // Deleted meanwhile
+        let Ok(metadata) = file_persistence::load_item_metadata(storage, item_id) else {
+            continue;
+        };
+        for (version, committed) in metadata.versions {
+            recorded_committed += committed.size.unwrap_or(0);
+            let dir = committed
+                .location
+                .unwrap_or_else(|| storage.data_dir.clone());
+            let version_key = (item_id.clone(), version);
+            recorded.insert(
+                version_key,
+                RecordedVersion {
+                    size: committed.size,
+                    dir,
+                },
+            );
+        }
+    }
+    let interrupted: BTreeSet<(String, u64)> = storage
+        .failed_uploads
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    let in_flight = |item_id: &str, version: u64| {
+        storage
+            .registry
+            .in_flight(item_id)
+            .versions
+            .contains(&version)
+    };
+
+    let locations: BTreeSet<String> = recorded
+        .values()
+        .map(|version| version.dir.clone())
+        .collect();
+    let locations: Vec<String> = locations.into_iter().collect();
+    let dirs = space_usage::dirs(storage, state.config.cold_dir.as_deref(), &locations);
+    let mut groups: BTreeMap<String, SpaceBreakdown> = BTreeMap::new();
+    let mut found = SpaceBreakdown::default();
+    let mut unattributed = SpaceBreakdown::default();
+    let mut seen: BTreeSet<(String, u64)> = BTreeSet::new();
+    let mut allocated_bytes = 0;
+    let mut files_scanned = 0;
+    for dir in &dirs {
+        let files = space_usage::list_dir(dir, || {
+            throttle.consumed(1);
+            !sender.is_closed()
+        })?;
+        if sender.is_closed() {
+            return Ok(None);
+        }
+        for file in files {
+            files_scanned += 1;
+            allocated_bytes += file.allocated_bytes;
+            let kind = if file.nested {
+                FileKind::Other
+            } else {
+                FileKind::of(&file.top_level_name)
+            };
+            let (item_id, version, category) = match kind {
+                FileKind::Metadata { item_id } => (item_id, 0, SpaceCategory::Metadata),
+                FileKind::Data { item_id, version } => {
+                    let key = (item_id, version);
+                    let category = match recorded.get(&key) {
+                        Some(committed) if committed.dir == *dir => {
+                            let recorded_bytes = committed.size.unwrap_or(file.bytes);
+                            if recorded_bytes.abs_diff(file.bytes) >= options.threshold_bytes
+                                && !flag(Discrepancy::SizeMismatch {
+                                    item_id: key.0.clone(),
+                                    version,
+                                    recorded_bytes,
+                                    found_bytes: file.bytes,
+                                })
+                            {
+                                return Ok(None);
+                            }
+                            seen.insert(key.clone());
+                            SpaceCategory::Committed
+                        }
+                        _ if in_flight(&key.0, version) => SpaceCategory::InFlight,
+                        _ if interrupted.contains(&key) => SpaceCategory::Interrupted,
+                        _ => SpaceCategory::Orphaned,
+                    };
+                    (key.0, version, category)
+                }
+                FileKind::Index { item_id, version } => {
+                    let known = recorded.contains_key(&(item_id.clone(), version))
+                        || in_flight(&item_id, version)
+                        || interrupted.contains(&(item_id.clone(), version));
+                    let category = if known {
+                        SpaceCategory::Indexes
+                    } else {
+                        SpaceCategory::Orphaned
+                    };
+                    (item_id, version, category)
+                }
+                FileKind::Journal { item_id, version } => {
+                    let known = in_flight(&item_id, version)
+                        || interrupted.contains(&(item_id.clone(), version));
+                    let category = if known {
+                        SpaceCategory::Journals
+                    } else {
+                        SpaceCategory::Orphaned
+                    };
+                    (item_id, version, category)
+                }
+                FileKind::Other => {
+                    let category = if file.nested
+                        && *dir == storage.data_dir
+                        && file.top_level_name == CAPTURE_DIR
+                    {
+                        SpaceCategory::DebugCaptures
+                    } else {
+                        SpaceCategory::Other
+                    };
+                    found.add(category, file.bytes);
+                    unattributed.add(category, file.bytes);
+                    continue;
+                }
+            };
+            if category == SpaceCategory::Orphaned
+                && file.bytes >= options.threshold_bytes
+                && !flag(Discrepancy::Orphaned {
+                    path: file.path,
+                    item_id: item_id.clone(),
+                    version,
+                    bytes: file.bytes,
+                })
+            {
+                return Ok(None);
+            }
+            found.add(category, file.bytes);
+            groups
+                .entry(options.group_by.key(&item_id).to_string())
+                .or_default()
+                .add(category, file.bytes);
+        }
+    }
+
+    // Committed versions whose data file was not where the metadata points
+    for (key, committed) in &recorded {
+        if let Some(recorded_bytes) = committed.size
+            && recorded_bytes >= options.threshold_bytes
+            && !seen.contains(key)
+            && !flag(Discrepancy::SizeMismatch {
+                item_id: key.0.clone(),
+                version: key.1,
+                recorded_bytes,
+                found_bytes: 0,
+            })
+        {
+            return Ok(None);
+        }
+    }
+    let accounted = AccountedBytes {
+        committed: storage.usage.committed_bytes(),
+        in_flight: storage.usage.in_flight_bytes(),
+        recorded_committed,
+    };
+    for (category, accounted_bytes, found_bytes) in [
+        (
+            SpaceCategory::Committed,
+            accounted.committed,
+            found.committed,
+        ),
+        (
+            SpaceCategory::InFlight,
+            accounted.in_flight,
+            found.in_flight,
+        ),
+    ] {
+        if accounted_bytes.abs_diff(found_bytes) >= options.threshold_bytes
+            && !flag(Discrepancy::AccountingDrift {
+                category,
+                accounted_bytes,
+                found_bytes,
+            })
+        {
+            return Ok(None);
+        }
+    }
+
+    // Only the scan knows these, the committed and in-flight totals are kept up to date
+    // by uploads, commits and deletes
+    state.metrics.space_interrupted_bytes.set(found.interrupted);
+    state.metrics.space_orphaned_bytes.set(found.orphaned);
+    state.metrics.space_index_bytes.set(found.indexes);
+    state.metrics.space_journal_bytes.set(found.journals);
+    state
+        .metrics
+        .space_debug_capture_bytes
+        .set(found.debug_captures);
+    state.metrics.space_metadata_bytes.set(found.metadata);
+    state.metrics.space_other_bytes.set(found.other);
+
+    let groups_total = groups.len();
+    let mut groups: Vec<SpaceGroup> = groups
+        .into_iter()
+        .map(|(key, bytes)| SpaceGroup {
+            key,
+            total_bytes: bytes.total(),
+            bytes,
+        })
+        .collect();
+    groups.sort_by(|a, b| b.total_bytes.cmp(&a.total_bytes).then(a.key.cmp(&b.key)));
+    groups.truncate(options.top);
+    Ok(Some(SpaceReport {
+        group_by: options.group_by,
+        groups,
+        groups_total,
+        found_bytes: found.total(),
+        found,
+        unattributed,
+        allocated_bytes,
+        accounted,
+        files_scanned,
+        discrepancies,
+        dirs,
+        duration_ms: 0,
+    }))
+}
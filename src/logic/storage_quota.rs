@@ -0,0 +1,99 @@
+use crate::state::StreamDb;
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Stands for "no quota" in [`StorageQuota`]
+const UNLIMITED: u64 = u64::MAX;
+
+/// Cap on the bytes stored by one instance, adjustable at runtime through `/admin/usage`
+pub struct StorageQuota {
+    quota_bytes: AtomicU64,
+    /// Separate cap on uploads in flight, which may be on another volume, fixed at
+    /// startup
+    inflight_quota_bytes: Option<u64>,
+}
+
+impl StorageQuota {
+    pub fn new(quota_bytes: Option<u64>, inflight_quota_bytes: Option<u64>) -> Self {
+        Self {
+            quota_bytes: AtomicU64::new(quota_bytes.unwrap_or(UNLIMITED)),
+            inflight_quota_bytes,
+        }
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        Some(self.quota_bytes.load(Ordering::Acquire)).filter(|quota| *quota != UNLIMITED)
+    }
+
+    pub fn set(&self, quota_bytes: Option<u64>) {
+        self.quota_bytes
+            .store(quota_bytes.unwrap_or(UNLIMITED), Ordering::Release);
+    }
+}
+
+/// Bytes stored by the instance against its quota, uploads in flight included so that
+/// many parallel uploads cannot overshoot it together
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct StorageUsageReport {
+    pub committed_bytes: u64,
+    pub in_flight_bytes: u64,
+    pub used_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub available_bytes: Option<u64>,
+    /// `STREAM_DB_INFLIGHT_QUOTA_BYTES`, counted against `in_flight_bytes` alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_flight_quota_bytes: Option<u64>,
+}
+
+/// An upload that would take the instance past its quota
+#[derive(Serialize)]
+pub struct QuotaExceeded {
+    #[serde(flatten)]
+    pub usage: StorageUsageReport,
+    /// Bytes the upload was about to add
+    pub requested_bytes: u64,
+    #[serde(skip)]
+    pub message: String,
+}
+
+pub fn usage(state: &StreamDb) -> StorageUsageReport {
+    let committed_bytes = state.storage.usage.committed_bytes();
+    let in_flight_bytes = state.storage.usage.in_flight_bytes();
+    let used_bytes = committed_bytes + in_flight_bytes;
+    let quota_bytes = state.quota.get();
+    StorageUsageReport {
+        committed_bytes,
+        in_flight_bytes,
+        used_bytes,
+        quota_bytes,
+        available_bytes: quota_bytes.map(|quota| quota.saturating_sub(used_bytes)),
+        in_flight_quota_bytes: state.quota.inflight_quota_bytes,
+    }
+}
+
+/// Check that `requested_bytes` more still fit into the quota
+pub fn check(state: &StreamDb, requested_bytes: u64) -> Result<(), QuotaExceeded> {
+    let usage = usage(state);
+    match usage.quota_bytes {
+        Some(quota) if usage.used_bytes + requested_bytes > quota => Err(QuotaExceeded {
+            message: format!(
+                "Storage quota of {quota} bytes exceeded: {} bytes used, {requested_bytes} more requested",
+                usage.used_bytes
+            ),
+            usage,
+            requested_bytes,
+        }),
+        _ => match usage.in_flight_quota_bytes {
+            Some(quota) if usage.in_flight_bytes + requested_bytes > quota => Err(QuotaExceeded {
+                message: format!(
+                    "In-flight quota of {quota} bytes exceeded: {} bytes in flight, {requested_bytes} more requested",
+                    usage.in_flight_bytes
+                ),
+                usage,
+                requested_bytes,
+            }),
+            _ => Ok(()),
+        },
+    }
+}
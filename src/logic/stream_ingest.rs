@@ -0,0 +1,618 @@
+use crate::logic::content_encoding::Decoder;
+use crate::logic::content_sniff::{ContentSniffer, SniffMismatch};
+use crate::logic::extra_elements::ExtraElements;
+use crate::logic::framing::FrameDecoder;
+use crate::logic::item_stream_logic::ItemStreamLogic;
+use crate::logic::property_dedupe::DuplicateProperty;
+use crate::logic::property_element::{PROPERTY_END_TAG, PROPERTY_START_TAG};
+use crate::logic::property_types::TypeViolations;
+use crate::logic::read_stats;
+use crate::logic::storage_quota::QuotaExceeded;
+use crate::logic::upload_completeness::{Completeness, CompletenessCheck};
+use crate::logic::write_limits::{LimitViolation, WriteLimits};
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::debug_capture::DebugCapture;
+use crate::persistence::file_persistence;
+use crate::persistence::item_metadata::VersionMetadata;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What an upload sent, as received. Differs from the committed receipt when the
+/// properties were wrapped, deduplicated or the body ended in something that is not one.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WriteStats {
+    /// Body bytes received
+    pub bytes: u64,
+    /// Properties received, including duplicates that were not stored
+    pub properties: u64,
+    /// SHA-256 of the body received
+    pub checksum: String,
+    /// RFC 3339 timestamp in UTC
+    pub started_at: String,
+    /// Why the first bytes of the body did not look like XML, see `STREAM_DB_CONTENT_SNIFF`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_sniff_warning: Option<String>,
+    /// What confirmed the body arrived complete, for uploads through the endpoints
+    /// checking it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completeness: Option<Completeness>,
+}
+
+/// Why an upload was refused. Every one of them aborts the writer.
+pub enum IngestError {
+    /// The body is, or announced to be, larger than the item allows
+    TooLarge(LimitViolation),
+    /// A property broke one of the item's limits
+    LimitExceeded(LimitViolation),
+    QuotaExceeded(QuotaExceeded),
+    InvalidUtf8 {
+        byte_offset: u64,
+    },
+    /// The body ended without a single property in it
+    NoProperties {
+        bytes_received: u64,
+    },
+    TypeMismatch(TypeViolations),
+    /// The first bytes of the body contradict its XML `Content-Type`
+    ContentMismatch(SniffMismatch),
+    DuplicateProperty {
+        message: String,
+        duplicate: DuplicateProperty,
+    },
+    /// The producer stopped sending halfway, e.g. its connection broke or it sent
+    /// something the endpoint does not understand
+    Interrupted {
+        message: String,
+        bytes_received: u64,
+    },
+    /// The upload was failed by someone else, e.g. its version was deleted meanwhile
+    Aborted(String),
+    /// The upload was cancelled, e.g. killed through the admin API or cut off by a
+    /// shutdown
+    Cancelled {
+        reason: CancelReason,
+        bytes_received: u64,
+    },
+    /// A newer version of the item was committed while the upload ran
+    Superseded {
+        message: String,
+        requested: u64,
+        current: u64,
+    },
+    Failed(String),
+}
+
+impl IngestError {
+    /// Name of the error as reported by the API, also recorded in debug captures
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TooLarge(_) => "PAYLOAD_TOO_LARGE",
+            Self::LimitExceeded(_) => "LIMIT_EXCEEDED",
+            Self::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            Self::InvalidUtf8 { .. } | Self::NoProperties { .. } => "INVALID_XML",
+            Self::TypeMismatch(_) => "TYPE_MISMATCH",
+            Self::ContentMismatch(_) => "UNSUPPORTED_MEDIA_TYPE",
+            Self::DuplicateProperty { .. } => "DUPLICATE_PROPERTY",
+            Self::Interrupted { .. } => "BAD_REQUEST",
+            Self::Aborted(_) => "ABORTED",
+            Self::Cancelled {
+                reason: CancelReason::Shutdown | CancelReason::Timeout,
+                ..
+            } => "UNAVAILABLE",
+            Self::Cancelled { .. } => "ABORTED",
+            Self::Superseded { .. } => "VERSION_SUPERSEDED",
+            Self::Failed(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Self::TooLarge(violation) | Self::LimitExceeded(violation) => violation.message.clone(),
+            Self::QuotaExceeded(exceeded) => exceeded.message.clone(),
+            Self::InvalidUtf8 { .. } => "Invalid UTF-8 in XML data".to_string(),
+            Self::NoProperties { .. } => "No valid property elements found in XML".to_string(),
+            Self::Cancelled { reason, .. } => file_persistence::cancelled_message(*reason),
+            Self::ContentMismatch(mismatch) => mismatch.message.clone(),
+            Self::TypeMismatch(type_violations) => format!(
+                "Property values do not match their declared type ({} violations)",
+                type_violations.count
+            ),
+            Self::DuplicateProperty { message, .. }
+            | Self::Interrupted { message, .. }
+            | Self::Superseded { message, .. }
+            | Self::Aborted(message)
+            | Self::Failed(message) => message.clone(),
+        }
+    }
+}
+
+/// Turns the bytes of an upload into stored properties, whichever endpoint they arrive
+/// through: splits them into property elements as they come in, checks the item's
+/// limits and the quota on the way, writes every complete property and keeps the
+/// upload's [`WriteStats`].
+///
+/// The first error aborts the writer and ends the debug capture, if the upload is being
+/// captured. Dropping an unfinished ingest cancels the upload, its client is gone.
+pub struct StreamIngest {
+    logic: ItemStreamLogic,
+    limits: WriteLimits,
+    capture: Option<DebugCapture>,
+    /// Elements other than properties the item's receipt keeps a copy of
+    extra_elements: ExtraElements,
+    /// Buffer to accumulate partial XML chunks
+    xml_buffer: String,
+    /// Start of a UTF-8 character split across two chunks
+    partial_character: Vec<u8>,
+    /// Decodes the body of an upload sent compressed
+    decoder: Option<Decoder>,
+    /// Strips the framing off the body of a framed upload
+    framing: Option<FrameDecoder>,
+    /// Holds the first decoded bytes back until they tell whether the body looks like
+    /// XML, gone once they did
+    sniffer: Option<ContentSniffer>,
+    /// Why the body did not look like XML, for an upload that went through anyway
+    sniff_warning: Option<String>,
+    /// Whether the body is stored as received, compressed
+    stores_encoded: bool,
+    /// What has to confirm the body is complete once it ended, see
+    /// [`Self::check_completeness`]
+    completeness_check: Option<CompletenessCheck>,
+    /// Body offset of the start of the buffer, in decoded bytes
+    consumed_bytes: u64,
+    properties: u64,
+    received_bytes: u64,
+    /// Bytes of the body once decoded, the bytes received unless it was sent compressed
+    decoded_bytes: u64,
+    hasher: Sha256,
+    started_at: String,
+    /// The upload committed or failed, nothing is left to cancel when it is dropped
+    settled: bool,
+}
+
+impl StreamIngest {
+    /// Ingest the upload written through `logic`, failing for the logic of a reader
+    pub fn new(logic: ItemStreamLogic, capture: Option<DebugCapture>) -> Result<Self, IngestError> {
+        let Some(limits) = logic.limits().copied() else {
+            return Err(IngestError::Failed(
+                "Only the writer of an upload can ingest its body".to_string(),
+            ));
+        };
+        Ok(Self {
+            limits,
+            extra_elements: logic.extra_elements(),
+            decoder: logic.upload_encoding().map(|encoding| encoding.decoder()),
+            framing: logic.frame_decoder(),
+            sniffer: logic.content_sniffer(),
+            sniff_warning: None,
+            stores_encoded: logic.stored_encoding().is_some(),
+            completeness_check: None,
+            logic,
+            capture,
+            xml_buffer: String::new(),
+            partial_character: Vec::new(),
+            consumed_bytes: 0,
+            properties: 0,
+            received_bytes: 0,
+            decoded_bytes: 0,
+            hasher: Sha256::new(),
+            started_at: read_stats::now(),
+            settled: false,
+        })
+    }
+
+    /// Fires when the upload is cancelled, for endpoints waiting on their client to
+    /// select against
+    pub fn cancellation(&self) -> Cancellation {
+        self.logic.cancellation()
+    }
+
+    /// Stop the upload for `reason`, found by selecting against [`Self::cancellation`]
+    /// or noticed by the endpoint itself, e.g. the client went away
+    pub fn cancel(&mut self, reason: CancelReason) -> IngestError {
+        self.logic.cancel(reason);
+        let bytes_received = self.received_bytes;
+        // The first reason stands
+        let reason = self.logic.cancel_reason().unwrap_or(reason);
+        self.fail(
+            bytes_received,
+            IngestError::Cancelled {
+                reason,
+                bytes_received,
+            },
+        )
+    }
+
+    pub fn limits(&self) -> &WriteLimits {
+        &self.limits
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    /// Body bytes written so far
+    pub fn written_bytes(&self) -> u64 {
+        self.consumed_bytes
+    }
+
+    /// Refuse an upload whose announced size is too large before any of it arrives
+    pub fn check_declared_size(&mut self, declared_size: Option<u64>) -> Result<(), IngestError> {
+        self.logic.track_received(0, declared_size);
+        if let Some(declared_size) = declared_size
+            && let Err(violation) = self.limits.check_item_bytes(declared_size)
+        {
+            return Err(self.fail(0, IngestError::TooLarge(violation)));
+        }
+        if let Err(exceeded) = self.logic.check_quota(declared_size.unwrap_or(0)) {
+            return Err(self.fail(0, IngestError::QuotaExceeded(exceeded)));
+        }
+        Ok(())
+    }
+
+    /// Only commit the upload if `check` confirms the body complete once it ended, for
+    /// endpoints whose transport may end a body that broke off as if it were complete
+    pub fn check_completeness(&mut self, check: CompletenessCheck) {
+        self.completeness_check = Some(check);
+    }
+
+    /// Take the next bytes of the body and write the properties they complete
+    pub async fn push_bytes(&mut self, bytes: Bytes) -> Result<(), IngestError> {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.raw(&bytes);
+        }
+        if let Some(reason) = self.logic.cancel_reason() {
+            return Err(self.cancel(reason));
+        }
+        self.logic.track_received(bytes.len() as u64, None);
+        self.hasher.update(&bytes);
+        // Chunked uploads announce no size, so the limit is enforced as they arrive
+        self.received_bytes += bytes.len() as u64;
+        if let Err(violation) = self.limits.check_item_bytes(self.received_bytes) {
+            return Err(self.fail(self.received_bytes, IngestError::TooLarge(violation)));
+        }
+        if let Some(framing) = self.framing.as_mut() {
+            return match framing.push(&bytes) {
+                Ok(payload) => self.push_decoded(payload).await,
+                Err(message) => Err(self.interrupted(message)),
+            };
+        }
+        if self.decoder.is_none() {
+            return self.push_decoded(bytes.to_vec()).await;
+        }
+
+        if self.stores_encoded
+            && let Err(error) = self.logic.write_encoded(bytes.to_vec()).await
+        {
+            let error = self.write_failed(error);
+            return Err(self.fail(self.received_bytes, error));
+        }
+        let decoded = self.decoder.as_mut().map(|decoder| decoder.push(&bytes));
+        match decoded {
+            Some(Ok(decoded)) => self.push_decoded(decoded).await,
+            Some(Err(message)) => Err(self.interrupted(message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Take the next decoded bytes of the body and write the properties they complete
+    async fn push_decoded(&mut self, bytes: Vec<u8>) -> Result<(), IngestError> {
+        let bytes = match self.sniffer.as_mut() {
+            None => bytes,
+            Some(sniffer) => match sniffer.push(bytes) {
+                None => return Ok(()),
+                Some((bytes, mismatch)) => {
+                    self.sniffed(mismatch)?;
+                    bytes
+                }
+            },
+        };
+        self.decoded_bytes += bytes.len() as u64;
+        // A small compressed body may decode to far more than the item allows
+        if self.decoder.is_some()
+            && let Err(violation) = self.limits.check_item_bytes(self.decoded_bytes)
+        {
+            return Err(self.fail(self.received_bytes, IngestError::TooLarge(violation)));
+        }
+        // Checked again as the upload grows, since parallel uploads may have used up the
+        // room seen up front
+        if let Err(exceeded) = self
+            .logic
+            .check_quota((self.xml_buffer.len() + bytes.len()) as u64)
+        {
+            return Err(self.fail(self.received_bytes, IngestError::QuotaExceeded(exceeded)));
+        }
+
+        let text_start = self.decoded_bytes - (self.partial_character.len() + bytes.len()) as u64;
+        let text = if self.partial_character.is_empty() {
+            bytes
+        } else {
+            let mut text = std::mem::take(&mut self.partial_character);
+            text.extend_from_slice(&bytes);
+            text
+        };
+        let text = match String::from_utf8(text) {
+            Ok(text) => text,
+            // A character cut off at the end of the chunk is completed by the next one
+            Err(error) if error.utf8_error().error_len().is_none() => {
+                let valid_up_to = error.utf8_error().valid_up_to();
+                let mut text = error.into_bytes();
+                self.partial_character = text.split_off(valid_up_to);
+                String::from_utf8(text).expect("checked to be valid up to here")
+            }
+            Err(error) => {
+                let byte_offset = text_start + error.utf8_error().valid_up_to() as u64;
+                return Err(self.fail(byte_offset, IngestError::InvalidUtf8 { byte_offset }));
+            }
+        };
+        self.xml_buffer.push_str(&text);
+
+        loop {
+            // Extra elements are written as they arrive, they may be larger than any
+            // property
+            if self.extra_elements.is_open() {
+                let taken = self.extra_elements.take(&self.xml_buffer);
+                if taken == 0 {
+                    break;
+                }
+                self.write_unsplit(taken).await?;
+                continue;
+            }
+            if let Some(start) = self.extra_elements.open(&self.xml_buffer) {
+                if start > 0 {
+                    self.write_unsplit(start).await?;
+                }
+                continue;
+            }
+            let Some(end_tag_pos) = self.xml_buffer.find(PROPERTY_END_TAG) else {
+                break;
+            };
+            let property_end = end_tag_pos + PROPERTY_END_TAG.len();
+            let property_element = &self.xml_buffer[..property_end];
+            if let Err(violation) = self
+                .limits
+                .check_property(property_element, self.properties + 1)
+            {
+                return Err(self.fail(self.consumed_bytes, IngestError::LimitExceeded(violation)));
+            }
+
+            // Written as-is, the XML is never validated
+            let element = property_element.as_bytes().to_vec();
+            if let Err(error) = self.logic.write_property(element).await {
+                let error = self.write_failed(error);
+                return Err(self.fail(self.consumed_bytes, error));
+            }
+
+            self.properties += 1;
+            self.xml_buffer.drain(..property_end);
+            self.consumed_bytes += property_end as u64;
+            if let Some(capture) = self.capture.as_mut() {
+                capture.boundary(self.consumed_bytes);
+            }
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.buffered(self.xml_buffer.len());
+        }
+
+        // Reject an oversized property while it is still streaming in
+        if let Err(violation) = self.limits.check_partial_property(&self.xml_buffer) {
+            return Err(self.fail(self.consumed_bytes, IngestError::LimitExceeded(violation)));
+        }
+        Ok(())
+    }
+
+    /// The body ended: write what is left in the buffer and commit the version
+    pub async fn finish(mut self) -> Result<(VersionMetadata, WriteStats), IngestError> {
+        // What the decoder held back, or the body was cut off
+        match self.decoder.as_mut().map(Decoder::finish) {
+            Some(Ok(decoded)) => self.push_decoded(decoded).await?,
+            Some(Err(message)) => return Err(self.interrupted(message)),
+            None => (),
+        }
+        let segments = match self.framing.as_mut().map(FrameDecoder::finish) {
+            Some(Ok(boundaries)) => Some(boundaries),
+            Some(Err(message)) => return Err(self.interrupted(message)),
+            None => None,
+        };
+        // A body too short for the sniff to tell before it ended
+        if let Some((bytes, mismatch)) = self.sniffer.as_mut().map(ContentSniffer::finish) {
+            self.sniffed(mismatch)?;
+            self.push_decoded(bytes).await?;
+        }
+        // Before whatever is left in the buffer counts as a property, a body cut off
+        // would most likely end in the middle of one
+        let end_frame = self.framing.as_ref().is_some_and(FrameDecoder::ended);
+        let completeness = match self
+            .completeness_check
+            .take()
+            .map(|check| check.confirm(self.received_bytes, self.properties, end_frame))
+        {
+            Some(Ok(completeness)) => Some(completeness),
+            Some(Err(message)) => return Err(self.interrupted(message)),
+            None => None,
+        };
+        if !self.partial_character.is_empty() {
+            let byte_offset = self.decoded_bytes - self.partial_character.len() as u64;
+            return Err(self.fail(byte_offset, IngestError::InvalidUtf8 { byte_offset }));
+        }
+        self.extra_elements.close_unterminated();
+        if !self.xml_buffer.is_empty() {
+            let is_property = self.xml_buffer.contains(PROPERTY_START_TAG);
+            if let Some(capture) = self.capture.as_mut() {
+                capture.tail(self.consumed_bytes, self.xml_buffer.len(), is_property);
+            }
+            if is_property
+                && let Err(violation) = self
+                    .limits
+                    .check_property(&self.xml_buffer, self.properties + 1)
+            {
+                return Err(self.fail(self.consumed_bytes, IngestError::LimitExceeded(violation)));
+            }
+            // Written as-is, counted as a property if it looks like one
+            let remaining = std::mem::take(&mut self.xml_buffer).into_bytes();
+            let written = if is_property {
+                self.properties += 1;
+                self.logic.write_property(remaining).await
+            } else {
+                self.logic.write_chunk(remaining).await
+            };
+            if let Err(error) = written {
+                let error = self.write_failed(error);
+                return Err(self.fail(self.consumed_bytes, error));
+            }
+        }
+
+        if self.properties == 0 {
+            let bytes_received = self.received_bytes;
+            return Err(self.fail(bytes_received, IngestError::NoProperties { bytes_received }));
+        }
+        if let Some(type_violations) = self
+            .logic
+            .type_violations()
+            .filter(|type_violations| !type_violations.is_empty())
+        {
+            let error = IngestError::TypeMismatch(type_violations.clone());
+            return Err(self.fail(self.received_bytes, error));
+        }
+
+        if let Some(segments) = segments {
+            self.logic.record_segments(segments);
+        }
+        let extra_elements = self.extra_elements.take_copies();
+        self.logic.record_extra_elements(extra_elements);
+        match self.logic.finalize().await {
+            Ok(committed) => {
+                self.settled = true;
+                if let Some(mut capture) = self.capture.take() {
+                    capture.finish("committed");
+                }
+                let stats = WriteStats {
+                    bytes: self.received_bytes,
+                    properties: self.properties,
+                    checksum: format!("{:x}", self.hasher.clone().finalize()),
+                    started_at: self.started_at.clone(),
+                    content_sniff_warning: self.sniff_warning.take(),
+                    completeness,
+                };
+                Ok((committed, stats))
+            }
+            Err(error) => {
+                let error = self.write_failed(format!("Write error: {error}"));
+                Err(self.fail(self.received_bytes, error))
+            }
+        }
+    }
+
+    /// The sniff came to a verdict, and its bytes go on to the splitter. A mismatch
+    /// refuses the upload in strict mode and is only reported otherwise.
+    fn sniffed(&mut self, mismatch: Option<SniffMismatch>) -> Result<(), IngestError> {
+        let strict = self
+            .sniffer
+            .take()
+            .is_some_and(|sniffer| sniffer.is_strict());
+        let Some(mismatch) = mismatch else {
+            return Ok(());
+        };
+        if strict {
+            self.logic.metrics().content_sniff_rejections.increment();
+            let offset = mismatch.byte_offset;
+            return Err(self.fail(offset, IngestError::ContentMismatch(mismatch)));
+        }
+        self.logic.metrics().content_sniff_warnings.increment();
+        println!(
+            "Upload of item {} version {} does not look like XML ({}, first bytes {}): {}",
+            self.logic.item_id(),
+            self.logic.item_version(),
+            mismatch.content.name(),
+            mismatch.first_bytes,
+            mismatch.message
+        );
+        self.sniff_warning = Some(mismatch.message);
+        Ok(())
+    }
+
+    /// Write the first `bytes` of the buffer, which are not a property
+    async fn write_unsplit(&mut self, bytes: usize) -> Result<(), IngestError> {
+        let chunk = self.xml_buffer.as_bytes()[..bytes].to_vec();
+        if let Err(error) = self.logic.write_chunk(chunk).await {
+            let error = self.write_failed(error);
+            return Err(self.fail(self.consumed_bytes, error));
+        }
+        self.xml_buffer.drain(..bytes);
+        self.consumed_bytes += bytes as u64;
+        Ok(())
+    }
+
+    /// The producer stopped sending before the body ended, `message` says why
+    pub fn interrupted(&mut self, message: String) -> IngestError {
+        let bytes_received = self.received_bytes;
+        self.fail(
+            bytes_received,
+            IngestError::Interrupted {
+                message,
+                bytes_received,
+            },
+        )
+    }
+
+    /// Give up on the upload at the producer's request, cleaning up everything written
+    pub fn abort(&mut self) {
+        self.settled = true;
+        self.logic.abort();
+        if let Some(mut capture) = self.capture.take() {
+            capture.finish("ABORTED");
+        }
+    }
+
+    /// Tell a write that failed because the upload was killed or repeated a property
+    /// while rejecting duplicates from one where the disk gave up
+    fn write_failed(&self, error: String) -> IngestError {
+        if let Some(duplicate) = self.logic.duplicate_property() {
+            return IngestError::DuplicateProperty {
+                message: error,
+                duplicate: duplicate.clone(),
+            };
+        }
+        if let Some(reason) = self.logic.cancel_reason() {
+            return IngestError::Cancelled {
+                reason,
+                bytes_received: self.received_bytes,
+            };
+        }
+        if let Some(current) = self.logic.superseded_by() {
+            return IngestError::Superseded {
+                message: error,
+                requested: self.logic.item_version(),
+                current,
+            };
+        }
+        if self.logic.is_aborted() {
+            IngestError::Aborted(error)
+        } else {
+            IngestError::Failed(error)
+        }
+    }
+
+    /// Abort the writer and end the capture with `error`, which stopped the upload at
+    /// body offset `offset`
+    fn fail(&mut self, offset: u64, error: IngestError) -> IngestError {
+        self.settled = true;
+        self.logic.abort();
+        if let Some(mut capture) = self.capture.take() {
+            capture.error(offset, error.name(), &error.message());
+            capture.finish(error.name());
+        }
+        error
+    }
+}
+
+impl Drop for StreamIngest {
+    /// Dropped before it committed or failed, the endpoint gave up on its client: the
+    /// connection closed and the request was dropped with it
+    fn drop(&mut self) {
+        if !self.settled {
+            self.cancel(CancelReason::ClientGone);
+        }
+    }
+}
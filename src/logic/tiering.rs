@@ -0,0 +1,130 @@
+use crate::logic::read_stats;
+use crate::persistence::cold_tier::{self, MoveReport, StorageTier};
+use crate::persistence::file_persistence::{self, WriteError};
+use crate::state::{AppState, StreamDb};
+
+use tokio::time::Duration;
+
+/// How often the tiering pass runs at most
+const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Move a committed version between the data directory and the cold tier
+pub async fn move_version(
+    state: &AppState,
+    item_id: &str,
+    item_version: u64,
+    tier: StorageTier,
+    skip_if_read: bool,
+) -> Result<MoveReport, WriteError> {
+    let Some(cold_dir) = state.config.cold_dir.clone() else {
+        return Err(WriteError::Failed(
+            "Cold tier is disabled, set STREAM_DB_COLD_DIR to enable it".to_string(),
+        ));
+    };
+    let storage = state.storage.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || {
+        cold_tier::move_version(
+            &storage,
+            &item_id,
+            item_version,
+            tier,
+            &cold_dir,
+            skip_if_read,
+        )
+    })
+    .await
+    .map_err(|error| WriteError::Failed(error.to_string()))?
+}
+
+/// Bring a cold version back to the data directory after it was read, without holding
+/// up the reader. The reader that triggered it keeps streaming the cold copy.
+pub fn promote_after_read(state: &AppState, item_id: &str, item_version: u64) {
+    if !state.config.cold_promote_on_read || state.config.read_only {
+        return;
+    }
+    let state = state.clone();
+    let item_id = item_id.to_string();
+    tokio::spawn(async move {
+        if let Err(error) =
+            move_version(&state, &item_id, item_version, StorageTier::Hot, false).await
+        {
+            println!(
+                "Could not promote item {item_id} version {item_version}: {}",
+                error.message()
+            );
+        }
+    });
+}
+
+/// Committed versions that have not been read, or written if never read, for longer
+/// than the configured age
+fn cold_candidates(state: &StreamDb, cold_after: Duration) -> Result<Vec<(String, u64)>, String> {
+    let cutoff = chrono::Utc::now()
+        - chrono::Duration::from_std(cold_after).map_err(|error| error.to_string())?;
+    let mut candidates = Vec::new();
+
+    for item_id in cold_tier::item_ids(&state.storage)? {
+        let metadata = file_persistence::load_item_metadata(&state.storage, &item_id)?;
+        let stats = read_stats::item_stats(state, &item_id)?;
+        for (version, committed) in &metadata.versions {
+            // Left where they are until they are repaired or deleted
+            if committed.quarantined_at.is_some() {
+                continue;
+            }
+            if committed.location.is_some() {
+                // Finish a move that crashed before the originals were removed
+                if cold_tier::remove_stale_hot_copy(&state.storage, &item_id, *version) {
+                    println!("Removed stale copy of cold item {item_id} version {version}");
+                }
+                continue;
+            }
+            let last_used = stats
+                .versions
+                .get(version)
+                .and_then(|stats| stats.last_accessed.as_deref())
+                .or(committed.committed_at.as_deref());
+            let is_cold = last_used
+                .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+                .is_some_and(|timestamp| timestamp < cutoff);
+            if is_cold {
+                candidates.push((item_id.clone(), *version));
+            }
+        }
+    }
+    Ok(candidates)
+}
+
+/// Periodically move versions nobody reads any more to the cold tier
+pub fn start(state: AppState) {
+    let (Some(_), Some(cold_after_secs)) = (&state.config.cold_dir, state.config.cold_after_secs)
+    else {
+        return;
+    };
+    let cold_after = Duration::from_secs(cold_after_secs);
+
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(cold_after.clamp(Duration::from_secs(1), MAX_INTERVAL));
+        loop {
+            interval.tick().await;
+            let candidates = match cold_candidates(&state, cold_after) {
+                Ok(candidates) => candidates,
+                Err(error) => {
+                    println!("Tiering pass failed: {error}");
+                    continue;
+                }
+            };
+            for (item_id, version) in candidates {
+                if let Err(error) =
+                    move_version(&state, &item_id, version, StorageTier::Cold, true).await
+                {
+                    println!(
+                        "Could not move item {item_id} version {version} to the cold tier: {}",
+                        error.message()
+                    );
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+/// What confirmed that the body of an upload arrived complete, reported in its receipt
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Completeness {
+    /// As many bytes as its `Content-Length` announced
+    ContentLength,
+    /// The empty frame ending a framed upload
+    EndFrame,
+    /// As many bytes as its `X-Expected-Size` announced
+    ExpectedSize,
+    /// As many properties as its `X-Expected-Properties` announced
+    ExpectedProperties,
+    /// The `X-Stream-Complete: true` trailer
+    Trailer,
+    /// Nothing, taken anyway with `STREAM_DB_ALLOW_UNCONFIRMED_UPLOADS`
+    Unconfirmed,
+}
+
+/// What an upload announced about its body, checked once the body ended. Some transports
+/// report a connection that broke halfway as the end of the body rather than an error,
+/// so a body nothing confirms may just as well have been cut off.
+#[derive(Default)]
+pub struct CompletenessCheck {
+    pub content_length: Option<u64>,
+    pub expected_size: Option<u64>,
+    pub expected_properties: Option<u64>,
+    /// The body ended with the `X-Stream-Complete: true` trailer
+    pub trailer: bool,
+    /// Take a body nothing confirms, as uploads were before any of this was checked
+    pub allow_unconfirmed: bool,
+}
+
+impl CompletenessCheck {
+    /// What confirms a body of `bytes` holding `properties` complete properties,
+    /// `end_frame` when it is a framed body that ended with its empty frame. Fails when
+    /// the body does not match what was announced, or nothing confirms it and such
+    /// bodies are not taken.
+    pub fn confirm(
+        &self,
+        bytes: u64,
+        properties: u64,
+        end_frame: bool,
+    ) -> Result<Completeness, String> {
+        let announced = [
+            ("Content-Length", self.content_length, bytes, "bytes"),
+            ("X-Expected-Size", self.expected_size, bytes, "bytes"),
+            (
+                "X-Expected-Properties",
+                self.expected_properties,
+                properties,
+                "complete properties",
+            ),
+        ];
+        for (header, expected, actual, unit) in announced {
+            if let Some(expected) = expected
+                && expected != actual
+            {
+                return Err(format!(
+                    "The body ended after {actual} {unit}, {header} announced {expected}"
+                ));
+            }
+        }
+
+        // The first that applies is reported
+        let confirmed = [
+            (self.content_length.is_some(), Completeness::ContentLength),
+            (end_frame, Completeness::EndFrame),
+            (self.expected_size.is_some(), Completeness::ExpectedSize),
+            (
+                self.expected_properties.is_some(),
+                Completeness::ExpectedProperties,
+            ),
+            (self.trailer, Completeness::Trailer),
+        ]
+        .into_iter()
+        .find_map(|(confirms, completeness)| confirms.then_some(completeness));
+        match confirmed {
+            Some(completeness) => Ok(completeness),
+            None if self.allow_unconfirmed => Ok(Completeness::Unconfirmed),
+            None => Err(
+                "The body ended without anything confirming it is complete, it may have been cut off: send a Content-Length, X-Expected-Size or X-Expected-Properties, end a framed body with an empty frame or end the body with the X-Stream-Complete: true trailer"
+                    .to_string(),
+            ),
+        }
+    }
+}
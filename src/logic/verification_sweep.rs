@@ -0,0 +1,355 @@
+use crate::logic::commit_hooks;
+use crate::logic::metadata_backfill::{self, Throttle};
+use crate::logic::read_stats;
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence::{self, data_file_name, version_file_path};
+use crate::persistence::integrity::{self, SweepOutcome};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::state::{AppState, StreamDb};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+/// Errors kept in the report of a cycle, later ones are only counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// What one pass over the committed versions did
+#[derive(Serialize, Clone, Default)]
+pub struct SweepCycle {
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    /// Committed versions not verified within `STREAM_DB_VERIFY_SKIP_DAYS`, or moved
+    /// between tiers since they were
+    pub versions_due: usize,
+    /// ... of which the cycle verifies `STREAM_DB_VERIFY_SAMPLE_PERCENT`
+    pub versions_planned: usize,
+    pub versions_verified: usize,
+    pub versions_quarantined: usize,
+    /// Deleted, written again or quarantined by someone else while they were hashed
+    pub versions_changed: usize,
+    /// Versions still immutable, all of which are checked for their data file every cycle
+    pub immutable_checked: usize,
+    /// ... whose data file was gone or had another size than committed
+    pub immutable_missing: usize,
+    pub bytes_hashed: u64,
+    pub error_count: usize,
+    pub errors: Vec<String>,
+}
+
+/// State of the verification sweep, served at `/admin/verification`
+#[derive(Serialize, Clone, Default)]
+pub struct SweepStatus {
+    pub enabled: bool,
+    pub bytes_per_second: u64,
+    pub sample_percent: u64,
+    pub skip_days: u64,
+    pub interval_secs: u64,
+    pub cycles_completed: u64,
+    /// The cycle running now
+    pub current_cycle: Option<SweepCycle>,
+    pub last_cycle: Option<SweepCycle>,
+    pub next_cycle_at: Option<String>,
+}
+
+/// Progress of the sweep, shared with the handlers
+#[derive(Default)]
+pub struct VerificationSweep {
+    status: Mutex<SweepStatus>,
+}
+
+impl VerificationSweep {
+    pub fn status(&self) -> SweepStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update_cycle(&self, update: impl FnOnce(&mut SweepCycle)) {
+        if let Some(cycle) = self.status.lock().unwrap().current_cycle.as_mut() {
+            update(cycle);
+        }
+    }
+
+    fn record_error(&self, error: String) {
+        println!("Verification sweep: {error}");
+        self.update_cycle(|cycle| {
+            cycle.error_count += 1;
+            if cycle.errors.len() < MAX_REPORTED_ERRORS {
+                cycle.errors.push(error);
+            }
+        });
+    }
+}
+
+/// A committed version the sweep is due to verify
+struct DueVersion {
+    item_id: String,
+    version: VersionMetadata,
+    /// Moved between tiers since it was last verified, so its copy was never hashed
+    moved: bool,
+    last_verified_at: Option<DateTime<Utc>>,
+}
+
+fn parse_timestamp(timestamp: Option<&str>) -> Option<DateTime<Utc>> {
+    timestamp
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// The versions due for verification, in the order they are verified: those moved since
+/// they were last verified, then those never verified, then the ones verified longest ago
+fn due_versions(state: &StreamDb) -> Result<Vec<DueVersion>, String> {
+    let skip_after = Utc::now() - chrono::Duration::days(state.config.verify_skip_days as i64);
+    let mut due = Vec::new();
+    for item_id in cold_tier::item_ids(&state.storage)? {
+        let metadata = file_persistence::load_item_metadata(&state.storage, &item_id)?;
+        for version in metadata.versions.into_values() {
+            // Stays quarantined until it is repaired or deleted
+            if version.quarantined_at.is_some() {
+                continue;
+            }
+            let last_verified_at = parse_timestamp(version.last_verified_at.as_deref());
+            let moved = parse_timestamp(version.moved_at.as_deref()).is_some_and(|moved_at| {
+                last_verified_at.is_none_or(|last_verified_at| moved_at > last_verified_at)
+            });
+            let recently_verified =
+                last_verified_at.is_some_and(|last_verified_at| last_verified_at > skip_after);
+            if moved || !recently_verified {
+                due.push(DueVersion {
+                    item_id: item_id.clone(),
+                    version,
+                    moved,
+                    last_verified_at,
+                });
+            }
+        }
+    }
+    due.sort_by_key(|due| (!due.moved, due.last_verified_at));
+    Ok(due)
+}
+
+/// Check that every version still immutable has its data file at the committed size,
+/// returning how many were checked and what is wrong with the others. None of them can
+/// be deleted through the API, so a missing one was removed behind the instance's back.
+fn check_immutable(state: &StreamDb) -> Result<(usize, Vec<String>), String> {
+    let now = Utc::now();
+    let problem = |item_id: &str, version: &VersionMetadata| {
+        let path = version_file_path(
+            &state.storage,
+            version.location.as_deref(),
+            &data_file_name(item_id, version.version),
+        );
+        match std::fs::metadata(&path) {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                Some("its data file is missing".to_string())
+            }
+            Err(error) => Some(format!("its data file cannot be read: {error}")),
+            Ok(found) => version
+                .size
+                .filter(|size| *size != found.len())
+                .map(|size| format!("its data file has {} bytes instead of {size}", found.len())),
+        }
+    };
+    let mut checked = 0;
+    let mut missing = Vec::new();
+    for item_id in cold_tier::item_ids(&state.storage)? {
+        let metadata = file_persistence::load_item_metadata(&state.storage, &item_id)?;
+        for version in metadata.versions.values() {
+            if !version.immutable_at(now) {
+                continue;
+            }
+            checked += 1;
+            if problem(&item_id, version).is_none() {
+                continue;
+            }
+            // A move between tiers may have switched the version's location meanwhile
+            let reloaded = file_persistence::load_item_metadata(&state.storage, &item_id)?;
+            let Some(version) = reloaded.versions.get(&version.version) else {
+                continue;
+            };
+            if let Some(problem) = problem(&item_id, version) {
+                missing.push(format!(
+                    "ALARM, item {item_id} version {} is immutable until {} but {problem}",
+                    version.version,
+                    version.immutable_until.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    Ok((checked, missing))
+}
+
+/// Hash a version no faster than `throttle` and record what was found
+fn verify(
+    state: &StreamDb,
+    due: &DueVersion,
+    throttle: &mut Throttle,
+) -> Result<(u64, SweepOutcome), String> {
+    let found = metadata_backfill::hash(state, &due.item_id, &due.version, throttle)?;
+    let outcome = integrity::record_sweep(
+        &state.storage,
+        &due.item_id,
+        due.version.version,
+        due.version.epoch.unwrap_or(0),
+        &found,
+    )
+    .map_err(|error| error.message())?;
+    Ok((found.size, outcome))
+}
+
+/// Verify the due versions picked for one cycle, reading no faster than
+/// `STREAM_DB_VERIFY_BYTES_PER_SECOND` across the whole cycle
+async fn run_cycle(state: &AppState) {
+    state.verification.status.lock().unwrap().current_cycle = Some(SweepCycle {
+        started_at: read_stats::now(),
+        ..SweepCycle::default()
+    });
+    let due = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || due_versions(&state))
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|due| due)
+    };
+    let mut due = match due {
+        Ok(due) => due,
+        Err(error) => {
+            state
+                .verification
+                .record_error(format!("Could not list the versions due: {error}"));
+            Vec::new()
+        }
+    };
+    let immutable = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || check_immutable(&state))
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|immutable| immutable)
+    };
+    match immutable {
+        Ok((checked, missing)) => {
+            state.verification.update_cycle(|cycle| {
+                cycle.immutable_checked = checked;
+                cycle.immutable_missing = missing.len();
+            });
+            for alarm in missing {
+                state.metrics.immutable_versions_missing.increment();
+                state.verification.record_error(alarm);
+            }
+        }
+        Err(error) => state
+            .verification
+            .record_error(format!("Could not check the immutable versions: {error}")),
+    }
+
+    let versions_due = due.len();
+    // Rounded up, so a small store is still verified bit by bit
+    let planned = (versions_due as u64 * state.config.verify_sample_percent).div_ceil(100);
+    due.truncate(planned as usize);
+    state.verification.update_cycle(|cycle| {
+        cycle.versions_due = versions_due;
+        cycle.versions_planned = due.len();
+    });
+
+    let bytes_per_second = state.config.live().verify_bytes_per_second;
+    state.verification.status.lock().unwrap().bytes_per_second = bytes_per_second;
+    let mut throttle = Throttle::new(bytes_per_second);
+    for due in due {
+        let (returned, verified) = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                let verified = verify(&state, &due, &mut throttle);
+                ((throttle, due), verified)
+            })
+            .await
+            .expect("Verification of a version panicked")
+        };
+        throttle = returned.0;
+        let due = returned.1;
+        let DueVersion {
+            item_id, version, ..
+        } = &due;
+        match verified {
+            Ok((size, outcome)) => {
+                state.metrics.verification_bytes_hashed.add(size);
+                state
+                    .verification
+                    .update_cycle(|cycle| cycle.bytes_hashed += size);
+                match outcome {
+                    SweepOutcome::Intact => {
+                        state.metrics.verification_versions_verified.increment();
+                        state
+                            .verification
+                            .update_cycle(|cycle| cycle.versions_verified += 1);
+                    }
+                    SweepOutcome::Quarantined(failure) => {
+                        state.metrics.verification_failures.increment();
+                        state
+                            .verification
+                            .update_cycle(|cycle| cycle.versions_quarantined += 1);
+                        commit_hooks::dispatch_quarantine(state, item_id, version, *failure);
+                    }
+                    SweepOutcome::Changed => state
+                        .verification
+                        .update_cycle(|cycle| cycle.versions_changed += 1),
+                }
+            }
+            Err(error) => state.verification.record_error(format!(
+                "Could not verify item {item_id} version {}: {error}",
+                version.version
+            )),
+        }
+    }
+
+    let mut status = state.verification.status.lock().unwrap();
+    let Some(mut cycle) = status.current_cycle.take() else {
+        return;
+    };
+    cycle.finished_at = Some(read_stats::now());
+    println!(
+        "Verification sweep verified {} of {} versions due, {} quarantined, {} of {} immutable versions missing, {} bytes hashed, {} errors",
+        cycle.versions_verified,
+        cycle.versions_due,
+        cycle.versions_quarantined,
+        cycle.immutable_missing,
+        cycle.immutable_checked,
+        cycle.bytes_hashed,
+        cycle.error_count
+    );
+    status.cycles_completed += 1;
+    status.last_cycle = Some(cycle);
+}
+
+/// Keep verifying committed versions against their checksums in the background, one
+/// cycle every `STREAM_DB_VERIFY_INTERVAL_SECS`
+pub fn start(state: AppState) {
+    {
+        let config = &state.config;
+        let mut status = state.verification.status.lock().unwrap();
+        status.bytes_per_second = config.live().verify_bytes_per_second;
+        status.sample_percent = config.verify_sample_percent;
+        status.skip_days = config.verify_skip_days;
+        status.interval_secs = config.verify_interval_secs;
+        status.enabled = config.verify_sweep;
+    }
+    if !state.config.verify_sweep {
+        return;
+    }
+    println!(
+        "Verifying {}% of the versions due every {}s at up to {} bytes per second",
+        state.config.verify_sample_percent,
+        state.config.verify_interval_secs,
+        state.config.live().verify_bytes_per_second
+    );
+
+    tokio::spawn(async move {
+        let interval = Duration::from_secs(state.config.verify_interval_secs);
+        loop {
+            run_cycle(&state).await;
+            state.verification.status.lock().unwrap().next_cycle_at =
+                Some((Utc::now() + interval).to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,174 @@
+use crate::logic::property_search;
+use crate::persistence::file_persistence::{
+    self, DeleteError, DeleteReport, ResetVersionError, ResetVersionReport, VersionState,
+};
+use crate::persistence::item_metadata::ItemMetadata;
+use crate::persistence::version_tags::{self, VersionTags};
+use crate::state::StreamDb;
+
+/// Longest tag name accepted
+const MAX_TAG_LENGTH: usize = 128;
+
+/// Why a tag could not be changed
+pub enum TagError {
+    Invalid(String),
+    /// The version a tag should point at is not committed
+    NotCommitted(String),
+    NotFound(String),
+    Failed(String),
+}
+
+/// Tags end up in URLs and file contents, so they are kept to a safe alphabet
+fn validate_tag(tag: &str) -> Result<(), TagError> {
+    let valid = !tag.is_empty()
+        && tag.len() <= MAX_TAG_LENGTH
+        && tag
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(TagError::Invalid(format!(
+            "Tag {tag:?} must be 1 to {MAX_TAG_LENGTH} letters, digits, '-', '_' or '.'"
+        )));
+    }
+    Ok(())
+}
+
+pub fn list(state: &StreamDb, item_id: &str) -> Result<VersionTags, String> {
+    version_tags::load(&state.storage, item_id)
+}
+
+/// The version `tag` currently points at
+pub fn resolve(state: &StreamDb, item_id: &str, tag: &str) -> Result<Option<u64>, String> {
+    Ok(version_tags::load(&state.storage, item_id)?
+        .tags
+        .get(tag)
+        .copied())
+}
+
+/// Point `tag` at a committed version, moving it if it already exists
+pub fn set(
+    state: &StreamDb,
+    item_id: &str,
+    tag: &str,
+    item_version: u64,
+) -> Result<VersionTags, TagError> {
+    validate_tag(tag)?;
+    let _tags_guard = state.storage.tags_lock.lock().unwrap();
+
+    match file_persistence::version_state(&state.storage, item_id, item_version)
+        .map_err(TagError::Failed)?
+    {
+        VersionState::Committed(_) => {}
+        _ => {
+            return Err(TagError::NotCommitted(format!(
+                "Version {item_version} of item {item_id} is not committed"
+            )));
+        }
+    }
+
+    let mut tags = version_tags::load(&state.storage, item_id).map_err(TagError::Failed)?;
+    tags.tags.insert(tag.to_string(), item_version);
+    version_tags::store(&state.storage, item_id, &tags).map_err(TagError::Failed)?;
+    println!("Tagged item {item_id} version {item_version} as {tag}");
+    Ok(tags)
+}
+
+pub fn remove(state: &StreamDb, item_id: &str, tag: &str) -> Result<VersionTags, TagError> {
+    let _tags_guard = state.storage.tags_lock.lock().unwrap();
+
+    let mut tags = version_tags::load(&state.storage, item_id).map_err(TagError::Failed)?;
+    if tags.tags.remove(tag).is_none() {
+        return Err(TagError::NotFound(format!(
+            "Item {item_id} has no tag {tag:?}"
+        )));
+    }
+    version_tags::store(&state.storage, item_id, &tags).map_err(TagError::Failed)?;
+    Ok(tags)
+}
+
+/// Delete a version, which is refused while tags point at it unless the instance is
+/// configured to remove those tags along with it
+pub fn delete_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+) -> Result<DeleteReport, DeleteError> {
+    let _tags_guard = state.storage.tags_lock.lock().unwrap();
+
+    let mut tags = version_tags::load(&state.storage, item_id).map_err(DeleteError::Failed)?;
+    let tagged = tags.tags_of(item_version);
+    if !tagged.is_empty() && !state.config.cascade_tag_deletes {
+        return Err(DeleteError::Tagged {
+            message: format!(
+                "Version {item_version} of item {item_id} is tagged as {}, move the tags first",
+                tagged.join(", ")
+            ),
+            tags: tagged,
+        });
+    }
+
+    let mut report = file_persistence::delete_version(&state.storage, item_id, item_version)?;
+    property_search::touch(state, item_id, item_version);
+    if !tagged.is_empty() {
+        tags.tags.retain(|_, version| *version != item_version);
+        version_tags::store(&state.storage, item_id, &tags).map_err(DeleteError::Failed)?;
+        report.tags_removed = tagged;
+    }
+    Ok(report)
+}
+
+/// Lower an item's latest version to `item_version`, recovering an item a producer
+/// pushed to an implausible version number. Versions above it that still have data are
+/// refused, unless `force` deletes them first like [`delete_version`] would.
+pub fn reset_latest_version(
+    state: &StreamDb,
+    item_id: &str,
+    item_version: u64,
+    force: bool,
+) -> Result<ResetVersionReport, ResetVersionError> {
+    let metadata = ItemMetadata::load(&file_persistence::metadata_path(&state.storage, item_id))
+        .map_err(ResetVersionError::Failed)?;
+    let Some(previous_version) = metadata.latest_version else {
+        return Err(ResetVersionError::NotFound(format!(
+            "Item {item_id} has no committed version"
+        )));
+    };
+    if previous_version <= item_version {
+        return Err(ResetVersionError::NotBelow {
+            requested: item_version,
+            current: previous_version,
+        });
+    }
+
+    let mut versions_deleted = Vec::new();
+    if force {
+        let with_data =
+            file_persistence::versions_with_data_above(&state.storage, item_id, item_version)
+                .map_err(ResetVersionError::Failed)?;
+        for version in with_data {
+            match delete_version(state, item_id, version) {
+                Ok(_) => versions_deleted.push(version),
+                Err(DeleteError::NotFound(_)) => {}
+                Err(DeleteError::Locked(error)) => return Err(ResetVersionError::Locked(error)),
+                Err(DeleteError::Tagged { message, .. }) => {
+                    return Err(ResetVersionError::Blocked {
+                        message,
+                        versions: vec![version],
+                    });
+                }
+                Err(DeleteError::Immutable(immutable)) => {
+                    return Err(ResetVersionError::Immutable(immutable));
+                }
+                Err(DeleteError::Failed(error)) => return Err(ResetVersionError::Failed(error)),
+            }
+        }
+    }
+
+    let mut report = file_persistence::reset_latest_version(&state.storage, item_id, item_version)?;
+    for version in &report.versions_dropped {
+        property_search::touch(state, item_id, *version);
+    }
+    report.previous_version = Some(previous_version);
+    report.versions_deleted = versions_deleted;
+    Ok(report)
+}
@@ -0,0 +1,197 @@
+use crate::logic::read_stats;
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence;
+use crate::state::{AppState, StreamDb};
+
+use serde::Serialize;
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+/// Errors kept in the progress report, later ones are only counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupState {
+    /// No warm-up is configured
+    #[default]
+    Disabled,
+    Warming,
+    Done,
+}
+
+/// What the startup warm-up did so far, served at `/admin/warmup`
+#[derive(Serialize, Clone, Default)]
+pub struct WarmupProgress {
+    pub state: WarmupState,
+    pub items_planned: usize,
+    pub items_done: usize,
+    pub versions_preloaded: usize,
+    pub bytes_read_ahead: u64,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    /// Why the warm-up stopped before it went through every item
+    pub stopped_early: Option<String>,
+    pub error_count: usize,
+    pub errors: Vec<String>,
+}
+
+/// Progress of the warm-up, shared with the handlers
+#[derive(Default)]
+pub struct Warmup {
+    progress: Mutex<WarmupProgress>,
+}
+
+impl Warmup {
+    pub fn progress(&self) -> WarmupProgress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut WarmupProgress)) {
+        update(&mut self.progress.lock().unwrap());
+    }
+
+    fn record_error(&self, error: String) {
+        self.update(|progress| {
+            progress.error_count += 1;
+            if progress.errors.len() < MAX_REPORTED_ERRORS {
+                progress.errors.push(error);
+            }
+        });
+    }
+}
+
+/// The configured items followed by the most read ones, without duplicates
+fn planned_items(state: &StreamDb) -> Result<Vec<String>, String> {
+    let mut items = Vec::new();
+    for item_id in &state.config.warmup_items {
+        if !items.contains(item_id) {
+            items.push(item_id.clone());
+        }
+    }
+    let Some(top_n) = state.config.warmup_top_n.filter(|top_n| *top_n > 0) else {
+        return Ok(items);
+    };
+
+    let mut by_reads = Vec::new();
+    for item_id in cold_tier::item_ids(&state.storage)? {
+        let reads = read_stats::item_stats(state, &item_id)?
+            .total()
+            .reads_started;
+        if reads > 0 {
+            by_reads.push((reads, item_id));
+        }
+    }
+    by_reads.sort_by(|(a_reads, a_id), (b_reads, b_id)| b_reads.cmp(a_reads).then(a_id.cmp(b_id)));
+    for (_, item_id) in by_reads.into_iter().take(top_n) {
+        if !items.contains(&item_id) {
+            items.push(item_id);
+        }
+    }
+    Ok(items)
+}
+
+/// Open the latest version of every planned item and read ahead of its first readers,
+/// until the byte budget runs out
+async fn warm_up(state: &AppState) {
+    let planned = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || planned_items(&state))
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|planned| planned)
+    };
+    let planned = match planned {
+        Ok(planned) => planned,
+        Err(error) => {
+            state
+                .warmup
+                .record_error(format!("Could not pick items to warm up: {error}"));
+            return;
+        }
+    };
+    state
+        .warmup
+        .update(|progress| progress.items_planned = planned.len());
+
+    let mut budget = state.config.warmup_max_bytes;
+    for item_id in planned {
+        let preloaded = {
+            let storage = state.storage.clone();
+            let item_id = item_id.clone();
+            tokio::task::spawn_blocking(move || {
+                file_persistence::preload_latest(&storage, &item_id)
+            })
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|preloaded| preloaded)
+        };
+        match preloaded {
+            Ok(Some((item_version, shared_file))) => {
+                state.metrics.warmup_versions_preloaded.increment();
+                state
+                    .warmup
+                    .update(|progress| progress.versions_preloaded += 1);
+                let read_ahead = state.config.warmup_readahead_bytes.min(budget);
+                if read_ahead > 0 {
+                    match file_persistence::read_ahead(&shared_file, read_ahead).await {
+                        Ok(bytes_read) => {
+                            budget -= bytes_read;
+                            state.metrics.warmup_bytes_read_ahead.add(bytes_read);
+                            state
+                                .warmup
+                                .update(|progress| progress.bytes_read_ahead += bytes_read);
+                        }
+                        Err(error) => state.warmup.record_error(format!(
+                            "Could not read ahead item {item_id} version {item_version}: {error}"
+                        )),
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(error) => state
+                .warmup
+                .record_error(format!("Could not warm up item {item_id}: {error}")),
+        }
+        state.warmup.update(|progress| progress.items_done += 1);
+    }
+}
+
+/// Warm up the configured items in the background while requests are already served.
+/// `/health` reports `warming` until it is done.
+pub fn start(state: AppState) {
+    if state.config.warmup_items.is_empty() && state.config.warmup_top_n.is_none() {
+        return;
+    }
+    state.warmup.update(|progress| {
+        progress.state = WarmupState::Warming;
+        progress.started_at = Some(read_stats::now());
+    });
+    println!("Warming up items in the background");
+
+    tokio::spawn(async move {
+        let max_duration = Duration::from_secs(state.config.warmup_max_secs);
+        let timed_out = tokio::time::timeout(max_duration, warm_up(&state))
+            .await
+            .is_err();
+        state.warmup.update(|progress| {
+            progress.state = WarmupState::Done;
+            progress.finished_at = Some(read_stats::now());
+            if timed_out {
+                progress.stopped_early = Some(format!(
+                    "Time budget of {} seconds exhausted",
+                    max_duration.as_secs()
+                ));
+            }
+        });
+        let progress = state.warmup.progress();
+        println!(
+            "Warm-up done: {} of {} items, {} versions preloaded, {} bytes read ahead, {} errors",
+            progress.items_done,
+            progress.items_planned,
+            progress.versions_preloaded,
+            progress.bytes_read_ahead,
+            progress.error_count
+        );
+    });
+}
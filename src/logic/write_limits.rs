@@ -0,0 +1,226 @@
+use crate::config::Config;
+use crate::logic::property_element::{PROPERTY_START_TAG, property_name};
+use crate::persistence::item_settings::ItemSettings;
+
+use serde::{Deserialize, Serialize};
+
+/// Limits enforced on a single upload, resolved from the server configuration and the
+/// item's own settings. `None` means unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct WriteLimits {
+    pub max_property_bytes: Option<u64>,
+    pub max_properties_per_item: Option<u64>,
+    pub max_item_bytes: Option<u64>,
+    /// Ceiling of uploads whose properties are sorted at commit, see
+    /// `STREAM_DB_CANONICALIZE_MAX_BYTES`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_canonicalize_bytes: Option<u64>,
+}
+
+/// A limit an upload ran into
+#[derive(Serialize, Clone)]
+pub struct LimitViolation {
+    /// Name of the limit, as in [`WriteLimits`]
+    pub limit: &'static str,
+    pub max: u64,
+    /// What the upload reached, a lower bound for properties still streaming in
+    pub actual: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property: Option<String>,
+    #[serde(skip)]
+    pub message: String,
+}
+
+impl WriteLimits {
+    pub fn resolve(settings: &ItemSettings, config: &Config) -> Self {
+        let config = config.live();
+        Self {
+            max_property_bytes: settings.max_property_bytes.or(config.max_property_bytes),
+            max_properties_per_item: settings
+                .max_properties_per_item
+                .or(config.max_properties_per_item),
+            max_item_bytes: settings.max_item_bytes.or(config.max_item_bytes),
+            max_canonicalize_bytes: None,
+        }
+    }
+
+    /// Check a complete property element that is about to become property number
+    /// `property_number` (1-based) of the item
+    pub fn check_property(
+        &self,
+        element: &str,
+        property_number: u64,
+    ) -> Result<(), LimitViolation> {
+        if let Some(max_properties) = self.max_properties_per_item
+            && property_number > max_properties
+        {
+            let property = property_name(element);
+            let name = property.as_deref().unwrap_or("<unnamed>");
+            let message = format!(
+                "Property {name:?} is property {property_number}, exceeding the limit of {max_properties} properties"
+            );
+            return Err(LimitViolation {
+                limit: "max_properties_per_item",
+                max: max_properties,
+                actual: property_number,
+                property,
+                message,
+            });
+        }
+        self.check_partial_property(element)
+    }
+
+    /// Check the number of bytes uploaded so far, or announced up front by the client
+    pub fn check_item_bytes(&self, bytes: u64) -> Result<(), LimitViolation> {
+        if let Some(max_bytes) = self.max_item_bytes
+            && bytes > max_bytes
+        {
+            return Err(LimitViolation {
+                limit: "max_item_bytes",
+                max: max_bytes,
+                actual: bytes,
+                property: None,
+                message: format!("Item is {bytes} bytes, exceeding the limit of {max_bytes} bytes"),
+            });
+        }
+        if let Some(max_bytes) = self.max_canonicalize_bytes
+            && bytes > max_bytes
+        {
+            return Err(LimitViolation {
+                limit: "max_canonicalize_bytes",
+                max: max_bytes,
+                actual: bytes,
+                property: None,
+                message: format!(
+                    "Item is {bytes} bytes, too large to sort its properties (limit {max_bytes} bytes)"
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the bytes of a property that has not been closed yet, so an oversized
+    /// property is rejected as soon as it crosses the limit instead of once it ends
+    pub fn check_partial_property(&self, buffered: &str) -> Result<(), LimitViolation> {
+        let Some(max_bytes) = self.max_property_bytes else {
+            return Ok(());
+        };
+        let Some(start) = buffered.find(PROPERTY_START_TAG) else {
+            return Ok(());
+        };
+        let observed_bytes = (buffered.len() - start) as u64;
+        if observed_bytes > max_bytes {
+            let property = property_name(&buffered[start..]);
+            let name = property.as_deref().unwrap_or("<unnamed>");
+            let message = format!(
+                "Property {name:?} is at least {observed_bytes} bytes, exceeding the limit of {max_bytes} bytes"
+            );
+            return Err(LimitViolation {
+                limit: "max_property_bytes",
+                max: max_bytes,
+                actual: observed_bytes,
+                property,
+                message,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::property_element::PROPERTY_END_TAG;
+
+    fn limits(max_property_bytes: Option<u64>, max_properties: Option<u64>) -> WriteLimits {
+        WriteLimits {
+            max_property_bytes,
+            max_properties_per_item: max_properties,
+            max_item_bytes: None,
+            max_canonicalize_bytes: None,
+        }
+    }
+
+    fn property(name: &str, value: &str) -> String {
+        format!(r#"<property name="{name}" type="string">{value}</property>"#)
+    }
+
+    #[test]
+    fn a_property_at_the_byte_limit_passes_and_one_byte_more_fails() {
+        let element = property("p", "value");
+        let size = element.len() as u64;
+        assert!(limits(Some(size), None).check_property(&element, 1).is_ok());
+
+        let longer = property("p", "value!");
+        let violation = limits(Some(size), None)
+            .check_property(&longer, 1)
+            .unwrap_err();
+        assert_eq!(violation.limit, "max_property_bytes");
+        assert_eq!((violation.max, violation.actual), (size, size + 1));
+        assert_eq!(violation.property.as_deref(), Some("p"));
+        assert!(
+            violation.message.contains(&format!("{}", size + 1)),
+            "{}",
+            violation.message
+        );
+    }
+
+    #[test]
+    fn a_partial_property_is_measured_from_its_start_tag() {
+        let element = property("p", "value");
+        let size = element.len() as u64;
+        let open = &element[..element.len() - PROPERTY_END_TAG.len()];
+        // Whatever precedes the property, as the rest of an earlier chunk, does not count
+        let buffered = format!("</property>\n  {open}");
+        assert!(
+            limits(Some(open.len() as u64), None)
+                .check_partial_property(&buffered)
+                .is_ok()
+        );
+        let violation = limits(Some(open.len() as u64 - 1), None)
+            .check_partial_property(&buffered)
+            .unwrap_err();
+        assert_eq!(violation.actual, open.len() as u64);
+        assert_eq!(violation.property.as_deref(), Some("p"));
+        // Nothing to measure before a property starts
+        assert!(
+            limits(Some(1), None)
+                .check_partial_property("</property>\n")
+                .is_ok()
+        );
+        assert!(
+            limits(Some(size), None)
+                .check_partial_property(&element)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn the_property_at_the_count_limit_passes_and_the_next_fails() {
+        let element = property("p3", "value");
+        assert!(limits(None, Some(3)).check_property(&element, 3).is_ok());
+
+        let violation = limits(None, Some(3))
+            .check_property(&element, 4)
+            .unwrap_err();
+        assert_eq!(violation.limit, "max_properties_per_item");
+        assert_eq!((violation.max, violation.actual), (3, 4));
+        assert_eq!(violation.property.as_deref(), Some("p3"));
+        assert!(
+            violation.message.contains("\"p3\""),
+            "{}",
+            violation.message
+        );
+    }
+
+    #[test]
+    fn no_limits_accept_anything() {
+        let element = property("p", &"x".repeat(10_000));
+        assert!(
+            limits(None, None)
+                .check_property(&element, u64::MAX)
+                .is_ok()
+        );
+        assert!(limits(None, None).check_item_bytes(u64::MAX).is_ok());
+    }
+}
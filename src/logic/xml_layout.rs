@@ -0,0 +1,293 @@
+use crate::logic::property_alignment::PropertySplitter;
+use crate::persistence::item_persistence::ItemStreamReader;
+
+use async_trait::async_trait;
+use quick_xml::Reader;
+use quick_xml::Writer;
+use quick_xml::errors::IllFormedError;
+use quick_xml::events::{BytesEnd, BytesStart, Event};
+
+const INDENT: &[u8] = b"  ";
+
+/// How the XML a reader receives is laid out
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum XmlLayout {
+    /// The stored bytes as they are
+    #[default]
+    Verbatim,
+    /// Every element on its own line, indented by its depth
+    Pretty,
+    /// Without whitespace between elements or inside tags
+    Minified,
+}
+
+impl XmlLayout {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "verbatim" => Ok(Self::Verbatim),
+            "pretty" => Ok(Self::Pretty),
+            "minified" => Ok(Self::Minified),
+            other => Err(format!("Unsupported XML layout {other:?}")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Verbatim => "verbatim",
+            Self::Pretty => "pretty",
+            Self::Minified => "minified",
+        }
+    }
+}
+
+/// Reader wrapper re-laying out every complete property as it streams by. Only the
+/// property currently being read is held in memory. Whitespace is only added or removed
+/// between elements of elements holding nothing but elements, so text content, including
+/// whitespace-only values and mixed content, is passed on exactly as stored.
+pub struct XmlLayoutReader {
+    inner: Box<dyn ItemStreamReader>,
+    splitter: PropertySplitter,
+    formatter: XmlFormatter,
+    inner_finished: bool,
+}
+
+impl XmlLayoutReader {
+    pub fn new(
+        inner: Box<dyn ItemStreamReader>,
+        layout: XmlLayout,
+        max_property_bytes: usize,
+    ) -> Self {
+        Self {
+            inner,
+            splitter: PropertySplitter::new(max_property_bytes),
+            formatter: XmlFormatter {
+                layout,
+                open_elements: 0,
+                started: false,
+            },
+            inner_finished: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for XmlLayoutReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        while !self.inner_finished {
+            match self.inner.read_chunk().await? {
+                Some(chunk) => {
+                    let segments = self.splitter.push(&chunk).map_err(|error| {
+                        format!("{error} for xml={}", self.formatter.layout.name())
+                    })?;
+                    let mut output = Vec::new();
+                    for segment in segments {
+                        self.formatter.format(&segment, &mut output);
+                    }
+                    if !output.is_empty() {
+                        return Ok(Some(output));
+                    }
+                }
+                None => {
+                    self.inner_finished = true;
+                    let mut output = Vec::new();
+                    self.formatter
+                        .format(self.splitter.remainder(), &mut output);
+                    if self.formatter.layout == XmlLayout::Pretty && self.formatter.started {
+                        output.push(b'\n');
+                    }
+                    return Ok((!output.is_empty()).then_some(output));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+struct XmlFormatter {
+    layout: XmlLayout,
+    /// Elements opened by earlier segments and not closed yet, such as the `<item>`
+    /// envelope, which only ever hold properties
+    open_elements: usize,
+    /// Whether anything was written, so the output does not start with a line break
+    started: bool,
+}
+
+impl XmlFormatter {
+    /// Append `segment` laid out to `output`. A segment quick-xml cannot parse is passed
+    /// on as stored.
+    fn format(&mut self, segment: &[u8], output: &mut Vec<u8>) {
+        let Ok(events) = parse(segment) else {
+            output.extend_from_slice(segment);
+            self.started |= !segment.is_empty();
+            return;
+        };
+        let element_only = element_only(&events);
+
+        let mut writer = Writer::new(std::mem::take(output));
+        // Whether each element opened in this segment holds nothing but elements
+        let mut open: Vec<bool> = Vec::new();
+        for (position, event) in events.into_iter().enumerate() {
+            // Elements opened by earlier segments and the document itself only hold
+            // elements and whitespace between them
+            let context_element_only = open.last().copied().unwrap_or(true);
+            let depth = self.open_elements + open.len();
+            match event {
+                Event::Text(text) if text.iter().all(u8::is_ascii_whitespace) => {
+                    if !context_element_only {
+                        self.write(&mut writer, Event::Text(text));
+                    }
+                }
+                Event::Start(tag) => {
+                    if context_element_only {
+                        self.line_break(&mut writer, depth);
+                    }
+                    open.push(element_only[position]);
+                    let tag = self.tag(tag);
+                    self.write(&mut writer, Event::Start(tag));
+                }
+                Event::Empty(tag) => {
+                    if context_element_only {
+                        self.line_break(&mut writer, depth);
+                    }
+                    let tag = self.tag(tag);
+                    self.write(&mut writer, Event::Empty(tag));
+                }
+                Event::End(tag) => {
+                    let closes_element_only = match open.pop() {
+                        Some(element_only) => element_only,
+                        None => {
+                            self.open_elements = self.open_elements.saturating_sub(1);
+                            true
+                        }
+                    };
+                    if closes_element_only {
+                        self.line_break(&mut writer, depth.saturating_sub(1));
+                    }
+                    let tag = match self.layout {
+                        XmlLayout::Minified => {
+                            BytesEnd::new(String::from_utf8_lossy(tag.name().as_ref()).into_owned())
+                        }
+                        _ => tag,
+                    };
+                    self.write(&mut writer, Event::End(tag));
+                }
+                event @ (Event::Comment(_) | Event::PI(_) | Event::Decl(_) | Event::DocType(_)) => {
+                    if context_element_only {
+                        self.line_break(&mut writer, depth);
+                    }
+                    self.write(&mut writer, event);
+                }
+                Event::Eof => break,
+                event => self.write(&mut writer, event),
+            }
+        }
+        self.open_elements += open.len();
+        *output = writer.into_inner();
+    }
+
+    fn write(&mut self, writer: &mut Writer<Vec<u8>>, event: Event) {
+        // Writing to memory cannot fail
+        let _ = writer.write_event(event);
+        self.started = true;
+    }
+
+    /// Start a new line at `depth` when pretty printing, unless nothing was written yet
+    fn line_break(&self, writer: &mut Writer<Vec<u8>>, depth: usize) {
+        if self.layout == XmlLayout::Pretty && self.started {
+            let output = writer.get_mut();
+            output.push(b'\n');
+            for _ in 0..depth {
+                output.extend_from_slice(INDENT);
+            }
+        }
+    }
+
+    /// `tag` with single spaces between its name and attributes when minifying. Values
+    /// are kept as stored, escaped or not.
+    fn tag<'a>(&self, tag: BytesStart<'a>) -> BytesStart<'a> {
+        if self.layout != XmlLayout::Minified {
+            return tag;
+        }
+        let name = String::from_utf8_lossy(tag.name().as_ref()).into_owned();
+        let mut content = name.clone();
+        for attribute in tag.attributes().with_checks(false) {
+            let Ok(attribute) = attribute else {
+                return tag;
+            };
+            let value = String::from_utf8_lossy(&attribute.value);
+            let quote = if value.contains('"') { '\'' } else { '"' };
+            content.push_str(&format!(
+                " {}={quote}{value}{quote}",
+                String::from_utf8_lossy(attribute.key.as_ref())
+            ));
+        }
+        BytesStart::from_content(content, name.len())
+    }
+}
+
+fn parse(segment: &[u8]) -> Result<Vec<Event<'static>>, quick_xml::Error> {
+    let mut reader = Reader::from_reader(segment);
+    reader.config_mut().check_end_names = false;
+    let mut events = Vec::new();
+    let mut buffer = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Eof) => return Ok(events),
+            Ok(event) => events.push(event.into_owned()),
+            // A segment may close the envelope opened by an earlier one, the reader
+            // carries on after the tag
+            Err(quick_xml::Error::IllFormed(IllFormedError::UnmatchedEndTag(name))) => {
+                events.push(Event::End(BytesEnd::new(name)));
+            }
+            Err(error) => return Err(error),
+        }
+        buffer.clear();
+    }
+}
+
+/// For every event, whether it starts an element holding elements and no text. Elements
+/// not closed within `events` are taken to only hold properties.
+fn element_only(events: &[Event]) -> Vec<bool> {
+    let mut element_only = vec![false; events.len()];
+    // Position of every open element's start, with whether it has elements and text
+    let mut open: Vec<(usize, bool, bool)> = Vec::new();
+    for (position, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(_) => {
+                if let Some(parent) = open.last_mut() {
+                    parent.1 = true;
+                }
+                open.push((position, false, false));
+            }
+            Event::Empty(_) => {
+                if let Some(parent) = open.last_mut() {
+                    parent.1 = true;
+                }
+            }
+            Event::End(_) => {
+                if let Some((start, has_elements, has_text)) = open.pop() {
+                    element_only[start] = has_elements && !has_text;
+                }
+            }
+            Event::Text(text) if text.iter().all(u8::is_ascii_whitespace) => {}
+            Event::Text(_) | Event::CData(_) | Event::GeneralRef(_) => {
+                if let Some(parent) = open.last_mut() {
+                    parent.2 = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    for (start, _, _) in open {
+        element_only[start] = true;
+    }
+    element_only
+}
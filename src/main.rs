@@ -1,47 +1,102 @@
-mod api;
-mod component;
-mod logic;
-mod persistence;
-mod types;
-
-use api::write_item_stream_api;
-
-use axum::{
-    Router,
-    body::Body,
-    extract::Path,
-    http::Request,
-    routing::{get, post},
-};
-
-use crate::api::read_item_stream_api;
+use stream_db::api::{read_item_stream_api, router, write_item_stream_api};
+use stream_db::component::item_stream_component;
+use stream_db::config::Config;
+use stream_db::state::StreamDb;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    write_item_stream_api::init()
+    let reload_log_filter = init_tracing();
+    let allow_unknown = std::env::args().any(|arg| arg == "--allow-unknown-config");
+    let config =
+        Config::load(allow_unknown).map_err(|error| format!("Could not load config: {error}"))?;
+    config.log_settings();
+    let state = StreamDb::new(config);
+    state.log.reload_filter_with(reload_log_filter);
+    if std::env::args().any(|arg| arg == "--check-migrations") {
+        return check_migrations(&state);
+    }
+    write_item_stream_api::init(&state)
         .map_err(|error| format!("Could not initialize write item stream api: {:?}", error))?;
-    read_item_stream_api::init()
+    read_item_stream_api::init(&state)
         .map_err(|error| format!("Could not initialize read item stream api: {:?}", error))?;
 
-    let app = Router::new()
-        .route(
-            "/write-item-stream/{item_id}/{version}",
-            post(
-                |path: Path<(String, u64)>, request: Request<Body>| async move {
-                    write_item_stream_api::write_item_stream(path.0.0, path.0.1, request).await
-                },
-            ),
-        )
-        .route(
-            "/read-item-stream/{item_id}/{version}",
-            get(|path: Path<(String, u64)>| async move {
-                read_item_stream_api::read_item_stream(path.0.0, path.0.1).await
-            }),
-        );
+    item_stream_component::check_open_file_limits(&state);
+    item_stream_component::start_background_tasks(&state);
+
+    let app = router::app(state.clone());
+
+    if let Some(s3_addr) = state.config.s3_addr.clone() {
+        let s3_listener = tokio::net::TcpListener::bind(&s3_addr).await?;
+        println!("S3 API listening on http://{s3_addr}");
+        tokio::spawn(axum::serve(s3_listener, router::s3_app(state.clone())).into_future());
+    }
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     println!("Server listening on http://0.0.0.0:3000");
 
-    axum::serve(listener, app).await?;
+    // Keeps serving while draining, so streams can finish and `/admin/drain` answers
+    tokio::select! {
+        served = axum::serve(listener, app) => served?,
+        _ = item_stream_component::drain_on_shutdown(&state) => {}
+    }
     Ok(())
 }
+
+/// Install the subscriber writing the debug lines of streams, behind a filter the
+/// instance's log settings swap through the returned function. With the `tokio-console`
+/// feature it also serves the runtime's tasks to tokio-console, on 127.0.0.1:6669 unless
+/// TOKIO_CONSOLE_BIND says otherwise. Tokio only reports its tasks when built with
+/// `--cfg tokio_unstable`, without it there is nothing to serve.
+fn init_tracing() -> impl Fn(Targets) + Send + Sync + 'static {
+    let (filter, handle) = reload::Layer::new(Targets::new());
+    let subscriber =
+        tracing_subscriber::registry().with(fmt::layer().with_ansi(false).with_filter(filter));
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+    #[cfg(all(feature = "tokio-console", not(tokio_unstable)))]
+    println!("Not serving tokio-console, it needs a build with RUSTFLAGS=\"--cfg tokio_unstable\"");
+    subscriber.init();
+    move |targets| {
+        if let Err(error) = handle.reload(targets) {
+            println!("Could not change the log filter: {error}");
+        }
+    }
+}
+
+/// Report the migrations the data directory needs without running them. Exits with 1 if
+/// there are any, so deploy scripts can tell.
+fn check_migrations(state: &StreamDb) -> Result<(), Box<dyn std::error::Error>> {
+    let status = item_stream_component::store_format(state)
+        .map_err(|error| format!("Could not check the store format: {error}"))?;
+    match status.format {
+        None => println!(
+            "{} holds no store yet, it will be created in format {}",
+            status.data_dir, status.supported
+        ),
+        Some(format) if format > status.supported => {
+            return Err(format!(
+                "{} has format {format}, this binary only supports formats up to {}",
+                status.data_dir, status.supported
+            )
+            .into());
+        }
+        Some(format) => println!(
+            "{} has format {format}, this binary supports format {}",
+            status.data_dir, status.supported
+        ),
+    }
+    if status.pending.is_empty() {
+        println!("No migrations pending");
+        return Ok(());
+    }
+    for migration in &status.pending {
+        println!(
+            "Pending migration to format {} ({}): {}",
+            migration.format, migration.name, migration.description
+        );
+    }
+    std::process::exit(1);
+}
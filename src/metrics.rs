@@ -0,0 +1,687 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Dataset of the items no configured prefix matches
+const OTHER_DATASET: &str = "other";
+
+/// Dataset counted once a metric reached the most label values it may have
+const OVERFLOW_DATASET: &str = "__overflow";
+
+/// Monotonic counter exposed on `/metrics`
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, output: &mut String) {
+        output.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n",
+            name = self.name,
+            help = self.help,
+            value = self.get(),
+        ));
+    }
+}
+
+/// Value going up and down exposed on `/metrics`
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, amount: u64) {
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_sub(amount))
+            });
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, output: &mut String) {
+        output.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n",
+            name = self.name,
+            help = self.help,
+            value = self.get(),
+        ));
+    }
+}
+
+/// Counter broken down by the dataset of the items it counts, see [`MetricDatasets`].
+/// Label values are dataset names, never item IDs, and each counter takes at most
+/// `STREAM_DB_METRIC_LABEL_VALUES_MAX` of them, counting the rest under `__overflow`.
+pub struct DatasetCounter {
+    name: &'static str,
+    help: &'static str,
+    values: RwLock<BTreeMap<String, AtomicU64>>,
+}
+
+impl DatasetCounter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count `amount` for `dataset`, or for `__overflow` once `max_label_values` other
+    /// datasets were counted, which is returned as true
+    fn add(&self, dataset: String, amount: u64, max_label_values: usize) -> bool {
+        if let Some(value) = self.values.read().unwrap().get(dataset.as_str()) {
+            value.fetch_add(amount, Ordering::Relaxed);
+            return false;
+        }
+        let mut values = self.values.write().unwrap();
+        let distinct = values
+            .keys()
+            .filter(|value| *value != OVERFLOW_DATASET)
+            .count();
+        let overflowed = !values.contains_key(dataset.as_str()) && distinct >= max_label_values;
+        let dataset = if overflowed {
+            OVERFLOW_DATASET.to_string()
+        } else {
+            dataset
+        };
+        values
+            .entry(dataset)
+            .or_default()
+            .fetch_add(amount, Ordering::Relaxed);
+        overflowed
+    }
+
+    fn render(&self, output: &mut String) {
+        output.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} counter\n",
+            name = self.name,
+            help = self.help,
+        ));
+        for (dataset, value) in self.values.read().unwrap().iter() {
+            output.push_str(&format!(
+                "{}{{dataset=\"{dataset}\"}} {}\n",
+                self.name,
+                value.load(Ordering::Relaxed)
+            ));
+        }
+    }
+}
+
+/// Named groups of items the per-dataset metrics are broken down by, each made of the
+/// items whose ID starts with one of its prefixes. An item matching several prefixes
+/// belongs to the longest one's dataset, one matching none to `other`.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct MetricDatasets {
+    /// Prefix and dataset name, longest prefix first
+    prefixes: Vec<(String, String)>,
+    /// Distinct datasets a metric takes, besides `__overflow`
+    pub max_label_values: usize,
+}
+
+impl MetricDatasets {
+    /// Datasets from `dataset=prefix` entries, a dataset may be listed with several
+    /// prefixes
+    pub fn parse(entries: &[String], max_label_values: usize) -> Result<Self, String> {
+        let mut prefixes = Vec::new();
+        for entry in entries {
+            let Some((dataset, prefix)) = entry.split_once('=') else {
+                return Err(format!("Expected dataset=prefix, not {entry:?}"));
+            };
+            let (dataset, prefix) = (dataset.trim(), prefix.trim());
+            if dataset.is_empty()
+                || !dataset
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '-'))
+            {
+                return Err(format!(
+                    "Dataset names may only hold letters, digits, _ and -, not {dataset:?}"
+                ));
+            }
+            if dataset == OTHER_DATASET || dataset.starts_with("__") {
+                return Err(format!("Dataset name {dataset:?} is reserved"));
+            }
+            if prefix.is_empty() {
+                return Err(format!("Dataset {dataset:?} needs a prefix"));
+            }
+            if prefixes.iter().any(|(listed, _)| listed == prefix) {
+                return Err(format!("Prefix {prefix:?} is listed twice"));
+            }
+            prefixes.push((prefix.to_string(), dataset.to_string()));
+        }
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(Self {
+            prefixes,
+            max_label_values,
+        })
+    }
+
+    fn dataset_of(&self, item_id: &str) -> String {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| item_id.starts_with(prefix.as_str()))
+            .map_or(OTHER_DATASET, |(_, dataset)| dataset.as_str())
+            .to_string()
+    }
+}
+
+/// Most buckets a histogram may have, besides the `+Inf` one
+const MAX_BUCKETS: usize = 16;
+
+/// Distribution of observed values exposed on `/metrics`, counted in buckets with the
+/// upper bounds `bounds`, ascending
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [u64],
+    /// Observations per bucket, not cumulative: each falls into the first bucket whose
+    /// bound it does not exceed, the last slot past `bounds` holding those above all
+    buckets: [AtomicU64; MAX_BUCKETS + 1],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, bounds: &'static [u64]) -> Self {
+        assert!(bounds.len() <= MAX_BUCKETS);
+        Self {
+            name,
+            help,
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; MAX_BUCKETS + 1],
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String) {
+        output.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} histogram\n",
+            name = self.name,
+            help = self.help,
+        ));
+        let mut count = 0;
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+            count += bucket.load(Ordering::Relaxed);
+            output.push_str(&format!("{}_bucket{{le=\"{bound}\"}} {count}\n", self.name));
+        }
+        count += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        output.push_str(&format!(
+            "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}\n",
+            name = self.name,
+            sum = self.sum.load(Ordering::Relaxed),
+        ));
+    }
+}
+
+/// Bounds of the byte histograms, 1 KiB to 16 GiB
+const BYTE_BOUNDS: &[u64] = &[
+    1 << 10,
+    16 << 10,
+    256 << 10,
+    1 << 20,
+    16 << 20,
+    256 << 20,
+    1 << 30,
+    16 << 30,
+];
+
+/// Declares [`Metrics`] with one field per metric, rendered in the order listed. Histograms
+/// take their bucket bounds after the help text.
+macro_rules! metrics {
+    ($($field:ident: $kind:ident($name:literal, $help:literal $(, $bounds:expr)?),)*) => {
+        /// The metrics of one instance, exposed on its `/metrics` endpoint
+        pub struct Metrics {
+            /// What the per-dataset metrics are broken down by
+            datasets: RwLock<MetricDatasets>,
+            $(pub $field: $kind,)*
+        }
+
+        impl Metrics {
+            pub fn new() -> Self {
+                Self {
+                    datasets: RwLock::default(),
+                    $($field: $kind::new($name, $help $(, $bounds)?),)*
+                }
+            }
+
+            /// All metrics in the Prometheus text exposition format
+            pub fn render(&self) -> String {
+                let mut output = String::new();
+                $(self.$field.render(&mut output);)*
+                output
+            }
+        }
+    };
+}
+
+metrics! {
+    property_seeks_block_index: Counter(
+        "stream_db_property_seeks_block_index_total",
+        "Property seeks positioned through a block index sidecar"
+    ),
+    property_seeks_property_index: Counter(
+        "stream_db_property_seeks_property_index_total",
+        "Property seeks positioned through a property index"
+    ),
+    property_seeks_scan: Counter(
+        "stream_db_property_seeks_scan_total",
+        "Property seeks that had to scan the item from the start"
+    ),
+    reindex_completed: Counter(
+        "stream_db_reindex_completed_total",
+        "Block indexes built for committed versions"
+    ),
+    reindex_skipped: Counter(
+        "stream_db_reindex_skipped_total",
+        "Reindex requests that found nothing to do"
+    ),
+    reindex_bytes_scanned: Counter(
+        "stream_db_reindex_bytes_scanned_total",
+        "Bytes of committed data files scanned while building block indexes"
+    ),
+    reader_registry_hits: Counter(
+        "stream_db_reader_registry_hits_total",
+        "Readers that found their version already open in the registry"
+    ),
+    reader_disk_opens: Counter(
+        "stream_db_reader_disk_opens_total",
+        "Readers that had to open their version from disk"
+    ),
+    reader_opens_collapsed: Counter(
+        "stream_db_reader_opens_collapsed_total",
+        "Readers that took the version a concurrent reader was opening from disk instead of opening it too"
+    ),
+    negative_cache_hits: Counter(
+        "stream_db_negative_cache_hits_total",
+        "Reads answered with 404 because the version was found missing moments before"
+    ),
+    deletes_deferred: Counter(
+        "stream_db_deletes_deferred_total",
+        "Deleted or moved versions whose files were kept until their readers finished"
+    ),
+    reads_served_registry: Counter(
+        "stream_db_reads_served_registry_total",
+        "Reads whose version was found in the registry"
+    ),
+    reads_served_hot: Counter(
+        "stream_db_reads_served_hot_total",
+        "Reads whose version was opened from the data directory"
+    ),
+    reads_served_cold: Counter(
+        "stream_db_reads_served_cold_total",
+        "Reads whose version was opened from the cold tier"
+    ),
+    read_source_failures: Counter(
+        "stream_db_read_source_failures_total",
+        "Opens of a version that failed on one read source, after which the next was tried"
+    ),
+    read_source_budget_exhausted: Counter(
+        "stream_db_read_source_budget_exhausted_total",
+        "Reads that gave up with 503 because no read source served them within the budget"
+    ),
+    read_defaults_applied: Counter(
+        "stream_db_read_defaults_applied_total",
+        "Schema defaults sent in place of properties missing from the versions read"
+    ),
+    warmup_versions_preloaded: Counter(
+        "stream_db_warmup_versions_preloaded_total",
+        "Versions opened by the startup warm-up"
+    ),
+    warmup_bytes_read_ahead: Counter(
+        "stream_db_warmup_bytes_read_ahead_total",
+        "Bytes read ahead by the startup warm-up"
+    ),
+    write_accounting_mismatches: Counter(
+        "stream_db_write_accounting_mismatches_total",
+        "Commits refused because the bytes received, written and stored disagreed"
+    ),
+    commit_bytes_ingested: Counter(
+        "stream_db_commit_bytes_ingested_total",
+        "Bytes received by the uploads that committed"
+    ),
+    commit_bytes_rewritten: Counter(
+        "stream_db_commit_bytes_rewritten_total",
+        "Bytes written again by commit-time transforms, over the bytes ingested it is the write amplification"
+    ),
+    commit_rewrites: Counter(
+        "stream_db_commit_rewrites_total",
+        "Passes over the data of an upload rewriting it for its commit-time transforms"
+    ),
+    read_accounting_mismatches: Counter(
+        "stream_db_read_accounting_mismatches_total",
+        "Reads of committed versions that ended without returning every byte"
+    ),
+    read_first_bytes: Counter(
+        "stream_db_read_first_bytes_total",
+        "Reads that sent their first byte of data"
+    ),
+    read_time_to_first_byte_milliseconds: Counter(
+        "stream_db_read_time_to_first_byte_milliseconds_total",
+        "Milliseconds reads waited from their start for their first byte of data"
+    ),
+    io_fast_operations: Counter(
+        "stream_db_io_fast_operations_total",
+        "Upload I/O operations scheduled in the fast lane"
+    ),
+    io_fast_wait_milliseconds: Counter(
+        "stream_db_io_fast_wait_milliseconds_total",
+        "Milliseconds fast lane operations waited for a slot"
+    ),
+    io_heavy_operations: Counter(
+        "stream_db_io_heavy_operations_total",
+        "Upload I/O operations scheduled in the heavy lane"
+    ),
+    io_heavy_wait_milliseconds: Counter(
+        "stream_db_io_heavy_wait_milliseconds_total",
+        "Milliseconds heavy lane operations waited for a slot"
+    ),
+    io_fast_queue_depth: Gauge(
+        "stream_db_io_fast_queue_depth",
+        "Operations waiting for a slot in the fast lane"
+    ),
+    io_heavy_queue_depth: Gauge(
+        "stream_db_io_heavy_queue_depth",
+        "Operations waiting for a slot in the heavy lane"
+    ),
+    versions_quarantined: Counter(
+        "stream_db_versions_quarantined_total",
+        "Versions quarantined because their data no longer matched their checksum"
+    ),
+    quarantines_lifted: Counter(
+        "stream_db_quarantines_lifted_total",
+        "Quarantined versions served again after they were verified intact"
+    ),
+    idle_files_closed: Counter(
+        "stream_db_idle_files_closed_total",
+        "Idle versions closed to keep the open file handles below STREAM_DB_MAX_OPEN_FILES"
+    ),
+    streams_rejected_open_files: Counter(
+        "stream_db_streams_rejected_open_files_total",
+        "Requests refused because STREAM_DB_MAX_OPEN_FILES was reached"
+    ),
+    open_files: Gauge(
+        "stream_db_open_files",
+        "File handles kept open by the persistence layer"
+    ),
+    deferred_deletes_pending: Gauge(
+        "stream_db_deferred_deletes_pending",
+        "Deleted or moved versions whose files wait for their readers to finish"
+    ),
+    existence_cache_hits: Counter(
+        "stream_db_existence_cache_hits_total",
+        "Version lookups answered from the existence cache"
+    ),
+    existence_cache_misses: Counter(
+        "stream_db_existence_cache_misses_total",
+        "Version lookups that had to read the item's metadata"
+    ),
+    item_filter_negatives: Counter(
+        "stream_db_item_filter_negatives_total",
+        "Item lookups the item filter ruled out without touching the disk"
+    ),
+    item_filter_passes: Counter(
+        "stream_db_item_filter_passes_total",
+        "Item lookups the item filter let through to the disk"
+    ),
+    item_filter_false_positives: Counter(
+        "stream_db_item_filter_false_positives_total",
+        "Item lookups the item filter let through for items found not to be stored"
+    ),
+    item_filter_items: Gauge("stream_db_item_filter_items", "Items the item filter holds"),
+    item_filter_bytes: Gauge(
+        "stream_db_item_filter_bytes",
+        "Memory taken by the bits of the item filter"
+    ),
+    slow_readers_downgraded: Counter(
+        "stream_db_slow_readers_downgraded_total",
+        "Readers of uploads that fell behind STREAM_DB_SLOW_READER_MAX_LAG_MB and kept reading from disk"
+    ),
+    slow_readers_terminated: Counter(
+        "stream_db_slow_readers_terminated_total",
+        "Readers of uploads that fell behind STREAM_DB_SLOW_READER_MAX_LAG_MB and were failed"
+    ),
+    streams_cancelled_killed: Counter(
+        "stream_db_streams_cancelled_killed_total",
+        "Streams killed through the admin API"
+    ),
+    streams_cancelled_shutdown: Counter(
+        "stream_db_streams_cancelled_shutdown_total",
+        "Streams cut off by a forced drain"
+    ),
+    streams_cancelled_timeout: Counter(
+        "stream_db_streams_cancelled_timeout_total",
+        "Streams cut off by a drain that ran past STREAM_DB_DRAIN_TIMEOUT_SECS"
+    ),
+    streams_cancelled_client_gone: Counter(
+        "stream_db_streams_cancelled_client_gone_total",
+        "Streams whose client went away before they ended"
+    ),
+    content_sniff_warnings: Counter(
+        "stream_db_content_sniff_warnings_total",
+        "Uploads stored although their first bytes did not look like XML"
+    ),
+    content_sniff_rejections: Counter(
+        "stream_db_content_sniff_rejections_total",
+        "Uploads refused because their first bytes did not look like XML"
+    ),
+    digests_backfilled: Counter(
+        "stream_db_digests_backfilled_total",
+        "Versions committed without a size or checksum that had them recorded from their data"
+    ),
+    hook_runs: Counter(
+        "stream_db_hook_runs_total",
+        "Commit hook runs, test runs included"
+    ),
+    hook_failures: Counter(
+        "stream_db_hook_failures_total",
+        "Commit hook runs that did not exit with 0, timeouts included"
+    ),
+    hook_timeouts: Counter(
+        "stream_db_hook_timeouts_total",
+        "Commit hook runs killed after their timeout"
+    ),
+    hook_runs_dropped: Counter(
+        "stream_db_hook_runs_dropped_total",
+        "Commit hook runs dropped because STREAM_DB_HOOK_QUEUE was full"
+    ),
+    verification_versions_verified: Counter(
+        "stream_db_verification_versions_verified_total",
+        "Versions the verification sweep found matching their recorded size and checksum"
+    ),
+    verification_bytes_hashed: Counter(
+        "stream_db_verification_bytes_hashed_total",
+        "Bytes of data files read by the verification sweep"
+    ),
+    verification_failures: Counter(
+        "stream_db_verification_failures_total",
+        "Versions the verification sweep quarantined"
+    ),
+    immutable_versions_missing: Counter(
+        "stream_db_immutable_versions_missing_total",
+        "Times the verification sweep found an immutable version without its data file"
+    ),
+    writes_queued: Counter(
+        "stream_db_writes_queued_total",
+        "Uploads that waited in their item's write queue for another upload to finish"
+    ),
+    write_queue_timeouts: Counter(
+        "stream_db_write_queue_timeouts_total",
+        "Queued uploads that gave up after waiting as long as their X-Write-Queue allowed"
+    ),
+    write_queue_rejections: Counter(
+        "stream_db_write_queue_rejections_total",
+        "Uploads refused because their item's write queue or all write queues were full"
+    ),
+    write_queue_depth: Gauge(
+        "stream_db_write_queue_depth",
+        "Uploads waiting in the write queues of their items"
+    ),
+    space_committed_bytes: Gauge(
+        "stream_db_space_committed_bytes",
+        "Bytes of committed versions, as the quota counts them"
+    ),
+    space_in_flight_bytes: Gauge(
+        "stream_db_space_in_flight_bytes",
+        "Bytes written by uploads in flight, as the quota counts them"
+    ),
+    space_interrupted_bytes: Gauge(
+        "stream_db_space_interrupted_bytes",
+        "Bytes of interrupted uploads awaiting their purge, as of the last space report"
+    ),
+    space_orphaned_bytes: Gauge(
+        "stream_db_space_orphaned_bytes",
+        "Bytes of files of unknown versions, as of the last space report"
+    ),
+    space_index_bytes: Gauge(
+        "stream_db_space_index_bytes",
+        "Bytes of property, block and part checksum indexes, as of the last space report"
+    ),
+    space_journal_bytes: Gauge(
+        "stream_db_space_journal_bytes",
+        "Bytes of upload journals, as of the last space report"
+    ),
+    space_debug_capture_bytes: Gauge(
+        "stream_db_space_debug_capture_bytes",
+        "Bytes of debug captures, as of the last space report"
+    ),
+    space_metadata_bytes: Gauge(
+        "stream_db_space_metadata_bytes",
+        "Bytes of item metadata, settings, stats and tags, as of the last space report"
+    ),
+    space_other_bytes: Gauge(
+        "stream_db_space_other_bytes",
+        "Bytes of other files in the instance's directories, as of the last space report"
+    ),
+    metric_label_overflows: Counter(
+        "stream_db_metric_label_overflows_total",
+        "Per-dataset counts taken under __overflow because their metric reached STREAM_DB_METRIC_LABEL_VALUES_MAX datasets"
+    ),
+    dataset_uploads_committed: DatasetCounter(
+        "stream_db_dataset_uploads_committed_total",
+        "Versions committed by this instance, by dataset"
+    ),
+    dataset_bytes_committed: DatasetCounter(
+        "stream_db_dataset_bytes_committed_total",
+        "Bytes of the versions committed by this instance, by dataset"
+    ),
+    dataset_reads: DatasetCounter(
+        "stream_db_dataset_reads_total",
+        "Reads of committed and in-flight versions that ended, by dataset"
+    ),
+    dataset_bytes_read: DatasetCounter(
+        "stream_db_dataset_bytes_read_total",
+        "Bytes those reads sent, by dataset"
+    ),
+    transfer_duration_milliseconds: Histogram(
+        "stream_db_transfer_duration_milliseconds",
+        "Milliseconds requests took from their arrival until their response body ended",
+        &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000, 30_000, 60_000, 300_000]
+    ),
+    transfer_received_bytes: Histogram(
+        "stream_db_transfer_received_bytes",
+        "Bytes of request bodies read by the server",
+        BYTE_BOUNDS
+    ),
+    transfer_sent_bytes: Histogram(
+        "stream_db_transfer_sent_bytes",
+        "Bytes of response bodies handed to clients",
+        BYTE_BOUNDS
+    ),
+}
+
+impl Metrics {
+    /// Count the per-dataset metrics by `datasets` from now on. What was counted so far
+    /// stays under the datasets it was counted for.
+    pub fn configure(&self, datasets: MetricDatasets) {
+        *self.datasets.write().unwrap() = datasets;
+    }
+
+    /// Count a version of `item_id` committed with `bytes`
+    pub fn record_commit(&self, item_id: &str, bytes: u64) {
+        self.count_by_dataset(
+            &[
+                (&self.dataset_uploads_committed, 1),
+                (&self.dataset_bytes_committed, bytes),
+            ],
+            item_id,
+        );
+    }
+
+    /// Count a read of `item_id` that ended after sending `bytes`
+    pub fn record_read(&self, item_id: &str, bytes: u64) {
+        self.count_by_dataset(
+            &[(&self.dataset_reads, 1), (&self.dataset_bytes_read, bytes)],
+            item_id,
+        );
+    }
+
+    fn count_by_dataset(&self, amounts: &[(&DatasetCounter, u64)], item_id: &str) {
+        let (dataset, max_label_values) = {
+            let datasets = self.datasets.read().unwrap();
+            (datasets.dataset_of(item_id), datasets.max_label_values)
+        };
+        for (counter, amount) in amounts {
+            if counter.add(dataset.clone(), *amount, max_label_values) {
+                self.metric_label_overflows.increment();
+            }
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
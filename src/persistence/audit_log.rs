@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// One request that uploaded, changed or deleted something, appended to the audit log
+/// once its response ended
+#[derive(Serialize, Clone)]
+pub struct AuditRecord {
+    pub at: String,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub item_id: Option<String>,
+    pub version: Option<String>,
+    pub status: u16,
+    /// Bytes of the request body the server read
+    pub bytes_in: u64,
+    /// Bytes of the response body handed to the client
+    pub bytes_out: u64,
+    pub duration_ms: u64,
+    /// `completed`, `client_aborted` or `errored`
+    pub termination: &'static str,
+}
+
+/// File the audit records are appended to as JSON lines, at `STREAM_DB_AUDIT_LOG_FILE`.
+/// Appends take turns, so the lines of concurrent requests never interleave.
+pub struct AuditLog {
+    path: Option<String>,
+    appending: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: Option<String>) -> Self {
+        Self {
+            path,
+            appending: Mutex::new(()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Append `record` as one line, does nothing without an audit log
+    pub fn append(&self, record: &AuditRecord) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(record).map_err(|error| error.to_string())?;
+        line.push(b'\n');
+        let _appending = self.appending.lock().unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|error| format!("Audit log write error at {path}: {error}"))
+    }
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+/// A property boundary recorded in the block index
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct BlockIndexEntry {
+    /// Number of properties that precede `offset`
+    pub property: u64,
+    /// Byte offset in the data file right after the previous property element
+    pub offset: u64,
+}
+
+/// Sparse index of a committed data file, stored next to it as
+/// `{item_id}_{version}.blocks.json`.
+///
+/// Unlike the property index it only records a boundary every `block_properties`
+/// properties or `block_bytes` bytes, whichever comes first, so it stays small enough to
+/// load on every read of a huge item. A seek binary searches the blocks and then skips at
+/// most one block worth of properties.
+#[derive(Serialize, Deserialize)]
+pub struct BlockIndex {
+    /// Size of the data file the index was built from, an index whose size does not match
+    /// the data file is stale
+    pub size: u64,
+    pub property_count: u64,
+    pub block_properties: u64,
+    pub block_bytes: u64,
+    pub blocks: Vec<BlockIndexEntry>,
+}
+
+impl BlockIndex {
+    /// The last block at or before property `property` (0-based)
+    pub fn locate(&self, property: u64) -> Option<BlockIndexEntry> {
+        let after = self
+            .blocks
+            .partition_point(|block| block.property <= property);
+        after.checked_sub(1).map(|position| self.blocks[position])
+    }
+
+    /// Load an index, returns `None` when the version has not been indexed
+    pub fn load(path: &str) -> Result<Option<Self>, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|error| format!("Block index is corrupt: {error}")),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(format!("Block index read error: {error}")),
+        }
+    }
+
+    /// Replace the index at `path`, readers never see a partially written one
+    pub fn store(&self, path: &str) -> Result<(), String> {
+        let temporary_path = format!("{path}.tmp");
+        let bytes = serde_json::to_vec(self).map_err(|error| error.to_string())?;
+        std::fs::write(&temporary_path, bytes)
+            .map_err(|error| format!("Block index write error: {error}"))?;
+        std::fs::rename(&temporary_path, path)
+            .map_err(|error| format!("Block index write error: {error}"))
+    }
+
+    /// Remove the index at `path`, a missing index is not an error
+    pub fn remove(path: &str) -> Result<(), String> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(format!("Block index remove error: {error}")),
+        }
+    }
+}
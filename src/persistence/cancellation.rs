@@ -0,0 +1,87 @@
+use crate::metrics::Metrics;
+
+use serde::Serialize;
+use std::sync::{Arc, OnceLock};
+use tokio_util::sync::CancellationToken;
+
+/// Why a stream was stopped before it ended on its own
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// Killed through `POST /admin/streams/.../kill`
+    Killed,
+    /// Cut off by a forced drain, after a second signal or `POST /admin/drain/force`
+    Shutdown,
+    /// Cut off by a drain that ran past `STREAM_DB_DRAIN_TIMEOUT_SECS`
+    Timeout,
+    /// The client went away before the stream ended
+    ClientGone,
+}
+
+impl CancelReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Killed => "killed",
+            Self::Shutdown => "shutdown",
+            Self::Timeout => "timeout",
+            Self::ClientGone => "client_gone",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Killed => "it was killed through the admin API",
+            Self::Shutdown => "the server is shutting down",
+            Self::Timeout => "the server is shutting down and stopped waiting for it",
+            Self::ClientGone => "its client went away",
+        }
+    }
+}
+
+/// Stops one stream wherever it is: between chunks, writing one, or waiting for data, for
+/// its item or in a long poll. Every clone cancels the same stream, and whoever cancels
+/// it first decides the reason, which the stream's error and the log report.
+#[derive(Clone)]
+pub struct Cancellation {
+    token: CancellationToken,
+    reason: Arc<OnceLock<CancelReason>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Cancellation {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Cancellation {
+            token: CancellationToken::new(),
+            reason: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Cancel the stream for `reason`, returning false if it was cancelled already
+    pub fn cancel(&self, reason: CancelReason) -> bool {
+        // Set before the token fires, so whoever it wakes finds the reason
+        if self.reason.set(reason).is_err() {
+            return false;
+        }
+        match reason {
+            CancelReason::Killed => self.metrics.streams_cancelled_killed.increment(),
+            CancelReason::Shutdown => self.metrics.streams_cancelled_shutdown.increment(),
+            CancelReason::Timeout => self.metrics.streams_cancelled_timeout.increment(),
+            CancelReason::ClientGone => self.metrics.streams_cancelled_client_gone.increment(),
+        }
+        self.token.cancel();
+        true
+    }
+
+    /// Why the stream was cancelled, if it was
+    pub fn reason(&self) -> Option<CancelReason> {
+        self.reason.get().copied()
+    }
+
+    /// Resolves once the stream is cancelled, with the reason
+    pub async fn cancelled(&self) -> CancelReason {
+        self.token.cancelled().await;
+        self.reason()
+            .expect("the reason is set before the token fires")
+    }
+}
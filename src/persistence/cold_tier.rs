@@ -0,0 +1,392 @@
+use crate::persistence::deferred_deletes::DeferredReason;
+use crate::persistence::file_persistence::{
+    METADATA_LOCK_WAIT, WriteError, block_index_file_name, data_file_name, lock_metadata,
+    metadata_item_id, metadata_path, property_index_file_name, read_metadata, replace_metadata,
+    sync_dir, version_file_path,
+};
+use crate::persistence::integrity::{self, IntegrityCheck, IntegrityFailure};
+use crate::persistence::io_failure::IoFailure;
+use crate::persistence::item_metadata::{ItemMetadata, VersionMetadata};
+use crate::persistence::storage::Storage;
+
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+
+/// Where the files of a committed version live
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageTier {
+    /// The data directory
+    Hot,
+    /// A cheaper, slower directory versions are moved to once nobody reads them
+    Cold,
+}
+
+impl StorageTier {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "hot" => Ok(Self::Hot),
+            "cold" => Ok(Self::Cold),
+            other => Err(format!("Unknown storage tier {other:?}")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hot => "hot",
+            Self::Cold => "cold",
+        }
+    }
+}
+
+/// What happened when a version was asked to move between tiers
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveOutcome {
+    Moved,
+    AlreadyThere,
+    /// Left alone because readers were attached, retried on the next pass
+    Busy,
+}
+
+#[derive(Serialize)]
+pub struct MoveReport {
+    pub item_id: String,
+    pub version: u64,
+    pub tier: StorageTier,
+    pub outcome: MoveOutcome,
+    pub bytes_moved: u64,
+}
+
+/// IDs of every item with metadata in the data directory
+pub fn item_ids(storage: &Storage) -> Result<Vec<String>, String> {
+    let entries = std::fs::read_dir(&storage.data_dir)
+        .map_err(|error| format!("Failed to list output directory: {error}"))?;
+    let mut item_ids = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| format!("Failed to list output directory: {error}"))?;
+        if let Some(item_id) = entry.file_name().to_str().and_then(metadata_item_id) {
+            item_ids.push(item_id.to_string());
+        }
+    }
+    item_ids.sort();
+    Ok(item_ids)
+}
+
+/// Move the files of a committed version to `cold_dir`, or back to the data directory
+/// for [`StorageTier::Hot`]. Each step leaves the version readable if it fails or the
+/// process crashes: the files are copied, checked against the committed checksum and
+/// synced, then the copies are renamed into place, their directory is synced and the
+/// metadata is switched to the new location, and only then are the originals removed. An original that does not match
+/// its checksum either is quarantined. With `skip_if_read` versions that have readers
+/// attached are left alone.
+///
+/// The copying runs without the metadata lock, which uploads of the item would otherwise
+/// be refused with for as long as a large version takes to copy. The lock is only taken
+/// once the copy is verified, to check the version is still the same epoch in the same
+/// place and switch it over. A version deleted, rewritten or moved
+/// meanwhile, or whose item is being written at that moment, is left alone as busy and
+/// the copies are removed.
+///
+/// Readers already streaming the old copy keep their open file handle, new readers
+/// open the new location. The old copy is removed once those readers finished, and the
+/// version is not moved again before.
+pub fn move_version(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    tier: StorageTier,
+    cold_dir: &str,
+    skip_if_read: bool,
+) -> Result<MoveReport, WriteError> {
+    let mut report = MoveReport {
+        item_id: item_id.to_string(),
+        version: item_version,
+        tier,
+        outcome: MoveOutcome::AlreadyThere,
+        bytes_moved: 0,
+    };
+    // The tiering pass, promotions and admin requests may ask for the same move at once
+    let Some(_claim) = MoveClaim::take(storage, item_id, item_version) else {
+        report.outcome = MoveOutcome::Busy;
+        return Ok(report);
+    };
+
+    let (metadata_file, metadata) = lock_item_metadata(storage, item_id)?;
+    // Released while copying
+    drop(metadata_file);
+    let Some(version) = metadata.versions.get(&item_version) else {
+        return Err(WriteError::NotFound(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        )));
+    };
+    if version.quarantined_at.is_some() {
+        return Err(WriteError::Quarantined(Box::new(IntegrityFailure::new(
+            item_id, version,
+        ))));
+    }
+
+    let source = version.location.clone();
+    let target = match tier {
+        StorageTier::Hot => None,
+        StorageTier::Cold => Some(cold_dir.to_string()),
+    };
+    if source == target {
+        return Ok(report);
+    }
+    // A copy left behind by the last move still waits for its readers, and may be
+    // where this move would copy to
+    if storage.deferred_deletes.is_pending(item_id, item_version)
+        || skip_if_read
+            && storage
+                .registry
+                .get(item_id, item_version)
+                .is_some_and(|file| file.reader_count() > 0)
+    {
+        report.outcome = MoveOutcome::Busy;
+        return Ok(report);
+    }
+    if let Some(target) = target.as_deref() {
+        std::fs::create_dir_all(target)
+            .map_err(|error| format!("Failed to create cold tier directory: {error}"))?;
+    }
+
+    let mut temporaries = Vec::new();
+    let moved = copy_and_switch(
+        storage,
+        item_id,
+        version,
+        source.as_deref(),
+        target,
+        &mut temporaries,
+        &mut report,
+    );
+    // Renamed into place when the move went through, left over otherwise
+    remove_all(&temporaries);
+    moved?;
+    if report.outcome == MoveOutcome::Moved {
+        println!(
+            "Moved item {item_id} version {item_version} to the {} tier",
+            tier.name()
+        );
+    }
+    Ok(report)
+}
+
+/// Copy the files of `version` from `source` next to their place in `target`, recording
+/// the copies in `temporaries`, and switch the version over to them if it is still the
+/// one that was copied
+fn copy_and_switch(
+    storage: &Storage,
+    item_id: &str,
+    version: &VersionMetadata,
+    source: Option<&str>,
+    target: Option<String>,
+    temporaries: &mut Vec<String>,
+    report: &mut MoveReport,
+) -> Result<(), WriteError> {
+    let item_version = version.version;
+    // 1. Copy every file of the version, verifying the data against its receipt
+    let file_names = [
+        data_file_name(item_id, item_version),
+        property_index_file_name(item_id, item_version),
+        block_index_file_name(item_id, item_version),
+    ];
+    let mut copies = Vec::new();
+    for (position, file_name) in file_names.iter().enumerate() {
+        let from = version_file_path(storage, source, file_name);
+        let to = version_file_path(storage, target.as_deref(), file_name);
+        let temporary = format!("{to}.tmp");
+        match std::fs::copy(&from, &temporary) {
+            Ok(bytes) => report.bytes_moved += bytes,
+            // Only the data file is mandatory, indexes are optional
+            Err(error) if position > 0 && error.kind() == std::io::ErrorKind::NotFound => {
+                continue;
+            }
+            Err(error) => {
+                temporaries.push(temporary);
+                return Err(format!("Copy of {from} failed: {error}").into());
+            }
+        }
+        temporaries.push(temporary);
+        copies.push(to);
+    }
+    let copied = integrity::digest(&temporaries[0])?;
+    if !integrity::matches(version, &copied) {
+        // A bad copy is left for the next move to retry, a bad original is quarantined
+        let original = version_file_path(storage, source, &file_names[0]);
+        let found = integrity::digest(&original)?;
+        if integrity::matches(version, &found) {
+            return Err(format!("Copy of {original} does not match the committed checksum").into());
+        }
+        let (mut metadata_file, mut metadata) = lock_item_metadata(storage, item_id)?;
+        let unchanged = metadata
+            .versions
+            .get(&item_version)
+            .is_some_and(|current| is_unchanged(current, version));
+        if !unchanged {
+            report.outcome = MoveOutcome::Busy;
+            report.bytes_moved = 0;
+            return Ok(());
+        }
+        let failure = integrity::quarantine_locked(
+            storage,
+            item_id,
+            item_version,
+            &mut metadata_file,
+            &mut metadata,
+            &found,
+            IntegrityCheck::TierMove,
+        )?;
+        return Err(WriteError::Quarantined(Box::new(failure)));
+    }
+    // Durable like a commit's data file before anything points at them
+    for temporary in temporaries.iter() {
+        File::open(temporary)
+            .and_then(|file| file.sync_all())
+            .map_err(|error| WriteError::Failed(format!("Sync of {temporary} failed: {error}")))?;
+    }
+
+    // 2. Under the lock again, check nothing changed the version meanwhile, move the
+    // copies into place and point the metadata at them
+    let unchanged =
+        lock_item_metadata(storage, item_id)
+            .ok()
+            .and_then(|(metadata_file, mut metadata)| {
+                metadata
+                    .versions
+                    .get_mut(&item_version)
+                    .filter(|current| is_unchanged(current, version))
+                    .map(|current| {
+                        current.location = target.clone();
+                        current.moved_at = Some(chrono::Utc::now().to_rfc3339());
+                    })?;
+                Some((metadata_file, metadata))
+            });
+    let Some((mut metadata_file, metadata)) = unchanged else {
+        println!(
+            "Item {item_id} version {item_version} changed or was written while it was copied for a tier move, leaving it alone"
+        );
+        report.outcome = MoveOutcome::Busy;
+        report.bytes_moved = 0;
+        return Ok(());
+    };
+    for (temporary, to) in temporaries.iter().zip(&copies) {
+        std::fs::rename(temporary, to)
+            .map_err(|error| WriteError::Failed(format!("Rename of {to} failed: {error}")))?;
+    }
+    let target_dir = target.clone().unwrap_or_else(|| storage.data_dir.clone());
+    sync_dir(&target_dir)
+        .map_err(|error| WriteError::Failed(format!("Sync of {target_dir} failed: {error}")))?;
+    replace_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        &metadata,
+    )?;
+
+    // 3. New readers must open the copy, then the originals can go, once the readers
+    // still streaming them finished
+    let shared_file = storage.registry.get(item_id, item_version);
+    if let Some(shared_file) = &shared_file {
+        storage.registry.remove(item_id, item_version, shared_file);
+    }
+    let originals: Vec<String> = file_names
+        .iter()
+        .map(|file_name| version_file_path(storage, source, file_name))
+        .collect();
+    match shared_file {
+        Some(shared_file) if shared_file.reader_count() > 0 => {
+            storage.deferred_deletes.defer(
+                item_id,
+                item_version,
+                DeferredReason::Moved,
+                &shared_file,
+                originals,
+                true,
+            );
+        }
+        _ => remove_all(&originals),
+    }
+    drop(metadata_file);
+    report.outcome = MoveOutcome::Moved;
+    Ok(())
+}
+
+/// Open the item's metadata and lock it, waiting for the current holder like uploads do
+fn lock_item_metadata(
+    storage: &Storage,
+    item_id: &str,
+) -> Result<(File, ItemMetadata), WriteError> {
+    let metadata_path = metadata_path(storage, item_id);
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&metadata_path)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => {
+                WriteError::NotFound(format!("Item {item_id} not found"))
+            }
+            _ => WriteError::Failed(IoFailure::new("open metadata", &metadata_path, &error).into()),
+        })?;
+    // Uploads only hold it while validating and committing
+    if !lock_metadata(&mut metadata_file, &metadata_path, Some(METADATA_LOCK_WAIT))? {
+        return Err(WriteError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let metadata = read_metadata(&mut metadata_file, &metadata_path)?;
+    Ok((metadata_file, metadata))
+}
+
+/// Marks a version as being moved until dropped
+struct MoveClaim<'a> {
+    storage: &'a Storage,
+    key: (String, u64),
+}
+
+impl<'a> MoveClaim<'a> {
+    fn take(storage: &'a Storage, item_id: &str, item_version: u64) -> Option<Self> {
+        let key = (item_id.to_string(), item_version);
+        storage
+            .tier_moves
+            .lock()
+            .unwrap()
+            .insert(key.clone())
+            .then_some(Self { storage, key })
+    }
+}
+
+impl Drop for MoveClaim<'_> {
+    fn drop(&mut self) {
+        self.storage.tier_moves.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Remove the leftover data file of a version whose move to the cold tier was cut
+/// short after its metadata was switched. Returns whether anything was removed.
+pub fn remove_stale_hot_copy(storage: &Storage, item_id: &str, item_version: u64) -> bool {
+    let mut removed = false;
+    for file_name in [
+        data_file_name(item_id, item_version),
+        property_index_file_name(item_id, item_version),
+        block_index_file_name(item_id, item_version),
+    ] {
+        removed |= std::fs::remove_file(storage.path(&file_name)).is_ok();
+    }
+    removed
+}
+
+/// Whether `current` is still the generation in the place `copied` was copied from
+fn is_unchanged(current: &VersionMetadata, copied: &VersionMetadata) -> bool {
+    current.epoch == copied.epoch && current.location == copied.location
+}
+
+fn remove_all(paths: &[String]) {
+    for path in paths {
+        if let Err(error) = std::fs::remove_file(path)
+            && error.kind() != std::io::ErrorKind::NotFound
+        {
+            println!("Could not remove {path}: {error}");
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! What a crash or a failure between the steps of [`commit_durably`] leaves behind. The
+//! writer is held at each step through [`sync_points`] while the data and in-flight
+//! directories are copied, which is what a crash there leaves on disk, or the step is made
+//! to fail. An instance started on the copy, or the one whose commit failed, must only
+//! list versions whose data file is complete, and must take the version again.
+//!
+//! [`commit_durably`]: super::file_persistence::commit_durably
+
+use crate::component::item_stream_component;
+use crate::config::Config;
+use crate::persistence::cancellation::Cancellation;
+use crate::persistence::file_persistence::{
+    FileReader, FileWriter, ReadDurability, VersionState, WriteError, data_file_name,
+    metadata_path, version_state,
+};
+use crate::persistence::item_metadata::ItemMetadata;
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamReader, ItemStreamWriter};
+use crate::persistence::sync_points::{self, SyncPoint};
+use crate::state::{AppState, StreamDb};
+
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const FIRST: &[u8] = b"<properties><property name=\"a\">first</property></properties>";
+const SECOND: &[u8] = b"<properties><property name=\"b\">second version</property></properties>";
+
+/// Every point between the steps of a commit, in the order it passes them
+const POINTS: [SyncPoint; 5] = [
+    SyncPoint::CommitMoving,
+    SyncPoint::CommitSyncingDir,
+    SyncPoint::CommitRecording,
+    SyncPoint::MetadataReplacing,
+    SyncPoint::CommitRecorded,
+];
+
+fn committed(bytes_received: usize) -> CommitDetails {
+    CommitDetails {
+        property_count: 0,
+        request_id: None,
+        bytes_received: bytes_received as u64,
+        extra_elements: Default::default(),
+        extra_elements_truncated: Default::default(),
+        provenance: Default::default(),
+        user_metadata: Default::default(),
+        immutable_until: None,
+        segments: None,
+        transforms: Vec::new(),
+    }
+}
+
+/// A temporary directory holding the data directory and, for uploads written elsewhere,
+/// the in-flight directory, removed when dropped
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("stream-db-{name}-{}", uuid::Uuid::new_v4())))
+    }
+
+    fn join(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+
+    /// A copy of everything in the directory, as a crash right now leaves it
+    fn snapshot(&self, name: &str) -> Self {
+        let snapshot = Self::new(name);
+        copy_dir(&self.0, &snapshot.0);
+        snapshot
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// An instance over the directories in a [`Dir`]
+struct Instance {
+    state: AppState,
+}
+
+impl Instance {
+    /// With `inflight` uploads are written to an in-flight directory and renamed into the
+    /// data directory at commit, otherwise they are committed where they were written
+    fn start(dir: &Dir, inflight: bool) -> Self {
+        let mut config = Config::from_env().unwrap();
+        config.data_dir = dir.join("data");
+        config.inflight_dir = inflight.then(|| dir.join("inflight"));
+        let state = StreamDb::new(config);
+        item_stream_component::init(&state).unwrap();
+        Self { state }
+    }
+
+    fn metadata_path(&self) -> String {
+        metadata_path(&self.state.storage, "item")
+    }
+
+    fn writer(&self, version: u64) -> FileWriter {
+        FileWriter::new(
+            &self.state.storage,
+            "item",
+            &version,
+            None,
+            None,
+            None,
+            &Cancellation::new(self.state.metrics.clone()),
+        )
+        .unwrap_or_else(|error| panic!("{}", error.message()))
+    }
+
+    /// Write `bytes` as `version` and commit them
+    async fn upload(&self, version: u64, bytes: &[u8]) -> Result<(), String> {
+        let mut writer = self.writer(version);
+        writer.write_chunk(bytes.to_vec()).await?;
+        writer.commit(&committed(bytes.len())).await.map(|_| ())
+    }
+
+    async fn read(&self, version: u64) -> Vec<u8> {
+        let mut reader = FileReader::new(
+            &self.state.storage,
+            &self.state.faults,
+            "item".to_string(),
+            version,
+            ReadDurability::Written,
+        )
+        .unwrap_or_else(|_| panic!("version {version} could not be opened"));
+        let mut bytes = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await.unwrap() {
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+
+    /// The versions the metadata lists must have complete data files, version 1 must
+    /// still be served and not be taken again, and version 2 either be committed with its
+    /// data or be written again. Returns whether version 2 was committed before.
+    async fn check_recovered(&self, case: &str) -> bool {
+        let metadata = ItemMetadata::load(&self.metadata_path()).unwrap();
+        for (version, recorded) in &metadata.versions {
+            let path = self.state.storage.path(&data_file_name("item", *version));
+            let data = std::fs::read(&path).unwrap_or_else(|error| {
+                panic!("{case}: version {version} is listed, its data file is not: {error}")
+            });
+            assert_eq!(
+                Some(data.len() as u64),
+                recorded.size,
+                "{case}: version {version}"
+            );
+            assert_eq!(
+                recorded.sha256.as_deref(),
+                Some(format!("{:x}", Sha256::digest(&data)).as_str()),
+                "{case}: version {version}"
+            );
+        }
+        assert_eq!(self.read(1).await, FIRST, "{case}");
+        let upload_again = FileWriter::new(
+            &self.state.storage,
+            "item",
+            &1,
+            None,
+            None,
+            None,
+            &Cancellation::new(self.state.metrics.clone()),
+        );
+        assert!(
+            matches!(upload_again, Err(WriteError::VersionConflict { .. })),
+            "{case}: version 1 can be uploaded again"
+        );
+
+        let committed = metadata.versions.contains_key(&2);
+        if !committed {
+            let state = version_state(&self.state.storage, "item", 2).unwrap();
+            assert!(
+                matches!(state, VersionState::Interrupted(_) | VersionState::Missing),
+                "{case}: version 2 is neither committed nor interrupted"
+            );
+            self.upload(2, SECOND)
+                .await
+                .unwrap_or_else(|error| panic!("{case}: version 2 cannot be written: {error}"));
+        }
+        assert_eq!(self.read(2).await, SECOND, "{case}");
+        committed
+    }
+}
+
+/// Hold the commit of version 2 at every point in turn and start an instance on a copy of
+/// the directories taken there
+async fn crash_at_every_point(inflight: bool) {
+    for point in POINTS {
+        let dir = Dir::new("commit-crash");
+        let instance = Instance::start(&dir, inflight);
+        instance.upload(1, FIRST).await.unwrap();
+
+        let pause = sync_points::pause(&instance.metadata_path(), 2, point);
+        let mut writer = instance.writer(2);
+        writer.write_chunk(SECOND.to_vec()).await.unwrap();
+        let commit =
+            tokio::spawn(async move { writer.commit(&committed(SECOND.len())).await.map(|_| ()) });
+        pause.arrived().await;
+        let mut crashes = vec![(format!("{point:?}"), dir.snapshot("commit-crash-copy"))];
+        if inflight && point == SyncPoint::CommitSyncingDir {
+            // The rename is not durable before the directory is synced, the crash may
+            // lose it
+            let lost = dir.snapshot("commit-crash-copy");
+            std::fs::rename(
+                lost.0.join("data").join(data_file_name("item", 2)),
+                lost.0.join("inflight").join(data_file_name("item", 2)),
+            )
+            .unwrap();
+            crashes.push((format!("{point:?} with the rename lost"), lost));
+        }
+        pause.release();
+        commit.await.unwrap().unwrap();
+
+        for (case, crashed) in crashes {
+            // The new metadata was written in full beside the old one, which stays whole
+            // and is what the instance goes by
+            let replacement = crashed.0.join("data").join("item_metadata.xml.replacing");
+            assert_eq!(
+                replacement.exists(),
+                point == SyncPoint::MetadataReplacing,
+                "{case}"
+            );
+            let recovered = Instance::start(&crashed, inflight);
+            assert!(!replacement.exists(), "{case}: left after recovery");
+            let committed = recovered.check_recovered(&case).await;
+            assert_eq!(committed, point == SyncPoint::CommitRecorded, "{case}");
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_crash_in_a_commit_in_place_never_lists_incomplete_data() {
+    crash_at_every_point(false).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_crash_in_a_commit_from_the_inflight_dir_never_lists_incomplete_data() {
+    crash_at_every_point(true).await;
+}
+
+/// Fail the step after every point before the version is recorded, the commit must fail
+/// without listing the version, both in the instance and after a restart
+async fn fail_at_every_point(inflight: bool) {
+    for point in &POINTS[..4] {
+        let case = format!("{point:?}");
+        let dir = Dir::new("commit-failure");
+        let instance = Instance::start(&dir, inflight);
+        instance.upload(1, FIRST).await.unwrap();
+
+        let failure = sync_points::fail(&instance.metadata_path(), 2, *point);
+        let error = instance.upload(2, SECOND).await.unwrap_err();
+        assert!(error.contains("failure injected"), "{case}: {error}");
+        drop(failure);
+        let metadata = ItemMetadata::load(&instance.metadata_path()).unwrap();
+        assert!(!metadata.versions.contains_key(&2), "{case}");
+
+        let restarted_dir = dir.snapshot("commit-failure-copy");
+        let restarted = Instance::start(&restarted_dir, inflight);
+        assert!(!restarted.check_recovered(&case).await, "{case}");
+        assert!(!instance.check_recovered(&case).await, "{case}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_failed_commit_in_place_leaves_the_version_free() {
+    fail_at_every_point(false).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_failed_commit_from_the_inflight_dir_leaves_the_version_free() {
+    fail_at_every_point(true).await;
+}
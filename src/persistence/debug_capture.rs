@@ -0,0 +1,364 @@
+use crate::persistence::fault_injection::matches_pattern;
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::fs::File as TokioFile;
+
+/// Directory inside the data directory holding the captures
+pub(crate) const CAPTURE_DIR: &str = ".debug";
+/// Property boundaries recorded per trace, later ones are only counted
+const MAX_TRACED_BOUNDARIES: usize = 10_000;
+/// Errors recorded per trace
+const MAX_TRACED_ERRORS: usize = 100;
+/// Request IDs are client supplied, longer ones are cut off in file names
+const MAX_CAPTURE_ID_LENGTH: usize = 128;
+
+/// Where the write path split an upload and why it gave up, stored next to the raw
+/// bytes as `{request_id}.trace.json`
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CaptureTrace {
+    pub request_id: String,
+    pub item_id: String,
+    pub item_version: u64,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    /// `committed`, the error code the upload failed with, or `None` if the client went
+    /// away before the upload finished
+    pub outcome: Option<String>,
+    pub bytes_received: u64,
+    /// Bytes in the `.raw` file, fewer than received once the size limit was reached
+    pub bytes_captured: u64,
+    pub truncated: bool,
+    pub property_count: u64,
+    /// Body offsets just past every property the splitter cut off, the first 10,000.
+    /// Left out of capture listings.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub boundaries: Vec<u64>,
+    /// Most bytes the splitter held back waiting for a property to close
+    pub buffer_highwater: u64,
+    /// Bytes left over after the last complete property when the body ended
+    pub tail: Option<CaptureTail>,
+    pub errors: Vec<CaptureError>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CaptureTail {
+    pub offset: u64,
+    pub bytes: u64,
+    /// Whether the leftover looked like a property and was stored as one
+    pub counted_as_property: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CaptureError {
+    /// Body offset the error refers to
+    pub offset: u64,
+    pub code: String,
+    pub message: String,
+}
+
+/// The item patterns whose uploads are captured. Nothing is captured by default, and
+/// while no pattern is set the write path only pays for one atomic load.
+pub struct DebugCaptures {
+    enabled: AtomicBool,
+    patterns: RwLock<Vec<String>>,
+    max_bytes: u64,
+    max_count: usize,
+}
+
+impl DebugCaptures {
+    pub fn new(max_bytes: u64, max_count: usize) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            patterns: RwLock::new(Vec::new()),
+            max_bytes,
+            max_count,
+        }
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.read().unwrap().clone()
+    }
+
+    /// Capture the uploads of items matching any of `patterns`, where `*` matches any run
+    /// of characters. An empty list turns capturing off.
+    pub fn set_patterns(&self, patterns: Vec<String>) -> Result<(), String> {
+        if patterns.iter().any(|pattern| pattern.is_empty()) {
+            return Err("Item patterns must not be empty".to_string());
+        }
+        let mut current = self.patterns.write().unwrap();
+        self.enabled.store(!patterns.is_empty(), Ordering::Release);
+        *current = patterns;
+        Ok(())
+    }
+
+    /// Start capturing an upload if its item matches a pattern and the count limit
+    /// allows another capture
+    pub fn start(
+        &self,
+        storage: &Storage,
+        item_id: &str,
+        item_version: u64,
+        request_id: &str,
+    ) -> Option<DebugCapture> {
+        if !self.enabled.load(Ordering::Acquire) {
+            return None;
+        }
+        let matched = self
+            .patterns
+            .read()
+            .unwrap()
+            .iter()
+            .any(|pattern| matches_pattern(pattern.as_bytes(), item_id.as_bytes()));
+        if !matched {
+            return None;
+        }
+
+        match DebugCapture::create(storage, item_id, item_version, request_id, self) {
+            Ok(capture) => capture,
+            Err(error) => {
+                println!("Could not capture upload {request_id} of item {item_id}: {error}");
+                None
+            }
+        }
+    }
+}
+
+/// The capture of one upload. The raw body is appended as it arrives, the trace is
+/// written once the capture is dropped, however the upload ended.
+///
+/// Writes go straight to the page cache without going through tokio's blocking pool,
+/// captures are bounded and only taken while debugging.
+pub struct DebugCapture {
+    raw: File,
+    trace_path: String,
+    max_bytes: u64,
+    trace: CaptureTrace,
+}
+
+impl DebugCapture {
+    fn create(
+        storage: &Storage,
+        item_id: &str,
+        item_version: u64,
+        request_id: &str,
+        captures: &DebugCaptures,
+    ) -> Result<Option<Self>, String> {
+        let capture_dir = storage.path(CAPTURE_DIR);
+        std::fs::create_dir_all(&capture_dir)
+            .map_err(|error| format!("Failed to create capture directory: {error}"))?;
+        let existing = std::fs::read_dir(&capture_dir)
+            .map_err(|error| format!("Failed to list captures: {error}"))?
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".raw"))
+            .count();
+        if existing >= captures.max_count {
+            println!(
+                "Not capturing upload {request_id} of item {item_id}, {existing} captures already exist"
+            );
+            return Ok(None);
+        }
+
+        let capture_id = capture_id(request_id);
+        let raw = File::create(raw_path(storage, &capture_id))
+            .map_err(|error| format!("Failed to create capture file: {error}"))?;
+        println!("Capturing upload {request_id} of item {item_id} version {item_version}");
+        Ok(Some(Self {
+            raw,
+            trace_path: trace_path(storage, &capture_id),
+            max_bytes: captures.max_bytes,
+            trace: CaptureTrace {
+                request_id: request_id.to_string(),
+                item_id: item_id.to_string(),
+                item_version,
+                started_at: now(),
+                ..CaptureTrace::default()
+            },
+        }))
+    }
+
+    /// Append body bytes as they arrived, up to the size limit
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.trace.bytes_received += bytes.len() as u64;
+        let room = self.max_bytes.saturating_sub(self.trace.bytes_captured);
+        let captured = &bytes[..bytes.len().min(room as usize)];
+        if captured.len() < bytes.len() {
+            self.trace.truncated = true;
+        }
+        if captured.is_empty() {
+            return;
+        }
+        match self.raw.write_all(captured) {
+            Ok(()) => self.trace.bytes_captured += captured.len() as u64,
+            Err(error) => {
+                println!(
+                    "Capture of upload {} failed: {error}",
+                    self.trace.request_id
+                );
+                self.trace.truncated = true;
+                self.max_bytes = self.trace.bytes_captured;
+            }
+        }
+    }
+
+    /// A property the splitter cut off, ending just before `offset`
+    pub fn boundary(&mut self, offset: u64) {
+        self.trace.property_count += 1;
+        if self.trace.boundaries.len() < MAX_TRACED_BOUNDARIES {
+            self.trace.boundaries.push(offset);
+        }
+    }
+
+    /// Bytes the splitter currently holds back
+    pub fn buffered(&mut self, bytes: usize) {
+        self.trace.buffer_highwater = self.trace.buffer_highwater.max(bytes as u64);
+    }
+
+    pub fn tail(&mut self, offset: u64, bytes: usize, counted_as_property: bool) {
+        if counted_as_property {
+            self.trace.property_count += 1;
+        }
+        self.trace.tail = Some(CaptureTail {
+            offset,
+            bytes: bytes as u64,
+            counted_as_property,
+        });
+    }
+
+    pub fn error(&mut self, offset: u64, code: &str, message: &str) {
+        if self.trace.errors.len() < MAX_TRACED_ERRORS {
+            self.trace.errors.push(CaptureError {
+                offset,
+                code: code.to_string(),
+                message: message.to_string(),
+            });
+        }
+    }
+
+    pub fn finish(&mut self, outcome: &str) {
+        self.trace.outcome = Some(outcome.to_string());
+    }
+}
+
+impl Drop for DebugCapture {
+    fn drop(&mut self) {
+        self.trace.finished_at = Some(now());
+        let written = serde_json::to_vec_pretty(&self.trace)
+            .map_err(|error| error.to_string())
+            .and_then(|json| {
+                std::fs::write(&self.trace_path, json).map_err(|error| error.to_string())
+            });
+        if let Err(error) = written {
+            println!(
+                "Could not write the trace of upload {}: {error}",
+                self.trace.request_id
+            );
+        }
+    }
+}
+
+/// The traces of every capture without their boundaries, oldest first. Captures still
+/// being taken have no trace yet and are left out.
+pub fn list(storage: &Storage) -> Result<Vec<CaptureTrace>, String> {
+    let entries = match std::fs::read_dir(storage.path(CAPTURE_DIR)) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(format!("Failed to list captures: {error}")),
+    };
+    let mut traces = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| format!("Failed to list captures: {error}"))?;
+        if !entry.file_name().to_string_lossy().ends_with(".trace.json") {
+            continue;
+        }
+        let trace = std::fs::read(entry.path())
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| {
+                serde_json::from_slice::<CaptureTrace>(&bytes).map_err(|error| error.to_string())
+            });
+        match trace {
+            Ok(trace) => traces.push(CaptureTrace {
+                boundaries: Vec::new(),
+                ..trace
+            }),
+            Err(error) => println!(
+                "Skipping unreadable capture trace {:?}: {error}",
+                entry.path()
+            ),
+        }
+    }
+    traces.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+    Ok(traces)
+}
+
+/// The raw body of a capture, `None` if there is no such capture
+pub async fn open_raw(storage: &Storage, request_id: &str) -> Result<Option<TokioFile>, String> {
+    match TokioFile::open(raw_path(storage, &capture_id(request_id))).await {
+        Ok(file) => Ok(Some(file)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Capture read error: {error}")),
+    }
+}
+
+pub fn load_trace(storage: &Storage, request_id: &str) -> Result<Option<CaptureTrace>, String> {
+    match std::fs::read(trace_path(storage, &capture_id(request_id))) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|error| format!("Capture trace is corrupt: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Capture trace read error: {error}")),
+    }
+}
+
+/// Remove the captures older than `retention`, returning how many were removed
+pub fn purge(storage: &Storage, retention: Duration) -> usize {
+    let Ok(entries) = std::fs::read_dir(storage.path(CAPTURE_DIR)) else {
+        return 0;
+    };
+    let mut purged = 0;
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let expired = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= retention);
+        if !expired {
+            continue;
+        }
+        if std::fs::remove_file(entry.path()).is_ok() && file_name.ends_with(".raw") {
+            purged += 1;
+        }
+    }
+    purged
+}
+
+/// Client supplied request IDs reduced to characters safe in a file name
+fn capture_id(request_id: &str) -> String {
+    request_id
+        .chars()
+        .take(MAX_CAPTURE_ID_LENGTH)
+        .map(|character| match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => character,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn raw_path(storage: &Storage, capture_id: &str) -> String {
+    storage.path(&format!("{CAPTURE_DIR}/{capture_id}.raw"))
+}
+
+fn trace_path(storage: &Storage, capture_id: &str) -> String {
+    storage.path(&format!("{CAPTURE_DIR}/{capture_id}.trace.json"))
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
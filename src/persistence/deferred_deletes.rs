@@ -0,0 +1,158 @@
+use crate::metrics::Metrics;
+use crate::persistence::shared_file::SharedFile;
+
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+
+/// Why the files of a version wait to be removed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeferredReason {
+    /// The version was deleted. New reads of it are answered with `410 Gone` until its
+    /// files are gone or the version is uploaded again.
+    Deleted,
+    /// The version was deleted and is being uploaded again, the files are the deleted
+    /// generation's
+    Replaced,
+    /// The version was moved to the other tier, the files are the copy it left behind
+    Moved,
+}
+
+struct DeferredDelete {
+    item_id: String,
+    version: u64,
+    reason: DeferredReason,
+    /// Dropped once its last reader detached, which closes the handle they read through
+    file: Weak<SharedFile>,
+    /// Files still to remove
+    paths: Vec<String>,
+    /// Whether the files are where the version's files are written, rather than set aside
+    in_place: bool,
+}
+
+const SET_ASIDE_MARKER: &str = ".deleted-";
+
+/// Where a file of a deleted version waits for its readers. Set aside under the epoch of
+/// its generation, a new upload of the version writes files of its own meanwhile.
+pub fn set_aside_path(path: &str, epoch: u64) -> String {
+    format!("{path}{SET_ASIDE_MARKER}{epoch}")
+}
+
+/// Whether `file_name` was set aside by [`set_aside_path`]
+pub fn is_set_aside(file_name: &str) -> bool {
+    file_name
+        .rsplit_once(SET_ASIDE_MARKER)
+        .is_some_and(|(_, epoch)| epoch.parse::<u64>().is_ok())
+}
+
+/// Files of versions that were deleted or moved while readers were streaming them. They
+/// are only removed once nothing holds them open anymore, the same on every platform:
+/// removing an open file fails on Windows and leaves the readers of other platforms
+/// streaming a file the metadata no longer knows.
+pub struct DeferredDeletes {
+    queue: Mutex<Vec<DeferredDelete>>,
+    /// Woken when the file of a queued version is dropped
+    pub released: Arc<Notify>,
+    metrics: Arc<Metrics>,
+}
+
+impl DeferredDeletes {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            queue: Mutex::default(),
+            released: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Remove `paths` once the readers of `shared_file` are done with it. The caller
+    /// already took it out of the registry, so no new reader attaches to it. `in_place`
+    /// tells whether they are the paths the version's files are written to.
+    pub fn defer(
+        &self,
+        item_id: &str,
+        item_version: u64,
+        reason: DeferredReason,
+        shared_file: &Arc<SharedFile>,
+        paths: Vec<String>,
+        in_place: bool,
+    ) {
+        shared_file.notify_when_dropped(self.released.clone());
+        self.queue.lock().unwrap().push(DeferredDelete {
+            item_id: item_id.to_string(),
+            version: item_version,
+            reason,
+            file: Arc::downgrade(shared_file),
+            paths,
+            in_place,
+        });
+        self.metrics.deletes_deferred.increment();
+        self.metrics.deferred_deletes_pending.add(1);
+        println!(
+            "Removal of item {item_id} version {item_version} deferred until its {} readers finish",
+            shared_file.reader_count()
+        );
+    }
+
+    /// Whether the version was deleted and its files are still waiting for its readers
+    pub fn is_deleted(&self, item_id: &str, item_version: u64) -> bool {
+        self.queue.lock().unwrap().iter().any(|deferred| {
+            deferred.reason == DeferredReason::Deleted
+                && deferred.item_id == item_id
+                && deferred.version == item_version
+        })
+    }
+
+    /// Whether files of the version are waiting for its readers where a new upload or
+    /// move of it would write, and remove them from under the readers
+    pub fn is_pending(&self, item_id: &str, item_version: u64) -> bool {
+        self.queue.lock().unwrap().iter().any(|deferred| {
+            deferred.in_place && deferred.item_id == item_id && deferred.version == item_version
+        })
+    }
+
+    /// The deleted version is uploaded again, new reads of it no longer are `410 Gone`
+    pub fn uploaded_again(&self, item_id: &str, item_version: u64) {
+        for deferred in self.queue.lock().unwrap().iter_mut() {
+            if deferred.reason == DeferredReason::Deleted
+                && deferred.item_id == item_id
+                && deferred.version == item_version
+            {
+                deferred.reason = DeferredReason::Replaced;
+            }
+        }
+    }
+
+    /// Remove the files nobody holds open anymore, returning for how many versions all
+    /// of them are gone. Files that cannot be removed yet, e.g. because the handle is
+    /// only being closed, are tried again on the next call.
+    pub fn remove_released(&self) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        let mut removed = 0;
+        queue.retain_mut(|deferred| {
+            if deferred.file.strong_count() > 0 {
+                return true;
+            }
+            deferred
+                .paths
+                .retain(|path| match std::fs::remove_file(path) {
+                    Ok(()) => false,
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => false,
+                    Err(error) => {
+                        println!("Could not remove {path} yet: {error}");
+                        true
+                    }
+                });
+            if !deferred.paths.is_empty() {
+                return true;
+            }
+            println!(
+                "Removed the deferred files of item {} version {}",
+                deferred.item_id, deferred.version
+            );
+            removed += 1;
+            false
+        });
+        self.metrics.deferred_deletes_pending.sub(removed as u64);
+        removed
+    }
+}
@@ -0,0 +1,115 @@
+use crate::persistence::file_persistence::{self, WriteError};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamWriter};
+use crate::persistence::property_index::PropertyIndex;
+use crate::persistence::storage::Storage;
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+/// Writer of a dry run: counts and hashes what an upload would store, but writes nothing
+/// and its commit only reports the receipt the upload would get. The version is checked
+/// against the item's metadata read under a shared lock, the only file a dry run ever
+/// opens, so it claims nothing and leaves no trace however it ends.
+pub struct DiscardWriter {
+    item_version: u64,
+    /// The generation the version would be
+    epoch: u64,
+    /// The item has no committed version yet
+    first_version: bool,
+    bytes_received: u64,
+    /// Bytes of properties a deduplicating upload would cut out at commit
+    dropped_bytes: u64,
+    /// Bytes the commit-time transforms would rewrite
+    bytes_rewritten: u64,
+    hasher: Sha256,
+    /// The properties were rearranged or cut, which a dry run cannot hash without
+    /// keeping them
+    rewritten: bool,
+    content_encoding: Option<String>,
+}
+
+impl DiscardWriter {
+    pub fn new(
+        storage: &Storage,
+        item_id: &str,
+        item_version: u64,
+        max_version_jump: Option<u64>,
+        content_encoding: Option<&str>,
+    ) -> Result<Self, WriteError> {
+        let metadata = file_persistence::load_item_metadata(storage, item_id)?;
+        file_persistence::check_version(&metadata, item_version, max_version_jump)?;
+        Ok(Self {
+            item_version,
+            epoch: metadata.next_epoch(item_version),
+            first_version: metadata.latest_version.is_none(),
+            bytes_received: 0,
+            dropped_bytes: 0,
+            bytes_rewritten: 0,
+            hasher: Sha256::new(),
+            rewritten: false,
+            content_encoding: content_encoding.map(str::to_string),
+        })
+    }
+}
+
+#[async_trait]
+impl ItemStreamWriter for DiscardWriter {
+    async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
+        self.hasher.update(&chunk);
+        self.bytes_received += chunk.len() as u64;
+        Ok(())
+    }
+
+    async fn rewrite_properties(
+        &mut self,
+        index: &PropertyIndex,
+        order: &[usize],
+    ) -> Result<PropertyIndex, String> {
+        let size = self.bytes_received - self.dropped_bytes;
+        let (rewritten_index, ranges) = index.rewrite(order, size)?;
+        let bytes_copied = ranges.iter().map(|(_, length)| length).sum::<u64>();
+        self.dropped_bytes += size - bytes_copied;
+        self.bytes_rewritten += bytes_copied;
+        self.rewritten = true;
+        Ok(rewritten_index)
+    }
+
+    fn store_property_index(&mut self, _index: &PropertyIndex) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn commit(&mut self, details: &CommitDetails) -> Result<VersionMetadata, String> {
+        if details.bytes_received != self.bytes_received {
+            return Err(format!(
+                "Byte accounting mismatch: {} handed to the writer, {} received by the writer",
+                details.bytes_received, self.bytes_received
+            ));
+        }
+        Ok(VersionMetadata {
+            version: self.item_version,
+            size: Some(self.bytes_received - self.dropped_bytes),
+            property_count: Some(details.property_count),
+            sha256: (!self.rewritten).then(|| format!("{:x}", self.hasher.clone().finalize())),
+            request_id: details.request_id.clone(),
+            epoch: Some(self.epoch + u64::from(self.rewritten)),
+            first_version: Some(self.first_version),
+            extra_elements: Some(details.extra_elements.clone())
+                .filter(|extra_elements| !extra_elements.is_empty()),
+            extra_elements_truncated: Some(details.extra_elements_truncated.clone())
+                .filter(|truncated| !truncated.is_empty()),
+            provenance: details.provenance.clone(),
+            user_metadata: Some(details.user_metadata.clone())
+                .filter(|user_metadata| !user_metadata.is_empty()),
+            immutable_until: details.immutable_until.clone(),
+            segments: details.segments.clone(),
+            commit_transforms: Some(details.transforms.clone())
+                .filter(|transforms| !transforms.is_empty()),
+            write_amplification: details.write_amplification(self.bytes_rewritten),
+            content_encoding: self.content_encoding.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn abort(&mut self) {}
+}
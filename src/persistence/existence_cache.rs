@@ -0,0 +1,243 @@
+use crate::metrics::Metrics;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Items cached at most, further items are looked up on disk every time. Keeps lookups of
+/// made-up item IDs from growing the cache without bounds.
+const MAX_ITEMS: usize = 100_000;
+
+/// Versions of one item remembered as missing at most
+const MAX_MISSES_PER_ITEM: usize = 64;
+
+/// Whether a version can be read, as far as the existence cache knows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExistsState {
+    Committed,
+    /// Being uploaded by this instance
+    InFlight,
+    /// Never committed, deleted, or the remains of an interrupted upload
+    Missing,
+}
+
+/// What identifies a version of an item's metadata file without reading it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileStamp {
+    /// The stamp of the file at `path`, `None` if there is none
+    pub fn of(path: &str) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+struct CachedItem {
+    /// Committed versions, sorted
+    committed: Vec<u64>,
+    /// The metadata file the versions were read from
+    stamp: Option<FileStamp>,
+}
+
+/// Committed versions of the items looked up lately, so telling whether a version exists
+/// does not take opening, locking and parsing the item's metadata. Lookups only share a
+/// read lock with each other.
+///
+/// Items are filled in from metadata reads and replaced by every commit, delete and reset
+/// of this instance while it still holds the metadata lock. Changes made by other
+/// processes are caught through the metadata file's modification time and size, compared
+/// on every lookup, unless the data directory watch receives filesystem notifications;
+/// then the watch invalidates the items it is notified of, and lookups do not touch the
+/// disk at all.
+///
+/// A fill races with the writers: it only lands if no item changed since the metadata
+/// was read, so a version deleted meanwhile is never cached as committed.
+pub struct ExistenceCache {
+    items: RwLock<HashMap<String, CachedItem>>,
+    /// Bumped on every change, see [`fill`](Self::fill)
+    generation: AtomicU64,
+    /// The data directory watch reports changes made by other processes
+    watched: AtomicBool,
+    /// Versions reads recently found in none of their sources, by item, with when, see
+    /// [`missed`](Self::missed)
+    misses: Mutex<HashMap<String, HashMap<u64, Instant>>>,
+    /// How long a miss is remembered, not at all when zero
+    miss_ttl: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl ExistenceCache {
+    /// A cache remembering the versions reads did not find for `miss_ttl`
+    pub fn new(miss_ttl: Duration, metrics: Arc<Metrics>) -> Self {
+        Self {
+            items: RwLock::default(),
+            generation: AtomicU64::default(),
+            watched: AtomicBool::default(),
+            misses: Mutex::default(),
+            miss_ttl,
+            metrics,
+        }
+    }
+
+    /// Whether lookups have to compare the metadata file's stamp
+    pub fn needs_stamp(&self) -> bool {
+        !self.watched.load(Ordering::Acquire)
+    }
+
+    /// Whether the data directory watch receives notifications of changes by other
+    /// processes. Items cached before it did may have changed unnoticed, so they are
+    /// dropped.
+    pub fn set_watched(&self, watched: bool) {
+        let mut items = self.items.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        items.clear();
+        self.misses.lock().unwrap().clear();
+        self.watched.store(watched, Ordering::Release);
+    }
+
+    /// Whether the version is committed, `None` if the item is not cached or its metadata
+    /// file no longer has `stamp`
+    pub fn get(
+        &self,
+        item_id: &str,
+        item_version: u64,
+        stamp: Option<Option<FileStamp>>,
+    ) -> Option<bool> {
+        self.lookup(item_id, stamp, |committed| {
+            committed.binary_search(&item_version).is_ok()
+        })
+    }
+
+    /// The latest committed version, `Some(None)` if the item has none, `None` if it is
+    /// not cached or its metadata file no longer has `stamp`
+    pub fn latest(&self, item_id: &str, stamp: Option<Option<FileStamp>>) -> Option<Option<u64>> {
+        self.lookup(item_id, stamp, |committed| committed.last().copied())
+    }
+
+    fn lookup<T>(
+        &self,
+        item_id: &str,
+        stamp: Option<Option<FileStamp>>,
+        answer: impl FnOnce(&[u64]) -> T,
+    ) -> Option<T> {
+        let items = self.items.read().unwrap();
+        let cached = items
+            .get(item_id)
+            .filter(|cached| stamp.is_none_or(|stamp| stamp == cached.stamp));
+        match cached {
+            Some(cached) => {
+                self.metrics.existence_cache_hits.increment();
+                Some(answer(&cached.committed))
+            }
+            None => {
+                self.metrics.existence_cache_misses.increment();
+                None
+            }
+        }
+    }
+
+    /// Taken before reading the metadata to [`fill`](Self::fill) the cache with
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Cache the versions read from an item's metadata, unless anything changed since
+    /// `generation` was taken, as the metadata may be outdated then
+    pub fn fill(
+        &self,
+        item_id: &str,
+        committed: Vec<u64>,
+        stamp: Option<FileStamp>,
+        generation: u64,
+    ) {
+        let mut items = self.items.write().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation
+            && (items.len() < MAX_ITEMS || items.contains_key(item_id))
+        {
+            items.insert(item_id.to_string(), CachedItem { committed, stamp });
+        }
+    }
+
+    /// Replace the versions of an item with those just written to its metadata, to be
+    /// called while holding the metadata lock
+    pub fn store(&self, item_id: &str, committed: Vec<u64>, stamp: Option<FileStamp>) {
+        let mut items = self.items.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if items.len() < MAX_ITEMS || items.contains_key(item_id) {
+            items.insert(item_id.to_string(), CachedItem { committed, stamp });
+        }
+        self.misses.lock().unwrap().remove(item_id);
+    }
+
+    /// Forget an item changed by another process
+    pub fn invalidate(&self, item_id: &str) {
+        let mut items = self.items.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        items.remove(item_id);
+        self.misses.lock().unwrap().remove(item_id);
+    }
+
+    /// Whether a read found the version in none of its sources less than the miss TTL
+    /// ago, and nothing changed the item since
+    pub fn recently_missed(&self, item_id: &str, item_version: u64) -> bool {
+        if self.miss_ttl.is_zero() {
+            return false;
+        }
+        let mut misses = self.misses.lock().unwrap();
+        let Some(versions) = misses.get_mut(item_id) else {
+            return false;
+        };
+        match versions.get(&item_version) {
+            Some(missed_at) if missed_at.elapsed() < self.miss_ttl => {
+                self.metrics.negative_cache_hits.increment();
+                true
+            }
+            Some(_) => {
+                versions.remove(&item_version);
+                if versions.is_empty() {
+                    misses.remove(item_id);
+                }
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remember that a read found the version in none of its sources, unless anything
+    /// changed since `generation` was taken before it looked, as a commit may have come
+    /// in between. Changes made by other processes are only noticed once the miss
+    /// expires, or once the data directory watch reports them.
+    pub fn missed(&self, item_id: &str, item_version: u64, generation: u64) {
+        if self.miss_ttl.is_zero() {
+            return;
+        }
+        let mut misses = self.misses.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) != generation {
+            return;
+        }
+        if misses.len() >= MAX_ITEMS {
+            misses.retain(|_, versions| {
+                versions.retain(|_, missed_at| missed_at.elapsed() < self.miss_ttl);
+                !versions.is_empty()
+            });
+        }
+        if misses.len() >= MAX_ITEMS && !misses.contains_key(item_id) {
+            return;
+        }
+        let versions = misses.entry(item_id.to_string()).or_default();
+        if versions.len() >= MAX_MISSES_PER_ITEM {
+            versions.retain(|_, missed_at| missed_at.elapsed() < self.miss_ttl);
+        }
+        if versions.len() < MAX_MISSES_PER_ITEM {
+            versions.insert(item_version, Instant::now());
+        }
+    }
+}
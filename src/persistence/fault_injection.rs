@@ -0,0 +1,392 @@
+use crate::persistence::block_index::BlockIndex;
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamReader, ItemStreamWriter};
+use crate::persistence::property_index::PropertyIndex;
+use crate::persistence::read_sources::ReadSource;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Failures to inject into the uploads and reads of items whose ID matches
+/// `item_pattern`, where `*` matches any run of characters. Every fault fires with
+/// `probability` (always by default), and the rule stops firing after `times` faults.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    pub item_pattern: String,
+    /// Fail the chunk that takes an upload past this many bytes
+    #[serde(default)]
+    pub write_error_after_bytes: Option<u64>,
+    /// Silently drop up to this many bytes from the end of every chunk written, which the
+    /// commit's byte accounting must catch
+    #[serde(default)]
+    pub drop_write_bytes: Option<u64>,
+    /// Fail the commit of uploads
+    #[serde(default)]
+    pub fail_commit: bool,
+    /// Fail the chunk that takes an upload past this many bytes as a full disk would,
+    /// with `ENOSPC`
+    #[serde(default)]
+    pub disk_full_after_bytes: Option<u64>,
+    /// Fail the commit of uploads as a sync of their data file reporting `EIO` would, the
+    /// way a failing disk shows up
+    #[serde(default)]
+    pub fail_sync: bool,
+    /// Hold every read chunk back for this long
+    #[serde(default)]
+    pub read_stall_ms: Option<u64>,
+    /// Flip the bits of one byte in every chunk read
+    #[serde(default)]
+    pub corrupt_reads: bool,
+    /// Silently drop up to this many bytes from the end of every chunk read, which the
+    /// declared `Content-Length` of a committed read must catch
+    #[serde(default)]
+    pub drop_read_bytes: Option<u64>,
+    /// Fail opening a version from these sources, so reads fall through to the next one
+    #[serde(default)]
+    pub fail_read_sources: Vec<ReadSource>,
+    /// Hold opening a version from these sources back for `read_source_stall_ms`
+    #[serde(default)]
+    pub stall_read_sources: Vec<ReadSource>,
+    #[serde(default)]
+    pub read_source_stall_ms: Option<u64>,
+    #[serde(default)]
+    pub probability: Option<f64>,
+    #[serde(default)]
+    pub times: Option<u64>,
+    /// Faults injected by the rule so far
+    #[serde(default, skip_deserializing)]
+    pub fired: AtomicU64,
+}
+
+impl FaultRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.item_pattern.is_empty() {
+            return Err("item_pattern must not be empty".to_string());
+        }
+        if self
+            .probability
+            .is_some_and(|probability| !(0.0..=1.0).contains(&probability))
+        {
+            return Err("probability must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the rule injects anything at all, rules that do not are removed
+    pub fn is_active(&self) -> bool {
+        self.write_error_after_bytes.is_some()
+            || self.drop_write_bytes.is_some()
+            || self.fail_commit
+            || self.disk_full_after_bytes.is_some()
+            || self.fail_sync
+            || self.read_stall_ms.is_some()
+            || self.corrupt_reads
+            || self.drop_read_bytes.is_some()
+            || !self.fail_read_sources.is_empty()
+            || (!self.stall_read_sources.is_empty() && self.read_source_stall_ms.is_some())
+    }
+
+    /// A copy of the rule as it is now, fire count included
+    fn snapshot(&self) -> Self {
+        Self {
+            item_pattern: self.item_pattern.clone(),
+            fail_read_sources: self.fail_read_sources.clone(),
+            stall_read_sources: self.stall_read_sources.clone(),
+            fired: AtomicU64::new(self.fired.load(Ordering::Acquire)),
+            ..*self
+        }
+    }
+
+    fn matches(&self, item_id: &str) -> bool {
+        matches_pattern(self.item_pattern.as_bytes(), item_id.as_bytes())
+    }
+
+    /// Decide whether a fault fires now, counting it if it does
+    fn fire(&self) -> bool {
+        if self
+            .times
+            .is_some_and(|times| self.fired.load(Ordering::Acquire) >= times)
+        {
+            return false;
+        }
+        if let Some(probability) = self.probability
+            && random_fraction() >= probability
+        {
+            return false;
+        }
+        self.fired.fetch_add(1, Ordering::AcqRel);
+        true
+    }
+}
+
+/// The fault rules of one instance, which wrap the writers and readers of matching items
+/// while the instance allows fault injection
+pub struct FaultInjector {
+    enabled: bool,
+    rules: RwLock<Vec<Arc<FaultRule>>>,
+}
+
+impl FaultInjector {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|rule| rule.snapshot())
+            .collect()
+    }
+
+    /// Install `rule`, replacing the rule for the same pattern. A rule that injects
+    /// nothing removes it instead.
+    pub fn set_rule(&self, rule: FaultRule) -> Result<(), String> {
+        if !self.enabled {
+            return Err("Fault injection is disabled, set STREAM_DB_FAULT_INJECTION".to_string());
+        }
+        rule.validate()?;
+        let mut rules = self.rules.write().unwrap();
+        rules.retain(|existing| existing.item_pattern != rule.item_pattern);
+        if rule.is_active() {
+            rules.push(Arc::new(rule));
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        self.rules.write().unwrap().clear();
+    }
+
+    /// The first rule matching `item_id`, picked once when a stream is opened
+    fn rule_for(&self, item_id: &str) -> Option<Arc<FaultRule>> {
+        if !self.enabled {
+            return None;
+        }
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|rule| rule.matches(item_id))
+            .cloned()
+    }
+
+    pub fn wrap_writer(
+        &self,
+        item_id: &str,
+        writer: Box<dyn ItemStreamWriter>,
+    ) -> Box<dyn ItemStreamWriter> {
+        match self.rule_for(item_id) {
+            Some(rule) => Box::new(FaultInjectingWriter {
+                inner: writer,
+                rule,
+                bytes_written: 0,
+            }),
+            None => writer,
+        }
+    }
+
+    /// Stall or fail opening a version of `item_id` from `source`, as the matching rule
+    /// says. A stall blocks the thread, for at most `budget`.
+    pub fn open_from(
+        &self,
+        item_id: &str,
+        source: ReadSource,
+        budget: Duration,
+    ) -> Result<(), String> {
+        let Some(rule) = self.rule_for(item_id) else {
+            return Ok(());
+        };
+        if let Some(stall_ms) = rule.read_source_stall_ms
+            && rule.stall_read_sources.contains(&source)
+            && rule.fire()
+        {
+            std::thread::sleep(Duration::from_millis(stall_ms).min(budget));
+        }
+        if rule.fail_read_sources.contains(&source) && rule.fire() {
+            return Err(format!(
+                "Injected fault: read source {} failed",
+                source.name()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn wrap_reader(
+        &self,
+        item_id: &str,
+        reader: Box<dyn ItemStreamReader>,
+    ) -> Box<dyn ItemStreamReader> {
+        match self.rule_for(item_id) {
+            Some(rule) => Box::new(FaultInjectingReader {
+                inner: reader,
+                rule,
+            }),
+            None => reader,
+        }
+    }
+}
+
+/// `errno` values of a full disk and of a failed I/O, the same on Linux and macOS
+const ENOSPC: i32 = 28;
+const EIO: i32 = 5;
+
+/// Passes everything through to the wrapped writer unless its rule fires
+struct FaultInjectingWriter {
+    inner: Box<dyn ItemStreamWriter>,
+    rule: Arc<FaultRule>,
+    bytes_written: u64,
+}
+
+#[async_trait]
+impl ItemStreamWriter for FaultInjectingWriter {
+    async fn write_chunk(&mut self, mut chunk: Vec<u8>) -> Result<(), String> {
+        let chunk_len = chunk.len() as u64;
+        if let Some(max_bytes) = self.rule.write_error_after_bytes
+            && self.bytes_written + chunk_len > max_bytes
+            && self.rule.fire()
+        {
+            return Err(format!(
+                "Injected fault: write failed after {max_bytes} bytes"
+            ));
+        }
+        if let Some(max_bytes) = self.rule.disk_full_after_bytes
+            && self.bytes_written + chunk_len > max_bytes
+            && self.rule.fire()
+        {
+            return Err(format!(
+                "Injected fault: write failed after {} bytes: {}",
+                self.bytes_written,
+                std::io::Error::from_raw_os_error(ENOSPC)
+            ));
+        }
+        if let Some(drop_bytes) = self.rule.drop_write_bytes
+            && !chunk.is_empty()
+            && self.rule.fire()
+        {
+            chunk.truncate(chunk.len().saturating_sub(drop_bytes as usize));
+        }
+        self.inner.write_chunk(chunk).await?;
+        self.bytes_written += chunk_len;
+        Ok(())
+    }
+
+    async fn rewrite_properties(
+        &mut self,
+        index: &PropertyIndex,
+        order: &[usize],
+    ) -> Result<PropertyIndex, String> {
+        self.inner.rewrite_properties(index, order).await
+    }
+
+    fn store_property_index(&mut self, index: &PropertyIndex) -> Result<(), String> {
+        self.inner.store_property_index(index)
+    }
+
+    async fn commit(&mut self, details: &CommitDetails) -> Result<VersionMetadata, String> {
+        if self.rule.fail_commit && self.rule.fire() {
+            return Err("Injected fault: commit failed".to_string());
+        }
+        if self.rule.fail_sync && self.rule.fire() {
+            return Err(format!(
+                "Injected fault: sync of the data file failed: {}",
+                std::io::Error::from_raw_os_error(EIO)
+            ));
+        }
+        self.inner.commit(details).await
+    }
+
+    fn abort(&mut self) {
+        self.inner.abort()
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn superseded_by(&self) -> Option<u64> {
+        self.inner.superseded_by()
+    }
+}
+
+/// Passes everything through to the wrapped reader unless its rule fires
+struct FaultInjectingReader {
+    inner: Box<dyn ItemStreamReader>,
+    rule: Arc<FaultRule>,
+}
+
+#[async_trait]
+impl ItemStreamReader for FaultInjectingReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(stall_ms) = self.rule.read_stall_ms
+            && self.rule.fire()
+        {
+            tokio::time::sleep(Duration::from_millis(stall_ms)).await;
+        }
+        let mut chunk = self.inner.read_chunk().await?;
+        if let Some(chunk) = chunk.as_mut().filter(|chunk| !chunk.is_empty())
+            && self.rule.corrupt_reads
+            && self.rule.fire()
+        {
+            let middle = chunk.len() / 2;
+            chunk[middle] ^= 0xff;
+        }
+        if let Some(drop_bytes) = self.rule.drop_read_bytes
+            && let Some(chunk) = chunk.as_mut().filter(|chunk| !chunk.is_empty())
+            && self.rule.fire()
+        {
+            chunk.truncate(chunk.len().saturating_sub(drop_bytes as usize));
+        }
+        Ok(chunk)
+    }
+
+    fn property_index(&self) -> Result<Option<PropertyIndex>, String> {
+        self.inner.property_index()
+    }
+
+    fn block_index(&self) -> Result<Option<BlockIndex>, String> {
+        self.inner.block_index()
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), String> {
+        self.inner.seek(offset)
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.inner.is_aborted()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.inner.is_too_slow()
+    }
+}
+
+/// Glob match supporting only `*`
+pub fn matches_pattern(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| matches_pattern(rest, &text[skip..])),
+        Some((byte, rest)) => text
+            .split_first()
+            .is_some_and(|(first, text)| first == byte && matches_pattern(rest, text)),
+    }
+}
+
+/// Uniformly distributed in `[0, 1)`, good enough to pick which faults fire
+fn random_fraction() -> f64 {
+    // The low 62 bits of a v4 UUID are all random
+    const MANTISSA: u64 = 1 << 53;
+    (uuid::Uuid::new_v4().as_u128() as u64 % MANTISSA) as f64 / MANTISSA as f64
+}
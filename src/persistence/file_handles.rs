@@ -0,0 +1,121 @@
+use crate::metrics::Metrics;
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Handles a new stream may keep open: an upload's data file, the duplicate its lock is
+/// held through, its upload marker, its journal and the read handle shared with its
+/// readers
+pub const STREAM_HANDLES: u64 = 5;
+
+/// File handles kept open by the persistence layer, against `STREAM_DB_MAX_OPEN_FILES`.
+/// The long-lived ones are counted: the read handle of every version in the registry and
+/// the handles every upload holds until it commits or aborts. Handles opened for a
+/// moment to read or write metadata and indexes are not, and neither are sockets, so the
+/// ceiling has to leave room for them below `RLIMIT_NOFILE`.
+pub struct FileHandles {
+    open: Arc<AtomicU64>,
+    ceiling: Option<u64>,
+    metrics: Arc<Metrics>,
+}
+
+/// Handles counted as open until the guard is dropped
+pub struct HandleGuard {
+    open: Arc<AtomicU64>,
+    count: u64,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for HandleGuard {
+    fn drop(&mut self) {
+        self.open.fetch_sub(self.count, Ordering::AcqRel);
+        self.metrics.open_files.sub(self.count);
+    }
+}
+
+impl FileHandles {
+    pub fn new(ceiling: Option<u64>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            open: Arc::new(AtomicU64::new(0)),
+            ceiling,
+            metrics,
+        }
+    }
+
+    /// Count `count` handles as open for as long as the returned guard lives
+    pub fn track(&self, count: u64) -> HandleGuard {
+        self.open.fetch_add(count, Ordering::AcqRel);
+        self.metrics.open_files.add(count);
+        HandleGuard {
+            open: self.open.clone(),
+            count,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    pub fn open(&self) -> u64 {
+        self.open.load(Ordering::Acquire)
+    }
+
+    pub fn ceiling(&self) -> Option<u64> {
+        self.ceiling
+    }
+
+    /// Whether idle handles should be closed before a new stream starts, from 90% of the
+    /// ceiling on
+    pub fn is_near_ceiling(&self) -> bool {
+        self.ceiling
+            .is_some_and(|ceiling| self.open() + STREAM_HANDLES > ceiling - ceiling / 10)
+    }
+
+    /// Whether the handles of a new stream still fit below the ceiling
+    pub fn has_room(&self) -> bool {
+        self.ceiling
+            .is_none_or(|ceiling| self.open() + STREAM_HANDLES <= ceiling)
+    }
+}
+
+/// A stream refused because its handles would not fit below the ceiling
+#[derive(Serialize)]
+pub struct HandlesExhausted {
+    pub open_files: u64,
+    pub max_open_files: u64,
+    #[serde(skip)]
+    pub message: String,
+}
+
+/// Where the file handles of the instance stand, for `/admin/storage`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileHandleReport {
+    /// Handles counted by the persistence layer
+    pub open_files: u64,
+    pub max_open_files: Option<u64>,
+    /// Every handle of the process, sockets included, where the platform tells
+    pub process_open_files: Option<u64>,
+    pub nofile_soft_limit: Option<u64>,
+    pub nofile_hard_limit: Option<u64>,
+    /// Versions in the registry, each holding a read handle
+    pub registered_versions: usize,
+    /// ... of which nothing but the registry holds on to, closed first when handles run low
+    pub idle_versions: usize,
+}
+
+/// Handles the whole process has open, only known on Linux
+pub fn process_open_files() -> Option<u64> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count() as u64)
+}
+
+/// The soft and hard `RLIMIT_NOFILE`
+pub fn nofile_limits() -> Option<(u64, u64)> {
+    rlimit::getrlimit(rlimit::Resource::NOFILE).ok()
+}
+
+/// Raise the soft `RLIMIT_NOFILE` as far as the hard limit allows, returning the new
+/// soft limit
+pub fn raise_nofile_limit() -> Result<u64, String> {
+    rlimit::increase_nofile_limit(u64::MAX)
+        .map_err(|error| format!("Could not raise RLIMIT_NOFILE: {error}"))
+}
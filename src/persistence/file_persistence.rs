@@ -1,198 +1,3679 @@
-use crate::persistence::item_persistence::{ItemStreamReader, ItemStreamWriter};
-use crate::persistence::shared_file::{SharedFile, get_shared_file_registry};
+use crate::log_control;
+use crate::metrics::Metrics;
+use crate::persistence::block_index::BlockIndex;
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::cold_tier::{self, StorageTier};
+use crate::persistence::deferred_deletes::{self, DeferredReason};
+use crate::persistence::existence_cache::{ExistsState, FileStamp};
+use crate::persistence::fault_injection::FaultInjector;
+use crate::persistence::file_handles::{self, FileHandleReport, HandleGuard, HandlesExhausted};
+use crate::persistence::integrity::{self, IntegrityFailure};
+use crate::persistence::io_engine::{Appender, FsyncPolicy};
+use crate::persistence::io_failure::IoFailure;
+use crate::persistence::io_scheduler::IoPermit;
+use crate::persistence::item_metadata::{ItemMetadata, VersionMetadata};
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamReader, ItemStreamWriter};
+use crate::persistence::item_settings;
+use crate::persistence::journal::{self, Checkpoint, Journal};
+use crate::persistence::part_checksums::PartChecksums;
+use crate::persistence::property_index::PropertyIndex;
+use crate::persistence::publish_groups::{self, PublishGroupError};
+use crate::persistence::read_sources::ReadSource;
+use crate::persistence::shared_file::{
+    ItemClaim, ReaderProgress, SharedFile, SlowReaderLimit, SlowReaderPolicy,
+};
+use crate::persistence::storage::{CommitStrategy, Storage};
+use crate::persistence::store_format;
+use crate::persistence::stream_phase::{PhaseStatus, StreamPhase};
+use crate::persistence::sync_points::{self, SyncPoint};
+use crate::persistence::write_queue::JoinError;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use fs2::FileExt;
 use quick_xml::Reader;
 use quick_xml::events::Event;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
+use std::ops::Bound;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::File as TokioFile;
-use tokio::io::AsyncWriteExt;
 
-static OUTPUT_FOLDER_PATH: &str = "tmp_outputs";
-const CHUNK_SIZE: usize = 8192; // 8KB chunks for reading
+pub fn init(storage: &Storage) -> Result<(), String> {
+    println!("Initializing file persistence in {}", storage.data_dir);
+    if storage.read_only {
+        // Uncommitted data files belong to uploads of the writing instance, they are not
+        // interrupted
+        if !std::path::Path::new(&storage.data_dir).is_dir() {
+            return Err(format!(
+                "Data directory {} does not exist, read-only instances do not create it",
+                storage.data_dir
+            ));
+        }
+    } else {
+        std::fs::create_dir_all(&storage.data_dir)
+            .map_err(|error| IoFailure::new("create directory", &storage.data_dir, &error))?;
+        std::fs::create_dir_all(&storage.inflight_dir)
+            .map_err(|error| IoFailure::new("create directory", &storage.inflight_dir, &error))?;
+        let strategy = commit_strategy(storage)?;
+        let _ = storage.commit_strategy.set(strategy);
+        match strategy {
+            CommitStrategy::InPlace => {}
+            CommitStrategy::Rename => println!(
+                "Uploads are written to {} and renamed into place on commit, it shares a filesystem with the data directory",
+                storage.inflight_dir
+            ),
+            CommitStrategy::Copy => println!(
+                "Uploads are written to {} and copied into place on commit, it is on another filesystem than the data directory",
+                storage.inflight_dir
+            ),
+        }
+    }
+    store_format::migrate(storage)?;
+    if !storage.read_only {
+        let staged = publish_groups::recover(storage)?;
+        recover_interrupted_uploads(storage, &staged)?;
+        remove_set_aside_files(storage)?;
+    }
+    count_committed_bytes(storage)?;
+    println!(
+        "Committed versions take up {} bytes",
+        storage.usage.committed_bytes()
+    );
+    Ok(())
+}
+
+/// Remove the files of deleted versions whose readers a restart cut off, see
+/// [`deferred_deletes::set_aside_path`]
+fn remove_set_aside_files(storage: &Storage) -> Result<(), String> {
+    let dirs = std::iter::once(storage.data_dir.as_str()).chain(storage.read_sources.cold_dir());
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(IoFailure::new("list directory", dir, &error).into()),
+        };
+        for entry in entries {
+            let entry = entry.map_err(|error| IoFailure::new("list directory", dir, &error))?;
+            if !entry
+                .file_name()
+                .to_str()
+                .is_some_and(deferred_deletes::is_set_aside)
+            {
+                continue;
+            }
+            let path = entry.path().display().to_string();
+            match std::fs::remove_file(&path) {
+                Ok(()) => println!("Removed {path}, left by a delete before a restart"),
+                Err(error) => println!("Could not remove {path}: {error}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decide how uploads move into the data directory, by whether the in-flight directory
+/// is on the same filesystem, where a rename is atomic
+fn commit_strategy(storage: &Storage) -> Result<CommitStrategy, String> {
+    if !storage.has_inflight_dir() {
+        return Ok(CommitStrategy::InPlace);
+    }
+    if same_filesystem(&storage.inflight_dir, &storage.data_dir)? {
+        Ok(CommitStrategy::Rename)
+    } else {
+        Ok(CommitStrategy::Copy)
+    }
+}
+
+/// Whether the directories `from` and `to` are on one device
+#[cfg(unix)]
+fn same_filesystem(from: &str, to: &str) -> Result<bool, String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |dir: &str| {
+        std::fs::metadata(dir)
+            .map(|metadata| metadata.dev())
+            .map_err(|error| IoFailure::new("inspect", dir, &error).to_string())
+    };
+    Ok(device(from)? == device(to)?)
+}
+
+/// Whether a file can be renamed from the directory `from` into `to`, which fails across
+/// filesystems, where there are no device IDs to compare
+#[cfg(not(unix))]
+fn same_filesystem(from: &str, to: &str) -> Result<bool, String> {
+    let probe_name = format!(".rename-probe-{}", uuid::Uuid::new_v4());
+    let probe = format!("{from}/{probe_name}");
+    let target = format!("{to}/{probe_name}");
+    File::create(&probe).map_err(|error| format!("Failed to create {probe}: {error}"))?;
+    let renamed = std::fs::rename(&probe, &target).is_ok();
+    let _ = std::fs::remove_file(if renamed { &target } else { &probe });
+    Ok(renamed)
+}
+
+/// Recount the bytes of all committed versions from the metadata, which commits and
+/// deletes then keep up to date
+pub fn count_committed_bytes(storage: &Storage) -> Result<(), String> {
+    let mut committed_bytes = 0;
+    for item_id in cold_tier::item_ids(storage)? {
+        // One broken item does not keep the others from being served, its reads fail
+        let metadata = match load_item_metadata(storage, &item_id) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                println!(
+                    "Not counting the versions of item {item_id}, its metadata cannot be read: {error}"
+                );
+                continue;
+            }
+        };
+        for (item_version, committed) in &metadata.versions {
+            // Versions committed before sizes were recorded are measured on disk
+            committed_bytes += committed.size.unwrap_or_else(|| {
+                let path = version_file_path(
+                    storage,
+                    committed.location.as_deref(),
+                    &data_file_name(&item_id, *item_version),
+                );
+                std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+            });
+        }
+    }
+    storage.usage.set_committed(committed_bytes);
+    Ok(())
+}
+
+/// Leftovers of an upload that was still in flight when the previous process stopped
+#[derive(Serialize, Clone)]
+pub struct FailedUpload {
+    pub item_id: String,
+    pub version: u64,
+    /// Bytes that made it to disk
+    pub size: u64,
+    /// RFC 3339 timestamp in UTC of the last write
+    pub last_modified: String,
+    /// Last checkpoint of the upload's journal the data file was cut back to, with
+    /// `STREAM_DB_JOURNAL_INTERVAL_MB`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+    #[serde(skip)]
+    modified: SystemTime,
+}
+
+/// Find data files of versions their item's metadata does not list as committed. They
+/// are the remains of uploads interrupted by a crash and stay unreadable until they are
+/// deleted or uploaded again. Where the upload kept a journal, its data file is cut back
+/// to the last checkpoint, the bytes known to have been synced.
+///
+/// Uploads are written to the in-flight directory, but the data directory is searched
+/// as well: a commit cut short right after moving the data file leaves it there, and so
+/// do uploads interrupted before `STREAM_DB_INFLIGHT_DIR` was set. The versions `staged`
+/// in a publish group are there too, waiting for the group's commit.
+fn recover_interrupted_uploads(
+    storage: &Storage,
+    staged: &HashSet<(String, u64)>,
+) -> Result<(), String> {
+    let mut metadata_by_item: HashMap<String, Option<ItemMetadata>> = HashMap::new();
+    let mut failed_uploads = BTreeMap::new();
+    let mut journals = Vec::new();
+
+    for dir in upload_dirs(storage) {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|error| IoFailure::new("list directory", dir, &error))?;
+        for entry in entries {
+            let entry = entry.map_err(|error| IoFailure::new("list directory", dir, &error))?;
+            let file_name = entry.file_name();
+            if let Some((item_id, version)) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".journal.jsonl"))
+                .and_then(|name| name.rsplit_once('_'))
+                .and_then(|(item_id, version)| Some((item_id.to_string(), version.parse().ok()?)))
+            {
+                journals.push((dir, item_id, version));
+                continue;
+            }
+            // A copy into the data directory cut short, the upload is still in flight, or
+            // a metadata rewrite that never replaced the metadata
+            if let Some(name) = file_name.to_str().filter(|name| {
+                name.ends_with(".commit.tmp") || name.ends_with(METADATA_REPLACEMENT_SUFFIX)
+            }) {
+                let path = format!("{dir}/{name}");
+                if let Err(error) = std::fs::remove_file(&path) {
+                    println!("Could not remove {path}: {error}");
+                }
+                continue;
+            }
+            let Some((item_id, item_version)) = file_name.to_str().and_then(parse_data_file_name)
+            else {
+                continue;
+            };
+            // Uploads running in this process are not interrupted
+            if storage.registry.get(item_id, item_version).is_some() {
+                continue;
+            }
+            let metadata = match metadata_by_item.entry(item_id.to_string()) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let metadata = ItemMetadata::load(&metadata_path(storage, item_id));
+                    if let Err(error) = &metadata {
+                        // Its files may well be committed, they are left alone
+                        println!(
+                            "Not looking for interrupted uploads of item {item_id}, its metadata cannot be read: {error}"
+                        );
+                    }
+                    entry.insert(metadata.ok())
+                }
+            };
+            let Some(metadata) = metadata else {
+                continue;
+            };
+            if metadata.versions.contains_key(&item_version)
+                || staged.contains(&(item_id.to_string(), item_version))
+            {
+                continue;
+            }
+
+            let file_metadata = entry.metadata().map_err(|error| {
+                IoFailure::new("inspect", &entry.path().display().to_string(), &error)
+            })?;
+            let modified = file_metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            let mut size = file_metadata.len();
+            let checkpoint = restore_checkpoint(storage, dir, item_id, item_version, size);
+            if let Some(checkpoint) = &checkpoint {
+                size = checkpoint.offset;
+            }
+            failed_uploads.insert(
+                (item_id.to_string(), item_version),
+                FailedUpload {
+                    item_id: item_id.to_string(),
+                    version: item_version,
+                    size,
+                    last_modified: chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339(),
+                    checkpoint,
+                    modified,
+                },
+            );
+        }
+    }
+
+    // Journals outliving their upload, e.g. of a commit cut short before its cleanup
+    for (dir, item_id, item_version) in journals {
+        if !storage.read_only
+            && !failed_uploads.contains_key(&(item_id.clone(), item_version))
+            && storage.registry.get(&item_id, item_version).is_none()
+        {
+            journal::remove(&format!(
+                "{dir}/{}",
+                journal_file_name(&item_id, item_version)
+            ));
+        }
+    }
+
+    if !failed_uploads.is_empty() {
+        println!(
+            "Found {} uploads interrupted before they were committed",
+            failed_uploads.len()
+        );
+    }
+    *storage.failed_uploads.lock().unwrap() = failed_uploads;
+    Ok(())
+}
+
+/// Cut the data file of an interrupted upload back to the last checkpoint of its journal,
+/// if the bytes up to it are still those checkpointed. Bytes past the checkpoint may not
+/// have reached the disk in full. A read-only instance reports the checkpoint without
+/// touching the file.
+fn restore_checkpoint(
+    storage: &Storage,
+    dir: &str,
+    item_id: &str,
+    item_version: u64,
+    size: u64,
+) -> Option<Checkpoint> {
+    let journal_path = format!("{dir}/{}", journal_file_name(item_id, item_version));
+    let data_path = format!("{dir}/{}", data_file_name(item_id, item_version));
+    let checkpoint = match journal::last_checkpoint(&journal_path) {
+        Ok(checkpoint) => checkpoint?,
+        Err(error) => {
+            println!("Ignoring journal of {item_id} version {item_version}: {error}");
+            return None;
+        }
+    };
+    match journal::matches(&data_path, &checkpoint) {
+        Ok(true) => {}
+        Ok(false) => {
+            println!(
+                "Ignoring journal of {item_id} version {item_version}, the data file does not match its checkpoint at {} bytes",
+                checkpoint.offset
+            );
+            return None;
+        }
+        Err(error) => {
+            println!("Ignoring journal of {item_id} version {item_version}: {error}");
+            return None;
+        }
+    }
+    if storage.read_only || size == checkpoint.offset {
+        return Some(checkpoint);
+    }
+    let truncated = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&data_path)
+        .and_then(|file| {
+            file.set_len(checkpoint.offset)?;
+            file.sync_all()
+        });
+    match truncated {
+        Ok(()) => {
+            println!(
+                "Cut interrupted upload of {item_id} version {item_version} back from {size} to {} bytes, its last checkpoint",
+                checkpoint.offset
+            );
+            Some(checkpoint)
+        }
+        Err(error) => {
+            println!(
+                "Could not cut {item_id} version {item_version} back to its checkpoint: {error}"
+            );
+            None
+        }
+    }
+}
+
+/// Splits `{item_id}_{version}.xml` into its parts
+fn parse_data_file_name(file_name: &str) -> Option<(&str, u64)> {
+    let (item_id, item_version) = file_name.strip_suffix(".xml")?.rsplit_once('_')?;
+    Some((item_id, item_version.parse().ok()?))
+}
+
+pub fn failed_uploads(storage: &Storage) -> Vec<FailedUpload> {
+    storage
+        .failed_uploads
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Remove the leftovers of an interrupted upload, returning how many files were removed,
+/// or `None` when the version is not an interrupted upload
+fn remove_failed_upload(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<Option<usize>, DeleteError> {
+    let key = (item_id.to_string(), item_version);
+    if !storage.failed_uploads.lock().unwrap().contains_key(&key) {
+        return Ok(None);
+    }
+
+    // A new upload of the version holds this lock and owns the file from then on
+    let mut data_files = Vec::new();
+    for dir in upload_dirs(storage) {
+        let data_path = format!("{dir}/{}", data_file_name(item_id, item_version));
+        match File::open(&data_path) {
+            Ok(data_file) => data_files.push(data_file),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+            Err(error) => {
+                return Err(DeleteError::Failed(
+                    IoFailure::new("open data file", &data_path, &error).into(),
+                ));
+            }
+        }
+    }
+    for data_file in &data_files {
+        data_file
+            .try_lock_exclusive()
+            .map_err(|_| DeleteError::Locked("Version is being uploaded again".to_string()))?;
+    }
+    if storage
+        .failed_uploads
+        .lock()
+        .unwrap()
+        .remove(&key)
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let mut paths = vec![
+        property_index_path(storage, item_id, item_version),
+        block_index_path(storage, item_id, item_version),
+    ];
+    for dir in upload_dirs(storage) {
+        paths.push(format!("{dir}/{}", data_file_name(item_id, item_version)));
+        paths.push(format!(
+            "{dir}/{}",
+            journal_file_name(item_id, item_version)
+        ));
+    }
+    let mut files_removed = 0;
+    for path in paths {
+        match std::fs::remove_file(&path) {
+            Ok(()) => files_removed += 1,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+            Err(error) => println!("Could not remove {path}: {error}"),
+        }
+    }
+    println!("Removed interrupted upload of item {item_id} version {item_version}");
+    Ok(Some(files_removed))
+}
+
+/// Remove interrupted uploads whose last write is older than `retention`, returning how
+/// many were removed
+pub fn purge_failed_uploads(storage: &Storage, retention: Duration) -> usize {
+    let expired: Vec<(String, u64)> = storage
+        .failed_uploads
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|failed| failed.modified.elapsed().is_ok_and(|age| age >= retention))
+        .map(|failed| (failed.item_id.clone(), failed.version))
+        .collect();
+
+    let mut purged = 0;
+    for (item_id, item_version) in expired {
+        match remove_failed_upload(storage, &item_id, item_version) {
+            Ok(Some(_)) => purged += 1,
+            Ok(None) => (),
+            Err(
+                DeleteError::NotFound(error)
+                | DeleteError::Locked(error)
+                | DeleteError::Tagged { message: error, .. }
+                | DeleteError::Failed(error),
+            ) => {
+                println!("Could not purge item {item_id} version {item_version}: {error}")
+            }
+            // Only committed versions are immutable
+            Err(DeleteError::Immutable(_)) => (),
+        }
+    }
+    purged
+}
+
+pub(crate) fn metadata_path(storage: &Storage, item_id: &str) -> String {
+    storage.path(&format!("{item_id}_metadata.xml"))
+}
+
+/// Marker an upload of the item holds locked in `slot`, so uploads in other processes
+/// back off
+fn writing_marker_path(storage: &Storage, item_id: &str, slot: usize) -> String {
+    match slot {
+        0 => storage.path(&format!("{item_id}.writing")),
+        slot => storage.path(&format!("{item_id}.writing.{slot}")),
+    }
+}
+
+pub(crate) fn data_file_name(item_id: &str, item_version: u64) -> String {
+    format!("{item_id}_{item_version}.xml")
+}
+
+pub(crate) fn property_index_file_name(item_id: &str, item_version: u64) -> String {
+    format!("{item_id}_{item_version}.index.jsonl")
+}
+
+pub(crate) fn block_index_file_name(item_id: &str, item_version: u64) -> String {
+    format!("{item_id}_{item_version}.blocks.json")
+}
+
+/// Kept in the data directory whichever tier the version is in
+fn part_checksums_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.path(&format!("{item_id}_{item_version}.parts.json"))
+}
+
+/// Path of one of a version's files, in the cold-tier `location` it was moved to or
+/// else in the data directory
+pub(crate) fn version_file_path(
+    storage: &Storage,
+    location: Option<&str>,
+    file_name: &str,
+) -> String {
+    match location {
+        Some(location) => format!("{location}/{file_name}"),
+        None => storage.path(file_name),
+    }
+}
+
+fn journal_file_name(item_id: &str, item_version: u64) -> String {
+    format!("{item_id}_{item_version}.journal.jsonl")
+}
+
+/// Where a committed version's data file is, unless it was moved to the cold tier
+pub(crate) fn data_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.path(&data_file_name(item_id, item_version))
+}
+
+/// Where an upload writes its data file until it commits
+fn inflight_data_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.inflight_path(&data_file_name(item_id, item_version))
+}
+
+/// Journals are kept next to the data file of the upload
+fn journal_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.inflight_path(&journal_file_name(item_id, item_version))
+}
+
+/// Directories data files of uncommitted versions may be in
+fn upload_dirs(storage: &Storage) -> Vec<&str> {
+    let mut dirs = vec![storage.data_dir.as_str()];
+    if storage.has_inflight_dir() {
+        dirs.push(&storage.inflight_dir);
+    }
+    dirs
+}
+
+fn property_index_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.path(&property_index_file_name(item_id, item_version))
+}
+
+fn block_index_path(storage: &Storage, item_id: &str, item_version: u64) -> String {
+    storage.path(&block_index_file_name(item_id, item_version))
+}
+
+pub struct FileWriter {
+    storage: Arc<Storage>,
+    data_file: Appender,
+    item_id: String,
+    item_version: u64,
+    shared_file: Arc<SharedFile>,
+    /// Bytes handed to `write_chunk`, checked against the bytes written at commit
+    bytes_received: u64,
+    current_offset: u64,
+    is_done: bool,
+    hasher: Sha256,
+    /// The data file was replaced by a rewritten copy, see `rewrite_properties`
+    rewritten: bool,
+    /// Bytes of the properties `rewrite_properties` cut out of the data file
+    dropped_bytes: u64,
+    /// Bytes `rewrite_properties` copied into the rewritten data file
+    bytes_rewritten: u64,
+    /// The data file was moved from the in-flight directory into the data directory,
+    /// here, on commit
+    moved_to: Option<String>,
+    /// Identifies the upload to the I/O scheduler
+    io_stream: u64,
+    /// Checkpoints of the upload, with `STREAM_DB_JOURNAL_INTERVAL_MB`
+    journal: Option<Journal>,
+    /// Counts the upload marker, the data file, its lock duplicate and the journal as open
+    _handles: HandleGuard,
+    /// Publish group the version is staged in on commit rather than committed, see
+    /// [`publish_groups`]
+    publish_group: Option<String>,
+    /// Newer version that was committed while this one was uploaded, failing its commit
+    superseded_by: Option<u64>,
+    /// Debug lines are written for the upload, see [`LogControl::debug_enabled`](log_control::LogControl::debug_enabled)
+    debug: bool,
+    /// Stops the upload from outside, see [`kill_stream`]
+    cancellation: Cancellation,
+}
+
+impl FileWriter {
+    /// Pass `point` of the read-while-write protocol, see [`sync_points`]
+    fn sync_point(&self, point: SyncPoint) {
+        sync_points::reached(&self.shared_file.metadata_path, self.item_version, point);
+    }
+
+    /// Start an upload of `item_version`. Every check that can reject the upload runs
+    /// before the data file is touched, so a rejected upload leaves nothing behind.
+    ///
+    /// The metadata lock is only held while the version is validated and again while it
+    /// is committed, never during the transfer, so stats, receipts, reads and deletes of
+    /// the item's other versions go on meanwhile. This relies on three invariants:
+    /// - One upload per item at a time by default: the upload holds the item's claim
+    ///   from before the validation until it commits or aborts, see
+    ///   [`crate::persistence::shared_file::ItemClaim`]. No other upload can commit a
+    ///   newer version in between, so a validated version stays valid. With
+    ///   `STREAM_DB_MAX_IN_FLIGHT_PER_ITEM` above 1 uploads of different versions share
+    ///   the item, and one overtaken by a newer version fails its commit with
+    ///   [`WriteError::Superseded`].
+    /// - The metadata only ever lists committed versions. A validated upload adds
+    ///   nothing to it, so metadata readers see the item as it was before the upload.
+    /// - Whoever else rewrites the metadata (deletes, tier moves, other processes) does
+    ///   so under the lock, and the commit re-reads the metadata under the lock and
+    ///   checks the version again, so their changes are kept.
+    ///
+    /// An upload that waited for the item with [`claim_item_queued`] passes the `claim`
+    /// it got, and is validated against the metadata the upload before it committed.
+    ///
+    /// An upload to a `publish_group` is staged in it on commit. Its data file is never
+    /// registered, so readers do not follow it and nothing sees the version before the
+    /// group is committed.
+    ///
+    /// The upload stops at its next chunk once `cancellation` fires, which a kill of the
+    /// version does too.
+    pub fn new(
+        storage: &Arc<Storage>,
+        item_id: &str,
+        item_version: &u64,
+        max_version_jump: Option<u64>,
+        claim: Option<ItemClaim>,
+        publish_group: Option<&str>,
+        cancellation: &Cancellation,
+    ) -> Result<Self, WriteError> {
+        let metadata_path = metadata_path(storage, item_id);
+        let versioned_path = inflight_data_path(storage, item_id, *item_version);
+        let debug = storage.log.debug_enabled(item_id);
+        let started = Instant::now();
+
+        // 1. Claim the item, released when the upload commits or aborts
+        let claim = match claim {
+            Some(claim) => claim,
+            None => claim_item(storage, item_id, *item_version)?.ok_or_else(|| {
+                WriteError::InFlightLimit(InFlightLimit::of(storage, item_id, *item_version))
+            })?,
+        };
+
+        let journaled = storage.journal_interval_bytes > 0;
+        let handles = storage.file_handles.track(3 + u64::from(journaled));
+
+        // 2. Validate the version. A version staged in a publish group is not in the
+        // metadata yet, the item waits for the group.
+        if let Some(group_id) = publish_group {
+            publish_groups::check_open(storage, group_id)?;
+        }
+        if let Some((group_id, staged_version)) = publish_groups::staged_in(storage, item_id)? {
+            return Err(WriteError::Locked(format!(
+                "Version {staged_version} of the item is staged in publish group {group_id}, retry once the group was committed or aborted"
+            )));
+        }
+        // Files of the version that could not be set aside would be removed from under
+        // the new upload
+        if storage.deferred_deletes.is_pending(item_id, *item_version) {
+            return Err(WriteError::Locked(format!(
+                "Files of version {item_version} of the item wait for the readers still streaming them, retry once they finished"
+            )));
+        }
+        let metadata = validate_version(&metadata_path, *item_version, max_version_jump)?;
+        // A deleted generation still being read is set aside, this one is served instead
+        storage
+            .deferred_deletes
+            .uploaded_again(item_id, *item_version);
+        // The metadata exists now, if the item is new
+        storage.item_filter.insert(item_id);
+        if debug {
+            log_control::debug(
+                item_id,
+                *item_version,
+                format_args!(
+                    "claimed the item and validated the version under the metadata lock in {} ms",
+                    started.elapsed().as_millis()
+                ),
+            );
+        }
+
+        // 3. Open, Lock & Truncate the Data File
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .read(true) // Need read access for shared file
+            .create(true)
+            .truncate(true)
+            .open(&versioned_path)
+            .map_err(|error| IoFailure::new("open data file", &versioned_path, &error))?;
+        data_file
+            .try_lock_exclusive()
+            .map_err(|_| WriteError::Locked("Data file is locked.".to_string()))?;
+        data_file.set_len(0).map_err(|error| error.to_string())?;
+        data_file.rewind().map_err(|error| error.to_string())?;
+        // Whatever was indexed under this version before describes a different file
+        BlockIndex::remove(&block_index_path(storage, item_id, *item_version))?;
+        // ... and the leftovers of an interrupted upload of it are gone now
+        storage
+            .failed_uploads
+            .lock()
+            .unwrap()
+            .remove(&(item_id.to_string(), *item_version));
+        if storage.has_inflight_dir() {
+            // Left by a commit cut short after moving it, replaced on this commit
+            let stale_path = data_path(storage, item_id, *item_version);
+            if let Err(error) = std::fs::remove_file(&stale_path)
+                && error.kind() != std::io::ErrorKind::NotFound
+            {
+                return Err(IoFailure::new("remove stale data file", &stale_path, &error).into());
+            }
+        }
+
+        let journal = if journaled {
+            Some(Journal::create(
+                journal_path(storage, item_id, *item_version),
+                storage.journal_interval_bytes,
+            )?)
+        } else {
+            None
+        };
+
+        let lock_handles = vec![data_file.try_clone().map_err(|error| error.to_string())?];
+        let data_file = storage.io_engine.appender(data_file);
+
+        // 4. Create or get shared file for this item/version
+        let item_id_clone = item_id.to_string();
+        let version_clone = *item_version;
+        let metadata_path_clone = metadata_path.clone();
+        let versioned_path_clone = versioned_path.clone();
+
+        let epoch = metadata.next_epoch(*item_version);
+
+        let create_shared_file = || {
+            // Create a new shared file handle
+            let file_handle = OpenOptions::new()
+                .read(true)
+                .open(&versioned_path_clone)
+                .map_err(|e| e.to_string())?;
+            Ok(SharedFile::new(
+                storage.io_engine.reader(file_handle),
+                versioned_path_clone,
+                metadata_path_clone,
+                epoch,
+                None,
+                storage.file_handles.track(1),
+            ))
+        };
+        let shared_file = match publish_group {
+            Some(_) => create_shared_file()?,
+            None => storage.registry.get_or_create(
+                item_id_clone,
+                version_clone,
+                epoch,
+                create_shared_file,
+            )?,
+        };
+        shared_file.hold_writer_locks(lock_handles, claim);
+        shared_file.hold_writer_cancellation(cancellation.clone());
+
+        Ok(Self {
+            storage: storage.clone(),
+            data_file,
+            item_id: item_id.to_string(),
+            item_version: *item_version,
+            shared_file,
+            bytes_received: 0,
+            current_offset: 0,
+            is_done: false,
+            hasher: Sha256::new(),
+            rewritten: false,
+            dropped_bytes: 0,
+            bytes_rewritten: 0,
+            moved_to: None,
+            io_stream: storage.io_scheduler.new_stream(),
+            journal,
+            _handles: handles,
+            publish_group: publish_group.map(str::to_string),
+            superseded_by: None,
+            debug,
+            cancellation: cancellation.clone(),
+        })
+    }
+}
+
+impl FileWriter {
+    /// Write a debug line about the upload, if it writes any
+    fn debug(&self, message: std::fmt::Arguments) {
+        if self.debug {
+            log_control::debug(&self.item_id, self.item_version, message);
+        }
+    }
+
+    /// Mark the data as stored compressed with `content_encoding`, the bytes handed to
+    /// the writer being the upload's body as received. Recorded in the version's metadata
+    /// on commit, readers following the upload see it right away.
+    pub fn store_encoded(&self, content_encoding: &str) {
+        self.shared_file.set_content_encoding(content_encoding);
+    }
+
+    /// Compare the bytes handed down by the layers above, taken by the writer, appended
+    /// to the data file and announced to readers. A byte lost anywhere on the way fails
+    /// the commit rather than committing a truncated version.
+    fn check_accounting(&self, details: &CommitDetails) -> Result<(), String> {
+        let data_file_size = std::fs::metadata(&self.shared_file.data_path)
+            .map_err(|error| {
+                IoFailure::new("inspect data file", &self.shared_file.data_path, &error)
+            })?
+            .len();
+        let counts = [
+            ("handed to the writer", details.bytes_received),
+            ("received by the writer", self.bytes_received),
+            ("written", self.current_offset),
+            ("announced to readers", self.shared_file.get_size()),
+            // Dropped properties were written, but are no longer in the data file
+            ("in the data file", data_file_size + self.dropped_bytes),
+        ];
+        if counts
+            .iter()
+            .all(|(_, bytes)| *bytes == details.bytes_received)
+        {
+            return Ok(());
+        }
+
+        self.storage.metrics.write_accounting_mismatches.increment();
+        let counts = counts
+            .iter()
+            .map(|(what, bytes)| format!("{bytes} {what}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "BYTE ACCOUNTING MISMATCH: refusing to commit item {} version {}: {counts}",
+            self.item_id, self.item_version
+        );
+        Err(format!("Byte accounting mismatch: {counts}"))
+    }
+
+    /// Size of the data file once committed, without the properties dropped from it
+    fn committed_size(&self) -> u64 {
+        self.current_offset - self.dropped_bytes
+    }
+
+    /// Sync the data file and record how far it got in the journal
+    async fn checkpoint(&mut self) -> Result<(), String> {
+        // Chunk by chunk syncing has synced everything already
+        if self.storage.fsync_policy != FsyncPolicy::Chunk {
+            self.shared_file.writer_phase.enter(StreamPhase::Fsync);
+            self.data_file.sync().await.map_err(|error| {
+                IoFailure::new("sync data file", &self.shared_file.data_path, &error)
+            })?;
+            self.shared_file.update_durable_size(self.current_offset);
+        }
+        let checkpoint = Checkpoint {
+            offset: self.current_offset,
+            sha256: format!("{:x}", self.hasher.clone().finalize()),
+            recorded_at: chrono::Utc::now().to_rfc3339(),
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.record(&checkpoint).await?;
+        }
+        Ok(())
+    }
+
+    /// The journal no longer describes the data file, because the upload committed or
+    /// aborted or the file was rewritten
+    fn discard_journal(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            journal::remove(journal.path());
+        }
+    }
+
+    /// Wait for this upload's turn on the blocking pool, for an operation on a file of
+    /// `bytes`. Metadata updates pass 0 and always go through the fast lane.
+    async fn io_turn(&self, bytes: u64) -> IoPermit {
+        let scheduler = &self.storage.io_scheduler;
+        scheduler
+            .acquire(scheduler.lane_for(bytes), self.io_stream)
+            .await
+    }
+}
+
+/// Copy the `ranges` `(offset, length)` of `source` one after the other to `target`,
+/// sync `target` and rename it over `source`. Returns the checksum of the copy and its
+/// size.
+fn copy_ranges(
+    source: &str,
+    target: &str,
+    ranges: &[(u64, u64)],
+    chunk_size: usize,
+) -> std::io::Result<(Sha256, u64)> {
+    let result = (|| {
+        let mut input = File::open(source)?;
+        let mut output = std::io::BufWriter::new(File::create(target)?);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; chunk_size.max(1)];
+        let mut position = 0u64;
+        let mut copied = 0u64;
+        for &(offset, mut length) in ranges {
+            // Cut properties are skipped, sorted ones read out of order
+            if offset != position {
+                input.seek(std::io::SeekFrom::Start(offset))?;
+            }
+            position = offset + length;
+            copied += length;
+            while length > 0 {
+                let part = buffer.len().min(length as usize);
+                input.read_exact(&mut buffer[..part])?;
+                hasher.update(&buffer[..part]);
+                output.write_all(&buffer[..part])?;
+                length -= part as u64;
+            }
+        }
+        output.into_inner()?.sync_all()?;
+        std::fs::rename(target, source)?;
+        Ok((hasher, copied))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(target);
+    }
+    result
+}
+
+/// Move the synced data file of a finished upload from `source` in the in-flight
+/// directory to `target` in the data directory, where it only ever appears complete.
+/// Across filesystems it is copied next to `target` and renamed, the copy is synced
+/// first. A file already at `target`, written in place or staged in a publish group, is
+/// left where it is, the directory is synced all the same as the file was created there.
+/// `moved` runs between the move and the sync.
+fn move_into_data_dir(
+    storage: &Storage,
+    source: &str,
+    target: &str,
+    moved: impl FnOnce() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let strategy = match storage.commit_strategy() {
+        _ if source == target => CommitStrategy::InPlace,
+        strategy => strategy,
+    };
+    match strategy {
+        CommitStrategy::InPlace => {}
+        CommitStrategy::Rename => std::fs::rename(source, target)?,
+        CommitStrategy::Copy => {
+            let temporary_path = format!("{target}.commit.tmp");
+            let copied = std::fs::copy(source, &temporary_path)
+                .and_then(|_| File::open(&temporary_path)?.sync_all())
+                .and_then(|_| std::fs::rename(&temporary_path, target));
+            if let Err(error) = copied {
+                let _ = std::fs::remove_file(&temporary_path);
+                return Err(error);
+            }
+        }
+    }
+    moved()?;
+    // The file's directory entry is only durable once the directory is synced
+    sync_dir(&storage.data_dir)?;
+    if strategy == CommitStrategy::Copy
+        && let Err(error) = std::fs::remove_file(source)
+    {
+        println!("Could not remove in-flight data file {source}: {error}");
+    }
+    Ok(())
+}
+
+/// Make the files created, renamed or removed in `dir` so far survive a crash
+pub(crate) fn sync_dir(dir: &str) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// A [`commit_durably`] that failed
+pub(crate) struct CommitFailure<E> {
+    pub error: E,
+    /// The data file was moved to its target before the failure, cleaning up has to
+    /// look there
+    pub moved: bool,
+}
+
+/// Make the data file of a version durable in the data directory, then record the
+/// version as committed. Every commit goes through here, in this order:
+///
+/// 1. sync the data file at `source`, and create the item's metadata file if it does
+///    not exist yet
+/// 2. move the data file to `target` in the data directory, see [`move_into_data_dir`],
+///    and sync the data directory so the directory entries of both files survive a
+///    crash along with their content
+/// 3. `record` the version, which writes and syncs the item's metadata or the entry of
+///    a publish group
+///
+/// A crash before step 3 completed leaves the version uncommitted, with its data in the
+/// in-flight or the data directory, where startup finds it as an interrupted upload. So
+/// the metadata never names a data file that is missing or shorter than recorded. Nothing
+/// can fail once the version is recorded, a commit that fails never leaves it recorded.
+/// Syncing a file the writer synced already costs next to nothing, which is why the
+/// data file is synced here regardless.
+pub(crate) fn commit_durably<T, E: From<String>>(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    source: &str,
+    target: &str,
+    record: impl FnOnce() -> Result<T, E>,
+) -> Result<T, CommitFailure<E>> {
+    let failed = |message: String, moved: bool| CommitFailure {
+        error: E::from(message),
+        moved,
+    };
+    let metadata_path = metadata_path(storage, item_id);
+    File::open(source)
+        .and_then(|file| file.sync_all())
+        .map_err(|error| {
+            failed(
+                IoFailure::new("sync data file", source, &error).into(),
+                false,
+            )
+        })?;
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&metadata_path)
+        .map_err(|error| {
+            failed(
+                IoFailure::new("create metadata", &metadata_path, &error).into(),
+                false,
+            )
+        })?;
+    let step = |point| sync_points::step(&metadata_path, item_version, point);
+    let moved = source != target;
+    let mut renamed = false;
+    step(SyncPoint::CommitMoving)
+        .and_then(|_| {
+            move_into_data_dir(storage, source, target, || {
+                renamed = moved;
+                step(SyncPoint::CommitSyncingDir)
+            })
+        })
+        .and_then(|_| step(SyncPoint::CommitRecording))
+        .map_err(|error| {
+            failed(
+                IoFailure::new("move data file to", target, &error).into(),
+                renamed,
+            )
+        })?;
+    let recorded = record().map_err(|error| CommitFailure { error, moved })?;
+    sync_points::reached(&metadata_path, item_version, SyncPoint::CommitRecorded);
+    Ok(recorded)
+}
+
+/// How long a request waits for the metadata lock before giving up. Holders only keep
+/// it to rewrite the metadata, or to copy a version for a tier move.
+pub(crate) const METADATA_LOCK_WAIT: Duration = Duration::from_secs(2);
+
+/// Exclusively lock the metadata file opened from `path`, waiting up to `wait` for the
+/// current holder, or as long as it takes without one. The lock is held for as long as
+/// the file stays open. A rewrite replaces the file, see [`replace_metadata`], so a lock
+/// won on a file that was replaced meanwhile protects nothing: the file now at `path` is
+/// opened in its place and locked in turn. The wait blocks the thread, async callers get
+/// here through the blocking pool. Returns whether the lock was taken.
+pub(crate) fn lock_metadata(
+    metadata_file: &mut File,
+    path: &str,
+    wait: Option<Duration>,
+) -> Result<bool, String> {
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    loop {
+        let locked = match deadline {
+            Some(_) => metadata_file.try_lock_exclusive().is_ok(),
+            None => {
+                metadata_file
+                    .lock_exclusive()
+                    .map_err(|error| IoFailure::new("lock metadata", path, &error))?;
+                true
+            }
+        };
+        if locked {
+            if is_current_metadata(metadata_file, path) {
+                return Ok(true);
+            }
+            *metadata_file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|error| IoFailure::new("open metadata", path, &error))?;
+            continue;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Whether `metadata_file` is still the file at `path`, rather than one a rewrite replaced
+#[cfg(unix)]
+fn is_current_metadata(metadata_file: &File, path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (metadata_file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `metadata_file` is still the file at `path`, told by its creation time where
+/// there are no inode numbers to compare
+#[cfg(not(unix))]
+fn is_current_metadata(metadata_file: &File, path: &str) -> bool {
+    match (metadata_file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.created().ok() == current.created().ok(),
+        _ => false,
+    }
+}
+
+/// Where a rewrite of the metadata at `path` is written before it replaces it
+fn replacement_path(path: &str) -> String {
+    format!("{path}{METADATA_REPLACEMENT_SUFFIX}")
+}
+
+const METADATA_REPLACEMENT_SUFFIX: &str = ".replacing";
+
+/// Replace the metadata at `path`, whose file the caller holds locked as `metadata_file`,
+/// with `metadata`. Every write of an item's metadata goes through here: the new content
+/// is written to a file beside it and synced, locked, renamed over the old file and the
+/// directory synced, so a crash at any point leaves either the old or the new metadata,
+/// never a truncated one. `metadata_file` is the new file afterwards, still locked, and
+/// whoever waited for the old one finds it replaced, see [`lock_metadata`].
+pub(crate) fn replace_metadata(
+    metadata_file: &mut File,
+    path: &str,
+    metadata: &ItemMetadata,
+) -> Result<(), String> {
+    let replacement_path = replacement_path(path);
+    let mut replacement = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&replacement_path)
+        .map_err(|error| IoFailure::new("open metadata", &replacement_path, &error))?;
+    let written = replacement
+        .write_all(metadata.to_xml().as_bytes())
+        .and_then(|_| replacement.sync_all())
+        .and_then(|_| replacement.try_lock_exclusive())
+        .and_then(|_| replacement.rewind())
+        .map_err(|error| IoFailure::new("write metadata", &replacement_path, &error).into())
+        .and_then(|_| {
+            let version = metadata.latest_version.unwrap_or(0);
+            sync_points::step(path, version, SyncPoint::MetadataReplacing)
+                .and_then(|_| std::fs::rename(&replacement_path, path))
+                .map_err(|error| IoFailure::new("replace metadata", path, &error).into())
+        });
+    if let Err(failure) = written {
+        let _ = std::fs::remove_file(&replacement_path);
+        return Err(failure);
+    }
+    *metadata_file = replacement;
+    // The new metadata is in place, failing now would have callers undo what it records
+    let dir = match std::path::Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    };
+    if let Err(error) = sync_dir(&dir) {
+        println!(
+            "Could not sync the directory of {path}, the rewrite may not survive a crash: {error}"
+        );
+    }
+    Ok(())
+}
+
+/// Read and parse the metadata at `path` through its open and locked `metadata_file`
+pub(crate) fn read_metadata(metadata_file: &mut File, path: &str) -> Result<ItemMetadata, String> {
+    let mut meta_bytes = Vec::new();
+    metadata_file
+        .read_to_end(&mut meta_bytes)
+        .map_err(|error| IoFailure::new("read metadata", path, &error))?;
+    ItemMetadata::parse(&meta_bytes)
+}
+
+/// Check under the metadata lock that `item_version` may be written, which is released
+/// again before the upload starts. With `max_version_jump` it may be at most that far
+/// above the latest version, or above 0 for a new item.
+fn validate_version(
+    metadata_path: &str,
+    item_version: u64,
+    max_version_jump: Option<u64>,
+) -> Result<ItemMetadata, WriteError> {
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false) // Existing versions are rewritten at commit
+        .open(metadata_path)
+        .map_err(|error| IoFailure::new("open metadata", metadata_path, &error))?;
+    if !lock_metadata(&mut metadata_file, metadata_path, Some(METADATA_LOCK_WAIT))? {
+        return Err(WriteError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let metadata = read_metadata(&mut metadata_file, metadata_path)?;
+    check_version(&metadata, item_version, max_version_jump)?;
+    Ok(metadata)
+}
+
+/// Whether `item_version` may be written after the versions `metadata` lists
+pub(crate) fn check_version(
+    metadata: &ItemMetadata,
+    item_version: u64,
+    max_version_jump: Option<u64>,
+) -> Result<(), WriteError> {
+    if let Some(current_version) = metadata.latest_version
+        && item_version <= current_version
+    {
+        return Err(WriteError::VersionConflict {
+            requested: item_version,
+            current: current_version,
+        });
+    }
+    let current_version = metadata.latest_version.unwrap_or(0);
+    if let Some(max_jump) = max_version_jump
+        && item_version - current_version > max_jump
+    {
+        return Err(WriteError::VersionJump {
+            requested: item_version,
+            current: current_version,
+            max_jump,
+        });
+    }
+    Ok(())
+}
+
+/// Record `version` as committed in the item's metadata, re-read under the lock so the
+/// changes others made since the upload was validated are kept. Waits for the lock as
+/// long as it takes, its holders all finish. Returns the version as recorded, including
+/// whether it is the item's first.
+fn commit_metadata(
+    storage: &Storage,
+    item_id: &str,
+    mut version: VersionMetadata,
+) -> Result<VersionMetadata, WriteError> {
+    let metadata_path = metadata_path(storage, item_id);
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&metadata_path)
+        .map_err(|error| IoFailure::new("open metadata", &metadata_path, &error))?;
+    storage.item_filter.insert(item_id);
+    lock_metadata(&mut metadata_file, &metadata_path, None)?;
+    let mut metadata = read_metadata(&mut metadata_file, &metadata_path)?;
+    // Uploads of several versions of the item may run at once, the one of a newer
+    // version may have committed first
+    if let Some(current_version) = metadata.latest_version
+        && version.version <= current_version
+    {
+        return Err(WriteError::Superseded {
+            requested: version.version,
+            current: current_version,
+        });
+    }
+    // Decided under the lock, deletes may have emptied the item since the upload was
+    // validated
+    version.first_version = Some(metadata.versions.is_empty());
+    metadata.add_committed(version.clone());
+    rewrite_metadata(storage, item_id, &mut metadata_file, &metadata)?;
+    Ok(version)
+}
+
+/// Replace an item's locked metadata with `metadata`, whose versions are committed from
+/// then on, see [`replace_metadata`]
+pub(crate) fn rewrite_metadata(
+    storage: &Storage,
+    item_id: &str,
+    metadata_file: &mut File,
+    metadata: &ItemMetadata,
+) -> Result<(), String> {
+    replace_metadata(metadata_file, &metadata_path(storage, item_id), metadata)?;
+    cache_committed(storage, item_id, metadata);
+    Ok(())
+}
+
+/// Give the latest version of an item whose metadata names only that one a committed
+/// entry, as metadata written before versions were tracked individually does. Returns
+/// whether the metadata had to be rewritten.
+pub(crate) fn list_latest_version(storage: &Storage, item_id: &str) -> Result<bool, String> {
+    let metadata_path = metadata_path(storage, item_id);
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&metadata_path)
+        .map_err(|error| IoFailure::new("open metadata", &metadata_path, &error))?;
+    lock_metadata(&mut metadata_file, &metadata_path, None)?;
+    let mut metadata = read_metadata(&mut metadata_file, &metadata_path)?;
+    let Some(latest_version) = metadata
+        .latest_version
+        .filter(|latest_version| !metadata.versions.contains_key(latest_version))
+    else {
+        return Ok(false);
+    };
+    metadata.versions.insert(
+        latest_version,
+        VersionMetadata {
+            version: latest_version,
+            ..Default::default()
+        },
+    );
+    replace_metadata(&mut metadata_file, &metadata_path, &metadata)?;
+    Ok(true)
+}
+
+/// Remove the files of a version staged in a publish group that was aborted
+pub(crate) fn remove_staged_files(storage: &Storage, item_id: &str, item_version: u64) {
+    for path in [
+        data_path(storage, item_id, item_version),
+        property_index_path(storage, item_id, item_version),
+        part_checksums_path(storage, item_id, item_version),
+    ] {
+        if let Err(error) = std::fs::remove_file(&path)
+            && error.kind() != std::io::ErrorKind::NotFound
+        {
+            println!("Could not remove {path}: {error}");
+        }
+    }
+}
+
+/// Hand the versions just written to an item's metadata to the existence cache, while
+/// the metadata lock is still held
+fn cache_committed(storage: &Storage, item_id: &str, metadata: &ItemMetadata) {
+    storage.existence.store(
+        item_id,
+        metadata.versions.keys().copied().collect(),
+        FileStamp::of(&metadata_path(storage, item_id)),
+    );
+}
+
+#[async_trait]
+impl ItemStreamWriter for FileWriter {
+    async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
+        if let Some(reason) = self.cancellation.reason() {
+            return Err(cancelled_message(reason));
+        }
+        if self.shared_file.is_failed() {
+            return Err("Upload was cancelled".to_string());
+        }
+
+        self.shared_file
+            .writer_phase
+            .enter(StreamPhase::WritingChunk);
+        let chunk_len = chunk.len();
+        self.bytes_received += chunk_len as u64;
+        self.hasher.update(&chunk);
+        // Both the write and the sync run on the blocking pool
+        let _permit = tokio::select! {
+            permit = self.io_turn(self.current_offset) => permit,
+            reason = self.cancellation.cancelled() => return Err(cancelled_message(reason)),
+        };
+        self.data_file.append(chunk).await.map_err(|error| {
+            IoFailure::new("append to data file", &self.shared_file.data_path, &error)
+        })?;
+
+        // Update shared file state
+        self.debug(format_args!(
+            "wrote {chunk_len} bytes at offset {}",
+            self.current_offset
+        ));
+        self.current_offset += chunk_len as u64;
+        self.shared_file.update_size(self.current_offset);
+        self.storage.usage.add_in_flight(chunk_len as u64);
+
+        if self.storage.fsync_policy == FsyncPolicy::Chunk {
+            self.shared_file.writer_phase.enter(StreamPhase::Fsync);
+            self.data_file.sync().await.map_err(|error| {
+                IoFailure::new("sync data file", &self.shared_file.data_path, &error)
+            })?;
+            self.shared_file.update_durable_size(self.current_offset);
+            self.debug(format_args!("synced up to offset {}", self.current_offset));
+        }
+        if self
+            .journal
+            .as_ref()
+            .is_some_and(|journal| journal.is_due(self.current_offset))
+        {
+            self.checkpoint().await?;
+        }
+
+        // Until the next chunk comes in
+        self.shared_file
+            .writer_phase
+            .enter(StreamPhase::ReadingBody);
+        Ok(())
+    }
+
+    async fn rewrite_properties(
+        &mut self,
+        index: &PropertyIndex,
+        order: &[usize],
+    ) -> Result<PropertyIndex, String> {
+        if self.shared_file.is_failed() {
+            return Err("Upload was cancelled".to_string());
+        }
+        self.shared_file.writer_phase.enter(StreamPhase::Committing);
+        let size = self.committed_size();
+        let (rewritten_index, ranges) = index.rewrite(order, size)?;
+        self.discard_journal();
+
+        // Copied piece by piece, so memory stays bounded however large the upload is.
+        // Readers following the upload hold the original file open and finish reading it
+        // as it was uploaded, the renamed copy is what the commit refers to.
+        let data_path = self.shared_file.data_path.clone();
+        let temporary_path = format!("{data_path}.rewrite.tmp");
+        let chunk_size = self.storage.io_engine.read_chunk_size();
+        let permit = self.io_turn(self.current_offset).await;
+        let result = tokio::task::spawn_blocking(move || {
+            copy_ranges(&data_path, &temporary_path, &ranges, chunk_size)
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+        drop(permit);
+        let (hasher, bytes_copied) = result.map_err(|error| {
+            IoFailure::new("rewrite data file", &self.shared_file.data_path, &error)
+        })?;
+
+        self.hasher = hasher;
+        self.dropped_bytes += size - bytes_copied;
+        self.bytes_rewritten += bytes_copied;
+        self.rewritten = true;
+        self.storage.metrics.commit_rewrites.increment();
+        self.storage
+            .metrics
+            .commit_bytes_rewritten
+            .add(bytes_copied);
+        Ok(rewritten_index)
+    }
+
+    fn store_property_index(&mut self, index: &PropertyIndex) -> Result<(), String> {
+        index.store(&property_index_path(
+            &self.storage,
+            &self.item_id,
+            self.item_version,
+        ))
+    }
+
+    async fn commit(&mut self, details: &CommitDetails) -> Result<VersionMetadata, String> {
+        if self.shared_file.is_failed() || !self.shared_file.claim_outcome() {
+            return Err("Upload was cancelled".to_string());
+        }
+        self.sync_point(SyncPoint::WriterCommitting);
+        self.check_accounting(details)?;
+
+        // The data is on disk before the metadata claims it is committed
+        self.shared_file.writer_phase.enter(StreamPhase::Fsync);
+        let permit = self.io_turn(self.current_offset).await;
+        self.data_file.sync().await.map_err(|error| {
+            IoFailure::new("sync data file", &self.shared_file.data_path, &error)
+        })?;
+        drop(permit);
+        self.shared_file.update_durable_size(self.current_offset);
+        self.shared_file.writer_phase.enter(StreamPhase::Committing);
+        self.debug(format_args!(
+            "synced all {} bytes, committing",
+            self.current_offset
+        ));
+
+        let version = VersionMetadata {
+            version: self.item_version,
+            size: Some(self.committed_size()),
+            property_count: Some(details.property_count),
+            sha256: Some(format!("{:x}", self.hasher.clone().finalize())),
+            committed_at: Some(chrono::Utc::now().to_rfc3339()),
+            request_id: details.request_id.clone(),
+            // The rewritten data is a different generation than the one readers followed
+            epoch: Some(self.shared_file.epoch + u64::from(self.rewritten)),
+            location: None,
+            first_version: None,
+            extra_elements: Some(details.extra_elements.clone())
+                .filter(|extra_elements| !extra_elements.is_empty()),
+            extra_elements_truncated: Some(details.extra_elements_truncated.clone())
+                .filter(|truncated| !truncated.is_empty()),
+            provenance: details.provenance.clone(),
+            user_metadata: Some(details.user_metadata.clone())
+                .filter(|user_metadata| !user_metadata.is_empty()),
+            immutable_until: details.immutable_until.clone(),
+            segments: details.segments.clone(),
+            commit_transforms: Some(details.transforms.clone())
+                .filter(|transforms| !transforms.is_empty()),
+            write_amplification: details.write_amplification(self.bytes_rewritten),
+            content_encoding: self.shared_file.content_encoding().map(str::to_string),
+            ..Default::default()
+        };
+        let storage = self.storage.clone();
+        let item_id = self.item_id.clone();
+        let publish_group = self.publish_group.clone();
+        // ... and in the data directory, complete
+        let source = self.shared_file.data_path.clone();
+        let target = match self.storage.commit_strategy() {
+            CommitStrategy::InPlace => source.clone(),
+            _ => data_path(&self.storage, &self.item_id, self.item_version),
+        };
+        let moved_to = target.clone();
+        let permit = self.io_turn(self.current_offset).await;
+        let started = Instant::now();
+        let committed = tokio::task::spawn_blocking(move || {
+            commit_durably(
+                &storage,
+                &item_id,
+                version.version,
+                &source,
+                &target,
+                || match publish_group {
+                    Some(group_id) => {
+                        publish_groups::stage(&storage, &group_id, &item_id, &version)?;
+                        Ok(version)
+                    }
+                    None => commit_metadata(&storage, &item_id, version),
+                },
+            )
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+        drop(permit);
+        self.debug(format_args!(
+            "recorded the version under the metadata lock in {} ms",
+            started.elapsed().as_millis()
+        ));
+        let version = committed.map_err(|failure| {
+            if failure.moved {
+                self.moved_to = Some(moved_to.clone());
+            }
+            if let WriteError::Superseded { current, .. } = failure.error {
+                self.superseded_by = Some(current);
+            }
+            failure.error.message()
+        })?;
+        if moved_to != self.shared_file.data_path {
+            self.moved_to = Some(moved_to);
+        }
+
+        self.discard_journal();
+        self.storage
+            .metrics
+            .commit_bytes_ingested
+            .add(details.bytes_received);
+        if let Some(property_count) = version.property_count {
+            self.shared_file.set_property_count(property_count);
+        }
+        self.sync_point(SyncPoint::WriterFinishing);
+        // Mark shared file as finished
+        self.shared_file.mark_finished();
+        self.storage.usage.remove_in_flight(self.dropped_bytes);
+        // A staged version stays in flight until its group is committed
+        if self.publish_group.is_none() {
+            self.storage.usage.commit(self.committed_size());
+        }
+        self.shared_file.release_writer_locks();
+        if self.rewritten || self.moved_to.is_some() {
+            // New readers open the rewritten or moved file instead of the one followed
+            // so far
+            self.storage
+                .registry
+                .remove(&self.item_id, self.item_version, &self.shared_file);
+        }
+        self.is_done = true;
+
+        Ok(version)
+    }
+
+    fn abort(&mut self) {
+        if self.is_done {
+            return;
+        }
+        self.is_done = true;
+        self.storage.usage.remove_in_flight(self.current_offset);
+        self.journal = None;
+        tear_down(
+            &self.storage,
+            &self.item_id,
+            self.item_version,
+            &self.shared_file,
+            self.moved_to.as_deref(),
+        );
+        self.debug(format_args!("aborted at offset {}", self.current_offset));
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.shared_file.is_failed()
+    }
+
+    fn superseded_by(&self) -> Option<u64> {
+        self.superseded_by
+    }
+}
+
+impl Drop for FileWriter {
+    /// A writer dropped without committing (client disconnect, rejected upload, ...)
+    /// must never leave a half written version behind
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Where a version of an item currently stands
+pub enum VersionState {
+    Committed(Box<VersionMetadata>),
+    InFlight {
+        bytes_written: u64,
+        bytes_durable: u64,
+    },
+    Interrupted(FailedUpload),
+    Missing,
+}
+
+/// [`version_state`] on the blocking pool, for async callers: loading the metadata may
+/// wait briefly for a rewrite of it to finish
+pub async fn load_version_state(
+    storage: &Arc<Storage>,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VersionState, String> {
+    let storage = storage.clone();
+    let item_id = item_id.to_string();
+    tokio::task::spawn_blocking(move || version_state(&storage, &item_id, item_version))
+        .await
+        .map_err(|error| error.to_string())?
+}
+
+pub fn version_state(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VersionState, String> {
+    if !storage.item_filter.rules_out(item_id) {
+        let metadata = ItemMetadata::load(&metadata_path(storage, item_id))?;
+        if let Some(version) = metadata.versions.get(&item_version) {
+            return Ok(VersionState::Committed(Box::new(version.clone())));
+        }
+    }
+
+    match storage.registry.get(item_id, item_version) {
+        Some(shared_file) if !shared_file.is_finished() && !shared_file.is_failed() => {
+            Ok(VersionState::InFlight {
+                bytes_written: shared_file.get_size(),
+                bytes_durable: shared_file.get_durable_size(),
+            })
+        }
+        _ => Ok(storage
+            .failed_uploads
+            .lock()
+            .unwrap()
+            .get(&(item_id.to_string(), item_version))
+            .cloned()
+            .map_or(VersionState::Missing, VersionState::Interrupted)),
+    }
+}
+
+/// Whether a version is committed, being uploaded or neither, without reading the item's
+/// metadata if the existence cache has it
+pub fn exists(storage: &Storage, item_id: &str, item_version: u64) -> Result<ExistsState, String> {
+    if storage
+        .registry
+        .get(item_id, item_version)
+        .is_some_and(|shared_file| !shared_file.is_finished() && !shared_file.is_failed())
+    {
+        return Ok(ExistsState::InFlight);
+    }
+    if storage.item_filter.rules_out(item_id) {
+        return Ok(ExistsState::Missing);
+    }
+    let cache = &storage.existence;
+    let path = metadata_path(storage, item_id);
+    let stamp = cache.needs_stamp().then(|| FileStamp::of(&path));
+    let committed = match cache.get(item_id, item_version, stamp) {
+        Some(committed) => committed,
+        None => {
+            let generation = cache.generation();
+            // Taken before reading, a change in between only makes the next lookup read again
+            let stamp = stamp.unwrap_or_else(|| FileStamp::of(&path));
+            if stamp.is_none() {
+                storage.item_filter.false_positive();
+            }
+            let metadata = ItemMetadata::load(&path)?;
+            let committed = metadata.versions.contains_key(&item_version);
+            cache.fill(
+                item_id,
+                metadata.versions.into_keys().collect(),
+                stamp,
+                generation,
+            );
+            committed
+        }
+    };
+    Ok(if committed {
+        ExistsState::Committed
+    } else {
+        ExistsState::Missing
+    })
+}
+
+/// A version [`stat_versions`] is asked about, the latest committed one of the item when
+/// `version` is unset
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StatRequest {
+    pub item_id: String,
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StatState {
+    Committed,
+    /// Being uploaded by this instance
+    InFlight,
+    /// Left behind by an interrupted upload, see [`FailedUpload`]
+    Failed,
+    Missing,
+}
+
+/// What [`stat_versions`] found of a version
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VersionStat {
+    pub item_id: String,
+    /// Committed or being uploaded, so a read finds it
+    pub exists: bool,
+    pub state: StatState,
+    /// The version asked for, or the latest committed one, unset for an item without any
+    pub version: Option<u64>,
+    /// Bytes committed, written so far, or left on disk by the interrupted upload
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub committed_at: Option<String>,
+    /// Complete properties of a committed version, unset for raw data or a version
+    /// committed before they were counted and not backfilled since
+    pub property_count: Option<u64>,
+}
+
+impl VersionStat {
+    fn new(item_id: &str, state: StatState, version: Option<u64>, size: Option<u64>) -> Self {
+        Self {
+            item_id: item_id.to_string(),
+            exists: matches!(state, StatState::Committed | StatState::InFlight),
+            state,
+            version,
+            size,
+            sha256: None,
+            committed_at: None,
+            property_count: None,
+        }
+    }
+}
+
+/// Stat many versions at once, without opening any data file. Uploads in flight are
+/// found in the registry and interrupted ones in memory. The existence cache answers for
+/// the versions it knows are not committed, without touching the disk at all while the
+/// data directory watch keeps it up to date. Only the items it does not know and the
+/// committed versions, for their size and checksum, take reading the item's metadata,
+/// once per item in `requests`.
+pub fn stat_versions(
+    storage: &Storage,
+    requests: &[StatRequest],
+) -> Result<Vec<VersionStat>, String> {
+    let cache = &storage.existence;
+    let mut loaded: HashMap<&str, ItemMetadata> = HashMap::new();
+    let mut stats = Vec::with_capacity(requests.len());
+    for request in requests {
+        let item_id = request.item_id.as_str();
+        if let Some(version) = request.version
+            && let Some(shared_file) = storage.registry.get(item_id, version)
+            && !shared_file.is_finished()
+            && !shared_file.is_failed()
+        {
+            stats.push(VersionStat::new(
+                item_id,
+                StatState::InFlight,
+                Some(version),
+                Some(shared_file.get_size()),
+            ));
+            continue;
+        }
+
+        if !loaded.contains_key(item_id) {
+            if storage.item_filter.rules_out(item_id) {
+                stats.push(not_committed(storage, item_id, request.version));
+                continue;
+            }
+            let path = metadata_path(storage, item_id);
+            let stamp = cache.needs_stamp().then(|| FileStamp::of(&path));
+            let known_missing = match request.version {
+                Some(version) => cache.get(item_id, version, stamp) == Some(false),
+                None => cache.latest(item_id, stamp) == Some(None),
+            };
+            if known_missing {
+                stats.push(not_committed(storage, item_id, request.version));
+                continue;
+            }
+            let generation = cache.generation();
+            let stamp = stamp.unwrap_or_else(|| FileStamp::of(&path));
+            if stamp.is_none() {
+                storage.item_filter.false_positive();
+            }
+            let metadata = ItemMetadata::load(&path)?;
+            cache.fill(
+                item_id,
+                metadata.versions.keys().copied().collect(),
+                stamp,
+                generation,
+            );
+            loaded.insert(item_id, metadata);
+        }
+
+        let metadata = &loaded[item_id];
+        let version = request
+            .version
+            .or_else(|| metadata.versions.keys().next_back().copied());
+        match version.and_then(|version| metadata.versions.get(&version)) {
+            Some(committed) => stats.push(VersionStat {
+                sha256: committed.sha256.clone(),
+                committed_at: committed.committed_at.clone(),
+                property_count: committed.property_count,
+                ..VersionStat::new(item_id, StatState::Committed, version, committed.size)
+            }),
+            None => stats.push(not_committed(storage, item_id, version)),
+        }
+    }
+    Ok(stats)
+}
+
+/// The stat of a version the item's metadata does not list
+fn not_committed(storage: &Storage, item_id: &str, version: Option<u64>) -> VersionStat {
+    let failed = version.and_then(|version| {
+        storage
+            .failed_uploads
+            .lock()
+            .unwrap()
+            .get(&(item_id.to_string(), version))
+            .map(|failed| failed.size)
+    });
+    match failed {
+        Some(size) => VersionStat::new(item_id, StatState::Failed, version, Some(size)),
+        None => VersionStat::new(item_id, StatState::Missing, version, None),
+    }
+}
+
+/// An item's metadata was changed, possibly by another process, which may have created it
+pub fn forget_cached_versions(storage: &Storage, item_id: &str) {
+    storage.item_filter.insert(item_id);
+    storage.existence.invalidate(item_id);
+}
+
+/// Whether changes to the metadata by other processes are notified, see
+/// [`ExistenceCache`](super::existence_cache::ExistenceCache)
+pub fn set_metadata_watched(storage: &Storage, watched: bool) {
+    storage.existence.set_watched(watched);
+}
+
+/// Whether generation `epoch` of a version is registered, i.e. this process uploaded it
+/// or has already opened it
+pub fn is_registered(storage: &Storage, item_id: &str, item_version: u64, epoch: u64) -> bool {
+    storage
+        .registry
+        .get(item_id, item_version)
+        .is_some_and(|shared_file| shared_file.epoch == epoch && !shared_file.is_failed())
+}
+
+/// Readers currently streaming a version
+pub fn reader_count(storage: &Storage, item_id: &str, item_version: u64) -> usize {
+    storage
+        .registry
+        .get(item_id, item_version)
+        .map_or(0, |shared_file| shared_file.reader_count())
+}
+
+/// When an item's metadata file was last changed, `None` if it is gone
+pub fn metadata_modified(storage: &Storage, item_id: &str) -> Option<SystemTime> {
+    std::fs::metadata(metadata_path(storage, item_id))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// The item a file in the data directory holds the metadata of
+pub fn metadata_item_id(file_name: &str) -> Option<&str> {
+    file_name.strip_suffix("_metadata.xml")
+}
+
+/// Whether anything of the item is stored: its metadata, which an upload creates as soon
+/// as it starts, or its settings
+pub fn item_exists(storage: &Storage, item_id: &str) -> bool {
+    std::path::Path::new(&metadata_path(storage, item_id)).exists()
+        || item_settings::exists(storage, item_id)
+}
+
+/// Everything recorded about an item's committed versions
+pub fn load_item_metadata(storage: &Storage, item_id: &str) -> Result<ItemMetadata, String> {
+    ItemMetadata::load(&metadata_path(storage, item_id))
+}
+
+/// The property index of a committed version in whichever tier it is stored, `None` if
+/// it was stored without one
+pub fn load_committed_property_index(
+    storage: &Storage,
+    item_id: &str,
+    committed: &VersionMetadata,
+) -> Result<Option<PropertyIndex>, String> {
+    PropertyIndex::load(&version_file_path(
+        storage,
+        committed.location.as_deref(),
+        &property_index_file_name(item_id, committed.version),
+    ))
+}
+
+/// Open the latest committed version of an item and register it, so its first reader
+/// finds it in the registry instead of opening it from disk. Returns the version, `None`
+/// for items without committed versions.
+pub fn preload_latest(
+    storage: &Storage,
+    item_id: &str,
+) -> Result<Option<(u64, Arc<SharedFile>)>, String> {
+    let Some(item_version) = load_item_metadata(storage, item_id)?.latest_version else {
+        return Ok(None);
+    };
+    let shared_file =
+        match storage.registry.get(item_id, item_version) {
+            Some(shared_file) => shared_file,
+            None => open_committed_shared_file(storage, item_id, item_version, None).map_err(
+                |error| match error {
+                    OpenError::NotFound(error)
+                    | OpenError::Interrupted(error)
+                    | OpenError::Unavailable(error)
+                    | OpenError::Deleted(error)
+                    | OpenError::Failed(error) => error,
+                    OpenError::Quarantined(failure) => failure.message(),
+                },
+            )?,
+        };
+    Ok(Some((item_version, shared_file)))
+}
+
+/// Bytes read at a time while reading ahead
+const READ_AHEAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Read the first `max_bytes` of a version and throw them away, so they are in the page
+/// cache when the first reader asks for them. Returns the bytes read.
+pub async fn read_ahead(shared_file: &SharedFile, max_bytes: u64) -> Result<u64, String> {
+    let end = max_bytes.min(shared_file.get_size());
+    let mut buffer = vec![0u8; READ_AHEAD_CHUNK_SIZE.min(end as usize)];
+    let mut offset = 0;
+    while offset < end {
+        let to_read = buffer.len().min((end - offset) as usize);
+        let bytes_read = shared_file
+            .read_at(offset, &mut buffer[..to_read])
+            .await
+            .map_err(|error| IoFailure::new("read data file", &shared_file.data_path, &error))?;
+        if bytes_read == 0 {
+            break;
+        }
+        offset += bytes_read as u64;
+    }
+    Ok(offset)
+}
+
+/// Open the data file of a committed version for a one-off scan, returning its receipt
+/// and the file's size
+pub async fn open_committed(
+    storage: &Arc<Storage>,
+    item_id: &str,
+    item_version: u64,
+) -> Result<(VersionMetadata, TokioFile, u64), String> {
+    let VersionState::Committed(version) =
+        load_version_state(storage, item_id, item_version).await?
+    else {
+        return Err(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        ));
+    };
+    let data_path = version_file_path(
+        storage,
+        version.location.as_deref(),
+        &data_file_name(item_id, item_version),
+    );
+    let data_file = TokioFile::open(&data_path)
+        .await
+        .map_err(|error| IoFailure::new("open data file", &data_path, &error))?;
+    let size = data_file
+        .metadata()
+        .await
+        .map_err(|error| IoFailure::new("inspect data file", &data_path, &error))?
+        .len();
+    Ok((*version, data_file, size))
+}
+
+pub fn load_block_index(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<Option<BlockIndex>, String> {
+    BlockIndex::load(&block_index_path(storage, item_id, item_version))
+}
+
+pub fn store_block_index(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    block_index: &BlockIndex,
+) -> Result<(), String> {
+    block_index.store(&block_index_path(storage, item_id, item_version))
+}
+
+pub fn load_part_checksums(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<Option<PartChecksums>, String> {
+    PartChecksums::load(&part_checksums_path(storage, item_id, item_version))
+}
+
+pub fn store_part_checksums(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    part_checksums: &PartChecksums,
+) -> Result<(), String> {
+    part_checksums.store(&part_checksums_path(storage, item_id, item_version))
+}
+
+/// Why a version could not be deleted
+pub enum DeleteError {
+    NotFound(String),
+    /// An upload of the item holds its locks
+    Locked(String),
+    /// Version tags point at the version
+    Tagged {
+        message: String,
+        tags: Vec<String>,
+    },
+    /// The version is immutable for a while yet
+    Immutable(ImmutableVersion),
+    Failed(String),
+}
+
+/// A committed version that cannot be deleted, replaced or hidden before
+/// `immutable_until`
+#[derive(Serialize, Clone, Debug)]
+pub struct ImmutableVersion {
+    pub item_id: String,
+    pub version: u64,
+    /// RFC 3339 timestamp
+    pub immutable_until: String,
+}
+
+impl ImmutableVersion {
+    /// The version's immutability if it has not run out yet at `now`
+    pub fn of(
+        item_id: &str,
+        version: &VersionMetadata,
+        now: DateTime<Utc>,
+    ) -> Option<ImmutableVersion> {
+        version.immutable_at(now).then(|| ImmutableVersion {
+            item_id: item_id.to_string(),
+            version: version.version,
+            immutable_until: version.immutable_until.clone().unwrap_or_default(),
+        })
+    }
+
+    pub fn message(&self) -> String {
+        format!(
+            "Version {} of item {} is immutable until {}",
+            self.version, self.item_id, self.immutable_until
+        )
+    }
+}
+
+/// How often an upload at the head of its item's write queue retries a claim it did not
+/// hear being released, which is how claims held by other processes end
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn claim_item(storage: &Storage, item_id: &str, version: u64) -> Result<Option<ItemClaim>, String> {
+    storage.registry.claim_item(item_id, version, |slot| {
+        writing_marker_path(storage, item_id, slot)
+    })
+}
+
+/// Why an upload of `version` got no claim on its item, the details of its
+/// `IN_FLIGHT_LIMIT`
+#[derive(Debug, Serialize)]
+pub struct InFlightLimit {
+    pub item_id: String,
+    pub version: u64,
+    /// Versions of the item uploads of this instance are writing. Uploads of another
+    /// instance sharing the data directory are not listed.
+    pub in_flight: Vec<u64>,
+    pub max_per_item: usize,
+    /// Uploads of this instance in flight across all items
+    pub in_flight_total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total: Option<usize>,
+}
+
+impl InFlightLimit {
+    fn of(storage: &Storage, item_id: &str, version: u64) -> Self {
+        let in_flight = storage.registry.in_flight(item_id);
+        Self {
+            item_id: item_id.to_string(),
+            version,
+            in_flight: in_flight.versions,
+            max_per_item: storage.registry.max_per_item,
+            in_flight_total: in_flight.total,
+            max_total: storage.registry.max_total,
+        }
+    }
+
+    /// The limit of the whole instance was hit rather than the item's
+    pub fn instance_full(&self) -> bool {
+        self.max_total
+            .is_some_and(|max_total| self.in_flight_total >= max_total)
+    }
+
+    pub fn message(&self) -> String {
+        if self.in_flight.contains(&self.version) {
+            return format!(
+                "Version {} of item {} is already being uploaded",
+                self.version, self.item_id
+            );
+        }
+        if let Some(max_total) = self.max_total.filter(|_| self.instance_full()) {
+            return format!(
+                "{} uploads are in flight, at most {max_total} may run at once, retry once one finished",
+                self.in_flight_total
+            );
+        }
+        if self.in_flight.len() >= self.max_per_item {
+            let versions: Vec<String> = self.in_flight.iter().map(u64::to_string).collect();
+            let uploads = match versions.len() {
+                1 => "the upload of version",
+                _ => "the uploads of versions",
+            };
+            return format!(
+                "Item {} is being written by {uploads} {}, at most {} may run at once, retry once one finished",
+                self.item_id,
+                versions.join(", "),
+                self.max_per_item
+            );
+        }
+        "Item is being written by another upload, retry once it finished".to_string()
+    }
+}
+
+/// Claim `item_id` for an upload of `version`, waiting up to `wait` in the item's
+/// [`WriteQueue`](crate::persistence::write_queue::WriteQueue) while another upload
+/// holds the claim. The uploads waiting for an item get it in the order they arrived,
+/// each as soon as the one before it commits or aborts, unless `cancellation` fires first.
+pub async fn claim_item_queued(
+    storage: &Storage,
+    item_id: &str,
+    version: u64,
+    wait: Duration,
+    cancellation: &Cancellation,
+) -> Result<ItemClaim, WriteError> {
+    let queue = &storage.write_queue;
+    let shutting_down = || WriteError::Unavailable("The instance is shutting down".to_string());
+    if queue.is_closed() {
+        return Err(shutting_down());
+    }
+    // Uploads arriving while others wait line up behind them
+    if queue.depth(item_id) == 0
+        && let Some(claim) = claim_item(storage, item_id, version)?
+    {
+        return Ok(claim);
+    }
+    let ticket = queue.join(item_id).map_err(|error| {
+        storage.metrics.write_queue_rejections.increment();
+        match error {
+            JoinError::ItemFull { max_depth } => WriteError::QueueFull(format!(
+                "{max_depth} uploads already wait for item {item_id}, retry later"
+            )),
+            JoinError::Full { max_total } => WriteError::QueueFull(format!(
+                "{max_total} uploads already wait for their items, retry later"
+            )),
+            JoinError::Closed => shutting_down(),
+        }
+    })?;
+    storage.metrics.writes_queued.increment();
+    let started = Instant::now();
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        // Registered before the claim is tried, so a release in between is not missed
+        let released = storage.registry.claim_released();
+        let changed = queue.changed();
+        tokio::pin!(released, changed);
+        released.as_mut().enable();
+        changed.as_mut().enable();
+
+        if queue.is_closed() {
+            return Err(shutting_down());
+        }
+        if ticket.position() == 1
+            && let Some(claim) = claim_item(storage, item_id, version)?
+        {
+            return Ok(claim);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            storage.metrics.write_queue_timeouts.increment();
+            return Err(WriteError::QueueTimeout {
+                position: ticket.position(),
+                depth: queue.depth(item_id),
+                waited_ms: started.elapsed().as_millis() as u64,
+            });
+        }
+        tokio::select! {
+            _ = released => {}
+            _ = changed => {}
+            _ = tokio::time::sleep(QUEUE_POLL_INTERVAL) => {}
+            _ = tokio::time::sleep_until(deadline) => {}
+            reason = cancellation.cancelled() => return Err(WriteError::Cancelled(reason)),
+        }
+    }
+}
+
+/// Why an upload could not be started
+#[derive(Debug)]
+pub enum WriteError {
+    /// Another request holds the item's locks
+    Locked(String),
+    /// As many uploads as allowed already run for the item or in all
+    InFlightLimit(InFlightLimit),
+    /// Too many uploads already wait in the write queues
+    QueueFull(String),
+    /// The upload waited in its item's write queue as long as it asked to, at
+    /// `position` of `depth`
+    QueueTimeout {
+        position: usize,
+        depth: usize,
+        waited_ms: u64,
+    },
+    /// The instance is shutting down
+    Unavailable(String),
+    /// The upload was cancelled before it started, see [`Cancellation`]
+    Cancelled(CancelReason),
+    /// The version is not newer than the latest committed one
+    VersionConflict {
+        requested: u64,
+        current: u64,
+    },
+    /// A newer version was committed while the upload ran, which only happens with
+    /// `STREAM_DB_MAX_IN_FLIGHT_PER_ITEM` above 1
+    Superseded {
+        requested: u64,
+        current: u64,
+    },
+    /// The version is further above the latest one than `STREAM_DB_MAX_VERSION_JUMP`
+    /// allows
+    VersionJump {
+        requested: u64,
+        current: u64,
+        max_jump: u64,
+    },
+    NotFound(String),
+    /// The version's data no longer matches its checksum
+    Quarantined(Box<IntegrityFailure>),
+    /// The version is immutable for a while yet
+    Immutable(ImmutableVersion),
+    Failed(String),
+}
+
+impl WriteError {
+    pub fn message(&self) -> String {
+        match self {
+            Self::Locked(error)
+            | Self::QueueFull(error)
+            | Self::Unavailable(error)
+            | Self::NotFound(error)
+            | Self::Failed(error) => error.clone(),
+            Self::Quarantined(failure) => failure.message(),
+            Self::Immutable(immutable) => immutable.message(),
+            Self::InFlightLimit(limit) => limit.message(),
+            Self::Cancelled(reason) => cancelled_message(*reason),
+            Self::QueueTimeout {
+                position,
+                depth,
+                waited_ms,
+            } => format!(
+                "Item is still being written by another upload after waiting {waited_ms} ms, \
+                 at position {position} of {depth} in its write queue"
+            ),
+            Self::VersionConflict { requested, current } => {
+                format!("Conflict: Version {requested} is not newer than {current}")
+            }
+            Self::Superseded { requested, current } => format!(
+                "Version {current} was committed while version {requested} was uploaded, \
+                 version {requested} is not written"
+            ),
+            Self::VersionJump {
+                requested,
+                current,
+                max_jump,
+            } => format!(
+                "Version {requested} is more than {max_jump} above the latest version {current}"
+            ),
+        }
+    }
+}
+
+impl From<String> for WriteError {
+    fn from(error: String) -> Self {
+        Self::Failed(error)
+    }
+}
+
+impl From<IoFailure> for WriteError {
+    fn from(failure: IoFailure) -> Self {
+        Self::Failed(failure.to_string())
+    }
+}
+
+impl From<PublishGroupError> for WriteError {
+    fn from(error: PublishGroupError) -> Self {
+        match error {
+            PublishGroupError::NotFound(error) => Self::NotFound(error),
+            PublishGroupError::Locked(error) | PublishGroupError::Conflict(error) => {
+                Self::Locked(error)
+            }
+            PublishGroupError::Failed(error) => Self::Failed(error),
+        }
+    }
+}
+
+/// Summary of a deleted version
+#[derive(Serialize)]
+pub struct DeleteReport {
+    pub item_id: String,
+    pub version: u64,
+    pub epoch: u64,
+    /// False when the leftovers of an interrupted upload were removed
+    pub committed: bool,
+    /// Readers streaming the version when it was deleted, which get to finish
+    pub readers_attached: usize,
+    /// The files are kept until the attached readers finished, and removed then
+    pub deferred: bool,
+    pub files_removed: usize,
+    /// Tags that pointed at the version and were removed along with it
+    pub tags_removed: Vec<String>,
+}
+
+/// Delete a committed version together with its sidecars. Its epoch is remembered so a
+/// later upload of the same version starts a new generation. Readers still attached to
+/// the deleted one finish their read: its files are set aside under its epoch and only
+/// removed once they are done. Until then new reads of the version are answered with
+/// `410 Gone`, unless it is uploaded again, which never waits for them.
+pub fn delete_version(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<DeleteReport, DeleteError> {
+    if let Some(files_removed) = remove_failed_upload(storage, item_id, item_version)? {
+        return Ok(DeleteReport {
+            item_id: item_id.to_string(),
+            version: item_version,
+            epoch: 0,
+            committed: false,
+            readers_attached: 0,
+            deferred: false,
+            files_removed,
+            tags_removed: Vec::new(),
+        });
+    }
+
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(metadata_path(storage, item_id))
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => {
+                DeleteError::NotFound(format!("Item {item_id} not found"))
+            }
+            _ => DeleteError::Failed(
+                IoFailure::new("open metadata", &metadata_path(storage, item_id), &error).into(),
+            ),
+        })?;
+    // Uploads only hold it while validating and committing
+    if !lock_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        Some(METADATA_LOCK_WAIT),
+    )
+    .map_err(DeleteError::Failed)?
+    {
+        return Err(DeleteError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let mut metadata = read_metadata(&mut metadata_file, &metadata_path(storage, item_id))
+        .map_err(DeleteError::Failed)?;
+    if let Some(immutable) = metadata
+        .versions
+        .get(&item_version)
+        .and_then(|version| ImmutableVersion::of(item_id, version, Utc::now()))
+    {
+        return Err(DeleteError::Immutable(immutable));
+    }
+    let Some(removed) = metadata.remove_committed(item_version) else {
+        return Err(DeleteError::NotFound(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        )));
+    };
+
+    // The metadata goes first: a crash halfway leaves orphaned files, never a committed
+    // version without data
+    rewrite_metadata(storage, item_id, &mut metadata_file, &metadata)
+        .map_err(DeleteError::Failed)?;
+
+    storage.usage.remove_committed(removed.size.unwrap_or(0));
+
+    let mut report = DeleteReport {
+        item_id: item_id.to_string(),
+        version: item_version,
+        epoch: removed.epoch.unwrap_or(0),
+        committed: true,
+        readers_attached: 0,
+        deferred: false,
+        files_removed: 0,
+        tags_removed: Vec::new(),
+    };
+    let registry = &storage.registry;
+    let mut streamed = None;
+    if let Some(shared_file) = registry.get(item_id, item_version) {
+        registry.remove(item_id, item_version, &shared_file);
+        report.readers_attached = shared_file.reader_count();
+        if report.readers_attached > 0 {
+            streamed = Some(shared_file);
+        } else {
+            shared_file.mark_replaced();
+        }
+    }
+
+    let paths: Vec<String> = [
+        data_file_name(item_id, item_version),
+        property_index_file_name(item_id, item_version),
+        block_index_file_name(item_id, item_version),
+    ]
+    .iter()
+    .map(|file_name| version_file_path(storage, removed.location.as_deref(), file_name))
+    .collect();
+    match streamed {
+        Some(shared_file) => {
+            // The readers keep reading through their open handle
+            let mut in_place = false;
+            let paths = paths
+                .into_iter()
+                .filter_map(|path| {
+                    let set_aside = deferred_deletes::set_aside_path(&path, report.epoch);
+                    match std::fs::rename(&path, &set_aside) {
+                        Ok(()) => Some(set_aside),
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+                        Err(error) => {
+                            println!("Could not set {path} aside, it stays in place: {error}");
+                            in_place = true;
+                            Some(path)
+                        }
+                    }
+                })
+                .collect();
+            storage.deferred_deletes.defer(
+                item_id,
+                item_version,
+                DeferredReason::Deleted,
+                &shared_file,
+                paths,
+                in_place,
+            );
+            report.deferred = true;
+        }
+        None => {
+            for path in paths {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => report.files_removed += 1,
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(error) => println!("Could not remove {path}: {error}"),
+                }
+            }
+        }
+    }
+    // Only a cache, stale anyway once the version is written again
+    let _ = std::fs::remove_file(part_checksums_path(storage, item_id, item_version));
+    println!(
+        "Deleted item {item_id} version {item_version} (epoch {})",
+        report.epoch
+    );
+
+    Ok(report)
+}
+
+/// Summary of an item's latest version being reset
+#[derive(Serialize)]
+pub struct ResetVersionReport {
+    pub item_id: String,
+    pub previous_version: Option<u64>,
+    pub latest_version: Option<u64>,
+    /// Versions above the reset point deleted along with their data, with `force`
+    pub versions_deleted: Vec<u64>,
+    /// Versions above the reset point the metadata listed without any data
+    pub versions_dropped: Vec<u64>,
+}
+
+/// Why an item's latest version could not be reset
+pub enum ResetVersionError {
+    NotFound(String),
+    /// An upload of the item holds its locks
+    Locked(String),
+    /// The latest version is not above the requested one
+    NotBelow {
+        requested: u64,
+        current: u64,
+    },
+    /// Versions above the requested one still have data or tags
+    Blocked {
+        message: String,
+        versions: Vec<u64>,
+    },
+    /// A version above the requested one is immutable for a while yet
+    Immutable(ImmutableVersion),
+    Failed(String),
+}
+
+/// Versions of an item above `version` that still have data: committed versions whose
+/// data file exists and the leftovers of interrupted uploads
+pub fn versions_with_data_above(
+    storage: &Storage,
+    item_id: &str,
+    version: u64,
+) -> Result<Vec<u64>, String> {
+    let metadata = ItemMetadata::load(&metadata_path(storage, item_id))?;
+    Ok(versions_with_data(storage, item_id, &metadata, version))
+}
+
+fn versions_with_data(
+    storage: &Storage,
+    item_id: &str,
+    metadata: &ItemMetadata,
+    above: u64,
+) -> Vec<u64> {
+    let committed = metadata
+        .versions
+        .range((Bound::Excluded(above), Bound::Unbounded))
+        .filter(|(item_version, committed)| {
+            let file_name = data_file_name(item_id, **item_version);
+            std::path::Path::new(&version_file_path(
+                storage,
+                committed.location.as_deref(),
+                &file_name,
+            ))
+            .exists()
+        })
+        .map(|(item_version, _)| *item_version);
+    let failed_uploads = storage.failed_uploads.lock().unwrap();
+    let interrupted = failed_uploads
+        .keys()
+        .filter(|(failed_item_id, item_version)| failed_item_id == item_id && *item_version > above)
+        .map(|(_, item_version)| *item_version);
+    committed
+        .chain(interrupted)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Lower an item's latest version to the newest committed version at or below
+/// `version`, forgetting the versions above it. Only allowed while none of them has data
+/// left, see [`versions_with_data_above`]; what is forgotten are entries the metadata
+/// lists without a data file. The item is claimed like an upload meanwhile, so no upload
+/// races the reset.
+pub fn reset_latest_version(
+    storage: &Storage,
+    item_id: &str,
+    version: u64,
+) -> Result<ResetVersionReport, ResetVersionError> {
+    let _claim = storage
+        .registry
+        .claim_whole_item(item_id, version, |slot| {
+            writing_marker_path(storage, item_id, slot)
+        })
+        .map_err(ResetVersionError::Failed)?
+        .ok_or_else(|| {
+            ResetVersionError::Locked(
+                "Item is being written by an upload, retry once it finished".to_string(),
+            )
+        })?;
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(metadata_path(storage, item_id))
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => {
+                ResetVersionError::NotFound(format!("Item {item_id} not found"))
+            }
+            _ => ResetVersionError::Failed(
+                IoFailure::new("open metadata", &metadata_path(storage, item_id), &error).into(),
+            ),
+        })?;
+    if !lock_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        Some(METADATA_LOCK_WAIT),
+    )
+    .map_err(ResetVersionError::Failed)?
+    {
+        return Err(ResetVersionError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let mut metadata = read_metadata(&mut metadata_file, &metadata_path(storage, item_id))
+        .map_err(ResetVersionError::Failed)?;
+    let previous_version = metadata.latest_version;
+
+    let with_data = versions_with_data(storage, item_id, &metadata, version);
+    if !with_data.is_empty() {
+        let listed: Vec<String> = with_data.iter().map(u64::to_string).collect();
+        return Err(ResetVersionError::Blocked {
+            message: format!(
+                "Versions above {version} still have data ({}), delete them or reset with force",
+                listed.join(", ")
+            ),
+            versions: with_data,
+        });
+    }
+    let above = metadata
+        .versions
+        .range((Bound::Excluded(version), Bound::Unbounded));
+    // Its entry is all that is left to tell the verification sweep it went missing
+    let now = Utc::now();
+    if let Some(immutable) = above
+        .clone()
+        .find_map(|(_, committed)| ImmutableVersion::of(item_id, committed, now))
+    {
+        return Err(ResetVersionError::Immutable(immutable));
+    }
+    let dropped: Vec<u64> = above.map(|(item_version, _)| *item_version).collect();
+    for item_version in &dropped {
+        metadata.remove_committed(*item_version);
+    }
+    if !dropped.is_empty() {
+        rewrite_metadata(storage, item_id, &mut metadata_file, &metadata)
+            .map_err(ResetVersionError::Failed)?;
+        println!(
+            "Reset the latest version of item {item_id} from {previous_version:?} to {:?}",
+            metadata.latest_version
+        );
+    }
+
+    Ok(ResetVersionReport {
+        item_id: item_id.to_string(),
+        previous_version,
+        latest_version: metadata.latest_version,
+        versions_deleted: Vec::new(),
+        versions_dropped: dropped,
+    })
+}
+
+/// Mark a committed version as failed by the commit hook `hook`, unless it was deleted
+/// or written again (`epoch`) since. Returns whether the metadata was changed.
+pub fn flag_hook_failure(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+    hook: &str,
+) -> Result<bool, WriteError> {
+    let flagged = update_version_metadata(storage, item_id, item_version, epoch, |version| {
+        let failed_hooks = version.failed_hooks.get_or_insert_with(Vec::new);
+        if failed_hooks.iter().any(|failed| failed == hook) {
+            return Ok(false);
+        }
+        failed_hooks.push(hook.to_string());
+        version.hook_failed = Some(true);
+        Ok(true)
+    })?;
+    if flagged {
+        println!("Flagged item {item_id} version {item_version} as failed by hook {hook}");
+    }
+    Ok(flagged)
+}
+
+/// Mark a committed version as deleted through the S3 API, unless it was deleted or
+/// written again (`epoch`) since. Returns whether the metadata was changed, immutable
+/// versions are refused.
+pub fn mark_s3_deleted(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+) -> Result<bool, WriteError> {
+    let marked = update_version_metadata(storage, item_id, item_version, epoch, |version| {
+        if version.s3_deleted_at.is_some() {
+            return Ok(false);
+        }
+        if let Some(immutable) = ImmutableVersion::of(item_id, version, Utc::now()) {
+            return Err(WriteError::Immutable(immutable));
+        }
+        version.s3_deleted_at = Some(Utc::now().to_rfc3339());
+        Ok(true)
+    })?;
+    if marked {
+        println!("Marked item {item_id} version {item_version} as deleted through the S3 API");
+    }
+    Ok(marked)
+}
+
+/// Push the immutability of a committed version out to `until`. Versions that are not
+/// immutable yet become so, a deadline earlier than the recorded one is refused, even
+/// if that one already passed. Returns what is recorded afterwards.
+pub fn extend_immutability(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    until: DateTime<Utc>,
+) -> Result<ImmutableVersion, WriteError> {
+    let not_committed = || {
+        WriteError::NotFound(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        ))
+    };
+    let metadata = ItemMetadata::load(&metadata_path(storage, item_id))?;
+    let epoch = metadata
+        .versions
+        .get(&item_version)
+        .ok_or_else(not_committed)?
+        .epoch
+        .unwrap_or(0);
+    let mut recorded = None;
+    update_version_metadata(storage, item_id, item_version, epoch, |version| {
+        if let Some(current) = &version.immutable_until {
+            // An unparseable deadline cannot be compared, so it is not replaced either
+            let shortened =
+                DateTime::parse_from_rfc3339(current).map_or(true, |current| current > until);
+            if shortened {
+                return Err(WriteError::Immutable(ImmutableVersion {
+                    item_id: item_id.to_string(),
+                    version: item_version,
+                    immutable_until: current.clone(),
+                }));
+            }
+        }
+        let until = until.to_rfc3339();
+        recorded = Some(until.clone());
+        if version.immutable_until.as_ref() == Some(&until) {
+            return Ok(false);
+        }
+        version.immutable_until = Some(until);
+        Ok(true)
+    })?;
+    let Some(immutable_until) = recorded else {
+        // Deleted or written again since the metadata was read
+        return Err(not_committed());
+    };
+    println!("Item {item_id} version {item_version} is immutable until {immutable_until}");
+    Ok(ImmutableVersion {
+        item_id: item_id.to_string(),
+        version: item_version,
+        immutable_until,
+    })
+}
+
+/// Change the entry of a committed version of generation `epoch` under the metadata
+/// lock. `update` returns whether it changed anything, the metadata is only rewritten
+/// if it did.
+fn update_version_metadata(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+    update: impl FnOnce(&mut VersionMetadata) -> Result<bool, WriteError>,
+) -> Result<bool, WriteError> {
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(metadata_path(storage, item_id))
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => {
+                WriteError::NotFound(format!("Item {item_id} not found"))
+            }
+            _ => WriteError::Failed(
+                IoFailure::new("open metadata", &metadata_path(storage, item_id), &error).into(),
+            ),
+        })?;
+    if !lock_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        Some(METADATA_LOCK_WAIT),
+    )? {
+        return Err(WriteError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let mut metadata = read_metadata(&mut metadata_file, &metadata_path(storage, item_id))?;
+    let Some(version) = metadata
+        .versions
+        .get_mut(&item_version)
+        .filter(|version| version.epoch.unwrap_or(0) == epoch)
+    else {
+        return Ok(false);
+    };
+    if !update(version)? {
+        return Ok(false);
+    }
+
+    replace_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        &metadata,
+    )?;
+    Ok(true)
+}
+
+/// What happened to an in-flight upload that was forcibly killed
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KillOutcome {
+    Killed,
+    AlreadyCommitted,
+    NotFound,
+}
+
+/// Summary of everything torn down when an in-flight upload is killed
+#[derive(Serialize)]
+pub struct KillReport {
+    pub item_id: String,
+    pub version: u64,
+    pub outcome: KillOutcome,
+    pub readers_notified: usize,
+    pub writer_cancelled: bool,
+    pub locks_released: usize,
+    pub data_file_deleted: bool,
+    pub registry_evicted: bool,
+}
+
+/// What tearing down an upload released
+struct TornDown {
+    readers_notified: usize,
+    locks_released: usize,
+    registry_evicted: bool,
+    data_file_deleted: bool,
+}
+
+/// Tear down an upload that will not commit, from whichever side stops it first: its
+/// writer aborting, or a kill on behalf of a writer that may be stuck. Readers following
+/// it are failed, its locks released and its partial files removed. Safe to run twice,
+/// the files are only removed and the outcome only logged once. `moved_to` is where a
+/// commit failing after the move left the data file.
+fn tear_down(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    shared_file: &Arc<SharedFile>,
+    moved_to: Option<&str>,
+) -> TornDown {
+    // Fail readers first so nobody keeps waiting on a file that is about to vanish
+    shared_file.mark_failed();
+    let mut torn_down = TornDown {
+        readers_notified: shared_file.reader_count(),
+        locks_released: shared_file.release_writer_locks(),
+        registry_evicted: storage.registry.remove(item_id, item_version, shared_file),
+        data_file_deleted: false,
+    };
+    // By now a new upload of the same version may own these paths
+    if !shared_file.claim_cleanup() {
+        return torn_down;
+    }
+    let data_path = moved_to.unwrap_or(&shared_file.data_path);
+    match std::fs::remove_file(data_path) {
+        Ok(()) => torn_down.data_file_deleted = true,
+        Err(error) => println!("Could not remove partial data file {data_path}: {error}"),
+    }
+    journal::remove(&journal_path(storage, item_id, item_version));
+    // Stored right before the commit, so a failed commit leaves one behind
+    let index_path = property_index_path(storage, item_id, item_version);
+    if let Err(error) = std::fs::remove_file(&index_path)
+        && error.kind() != std::io::ErrorKind::NotFound
+    {
+        println!("Could not remove property index {index_path}: {error}");
+    }
+    match shared_file.writer_cancel_reason() {
+        Some(reason) => println!(
+            "Cancelled upload of item {item_id} version {item_version}, {}",
+            reason.message()
+        ),
+        None => println!("Aborted upload of item {item_id} version {item_version}"),
+    }
+    torn_down
+}
+
+/// Error of a stream stopped through its [`Cancellation`]
+pub fn cancelled_message(reason: CancelReason) -> String {
+    format!("Stream was cancelled, {}", reason.message())
+}
+
+/// Force-fail an in-flight upload for `reason`: its writer is cancelled and fails
+/// wherever it is, readers are woken with an abort error, its locks are released and the
+/// partial data is removed. Committed versions are left untouched.
+pub fn kill_stream(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    reason: CancelReason,
+) -> KillReport {
+    let mut report = KillReport {
+        item_id: item_id.to_string(),
+        version: item_version,
+        outcome: KillOutcome::NotFound,
+        readers_notified: 0,
+        writer_cancelled: false,
+        locks_released: 0,
+        data_file_deleted: false,
+        registry_evicted: false,
+    };
 
-macro_rules! metadata_format {
-    () => {
-        r#"<metadata>
-    <version>{item_version}</version>
-</metadata>"#
+    let registry = &storage.registry;
+    let Some(shared_file) = registry.get(item_id, item_version) else {
+        return report;
     };
-}
+    // An upload that already started committing is let through, its metadata may be on
+    // disk by now
+    if shared_file.is_finished() || !shared_file.claim_outcome() {
+        report.outcome = KillOutcome::AlreadyCommitted;
+        return report;
+    }
 
-pub fn init() -> Result<(), String> {
-    println!("Initializing file persistence");
-    std::fs::create_dir_all(OUTPUT_FOLDER_PATH)
-        .map_err(|error| format!("Failed to create output directory: {error}"))?;
-    Ok(())
+    report.outcome = KillOutcome::Killed;
+    shared_file.cancel_writer(reason);
+    let torn_down = tear_down(storage, item_id, item_version, &shared_file, None);
+    report.readers_notified = torn_down.readers_notified;
+    report.locks_released = torn_down.locks_released;
+    report.writer_cancelled = torn_down.locks_released > 0;
+    report.registry_evicted = torn_down.registry_evicted;
+    report.data_file_deleted = torn_down.data_file_deleted;
+
+    report
 }
 
-pub struct FileWriter {
-    data_file: TokioFile,
-    metadata_file: File,
-    item_version: u64,
-    shared_file: Arc<SharedFile>,
-    current_offset: u64,
+/// What an admin sees of a version registered with this instance
+#[derive(Serialize)]
+pub struct StreamStatus {
+    pub item_id: String,
+    pub version: u64,
+    pub epoch: u64,
+    /// `uploading`, `committed` or `failed`
+    pub state: &'static str,
+    /// Bytes written by the writer, visible to default readers
+    pub size: u64,
+    /// Bytes synced to disk, visible to `durability=committed` readers
+    pub durable_size: u64,
+    pub readers: usize,
+    /// Bytes of `size` each attached reader has not read yet, largest first
+    pub reader_lags: Vec<u64>,
+    /// Bytes read ahead for the attached readers and waiting to be sent, at most
+    /// `STREAM_DB_READ_PREFETCH_DEPTH` chunks per reader
+    pub prefetched_bytes: u64,
+    /// What the writer waits for and since when, while the version is `uploading`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writer_phase: Option<PhaseStatus>,
+    /// What each attached reader waits for, the one longest in its phase first
+    pub reader_phases: Vec<PhaseStatus>,
 }
 
-impl FileWriter {
-    pub fn new(item_id: &String, item_version: &u64) -> Result<Self, String> {
-        let metadata_path = format!("{OUTPUT_FOLDER_PATH}/{item_id}_metadata.xml");
-        let versioned_path = format!("{OUTPUT_FOLDER_PATH}/{item_id}_{item_version}.xml");
+/// Make room for the handles of a new stream. Once the open handles come close to
+/// `STREAM_DB_MAX_OPEN_FILES`, idle committed versions are closed, least recently used
+/// first, and the stream is refused if its handles still do not fit.
+pub fn make_room_for_stream(storage: &Storage) -> Result<(), HandlesExhausted> {
+    let handles = &storage.file_handles;
+    if handles.is_near_ceiling() {
+        let closed = storage.registry.evict_idle(|| !handles.is_near_ceiling());
+        if closed > 0 {
+            storage.metrics.idle_files_closed.add(closed as u64);
+            println!(
+                "Closed {closed} idle versions, {} file handles are open",
+                handles.open()
+            );
+        }
+    }
+    match handles.ceiling() {
+        Some(max_open_files) if !handles.has_room() => {
+            let open_files = handles.open();
+            Err(HandlesExhausted {
+                message: format!(
+                    "{open_files} of {max_open_files} file handles are in use by running streams, retry shortly"
+                ),
+                open_files,
+                max_open_files,
+            })
+        }
+        _ => Ok(()),
+    }
+}
 
-        // 1. Open & Lock Metadata File
-        let mut metadata_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&metadata_path)
-            .map_err(|error| format!("Metadata open error: {error}"))?;
+pub fn file_handle_report(storage: &Storage) -> FileHandleReport {
+    let (registered_versions, idle_versions) = storage.registry.counts();
+    let limits = file_handles::nofile_limits();
+    FileHandleReport {
+        open_files: storage.file_handles.open(),
+        max_open_files: storage.file_handles.ceiling(),
+        process_open_files: file_handles::process_open_files(),
+        nofile_soft_limit: limits.map(|(soft, _)| soft),
+        nofile_hard_limit: limits.map(|(_, hard)| hard),
+        registered_versions,
+        idle_versions,
+    }
+}
 
-        metadata_file
-            .try_lock_exclusive()
-            .map_err(|_| "Metadata file is locked by another request.")?;
-
-        // 2. Validate Version from Metadata
-        let mut meta_bytes = Vec::new();
-        metadata_file.read_to_end(&mut meta_bytes).ok(); // ok() handles empty new files
-
-        if !meta_bytes.is_empty() {
-            let mut reader = Reader::from_reader(&meta_bytes[..]);
-            let mut buffer = Vec::new();
-            while let Ok(event) = reader.read_event_into(&mut buffer) {
-                match event {
-                    Event::Start(ref event) if event.name().as_ref() == b"version" => {
-                        let v_str = reader
-                            .read_text(event.name())
-                            .map_err(|error| error.to_string())?;
-                        let current_version: u64 = v_str.parse().unwrap_or(0);
-                        if *item_version <= current_version {
-                            return Err(format!(
-                                "Conflict: Version {item_version} is not newer than {current_version}"
-                            ));
-                        }
-                        break;
-                    }
-                    Event::Eof => break,
-                    _ => (),
-                }
+/// Every version currently followed through the registry, in-flight uploads included
+pub fn stream_statuses(storage: &Storage) -> Vec<StreamStatus> {
+    storage
+        .registry
+        .entries()
+        .into_iter()
+        .map(|((item_id, version), shared_file)| {
+            let uploading = !shared_file.is_failed() && !shared_file.is_finished();
+            StreamStatus {
+                item_id,
+                version,
+                epoch: shared_file.epoch,
+                state: if shared_file.is_failed() {
+                    "failed"
+                } else if shared_file.is_finished() {
+                    "committed"
+                } else {
+                    "uploading"
+                },
+                size: shared_file.get_size(),
+                durable_size: shared_file.get_durable_size(),
+                readers: shared_file.reader_count(),
+                reader_lags: shared_file.reader_lags(),
+                prefetched_bytes: shared_file.prefetched_bytes(),
+                writer_phase: uploading.then(|| shared_file.writer_phase.status()),
+                reader_phases: shared_file.reader_phases(),
             }
+        })
+        .collect()
+}
+
+/// Registry entries a read-only instance dropped because the writing instance changed
+/// their versions
+#[derive(Default)]
+pub struct RefreshReport {
+    /// Versions that were deleted or uploaded again, their readers are failed
+    pub replaced: usize,
+    /// Versions moved between the tiers, their readers keep the file they opened
+    pub moved: usize,
+    /// Versions quarantined by the writing instance, their readers keep the file they
+    /// opened
+    pub quarantined: usize,
+}
+
+/// Compare the registered versions with the metadata on disk. A read-only instance learns
+/// about commits from the metadata whenever a version is opened, but versions it has
+/// registered would otherwise be served as they were when they were first read.
+pub fn refresh_registry(storage: &Storage) -> Result<RefreshReport, String> {
+    let mut report = RefreshReport::default();
+    let mut metadata_by_item: HashMap<String, ItemMetadata> = HashMap::new();
+    for ((item_id, item_version), shared_file) in storage.registry.entries() {
+        if !shared_file.is_finished() {
+            continue;
         }
+        let metadata = match metadata_by_item.entry(item_id.clone()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(ItemMetadata::load(&metadata_path(storage, &item_id))?)
+            }
+        };
+        refresh_entry(
+            storage,
+            &item_id,
+            item_version,
+            &shared_file,
+            metadata,
+            &mut report,
+        );
+    }
+    count_committed_bytes(storage)?;
+    Ok(report)
+}
 
-        // 3. Open, Lock & Truncate the Data File
-        let mut data_file = OpenOptions::new()
-            .write(true)
-            .read(true) // Need read access for shared file
-            .create(true)
-            .truncate(true)
-            .open(&versioned_path)
-            .map_err(|error| format!("Data file open error: {error}"))?;
-        data_file
-            .try_lock_exclusive()
-            .map_err(|_| "Data file is locked.")?;
-        data_file.set_len(0).map_err(|error| error.to_string())?;
-        data_file.rewind().map_err(|error| error.to_string())?;
+/// [`refresh_registry`] for the versions of a single item whose metadata was just loaded
+pub fn refresh_item_registry(
+    storage: &Storage,
+    item_id: &str,
+    metadata: &ItemMetadata,
+) -> RefreshReport {
+    let mut report = RefreshReport::default();
+    for ((registered_item_id, item_version), shared_file) in storage.registry.entries() {
+        if registered_item_id == item_id && shared_file.is_finished() {
+            refresh_entry(
+                storage,
+                item_id,
+                item_version,
+                &shared_file,
+                metadata,
+                &mut report,
+            );
+        }
+    }
+    report
+}
 
-        let data_file = TokioFile::from_std(data_file);
+fn refresh_entry(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    shared_file: &Arc<SharedFile>,
+    metadata: &ItemMetadata,
+    report: &mut RefreshReport,
+) {
+    match metadata.versions.get(&item_version) {
+        Some(version) if version.epoch.unwrap_or(0) == shared_file.epoch => {
+            if version.quarantined_at.is_some() {
+                storage.registry.remove(item_id, item_version, shared_file);
+                report.quarantined += 1;
+            } else if version.location != shared_file.location {
+                storage.registry.remove(item_id, item_version, shared_file);
+                report.moved += 1;
+            }
+        }
+        _ => {
+            shared_file.mark_replaced();
+            storage.registry.remove(item_id, item_version, shared_file);
+            report.replaced += 1;
+        }
+    }
+}
 
-        // 4. Create or get shared file for this item/version
-        let item_id_clone = item_id.clone();
-        let version_clone = *item_version;
-        let metadata_path_clone = metadata_path.clone();
-        let versioned_path_clone = versioned_path.clone();
+/// Why a reader could not be opened
+#[derive(Clone)]
+pub enum OpenError {
+    NotFound(String),
+    /// The version's upload was interrupted by a crash and never committed
+    Interrupted(String),
+    /// The version's data no longer matches its checksum
+    Quarantined(Box<IntegrityFailure>),
+    /// No read source served the version within `STREAM_DB_READ_SOURCE_BUDGET_MS`
+    Unavailable(String),
+    /// The version was deleted while readers were streaming it, and its files wait for
+    /// them to finish
+    Deleted(String),
+    Failed(String),
+}
 
-        let shared_file =
-            get_shared_file_registry().get_or_create(item_id_clone, version_clone, || {
-                // Create a new shared file handle
-                let file_handle = OpenOptions::new()
-                    .read(true)
-                    .open(&versioned_path_clone)
-                    .map_err(|e| e.to_string())?;
-                let tokio_file = TokioFile::from_std(file_handle);
+/// Register a committed version that is not in the registry, e.g. because it was
+/// committed before the server restarted, so it can be read from disk. With a `tier` the
+/// data file is taken from that tier, which may hold a copy of a version recorded in the
+/// other one, e.g. while it is being moved. Such a copy is only used when it has the
+/// committed size, and is not found otherwise.
+fn open_committed_shared_file(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    tier: Option<StorageTier>,
+) -> Result<Arc<SharedFile>, OpenError> {
+    // Requests for versions that do not exist are answered without reading the metadata
+    if exists(storage, item_id, item_version).map_err(OpenError::Failed)? == ExistsState::Missing
+        && !storage
+            .failed_uploads
+            .lock()
+            .unwrap()
+            .contains_key(&(item_id.to_string(), item_version))
+    {
+        return Err(OpenError::NotFound("Item not found".to_string()));
+    }
+    let version = match version_state(storage, item_id, item_version).map_err(OpenError::Failed)? {
+        VersionState::Committed(version) => version,
+        VersionState::Interrupted(_) => {
+            return Err(OpenError::Interrupted(format!(
+                "Upload of version {item_version} of item {item_id} was interrupted before it was committed"
+            )));
+        }
+        VersionState::InFlight { .. } | VersionState::Missing => {
+            return Err(OpenError::NotFound("Item not found".to_string()));
+        }
+    };
+    if version.quarantined_at.is_some() {
+        return Err(OpenError::Quarantined(Box::new(IntegrityFailure::new(
+            item_id, &version,
+        ))));
+    }
 
-                Ok(SharedFile::new(
-                    tokio_file,
-                    versioned_path_clone,
-                    metadata_path_clone,
-                ))
-            })?;
+    let location = match tier {
+        None => version.location.clone(),
+        Some(StorageTier::Hot) => None,
+        Some(StorageTier::Cold) => match version
+            .location
+            .as_deref()
+            .or(storage.read_sources.cold_dir())
+        {
+            Some(cold_dir) => Some(cold_dir.to_string()),
+            None => return Err(OpenError::NotFound("Item not found".to_string())),
+        },
+    };
+    // Without a committed size nothing tells a copy is complete
+    let copy = location != version.location;
+    if copy && version.size.is_none() {
+        return Err(OpenError::NotFound("Item not found".to_string()));
+    }
+    let data_path = version_file_path(
+        storage,
+        location.as_deref(),
+        &data_file_name(item_id, item_version),
+    );
+    let data_file = match File::open(&data_path) {
+        Ok(data_file) => data_file,
+        Err(error) if copy && error.kind() == std::io::ErrorKind::NotFound => {
+            return Err(OpenError::NotFound("Item not found".to_string()));
+        }
+        Err(error) => {
+            return Err(OpenError::Failed(
+                IoFailure::new("open data file", &data_path, &error).into(),
+            ));
+        }
+    };
+    let size = data_file
+        .metadata()
+        .map_err(|error| {
+            OpenError::Failed(IoFailure::new("inspect data file", &data_path, &error).into())
+        })?
+        .len();
+    if version
+        .size
+        .is_some_and(|committed_size| committed_size != size)
+    {
+        if copy {
+            return Err(OpenError::NotFound("Item not found".to_string()));
+        }
+        return Err(integrity::refuse_mismatched_read(
+            storage,
+            item_id,
+            item_version,
+        ));
+    }
 
-        Ok(Self {
-            data_file,
-            metadata_file,
-            item_version: *item_version,
-            shared_file,
-            current_offset: 0,
+    let epoch = version.epoch.unwrap_or(0);
+    let metadata_path = metadata_path(storage, item_id);
+    sync_points::reached(&metadata_path, item_version, SyncPoint::ReaderRegistering);
+    let shared_file = storage
+        .registry
+        .get_or_create(item_id.to_string(), item_version, epoch, || {
+            let shared_file = SharedFile::new(
+                storage.io_engine.reader(data_file),
+                data_path,
+                metadata_path,
+                epoch,
+                location.clone(),
+                storage.file_handles.track(1),
+            );
+            shared_file.update_size(size);
+            shared_file.update_durable_size(size);
+            shared_file.mark_finished();
+            shared_file.mark_lacks_digest(integrity::lacks_digest(&version));
+            if let Some(content_encoding) = &version.content_encoding {
+                shared_file.set_content_encoding(content_encoding);
+            }
+            if let Some(property_count) = version.property_count {
+                shared_file.set_property_count(property_count);
+            }
+            Ok(shared_file)
         })
+        .map_err(OpenError::Failed)?;
+
+    // A delete or a new upload may have changed the version between looking it up and
+    // registering it, and would then have found nothing to evict
+    match version_state(storage, item_id, item_version).map_err(OpenError::Failed)? {
+        VersionState::Committed(current) if current.epoch.unwrap_or(0) == epoch => Ok(shared_file),
+        _ => {
+            shared_file.mark_replaced();
+            storage.registry.remove(item_id, item_version, &shared_file);
+            Err(OpenError::NotFound("Item not found".to_string()))
+        }
     }
 }
 
-#[async_trait]
-impl ItemStreamWriter for FileWriter {
-    async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
-        let chunk_len = chunk.len();
-        self.data_file
-            .write_all(&chunk)
-            .await
-            .map_err(|error| format!("Write failed for chunk to file: {error}"))?;
-        self.data_file
-            .sync_data()
-            .await
-            .map_err(|error| format!("Failed to sync data to disk: {error}"))?;
+/// Find a version in the read sources, in the order [`ReadSources::resolution_order`]
+/// gives. A source that does not have the version is skipped, one that fails is
+/// counted against its health and the next one is tried, until one serves the version
+/// or `STREAM_DB_READ_SOURCE_BUDGET_MS` is spent. The budget cuts short waiting on a
+/// stalled source, an open already under way on disk is not interrupted but given up on
+/// once it returns. Interrupted uploads and quarantined versions are reported by the
+/// first source finding them.
+///
+/// [`ReadSources::resolution_order`]: crate::persistence::read_sources::ReadSources::resolution_order
+fn resolve(
+    storage: &Storage,
+    faults: &FaultInjector,
+    item_id: &str,
+    item_version: u64,
+) -> Result<(Arc<SharedFile>, ReadSource), OpenError> {
+    if storage.deferred_deletes.is_deleted(item_id, item_version) {
+        return Err(OpenError::Deleted(format!(
+            "Version {item_version} of item {item_id} was deleted, its files are removed once the reads under way finish"
+        )));
+    }
+    // Taken before looking, so a commit meanwhile keeps the miss from being remembered
+    let generation = storage.existence.generation();
+    if storage.registry.get(item_id, item_version).is_none()
+        && storage.existence.recently_missed(item_id, item_version)
+    {
+        return Err(OpenError::NotFound("Item not found".to_string()));
+    }
+    let sources = &storage.read_sources;
+    let budget = sources.budget();
+    let started = Instant::now();
+    let mut failures = Vec::new();
+    let mut exhausted = false;
+    for source in sources.resolution_order() {
+        let Some(remaining) = budget.checked_sub(started.elapsed()) else {
+            exhausted = true;
+            break;
+        };
+        let attempt_started = Instant::now();
+        let open = || {
+            faults
+                .open_from(item_id, source, remaining)
+                .map_err(OpenError::Failed)?;
+            match source {
+                ReadSource::Registry => {
+                    let shared_file = storage
+                        .registry
+                        .get(item_id, item_version)
+                        .ok_or_else(|| OpenError::NotFound("Item not found".to_string()))?;
+                    sync_points::reached(
+                        &shared_file.metadata_path,
+                        item_version,
+                        SyncPoint::ReaderFoundRegistered,
+                    );
+                    Ok(shared_file)
+                }
+                ReadSource::Hot => open_committed_shared_file(
+                    storage,
+                    item_id,
+                    item_version,
+                    Some(StorageTier::Hot),
+                ),
+                ReadSource::Cold => open_committed_shared_file(
+                    storage,
+                    item_id,
+                    item_version,
+                    Some(StorageTier::Cold),
+                ),
+            }
+        };
+        let attempt = match source {
+            ReadSource::Registry => open(),
+            // Readers of a version being opened from disk wait for that open
+            ReadSource::Hot | ReadSource::Cold => {
+                storage.opening.join(item_id, item_version, source, open)
+            }
+        };
+        let elapsed = attempt_started.elapsed();
+        // Opening blocks, so a source that ran out of the budget is only given up on
+        // once it returned
+        if elapsed >= remaining {
+            sources.record_failure(source, elapsed);
+            storage.metrics.read_source_failures.increment();
+            failures.push(format!("{} timed out", source.name()));
+            exhausted = true;
+            break;
+        }
+        match attempt {
+            Ok(shared_file) => {
+                sources.record_served(source, elapsed);
+                // The registry may have handed out the copy of the other tier, which is
+                // where the bytes then come from
+                let source = match (source, &shared_file.location) {
+                    (ReadSource::Registry, _) => ReadSource::Registry,
+                    (_, Some(_)) => ReadSource::Cold,
+                    (_, None) => ReadSource::Hot,
+                };
+                match source {
+                    ReadSource::Registry => storage.metrics.reads_served_registry.increment(),
+                    ReadSource::Hot => storage.metrics.reads_served_hot.increment(),
+                    ReadSource::Cold => storage.metrics.reads_served_cold.increment(),
+                }
+                if !failures.is_empty() {
+                    println!(
+                        "Read of item {item_id} version {item_version} served from {} after {}",
+                        source.name(),
+                        failures.join("; ")
+                    );
+                }
+                return Ok((shared_file, source));
+            }
+            Err(OpenError::NotFound(_)) => {}
+            Err(OpenError::Failed(error)) => {
+                sources.record_failure(source, elapsed);
+                storage.metrics.read_source_failures.increment();
+                failures.push(format!("{} failed: {error}", source.name()));
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    if exhausted {
+        storage.metrics.read_source_budget_exhausted.increment();
+        let message = format!(
+            "No read source served version {item_version} of item {item_id} within {} ms",
+            budget.as_millis()
+        );
+        // What the sources failed with names paths, which only the log gets to see
+        println!("{message} ({})", failures.join("; "));
+        return Err(OpenError::Unavailable(message));
+    }
+    if failures.is_empty() {
+        storage.existence.missed(item_id, item_version, generation);
+        Err(OpenError::NotFound("Item not found".to_string()))
+    } else {
+        Err(OpenError::Failed(failures.join("; ")))
+    }
+}
 
-        // Update shared file state
-        self.current_offset += chunk_len as u64;
-        self.shared_file.update_size(self.current_offset);
+/// How far a reader may follow an upload that is still in flight
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum ReadDurability {
+    /// Every byte as soon as it is written, lowest latency
+    #[default]
+    Written,
+    /// Only bytes the writer has synced to disk, which survive a crash
+    Committed,
+}
 
-        Ok(())
+impl ReadDurability {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "written" => Ok(Self::Written),
+            "committed" => Ok(Self::Committed),
+            other => Err(format!("Unknown durability {other:?}")),
+        }
     }
+}
 
-    fn commit(&mut self) -> Result<(), String> {
-        let new_metadata = format!(metadata_format!(), item_version = self.item_version);
-        self.metadata_file
-            .set_len(0)
-            .map_err(|error| error.to_string())?;
-        self.metadata_file
-            .rewind()
-            .map_err(|error| error.to_string())?;
-        self.metadata_file
-            .write_all(new_metadata.as_bytes())
-            .map_err(|error| error.to_string())?;
-        self.metadata_file
-            .sync_all()
-            .map_err(|error| error.to_string())?;
-
-        // Mark shared file as finished
-        self.shared_file.mark_finished();
+/// What a read waits for before it starts, see [`FileReader::wait_until`]
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct ReadCondition {
+    /// At least this many bytes are readable, or the version is finished
+    pub min_bytes: Option<u64>,
+    /// The version is committed
+    pub finished: bool,
+}
 
-        Ok(())
-    }
+/// How waiting for a [`ReadCondition`] ended
+pub enum WaitOutcome {
+    Met,
+    TimedOut {
+        bytes_available: u64,
+    },
+    /// The upload was aborted or the version replaced while waiting
+    Failed(String),
+    /// The read was cancelled while waiting
+    Cancelled(CancelReason),
 }
 
 pub struct FileReader {
     shared_file: Arc<SharedFile>,
-    current_offset: AtomicU64,
+    item_version: u64,
+    durability: ReadDurability,
+    /// Shared with the version, which reports how far behind the writer the reader is and
+    /// what it waits for
+    progress: Arc<ReaderProgress>,
+    /// Where reading started, the beginning or the last seek
+    start_offset: u64,
+    /// Bytes returned since `start_offset`, checked against the size of the version once
+    /// it has been read to its end
+    bytes_yielded: u64,
+    chunk_size: usize,
+    property_index_path: String,
+    block_index_path: String,
+    metrics: Arc<Metrics>,
+    /// Where the version was found, see [`ReadSource`]
+    served_from: ReadSource,
+    slow_readers: Option<SlowReaderLimit>,
+    /// Set once the reader fell behind its limit while following an upload
+    fell_behind: AtomicBool,
+    too_slow: AtomicBool,
+    item_id: String,
+    /// Hashes what is read of a version committed without a size or checksum, to
+    /// record them once all of it was read. Dropped by a seek.
+    digest: Option<Sha256>,
+    /// Reads what an interrupted upload left on disk, see [`FileReader::salvage`]
+    salvage: bool,
+    /// Debug lines are written for the read, see [`LogControl::debug_enabled`](log_control::LogControl::debug_enabled)
+    debug: bool,
+    /// Stops the read wherever it waits, see [`FileReader::with_cancellation`]
+    cancellation: Cancellation,
 }
 
 impl FileReader {
-    pub fn new(item_id: String, item_version: u64) -> Result<Self, String> {
-        // Try to get existing shared file from registry (active writer case)
-        let shared_file = match get_shared_file_registry().get(&item_id, item_version) {
-            Some(sf) => sf,
-            None => {
-                // File doesn't exist - return 404 Not Found
-                return Err("Item not found".to_string());
+    /// Open a version from the first of the instance's read sources that has it, see
+    /// [`resolve`]
+    pub fn new(
+        storage: &Storage,
+        faults: &FaultInjector,
+        item_id: String,
+        item_version: u64,
+        durability: ReadDurability,
+    ) -> Result<Self, OpenError> {
+        let (shared_file, served_from) = resolve(storage, faults, &item_id, item_version)?;
+        Ok(Self::attach(
+            storage,
+            shared_file,
+            item_id,
+            item_version,
+            durability,
+            served_from,
+        ))
+    }
+
+    /// Read what an interrupted upload left on disk, up to the bytes known to have made
+    /// it there: its last checkpoint where it kept a journal, or else the data file as
+    /// found at startup. Nothing but this reader follows the file, which is never
+    /// registered, and the version's indexes are not used, they may describe bytes that
+    /// were lost. Once the leftovers were deleted, purged or replaced by a new upload the
+    /// version is not found.
+    pub fn salvage(
+        storage: &Storage,
+        item_id: String,
+        item_version: u64,
+    ) -> Result<Self, OpenError> {
+        let key = (item_id.clone(), item_version);
+        let not_found = || OpenError::NotFound("Item not found".to_string());
+        let Some(failed) = storage.failed_uploads.lock().unwrap().get(&key).cloned() else {
+            return Err(not_found());
+        };
+        let mut opened = None;
+        for dir in upload_dirs(storage) {
+            let data_path = format!("{dir}/{}", data_file_name(&item_id, item_version));
+            match File::open(&data_path) {
+                Ok(data_file) => {
+                    opened = Some((data_path, data_file));
+                    break;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(OpenError::Failed(
+                        IoFailure::new("open data file", &data_path, &error).into(),
+                    ));
+                }
             }
+        }
+        let Some((data_path, data_file)) = opened else {
+            return Err(not_found());
         };
+        let size = data_file
+            .metadata()
+            .map_err(|error| {
+                OpenError::Failed(IoFailure::new("inspect data file", &data_path, &error).into())
+            })?
+            .len();
+        // Removed or uploaded again between looking it up and opening it, the file no
+        // longer holds what the upload left
+        if size < failed.size || !storage.failed_uploads.lock().unwrap().contains_key(&key) {
+            return Err(not_found());
+        }
 
-        Ok(Self {
+        let shared_file = SharedFile::new(
+            storage.io_engine.reader(data_file),
+            data_path,
+            metadata_path(storage, &item_id),
+            0,
+            None,
+            storage.file_handles.track(1),
+        );
+        shared_file.update_size(failed.size);
+        shared_file.update_durable_size(failed.size);
+        shared_file.mark_finished();
+        let mut reader = Self::attach(
+            storage,
             shared_file,
-            current_offset: AtomicU64::new(0),
-        })
+            item_id,
+            item_version,
+            ReadDurability::Committed,
+            ReadSource::Hot,
+        );
+        reader.salvage = true;
+        Ok(reader)
+    }
+
+    fn attach(
+        storage: &Storage,
+        shared_file: Arc<SharedFile>,
+        item_id: String,
+        item_version: u64,
+        durability: ReadDurability,
+        served_from: ReadSource,
+    ) -> Self {
+        let progress = shared_file.reader_attached();
+        let location = shared_file.location.clone();
+        let digest = (!storage.read_only && shared_file.lacks_digest()).then(Sha256::new);
+        let debug = storage.log.debug_enabled(&item_id);
+        if debug {
+            log_control::debug(
+                &item_id,
+                item_version,
+                format_args!(
+                    "opened from {} with {} bytes written, the version {}",
+                    served_from.name(),
+                    shared_file.get_size(),
+                    if shared_file.is_finished() {
+                        "finished"
+                    } else {
+                        "in flight"
+                    }
+                ),
+            );
+        }
+        Self {
+            shared_file,
+            item_version,
+            durability,
+            progress,
+            start_offset: 0,
+            bytes_yielded: 0,
+            chunk_size: storage.io_engine.read_chunk_size(),
+            property_index_path: version_file_path(
+                storage,
+                location.as_deref(),
+                &property_index_file_name(&item_id, item_version),
+            ),
+            block_index_path: version_file_path(
+                storage,
+                location.as_deref(),
+                &block_index_file_name(&item_id, item_version),
+            ),
+            metrics: storage.metrics.clone(),
+            served_from,
+            slow_readers: storage.slow_readers,
+            fell_behind: AtomicBool::new(false),
+            too_slow: AtomicBool::new(false),
+            item_id,
+            digest,
+            salvage: false,
+            debug,
+            cancellation: Cancellation::new(storage.metrics.clone()),
+        }
+    }
+
+    /// Let `cancellation` stop the read, also while it waits for an upload to write more
+    pub fn with_cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// Write a debug line about the read, if it writes any
+    fn debug(&self, message: std::fmt::Arguments) {
+        if self.debug {
+            log_control::debug(&self.item_id, self.item_version, message);
+        }
+    }
+
+    /// Pass `point` of the read-while-write protocol, see [`sync_points`]
+    fn sync_point(&self, point: SyncPoint) {
+        sync_points::reached(&self.shared_file.metadata_path, self.item_version, point);
     }
 
     #[allow(dead_code)]
@@ -223,21 +3704,249 @@ impl FileReader {
     fn is_finished(&self) -> bool {
         self.shared_file.is_finished()
     }
+
+    /// Generation of the version this reader follows
+    pub fn epoch(&self) -> u64 {
+        self.shared_file.epoch
+    }
+
+    /// Size of the version if it was committed when the reader was opened
+    pub fn committed_size(&self) -> Option<u64> {
+        self.is_finished().then(|| self.shared_file.get_size())
+    }
+
+    /// Complete properties of the version if it was committed when the reader was
+    /// opened, and they were counted
+    pub fn property_count(&self) -> Option<u64> {
+        self.is_finished()
+            .then(|| self.shared_file.property_count())
+            .flatten()
+    }
+
+    /// Count of the bytes a prefetch queue holds for this reader, reported with the
+    /// version in `/admin/streams`
+    pub fn prefetch_attached(&self) -> Arc<AtomicU64> {
+        self.shared_file.prefetch_attached()
+    }
+
+    /// Wait up to `timeout` until `condition` holds. Bytes count as this reader's
+    /// durability sees them, so `durability=committed` waits for synced bytes. Nothing
+    /// is held while waiting but a registration for the writer's notifications.
+    pub async fn wait_until(&self, condition: ReadCondition, timeout: Duration) -> WaitOutcome {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before the state is looked at, like in `read_chunk`
+            let notified = self.shared_file.write_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(reason) = self.cancellation.reason() {
+                return WaitOutcome::Cancelled(reason);
+            }
+            if self.shared_file.is_replaced() || self.shared_file.is_failed() {
+                return WaitOutcome::Failed(
+                    "Upload was aborted before the read could start".to_string(),
+                );
+            }
+            let finished = self.is_finished();
+            let bytes_available = self.readable_size();
+            // A finished version never grows, all of it is as much as there will be
+            let enough_bytes = condition
+                .min_bytes
+                .is_none_or(|min_bytes| bytes_available >= min_bytes || finished);
+            if enough_bytes && (finished || !condition.finished) {
+                return WaitOutcome::Met;
+            }
+            self.progress.phase.enter(StreamPhase::WaitingForData);
+            tokio::select! {
+                woken = tokio::time::timeout_at(deadline, notified) => {
+                    if woken.is_err() {
+                        return WaitOutcome::TimedOut {
+                            bytes_available: self.readable_size(),
+                        };
+                    }
+                }
+                reason = self.cancellation.cancelled() => return WaitOutcome::Cancelled(reason),
+            }
+        }
+    }
+
+    /// `Content-Encoding` of the stored bytes, if the version is stored compressed
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.shared_file.content_encoding()
+    }
+
+    /// Whether the version is read from the cold tier
+    pub fn is_cold(&self) -> bool {
+        self.shared_file.location.is_some()
+    }
+
+    /// Whether the version had to be opened from disk, rather than being found in the
+    /// registry because it is being written, was read before or was warmed up
+    pub fn opened_from_disk(&self) -> bool {
+        self.served_from != ReadSource::Registry
+    }
+
+    /// Where the version was found
+    pub fn served_from(&self) -> ReadSource {
+        self.served_from
+    }
+
+    /// Whether the reader reads what an interrupted upload left, see
+    /// [`FileReader::salvage`]
+    pub fn is_salvage(&self) -> bool {
+        self.salvage
+    }
+
+    /// A read of a committed version that reached its end must have returned every byte
+    /// from where it started. One that did not is failed instead of looking complete.
+    fn check_accounting(&self) -> Result<(), String> {
+        let size = self.shared_file.get_size();
+        let expected = size.saturating_sub(self.start_offset);
+        if self.bytes_yielded == expected && self.readable_size() == size {
+            return Ok(());
+        }
+
+        self.metrics.read_accounting_mismatches.increment();
+        let message = format!(
+            "Byte accounting mismatch: read {} bytes from offset {} of a version holding {size}",
+            self.bytes_yielded, self.start_offset
+        );
+        println!(
+            "BYTE ACCOUNTING MISMATCH: {} {message}",
+            self.shared_file.data_path
+        );
+        Err(message)
+    }
+
+    /// Apply `STREAM_DB_SLOW_READER_MAX_LAG_MB` to a reader of an upload that has `lag`
+    /// bytes left to read. Only bytes it could read count, so `durability=committed`
+    /// readers are not blamed for a writer that has not synced yet.
+    fn check_lag(&self, lag: u64) -> Result<(), String> {
+        let Some(limit) = self.slow_readers.filter(|limit| lag > limit.max_lag_bytes) else {
+            return Ok(());
+        };
+        match limit.policy {
+            SlowReaderPolicy::Disk => {
+                if !self.fell_behind.swap(true, Ordering::AcqRel) {
+                    self.metrics.slow_readers_downgraded.increment();
+                    println!(
+                        "Reader of {} is {lag} bytes behind the writer, reading on from disk",
+                        self.shared_file.data_path
+                    );
+                }
+                Ok(())
+            }
+            SlowReaderPolicy::Terminate => {
+                self.too_slow.store(true, Ordering::Release);
+                self.metrics.slow_readers_terminated.increment();
+                Err(format!(
+                    "Reader too slow: {lag} bytes behind the upload, at most {} allowed",
+                    limit.max_lag_bytes
+                ))
+            }
+        }
+    }
+
+    /// Record the size and checksum of a version committed without them from what this
+    /// reader just read of it, in the background. The read is complete either way, a
+    /// failure is only logged and left to the next reader.
+    fn backfill_digest(
+        metrics: &Arc<Metrics>,
+        shared_file: &Arc<SharedFile>,
+        item_id: &str,
+        item_version: u64,
+        hasher: Sha256,
+        size: u64,
+    ) {
+        let found = integrity::FileDigest {
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        };
+        let metrics = metrics.clone();
+        let shared_file = shared_file.clone();
+        let item_id = item_id.to_string();
+        // Later readers need not hash it too, unless this one fails
+        shared_file.mark_lacks_digest(false);
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = integrity::backfill_digest(
+                &metrics,
+                &shared_file.metadata_path,
+                &item_id,
+                item_version,
+                shared_file.epoch,
+                &found,
+            ) {
+                shared_file.mark_lacks_digest(true);
+                println!(
+                    "Could not record the size and checksum of item {item_id} version {item_version}: {}",
+                    error.message()
+                );
+            }
+        });
+    }
+
+    /// How far this reader may read right now
+    fn readable_size(&self) -> u64 {
+        match self.durability {
+            ReadDurability::Written => self.shared_file.get_size(),
+            ReadDurability::Committed => self.shared_file.get_durable_size(),
+        }
+    }
+}
+
+impl Drop for FileReader {
+    fn drop(&mut self) {
+        self.shared_file.reader_detached();
+    }
 }
 
 #[async_trait]
 impl ItemStreamReader for FileReader {
     async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
-        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut buffer = vec![0u8; self.chunk_size];
 
         loop {
-            let offset = self.current_offset.load(Ordering::Acquire);
-            let file_size = self.shared_file.get_size();
+            // Registered as a waiter before the state is looked at, so a write, commit or
+            // abort happening in between still wakes the wait below
+            let notified = self.shared_file.write_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.shared_file.is_replaced() {
+                return Err(format!(
+                    "Version was replaced, generation {} is no longer available",
+                    self.shared_file.epoch
+                ));
+            }
+            if self.shared_file.is_failed() {
+                return Err(match self.shared_file.writer_cancel_reason() {
+                    Some(reason) => format!(
+                        "Upload was cancelled before it was committed, {}",
+                        reason.message()
+                    ),
+                    None => "Upload was aborted before it was committed".to_string(),
+                });
+            }
+            if let Some(reason) = self.cancellation.reason() {
+                return Err(cancelled_message(reason));
+            }
+
+            // Loaded before the size: once the version is finished its size is final, so
+            // a reader that has caught up with it has read everything
+            let finished = self.is_finished();
+            let offset = self.progress.position.load(Ordering::Acquire);
+            let file_size = self.readable_size();
+            self.sync_point(SyncPoint::ReaderLoadedState);
+            if !finished {
+                self.check_lag(file_size.saturating_sub(offset))?;
+            }
 
             // Check if there's data available to read
             if offset < file_size {
                 // Read available data
-                let to_read = std::cmp::min(CHUNK_SIZE, (file_size - offset) as usize);
+                let to_read = std::cmp::min(self.chunk_size, (file_size - offset) as usize);
+                self.progress.phase.enter(StreamPhase::ReadingChunk);
 
                 let bytes_read = self
                     .shared_file
@@ -246,35 +3955,298 @@ impl ItemStreamReader for FileReader {
                     .map_err(|e| e.to_string())?;
 
                 if bytes_read > 0 {
-                    self.current_offset
+                    self.debug(format_args!("read {bytes_read} bytes at offset {offset}"));
+                    self.progress
+                        .position
                         .fetch_add(bytes_read as u64, Ordering::Release);
+                    self.bytes_yielded += bytes_read as u64;
                     buffer.truncate(bytes_read);
+                    if let Some(hasher) = self.digest.as_mut() {
+                        hasher.update(&buffer);
+                    }
+                    // Responses with a length are not polled again after the last byte
+                    if finished
+                        && self.bytes_yielded == file_size
+                        && let Some(hasher) = self.digest.take()
+                    {
+                        Self::backfill_digest(
+                            &self.metrics,
+                            &self.shared_file,
+                            &self.item_id,
+                            self.item_version,
+                            hasher,
+                            self.bytes_yielded,
+                        );
+                    }
+                    self.progress.phase.enter(StreamPhase::Sending);
                     return Ok(Some(buffer));
                 }
+                // A new upload of the version truncates the file it reads
+                if self.salvage {
+                    return Err(format!(
+                        "Data file ends at {offset} bytes, the interrupted upload left {file_size}"
+                    ));
+                }
             }
 
             // Check if we're at EOF and file is finished
-            if offset >= file_size && self.is_finished() {
+            if offset >= file_size && finished {
+                self.check_accounting()?;
                 return Ok(None);
             }
 
-            // Wait for new data to be written
-            // Use a timeout to prevent indefinite waiting
+            // Wait for new data to be written. The timeout is only a safety net, the state
+            // is looked at again either way, so bytes written in the meantime are not
+            // skipped.
             let timeout = tokio::time::Duration::from_secs(30);
-            let notified =
-                tokio::time::timeout(timeout, self.shared_file.write_notify.notified()).await;
-
-            match notified {
-                Ok(_) => continue, // Notified of new data, try reading again
-                Err(_) => {
-                    // Timeout - check if file is finished
-                    if self.is_finished() {
-                        return Ok(None);
-                    }
-                    // Otherwise continue waiting
-                    continue;
-                }
+            self.sync_point(SyncPoint::ReaderCaughtUp);
+            self.progress.phase.enter(StreamPhase::WaitingForData);
+            self.debug(format_args!(
+                "caught up at offset {offset}, waiting for data"
+            ));
+            let woken = tokio::select! {
+                woken = tokio::time::timeout(timeout, notified) => woken.is_ok(),
+                reason = self.cancellation.cancelled() => return Err(cancelled_message(reason)),
+            };
+            self.debug(format_args!(
+                "{} with {} bytes readable",
+                if woken { "woken" } else { "timed out" },
+                self.readable_size()
+            ));
+        }
+    }
+
+    fn property_index(&self) -> Result<Option<PropertyIndex>, String> {
+        // The index is only written at commit
+        if !self.is_finished() || self.salvage {
+            return Ok(None);
+        }
+        PropertyIndex::load(&self.property_index_path)
+    }
+
+    fn block_index(&self) -> Result<Option<BlockIndex>, String> {
+        if !self.is_finished() || self.salvage {
+            return Ok(None);
+        }
+        // An index left behind by an earlier upload of this version must not be trusted
+        Ok(BlockIndex::load(&self.block_index_path)?
+            .filter(|block_index| block_index.size == self.shared_file.get_size()))
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), String> {
+        self.progress.position.store(offset, Ordering::Release);
+        self.start_offset = offset;
+        self.bytes_yielded = 0;
+        self.digest = None;
+        Ok(())
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.shared_file.is_failed() || self.shared_file.is_replaced()
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.too_slow.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::{AppState, StreamDb};
+
+    /// An item of an instance of its own, whose data directory is removed when dropped
+    pub(crate) struct TestItem {
+        pub(crate) state: AppState,
+        pub(crate) id: String,
+    }
+
+    impl TestItem {
+        pub(crate) fn new(name: &str) -> Self {
+            Self::with_config(name, |_| {})
+        }
+
+        /// An item of an instance whose settings `configure` adjusted
+        pub(crate) fn with_config(name: &str, configure: impl FnOnce(&mut Config)) -> Self {
+            let mut config = Config::from_env().unwrap();
+            config.data_dir = std::env::temp_dir()
+                .join(format!("stream-db-{name}-{}", uuid::Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned();
+            configure(&mut config);
+            let state = StreamDb::new(config);
+            init(&state.storage).unwrap();
+            Self {
+                state,
+                id: name.to_string(),
+            }
+        }
+
+        pub(crate) fn storage(&self) -> &Arc<Storage> {
+            &self.state.storage
+        }
+
+        pub(crate) fn data_path(&self, version: u64) -> String {
+            data_path(self.storage(), &self.id, version)
+        }
+    }
+
+    impl Drop for TestItem {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.state.config.data_dir);
+        }
+    }
+
+    fn cancellation(item: &TestItem) -> Cancellation {
+        Cancellation::new(item.storage().metrics.clone())
+    }
+
+    fn open_reader(item: &TestItem) -> FileReader {
+        match FileReader::new(
+            item.storage(),
+            &FaultInjector::new(false),
+            item.id.clone(),
+            1,
+            ReadDurability::default(),
+        ) {
+            Ok(reader) => reader,
+            Err(OpenError::Quarantined(failure)) => panic!("{}", failure.message()),
+            Err(
+                OpenError::NotFound(error)
+                | OpenError::Interrupted(error)
+                | OpenError::Unavailable(error)
+                | OpenError::Deleted(error)
+                | OpenError::Failed(error),
+            ) => {
+                panic!("{error}")
             }
         }
     }
+
+    fn details(bytes_received: usize) -> CommitDetails {
+        CommitDetails {
+            property_count: 1,
+            request_id: None,
+            bytes_received: bytes_received as u64,
+            extra_elements: Default::default(),
+            extra_elements_truncated: Default::default(),
+            provenance: Default::default(),
+            user_metadata: Default::default(),
+            immutable_until: None,
+            segments: None,
+            transforms: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_killed_upload_fails_its_writer_and_readers_and_can_be_uploaded_again() {
+        let item = TestItem::new("kill");
+        let mut writer = FileWriter::new(
+            item.storage(),
+            &item.id,
+            &1,
+            None,
+            None,
+            None,
+            &cancellation(&item),
+        )
+        .unwrap();
+        writer.write_chunk(b"<property/>".to_vec()).await.unwrap();
+        let mut reader = open_reader(&item);
+        assert_eq!(
+            reader.read_chunk().await.unwrap().as_deref(),
+            Some(&b"<property/>"[..])
+        );
+
+        // The reader is waiting for more when the upload is killed
+        let waiting_reader = tokio::spawn(async move { reader.read_chunk().await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let report = kill_stream(item.storage(), &item.id, 1, CancelReason::Killed);
+        assert!(report.outcome == KillOutcome::Killed);
+        assert_eq!(report.readers_notified, 1);
+        assert!(report.writer_cancelled);
+        assert_eq!(report.locks_released, 2);
+        assert!(report.data_file_deleted);
+        assert!(report.registry_evicted);
+        assert!(!std::path::Path::new(&item.data_path(1)).exists());
+
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), waiting_reader)
+            .await
+            .expect("the reader kept waiting")
+            .unwrap();
+        assert!(read.is_err());
+        assert!(writer.write_chunk(b"<property/>".to_vec()).await.is_err());
+        assert!(
+            FileReader::new(
+                item.storage(),
+                &FaultInjector::new(false),
+                item.id.clone(),
+                1,
+                ReadDurability::default()
+            )
+            .is_err()
+        );
+
+        // A fresh upload of the same version succeeds, and the killed writer going away
+        // afterwards leaves its file alone
+        let mut fresh = FileWriter::new(
+            item.storage(),
+            &item.id,
+            &1,
+            None,
+            None,
+            None,
+            &cancellation(&item),
+        )
+        .unwrap();
+        fresh
+            .write_chunk(b"<property>2</property>".to_vec())
+            .await
+            .unwrap();
+        drop(writer);
+        fresh.commit(&details(22)).await.unwrap();
+        let mut reader = open_reader(&item);
+        assert_eq!(
+            reader.read_chunk().await.unwrap().as_deref(),
+            Some(&b"<property>2</property>"[..])
+        );
+        assert_eq!(reader.read_chunk().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn killing_a_committed_or_unknown_version_changes_nothing() {
+        let item = TestItem::new("kill-committed");
+        assert!(
+            kill_stream(item.storage(), &item.id, 1, CancelReason::Killed).outcome
+                == KillOutcome::NotFound
+        );
+
+        let mut writer = FileWriter::new(
+            item.storage(),
+            &item.id,
+            &1,
+            None,
+            None,
+            None,
+            &cancellation(&item),
+        )
+        .unwrap();
+        writer.write_chunk(b"<property/>".to_vec()).await.unwrap();
+        writer.commit(&details(11)).await.unwrap();
+        let report = kill_stream(item.storage(), &item.id, 1, CancelReason::Killed);
+        assert!(report.outcome == KillOutcome::AlreadyCommitted);
+        assert!(!report.data_file_deleted && !report.registry_evicted);
+        assert!(std::path::Path::new(&item.data_path(1)).exists());
+        assert!(
+            FileReader::new(
+                item.storage(),
+                &FaultInjector::new(false),
+                item.id.clone(),
+                1,
+                ReadDurability::default()
+            )
+            .is_ok()
+        );
+    }
 }
@@ -0,0 +1,48 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The response to a request carrying an `Idempotency-Key`, replayed for its retries
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedResponse {
+    /// Method and URI of the request, a retry must repeat them
+    pub request: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Base64 encoded body
+    pub body: String,
+    /// Unix seconds the response was recorded at, it expires after the configured TTL
+    pub recorded_at: u64,
+    /// Unix seconds the key was last used at, the least recently used keys are dropped
+    /// first once the store is full
+    pub used_at: u64,
+}
+
+/// Recorded responses by scoped key
+pub type RecordedResponses = BTreeMap<String, RecordedResponse>;
+
+fn responses_path(storage: &Storage) -> String {
+    storage.path(".idempotency.json")
+}
+
+pub fn load(storage: &Storage) -> Result<RecordedResponses, String> {
+    match std::fs::read(responses_path(storage)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|error| format!("Idempotency key file is corrupt: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(format!("Idempotency key read error: {error}")),
+    }
+}
+
+/// Replace the stored responses through a rename, so a crash never leaves a half
+/// written file behind
+pub fn store(storage: &Storage, responses: &RecordedResponses) -> Result<(), String> {
+    let path = responses_path(storage);
+    let temporary_path = format!("{path}.tmp");
+    let bytes = serde_json::to_vec(responses).map_err(|error| error.to_string())?;
+    std::fs::write(&temporary_path, bytes)
+        .map_err(|error| format!("Idempotency key write error: {error}"))?;
+    std::fs::rename(&temporary_path, &path)
+        .map_err(|error| format!("Idempotency key write error: {error}"))
+}
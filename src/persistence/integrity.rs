@@ -0,0 +1,490 @@
+use crate::metrics::Metrics;
+use crate::persistence::file_persistence::{
+    METADATA_LOCK_WAIT, OpenError, WriteError, data_file_name, lock_metadata, metadata_path,
+    read_metadata, replace_metadata, version_file_path,
+};
+use crate::persistence::io_failure::IoFailure;
+use crate::persistence::item_metadata::{ItemMetadata, VersionMetadata};
+use crate::persistence::storage::Storage;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+
+/// What a data file was found to hold when it was checked
+#[derive(Serialize, Clone, Debug)]
+pub struct FileDigest {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// What checked a version and found its data no longer matching the receipt, recorded
+/// with the quarantine
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IntegrityCheck {
+    /// A reader found the data file at a different size than committed
+    Read,
+    /// An admin asked for the version to be verified
+    Verify,
+    /// A tier move found its copy not matching and checked the original
+    TierMove,
+    /// The background verification sweep
+    Sweep,
+}
+
+impl IntegrityCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Verify => "verify",
+            Self::TierMove => "tier_move",
+            Self::Sweep => "sweep",
+        }
+    }
+}
+
+/// Why a quarantined version is not served, with what its receipt promised and what was
+/// found on disk
+#[derive(Serialize, Clone, Debug)]
+pub struct IntegrityFailure {
+    pub item_id: String,
+    pub version: u64,
+    pub expected_size: Option<u64>,
+    pub expected_sha256: Option<String>,
+    pub found_size: Option<u64>,
+    pub found_sha256: Option<String>,
+    pub detected_by: Option<String>,
+    pub quarantined_at: Option<String>,
+}
+
+impl IntegrityFailure {
+    pub fn new(item_id: &str, version: &VersionMetadata) -> Self {
+        Self {
+            item_id: item_id.to_string(),
+            version: version.version,
+            expected_size: version.size,
+            expected_sha256: version.sha256.clone(),
+            found_size: version.found_size,
+            found_sha256: version.found_sha256.clone(),
+            detected_by: version.quarantine_check.clone(),
+            quarantined_at: version.quarantined_at.clone(),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        format!(
+            "Version {} of item {} failed its integrity check and is quarantined",
+            self.version, self.item_id
+        )
+    }
+}
+
+/// How a checked version compared with its receipt
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyOutcome {
+    /// Size and checksum match the receipt, or the size alone for versions committed
+    /// before checksums were recorded
+    Intact,
+    /// Intact again after a repair, the quarantine was lifted
+    Released,
+    /// Committed without a size or checksum, which were recorded from the data now
+    Backfilled,
+}
+
+/// Summary of a version that was checked and found intact
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub item_id: String,
+    pub version: u64,
+    pub outcome: VerifyOutcome,
+    pub size: u64,
+    pub sha256: String,
+    /// Whether the receipt had a checksum to compare with
+    pub checksum_verified: bool,
+}
+
+/// Size and SHA-256 of the file at `path`
+pub fn digest(path: &str) -> Result<FileDigest, String> {
+    let mut file = File::open(path).map_err(|error| format!("Open of {path} failed: {error}"))?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)
+        .map_err(|error| format!("Read of {path} failed: {error}"))?;
+    Ok(FileDigest {
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+/// Whether `found` is what the receipt of `version` recorded. Versions committed before
+/// sizes or checksums were recorded are only compared on what they have.
+pub fn matches(version: &VersionMetadata, found: &FileDigest) -> bool {
+    version.size.is_none_or(|size| size == found.size)
+        && version
+            .sha256
+            .as_deref()
+            .is_none_or(|sha256| sha256 == found.sha256)
+}
+
+/// Whether the receipt of `version` lacks a size or checksum, because it was committed
+/// before they were recorded
+pub fn lacks_digest(version: &VersionMetadata) -> bool {
+    version.size.is_none() || version.sha256.is_none()
+}
+
+/// Record the size and checksum a version committed without them was found with, on
+/// top of whatever of them it has by now. Nothing changes if the version was deleted,
+/// written again (`epoch`) or quarantined since the data was read, or if the data does
+/// not match what the receipt already holds. Returns whether the metadata was changed.
+pub fn backfill_digest(
+    metrics: &Metrics,
+    metadata_path: &str,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+    found: &FileDigest,
+) -> Result<bool, WriteError> {
+    let (mut metadata_file, mut metadata) = lock_metadata_at(metadata_path, item_id)?;
+    let Some(version) = metadata.versions.get_mut(&item_version) else {
+        return Ok(false);
+    };
+    if version.epoch.unwrap_or(0) != epoch
+        || version.quarantined_at.is_some()
+        || !fill_in_digest(version, found)
+    {
+        return Ok(false);
+    }
+    replace_metadata(&mut metadata_file, metadata_path, &metadata)?;
+    log_backfill(metrics, item_id, item_version, found);
+    Ok(true)
+}
+
+/// Record the complete properties a version committed without a count was found to
+/// hold, unless it was deleted, written again (`epoch`) or quarantined since its data
+/// was read, or was given a count meanwhile. Returns whether the metadata was changed.
+pub fn backfill_property_count(
+    metadata_path: &str,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+    property_count: u64,
+) -> Result<bool, WriteError> {
+    let (mut metadata_file, mut metadata) = lock_metadata_at(metadata_path, item_id)?;
+    let Some(version) = metadata.versions.get_mut(&item_version) else {
+        return Ok(false);
+    };
+    if version.epoch.unwrap_or(0) != epoch
+        || version.quarantined_at.is_some()
+        || version.property_count.is_some()
+    {
+        return Ok(false);
+    }
+    version.property_count = Some(property_count);
+    replace_metadata(&mut metadata_file, metadata_path, &metadata)?;
+    println!(
+        "Recorded {property_count} properties of item {item_id} version {item_version}, committed without a count"
+    );
+    Ok(true)
+}
+
+/// Fill in the size and checksum the receipt of `version` lacks from `found`, if the
+/// data matches what it has
+fn fill_in_digest(version: &mut VersionMetadata, found: &FileDigest) -> bool {
+    if !lacks_digest(version) || !matches(version, found) {
+        return false;
+    }
+    version.size = Some(found.size);
+    version.sha256 = Some(found.sha256.clone());
+    version.computed_at = Some(chrono::Utc::now().to_rfc3339());
+    true
+}
+
+fn log_backfill(metrics: &Metrics, item_id: &str, item_version: u64, found: &FileDigest) {
+    metrics.digests_backfilled.increment();
+    println!(
+        "Recorded size {} and sha256 {} of item {item_id} version {item_version}, committed without them",
+        found.size, found.sha256
+    );
+}
+
+/// How a version hashed by the verification sweep compared with its receipt
+pub enum SweepOutcome {
+    /// It matched, `last_verified_at` was bumped
+    Intact,
+    /// It did not and was quarantined
+    Quarantined(Box<IntegrityFailure>),
+    /// It was deleted, written again or quarantined while it was hashed, nothing changed
+    Changed,
+}
+
+/// Record what the verification sweep found hashing a committed version, which it does
+/// without holding the metadata lock: an intact version is marked with
+/// `last_verified_at`, one that does not match is quarantined. `epoch` is the one the
+/// version had when it was hashed.
+pub fn record_sweep(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    epoch: u64,
+    found: &FileDigest,
+) -> Result<SweepOutcome, WriteError> {
+    let (mut metadata_file, mut metadata) = lock_item(storage, item_id)?;
+    let Some(version) = metadata.versions.get_mut(&item_version) else {
+        return Ok(SweepOutcome::Changed);
+    };
+    if version.epoch.unwrap_or(0) != epoch || version.quarantined_at.is_some() {
+        return Ok(SweepOutcome::Changed);
+    }
+    if !matches(version, found) {
+        let failure = quarantine_locked(
+            storage,
+            item_id,
+            item_version,
+            &mut metadata_file,
+            &mut metadata,
+            found,
+            IntegrityCheck::Sweep,
+        )?;
+        return Ok(SweepOutcome::Quarantined(Box::new(failure)));
+    }
+    version.last_verified_at = Some(chrono::Utc::now().to_rfc3339());
+    let backfilled = fill_in_digest(version, found);
+    replace_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        &metadata,
+    )?;
+    if backfilled {
+        log_backfill(&storage.metrics, item_id, item_version, found);
+    }
+    Ok(SweepOutcome::Intact)
+}
+
+/// Hash the data file of a committed version and compare it with its receipt. A version
+/// that does not match is quarantined: it stays on disk but is no longer served until
+/// it is repaired and released with [`unquarantine`], or deleted.
+pub fn verify_version(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    check: IntegrityCheck,
+) -> Result<VerifyReport, WriteError> {
+    let (mut metadata_file, mut metadata) = lock_item(storage, item_id)?;
+    let version = committed(&metadata, item_id, item_version)?;
+    if version.quarantined_at.is_some() {
+        return Err(WriteError::Quarantined(Box::new(IntegrityFailure::new(
+            item_id, version,
+        ))));
+    }
+    check_locked(
+        storage,
+        item_id,
+        item_version,
+        &mut metadata_file,
+        &mut metadata,
+        check,
+    )
+}
+
+/// Verify a quarantined version again after it was repaired by hand, and serve it again
+/// if it now matches its receipt. Versions that are not quarantined are just verified.
+pub fn unquarantine(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> Result<VerifyReport, WriteError> {
+    let (mut metadata_file, mut metadata) = lock_item(storage, item_id)?;
+    let was_quarantined = committed(&metadata, item_id, item_version)?
+        .quarantined_at
+        .is_some();
+    let mut report = check_locked(
+        storage,
+        item_id,
+        item_version,
+        &mut metadata_file,
+        &mut metadata,
+        IntegrityCheck::Verify,
+    )?;
+    if !was_quarantined {
+        return Ok(report);
+    }
+    let Some(version) = metadata.versions.get_mut(&item_version) else {
+        return Ok(report);
+    };
+
+    version.quarantined_at = None;
+    version.quarantine_check = None;
+    version.found_size = None;
+    version.found_sha256 = None;
+    replace_metadata(
+        &mut metadata_file,
+        &metadata_path(storage, item_id),
+        &metadata,
+    )?;
+    // Readers refused while it was quarantined open it from disk again
+    if let Some(shared_file) = storage.registry.get(item_id, item_version) {
+        storage.registry.remove(item_id, item_version, &shared_file);
+    }
+    storage.metrics.quarantines_lifted.increment();
+    println!("Released item {item_id} version {item_version} from quarantine");
+    report.outcome = VerifyOutcome::Released;
+    Ok(report)
+}
+
+/// Record that the data of `item_version` was found as `found`, which does not match its
+/// receipt, in the item's metadata the caller holds locked
+pub(crate) fn quarantine_locked(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    metadata_file: &mut File,
+    metadata: &mut ItemMetadata,
+    found: &FileDigest,
+    check: IntegrityCheck,
+) -> Result<IntegrityFailure, WriteError> {
+    let Some(version) = metadata.versions.get_mut(&item_version) else {
+        return Err(WriteError::NotFound(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        )));
+    };
+    version.quarantined_at = Some(chrono::Utc::now().to_rfc3339());
+    version.quarantine_check = Some(check.name().to_string());
+    version.found_size = Some(found.size);
+    version.found_sha256 = Some(found.sha256.clone());
+    let failure = IntegrityFailure::new(item_id, version);
+    replace_metadata(metadata_file, &metadata_path(storage, item_id), metadata)?;
+
+    // New readers open it from disk, where they are refused
+    if let Some(shared_file) = storage.registry.get(item_id, item_version) {
+        storage.registry.remove(item_id, item_version, &shared_file);
+    }
+    storage.metrics.versions_quarantined.increment();
+    println!(
+        "Quarantined item {item_id} version {item_version} after a failed {} check: expected {} bytes with sha256 {}, found {} bytes with sha256 {}",
+        check.name(),
+        failure
+            .expected_size
+            .map_or("?".to_string(), |size| size.to_string()),
+        failure.expected_sha256.as_deref().unwrap_or("?"),
+        found.size,
+        found.sha256
+    );
+    Ok(failure)
+}
+
+/// The reason a reader is refused a committed version whose data file was found at the
+/// wrong size. Instances that may write quarantine the version on the way.
+pub(crate) fn refuse_mismatched_read(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+) -> OpenError {
+    let mismatch = OpenError::Failed("Data file does not match the committed size".to_string());
+    if storage.read_only {
+        return mismatch;
+    }
+    match verify_version(storage, item_id, item_version, IntegrityCheck::Read) {
+        Err(WriteError::Quarantined(failure)) => OpenError::Quarantined(failure),
+        Err(WriteError::NotFound(error)) => OpenError::NotFound(error),
+        // Rewritten or moved since it was opened
+        Ok(_) => mismatch,
+        Err(error) => OpenError::Failed(error.message()),
+    }
+}
+
+fn lock_item(storage: &Storage, item_id: &str) -> Result<(File, ItemMetadata), WriteError> {
+    lock_metadata_at(&metadata_path(storage, item_id), item_id)
+}
+
+fn lock_metadata_at(
+    metadata_path: &str,
+    item_id: &str,
+) -> Result<(File, ItemMetadata), WriteError> {
+    let mut metadata_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(metadata_path)
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => {
+                WriteError::NotFound(format!("Item {item_id} not found"))
+            }
+            _ => WriteError::Failed(IoFailure::new("open metadata", metadata_path, &error).into()),
+        })?;
+    if !lock_metadata(&mut metadata_file, metadata_path, Some(METADATA_LOCK_WAIT))? {
+        return Err(WriteError::Locked(
+            "Item metadata is being updated by another request, try again".to_string(),
+        ));
+    }
+    let metadata = read_metadata(&mut metadata_file, metadata_path)?;
+    Ok((metadata_file, metadata))
+}
+
+fn committed<'a>(
+    metadata: &'a ItemMetadata,
+    item_id: &str,
+    item_version: u64,
+) -> Result<&'a VersionMetadata, WriteError> {
+    metadata.versions.get(&item_version).ok_or_else(|| {
+        WriteError::NotFound(format!(
+            "Version {item_version} of item {item_id} is not committed"
+        ))
+    })
+}
+
+/// Hash the version's data file, quarantining it if it does not match. The size and
+/// checksum of a version committed without them are recorded on the way.
+fn check_locked(
+    storage: &Storage,
+    item_id: &str,
+    item_version: u64,
+    metadata_file: &mut File,
+    metadata: &mut ItemMetadata,
+    check: IntegrityCheck,
+) -> Result<VerifyReport, WriteError> {
+    let version = committed(metadata, item_id, item_version)?;
+    let data_path = version_file_path(
+        storage,
+        version.location.as_deref(),
+        &data_file_name(item_id, item_version),
+    );
+    let found = digest(&data_path)?;
+    if !matches(version, &found) && version.quarantined_at.is_some() {
+        // Still broken, the quarantine stays as first recorded
+        let mut failure = IntegrityFailure::new(item_id, version);
+        failure.found_size = Some(found.size);
+        failure.found_sha256 = Some(found.sha256);
+        return Err(WriteError::Quarantined(Box::new(failure)));
+    }
+    if !matches(version, &found) {
+        let failure = quarantine_locked(
+            storage,
+            item_id,
+            item_version,
+            metadata_file,
+            metadata,
+            &found,
+            check,
+        )?;
+        return Err(WriteError::Quarantined(Box::new(failure)));
+    }
+    let mut report = VerifyReport {
+        item_id: item_id.to_string(),
+        version: item_version,
+        outcome: VerifyOutcome::Intact,
+        checksum_verified: version.sha256.is_some(),
+        size: found.size,
+        sha256: found.sha256.clone(),
+    };
+    if !storage.read_only
+        && let Some(version) = metadata.versions.get_mut(&item_version)
+    {
+        version.last_verified_at = Some(chrono::Utc::now().to_rfc3339());
+        let backfilled = fill_in_digest(version, &found);
+        replace_metadata(metadata_file, &metadata_path(storage, item_id), metadata)?;
+        if backfilled {
+            log_backfill(&storage.metrics, item_id, item_version, &found);
+            report.outcome = VerifyOutcome::Backfilled;
+        }
+    }
+    Ok(report)
+}
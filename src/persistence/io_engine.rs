@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::SeekFrom;
+use tokio::fs::File as TokioFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::RwLock;
+
+/// How data files are read and written (`STREAM_DB_IO_ENGINE`).
+///
+/// `tokio` goes through tokio's `File`, which hands every operation to the blocking pool
+/// and buffers internally. `blocking` (feature `blocking-io`, unix only) runs positional
+/// `pread`/`pwrite` calls on a plain file in `spawn_blocking` with larger buffers, and
+/// lets concurrent readers of one file proceed without taking turns on a shared cursor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoEngine {
+    Tokio,
+    #[cfg(feature = "blocking-io")]
+    Blocking,
+}
+
+impl IoEngine {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "tokio" => Ok(Self::Tokio),
+            #[cfg(feature = "blocking-io")]
+            "blocking" => Ok(Self::Blocking),
+            #[cfg(not(feature = "blocking-io"))]
+            "blocking" => Err("The blocking I/O engine needs the blocking-io feature".to_string()),
+            "uring" => Err("The io_uring engine is not supported".to_string()),
+            other => Err(format!("Unknown I/O engine {other:?}")),
+        }
+    }
+
+    /// Bytes a reader asks for at once
+    pub fn read_chunk_size(&self) -> usize {
+        match self {
+            Self::Tokio => 8 * 1024,
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking => 256 * 1024,
+        }
+    }
+
+    pub fn reader(&self, file: File) -> PositionalReader {
+        match self {
+            Self::Tokio => PositionalReader::Tokio(RwLock::new(TokioFile::from_std(file))),
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking => PositionalReader::Blocking(std::sync::Arc::new(file)),
+        }
+    }
+
+    /// Appends to `file`, which must be empty
+    pub fn appender(&self, file: File) -> Appender {
+        match self {
+            Self::Tokio => Appender::Tokio(TokioFile::from_std(file)),
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking => Appender::Blocking {
+                file: std::sync::Arc::new(file),
+                offset: 0,
+            },
+        }
+    }
+}
+
+/// When the data of an upload is synced to disk (`STREAM_DB_FSYNC`).
+///
+/// `chunk` syncs every chunk before it is acknowledged, so whatever readers see survives
+/// a crash. `commit` only syncs once when the upload commits, which is faster but lets
+/// readers see bytes that may still be lost, see [`ReadDurability`].
+///
+/// [`ReadDurability`]: crate::persistence::file_persistence::ReadDurability
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FsyncPolicy {
+    Chunk,
+    Commit,
+}
+
+impl FsyncPolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "chunk" => Ok(Self::Chunk),
+            "commit" => Ok(Self::Commit),
+            other => Err(format!("Unknown fsync policy {other:?}")),
+        }
+    }
+}
+
+/// Read access to a data file at arbitrary offsets, shared by all of its readers
+pub enum PositionalReader {
+    Tokio(RwLock<TokioFile>),
+    #[cfg(feature = "blocking-io")]
+    Blocking(std::sync::Arc<File>),
+}
+
+impl PositionalReader {
+    pub async fn read_at(&self, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tokio(file) => {
+                let mut file = file.write().await;
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.read(buffer).await
+            }
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking(file) => {
+                use std::os::unix::fs::FileExt;
+
+                let file = file.clone();
+                let length = buffer.len();
+                let bytes = tokio::task::spawn_blocking(move || {
+                    let mut bytes = vec![0u8; length];
+                    let bytes_read = file.read_at(&mut bytes, offset)?;
+                    bytes.truncate(bytes_read);
+                    Ok::<_, std::io::Error>(bytes)
+                })
+                .await
+                .map_err(std::io::Error::other)??;
+                buffer[..bytes.len()].copy_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+        }
+    }
+}
+
+/// Appends to the data file of an upload
+pub enum Appender {
+    Tokio(TokioFile),
+    #[cfg(feature = "blocking-io")]
+    Blocking {
+        file: std::sync::Arc<File>,
+        offset: u64,
+    },
+}
+
+impl Appender {
+    /// Append `chunk`, which is visible to readers of the file once this returns
+    pub async fn append(&mut self, chunk: Vec<u8>) -> std::io::Result<()> {
+        match self {
+            Self::Tokio(file) => {
+                file.write_all(&chunk).await?;
+                file.flush().await
+            }
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking { file, offset } => {
+                use std::os::unix::fs::FileExt;
+
+                let file = file.clone();
+                let length = chunk.len() as u64;
+                let at = *offset;
+                tokio::task::spawn_blocking(move || file.write_all_at(&chunk, at))
+                    .await
+                    .map_err(std::io::Error::other)??;
+                *offset += length;
+                Ok(())
+            }
+        }
+    }
+
+    /// Sync everything appended so far to disk
+    pub async fn sync(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tokio(file) => file.sync_data().await,
+            #[cfg(feature = "blocking-io")]
+            Self::Blocking { file, .. } => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .map_err(std::io::Error::other)?
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+use std::io;
+
+/// A filesystem operation that failed, with everything an operator needs to tell why:
+/// what was done, to which path, and the OS error with its kind and errno. It ends up
+/// in the message of an internal error, which clients only get to see with
+/// `STREAM_DB_EXPOSE_INTERNAL_ERRORS`; the server log always has it.
+#[derive(Debug)]
+pub struct IoFailure {
+    pub operation: &'static str,
+    pub path: String,
+    pub kind: io::ErrorKind,
+    pub raw_os_error: Option<i32>,
+    pub message: String,
+}
+
+impl IoFailure {
+    pub fn new(operation: &'static str, path: &str, error: &io::Error) -> Self {
+        Self {
+            operation,
+            path: path.to_string(),
+            kind: error.kind(),
+            raw_os_error: error.raw_os_error(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for IoFailure {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The message of an OS error ends in its errno already
+        write!(
+            formatter,
+            "{} {} failed ({:?}): {}",
+            self.operation, self.path, self.kind, self.message
+        )
+    }
+}
+
+impl From<IoFailure> for String {
+    fn from(failure: IoFailure) -> Self {
+        failure.to_string()
+    }
+}
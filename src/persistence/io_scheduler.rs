@@ -0,0 +1,351 @@
+use crate::metrics::{Counter, Gauge, Metrics};
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+/// How upload I/O waits for its turn once a lane is busy (`STREAM_DB_IO_SCHEDULING`).
+///
+/// `fair` serves the uploads waiting in a lane round-robin, one operation each, so an
+/// upload sending chunks back to back cannot crowd out the others. `fifo` serves the
+/// operations in the order they arrived. `off` lets every operation run right away.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoSchedulingPolicy {
+    Fair,
+    Fifo,
+    Off,
+}
+
+impl IoSchedulingPolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "fair" => Ok(Self::Fair),
+            "fifo" => Ok(Self::Fifo),
+            "off" => Ok(Self::Off),
+            other => Err(format!("Unknown I/O scheduling policy {other:?}")),
+        }
+    }
+}
+
+/// Which lane an operation queues in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoLane {
+    /// Metadata updates and the writes of uploads still below the small item threshold
+    Fast,
+    /// The writes and syncs of large uploads
+    Heavy,
+}
+
+/// Bounds how many upload operations occupy the blocking pool at once. Each lane has its
+/// own slots, so small items and metadata updates never queue behind large uploads, and
+/// large uploads take turns on theirs.
+pub struct IoScheduler {
+    policy: IoSchedulingPolicy,
+    small_item_bytes: u64,
+    fast: Arc<Lane>,
+    heavy: Arc<Lane>,
+    next_stream: AtomicU64,
+}
+
+impl IoScheduler {
+    pub fn new(
+        policy: IoSchedulingPolicy,
+        fast_slots: usize,
+        heavy_slots: usize,
+        small_item_bytes: u64,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            policy,
+            small_item_bytes,
+            fast: Arc::new(Lane::new(IoLane::Fast, fast_slots, metrics.clone())),
+            heavy: Arc::new(Lane::new(IoLane::Heavy, heavy_slots, metrics)),
+            next_stream: AtomicU64::new(1),
+        }
+    }
+
+    /// Identifies an upload to the fair policy
+    pub fn new_stream(&self) -> u64 {
+        self.next_stream.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The lane for the next operation of an upload that has written `bytes` so far
+    pub fn lane_for(&self, bytes: u64) -> IoLane {
+        if bytes < self.small_item_bytes {
+            IoLane::Fast
+        } else {
+            IoLane::Heavy
+        }
+    }
+
+    /// Wait for a slot in `lane`, held until the returned permit is dropped
+    pub async fn acquire(&self, lane: IoLane, stream: u64) -> IoPermit {
+        let lane = match lane {
+            IoLane::Fast => &self.fast,
+            IoLane::Heavy => &self.heavy,
+        };
+        if self.policy == IoSchedulingPolicy::Off {
+            lane.operations().increment();
+            return IoPermit { lane: None };
+        }
+        // Every waiter in one queue is served in arrival order
+        let stream = match self.policy {
+            IoSchedulingPolicy::Fair => stream,
+            _ => 0,
+        };
+        lane.acquire(stream).await
+    }
+}
+
+struct Lane {
+    lane: IoLane,
+    slots: usize,
+    state: Mutex<LaneState>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct LaneState {
+    busy: usize,
+    /// Streams with operations waiting, in the order they are served, each with its
+    /// waiting operations in arrival order
+    waiting: VecDeque<(u64, VecDeque<oneshot::Sender<IoPermit>>)>,
+}
+
+impl Lane {
+    fn new(lane: IoLane, slots: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            lane,
+            slots: slots.max(1),
+            state: Mutex::new(LaneState::default()),
+            metrics,
+        }
+    }
+
+    fn depth(&self) -> &Gauge {
+        match self.lane {
+            IoLane::Fast => &self.metrics.io_fast_queue_depth,
+            IoLane::Heavy => &self.metrics.io_heavy_queue_depth,
+        }
+    }
+
+    fn operations(&self) -> &Counter {
+        match self.lane {
+            IoLane::Fast => &self.metrics.io_fast_operations,
+            IoLane::Heavy => &self.metrics.io_heavy_operations,
+        }
+    }
+
+    fn wait_milliseconds(&self) -> &Counter {
+        match self.lane {
+            IoLane::Fast => &self.metrics.io_fast_wait_milliseconds,
+            IoLane::Heavy => &self.metrics.io_heavy_wait_milliseconds,
+        }
+    }
+
+    async fn acquire(self: &Arc<Self>, stream: u64) -> IoPermit {
+        self.operations().increment();
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.busy < self.slots {
+                state.busy += 1;
+                return IoPermit {
+                    lane: Some(self.clone()),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            match state
+                .waiting
+                .iter_mut()
+                .find(|(waiting, _)| *waiting == stream)
+            {
+                Some((_, operations)) => operations.push_back(sender),
+                None => state.waiting.push_back((stream, VecDeque::from([sender]))),
+            }
+            self.depth().add(1);
+            receiver
+        };
+        let waiting_since = Instant::now();
+        // Senders are only dropped after handing over a permit
+        let permit = receiver
+            .await
+            .expect("I/O lane dropped a waiting operation");
+        self.wait_milliseconds()
+            .add(waiting_since.elapsed().as_millis() as u64);
+        permit
+    }
+
+    /// Hand the slot of a finished operation to the next stream in turn
+    fn release(self: &Arc<Self>) {
+        let mut permit = IoPermit {
+            lane: Some(self.clone()),
+        };
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                match state.waiting.pop_front() {
+                    Some((stream, mut operations)) => {
+                        let next = operations.pop_front();
+                        if !operations.is_empty() {
+                            // Back of the line until every other stream had a turn
+                            state.waiting.push_back((stream, operations));
+                        }
+                        self.depth().sub(1);
+                        next
+                    }
+                    None => {
+                        state.busy -= 1;
+                        permit.lane = None;
+                        return;
+                    }
+                }
+            };
+            let Some(next) = next else { continue };
+            // An operation that gave up waiting returns the permit for the next one
+            match next.send(permit) {
+                Ok(()) => return,
+                Err(returned) => permit = returned,
+            }
+        }
+    }
+}
+
+/// A slot in a lane, released when dropped. A permit handed to an operation that gave up
+/// waiting is dropped with the channel and passed on from there.
+pub struct IoPermit {
+    lane: Option<Arc<Lane>>,
+}
+
+impl Drop for IoPermit {
+    fn drop(&mut self) {
+        if let Some(lane) = self.lane.take() {
+            lane.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Served = Arc<Mutex<Vec<&'static str>>>;
+
+    /// A scheduler whose lanes run one operation at a time, every upload in the fast lane
+    fn scheduler(policy: IoSchedulingPolicy) -> Arc<IoScheduler> {
+        let metrics = Arc::new(Metrics::new());
+        Arc::new(IoScheduler::new(policy, 1, 1, u64::MAX, metrics))
+    }
+
+    fn busy(scheduler: &IoScheduler) -> usize {
+        scheduler.fast.state.lock().unwrap().busy
+    }
+
+    fn queued(scheduler: &IoScheduler) -> usize {
+        let state = scheduler.fast.state.lock().unwrap();
+        state.waiting.iter().map(|(_, waiting)| waiting.len()).sum()
+    }
+
+    /// Queue an operation of `stream`, which notes `name` once it got the slot and lets
+    /// go of it right away. Returns once the operation waits in the lane.
+    async fn queue(
+        scheduler: &Arc<IoScheduler>,
+        stream: u64,
+        name: &'static str,
+        served: &Served,
+    ) -> tokio::task::JoinHandle<()> {
+        let queued_before = queued(scheduler);
+        let operation = {
+            let scheduler = scheduler.clone();
+            let served = served.clone();
+            tokio::spawn(async move {
+                let _permit = scheduler.acquire(IoLane::Fast, stream).await;
+                served.lock().unwrap().push(name);
+            })
+        };
+        while queued(scheduler) == queued_before {
+            tokio::task::yield_now().await;
+        }
+        operation
+    }
+
+    /// Wait for a queued operation to be served, failing the test if its slot never comes
+    async fn until_served(operation: tokio::task::JoinHandle<()>) {
+        tokio::time::timeout(std::time::Duration::from_secs(2), operation)
+            .await
+            .expect("the operation was never handed a slot")
+            .unwrap();
+    }
+
+    /// The order in which two operations of stream 1 and then one of stream 2, all
+    /// queued behind a busy slot, are served
+    async fn served_order(policy: IoSchedulingPolicy) -> Vec<&'static str> {
+        let scheduler = scheduler(policy);
+        let served = Served::default();
+        let holder = scheduler.acquire(IoLane::Fast, 9).await;
+        let operations = [
+            queue(&scheduler, 1, "a1", &served).await,
+            queue(&scheduler, 1, "a2", &served).await,
+            queue(&scheduler, 2, "b1", &served).await,
+        ];
+        assert_eq!(scheduler.fast.depth().get(), 3);
+        drop(holder);
+        for operation in operations {
+            until_served(operation).await;
+        }
+        assert_eq!(busy(&scheduler), 0);
+        assert_eq!(scheduler.fast.depth().get(), 0);
+        served.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn fair_scheduling_takes_turns_across_streams() {
+        assert_eq!(
+            served_order(IoSchedulingPolicy::Fair).await,
+            ["a1", "b1", "a2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fifo_scheduling_serves_in_arrival_order() {
+        assert_eq!(
+            served_order(IoSchedulingPolicy::Fifo).await,
+            ["a1", "a2", "b1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_waiter_that_gave_up_passes_its_slot_on() {
+        let scheduler = scheduler(IoSchedulingPolicy::Fair);
+        let served = Served::default();
+        let holder = scheduler.acquire(IoLane::Fast, 9).await;
+        let gave_up = queue(&scheduler, 1, "gave up", &served).await;
+        let next = queue(&scheduler, 2, "next", &served).await;
+        gave_up.abort();
+        assert!(gave_up.await.unwrap_err().is_cancelled());
+        drop(holder);
+        until_served(next).await;
+        assert_eq!(*served.lock().unwrap(), ["next"]);
+        assert_eq!(busy(&scheduler), 0);
+    }
+
+    #[tokio::test]
+    async fn a_slot_handed_to_a_waiter_that_then_gave_up_is_passed_on() {
+        let scheduler = scheduler(IoSchedulingPolicy::Fair);
+        let served = Served::default();
+        let holder = scheduler.acquire(IoLane::Fast, 9).await;
+        let gave_up = queue(&scheduler, 1, "gave up", &served).await;
+        let next = queue(&scheduler, 2, "next", &served).await;
+        // The permit is sent before the waiter runs again, it is dropped with the channel
+        drop(holder);
+        gave_up.abort();
+        assert!(gave_up.await.unwrap_err().is_cancelled());
+        until_served(next).await;
+        assert_eq!(*served.lock().unwrap(), ["next"]);
+        assert_eq!(busy(&scheduler), 0);
+        // The slot is free again
+        drop(scheduler.acquire(IoLane::Fast, 1).await);
+        assert_eq!(busy(&scheduler), 0);
+    }
+}
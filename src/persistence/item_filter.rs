@@ -0,0 +1,211 @@
+use crate::metrics::Metrics;
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+/// Items a filter is sized for, per item found when it is built, so the items created
+/// until the next rebuild keep the false positive rate
+const HEADROOM: usize = 2;
+/// Items a filter is sized for at least, so a new instance does not fill up right away
+const MIN_CAPACITY: usize = 100_000;
+/// Bit positions set per item at most, whatever the false positive rate asks for
+const MAX_HASHES: u64 = 16;
+
+/// A bloom filter over item IDs, whose bits are set without a lock
+struct Bloom {
+    bits: Vec<AtomicU64>,
+    hashes: u64,
+    /// Items the filter was sized for
+    capacity: usize,
+    /// Items added, not counting those all of whose bits were set already
+    items: AtomicUsize,
+    /// The memory budget held fewer bits than the false positive rate asks for
+    over_budget: bool,
+}
+
+impl Bloom {
+    fn new(capacity: usize, false_positive_rate: f64, max_bytes: u64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let wanted_bits = -(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2);
+        let budget_bits = max_bytes as f64 * 8.0;
+        let bits = wanted_bits.min(budget_bits).max(64.0) as u64;
+        let words = bits.div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity as f64 * ln2).round() as u64;
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes: hashes.clamp(1, MAX_HASHES),
+            capacity,
+            items: AtomicUsize::new(0),
+            over_budget: wanted_bits > budget_bits,
+        }
+    }
+
+    /// Bits of `item_id`, by double hashing
+    fn positions(&self, item_id: &str) -> impl Iterator<Item = u64> + use<> {
+        let mut hasher = DefaultHasher::new();
+        item_id.hash(&mut hasher);
+        let first = hasher.finish();
+        let mut hasher = DefaultHasher::new();
+        (item_id, 1u8).hash(&mut hasher);
+        // Odd, so the positions do not repeat before wrapping around
+        let second = hasher.finish() | 1;
+        let bits = self.bits.len() as u64 * 64;
+        (0..self.hashes).map(move |hash| first.wrapping_add(hash.wrapping_mul(second)) % bits)
+    }
+
+    /// Add `item_id`, returning whether it was not in the filter yet
+    fn insert(&self, item_id: &str) -> bool {
+        let mut added = false;
+        for position in self.positions(item_id) {
+            let bit = 1 << (position % 64);
+            let previous = self.bits[(position / 64) as usize].fetch_or(bit, Ordering::AcqRel);
+            added |= previous & bit == 0;
+        }
+        if added {
+            self.items.fetch_add(1, Ordering::AcqRel);
+        }
+        added
+    }
+
+    fn may_contain(&self, item_id: &str) -> bool {
+        self.positions(item_id).all(|position| {
+            self.bits[(position / 64) as usize].load(Ordering::Acquire) & (1 << (position % 64))
+                != 0
+        })
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    fn is_full(&self) -> bool {
+        self.items.load(Ordering::Acquire) > self.capacity
+    }
+}
+
+/// What a rebuild of the [`ItemFilter`] found
+pub struct FilterBuild {
+    pub items: usize,
+    pub bytes: u64,
+    pub hashes: u64,
+    /// The memory budget held fewer bits than the false positive rate asks for
+    pub over_budget: bool,
+}
+
+/// Every item stored in the data directory, as far as a bloom filter tells: an item it
+/// rules out is certainly not stored, so looking up made-up item IDs costs no disk
+/// access at all. An item it lets through may still not be stored, at the configured
+/// false positive rate.
+///
+/// The filter is built from a scan of the data directory and takes every item whose
+/// metadata this instance creates afterwards, and those the data directory watch finds
+/// created by other processes. Deleted items stay in it until it is rebuilt. Nothing is
+/// ruled out until it was first built.
+pub struct ItemFilter {
+    bloom: RwLock<Option<Bloom>>,
+    /// Items created while a rebuild scans the data directory, which it may not see,
+    /// `None` unless one does
+    created_while_building: Mutex<Option<Vec<String>>>,
+    /// Woken once more items were added than the filter was sized for
+    full: Notify,
+    metrics: Arc<Metrics>,
+}
+
+impl ItemFilter {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            bloom: RwLock::default(),
+            created_while_building: Mutex::default(),
+            full: Notify::new(),
+            metrics,
+        }
+    }
+
+    /// Whether `item_id` is certainly not stored
+    pub fn rules_out(&self, item_id: &str) -> bool {
+        let bloom = self.bloom.read().unwrap();
+        let Some(bloom) = bloom.as_ref() else {
+            return false;
+        };
+        if bloom.may_contain(item_id) {
+            self.metrics.item_filter_passes.increment();
+            false
+        } else {
+            self.metrics.item_filter_negatives.increment();
+            true
+        }
+    }
+
+    /// An item the filter let through turned out not to be stored
+    pub fn false_positive(&self) {
+        if self.bloom.read().unwrap().is_some() {
+            self.metrics.item_filter_false_positives.increment();
+        }
+    }
+
+    /// Add an item whose metadata was just created
+    pub fn insert(&self, item_id: &str) {
+        let bloom = self.bloom.read().unwrap();
+        if let Some(bloom) = bloom.as_ref()
+            && bloom.insert(item_id)
+        {
+            self.metrics.item_filter_items.add(1);
+            if bloom.is_full() {
+                self.full.notify_one();
+            }
+        }
+        // Under the read lock, so a rebuild swapping the filter either sees it here or
+        // the item went into the new filter
+        if let Some(created) = self.created_while_building.lock().unwrap().as_mut() {
+            created.push(item_id.to_string());
+        }
+    }
+
+    /// Start recording the items created from now on, to be called before the data
+    /// directory is scanned for [`rebuild`](Self::rebuild)
+    pub fn start_build(&self) {
+        *self.created_while_building.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Replace the filter with one holding `item_ids`, found by a scan that started
+    /// after [`start_build`](Self::start_build), and the items created since
+    pub fn rebuild(
+        &self,
+        item_ids: &[String],
+        false_positive_rate: f64,
+        max_bytes: u64,
+    ) -> FilterBuild {
+        let capacity = (item_ids.len() * HEADROOM).max(MIN_CAPACITY);
+        let new = Bloom::new(capacity, false_positive_rate, max_bytes);
+        for item_id in item_ids {
+            new.insert(item_id);
+        }
+        let mut bloom = self.bloom.write().unwrap();
+        for item_id in self
+            .created_while_building
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default()
+        {
+            new.insert(&item_id);
+        }
+        let build = FilterBuild {
+            items: new.items.load(Ordering::Acquire),
+            bytes: new.bytes(),
+            hashes: new.hashes,
+            over_budget: new.over_budget,
+        };
+        self.metrics.item_filter_items.set(build.items as u64);
+        self.metrics.item_filter_bytes.set(build.bytes);
+        *bloom = Some(new);
+        build
+    }
+
+    /// Wait until more items were added than the filter was sized for
+    pub async fn filled_up(&self) {
+        self.full.notified().await;
+    }
+}
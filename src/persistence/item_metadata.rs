@@ -0,0 +1,541 @@
+use crate::persistence::io_failure::IoFailure;
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use quick_xml::Reader;
+use quick_xml::escape::{escape, unescape};
+use quick_xml::events::{BytesStart, Event};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// How long a metadata read waits for a writer to finish rewriting the file
+const REWRITE_WAIT: Duration = Duration::from_millis(200);
+
+/// Everything recorded about a committed version, which doubles as its write receipt.
+///
+/// Versions committed before this was recorded only carry their version number.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct VersionMetadata {
+    pub version: u64,
+    pub size: Option<u64>,
+    pub property_count: Option<u64>,
+    pub sha256: Option<String>,
+    /// RFC 3339 timestamp in UTC
+    pub committed_at: Option<String>,
+    pub request_id: Option<String>,
+    /// Generation of the version number, bumped every time it is written again after
+    /// being deleted
+    pub epoch: Option<u64>,
+    /// Cold-tier directory the version's files were moved to, they live in the data
+    /// directory when unset
+    pub location: Option<String>,
+    /// Whether the item had no committed version when this one was committed, i.e. the
+    /// upload created the item
+    pub first_version: Option<bool>,
+    /// RFC 3339 timestamp of when the data was found not matching `size` and `sha256`,
+    /// quarantined versions are not served until they are repaired or deleted
+    pub quarantined_at: Option<String>,
+    /// What found the mismatch, see [`IntegrityCheck`](super::integrity::IntegrityCheck)
+    pub quarantine_check: Option<String>,
+    /// Size of the data file when the mismatch was found
+    pub found_size: Option<u64>,
+    /// Checksum of the data file when the mismatch was found
+    pub found_sha256: Option<String>,
+    /// RFC 3339 timestamp of when `size` or `sha256` were computed from the stored data
+    /// of a version committed without them, rather than recorded by the upload
+    pub computed_at: Option<String>,
+    /// RFC 3339 timestamp of when the data was last hashed and found matching `size` and
+    /// `sha256`, by the verification sweep or an admin's verify
+    pub last_verified_at: Option<String>,
+    /// RFC 3339 timestamp of the version's last move between the data directory and the
+    /// cold tier
+    pub moved_at: Option<String>,
+    /// A commit hook whose failure policy is `flag` failed for the version
+    pub hook_failed: Option<bool>,
+    /// ... which are these
+    pub failed_hooks: Option<Vec<String>>,
+    /// RFC 3339 timestamp of when the version was deleted through the S3 API, which no
+    /// longer serves it while every other endpoint still does
+    pub s3_deleted_at: Option<String>,
+    /// RFC 3339 timestamp until which the version cannot be deleted, replaced or hidden,
+    /// by anyone, see [`VersionMetadata::immutable_at`]. It can only be pushed further out.
+    pub immutable_until: Option<String>,
+    /// `Content-Encoding` of the stored data, set when a compressed upload was stored as
+    /// received rather than decoded, see `STREAM_DB_STORE_GZIP_UPLOADS`
+    pub content_encoding: Option<String>,
+    /// Where each segment of a framed upload starts in the stored data, followed by where
+    /// the last one ends, so segment `n` is the bytes from entry `n` up to entry `n + 1`
+    pub segments: Option<Vec<u64>>,
+    /// Commit-time transforms the upload went through, in the order they apply:
+    /// `wrap_root`, `dedupe_last` and `sort_by_name`
+    pub commit_transforms: Option<Vec<String>>,
+    /// Bytes rewritten at commit per byte received, set along with `commit_transforms`.
+    /// The transforms share a single rewrite, so it stays at most about 1, and is 0 when
+    /// none of them had to touch the stored bytes.
+    pub write_amplification: Option<f64>,
+    /// Raw XML of the extra elements the upload carried by name, see
+    /// [`ExtraElements`](crate::logic::extra_elements::ExtraElements)
+    pub extra_elements: Option<BTreeMap<String, String>>,
+    /// Extra elements that were stored but too large to be copied
+    pub extra_elements_truncated: Option<Vec<String>>,
+    /// The `x-amz-meta-*` headers of an upload through the S3 API, by their name without
+    /// the prefix
+    pub user_metadata: Option<BTreeMap<String, String>>,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+impl VersionMetadata {
+    /// Whether the version is still immutable at `now`. A deadline that does not parse
+    /// counts as one that has not passed, so a damaged attribute cannot unlock a version.
+    pub fn immutable_at(&self, now: DateTime<Utc>) -> bool {
+        self.immutable_until.as_deref().is_some_and(|until| {
+            DateTime::parse_from_rfc3339(until).map_or(true, |until| until > now)
+        })
+    }
+}
+
+/// Who wrote a version: what the producer said about itself in the upload's
+/// `X-Producer`, `X-Producer-Version` and `X-Commit-Message` headers, and who it
+/// authenticated as. Fields the upload did not provide are left out.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Provenance {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub producer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub producer_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_message: Option<String>,
+    /// The commit message was cut to fit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_message_truncated: Option<bool>,
+    /// Identity the upload authenticated with, unlike the headers not self-reported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authenticated_as: Option<String>,
+}
+
+/// Contents of `{item_id}_metadata.xml`:
+///
+/// ```xml
+/// <metadata>
+///     <version>2</version>
+///     <versions>
+///         <committed version="1" size="120" property_count="3" sha256="..." committed_at="..." request_id="..." first_version="true"/>
+///         <committed version="2" ...>
+///             <extra_element name="summary">&lt;summary&gt;...&lt;/summary&gt;</extra_element>
+///             <extra_element name="provenance" truncated="true"/>
+///             <user_metadata name="camera">x100</user_metadata>
+///         </committed>
+///         <retired version="3" epoch="1"/>
+///     </versions>
+/// </metadata>
+/// ```
+///
+/// `<version>` is the latest committed version and the only element older metadata files
+/// have, which is why it stays the first child. A `quarantined_at` attribute, along with
+/// `quarantine_check`, `found_size` and `found_sha256`, marks a version whose data no
+/// longer matched its checksum. `computed_at` marks a `size` and `sha256` filled in later
+/// for a version committed without them, `last_verified_at` when its data was last found
+/// intact and `moved_at` when it last moved between tiers. `hook_failed` and the comma
+/// separated `failed_hooks` mark a version a commit hook failed for, `s3_deleted_at` one
+/// deleted through the S3 API, `immutable_until` one that cannot be removed before then
+/// and `content_encoding` one whose data is stored compressed. The comma separated
+/// `segments` are the boundaries of the segments of a framed upload, `commit_transforms`
+/// and `write_amplification` what was done to the upload at commit. `producer`,
+/// `producer_version`, `commit_message`, `commit_message_truncated` and `authenticated_as`
+/// record who wrote the version, see [`Provenance`]. `<extra_element>` children hold the
+/// escaped copies of the version's extra elements and `<user_metadata>` children the
+/// metadata an upload through the S3 API carried. `<retired>` remembers the epoch of a
+/// deleted version so writing it again starts a new generation.
+#[derive(Default, Clone)]
+pub struct ItemMetadata {
+    pub latest_version: Option<u64>,
+    pub versions: BTreeMap<u64, VersionMetadata>,
+    pub retired: BTreeMap<u64, u64>,
+}
+
+impl ItemMetadata {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut metadata = ItemMetadata::default();
+        let mut reader = Reader::from_reader(bytes);
+        let mut buffer = Vec::new();
+        // Version whose children are being read
+        let mut open_version = None;
+
+        loop {
+            match reader
+                .read_event_into(&mut buffer)
+                .map_err(|error| format!("Metadata is not valid XML: {error}"))?
+            {
+                Event::Start(ref element) if element.name().as_ref() == b"version" => {
+                    let text = reader
+                        .read_text(element.name())
+                        .map_err(|error| error.to_string())?;
+                    metadata.latest_version = Some(
+                        text.trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid latest version {text:?} in metadata"))?,
+                    );
+                }
+                Event::Empty(ref element) if element.name().as_ref() == b"committed" => {
+                    let version = parse_version_entry(element)?;
+                    metadata.versions.insert(version.version, version);
+                }
+                Event::Start(ref element) if element.name().as_ref() == b"committed" => {
+                    let version = parse_version_entry(element)?;
+                    open_version = Some(version.version);
+                    metadata.versions.insert(version.version, version);
+                }
+                Event::End(ref element) if element.name().as_ref() == b"committed" => {
+                    open_version = None;
+                }
+                Event::Start(ref element) if element.name().as_ref() == b"extra_element" => {
+                    let name = name_attribute(element)?;
+                    let text = reader
+                        .read_text(element.name())
+                        .map_err(|error| error.to_string())?;
+                    let copy = unescape(&text)
+                        .map_err(|error| format!("Invalid extra element in metadata: {error}"))?
+                        .into_owned();
+                    if let Some(version) =
+                        open_version.and_then(|version| metadata.versions.get_mut(&version))
+                    {
+                        version
+                            .extra_elements
+                            .get_or_insert_default()
+                            .insert(name, copy);
+                    }
+                }
+                Event::Start(ref element) if element.name().as_ref() == b"user_metadata" => {
+                    let name = name_attribute(element)?;
+                    let text = reader
+                        .read_text(element.name())
+                        .map_err(|error| error.to_string())?;
+                    let value = unescape(&text)
+                        .map_err(|error| format!("Invalid user metadata in metadata: {error}"))?
+                        .into_owned();
+                    if let Some(version) =
+                        open_version.and_then(|version| metadata.versions.get_mut(&version))
+                    {
+                        version
+                            .user_metadata
+                            .get_or_insert_default()
+                            .insert(name, value);
+                    }
+                }
+                // Extra elements too large to be copied leave an empty element behind
+                Event::Empty(ref element) if element.name().as_ref() == b"extra_element" => {
+                    let name = name_attribute(element)?;
+                    if let Some(version) =
+                        open_version.and_then(|version| metadata.versions.get_mut(&version))
+                    {
+                        version
+                            .extra_elements_truncated
+                            .get_or_insert_default()
+                            .push(name);
+                    }
+                }
+                Event::Empty(ref element) | Event::Start(ref element)
+                    if element.name().as_ref() == b"retired" =>
+                {
+                    let retired = parse_version_entry(element)?;
+                    metadata
+                        .retired
+                        .insert(retired.version, retired.epoch.unwrap_or(0));
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+            buffer.clear();
+        }
+        Ok(metadata)
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<metadata>\n");
+        if let Some(latest_version) = self.latest_version {
+            xml.push_str(&format!("    <version>{latest_version}</version>\n"));
+        }
+        xml.push_str("    <versions>\n");
+        for version in self.versions.values() {
+            xml.push_str(&format!(
+                r#"        <committed version="{}""#,
+                version.version
+            ));
+            let attributes = [
+                ("size", version.size.map(|size| size.to_string())),
+                (
+                    "property_count",
+                    version.property_count.map(|count| count.to_string()),
+                ),
+                ("sha256", version.sha256.clone()),
+                ("committed_at", version.committed_at.clone()),
+                ("request_id", version.request_id.clone()),
+                ("epoch", version.epoch.map(|epoch| epoch.to_string())),
+                ("location", version.location.clone()),
+                (
+                    "first_version",
+                    version.first_version.map(|first| first.to_string()),
+                ),
+                ("quarantined_at", version.quarantined_at.clone()),
+                ("quarantine_check", version.quarantine_check.clone()),
+                (
+                    "found_size",
+                    version.found_size.map(|size| size.to_string()),
+                ),
+                ("found_sha256", version.found_sha256.clone()),
+                ("computed_at", version.computed_at.clone()),
+                ("last_verified_at", version.last_verified_at.clone()),
+                ("moved_at", version.moved_at.clone()),
+                (
+                    "hook_failed",
+                    version.hook_failed.map(|failed| failed.to_string()),
+                ),
+                (
+                    "failed_hooks",
+                    version.failed_hooks.as_ref().map(|hooks| hooks.join(",")),
+                ),
+                ("s3_deleted_at", version.s3_deleted_at.clone()),
+                ("immutable_until", version.immutable_until.clone()),
+                ("content_encoding", version.content_encoding.clone()),
+                (
+                    "segments",
+                    version.segments.as_ref().map(|segments| {
+                        segments
+                            .iter()
+                            .map(u64::to_string)
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    }),
+                ),
+                (
+                    "commit_transforms",
+                    version
+                        .commit_transforms
+                        .as_ref()
+                        .map(|transforms| transforms.join(",")),
+                ),
+                (
+                    "write_amplification",
+                    version.write_amplification.map(|factor| factor.to_string()),
+                ),
+                ("producer", version.provenance.producer.clone()),
+                (
+                    "producer_version",
+                    version.provenance.producer_version.clone(),
+                ),
+                ("commit_message", version.provenance.commit_message.clone()),
+                (
+                    "commit_message_truncated",
+                    version
+                        .provenance
+                        .commit_message_truncated
+                        .map(|truncated| truncated.to_string()),
+                ),
+                (
+                    "authenticated_as",
+                    version.provenance.authenticated_as.clone(),
+                ),
+            ];
+            for (name, value) in attributes {
+                if let Some(value) = value {
+                    xml.push_str(&format!(r#" {name}="{}""#, escape(value.as_str())));
+                }
+            }
+            let copied = version.extra_elements.iter().flatten();
+            let truncated = version.extra_elements_truncated.iter().flatten();
+            let user_metadata = version.user_metadata.iter().flatten();
+            if copied.clone().next().is_none()
+                && truncated.clone().next().is_none()
+                && user_metadata.clone().next().is_none()
+            {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            for (name, copy) in copied {
+                xml.push_str(&format!(
+                    "            <extra_element name=\"{}\">{}</extra_element>\n",
+                    escape(name.as_str()),
+                    escape(copy.as_str())
+                ));
+            }
+            for name in truncated {
+                xml.push_str(&format!(
+                    "            <extra_element name=\"{}\" truncated=\"true\"/>\n",
+                    escape(name.as_str())
+                ));
+            }
+            for (name, value) in user_metadata {
+                xml.push_str(&format!(
+                    "            <user_metadata name=\"{}\">{}</user_metadata>\n",
+                    escape(name.as_str()),
+                    escape(value.as_str())
+                ));
+            }
+            xml.push_str("        </committed>\n");
+        }
+        for (version, epoch) in &self.retired {
+            xml.push_str(&format!(
+                "        <retired version=\"{version}\" epoch=\"{epoch}\"/>\n"
+            ));
+        }
+        xml.push_str("    </versions>\n</metadata>");
+        xml
+    }
+
+    /// Load the metadata at `path`, which lists nothing for items without one. The file
+    /// is rewritten in place, so the read waits briefly for a shared lock to not catch a
+    /// rewrite halfway. The lock is only held exclusively for rewrites, an upload in
+    /// flight is not in the metadata until it commits. The wait blocks the thread, async
+    /// callers load the metadata on the blocking pool, see
+    /// [`load_version_state`](crate::persistence::file_persistence::load_version_state).
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(error) => return Err(IoFailure::new("open metadata", path, &error).into()),
+        };
+        let deadline = Instant::now() + REWRITE_WAIT;
+        while FileExt::try_lock_shared(&file).is_err() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .map_err(|error| IoFailure::new("read metadata", path, &error))?;
+        Self::parse(&bytes)
+    }
+
+    /// Record a newly committed version and make it the latest one
+    pub fn add_committed(&mut self, version: VersionMetadata) {
+        self.latest_version = Some(
+            self.latest_version
+                .map_or(version.version, |latest| latest.max(version.version)),
+        );
+        self.retired.remove(&version.version);
+        self.versions.insert(version.version, version);
+    }
+
+    /// The newest version committed at or before `at`. Versions committed before commit
+    /// times were recorded have none and are never picked.
+    pub fn version_as_of(&self, at: DateTime<Utc>) -> Option<u64> {
+        self.versions
+            .values()
+            .rev()
+            .find(|version| {
+                version
+                    .committed_at
+                    .as_deref()
+                    .and_then(|committed_at| DateTime::parse_from_rfc3339(committed_at).ok())
+                    .is_some_and(|committed_at| committed_at.with_timezone(&Utc) <= at)
+            })
+            .map(|version| version.version)
+    }
+
+    /// Epoch a new upload of `version` gets, one past whatever generation came before it
+    pub fn next_epoch(&self, version: u64) -> u64 {
+        let previous = self
+            .versions
+            .get(&version)
+            .map(|committed| committed.epoch.unwrap_or(0))
+            .or_else(|| self.retired.get(&version).copied());
+        previous.map_or(1, |epoch| epoch + 1)
+    }
+
+    /// Forget a committed version, remembering its epoch. The latest version falls back
+    /// to the newest one left so the deleted version number can be written again.
+    pub fn remove_committed(&mut self, version: u64) -> Option<VersionMetadata> {
+        let removed = self.versions.remove(&version)?;
+        self.retired.insert(version, removed.epoch.unwrap_or(0));
+        self.latest_version = self.versions.keys().next_back().copied();
+        Some(removed)
+    }
+}
+
+fn name_attribute(element: &BytesStart) -> Result<String, String> {
+    let attribute = element
+        .try_get_attribute("name")
+        .map_err(|error| error.to_string())?
+        .ok_or("Extra element or user metadata without a name in metadata")?;
+    attribute
+        .unescape_value()
+        .map(|name| name.into_owned())
+        .map_err(|error| error.to_string())
+}
+
+fn parse_version_entry(element: &BytesStart) -> Result<VersionMetadata, String> {
+    let mut version = VersionMetadata::default();
+    let mut has_version = false;
+
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(|error| error.to_string())?;
+        let value = attribute
+            .unescape_value()
+            .map_err(|error| error.to_string())?
+            .into_owned();
+        let as_number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid number {value:?} in metadata"))
+        };
+        match attribute.key.as_ref() {
+            b"version" => {
+                version.version = as_number(&value)?;
+                has_version = true;
+            }
+            b"size" => version.size = Some(as_number(&value)?),
+            b"property_count" => version.property_count = Some(as_number(&value)?),
+            b"sha256" => version.sha256 = Some(value),
+            b"committed_at" => version.committed_at = Some(value),
+            b"request_id" => version.request_id = Some(value),
+            b"epoch" => version.epoch = Some(as_number(&value)?),
+            b"location" => version.location = Some(value),
+            b"first_version" => version.first_version = Some(value == "true"),
+            b"quarantined_at" => version.quarantined_at = Some(value),
+            b"quarantine_check" => version.quarantine_check = Some(value),
+            b"found_size" => version.found_size = Some(as_number(&value)?),
+            b"found_sha256" => version.found_sha256 = Some(value),
+            b"computed_at" => version.computed_at = Some(value),
+            b"last_verified_at" => version.last_verified_at = Some(value),
+            b"moved_at" => version.moved_at = Some(value),
+            b"hook_failed" => version.hook_failed = Some(value == "true"),
+            b"failed_hooks" => {
+                version.failed_hooks = Some(value.split(',').map(str::to_string).collect())
+            }
+            b"s3_deleted_at" => version.s3_deleted_at = Some(value),
+            b"immutable_until" => version.immutable_until = Some(value),
+            b"content_encoding" => version.content_encoding = Some(value),
+            b"segments" => {
+                version.segments = Some(value.split(',').map(as_number).collect::<Result<_, _>>()?)
+            }
+            b"commit_transforms" => {
+                version.commit_transforms = Some(value.split(',').map(str::to_string).collect())
+            }
+            b"write_amplification" => {
+                version.write_amplification = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid number {value:?} in metadata"))?,
+                )
+            }
+            b"producer" => version.provenance.producer = Some(value),
+            b"producer_version" => version.provenance.producer_version = Some(value),
+            b"commit_message" => version.provenance.commit_message = Some(value),
+            b"commit_message_truncated" => {
+                version.provenance.commit_message_truncated = Some(value == "true")
+            }
+            b"authenticated_as" => version.provenance.authenticated_as = Some(value),
+            // Attributes added by newer releases are ignored
+            _ => (),
+        }
+    }
+
+    if !has_version {
+        return Err("Version entry without a version number in metadata".to_string());
+    }
+    Ok(version)
+}
@@ -1,12 +1,136 @@
+use crate::persistence::block_index::BlockIndex;
+use crate::persistence::file_persistence::{OpenError, WriteError};
+use crate::persistence::item_metadata::{Provenance, VersionMetadata};
+use crate::persistence::property_index::PropertyIndex;
+
 use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+/// What the layers above the writer know about a version when it is committed
+#[derive(Default)]
+pub struct CommitDetails {
+    pub property_count: u64,
+    pub request_id: Option<String>,
+    /// Bytes handed to the writer, the writer refuses to commit unless it stored exactly
+    /// these
+    pub bytes_received: u64,
+    /// Raw XML of the upload's extra elements by name
+    pub extra_elements: BTreeMap<String, String>,
+    /// Extra elements that were stored but too large to be copied
+    pub extra_elements_truncated: Vec<String>,
+    /// Who wrote the upload, recorded in its receipt
+    pub provenance: Provenance,
+    /// `x-amz-meta-*` headers of an upload through the S3 API
+    pub user_metadata: BTreeMap<String, String>,
+    /// RFC 3339 timestamp the version is immutable until
+    pub immutable_until: Option<String>,
+    /// Where the segments of a framed upload start in the stored data, followed by where
+    /// the last one ends
+    pub segments: Option<Vec<u64>>,
+    /// Commit-time transforms of the upload, in the order they apply, recorded in its
+    /// receipt
+    pub transforms: Vec<String>,
+}
+
+impl CommitDetails {
+    /// Bytes rewritten at commit per byte received, rounded to thousandths, for a receipt
+    /// of an upload with commit-time transforms
+    pub fn write_amplification(&self, bytes_rewritten: u64) -> Option<f64> {
+        if self.transforms.is_empty() {
+            return None;
+        }
+        let factor = bytes_rewritten as f64 / self.bytes_received.max(1) as f64;
+        Some((factor * 1000.0).round() / 1000.0)
+    }
+}
 
 #[async_trait]
 pub trait ItemStreamWriter: Send + Sync {
     async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String>;
-    fn commit(&mut self) -> Result<(), String>;
+    /// Rewrite the data written so far, described by `index`, before `commit`: the
+    /// properties are rearranged into `order` (positions into `index`) and the ones it
+    /// leaves out are cut, in a single pass over the data however many commit-time
+    /// transforms asked for it. Readers that follow the upload keep seeing the original
+    /// data, the committed version is a new generation. Returns the index of the
+    /// rewritten data.
+    async fn rewrite_properties(
+        &mut self,
+        _index: &PropertyIndex,
+        _order: &[usize],
+    ) -> Result<PropertyIndex, String> {
+        Err("Writer does not support rewriting properties".to_string())
+    }
+
+    /// Persist the property index of the version, called right before `commit`
+    fn store_property_index(&mut self, index: &PropertyIndex) -> Result<(), String>;
+    /// Sync the version to disk and make it visible as committed, returning what was
+    /// recorded about it
+    async fn commit(&mut self, details: &CommitDetails) -> Result<VersionMetadata, String>;
+    /// Discard everything written so far: readers following the upload are failed and
+    /// the partial data is removed. Does nothing once the writer committed.
+    fn abort(&mut self);
+
+    /// Whether the upload was failed from outside, e.g. killed through the admin API
+    fn is_aborted(&self) -> bool {
+        false
+    }
+
+    /// The newer version committed while this one was uploaded, when that failed the
+    /// commit
+    fn superseded_by(&self) -> Option<u64> {
+        None
+    }
 }
 
 #[async_trait]
 pub trait ItemStreamReader: Send + Sync {
     async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String>;
+
+    /// The property index of the version, if it is committed and was stored with one
+    fn property_index(&self) -> Result<Option<PropertyIndex>, String> {
+        Ok(None)
+    }
+
+    /// The block index of the version, if it is committed and has been indexed
+    fn block_index(&self) -> Result<Option<BlockIndex>, String> {
+        Ok(None)
+    }
+
+    /// Continue reading from `offset` instead of where the reader currently is
+    fn seek(&mut self, _offset: u64) -> Result<(), String> {
+        Err("Reader does not support seeking".to_string())
+    }
+
+    /// Whether the version stopped being readable while it was followed, because its
+    /// upload failed or it was deleted
+    fn is_aborted(&self) -> bool {
+        false
+    }
+
+    /// Whether the read was failed for falling too far behind the upload it follows
+    fn is_too_slow(&self) -> bool {
+        false
+    }
+}
+
+/// Where the logic layer opens the writers and readers of versions, in place of the data
+/// directory, see [`StreamDb::with_backend`](crate::state::StreamDb::with_backend).
+/// Everything else about an item, its settings for one, stays in the data directory.
+#[async_trait]
+pub trait ItemStreamBackend: Send + Sync {
+    /// A writer of the version, refused like the data directory refuses it: with
+    /// `VersionConflict` unless the version is newer than the item's latest committed
+    /// one, and with `Locked` while another upload of it runs
+    fn open_writer(
+        &self,
+        item_id: &str,
+        item_version: u64,
+    ) -> Result<Box<dyn ItemStreamWriter>, WriteError>;
+
+    /// A reader of the version, following its upload while it is not committed
+    async fn open_reader(
+        &self,
+        item_id: &str,
+        item_version: u64,
+    ) -> Result<Box<dyn ItemStreamReader>, OpenError>;
 }
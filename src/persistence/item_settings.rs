@@ -0,0 +1,110 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+
+/// Per-item overrides of server wide settings, stored next to the item's metadata
+/// in `{item_id}_settings.json`.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ItemSettings {
+    /// Largest single property element accepted by the write path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_property_bytes: Option<u64>,
+    /// Largest number of property elements accepted in a single version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_properties_per_item: Option<u64>,
+    /// Largest upload accepted for a single version in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_item_bytes: Option<u64>,
+    /// Reject uploads whose property values do not match their declared `type`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validate_types: Option<bool>,
+    /// Order the properties of every upload is stored in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonicalize: Option<Canonicalization>,
+    /// Keep every version immutable for this many seconds after its commit, see
+    /// `X-Immutable-Until`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub immutable_for_secs: Option<u64>,
+    /// Properties the item's versions are expected to hold, see [`ItemSchema`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<ItemSchema>,
+}
+
+/// Properties declared for an item. Nothing is enforced on upload; readers asking for
+/// `apply_defaults=true` get the declared defaults of the properties a version lacks.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ItemSchema {
+    /// Set by the server: 1 for the first schema of an item, counting up whenever its
+    /// properties change
+    #[serde(default)]
+    pub version: u64,
+    pub properties: Vec<SchemaProperty>,
+}
+
+/// One property of an [`ItemSchema`]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaProperty {
+    pub name: String,
+    /// Value type, one of those a property can declare through its `type` attribute
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+    /// Value sent in place of the property when a version lacks it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+/// Order a version's properties are stored in once it is committed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Canonicalization {
+    /// As uploaded
+    None,
+    /// Sorted by name at commit, properties sharing a name keep their upload order
+    SortByName,
+}
+
+impl Canonicalization {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "none" => Ok(Self::None),
+            "sort-by-name" => Ok(Self::SortByName),
+            other => Err(format!(
+                "Unknown canonicalization {other:?}, expected sort-by-name or none"
+            )),
+        }
+    }
+}
+
+fn settings_path(storage: &Storage, item_id: &str) -> String {
+    storage.path(&format!("{item_id}_settings.json"))
+}
+
+/// Whether settings were stored for the item
+pub fn exists(storage: &Storage, item_id: &str) -> bool {
+    std::path::Path::new(&settings_path(storage, item_id)).exists()
+}
+
+/// Load the settings of an item, items without a settings file get the defaults
+pub fn load(storage: &Storage, item_id: &str) -> Result<ItemSettings, String> {
+    match std::fs::read(settings_path(storage, item_id)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|error| format!("Settings file is corrupt: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(ItemSettings::default()),
+        Err(error) => Err(format!("Settings read error: {error}")),
+    }
+}
+
+/// Replace the settings of an item. The new file is written next to the old one and
+/// renamed over it so readers never observe a half written file.
+pub fn store(storage: &Storage, item_id: &str, settings: &ItemSettings) -> Result<(), String> {
+    let path = settings_path(storage, item_id);
+    let temporary_path = format!("{path}.tmp");
+    let bytes = serde_json::to_vec_pretty(settings).map_err(|error| error.to_string())?;
+    std::fs::write(&temporary_path, bytes)
+        .map_err(|error| format!("Settings write error: {error}"))?;
+    std::fs::rename(&temporary_path, &path)
+        .map_err(|error| format!("Settings write error: {error}"))
+}
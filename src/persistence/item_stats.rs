@@ -0,0 +1,71 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Read counters of one version
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct VersionStats {
+    pub reads_started: u64,
+    /// Reads that streamed the version to its end, the rest were cut off or failed
+    pub reads_completed: u64,
+    pub bytes_served: u64,
+    /// RFC 3339 timestamp in UTC with millisecond precision, so timestamps order as
+    /// strings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_accessed: Option<String>,
+}
+
+impl VersionStats {
+    /// Add the counters of `other` on top of these
+    pub fn merge(&mut self, other: &VersionStats) {
+        self.reads_started += other.reads_started;
+        self.reads_completed += other.reads_completed;
+        self.bytes_served += other.bytes_served;
+        if other.last_accessed > self.last_accessed {
+            self.last_accessed = other.last_accessed.clone();
+        }
+    }
+}
+
+/// Contents of `{item_id}_stats.json`, the read counters of every version of an item
+/// as of the last flush
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ItemStats {
+    pub versions: BTreeMap<u64, VersionStats>,
+}
+
+impl ItemStats {
+    /// The counters of all versions added up
+    pub fn total(&self) -> VersionStats {
+        let mut total = VersionStats::default();
+        for version in self.versions.values() {
+            total.merge(version);
+        }
+        total
+    }
+}
+
+fn stats_path(storage: &Storage, item_id: &str) -> String {
+    storage.path(&format!("{item_id}_stats.json"))
+}
+
+/// Load the stats of an item, items that were never read have none
+pub fn load(storage: &Storage, item_id: &str) -> Result<ItemStats, String> {
+    match std::fs::read(stats_path(storage, item_id)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|error| format!("Stats file is corrupt: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(ItemStats::default()),
+        Err(error) => Err(format!("Stats read error: {error}")),
+    }
+}
+
+/// Replace the stats of an item, written next to the old file and renamed over it
+pub fn store(storage: &Storage, item_id: &str, stats: &ItemStats) -> Result<(), String> {
+    let path = stats_path(storage, item_id);
+    let temporary_path = format!("{path}.tmp");
+    let bytes = serde_json::to_vec_pretty(stats).map_err(|error| error.to_string())?;
+    std::fs::write(&temporary_path, bytes)
+        .map_err(|error| format!("Stats write error: {error}"))?;
+    std::fs::rename(&temporary_path, &path).map_err(|error| format!("Stats write error: {error}"))
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use tokio::fs::File as TokioFile;
+use tokio::io::AsyncWriteExt;
+
+/// A point up to which an upload's data file was synced to disk, a line of its journal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    /// Bytes of the data file synced before the checkpoint was recorded
+    pub offset: u64,
+    /// SHA-256 of those bytes
+    pub sha256: String,
+    /// RFC 3339 timestamp in UTC
+    pub recorded_at: String,
+}
+
+/// Write-ahead journal of an upload, `{item_id}_{version}.journal.jsonl` next to its data
+/// file (`STREAM_DB_JOURNAL_INTERVAL_MB`). Every `interval` bytes the writer syncs the
+/// data file and appends a synced [`Checkpoint`], so a crash loses at most the bytes
+/// written since the last one, whatever the fsync policy. The journal is removed when
+/// the upload commits or aborts.
+pub struct Journal {
+    file: TokioFile,
+    path: String,
+    interval: u64,
+    next_checkpoint: u64,
+}
+
+impl Journal {
+    /// Start the journal of an upload, replacing that of an earlier upload of the version
+    pub fn create(path: String, interval: u64) -> Result<Self, String> {
+        let file = std::fs::File::create(&path)
+            .map_err(|error| format!("Journal create error: {error}"))?;
+        Ok(Self {
+            file: TokioFile::from_std(file),
+            path,
+            interval,
+            next_checkpoint: interval,
+        })
+    }
+
+    /// Whether the data file reached the next checkpoint at `offset`
+    pub fn is_due(&self, offset: u64) -> bool {
+        offset >= self.next_checkpoint
+    }
+
+    /// Record a checkpoint, the data file must be synced up to its offset already
+    pub async fn record(&mut self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let mut line = serde_json::to_vec(checkpoint).map_err(|error| error.to_string())?;
+        line.push(b'\n');
+        let result = async {
+            self.file.write_all(&line).await?;
+            self.file.flush().await?;
+            self.file.sync_data().await
+        }
+        .await;
+        result.map_err(|error| format!("Journal write error: {error}"))?;
+        self.next_checkpoint = checkpoint.offset + self.interval;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// The last checkpoint in the journal at `path`, `None` without a journal or checkpoint.
+/// A last line torn by a crash halfway through its append is skipped.
+pub fn last_checkpoint(path: &str) -> Result<Option<Checkpoint>, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("Journal read error: {error}")),
+    };
+    Ok(bytes
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| serde_json::from_slice::<Checkpoint>(line).ok())
+        .next_back())
+}
+
+/// Whether the first `checkpoint.offset` bytes of the data file at `path` are those the
+/// checkpoint was recorded for
+pub fn matches(path: &str, checkpoint: &Checkpoint) -> Result<bool, String> {
+    let file =
+        std::fs::File::open(path).map_err(|error| format!("Data file open error: {error}"))?;
+    let mut hasher = Sha256::new();
+    let copied = std::io::copy(&mut file.take(checkpoint.offset), &mut hasher)
+        .map_err(|error| format!("Data file read error: {error}"))?;
+    Ok(copied == checkpoint.offset && format!("{:x}", hasher.finalize()) == checkpoint.sha256)
+}
+
+/// Remove the journal at `path`, a missing journal is not an error
+pub fn remove(path: &str) {
+    if let Err(error) = std::fs::remove_file(path)
+        && error.kind() != std::io::ErrorKind::NotFound
+    {
+        println!("Could not remove journal {path}: {error}");
+    }
+}
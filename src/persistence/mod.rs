@@ -1,4 +1,40 @@
+pub mod audit_log;
+pub mod block_index;
+pub mod cancellation;
+pub mod cold_tier;
+#[cfg(test)]
+mod commit_crash_tests;
+pub mod debug_capture;
+pub mod deferred_deletes;
+pub mod discard_writer;
+pub mod existence_cache;
+pub mod fault_injection;
+pub mod file_handles;
 pub mod file_persistence;
+pub mod idempotency;
+pub mod integrity;
+pub mod io_engine;
+pub mod io_failure;
+pub mod io_scheduler;
+pub mod item_filter;
+pub mod item_metadata;
 pub mod item_persistence;
-pub mod property_serialization;
+pub mod item_settings;
+pub mod item_stats;
+pub mod journal;
+pub mod part_checksums;
+pub mod property_index;
+pub mod property_names;
+pub mod publish_groups;
+pub mod read_sources;
+#[cfg(test)]
+mod read_while_write_tests;
 pub mod shared_file;
+pub mod space_usage;
+pub mod storage;
+pub mod store_format;
+pub mod stream_phase;
+pub mod sync_points;
+pub mod transforms;
+pub mod version_tags;
+pub mod write_queue;
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+/// Most byte ranges remembered per version, manifests asking for other part counts push
+/// out the oldest ones
+const MAX_PARTS: usize = 1024;
+
+/// Checksum of a byte range of a committed data file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartChecksum {
+    pub start: u64,
+    pub length: u64,
+    pub sha256: String,
+}
+
+/// Checksums of byte ranges of a committed version, computed for its download manifests
+/// and stored next to its metadata as `{item_id}_{version}.parts.json`, so asking for
+/// the same manifest again does not read the data file.
+///
+/// Checksums of another generation of the version, or of a data file of another size, are
+/// stale and ignored.
+#[derive(Serialize, Deserialize)]
+pub struct PartChecksums {
+    pub epoch: u64,
+    pub size: u64,
+    /// Of the whole data file, kept for versions committed without one
+    pub sha256: Option<String>,
+    pub parts: Vec<PartChecksum>,
+}
+
+impl PartChecksums {
+    pub fn new(epoch: u64, size: u64) -> Self {
+        Self {
+            epoch,
+            size,
+            sha256: None,
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn describes(&self, epoch: u64, size: u64) -> bool {
+        self.epoch == epoch && self.size == size
+    }
+
+    /// The recorded checksum of `length` bytes from `start`
+    pub fn find(&self, start: u64, length: u64) -> Option<&str> {
+        self.parts
+            .iter()
+            .find(|part| part.start == start && part.length == length)
+            .map(|part| part.sha256.as_str())
+    }
+
+    pub fn insert(&mut self, part: PartChecksum) {
+        if self.find(part.start, part.length).is_some() {
+            return;
+        }
+        if self.parts.len() >= MAX_PARTS {
+            self.parts.remove(0);
+        }
+        self.parts.push(part);
+    }
+
+    /// Load the checksums, returns `None` when none were computed
+    pub fn load(path: &str) -> Result<Option<Self>, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|error| format!("Part checksums are corrupt: {error}")),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(format!("Part checksums read error: {error}")),
+        }
+    }
+
+    /// Replace the checksums at `path`, readers never see partially written ones
+    pub fn store(&self, path: &str) -> Result<(), String> {
+        let temporary_path = format!("{path}.tmp");
+        let bytes = serde_json::to_vec(self).map_err(|error| error.to_string())?;
+        std::fs::write(&temporary_path, bytes)
+            .map_err(|error| format!("Part checksums write error: {error}"))?;
+        std::fs::rename(&temporary_path, path)
+            .map_err(|error| format!("Part checksums write error: {error}"))
+    }
+}
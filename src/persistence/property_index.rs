@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Location of one property element within a stored data file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PropertyIndexEntry {
+    /// Offset of the element's `<` in the data file, including any envelope bytes
+    pub offset: u64,
+    /// Length of the element in bytes
+    pub length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Declared `type` of the property, only recorded when its value matched it
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+}
+
+/// Per-version index of property elements, built while writing and stored next to the
+/// data file as JSON lines, one entry per property in file order.
+#[derive(Default, Clone)]
+pub struct PropertyIndex {
+    pub entries: Vec<PropertyIndexEntry>,
+}
+
+impl PropertyIndex {
+    pub fn push(&mut self, entry: PropertyIndexEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Byte offset at which property `property_index` (0-based) starts
+    pub fn offset_of(&self, property_index: u64) -> Option<u64> {
+        self.entries
+            .get(usize::try_from(property_index).ok()?)
+            .map(|entry| entry.offset)
+    }
+
+    /// Plan the rewrite of the `size` bytes of data this index describes into `order`
+    /// (positions into the index), cutting out the properties it leaves out. Every place
+    /// of a property kept, in file order, takes the next property of `order`, and the
+    /// bytes around the elements, such as whitespace or an envelope, stay where they are.
+    /// Returns the index of the rewritten data and the byte ranges `(offset, length)` of
+    /// the original data it is made of, in the order they are copied.
+    pub fn rewrite(&self, order: &[usize], size: u64) -> Result<(Self, Vec<(u64, u64)>), String> {
+        let mut is_kept = vec![false; self.entries.len()];
+        for &position in order {
+            let is_kept = is_kept
+                .get_mut(position)
+                .ok_or("Rewriting lists an unknown property")?;
+            if std::mem::replace(is_kept, true) {
+                return Err("Rewriting lists a property twice".to_string());
+            }
+        }
+
+        let mut entries = Vec::with_capacity(order.len());
+        let mut ranges = Vec::new();
+        let mut written = 0u64;
+        let mut cursor = 0u64;
+        for (slot, is_kept) in self.entries.iter().zip(is_kept) {
+            if slot.offset < cursor || slot.offset + slot.length > size {
+                return Err("Property index does not match the data".to_string());
+            }
+            push_range(&mut ranges, cursor, slot.offset - cursor);
+            written += slot.offset - cursor;
+            cursor = slot.offset + slot.length;
+            if !is_kept {
+                continue;
+            }
+            let moved = &self.entries[order[entries.len()]];
+            entries.push(PropertyIndexEntry {
+                offset: written,
+                ..moved.clone()
+            });
+            push_range(&mut ranges, moved.offset, moved.length);
+            written += moved.length;
+        }
+        push_range(&mut ranges, cursor, size - cursor);
+        Ok((Self { entries }, ranges))
+    }
+
+    /// Load an index, returns `None` when the version was stored without one
+    pub fn load(path: &str) -> Result<Option<Self>, String> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(format!("Property index open error: {error}")),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|error| format!("Property index read error: {error}"))?;
+            if line.is_empty() {
+                continue;
+            }
+            entries.push(
+                serde_json::from_str(&line)
+                    .map_err(|error| format!("Property index is corrupt: {error}"))?,
+            );
+        }
+        Ok(Some(Self { entries }))
+    }
+
+    /// Write the index to `path`, synced to disk before returning
+    pub fn store(&self, path: &str) -> Result<(), String> {
+        let file = std::fs::File::create(path)
+            .map_err(|error| format!("Property index create error: {error}"))?;
+        let mut writer = BufWriter::new(file);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry).map_err(|error| error.to_string())?;
+            writer
+                .write_all(b"\n")
+                .map_err(|error| format!("Property index write error: {error}"))?;
+        }
+        let file = writer
+            .into_inner()
+            .map_err(|error| format!("Property index write error: {error}"))?;
+        file.sync_all()
+            .map_err(|error| format!("Property index sync error: {error}"))
+    }
+}
+
+/// Append `(offset, length)` to `ranges`, merged into the last one when it continues it
+fn push_range(ranges: &mut Vec<(u64, u64)>, offset: u64, length: u64) {
+    match ranges.last_mut() {
+        _ if length == 0 => {}
+        Some((last_offset, last_length)) if *last_offset + *last_length == offset => {
+            *last_length += length
+        }
+        _ => ranges.push((offset, length)),
+    }
+}
@@ -0,0 +1,219 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::time::SystemTime;
+
+/// One change to the property name index, a line of `.property-names.jsonl`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum NameIndexRecord {
+    /// The committed version holds properties with these names
+    Set {
+        item_id: String,
+        version: u64,
+        names: Vec<String>,
+    },
+    /// The version is no longer committed
+    Remove { item_id: String, version: u64 },
+}
+
+/// Committed versions by the names of the properties they hold, across all items. Every
+/// committed version is listed, those without named properties too, so the latest
+/// version of an item is known without reading its metadata.
+#[derive(Default)]
+pub struct PropertyNameIndex {
+    by_name: BTreeMap<String, BTreeSet<(String, u64)>>,
+    by_version: BTreeMap<(String, u64), Vec<String>>,
+    /// Lines in the log file, which is compacted once they far outnumber the versions
+    records: u64,
+}
+
+impl PropertyNameIndex {
+    pub fn apply(&mut self, record: NameIndexRecord) {
+        self.records += 1;
+        match record {
+            NameIndexRecord::Set {
+                item_id,
+                version,
+                names,
+            } => {
+                self.remove(&item_id, version);
+                for name in &names {
+                    self.by_name
+                        .entry(name.clone())
+                        .or_default()
+                        .insert((item_id.clone(), version));
+                }
+                self.by_version.insert((item_id, version), names);
+            }
+            NameIndexRecord::Remove { item_id, version } => self.remove(&item_id, version),
+        }
+    }
+
+    fn remove(&mut self, item_id: &str, version: u64) {
+        let key = (item_id.to_string(), version);
+        let Some(names) = self.by_version.remove(&key) else {
+            return;
+        };
+        for name in names {
+            if let Some(versions) = self.by_name.get_mut(&name) {
+                versions.remove(&key);
+                if versions.is_empty() {
+                    self.by_name.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Names recorded for a version, `None` if it is not indexed
+    pub fn names_of(&self, item_id: &str, version: u64) -> Option<&[String]> {
+        self.by_version
+            .get(&(item_id.to_string(), version))
+            .map(Vec::as_slice)
+    }
+
+    /// Versions holding a property called `name`, ordered by item and version, starting
+    /// after `after`
+    pub fn versions_with<'a>(
+        &'a self,
+        name: &str,
+        after: Option<&(String, u64)>,
+    ) -> impl Iterator<Item = &'a (String, u64)> + 'a {
+        let lower = match after {
+            Some(after) => Bound::Excluded(after.clone()),
+            None => Bound::Unbounded,
+        };
+        self.by_name
+            .get(name)
+            .into_iter()
+            .flat_map(move |versions| versions.range((lower.clone(), Bound::Unbounded)))
+    }
+
+    /// Highest committed version of an item
+    pub fn latest_version(&self, item_id: &str) -> Option<u64> {
+        self.by_version
+            .range((item_id.to_string(), 0)..=(item_id.to_string(), u64::MAX))
+            .next_back()
+            .map(|((_, version), _)| *version)
+    }
+
+    /// Names starting with `prefix` in order, each with the number of versions holding it
+    pub fn names_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, usize)> + 'a {
+        self.by_name
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(name, _)| name.starts_with(prefix))
+            .map(|(name, versions)| (name.as_str(), versions.len()))
+    }
+
+    pub fn version_count(&self) -> usize {
+        self.by_version.len()
+    }
+
+    pub fn name_count(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// Whether the log holds so many outdated lines that it is worth rewriting
+    pub fn needs_compaction(&self) -> bool {
+        self.records > 2 * self.by_version.len() as u64 + 1024
+    }
+}
+
+/// Length and modification time of the log, to tell whether another process changed it
+pub type LogStamp = (u64, SystemTime);
+
+fn log_path(storage: &Storage) -> String {
+    storage.path(".property-names.jsonl")
+}
+
+pub fn exists(storage: &Storage) -> bool {
+    std::path::Path::new(&log_path(storage)).exists()
+}
+
+pub fn stamp(storage: &Storage) -> Option<LogStamp> {
+    let metadata = std::fs::metadata(log_path(storage)).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Replay the log, also telling whether its last line was torn by a crash halfway
+/// through an append. That line is skipped, and has to be cut off by a [`rewrite`]
+/// before anything is appended. Anything else that does not parse means the index has to
+/// be rebuilt.
+pub fn load(storage: &Storage) -> Result<(PropertyNameIndex, bool), String> {
+    let mut index = PropertyNameIndex::default();
+    let mut torn = false;
+    let file = match std::fs::File::open(log_path(storage)) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok((index, torn)),
+        Err(error) => return Err(format!("Property name index open error: {error}")),
+    };
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line.map_err(|error| format!("Property name index read error: {error}"))?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => index.apply(record),
+            Err(_) if lines.peek().is_none() => {
+                println!("Skipped the torn last line of the property name index");
+                torn = true;
+            }
+            Err(error) => return Err(format!("Property name index is corrupt: {error}")),
+        }
+    }
+    Ok((index, torn))
+}
+
+/// Append `records` to the log, synced to disk before returning
+pub fn append(storage: &Storage, records: &[NameIndexRecord]) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(storage))
+        .map_err(|error| format!("Property name index open error: {error}"))?;
+    let mut lines = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut lines, record).map_err(|error| error.to_string())?;
+        lines.push(b'\n');
+    }
+    file.write_all(&lines)
+        .and_then(|_| file.sync_data())
+        .map_err(|error| format!("Property name index write error: {error}"))
+}
+
+/// Replace the log with one line per indexed version through a rename, so a crash never
+/// leaves a half written file behind. Resets the line count of `index`.
+pub fn rewrite(storage: &Storage, index: &mut PropertyNameIndex) -> Result<(), String> {
+    let path = log_path(storage);
+    let temporary_path = format!("{path}.tmp");
+    let file = std::fs::File::create(&temporary_path)
+        .map_err(|error| format!("Property name index write error: {error}"))?;
+    let mut writer = BufWriter::new(file);
+    for ((item_id, version), names) in &index.by_version {
+        let record = NameIndexRecord::Set {
+            item_id: item_id.clone(),
+            version: *version,
+            names: names.clone(),
+        };
+        serde_json::to_writer(&mut writer, &record).map_err(|error| error.to_string())?;
+        writer
+            .write_all(b"\n")
+            .map_err(|error| format!("Property name index write error: {error}"))?;
+    }
+    writer
+        .into_inner()
+        .map_err(|error| format!("Property name index write error: {error}"))?
+        .sync_all()
+        .map_err(|error| format!("Property name index sync error: {error}"))?;
+    std::fs::rename(&temporary_path, &path)
+        .map_err(|error| format!("Property name index write error: {error}"))?;
+    index.records = index.by_version.len() as u64;
+    Ok(())
+}
@@ -0,0 +1,565 @@
+use crate::persistence::file_persistence::{self, METADATA_LOCK_WAIT};
+use crate::persistence::io_failure::IoFailure;
+use crate::persistence::item_metadata::{ItemMetadata, VersionMetadata};
+use crate::persistence::storage::Storage;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::Duration;
+
+/// Directory in the data directory holding one directory per publish group
+const GROUPS_DIR: &str = ".publish-groups";
+/// The group's own record, in its directory
+const GROUP_FILE: &str = "group.json";
+/// Written once a promotion was decided, see [`promote`]
+const PROMOTION_JOURNAL: &str = "promoting.json";
+/// Staged metadata of a version is kept as `{item_id}_{version}.version.json`
+const STAGED_SUFFIX: &str = ".version.json";
+
+/// Uploads published together. Uploads sent with `X-Publish-Group` are staged: their
+/// data is written and synced into the data directory like any other, but their metadata
+/// is kept in the group's directory rather than the item's, so nothing reads or lists
+/// them until the group is promoted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PublishGroup {
+    pub id: String,
+    /// RFC 3339 timestamp in UTC
+    pub created_at: String,
+    /// RFC 3339 timestamp in UTC after which the group is aborted
+    pub expires_at: String,
+}
+
+/// A group and what was staged in it so far
+#[derive(Serialize)]
+pub struct PublishGroupStatus {
+    #[serde(flatten)]
+    pub group: PublishGroup,
+    pub staged: Vec<StagedVersion>,
+}
+
+/// A version staged in a group, or promoted or discarded with it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StagedVersion {
+    pub item_id: String,
+    pub version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// The decision to promote a group, the versions it lists are committed even if the
+/// promotion is cut short
+#[derive(Serialize, Deserialize)]
+struct Promotion {
+    /// Recorded as every promoted version's commit time
+    committed_at: String,
+    versions: Vec<StagedVersion>,
+}
+
+/// Why a group could not be staged into, promoted or aborted
+pub enum PublishGroupError {
+    /// No such group, or it expired
+    NotFound(String),
+    /// Another request holds the metadata of one of the group's items, try again
+    Locked(String),
+    /// A staged version can no longer be committed, or the group is being promoted
+    Conflict(String),
+    Failed(String),
+}
+
+impl From<String> for PublishGroupError {
+    fn from(error: String) -> Self {
+        Self::Failed(error)
+    }
+}
+
+impl PublishGroupError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::NotFound(message)
+            | Self::Locked(message)
+            | Self::Conflict(message)
+            | Self::Failed(message) => message,
+        }
+    }
+}
+
+fn groups_dir(storage: &Storage) -> String {
+    storage.path(GROUPS_DIR)
+}
+
+fn group_dir(storage: &Storage, group_id: &str) -> String {
+    format!("{}/{group_id}", groups_dir(storage))
+}
+
+fn staged_file_name(item_id: &str, item_version: u64) -> String {
+    format!("{item_id}_{item_version}{STAGED_SUFFIX}")
+}
+
+/// Group IDs are generated UUIDs, anything else names no group and never a path
+fn is_group_id(group_id: &str) -> bool {
+    group_id.len() == 36 && uuid::Uuid::parse_str(group_id).is_ok()
+}
+
+/// Replace `path` through a rename, so a crash leaves either the old or the new content
+fn write_atomically(path: &str, contents: &[u8]) -> Result<(), String> {
+    let temporary_path = format!("{path}.tmp");
+    File::create(&temporary_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary_path, path))
+        .map_err(|error| format!("Failed to write {path}: {error}"))
+}
+
+/// Renames and removals in `dir` are only durable once it is synced
+fn sync_dir(dir: &str) -> Result<(), String> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|error| format!("Failed to sync {dir}: {error}"))
+}
+
+fn is_expired(group: &PublishGroup) -> bool {
+    DateTime::parse_from_rfc3339(&group.expires_at).is_ok_and(|expires_at| expires_at < Utc::now())
+}
+
+/// Open a group accepting uploads for `ttl`
+pub fn create(storage: &Storage, ttl: Duration) -> Result<PublishGroup, String> {
+    let created_at = Utc::now();
+    let expires_at = created_at + chrono::Duration::from_std(ttl).map_err(|e| e.to_string())?;
+    let group = PublishGroup {
+        id: uuid::Uuid::new_v4().to_string(),
+        created_at: created_at.to_rfc3339(),
+        expires_at: expires_at.to_rfc3339(),
+    };
+    let dir = group_dir(storage, &group.id);
+    std::fs::create_dir_all(&dir)
+        .map_err(|error| format!("Failed to create publish group directory {dir}: {error}"))?;
+    let json = serde_json::to_vec_pretty(&group).map_err(|error| error.to_string())?;
+    write_atomically(&format!("{dir}/{GROUP_FILE}"), &json)?;
+    sync_dir(&groups_dir(storage))?;
+    Ok(group)
+}
+
+fn load_group(storage: &Storage, group_id: &str) -> Result<Option<PublishGroup>, String> {
+    if !is_group_id(group_id) {
+        return Ok(None);
+    }
+    let path = format!("{}/{GROUP_FILE}", group_dir(storage, group_id));
+    match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|error| format!("Failed to parse {path}: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Failed to read {path}: {error}")),
+    }
+}
+
+fn load_promotion(dir: &str) -> Result<Option<Promotion>, String> {
+    let path = format!("{dir}/{PROMOTION_JOURNAL}");
+    match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|error| format!("Failed to parse {path}: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("Failed to read {path}: {error}")),
+    }
+}
+
+/// The group `group_id` if it exists and did not expire yet
+fn open_group(storage: &Storage, group_id: &str) -> Result<PublishGroup, PublishGroupError> {
+    match load_group(storage, group_id)? {
+        Some(group) if is_expired(&group) => Err(PublishGroupError::NotFound(format!(
+            "Publish group {group_id} expired at {}",
+            group.expires_at
+        ))),
+        Some(group) => Ok(group),
+        None => Err(PublishGroupError::NotFound(format!(
+            "Publish group {group_id} not found"
+        ))),
+    }
+}
+
+/// The metadata of the versions staged in a group's directory, by item ID
+fn staged_versions(dir: &str) -> Result<BTreeMap<String, VersionMetadata>, String> {
+    let mut staged = BTreeMap::new();
+    let entries =
+        std::fs::read_dir(dir).map_err(|error| format!("Failed to list {dir}: {error}"))?;
+    for entry in entries {
+        let entry = entry.map_err(|error| format!("Failed to list {dir}: {error}"))?;
+        let file_name = entry.file_name();
+        let Some(item_id) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(STAGED_SUFFIX))
+            .and_then(|name| name.rsplit_once('_'))
+            .map(|(item_id, _)| item_id.to_string())
+        else {
+            continue;
+        };
+        let path = entry.path();
+        let version: VersionMetadata = std::fs::read(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|json| serde_json::from_slice(&json).map_err(|error| error.to_string()))
+            .map_err(|error| format!("Failed to read {}: {error}", path.display()))?;
+        staged.insert(item_id, version);
+    }
+    Ok(staged)
+}
+
+fn staged_list(staged: &BTreeMap<String, VersionMetadata>) -> Vec<StagedVersion> {
+    staged
+        .iter()
+        .map(|(item_id, version)| StagedVersion {
+            item_id: item_id.clone(),
+            version: version.version,
+            size: version.size,
+        })
+        .collect()
+}
+
+/// IDs of the groups in the data directory, none before the first was created
+fn group_ids(storage: &Storage) -> Result<Vec<String>, String> {
+    let dir = groups_dir(storage);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(format!("Failed to list {dir}: {error}")),
+    };
+    let mut group_ids = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|error| format!("Failed to list {dir}: {error}"))?;
+        if let Some(group_id) = entry.file_name().to_str().filter(|name| is_group_id(name)) {
+            group_ids.push(group_id.to_string());
+        }
+    }
+    group_ids.sort();
+    Ok(group_ids)
+}
+
+/// A group and the versions staged in it
+pub fn status(storage: &Storage, group_id: &str) -> Result<PublishGroupStatus, PublishGroupError> {
+    let group = open_group(storage, group_id)?;
+    let staged = staged_versions(&group_dir(storage, group_id))?;
+    Ok(PublishGroupStatus {
+        group,
+        staged: staged_list(&staged),
+    })
+}
+
+/// The group and version `item_id` has staged, if any. Uploads of the item are refused
+/// meanwhile, they would be validated against metadata the staged version is not in yet.
+pub(crate) fn staged_in(storage: &Storage, item_id: &str) -> Result<Option<(String, u64)>, String> {
+    for group_id in group_ids(storage)? {
+        let dir = group_dir(storage, &group_id);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // Promoted or aborted meanwhile
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(format!("Failed to list {dir}: {error}")),
+        };
+        for entry in entries.flatten() {
+            if let Some(version) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(STAGED_SUFFIX))
+                .and_then(|name| name.rsplit_once('_'))
+                .filter(|(staged_item_id, _)| *staged_item_id == item_id)
+                .and_then(|(_, version)| version.parse().ok())
+            {
+                return Ok(Some((group_id, version)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Check `group_id` accepts uploads, before an upload to it starts
+pub(crate) fn check_open(storage: &Storage, group_id: &str) -> Result<(), PublishGroupError> {
+    let _publishing = storage.publishing_lock.lock().unwrap();
+    open_group(storage, group_id)?;
+    if load_promotion(&group_dir(storage, group_id))?.is_some() {
+        return Err(PublishGroupError::Conflict(format!(
+            "Publish group {group_id} is being committed"
+        )));
+    }
+    Ok(())
+}
+
+/// Stage `version` of `item_id` in the group instead of committing it, its data file
+/// already synced in the data directory
+pub(crate) fn stage(
+    storage: &Storage,
+    group_id: &str,
+    item_id: &str,
+    version: &VersionMetadata,
+) -> Result<(), String> {
+    let _publishing = storage.publishing_lock.lock().unwrap();
+    // The group may have been committed, aborted or expired during the upload
+    open_group(storage, group_id).map_err(|error| error.message().to_string())?;
+    let dir = group_dir(storage, group_id);
+    if load_promotion(&dir)?.is_some() {
+        return Err(format!("Publish group {group_id} is being committed"));
+    }
+    // Only the first of several uploads of the item running at once gets to stage
+    if let Some((staged_group_id, staged_version)) = staged_in(storage, item_id)? {
+        return Err(format!(
+            "Version {staged_version} of the item was staged in publish group {staged_group_id} meanwhile"
+        ));
+    }
+    let json = serde_json::to_vec_pretty(version).map_err(|error| error.to_string())?;
+    write_atomically(
+        &format!("{dir}/{}", staged_file_name(item_id, version.version)),
+        &json,
+    )?;
+    sync_dir(&dir)
+}
+
+/// Open and lock the metadata of every item, in the order of their IDs so concurrent
+/// promotions cannot deadlock. The locks are held until the files are dropped.
+fn lock_items<'a>(
+    storage: &Storage,
+    item_ids: impl Iterator<Item = &'a String>,
+) -> Result<Vec<(File, ItemMetadata)>, PublishGroupError> {
+    let mut locked = Vec::new();
+    for item_id in item_ids {
+        let metadata_path = file_persistence::metadata_path(storage, item_id);
+        let mut metadata_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&metadata_path)
+            .map_err(|error| {
+                String::from(IoFailure::new("open metadata", &metadata_path, &error))
+            })?;
+        storage.item_filter.insert(item_id);
+        if !file_persistence::lock_metadata(
+            &mut metadata_file,
+            &metadata_path,
+            Some(METADATA_LOCK_WAIT),
+        )? {
+            return Err(PublishGroupError::Locked(format!(
+                "Metadata of item {item_id} is being updated by another request, try again"
+            )));
+        }
+        let metadata = file_persistence::read_metadata(&mut metadata_file, &metadata_path)?;
+        locked.push((metadata_file, metadata));
+    }
+    Ok(locked)
+}
+
+/// Commit every version the promotion lists that the item's metadata does not list yet,
+/// holding the locks of all items, then remove the group. Running it again after it was
+/// cut short commits the rest.
+fn finish_promotion(
+    storage: &Storage,
+    dir: &str,
+    promotion: &Promotion,
+    staged: &BTreeMap<String, VersionMetadata>,
+    locked: Vec<(File, ItemMetadata)>,
+) -> Result<Vec<(String, VersionMetadata)>, String> {
+    let mut promoted = Vec::new();
+    for (entry, (mut metadata_file, mut metadata)) in promotion.versions.iter().zip(locked) {
+        if let Some(committed) = metadata.versions.get(&entry.version) {
+            // Committed before the promotion was cut short
+            promoted.push((entry.item_id.clone(), committed.clone()));
+            continue;
+        }
+        let Some(version) = staged.get(&entry.item_id) else {
+            return Err(format!(
+                "Staged metadata of item {} version {} is missing",
+                entry.item_id, entry.version
+            ));
+        };
+        let mut version = version.clone();
+        version.committed_at = Some(promotion.committed_at.clone());
+        version.first_version = Some(metadata.versions.is_empty());
+        metadata.add_committed(version.clone());
+        // Moved into place when it was staged
+        let data_path = file_persistence::data_path(storage, &entry.item_id, entry.version);
+        file_persistence::commit_durably(
+            storage,
+            &entry.item_id,
+            entry.version,
+            &data_path,
+            &data_path,
+            || {
+                file_persistence::rewrite_metadata(
+                    storage,
+                    &entry.item_id,
+                    &mut metadata_file,
+                    &metadata,
+                )
+            },
+        )
+        .map_err(|failure| failure.error)?;
+        storage.usage.commit(version.size.unwrap_or(0));
+        promoted.push((entry.item_id.clone(), version));
+    }
+    // The journal goes last, a group without one was not promoted
+    for file_name in staged
+        .iter()
+        .map(|(item_id, version)| staged_file_name(item_id, version.version))
+        .chain([GROUP_FILE.to_string(), PROMOTION_JOURNAL.to_string()])
+    {
+        let path = format!("{dir}/{file_name}");
+        if let Err(error) = std::fs::remove_file(&path)
+            && error.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(format!("Failed to remove {path}: {error}"));
+        }
+    }
+    std::fs::remove_dir(dir).map_err(|error| format!("Failed to remove {dir}: {error}"))?;
+    Ok(promoted)
+}
+
+/// Commit every version staged in the group at once. All items are locked first, in the
+/// order of their IDs, and every staged version is checked to still be newer than its
+/// item's latest one; a group failing that is aborted. The promotion is then recorded in
+/// the group's journal before the first metadata is rewritten, so a crash part way is
+/// finished by [`recover`] and readers see either none or all of the group's versions.
+pub fn promote(
+    storage: &Storage,
+    group_id: &str,
+) -> Result<Vec<(String, VersionMetadata)>, PublishGroupError> {
+    let _publishing = storage.publishing_lock.lock().unwrap();
+    let dir = group_dir(storage, group_id);
+    let group = load_group(storage, group_id)?.ok_or_else(|| {
+        PublishGroupError::NotFound(format!("Publish group {group_id} not found"))
+    })?;
+    let staged = staged_versions(&dir)?;
+    if let Some(promotion) = load_promotion(&dir)? {
+        // A promotion that failed part way, which can only be finished
+        let locked = lock_items(
+            storage,
+            promotion.versions.iter().map(|entry| &entry.item_id),
+        )?;
+        return Ok(finish_promotion(
+            storage, &dir, &promotion, &staged, locked,
+        )?);
+    }
+    if is_expired(&group) {
+        discard(storage, &dir)?;
+        return Err(PublishGroupError::NotFound(format!(
+            "Publish group {group_id} expired at {}, its uploads were discarded",
+            group.expires_at
+        )));
+    }
+
+    let locked = lock_items(storage, staged.keys())?;
+    let conflict = staged
+        .iter()
+        .zip(&locked)
+        .find_map(|((item_id, version), (_, metadata))| {
+            let latest = metadata.latest_version?;
+            (version.version <= latest).then(|| {
+                format!(
+                    "Staged version {} of item {item_id} is no longer newer than its latest version {latest}",
+                    version.version
+                )
+            })
+        });
+    if let Some(conflict) = conflict {
+        drop(locked);
+        discard(storage, &dir)?;
+        return Err(PublishGroupError::Conflict(format!(
+            "{conflict}, publish group {group_id} was aborted"
+        )));
+    }
+
+    let promotion = Promotion {
+        committed_at: Utc::now().to_rfc3339(),
+        versions: staged_list(&staged),
+    };
+    let json = serde_json::to_vec_pretty(&promotion).map_err(|error| error.to_string())?;
+    write_atomically(&format!("{dir}/{PROMOTION_JOURNAL}"), &json)?;
+    sync_dir(&dir)?;
+    Ok(finish_promotion(
+        storage, &dir, &promotion, &staged, locked,
+    )?)
+}
+
+/// Discard the group and every version staged in it, returning what it had staged
+pub fn abort(storage: &Storage, group_id: &str) -> Result<PublishGroupStatus, PublishGroupError> {
+    let _publishing = storage.publishing_lock.lock().unwrap();
+    let group = load_group(storage, group_id)?.ok_or_else(|| {
+        PublishGroupError::NotFound(format!("Publish group {group_id} not found"))
+    })?;
+    let dir = group_dir(storage, group_id);
+    if load_promotion(&dir)?.is_some() {
+        return Err(PublishGroupError::Conflict(format!(
+            "Publish group {group_id} is being committed, commit it again to finish"
+        )));
+    }
+    let staged = discard(storage, &dir)?;
+    Ok(PublishGroupStatus { group, staged })
+}
+
+/// Remove a group's directory and the data of the versions staged in it
+fn discard(storage: &Storage, dir: &str) -> Result<Vec<StagedVersion>, String> {
+    let staged = staged_versions(dir)?;
+    for (item_id, version) in &staged {
+        file_persistence::remove_staged_files(storage, item_id, version.version);
+        storage.usage.remove_in_flight(version.size.unwrap_or(0));
+    }
+    std::fs::remove_dir_all(dir).map_err(|error| format!("Failed to remove {dir}: {error}"))?;
+    Ok(staged_list(&staged))
+}
+
+/// Abort the groups whose time ran out, returning what they had staged
+pub fn abort_expired(storage: &Storage) -> Result<Vec<PublishGroupStatus>, String> {
+    let _publishing = storage.publishing_lock.lock().unwrap();
+    let mut aborted = Vec::new();
+    for group_id in group_ids(storage)? {
+        let dir = group_dir(storage, &group_id);
+        let Some(group) = load_group(storage, &group_id)?.filter(is_expired) else {
+            continue;
+        };
+        // Past the point of no return, only a commit finishes it
+        if load_promotion(&dir)?.is_some() {
+            continue;
+        }
+        let staged = discard(storage, &dir)?;
+        aborted.push(PublishGroupStatus { group, staged });
+    }
+    Ok(aborted)
+}
+
+/// Settle the groups a previous process left behind, before interrupted uploads are
+/// looked for. A promotion that was cut short is finished; groups that were never
+/// promoted stay open with their staged versions, which are returned so they are not
+/// taken for interrupted uploads. A group whose record was never written is removed.
+pub fn recover(storage: &Storage) -> Result<HashSet<(String, u64)>, String> {
+    let mut staged_versions_found = HashSet::new();
+    for group_id in group_ids(storage)? {
+        let dir = group_dir(storage, &group_id);
+        let staged = staged_versions(&dir)?;
+        if let Some(promotion) = load_promotion(&dir)? {
+            let locked = lock_items(
+                storage,
+                promotion.versions.iter().map(|entry| &entry.item_id),
+            )
+            .map_err(|error| error.message().to_string())?;
+            let promoted = finish_promotion(storage, &dir, &promotion, &staged, locked)?;
+            println!(
+                "Finished the interrupted commit of publish group {group_id}, {} versions",
+                promoted.len()
+            );
+            continue;
+        }
+        if load_group(storage, &group_id)?.is_none() {
+            println!("Removing publish group {group_id}, it was never fully created");
+            std::fs::remove_dir_all(&dir)
+                .map_err(|error| format!("Failed to remove {dir}: {error}"))?;
+            continue;
+        }
+        for (item_id, version) in staged {
+            storage.usage.add_in_flight(version.size.unwrap_or(0));
+            staged_versions_found.insert((item_id, version.version));
+        }
+    }
+    Ok(staged_versions_found)
+}
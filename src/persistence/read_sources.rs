@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Weight of the newest sample in a source's latency and error rate
+const SMOOTHING: f64 = 0.2;
+
+/// Error rate at which a source is taken as unhealthy ...
+const UNHEALTHY_ERROR_RATE: f64 = 0.5;
+
+/// ... and at which it is healthy again, lower so a source does not flap between both
+const HEALTHY_ERROR_RATE: f64 = 0.2;
+
+/// An unhealthy source is tried in its usual place again this long after it last
+/// failed, which is the only way its error rate comes down
+const PROBE_AFTER: Duration = Duration::from_secs(30);
+
+/// Where a reader may find a version
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadSource {
+    /// Versions this instance has open: uploads in flight and versions read before
+    Registry,
+    /// The version's data file in the data directory
+    Hot,
+    /// The version's data file in the cold tier
+    Cold,
+}
+
+impl ReadSource {
+    const ALL: [ReadSource; 3] = [Self::Registry, Self::Hot, Self::Cold];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "registry" => Ok(Self::Registry),
+            "hot" => Ok(Self::Hot),
+            "cold" => Ok(Self::Cold),
+            other => Err(format!("Unknown read source {other:?}")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Registry => "registry",
+            Self::Hot => "hot",
+            Self::Cold => "cold",
+        }
+    }
+
+    /// Sources in the order of a comma separated list, each at most once. The registry
+    /// has to be among them, nothing else can serve uploads in flight.
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, String> {
+        let mut sources = Vec::new();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let source = Self::parse(name)?;
+            if sources.contains(&source) {
+                return Err(format!("Read source {name:?} is listed twice"));
+            }
+            sources.push(source);
+        }
+        if !sources.contains(&Self::Registry) {
+            return Err("The read sources must include registry".to_string());
+        }
+        Ok(sources)
+    }
+
+    fn index(&self) -> usize {
+        Self::ALL
+            .iter()
+            .position(|source| source == self)
+            .expect("every source is listed")
+    }
+}
+
+/// How the sources of a read are ordered
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SourcePolicy {
+    /// Always in the configured order
+    Ordered,
+    /// Healthy sources first, the fastest of them first, then unhealthy ones in the
+    /// configured order
+    FastestHealthy,
+}
+
+impl SourcePolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "ordered" => Ok(Self::Ordered),
+            "fastest_healthy" => Ok(Self::FastestHealthy),
+            other => Err(format!("Unknown read source policy {other:?}")),
+        }
+    }
+}
+
+/// Recent opens of one source
+#[derive(Default)]
+struct Health {
+    /// Smoothed milliseconds an open took, none before the first
+    latency_ms: Option<f64>,
+    /// Smoothed share of opens that failed
+    error_rate: f64,
+    unhealthy: bool,
+    last_failure: Option<Instant>,
+    served: u64,
+    failed: u64,
+}
+
+impl Health {
+    fn record(&mut self, elapsed: Duration, failed: bool) {
+        let latency_ms = elapsed.as_secs_f64() * 1000.0;
+        self.latency_ms = Some(match self.latency_ms {
+            Some(average) => average + SMOOTHING * (latency_ms - average),
+            None => latency_ms,
+        });
+        let sample = if failed { 1.0 } else { 0.0 };
+        self.error_rate += SMOOTHING * (sample - self.error_rate);
+        if failed {
+            self.failed += 1;
+            self.last_failure = Some(Instant::now());
+        } else {
+            self.served += 1;
+        }
+        if self.error_rate >= UNHEALTHY_ERROR_RATE {
+            self.unhealthy = true;
+        } else if self.error_rate <= HEALTHY_ERROR_RATE {
+            self.unhealthy = false;
+        }
+    }
+
+    /// Unhealthy and not due to be probed again
+    fn is_shunned(&self) -> bool {
+        self.unhealthy
+            && self
+                .last_failure
+                .is_some_and(|last_failure| last_failure.elapsed() < PROBE_AFTER)
+    }
+
+    /// Sources within the same power of two of milliseconds are taken as equally fast,
+    /// so two similar sources keep their configured order instead of trading places on
+    /// every sample
+    fn speed_class(&self) -> u32 {
+        let latency_ms = self.latency_ms.unwrap_or(0.0) as u64;
+        u64::BITS - latency_ms.leading_zeros()
+    }
+}
+
+/// How one source is doing, for `GET /admin/storage`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SourceHealthReport {
+    pub source: ReadSource,
+    pub healthy: bool,
+    pub latency_ms: Option<f64>,
+    pub error_rate: f64,
+    /// Reads the source served, and opens that failed on it
+    pub served: u64,
+    pub failed: u64,
+}
+
+/// The read sources of an instance and their health, for `GET /admin/storage`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadSourceReport {
+    pub policy: SourcePolicy,
+    pub budget_ms: u64,
+    /// The order the next read tries the sources in
+    pub order: Vec<ReadSource>,
+    pub sources: Vec<SourceHealthReport>,
+}
+
+/// Which sources a read tries, in which order, and how long it may spend on them, see
+/// [`crate::persistence::file_persistence::FileReader::new`]
+pub struct ReadSources {
+    order: Vec<ReadSource>,
+    policy: SourcePolicy,
+    budget: Duration,
+    /// Where versions of the hot tier are looked for in the cold tier
+    cold_dir: Option<String>,
+    health: [Mutex<Health>; 3],
+}
+
+impl Default for ReadSources {
+    fn default() -> Self {
+        Self::new(
+            ReadSource::ALL.to_vec(),
+            SourcePolicy::Ordered,
+            Duration::from_secs(5),
+            None,
+        )
+    }
+}
+
+impl ReadSources {
+    pub fn new(
+        order: Vec<ReadSource>,
+        policy: SourcePolicy,
+        budget: Duration,
+        cold_dir: Option<String>,
+    ) -> Self {
+        Self {
+            order,
+            policy,
+            budget,
+            cold_dir,
+            health: Default::default(),
+        }
+    }
+
+    /// How long a read may spend finding a source that serves it
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn cold_dir(&self) -> Option<&str> {
+        self.cold_dir.as_deref()
+    }
+
+    /// The sources the next read tries, in the order it tries them
+    pub fn resolution_order(&self) -> Vec<ReadSource> {
+        let mut order = self.order.clone();
+        if self.policy == SourcePolicy::FastestHealthy {
+            let health: Vec<(bool, u32)> = order
+                .iter()
+                .map(|source| {
+                    let health = self.health[source.index()].lock().unwrap();
+                    (health.is_shunned(), health.speed_class())
+                })
+                .collect();
+            let mut ranked: Vec<(usize, ReadSource)> = order.into_iter().enumerate().collect();
+            // Stable, so equals keep the configured order
+            ranked.sort_by_key(|(position, _)| {
+                let (shunned, speed_class) = health[*position];
+                (shunned, if shunned { 0 } else { speed_class })
+            });
+            order = ranked.into_iter().map(|(_, source)| source).collect();
+        }
+        order
+    }
+
+    /// `source` served a read, finding it took `elapsed`
+    pub fn record_served(&self, source: ReadSource, elapsed: Duration) {
+        self.health[source.index()]
+            .lock()
+            .unwrap()
+            .record(elapsed, false);
+    }
+
+    /// Opening a version from `source` failed after `elapsed`
+    pub fn record_failure(&self, source: ReadSource, elapsed: Duration) {
+        self.health[source.index()]
+            .lock()
+            .unwrap()
+            .record(elapsed, true);
+    }
+
+    pub fn report(&self) -> ReadSourceReport {
+        ReadSourceReport {
+            policy: self.policy,
+            budget_ms: self.budget.as_millis() as u64,
+            order: self.resolution_order(),
+            sources: self
+                .order
+                .iter()
+                .map(|source| {
+                    let health = self.health[source.index()].lock().unwrap();
+                    SourceHealthReport {
+                        source: *source,
+                        healthy: !health.unhealthy,
+                        latency_ms: health.latency_ms,
+                        error_rate: health.error_rate,
+                        served: health.served,
+                        failed: health.failed,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
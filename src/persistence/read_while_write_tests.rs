@@ -0,0 +1,456 @@
+//! Interleavings of readers following an upload with the upload's writer, deletes and
+//! kills, each played step by step through [`sync_points`]
+
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::deferred_deletes;
+use crate::persistence::fault_injection::FaultInjector;
+use crate::persistence::file_persistence::tests::TestItem;
+use crate::persistence::file_persistence::{
+    self, FileReader, FileWriter, KillOutcome, OpenError, ReadDurability, data_file_name,
+    metadata_path,
+};
+use crate::persistence::item_persistence::{CommitDetails, ItemStreamReader, ItemStreamWriter};
+use crate::persistence::sync_points::{self, SyncPoint};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+fn writer(item: &TestItem, version: u64) -> FileWriter {
+    let cancellation = Cancellation::new(item.storage().metrics.clone());
+    FileWriter::new(
+        item.storage(),
+        &item.id,
+        &version,
+        None,
+        None,
+        None,
+        &cancellation,
+    )
+    .unwrap_or_else(|error| panic!("{}", error.message()))
+}
+
+/// Start opening a reader on a blocking thread, as opening may be held at a sync point
+fn spawn_open(
+    item: &TestItem,
+    version: u64,
+) -> tokio::task::JoinHandle<Result<FileReader, OpenError>> {
+    let storage = item.storage().clone();
+    let item_id = item.id.clone();
+    tokio::task::spawn_blocking(move || {
+        FileReader::new(
+            &storage,
+            &FaultInjector::new(false),
+            item_id,
+            version,
+            ReadDurability::Written,
+        )
+    })
+}
+
+async fn open(item: &TestItem, version: u64) -> Result<FileReader, OpenError> {
+    spawn_open(item, version).await.unwrap()
+}
+
+async fn reader(item: &TestItem, version: u64) -> FileReader {
+    open(item, version)
+        .await
+        .unwrap_or_else(|_| panic!("version {version} could not be opened"))
+}
+
+/// Hold the next task passing `point` for `version` of `item`
+fn pause(item: &TestItem, version: u64, point: SyncPoint) -> sync_points::Pause {
+    sync_points::pause(&metadata_path(item.storage(), &item.id), version, point)
+}
+
+/// Write and commit `version` with `bytes`
+async fn upload(item: &TestItem, version: u64, bytes: &[u8]) {
+    let mut writer = writer(item, version);
+    writer.write_chunk(bytes.to_vec()).await.unwrap();
+    writer.commit(&committed(bytes.len())).await.unwrap();
+}
+
+fn committed(bytes_received: usize) -> CommitDetails {
+    CommitDetails {
+        property_count: 0,
+        request_id: None,
+        bytes_received: bytes_received as u64,
+        extra_elements: Default::default(),
+        extra_elements_truncated: Default::default(),
+        provenance: Default::default(),
+        user_metadata: Default::default(),
+        immutable_until: None,
+        segments: None,
+        transforms: Vec::new(),
+    }
+}
+
+/// Everything `reader` reads until the version ends, failing the test if it takes longer
+/// than 2 seconds, long before the 30 second safety net of a missed wakeup would end a wait
+async fn read_to_end(reader: &mut FileReader) -> Result<Vec<u8>, String> {
+    let read = async {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = reader.read_chunk().await? {
+            bytes.extend(chunk);
+        }
+        Ok(bytes)
+    };
+    tokio::time::timeout(Duration::from_secs(2), read)
+        .await
+        .expect("the read did not end within 2 seconds")
+}
+
+async fn next_chunk(reader: &mut FileReader) -> Result<Option<Vec<u8>>, String> {
+    tokio::time::timeout(Duration::from_secs(2), reader.read_chunk())
+        .await
+        .expect("no chunk within 2 seconds")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reader_that_caught_up_is_woken_by_the_next_write() {
+    let item = TestItem::new("caught-up");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"first ".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+    assert_eq!(next_chunk(&mut reader).await.unwrap().unwrap(), b"first ");
+
+    let caught_up = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let read = tokio::spawn(async move { (next_chunk(&mut reader).await, reader) });
+    caught_up.arrived().await;
+    writer.write_chunk(b"second".to_vec()).await.unwrap();
+    caught_up.release();
+    let (chunk, mut reader) = read.await.unwrap();
+    assert_eq!(chunk.unwrap().unwrap(), b"second");
+
+    writer.commit(&committed(12)).await.unwrap();
+    assert_eq!(read_to_end(&mut reader).await.unwrap(), b"");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_commit_between_the_size_check_and_the_wait_ends_the_read() {
+    let item = TestItem::new("commit-before-wait");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"all of it".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+    assert_eq!(
+        next_chunk(&mut reader).await.unwrap().unwrap(),
+        b"all of it"
+    );
+
+    // The reader found nothing to read and the version unfinished, and is about to wait
+    let caught_up = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let read = tokio::spawn(async move { read_to_end(&mut reader).await });
+    caught_up.arrived().await;
+    writer.commit(&committed(9)).await.unwrap();
+    caught_up.release();
+    assert_eq!(read.await.unwrap().unwrap(), b"");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_commit_after_the_reader_loaded_the_size_is_read_to_its_end() {
+    let item = TestItem::new("commit-during-read");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"head ".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+
+    // The reader saw 5 bytes of an unfinished version, the rest and the commit land
+    // before it reads them
+    let loaded = pause(&item, 1, SyncPoint::ReaderLoadedState);
+    let read = tokio::spawn(async move { read_to_end(&mut reader).await });
+    loaded.arrived().await;
+    writer.write_chunk(b"tail".to_vec()).await.unwrap();
+    writer.commit(&committed(9)).await.unwrap();
+    loaded.release();
+    assert_eq!(read.await.unwrap().unwrap(), b"head tail");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_waiting_reader_is_failed_when_the_writer_aborts() {
+    let item = TestItem::new("abort");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"partial".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+    assert_eq!(next_chunk(&mut reader).await.unwrap().unwrap(), b"partial");
+
+    let caught_up = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let read = tokio::spawn(async move { read_to_end(&mut reader).await });
+    caught_up.arrived().await;
+    writer.abort();
+    caught_up.release();
+    let error = read.await.unwrap().unwrap_err();
+    assert!(error.contains("aborted"), "{error}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_kill_racing_a_commit_that_claimed_the_outcome_lets_it_commit() {
+    let item = TestItem::new("kill-after-claim");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"complete".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+
+    let committing = pause(&item, 1, SyncPoint::WriterCommitting);
+    let commit = tokio::spawn(async move { writer.commit(&committed(8)).await });
+    committing.arrived().await;
+    let report = file_persistence::kill_stream(item.storage(), &item.id, 1, CancelReason::Killed);
+    assert!(report.outcome == KillOutcome::AlreadyCommitted);
+    committing.release();
+    commit.await.unwrap().unwrap();
+    assert_eq!(read_to_end(&mut reader).await.unwrap(), b"complete");
+    assert_eq!(
+        read_to_end(&mut self::reader(&item, 1).await)
+            .await
+            .unwrap(),
+        b"complete"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_commit_after_a_kill_is_refused() {
+    let item = TestItem::new("kill-before-claim");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"complete".to_vec()).await.unwrap();
+    let mut reader = reader(&item, 1).await;
+
+    let report = file_persistence::kill_stream(item.storage(), &item.id, 1, CancelReason::Killed);
+    assert!(report.outcome == KillOutcome::Killed);
+    assert!(writer.commit(&committed(8)).await.is_err());
+    assert!(read_to_end(&mut reader).await.is_err());
+    assert!(matches!(open(&item, 1).await, Err(OpenError::NotFound(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn two_readers_racing_the_finish_flag_both_read_everything() {
+    let item = TestItem::new("two-readers");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"one ".to_vec()).await.unwrap();
+    let first = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let second = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let mut reads = Vec::new();
+    for _ in 0..2 {
+        let mut reader = reader(&item, 1).await;
+        reads.push(tokio::spawn(async move { read_to_end(&mut reader).await }));
+    }
+    first.arrived().await;
+    second.arrived().await;
+
+    // Both wait with 4 bytes read, the last bytes and the finish flag arrive at once
+    let committing = pause(&item, 1, SyncPoint::WriterFinishing);
+    writer.write_chunk(b"two".to_vec()).await.unwrap();
+    let commit = tokio::spawn(async move { writer.commit(&committed(7)).await });
+    committing.arrived().await;
+    first.release();
+    committing.release();
+    second.release();
+    commit.await.unwrap().unwrap();
+    for read in reads {
+        assert_eq!(read.await.unwrap().unwrap(), b"one two");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reader_of_a_deleted_version_finishes_its_read() {
+    let item = TestItem::new("delete-during-read");
+    let body = vec![b'x'; 3 * item.storage().io_engine.read_chunk_size()];
+    upload(&item, 1, &body).await;
+    let mut reader = reader(&item, 1).await;
+    let first = next_chunk(&mut reader).await.unwrap().unwrap();
+
+    let report = file_persistence::delete_version(item.storage(), &item.id, 1)
+        .unwrap_or_else(|_| panic!("the version could not be deleted"));
+    assert_eq!(report.readers_attached, 1);
+    assert!(report.deferred);
+    assert!(matches!(open(&item, 1).await, Err(OpenError::Deleted(_))));
+    let rest = read_to_end(&mut reader).await.unwrap();
+    assert_eq!([first, rest].concat(), body);
+
+    // Its files go once the reader let go of them
+    assert_eq!(item.storage().deferred_deletes.remove_released(), 0);
+    drop(reader);
+    assert_eq!(item.storage().deferred_deletes.remove_released(), 1);
+    assert!(matches!(open(&item, 1).await, Err(OpenError::NotFound(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_version_deleted_under_a_reader_is_uploaded_again_without_waiting_for_it() {
+    let item = TestItem::new("delete-and-upload-again");
+    let storage = item.storage();
+    let old = vec![b'o'; 3 * storage.io_engine.read_chunk_size()];
+    upload(&item, 1, &old).await;
+    let mut old_reader = reader(&item, 1).await;
+    let first = next_chunk(&mut old_reader).await.unwrap().unwrap();
+
+    let report = file_persistence::delete_version(storage, &item.id, 1)
+        .unwrap_or_else(|_| panic!("the version could not be deleted"));
+    assert!(report.deferred);
+    let data_path = storage.path(&data_file_name(&item.id, 1));
+    let set_aside = deferred_deletes::set_aside_path(&data_path, report.epoch);
+    assert!(std::path::Path::new(&set_aside).exists());
+
+    // The new generation is written and read while the old reader is still attached
+    let new = vec![b'n'; 2 * storage.io_engine.read_chunk_size()];
+    upload(&item, 1, &new).await;
+    let mut new_reader = reader(&item, 1).await;
+    assert_eq!(read_to_end(&mut new_reader).await.unwrap(), new);
+    drop(new_reader);
+
+    // ... and the old reader still only receives the deleted one
+    let rest = read_to_end(&mut old_reader).await.unwrap();
+    assert_eq!([first, rest].concat(), old);
+    drop(old_reader);
+    assert_eq!(storage.deferred_deletes.remove_released(), 1);
+    assert!(!std::path::Path::new(&set_aside).exists());
+    assert_eq!(std::fs::read(&data_path).unwrap(), new);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_delete_racing_a_reader_opening_from_disk_leaves_no_stale_entry() {
+    let item = TestItem::new("delete-late-reader");
+    upload(&item, 1, b"deleted").await;
+    // As after a restart, the committed version is only on disk
+    let registered = item.storage().registry.get(&item.id, 1).unwrap();
+    item.storage().registry.remove(&item.id, 1, &registered);
+
+    // The reader found the version committed on disk and is about to register it
+    let registering = pause(&item, 1, SyncPoint::ReaderRegistering);
+    let opened = {
+        let storage = Arc::clone(item.storage());
+        let item_id = item.id.clone();
+        tokio::task::spawn_blocking(move || {
+            FileReader::new(
+                &storage,
+                &FaultInjector::new(false),
+                item_id,
+                1,
+                ReadDurability::Written,
+            )
+            .map(|_| ())
+        })
+    };
+    registering.arrived().await;
+    file_persistence::delete_version(item.storage(), &item.id, 1)
+        .unwrap_or_else(|_| panic!("the version could not be deleted"));
+    registering.release();
+    assert!(matches!(opened.await.unwrap(), Err(OpenError::NotFound(_))));
+    assert!(item.storage().registry.get(&item.id, 1).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reader_whose_entry_is_removed_before_it_attaches_reads_that_entry() {
+    let item = TestItem::new("remove-before-attach");
+    let storage = item.storage();
+    let body = vec![b'r'; 2 * storage.io_engine.read_chunk_size()];
+    upload(&item, 1, &body).await;
+
+    // The reader got the entry from the registry and is about to attach to it
+    let found = pause(&item, 1, SyncPoint::ReaderFoundRegistered);
+    let opened = spawn_open(&item, 1);
+    found.arrived().await;
+    // As a tier move does once the copy is recorded, the entry is dropped
+    let registered = storage.registry.get(&item.id, 1).unwrap();
+    assert!(storage.registry.remove(&item.id, 1, &registered));
+    found.release();
+    let mut attached = opened
+        .await
+        .unwrap()
+        .unwrap_or_else(|_| panic!("the version could not be opened"));
+    assert_eq!(read_to_end(&mut attached).await.unwrap(), body);
+
+    // The next reader opens the version from disk again, under an entry of its own
+    let mut reopened = reader(&item, 1).await;
+    let entry = storage.registry.get(&item.id, 1).unwrap();
+    assert!(!Arc::ptr_eq(&entry, &registered));
+    assert_eq!(read_to_end(&mut reopened).await.unwrap(), body);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_entry_found_by_a_reader_is_not_evicted_before_it_attaches() {
+    let item = TestItem::new("evict-before-attach");
+    let storage = item.storage();
+    let body = vec![b'e'; 2 * storage.io_engine.read_chunk_size()];
+    upload(&item, 1, &body).await;
+
+    let found = pause(&item, 1, SyncPoint::ReaderFoundRegistered);
+    let opened = spawn_open(&item, 1);
+    found.arrived().await;
+    // The reader's handle keeps the entry from counting as idle
+    assert_eq!(storage.registry.evict_idle(|| false), 0);
+    found.release();
+    let mut attached = opened
+        .await
+        .unwrap()
+        .unwrap_or_else(|_| panic!("the version could not be opened"));
+    assert_eq!(read_to_end(&mut attached).await.unwrap(), body);
+    drop(attached);
+
+    // Once it let go the entry is closed, and the next reader opens the version again
+    assert_eq!(storage.registry.evict_idle(|| false), 1);
+    assert!(storage.registry.get(&item.id, 1).is_none());
+    let mut reopened = reader(&item, 1).await;
+    assert_eq!(read_to_end(&mut reopened).await.unwrap(), body);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reader_of_an_aborted_upload_never_sees_the_upload_replacing_it() {
+    let item = TestItem::new("replacement");
+    let mut writer = writer(&item, 1);
+    writer.write_chunk(b"old".to_vec()).await.unwrap();
+    let mut old_reader = reader(&item, 1).await;
+    assert_eq!(next_chunk(&mut old_reader).await.unwrap().unwrap(), b"old");
+
+    let caught_up = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let read = tokio::spawn(async move { read_to_end(&mut old_reader).await });
+    caught_up.arrived().await;
+    writer.abort();
+    drop(writer);
+    // The next upload of the version writes the same path while the old reader waits
+    let mut replacement = self::writer(&item, 1);
+    replacement
+        .write_chunk(b"new bytes".to_vec())
+        .await
+        .unwrap();
+    caught_up.release();
+    assert!(read.await.unwrap().is_err());
+
+    let mut new_reader = reader(&item, 1).await;
+    replacement.commit(&committed(9)).await.unwrap();
+    assert_eq!(read_to_end(&mut new_reader).await.unwrap(), b"new bytes");
+}
+
+/// The scenarios above run on the engine `STREAM_DB_IO_ENGINE` picks, `tokio` unless set
+#[cfg(feature = "blocking-io")]
+#[tokio::test(flavor = "multi_thread")]
+async fn a_reader_follows_an_upload_written_through_the_blocking_engine() {
+    use crate::persistence::io_engine::IoEngine;
+
+    let item = TestItem::with_config("blocking-engine", |config| {
+        config.io_engine = IoEngine::Blocking;
+    });
+    let storage = item.storage();
+    let chunk_size = storage.io_engine.read_chunk_size();
+    let mut writer = writer(&item, 1);
+    let mut following = reader(&item, 1).await;
+    // A chunk and a half, so the second read ends short of a full chunk
+    let first: Vec<u8> = (0..chunk_size * 3 / 2).map(|at| (at % 251) as u8).collect();
+    writer.write_chunk(first.clone()).await.unwrap();
+    let mut received = Vec::new();
+    while received.len() < first.len() {
+        received.extend(next_chunk(&mut following).await.unwrap().unwrap());
+    }
+    assert_eq!(received, first);
+
+    // Caught up with the writer, the reader is woken by the next append
+    let caught_up = pause(&item, 1, SyncPoint::ReaderCaughtUp);
+    let read = tokio::spawn(async move { read_to_end(&mut following).await });
+    caught_up.arrived().await;
+    let second = vec![b's'; chunk_size];
+    writer.write_chunk(second.clone()).await.unwrap();
+    caught_up.release();
+    let body = [first, second.clone()].concat();
+    writer.commit(&committed(body.len())).await.unwrap();
+    assert_eq!(read.await.unwrap().unwrap(), second);
+
+    // Opened from disk again, the committed version reads back in full
+    let registered = storage.registry.get(&item.id, 1).unwrap();
+    storage.registry.remove(&item.id, 1, &registered);
+    let mut reopened = reader(&item, 1).await;
+    assert_eq!(read_to_end(&mut reopened).await.unwrap(), body);
+}
@@ -1,38 +1,116 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use tokio::fs::File as TokioFile;
-use tokio::io::AsyncReadExt;
+use crate::metrics::Metrics;
+use crate::persistence::cancellation::{CancelReason, Cancellation};
+use crate::persistence::file_handles::HandleGuard;
+use crate::persistence::io_engine::PositionalReader;
+use crate::persistence::io_failure::IoFailure;
+use crate::persistence::read_sources::ReadSource;
+use crate::persistence::stream_phase::{PhaseStatus, PhaseTracker, StreamPhase};
+
+use fs2::FileExt;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Instant;
 use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
 
 /// Shared file state that can be accessed by multiple concurrent readers
 /// and a single writer.
 pub struct SharedFile {
     /// The underlying file handle (shared for reads)
-    pub file_handle: Arc<tokio::sync::RwLock<TokioFile>>,
+    pub file_handle: PositionalReader,
     /// Current file size in bytes (updated by writer)
     pub file_size: AtomicU64,
+    /// Bytes the writer has synced to disk, never ahead of `file_size`
+    pub durable_size: AtomicU64,
     /// Whether the file has been finalized (writer finished)
     pub is_finished: AtomicBool,
+    /// Whether the upload was abandoned before it was committed
+    pub is_failed: AtomicBool,
     /// Notify readers when new data is available
     pub write_notify: Notify,
     /// Path to the data file
-    #[allow(dead_code)]
     pub data_path: String,
     /// Path to the metadata file
-    #[allow(dead_code)]
     pub metadata_path: String,
+    /// Number of readers currently attached
+    pub active_readers: AtomicUsize,
+    /// Offsets and phases of the attached readers, to tell how far behind the writer
+    /// they are and what they wait for
+    reader_progress: Mutex<Vec<Weak<ReaderProgress>>>,
+    /// What the writer of an upload waits for
+    pub writer_phase: PhaseTracker,
+    /// Bytes read ahead for the attached readers and not handed to their clients yet
+    prefetched: Mutex<Vec<Weak<AtomicU64>>>,
+    /// Duplicates of the handles the writer holds its locks through, and its claim on the
+    /// item, so both can be released on behalf of a writer that is stuck
+    pub writer_locks: Mutex<(Vec<File>, Option<ItemClaim>)>,
+    /// Cancels the upload writing the file, so a kill reaches the writer wherever it is
+    writer_cancellation: OnceLock<Cancellation>,
+    /// Set once somebody took responsibility for removing the partial data file
+    pub cleanup_claimed: AtomicBool,
+    /// Set once the upload's fate is decided, by the writer starting to commit or by a
+    /// kill, whichever comes first
+    pub outcome_claimed: AtomicBool,
+    /// Generation of the version this file belongs to
+    pub epoch: u64,
+    /// Whether the version was deleted or written again since this file was created
+    pub is_replaced: AtomicBool,
+    /// Cold-tier directory the file was opened from, `None` for the data directory
+    pub location: Option<String>,
+    /// The version was committed without a size or checksum, which the first reader
+    /// to read all of it records
+    lacks_digest: AtomicBool,
+    /// `Content-Encoding` of the stored bytes, when an upload is stored compressed
+    content_encoding: OnceLock<String>,
+    /// Complete properties of the committed version, when they were counted
+    property_count: OnceLock<u64>,
+    /// When the entry was last handed out by the registry
+    last_access: Mutex<Instant>,
+    /// Woken once the file is dropped, when its files wait for that to be removed
+    dropped_notify: OnceLock<Arc<Notify>>,
+    /// Counts `file_handle` as open until the file is dropped
+    _handle: HandleGuard,
 }
 
 impl SharedFile {
-    pub fn new(file_handle: TokioFile, data_path: String, metadata_path: String) -> Arc<Self> {
+    pub fn new(
+        file_handle: PositionalReader,
+        data_path: String,
+        metadata_path: String,
+        epoch: u64,
+        location: Option<String>,
+        handle: HandleGuard,
+    ) -> Arc<Self> {
         Arc::new(Self {
-            file_handle: Arc::new(tokio::sync::RwLock::new(file_handle)),
+            file_handle,
             file_size: AtomicU64::new(0),
+            durable_size: AtomicU64::new(0),
             is_finished: AtomicBool::new(false),
+            is_failed: AtomicBool::new(false),
             write_notify: Notify::new(),
             data_path,
             metadata_path,
+            active_readers: AtomicUsize::new(0),
+            reader_progress: Mutex::new(Vec::new()),
+            writer_phase: PhaseTracker::new(StreamPhase::ReadingBody),
+            prefetched: Mutex::new(Vec::new()),
+            writer_locks: Mutex::new((Vec::new(), None)),
+            writer_cancellation: OnceLock::new(),
+            cleanup_claimed: AtomicBool::new(false),
+            outcome_claimed: AtomicBool::new(false),
+            epoch,
+            is_replaced: AtomicBool::new(false),
+            location,
+            lacks_digest: AtomicBool::new(false),
+            content_encoding: OnceLock::new(),
+            property_count: OnceLock::new(),
+            last_access: Mutex::new(Instant::now()),
+            dropped_notify: OnceLock::new(),
+            _handle: handle,
         })
     }
 
@@ -43,56 +121,416 @@ impl SharedFile {
         self.write_notify.notify_waiters();
     }
 
+    /// Record that the first `durable_size` bytes are synced to disk and notify waiting
+    /// readers
+    pub fn update_durable_size(&self, durable_size: u64) {
+        self.durable_size.store(durable_size, Ordering::Release);
+        self.write_notify.notify_waiters();
+    }
+
     /// Mark the file as finished and notify all readers
     pub fn mark_finished(&self) {
         self.is_finished.store(true, Ordering::Release);
         self.write_notify.notify_waiters();
     }
 
+    /// Mark the upload as failed and wake all readers so they can give up
+    pub fn mark_failed(&self) {
+        self.is_failed.store(true, Ordering::Release);
+        self.write_notify.notify_waiters();
+    }
+
+    /// Retire this generation of the version: readers still following it are failed
+    /// rather than being pointed at whatever gets written under the same path next
+    pub fn mark_replaced(&self) {
+        self.is_replaced.store(true, Ordering::Release);
+        self.mark_failed();
+    }
+
+    pub fn is_replaced(&self) -> bool {
+        self.is_replaced.load(Ordering::Acquire)
+    }
+
+    /// Wake `notify` once the last reader let go of the file, see
+    /// [`crate::persistence::deferred_deletes::DeferredDeletes`]
+    pub fn notify_when_dropped(&self, notify: Arc<Notify>) {
+        let _ = self.dropped_notify.set(notify);
+    }
+
+    pub fn mark_lacks_digest(&self, lacks_digest: bool) {
+        self.lacks_digest.store(lacks_digest, Ordering::Release);
+    }
+
+    pub fn lacks_digest(&self) -> bool {
+        self.lacks_digest.load(Ordering::Acquire)
+    }
+
+    /// Record that the bytes are stored compressed with `content_encoding`, before any of
+    /// them is written
+    pub fn set_content_encoding(&self, content_encoding: &str) {
+        let _ = self.content_encoding.set(content_encoding.to_string());
+    }
+
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.content_encoding.get().map(String::as_str)
+    }
+
+    /// Record how many complete properties the version holds, before it is marked finished
+    pub fn set_property_count(&self, property_count: u64) {
+        let _ = self.property_count.set(property_count);
+    }
+
+    pub fn property_count(&self) -> Option<u64> {
+        self.property_count.get().copied()
+    }
+
     /// Get the current file size
     pub fn get_size(&self) -> u64 {
         self.file_size.load(Ordering::Acquire)
     }
 
+    /// Get the number of bytes synced to disk
+    pub fn get_durable_size(&self) -> u64 {
+        self.durable_size.load(Ordering::Acquire)
+    }
+
     /// Check if the file is finished
     pub fn is_finished(&self) -> bool {
         self.is_finished.load(Ordering::Acquire)
     }
 
+    /// Check if the upload failed before it was committed
+    pub fn is_failed(&self) -> bool {
+        self.is_failed.load(Ordering::Acquire)
+    }
+
+    /// Attach a reader, returning the offset and phase it keeps up to date while it reads
+    pub fn reader_attached(&self) -> Arc<ReaderProgress> {
+        self.active_readers.fetch_add(1, Ordering::AcqRel);
+        let progress = Arc::new(ReaderProgress {
+            position: AtomicU64::new(0),
+            phase: PhaseTracker::new(StreamPhase::ReadingChunk),
+        });
+        let mut attached = self.reader_progress.lock().unwrap();
+        attached.retain(|progress| progress.strong_count() > 0);
+        attached.push(Arc::downgrade(&progress));
+        progress
+    }
+
+    /// Register a reader's prefetch queue, returning the count of queued bytes it keeps up
+    /// to date
+    pub fn prefetch_attached(&self) -> Arc<AtomicU64> {
+        let queued = Arc::new(AtomicU64::new(0));
+        let mut prefetched = self.prefetched.lock().unwrap();
+        prefetched.retain(|queued| queued.strong_count() > 0);
+        prefetched.push(Arc::downgrade(&queued));
+        queued
+    }
+
+    /// Bytes queued by the prefetch queues of all readers
+    pub fn prefetched_bytes(&self) -> u64 {
+        self.prefetched
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|queued| queued.load(Ordering::Acquire))
+            .sum()
+    }
+
+    pub fn reader_detached(&self) {
+        self.active_readers.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn reader_count(&self) -> usize {
+        self.active_readers.load(Ordering::Acquire)
+    }
+
+    /// Bytes written that each attached reader has not read yet, largest first
+    pub fn reader_lags(&self) -> Vec<u64> {
+        let size = self.get_size();
+        let mut lags: Vec<u64> = self
+            .reader_progress
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|progress| size.saturating_sub(progress.position.load(Ordering::Acquire)))
+            .collect();
+        lags.sort_unstable_by(|a, b| b.cmp(a));
+        lags
+    }
+
+    /// Phases of the attached readers, the one longest in its phase first
+    pub fn reader_phases(&self) -> Vec<PhaseStatus> {
+        let mut phases: Vec<PhaseStatus> = self
+            .reader_progress
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|progress| progress.phase.status())
+            .collect();
+        phases.sort_unstable_by_key(|status| Reverse(status.elapsed_ms));
+        phases
+    }
+
+    /// Remember duplicates of the writer's locked file handles and its claim on the item
+    pub fn hold_writer_locks(&self, handles: Vec<File>, claim: ItemClaim) {
+        *self.writer_locks.lock().unwrap() = (handles, Some(claim));
+    }
+
+    /// Let the upload writing the file be cancelled through it
+    pub fn hold_writer_cancellation(&self, cancellation: Cancellation) {
+        let _ = self.writer_cancellation.set(cancellation);
+    }
+
+    /// Cancel the upload writing the file for `reason` and wake all readers so they can
+    /// give up
+    pub fn cancel_writer(&self, reason: CancelReason) {
+        if let Some(cancellation) = self.writer_cancellation.get() {
+            cancellation.cancel(reason);
+        }
+        self.mark_failed();
+    }
+
+    /// Why the upload writing the file was cancelled, if it was
+    pub fn writer_cancel_reason(&self) -> Option<CancelReason> {
+        self.writer_cancellation.get()?.reason()
+    }
+
+    /// Unlock and drop the writer's locked handles and its claim on the item, returning
+    /// how many locks were released. The locks belong to the open file descriptions
+    /// shared with the writer, so this frees them even while the writer is still alive.
+    pub fn release_writer_locks(&self) -> usize {
+        let (handles, claim) = std::mem::take(&mut *self.writer_locks.lock().unwrap());
+        for handle in &handles {
+            let _ = FileExt::unlock(handle);
+        }
+        handles.len() + usize::from(claim.is_some())
+    }
+
+    /// Returns true for exactly one caller, who then owns removing the partial data file
+    pub fn claim_cleanup(&self) -> bool {
+        !self.cleanup_claimed.swap(true, Ordering::AcqRel)
+    }
+
+    /// Returns true for exactly one of the committing writer and a kill, so a kill can
+    /// never remove the data of a version whose metadata is being written
+    pub fn claim_outcome(&self) -> bool {
+        !self.outcome_claimed.swap(true, Ordering::AcqRel)
+    }
+
+    fn touch(&self) {
+        *self.last_access.lock().unwrap() = Instant::now();
+    }
+
     /// Read data from a specific offset
     pub async fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
-        let mut file = self.file_handle.write().await;
-        // Use seek to position at offset
-        tokio::io::AsyncSeekExt::seek(&mut *file, std::io::SeekFrom::Start(offset)).await?;
-        let bytes_read = file.read(buffer).await?;
-        Ok(bytes_read)
+        self.file_handle.read_at(offset, buffer).await
+    }
+}
+
+/// Where an attached reader is, kept up to date by the reader itself
+pub struct ReaderProgress {
+    /// Offset of the next byte the reader reads
+    pub position: AtomicU64,
+    pub phase: PhaseTracker,
+}
+
+/// What happens to a reader of an in-flight upload that falls too far behind the writer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SlowReaderPolicy {
+    /// Keep serving the reader from the data file, where every read comes from anyway,
+    /// and count it as downgraded
+    Disk,
+    /// Fail the read with a `CONFLICT` error
+    Terminate,
+}
+
+impl SlowReaderPolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "disk" => Ok(Self::Disk),
+            "terminate" => Ok(Self::Terminate),
+            other => Err(format!("Unknown slow reader policy {other:?}")),
+        }
+    }
+}
+
+/// How far a reader of an in-flight upload may fall behind the bytes it could read
+#[derive(Clone, Copy, Debug)]
+pub struct SlowReaderLimit {
+    pub max_lag_bytes: u64,
+    pub policy: SlowReaderPolicy,
+}
+
+/// An upload's claim on its item. At most `max_per_item` uploads of an item run at a
+/// time, one by default: within the process the registry counts the claims of every
+/// item, and across processes each claim holds a lock on one of the item's upload
+/// markers in the data directory, `{item_id}.writing` for the first upload and
+/// `{item_id}.writing.{slot}` for the further ones. Dropping the claim removes its
+/// marker and frees the slot for the next upload.
+pub struct ItemClaim {
+    item_id: String,
+    version: u64,
+    /// Taken to reset the item, which holds every slot and lets no upload in
+    exclusive: bool,
+    slots: Vec<usize>,
+    markers: Vec<(File, String)>,
+    writing: Arc<Mutex<InFlightIndex>>,
+    released: Arc<Notify>,
+}
+
+impl Drop for SharedFile {
+    fn drop(&mut self) {
+        if let Some(notify) = self.dropped_notify.get() {
+            notify.notify_one();
+        }
+    }
+}
+
+impl Drop for ItemClaim {
+    fn drop(&mut self) {
+        // The markers go before the slots are freed, so they never remove the marker of
+        // the next upload
+        release_markers(&self.markers);
+        let mut writing = self.writing.lock().unwrap();
+        writing.release(self);
+        drop(writing);
+        self.released.notify_waiters();
     }
 }
 
+fn release_markers(markers: &[(File, String)]) {
+    for (marker, marker_path) in markers {
+        if let Err(error) = std::fs::remove_file(marker_path)
+            && error.kind() != std::io::ErrorKind::NotFound
+        {
+            println!("Could not remove {marker_path}: {error}");
+        }
+        let _ = FileExt::unlock(marker);
+    }
+}
+
+/// Lock the upload marker at `marker_path` and name `version` in it, `None` while
+/// another process holds it
+fn lock_marker(marker_path: &str, version: u64) -> Result<Option<File>, String> {
+    let mut marker = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(marker_path)
+        .map_err(|error| IoFailure::new("open upload marker", marker_path, &error))?;
+    if marker.try_lock_exclusive().is_err() {
+        return Ok(None);
+    }
+    // The previous holder removes the marker before unlocking it, a lock taken on
+    // the removed file claims nothing
+    if !is_file_at(&marker, marker_path) {
+        return Ok(None);
+    }
+    // Names the version being written for whoever looks at the data directory
+    marker
+        .set_len(0)
+        .and_then(|_| writeln!(marker, "{version}"))
+        .map_err(|error| IoFailure::new("write upload marker", marker_path, &error))?;
+    Ok(Some(marker))
+}
+
+/// Uploads of one item this process holds claims for
+#[derive(Default)]
+struct ItemUploads {
+    versions: BTreeSet<u64>,
+    /// Upload marker slots the claims hold
+    slots: BTreeSet<usize>,
+    exclusive: bool,
+}
+
+/// The claims this process holds, by item
+#[derive(Default)]
+struct InFlightIndex {
+    items: HashMap<String, ItemUploads>,
+    /// Versions being uploaded across all items
+    total: usize,
+}
+
+impl InFlightIndex {
+    fn release(&mut self, claim: &ItemClaim) {
+        let Some(uploads) = self.items.get_mut(&claim.item_id) else {
+            return;
+        };
+        if claim.exclusive {
+            uploads.exclusive = false;
+        } else if uploads.versions.remove(&claim.version) {
+            self.total -= 1;
+        }
+        for slot in &claim.slots {
+            uploads.slots.remove(slot);
+        }
+        if uploads.versions.is_empty() && !uploads.exclusive {
+            self.items.remove(&claim.item_id);
+        }
+    }
+}
+
+/// Versions of an item uploads of this process are writing, and how many uploads are
+/// in flight across all items
+pub struct InFlightUploads {
+    pub versions: Vec<u64>,
+    pub total: usize,
+}
+
+/// A committed version nobody reads, only kept open for the next reader
+fn is_idle(shared_file: &Arc<SharedFile>) -> bool {
+    shared_file.is_finished() && !shared_file.is_failed() && Arc::strong_count(shared_file) == 1
+}
+
 /// Registry to track shared files by (item_id, version)
 pub struct SharedFileRegistry {
     files: Mutex<HashMap<(String, u64), Arc<SharedFile>>>,
+    /// Claims held by uploads of this process, by item
+    writing: Arc<Mutex<InFlightIndex>>,
+    /// Signalled whenever a claim of this process is released
+    claim_released: Arc<Notify>,
+    /// Uploads of one item that may run at once
+    pub max_per_item: usize,
+    /// Uploads that may run at once across all items, unbounded when unset
+    pub max_total: Option<usize>,
 }
 
 impl SharedFileRegistry {
-    pub fn new() -> Self {
+    pub fn new(max_per_item: usize, max_total: Option<usize>) -> Self {
         Self {
-            files: Mutex::new(HashMap::new()),
+            files: Mutex::default(),
+            writing: Arc::default(),
+            claim_released: Arc::default(),
+            max_per_item,
+            max_total,
         }
     }
 
-    /// Get or create a shared file entry
+    /// Get or create the shared file entry of generation `epoch` of a version
     pub fn get_or_create(
         &self,
         item_id: String,
         version: u64,
+        epoch: u64,
         create_fn: impl FnOnce() -> Result<Arc<SharedFile>, String>,
     ) -> Result<Arc<SharedFile>, String> {
         let mut files = self.files.lock().unwrap();
         let key = (item_id, version);
 
+        // A failed upload's entry is replaced rather than reused, and so is the entry of
+        // an older generation, whose readers must not see the new generation's bytes
         if let Some(shared_file) = files.get(&key) {
-            return Ok(shared_file.clone());
+            if !shared_file.is_failed() && shared_file.epoch == epoch {
+                shared_file.touch();
+                return Ok(shared_file.clone());
+            }
+            if shared_file.epoch != epoch {
+                shared_file.mark_replaced();
+            }
         }
 
         let shared_file = create_fn()?;
@@ -103,21 +541,262 @@ impl SharedFileRegistry {
     /// Get an existing shared file
     pub fn get(&self, item_id: &str, version: u64) -> Option<Arc<SharedFile>> {
         let files = self.files.lock().unwrap();
-        files.get(&(item_id.to_string(), version)).cloned()
+        let shared_file = files.get(&(item_id.to_string(), version))?;
+        shared_file.touch();
+        Some(shared_file.clone())
     }
 
-    /// Remove a shared file from the registry
-    #[allow(dead_code)]
-    pub fn remove(&self, item_id: &str, version: u64) {
+    /// Number of entries, and of those a committed version nothing but the registry holds
+    /// on to
+    pub fn counts(&self) -> (usize, usize) {
+        let files = self.files.lock().unwrap();
+        let idle = files.values().filter(|file| is_idle(file)).count();
+        (files.len(), idle)
+    }
+
+    /// Close committed versions nothing but the registry holds on to, least recently used
+    /// first, until `enough` is satisfied. Versions being written or with readers attached
+    /// are left alone. Returns how many were closed.
+    pub fn evict_idle(&self, enough: impl Fn() -> bool) -> usize {
         let mut files = self.files.lock().unwrap();
-        files.remove(&(item_id.to_string(), version));
+        let mut idle: Vec<_> = files
+            .iter()
+            .filter(|(_, file)| is_idle(file))
+            .map(|(key, file)| (*file.last_access.lock().unwrap(), key.clone()))
+            .collect();
+        idle.sort();
+        let mut evicted = 0;
+        for (_, key) in idle {
+            if enough() {
+                break;
+            }
+            // Handles to the entry are only ever taken under the lock, so it is still idle
+            drop(files.remove(&key));
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Every entry currently registered, by (item_id, version)
+    pub fn entries(&self) -> Vec<((String, u64), Arc<SharedFile>)> {
+        let files = self.files.lock().unwrap();
+        let mut entries: Vec<_> = files
+            .iter()
+            .map(|(key, shared_file)| (key.clone(), shared_file.clone()))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// Claim `item_id` for an upload of `version`, `None` while `max_per_item` uploads
+    /// of the item already hold a claim, in this process or another one, while the
+    /// version itself is being uploaded, or while `max_total` uploads run in this
+    /// process. `marker_path` names the marker of each slot.
+    pub fn claim_item(
+        &self,
+        item_id: &str,
+        version: u64,
+        marker_path: impl Fn(usize) -> String,
+    ) -> Result<Option<ItemClaim>, String> {
+        let mut writing = self.writing.lock().unwrap();
+        if self
+            .max_total
+            .is_some_and(|max_total| writing.total >= max_total)
+        {
+            return Ok(None);
+        }
+        let taken = match writing.items.get(item_id) {
+            Some(uploads)
+                if uploads.exclusive
+                    || uploads.versions.contains(&version)
+                    || uploads.versions.len() >= self.max_per_item =>
+            {
+                return Ok(None);
+            }
+            Some(uploads) => uploads.slots.clone(),
+            None => BTreeSet::new(),
+        };
+        for slot in (0..self.max_per_item).filter(|slot| !taken.contains(slot)) {
+            let path = marker_path(slot);
+            let Some(marker) = lock_marker(&path, version)? else {
+                continue;
+            };
+            let uploads = writing.items.entry(item_id.to_string()).or_default();
+            uploads.versions.insert(version);
+            uploads.slots.insert(slot);
+            writing.total += 1;
+            return Ok(Some(ItemClaim {
+                item_id: item_id.to_string(),
+                version,
+                exclusive: false,
+                slots: vec![slot],
+                markers: vec![(marker, path)],
+                writing: self.writing.clone(),
+                released: self.claim_released.clone(),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Claim every slot of `item_id` at once, `None` while any upload of the item holds
+    /// a claim. No upload of the item starts until the claim is dropped.
+    pub fn claim_whole_item(
+        &self,
+        item_id: &str,
+        version: u64,
+        marker_path: impl Fn(usize) -> String,
+    ) -> Result<Option<ItemClaim>, String> {
+        let mut writing = self.writing.lock().unwrap();
+        if writing.items.contains_key(item_id) {
+            return Ok(None);
+        }
+        let mut markers = Vec::new();
+        for slot in 0..self.max_per_item {
+            let path = marker_path(slot);
+            match lock_marker(&path, version) {
+                Ok(Some(marker)) => markers.push((marker, path)),
+                locked => {
+                    release_markers(&markers);
+                    return locked.map(|_| None);
+                }
+            }
+        }
+        let slots: Vec<usize> = (0..self.max_per_item).collect();
+        writing.items.insert(
+            item_id.to_string(),
+            ItemUploads {
+                versions: BTreeSet::new(),
+                slots: slots.iter().copied().collect(),
+                exclusive: true,
+            },
+        );
+        Ok(Some(ItemClaim {
+            item_id: item_id.to_string(),
+            version,
+            exclusive: true,
+            slots,
+            markers,
+            writing: self.writing.clone(),
+            released: self.claim_released.clone(),
+        }))
+    }
+
+    /// What the uploads of this process are writing, to tell a refused upload why
+    pub fn in_flight(&self, item_id: &str) -> InFlightUploads {
+        let writing = self.writing.lock().unwrap();
+        InFlightUploads {
+            versions: writing
+                .items
+                .get(item_id)
+                .map(|uploads| uploads.versions.iter().copied().collect())
+                .unwrap_or_default(),
+            total: writing.total,
+        }
+    }
+
+    /// Resolves the next time a claim of this process is released. Claims held by other
+    /// processes are released without notice.
+    pub fn claim_released(&self) -> Notified<'_> {
+        self.claim_released.notified()
+    }
+
+    /// Remove a shared file from the registry, unless the entry has meanwhile been
+    /// replaced by a different one. Returns whether the entry was removed.
+    pub fn remove(&self, item_id: &str, version: u64, shared_file: &Arc<SharedFile>) -> bool {
+        let mut files = self.files.lock().unwrap();
+        let key = (item_id.to_string(), version);
+        if files
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, shared_file))
+        {
+            files.remove(&key);
+            return true;
+        }
+        false
+    }
+}
+
+/// Whether `file` is still the file at `path`, rather than one that was removed or
+/// replaced since it was opened
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `file` is still the file at `path`, told by its creation time where there are
+/// no inode numbers to compare
+#[cfg(not(unix))]
+fn is_file_at(file: &File, path: &str) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => match (open.created(), current.created()) {
+            (Ok(open), Ok(current)) => open == current,
+            _ => false,
+        },
+        _ => false,
     }
 }
 
-/// Global registry instance
-use std::sync::OnceLock;
-static SHARED_FILE_REGISTRY: OnceLock<SharedFileRegistry> = OnceLock::new();
+/// Item, version and read source of an open under way
+type FlightKey = (String, u64, ReadSource);
 
-pub fn get_shared_file_registry() -> &'static SharedFileRegistry {
-    SHARED_FILE_REGISTRY.get_or_init(SharedFileRegistry::new)
+/// Opens of committed versions from disk under way, by item, version and read source.
+/// Readers arriving while one is opening a version wait for its outcome instead of
+/// reading the metadata and opening the data file again, so a burst of readers of a
+/// version that just became readable opens it once.
+pub struct OpenFlights<T> {
+    flights: Mutex<HashMap<FlightKey, Arc<OnceLock<T>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T: Clone> OpenFlights<T> {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            flights: Mutex::default(),
+            metrics,
+        }
+    }
+
+    /// Run `open` for the version, or block until the caller already running it is done
+    /// and take its outcome
+    pub fn join(
+        &self,
+        item_id: &str,
+        item_version: u64,
+        source: ReadSource,
+        open: impl FnOnce() -> T,
+    ) -> T {
+        let key = (item_id.to_string(), item_version, source);
+        let flight = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut opened = false;
+        let outcome = flight
+            .get_or_init(|| {
+                opened = true;
+                open()
+            })
+            .clone();
+        if opened {
+            // Later readers find the version in the registry, or look again
+            let mut flights = self.flights.lock().unwrap();
+            if flights
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &flight))
+            {
+                flights.remove(&key);
+            }
+        } else {
+            self.metrics.reader_opens_collapsed.increment();
+        }
+        outcome
+    }
 }
@@ -0,0 +1,237 @@
+use crate::persistence::storage::Storage;
+
+use serde::Serialize;
+
+/// Where the bytes of a data directory go, see `GET /admin/space-report`
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SpaceCategory {
+    /// Data files of committed versions, in whichever tier they are
+    Committed,
+    /// Data files of uploads running in this process
+    InFlight,
+    /// Data files of uploads interrupted by a restart, until they are purged
+    Interrupted,
+    /// Files of versions nothing knows about, neither committed, running nor interrupted
+    Orphaned,
+    /// Property, block and part checksum indexes
+    Indexes,
+    Journals,
+    DebugCaptures,
+    /// Metadata, settings, stats, tags and writing markers of items
+    Metadata,
+    /// Everything else, e.g. the format marker, staged publish groups and temporaries
+    Other,
+}
+
+/// Bytes per [`SpaceCategory`]
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct SpaceBreakdown {
+    pub committed: u64,
+    pub in_flight: u64,
+    pub interrupted: u64,
+    pub orphaned: u64,
+    pub indexes: u64,
+    pub journals: u64,
+    pub debug_captures: u64,
+    pub metadata: u64,
+    pub other: u64,
+}
+
+impl SpaceBreakdown {
+    pub fn add(&mut self, category: SpaceCategory, bytes: u64) {
+        let bucket = match category {
+            SpaceCategory::Committed => &mut self.committed,
+            SpaceCategory::InFlight => &mut self.in_flight,
+            SpaceCategory::Interrupted => &mut self.interrupted,
+            SpaceCategory::Orphaned => &mut self.orphaned,
+            SpaceCategory::Indexes => &mut self.indexes,
+            SpaceCategory::Journals => &mut self.journals,
+            SpaceCategory::DebugCaptures => &mut self.debug_captures,
+            SpaceCategory::Metadata => &mut self.metadata,
+            SpaceCategory::Other => &mut self.other,
+        };
+        *bucket += bytes;
+    }
+
+    pub fn merge(&mut self, other: &SpaceBreakdown) {
+        self.committed += other.committed;
+        self.in_flight += other.in_flight;
+        self.interrupted += other.interrupted;
+        self.orphaned += other.orphaned;
+        self.indexes += other.indexes;
+        self.journals += other.journals;
+        self.debug_captures += other.debug_captures;
+        self.metadata += other.metadata;
+        self.other += other.other;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.committed
+            + self.in_flight
+            + self.interrupted
+            + self.orphaned
+            + self.indexes
+            + self.journals
+            + self.debug_captures
+            + self.metadata
+            + self.other
+    }
+}
+
+/// What a file in one of the instance's directories is, by its name
+#[derive(Clone, PartialEq, Debug)]
+pub enum FileKind {
+    /// `{item_id}_{version}.xml`
+    Data {
+        item_id: String,
+        version: u64,
+    },
+    /// `{item_id}_{version}.index.jsonl`, `.blocks.json` or `.parts.json`
+    Index {
+        item_id: String,
+        version: u64,
+    },
+    /// `{item_id}_{version}.journal.jsonl`
+    Journal {
+        item_id: String,
+        version: u64,
+    },
+    /// Metadata, settings, stats, tags or writing marker of an item
+    Metadata {
+        item_id: String,
+    },
+    Other,
+}
+
+impl FileKind {
+    pub fn of(file_name: &str) -> Self {
+        for suffix in [
+            "_metadata.xml",
+            "_settings.json",
+            "_stats.json",
+            "_tags.json",
+        ] {
+            if let Some(item_id) = file_name.strip_suffix(suffix) {
+                return Self::Metadata {
+                    item_id: item_id.to_string(),
+                };
+            }
+        }
+        if let Some((item_id, slot)) = file_name.split_once(".writing")
+            && (slot.is_empty() || slot.strip_prefix('.').is_some_and(is_number))
+        {
+            return Self::Metadata {
+                item_id: item_id.to_string(),
+            };
+        }
+        let versioned = |suffix: &str| {
+            let (item_id, version) = file_name.strip_suffix(suffix)?.rsplit_once('_')?;
+            Some((item_id.to_string(), version.parse().ok()?))
+        };
+        if let Some((item_id, version)) = versioned(".journal.jsonl") {
+            return Self::Journal { item_id, version };
+        }
+        for suffix in [".index.jsonl", ".blocks.json", ".parts.json"] {
+            if let Some((item_id, version)) = versioned(suffix) {
+                return Self::Index { item_id, version };
+            }
+        }
+        match versioned(".xml") {
+            Some((item_id, version)) => Self::Data { item_id, version },
+            None => Self::Other,
+        }
+    }
+}
+
+fn is_number(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// A file found by [`list_dir`]
+pub struct FoundFile {
+    pub path: String,
+    /// Name of the file, or of the subdirectory of `dir` it is in
+    pub top_level_name: String,
+    /// Whether it is inside a subdirectory of the directory listed
+    pub nested: bool,
+    pub bytes: u64,
+    /// Bytes the filesystem allocated for it, which differs for sparse files and
+    /// preallocated ones
+    pub allocated_bytes: u64,
+}
+
+/// Every file in `dir` and its subdirectories. `stat` is called before each entry is
+/// inspected and cuts the listing short, returning what was found so far, when it
+/// returns false. A missing directory has no files.
+pub fn list_dir(dir: &str, mut stat: impl FnMut() -> bool) -> Result<Vec<FoundFile>, String> {
+    let mut found = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(found),
+        Err(error) => return Err(format!("Failed to list {dir}: {error}")),
+    };
+    // Each entry with the name of the top-level entry it is under
+    let mut pending: Vec<(std::fs::DirEntry, String, bool)> = entries
+        .flatten()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (entry, name, false)
+        })
+        .collect();
+    while let Some((entry, top_level_name, nested)) = pending.pop() {
+        if !stat() {
+            break;
+        }
+        // Files removed meanwhile are skipped, they no longer take up space
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if let Ok(children) = std::fs::read_dir(entry.path()) {
+                pending.extend(
+                    children
+                        .flatten()
+                        .map(|child| (child, top_level_name.clone(), true)),
+                );
+            }
+            continue;
+        }
+        found.push(FoundFile {
+            path: entry.path().to_string_lossy().into_owned(),
+            top_level_name,
+            nested,
+            bytes: metadata.len(),
+            allocated_bytes: allocated_bytes(&metadata),
+        });
+    }
+    Ok(found)
+}
+
+/// Bytes the filesystem allocated for a file, its length where that is not known
+#[cfg(unix)]
+fn allocated_bytes(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// The directories the instance keeps files in: the data directory, the in-flight
+/// directory, the cold tier and any other directory versions were moved to
+pub fn dirs(storage: &Storage, cold_dir: Option<&str>, locations: &[String]) -> Vec<String> {
+    let mut dirs = vec![storage.data_dir.clone()];
+    let others = std::iter::once(storage.inflight_dir.as_str())
+        .chain(cold_dir)
+        .chain(locations.iter().map(String::as_str));
+    for dir in others {
+        if !dirs.iter().any(|known| known == dir) {
+            dirs.push(dir.to_string());
+        }
+    }
+    dirs
+}
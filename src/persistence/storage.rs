@@ -0,0 +1,285 @@
+use crate::log_control::{LogControl, LogLevel};
+use crate::metrics::Metrics;
+use crate::persistence::deferred_deletes::DeferredDeletes;
+use crate::persistence::existence_cache::ExistenceCache;
+use crate::persistence::file_handles::FileHandles;
+use crate::persistence::file_persistence::{FailedUpload, OpenError};
+use crate::persistence::io_engine::{FsyncPolicy, IoEngine};
+use crate::persistence::io_scheduler::IoScheduler;
+use crate::persistence::item_filter::ItemFilter;
+use crate::persistence::read_sources::ReadSources;
+use crate::persistence::shared_file::{
+    OpenFlights, SharedFile, SharedFileRegistry, SlowReaderLimit,
+};
+use crate::persistence::write_queue::WriteQueue;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Bytes of data files, kept up to date by uploads, commits and deletes and recounted
+/// from the metadata at startup. Committed versions are in the data directory and the
+/// cold tier, uploads in flight in the in-flight directory.
+pub struct StorageUsage {
+    committed_bytes: AtomicU64,
+    in_flight_bytes: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl StorageUsage {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        StorageUsage {
+            committed_bytes: AtomicU64::new(0),
+            in_flight_bytes: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    /// Bytes of every committed version
+    pub fn committed_bytes(&self) -> u64 {
+        self.committed_bytes.load(Ordering::Acquire)
+    }
+
+    /// Bytes written by uploads that have not committed yet
+    pub fn in_flight_bytes(&self) -> u64 {
+        self.in_flight_bytes.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_committed(&self, bytes: u64) {
+        let previous = self.committed_bytes.swap(bytes, Ordering::AcqRel);
+        self.metrics.space_committed_bytes.add(bytes);
+        self.metrics.space_committed_bytes.sub(previous);
+    }
+
+    pub(crate) fn add_in_flight(&self, bytes: u64) {
+        self.in_flight_bytes.fetch_add(bytes, Ordering::AcqRel);
+        self.metrics.space_in_flight_bytes.add(bytes);
+    }
+
+    pub(crate) fn remove_in_flight(&self, bytes: u64) {
+        let removed = subtract(&self.in_flight_bytes, bytes);
+        self.metrics.space_in_flight_bytes.sub(removed);
+    }
+
+    /// An upload of `bytes` committed
+    pub(crate) fn commit(&self, bytes: u64) {
+        self.remove_in_flight(bytes);
+        self.committed_bytes.fetch_add(bytes, Ordering::AcqRel);
+        self.metrics.space_committed_bytes.add(bytes);
+    }
+
+    pub(crate) fn remove_committed(&self, bytes: u64) {
+        let removed = subtract(&self.committed_bytes, bytes);
+        self.metrics.space_committed_bytes.sub(removed);
+    }
+}
+
+/// Take up to `bytes` off `total`, returning how many were taken
+fn subtract(total: &AtomicU64, bytes: u64) -> u64 {
+    let previous = total
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            Some(current.saturating_sub(bytes))
+        })
+        .unwrap_or_default();
+    previous.min(bytes)
+}
+
+/// How a finished upload gets from the in-flight directory into the data directory
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitStrategy {
+    /// Uploads are written in the data directory, nothing moves
+    InPlace,
+    /// Renamed, atomically, the directories are on the same filesystem
+    Rename,
+    /// Copied to a temporary file in the data directory, synced and renamed there
+    Copy,
+}
+
+/// Where uploads are written and where committed versions live
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StorageLayout {
+    pub data_dir: String,
+    pub inflight_dir: String,
+    /// Decided at startup, unknown on read-only instances which never commit
+    pub commit_strategy: Option<CommitStrategy>,
+}
+
+/// The files of one instance and the in-memory state shared by everyone reading or
+/// writing them. Instances with different data directories are fully independent.
+pub struct Storage {
+    pub data_dir: String,
+    /// Uploads are written here until they commit, the data directory unless
+    /// `STREAM_DB_INFLIGHT_DIR` points elsewhere
+    pub inflight_dir: String,
+    /// Set by [`crate::persistence::file_persistence::init`] once both directories exist
+    pub(crate) commit_strategy: OnceLock<CommitStrategy>,
+    pub io_engine: IoEngine,
+    pub fsync_policy: FsyncPolicy,
+    /// Bytes between two checkpoints of an upload's journal, no journal when 0
+    pub journal_interval_bytes: u64,
+    /// Takes turns between uploads on the blocking pool
+    pub io_scheduler: IoScheduler,
+    /// The data directory belongs to another instance and is never written to
+    pub read_only: bool,
+    /// In-flight and recently committed versions of this instance only, never shared
+    /// with another instance
+    pub(crate) registry: SharedFileRegistry,
+    /// Versions being opened from disk for readers, shared by the readers arriving meanwhile
+    pub(crate) opening: OpenFlights<Result<Arc<SharedFile>, OpenError>>,
+    /// Uploads found on disk at startup that never committed, by (item_id, version)
+    pub(crate) failed_uploads: Mutex<BTreeMap<(String, u64), FailedUpload>>,
+    /// Held while tags are changed and while a version is deleted, so a tag never ends
+    /// up pointing at a version that is gone
+    pub(crate) tags_lock: Mutex<()>,
+    /// Versions being moved between tiers, each by one request at a time
+    pub(crate) tier_moves: Mutex<BTreeSet<(String, u64)>>,
+    /// Held while the named transforms are changed
+    pub(crate) transforms_lock: Mutex<()>,
+    /// Held while a version is staged in a publish group and while a group is committed
+    /// or aborted, so nothing is staged into a group that is gone
+    pub(crate) publishing_lock: Mutex<()>,
+    pub usage: StorageUsage,
+    /// The metrics of the instance, so persistence counts into the same `/metrics`
+    pub metrics: Arc<Metrics>,
+    /// Long-lived file handles against `STREAM_DB_MAX_OPEN_FILES`
+    pub file_handles: FileHandles,
+    /// Committed versions of recently looked up items, and versions recently found missing
+    pub existence: ExistenceCache,
+    /// Items stored in the data directory, once built, see `STREAM_DB_ITEM_FILTER`
+    pub item_filter: ItemFilter,
+    /// Readers of uploads falling further behind are downgraded or failed, none by default
+    pub slow_readers: Option<SlowReaderLimit>,
+    /// Uploads waiting for another upload of their item to finish, unbounded by default
+    pub write_queue: WriteQueue,
+    /// Which streams write debug lines, the instance's log settings
+    pub log: Arc<LogControl>,
+    /// Where reads look for their version, and how those places have been doing
+    pub read_sources: ReadSources,
+    /// Files of deleted or moved versions waiting for their readers to finish
+    pub deferred_deletes: DeferredDeletes,
+}
+
+impl Storage {
+    pub fn new(
+        data_dir: String,
+        io_engine: IoEngine,
+        fsync_policy: FsyncPolicy,
+        io_scheduler: IoScheduler,
+        read_only: bool,
+        metrics: Arc<Metrics>,
+        max_open_files: Option<u64>,
+    ) -> Self {
+        Self {
+            inflight_dir: data_dir.clone(),
+            commit_strategy: OnceLock::new(),
+            data_dir,
+            io_engine,
+            fsync_policy,
+            journal_interval_bytes: 0,
+            io_scheduler,
+            read_only,
+            registry: SharedFileRegistry::new(1, None),
+            opening: OpenFlights::new(metrics.clone()),
+            failed_uploads: Mutex::new(BTreeMap::new()),
+            tags_lock: Mutex::new(()),
+            tier_moves: Mutex::new(BTreeSet::new()),
+            transforms_lock: Mutex::new(()),
+            publishing_lock: Mutex::new(()),
+            usage: StorageUsage::new(metrics.clone()),
+            file_handles: FileHandles::new(max_open_files, metrics.clone()),
+            existence: ExistenceCache::new(Duration::ZERO, metrics.clone()),
+            write_queue: WriteQueue::new(usize::MAX, usize::MAX, metrics.clone()),
+            item_filter: ItemFilter::new(metrics.clone()),
+            deferred_deletes: DeferredDeletes::new(metrics.clone()),
+            metrics,
+            slow_readers: None,
+            log: Arc::new(LogControl::new(LogLevel::Info, Vec::new())),
+            read_sources: ReadSources::default(),
+        }
+    }
+
+    /// Answer reads of a version found missing with `404` for `miss_ttl` without looking
+    /// again, see [`ExistenceCache::recently_missed`]
+    pub fn with_miss_ttl(mut self, miss_ttl: Duration) -> Self {
+        self.existence = ExistenceCache::new(miss_ttl, self.metrics.clone());
+        self
+    }
+
+    /// Resolve reads through `read_sources`
+    pub fn with_read_sources(mut self, read_sources: ReadSources) -> Self {
+        self.read_sources = read_sources;
+        self
+    }
+
+    /// Write the debug lines of streams by the instance's `log` settings
+    pub fn with_log_control(mut self, log: Arc<LogControl>) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Keep a journal of every upload with a checkpoint each `journal_interval_bytes`
+    pub fn with_journal_interval(mut self, journal_interval_bytes: u64) -> Self {
+        self.journal_interval_bytes = journal_interval_bytes;
+        self
+    }
+
+    /// Hold readers of uploads to `limit`
+    pub fn with_slow_reader_limit(mut self, limit: Option<SlowReaderLimit>) -> Self {
+        self.slow_readers = limit;
+        self
+    }
+
+    /// Let at most `max_depth` uploads wait for one item and `max_total` for all of them
+    pub fn with_write_queue(mut self, max_depth: usize, max_total: usize) -> Self {
+        self.write_queue = WriteQueue::new(max_depth, max_total, self.metrics.clone());
+        self
+    }
+
+    /// Let at most `max_per_item` uploads of one item and `max_total` uploads in all run
+    /// at once
+    pub fn with_in_flight_limits(mut self, max_per_item: usize, max_total: Option<usize>) -> Self {
+        self.registry = SharedFileRegistry::new(max_per_item, max_total);
+        self
+    }
+
+    /// Write uploads to `inflight_dir` instead of the data directory
+    pub fn with_inflight_dir(mut self, inflight_dir: Option<String>) -> Self {
+        if let Some(inflight_dir) = inflight_dir {
+            self.inflight_dir = inflight_dir;
+        }
+        self
+    }
+
+    /// Path of `file_name` inside the data directory
+    pub fn path(&self, file_name: &str) -> String {
+        format!("{}/{file_name}", self.data_dir)
+    }
+
+    /// Path of `file_name` inside the in-flight directory
+    pub fn inflight_path(&self, file_name: &str) -> String {
+        format!("{}/{file_name}", self.inflight_dir)
+    }
+
+    /// Uploads are written elsewhere than the data directory
+    pub fn has_inflight_dir(&self) -> bool {
+        self.inflight_dir != self.data_dir
+    }
+
+    pub fn commit_strategy(&self) -> CommitStrategy {
+        self.commit_strategy
+            .get()
+            .copied()
+            .unwrap_or(CommitStrategy::InPlace)
+    }
+
+    pub fn layout(&self) -> StorageLayout {
+        StorageLayout {
+            data_dir: self.data_dir.clone(),
+            inflight_dir: self.inflight_dir.clone(),
+            commit_strategy: self.commit_strategy.get().copied(),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::persistence::cold_tier;
+use crate::persistence::file_persistence;
+use crate::persistence::storage::Storage;
+
+use serde::Serialize;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// File in the data directory holding the format of the store
+const FORMAT_FILE: &str = "FORMAT";
+/// Format of the stores this binary reads and writes
+pub const CURRENT_FORMAT: u32 = 1;
+/// How often a migration logs its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One change of the on-disk layout, upgrading a store of format `format - 1` to
+/// `format`. Steps are idempotent: one interrupted by a crash runs again from the start
+/// and passes over what it already did, as the format is only recorded once it finished.
+struct Migration {
+    format: u32,
+    name: &'static str,
+    description: &'static str,
+    run: fn(&Storage, &mut Progress) -> Result<(), String>,
+}
+
+/// Every migration, in the order they run
+const MIGRATIONS: &[Migration] = &[Migration {
+    format: 1,
+    name: "metadata-versions",
+    description: "list the latest version of items written before versions were tracked individually as a committed version",
+    run: list_latest_versions,
+}];
+
+/// The format of a store and the migrations it is missing
+#[derive(Serialize)]
+pub struct FormatStatus {
+    pub data_dir: String,
+    /// `None` for a store that does not exist yet, which is created in the current format
+    pub format: Option<u32>,
+    pub supported: u32,
+    pub pending: Vec<PendingMigration>,
+}
+
+#[derive(Serialize)]
+pub struct PendingMigration {
+    pub format: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Logs how far a migration got, at most every [`PROGRESS_INTERVAL`]
+struct Progress {
+    name: &'static str,
+    total: usize,
+    done: usize,
+    changed: usize,
+    last_logged: Instant,
+}
+
+impl Progress {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            total: 0,
+            done: 0,
+            changed: 0,
+            last_logged: Instant::now(),
+        }
+    }
+
+    fn advance(&mut self, changed: bool) {
+        self.done += 1;
+        self.changed += usize::from(changed);
+        if self.last_logged.elapsed() >= PROGRESS_INTERVAL {
+            self.last_logged = Instant::now();
+            println!(
+                "Migration {}: {} of {} items done, {} changed",
+                self.name, self.done, self.total, self.changed
+            );
+        }
+    }
+}
+
+fn format_path(storage: &Storage) -> String {
+    format!("{}/{FORMAT_FILE}", storage.data_dir)
+}
+
+/// The format the `FORMAT` file records. Stores without one were written before formats
+/// were recorded, unless they hold no items at all.
+fn stored_format(storage: &Storage) -> Result<Option<u32>, String> {
+    match std::fs::read_to_string(format_path(storage)) {
+        Ok(text) => text.trim().parse().map(Some).map_err(|_| {
+            format!(
+                "{} does not hold a format number: {:?}",
+                format_path(storage),
+                text.trim()
+            )
+        }),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            let data_dir = std::path::Path::new(&storage.data_dir);
+            if data_dir.is_dir() && !cold_tier::item_ids(storage)?.is_empty() {
+                Ok(Some(0))
+            } else {
+                Ok(None)
+            }
+        }
+        Err(error) => Err(format!("Failed to read {}: {error}", format_path(storage))),
+    }
+}
+
+/// Record `format` through a rename, so a crash leaves either the old or the new one
+fn store_format(storage: &Storage, format: u32) -> Result<(), String> {
+    let path = format_path(storage);
+    let temporary_path = format!("{path}.tmp");
+    std::fs::File::create(&temporary_path)
+        .and_then(|mut file| {
+            file.write_all(format!("{format}\n").as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary_path, &path))
+        .map_err(|error| format!("Failed to record the store format in {path}: {error}"))
+}
+
+/// The store's format and the migrations it needs, without changing anything
+pub fn status(storage: &Storage) -> Result<FormatStatus, String> {
+    let format = stored_format(storage)?;
+    let pending = match format {
+        Some(format) => MIGRATIONS
+            .iter()
+            .filter(|migration| migration.format > format)
+            .map(|migration| PendingMigration {
+                format: migration.format,
+                name: migration.name,
+                description: migration.description,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    Ok(FormatStatus {
+        data_dir: storage.data_dir.clone(),
+        format,
+        supported: CURRENT_FORMAT,
+        pending,
+    })
+}
+
+/// Bring the store up to [`CURRENT_FORMAT`], running the migrations it is missing in
+/// order. Stores of a newer format are refused, and read-only instances only check, as
+/// the writing instance owns the files.
+pub fn migrate(storage: &Storage) -> Result<(), String> {
+    let status = status(storage)?;
+    let Some(format) = status.format else {
+        if !storage.read_only {
+            store_format(storage, CURRENT_FORMAT)?;
+        }
+        return Ok(());
+    };
+    if format > CURRENT_FORMAT {
+        return Err(format!(
+            "Data directory {} has format {format}, this binary only supports formats up to {CURRENT_FORMAT}",
+            storage.data_dir
+        ));
+    }
+    if status.pending.is_empty() {
+        return Ok(());
+    }
+    if storage.read_only {
+        return Err(format!(
+            "Data directory {} has format {format} and needs migrating to format {CURRENT_FORMAT}, start a writing instance on it first",
+            storage.data_dir
+        ));
+    }
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.format > format)
+    {
+        println!(
+            "Migrating {} to format {} ({}): {}",
+            storage.data_dir, migration.format, migration.name, migration.description
+        );
+        let started = Instant::now();
+        let mut progress = Progress::new(migration.name);
+        (migration.run)(storage, &mut progress)
+            .map_err(|error| format!("Migration {} failed: {error}", migration.name))?;
+        store_format(storage, migration.format)?;
+        println!(
+            "Migration {} done in {:?}: {} items, {} changed",
+            migration.name,
+            started.elapsed(),
+            progress.done,
+            progress.changed
+        );
+    }
+    Ok(())
+}
+
+/// Format 1: metadata used to only name the latest version, which readers then made up
+/// an entry for. Gives every such item a committed entry of its latest version.
+fn list_latest_versions(storage: &Storage, progress: &mut Progress) -> Result<(), String> {
+    let item_ids = cold_tier::item_ids(storage)?;
+    progress.total = item_ids.len();
+    for item_id in item_ids {
+        let changed = file_persistence::list_latest_version(storage, &item_id)
+            .map_err(|error| format!("item {item_id}: {error}"))?;
+        progress.advance(changed);
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Await point a writer or reader of a version is at, reported in `/admin/streams` so a
+/// stream that hangs shows where it hangs
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum StreamPhase {
+    /// The writer waits for the next chunk of the request body
+    ReadingBody,
+    /// The writer waits for its I/O turn or for a chunk to be written to the data file
+    WritingChunk,
+    /// The writer waits for the data file to be synced to disk
+    Fsync,
+    /// The writer rewrites, moves or records the version it received all of
+    Committing,
+    /// The reader reads a chunk from the data file
+    ReadingChunk,
+    /// The reader caught up with the writer and waits for more bytes
+    WaitingForData,
+    /// The reader handed out a chunk and waits to be asked for the next one
+    Sending,
+}
+
+impl StreamPhase {
+    const ALL: [StreamPhase; 7] = [
+        Self::ReadingBody,
+        Self::WritingChunk,
+        Self::Fsync,
+        Self::Committing,
+        Self::ReadingChunk,
+        Self::WaitingForData,
+        Self::Sending,
+    ];
+}
+
+/// Milliseconds since the process first tracked a phase
+fn now_ms() -> u64 {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+    STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Phase of one stream and when it was entered. Only the stream itself moves it on, so
+/// two atomics are enough and a transition costs no more than two stores.
+pub struct PhaseTracker {
+    phase: AtomicU8,
+    since_ms: AtomicU64,
+}
+
+/// What an admin sees of a stream's phase
+#[derive(Serialize)]
+pub struct PhaseStatus {
+    pub phase: StreamPhase,
+    /// Milliseconds spent in `phase` so far
+    pub elapsed_ms: u64,
+}
+
+impl PhaseTracker {
+    pub fn new(phase: StreamPhase) -> Self {
+        Self {
+            phase: AtomicU8::new(phase as u8),
+            since_ms: AtomicU64::new(now_ms()),
+        }
+    }
+
+    /// Move on to `phase`. Entering the phase the stream is already in keeps the time it
+    /// entered it.
+    pub fn enter(&self, phase: StreamPhase) {
+        if self.phase.load(Ordering::Relaxed) != phase as u8 {
+            self.since_ms.store(now_ms(), Ordering::Release);
+            self.phase.store(phase as u8, Ordering::Release);
+        }
+    }
+
+    pub fn status(&self) -> PhaseStatus {
+        let phase = StreamPhase::ALL[self.phase.load(Ordering::Acquire) as usize];
+        PhaseStatus {
+            phase,
+            elapsed_ms: now_ms().saturating_sub(self.since_ms.load(Ordering::Acquire)),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Points in the read-while-write protocol and in a commit where their tests can hold a
+//! reader or the writer, to play an interleaving of the two step by step instead of
+//! hoping a race happens, or to see what a crash there leaves on disk. A test can also
+//! fail the step after a point. Outside of the crate's own tests passing a point does
+//! nothing.
+//!
+//! A test scripts a [`Pause`] at a point of a version, waits for a task to arrive there,
+//! does whatever the other side should do in between, and lets the task go on:
+//!
+//! ```ignore
+//! let mut pause = sync_points::pause(&metadata_path, 1, SyncPoint::ReaderCaughtUp);
+//! let read = tokio::spawn(async move { reader.read_chunk().await });
+//! pause.arrived().await;
+//! writer.commit(&details).await?;
+//! pause.release();
+//! ```
+
+/// Where a task can be held, each passed once per step of the protocol
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SyncPoint {
+    /// A reader looked at the version's flags and size, before it reads what it found
+    ReaderLoadedState,
+    /// A reader found nothing more to read, before it waits for the writer
+    ReaderCaughtUp,
+    /// A reader got the version's shared file from the registry, before it attaches
+    ReaderFoundRegistered,
+    /// A reader found the version committed on disk, before it registers the file
+    ReaderRegistering,
+    /// The writer claimed the upload's outcome, before it syncs and commits
+    WriterCommitting,
+    /// The writer committed the version, before its readers are told
+    WriterFinishing,
+    /// A commit synced the data file, before it moves into the data directory
+    CommitMoving,
+    /// A commit moved the data file, before it syncs the data directory
+    CommitSyncingDir,
+    /// A commit synced the data directory, before it records the version
+    CommitRecording,
+    /// A rewrite of the item's metadata wrote the new one beside it, before it replaces
+    /// the old one. Passed for the latest version the new metadata lists.
+    MetadataReplacing,
+    /// A commit recorded the version, before it returns
+    CommitRecorded,
+}
+
+/// Pass `point` for version `version` of the item whose metadata is at `metadata_path`,
+/// the item as a test names it
+#[cfg(not(test))]
+#[inline(always)]
+pub fn reached(_metadata_path: &str, _version: u64, _point: SyncPoint) {}
+
+/// Pass `point` like [`reached`], then fail the step after it if a test scripted that
+#[cfg(not(test))]
+#[inline(always)]
+pub fn step(_metadata_path: &str, _version: u64, _point: SyncPoint) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub use scripted::{Failure, Pause, fail, pause, reached, step};
+
+#[cfg(test)]
+mod scripted {
+    use super::SyncPoint;
+
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::{Arc, Condvar, LazyLock, Mutex};
+    use std::time::Duration;
+
+    type Key = (String, u64, SyncPoint);
+
+    /// Pauses scripted and not reached yet, the first task to arrive takes the first one.
+    /// Keyed by the item's metadata path, which holds the test's own data directory, so
+    /// tests running at the same time do not take each other's pauses.
+    static SCRIPT: LazyLock<Mutex<HashMap<Key, VecDeque<Arc<Gate>>>>> =
+        LazyLock::new(Mutex::default);
+
+    /// Points whose next step fails, until the test's [`Failure`] is dropped
+    static FAILURES: LazyLock<Mutex<HashSet<Key>>> = LazyLock::new(Mutex::default);
+
+    #[derive(Default)]
+    struct Gate {
+        state: Mutex<GateState>,
+        changed: Condvar,
+    }
+
+    #[derive(Default)]
+    struct GateState {
+        arrived: bool,
+        released: bool,
+    }
+
+    /// Hold the task while a pause is scripted at `point`. The task blocks its thread,
+    /// the runtime is told so it moves its other tasks elsewhere, which needs the
+    /// multi-threaded runtime.
+    pub fn reached(metadata_path: &str, version: u64, point: SyncPoint) {
+        let key = (metadata_path.to_string(), version, point);
+        let gate = {
+            let mut script = SCRIPT.lock().unwrap();
+            let Some(pauses) = script.get_mut(&key) else {
+                return;
+            };
+            let gate = pauses.pop_front();
+            if pauses.is_empty() {
+                script.remove(&key);
+            }
+            gate
+        };
+        let Some(gate) = gate else {
+            return;
+        };
+        let wait = || {
+            let mut state = gate.state.lock().unwrap();
+            state.arrived = true;
+            gate.changed.notify_all();
+            let _released = gate
+                .changed
+                .wait_while(state, |state| !state.released)
+                .unwrap();
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(_) => tokio::task::block_in_place(wait),
+            Err(_) => wait(),
+        }
+    }
+
+    /// Pass `point` like [`reached`], then fail the step after it if [`fail`] scripted that
+    pub fn step(metadata_path: &str, version: u64, point: SyncPoint) -> std::io::Result<()> {
+        reached(metadata_path, version, point);
+        let key = (metadata_path.to_string(), version, point);
+        if FAILURES.lock().unwrap().contains(&key) {
+            return Err(std::io::Error::other(format!(
+                "failure injected after {point:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail the step after `point` for `version` of the item whose metadata is at
+    /// `metadata_path`, every time, until the returned [`Failure`] is dropped
+    pub fn fail(metadata_path: &str, version: u64, point: SyncPoint) -> Failure {
+        let key = (metadata_path.to_string(), version, point);
+        FAILURES.lock().unwrap().insert(key.clone());
+        Failure { key }
+    }
+
+    /// A step scripted to fail, see [`fail`]
+    pub struct Failure {
+        key: Key,
+    }
+
+    impl Drop for Failure {
+        fn drop(&mut self) {
+            FAILURES.lock().unwrap().remove(&self.key);
+        }
+    }
+
+    /// Hold the next task passing `point` for `version` of the item whose metadata is at
+    /// `metadata_path`. Further pauses at the same point hold the tasks after it.
+    pub fn pause(metadata_path: &str, version: u64, point: SyncPoint) -> Pause {
+        let gate = Arc::new(Gate::default());
+        SCRIPT
+            .lock()
+            .unwrap()
+            .entry((metadata_path.to_string(), version, point))
+            .or_default()
+            .push_back(gate.clone());
+        Pause {
+            gate,
+            key: (metadata_path.to_string(), version, point),
+        }
+    }
+
+    /// A task held at a point, let go by [`Pause::release`] or when the pause is dropped
+    pub struct Pause {
+        gate: Arc<Gate>,
+        key: Key,
+    }
+
+    impl Pause {
+        /// Wait for a task to arrive at the point, failing the test after 5 seconds
+        pub async fn arrived(&self) {
+            let gate = self.gate.clone();
+            let arrived = tokio::task::spawn_blocking(move || {
+                let state = gate.state.lock().unwrap();
+                let (state, _) = gate
+                    .changed
+                    .wait_timeout_while(state, Duration::from_secs(5), |state| !state.arrived)
+                    .unwrap();
+                state.arrived
+            })
+            .await
+            .unwrap();
+            assert!(
+                arrived,
+                "no task arrived at {:?} within 5 seconds",
+                self.key.2
+            );
+        }
+
+        pub fn release(self) {}
+    }
+
+    impl Drop for Pause {
+        fn drop(&mut self) {
+            // A pause nobody reached is taken out of the script, not left for a later test
+            let mut script = SCRIPT.lock().unwrap();
+            if let Some(pauses) = script.get_mut(&self.key) {
+                pauses.retain(|gate| !Arc::ptr_eq(gate, &self.gate));
+                if pauses.is_empty() {
+                    script.remove(&self.key);
+                }
+            }
+            drop(script);
+            self.gate.state.lock().unwrap().released = true;
+            self.gate.changed.notify_all();
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Transforms are meant to be small, larger specs are rejected
+const MAX_SPEC_ENTRIES: usize = 1000;
+const MAX_NAME_LENGTH: usize = 64;
+
+/// A named reshaping of items applied while they are read with `?transform=name`.
+/// Properties are matched by their stored name; dropping or allowing happens before
+/// renaming.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TransformSpec {
+    /// New names of properties, by their stored name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<String, String>,
+    /// Properties left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop: Vec<String>,
+    /// Only these properties are kept, unnamed ones are left out too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>,
+    /// Root element wrapped around the properties instead of the stored `<item>` envelope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<String>,
+}
+
+impl TransformSpec {
+    /// A short digest of the spec, which changes whenever the spec is changed
+    pub fn digest(&self) -> String {
+        let spec = serde_json::to_vec(self).unwrap_or_default();
+        let digest = format!("{:x}", Sha256::digest(&spec));
+        digest[..12].to_string()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.drop.is_empty() && self.allow.is_some() {
+            return Err(
+                "drop and allow cannot be combined, list the kept properties in allow".to_string(),
+            );
+        }
+        let entries = self.rename.len()
+            + self.drop.len()
+            + self.allow.as_ref().map_or(0, |allow| allow.len());
+        if entries > MAX_SPEC_ENTRIES {
+            return Err(format!(
+                "Transforms may list at most {MAX_SPEC_ENTRIES} properties, this one lists {entries}"
+            ));
+        }
+        for (from, to) in &self.rename {
+            if from.is_empty() || to.is_empty() {
+                return Err(format!(
+                    "rename maps {from:?} to {to:?}, property names must not be empty"
+                ));
+            }
+        }
+        let listed = self.drop.iter().chain(self.allow.iter().flatten());
+        if let Some(empty) = listed.into_iter().position(String::is_empty) {
+            return Err(format!(
+                "Entry {empty} of {} is empty, property names must not be empty",
+                if self.drop.is_empty() {
+                    "allow"
+                } else {
+                    "drop"
+                }
+            ));
+        }
+        if let Some(envelope) = &self.envelope {
+            validate_element_name(envelope).map_err(|error| {
+                format!("envelope {envelope:?} is not a valid element name: {error}")
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Narrow the transform down to the properties stored as `names`, on top of what it
+    /// already allows or drops
+    pub fn keep_only(&mut self, names: &[String]) {
+        let kept = names
+            .iter()
+            .filter(|name| {
+                self.allow.as_ref().is_none_or(|allow| allow.contains(name))
+                    && !self.drop.contains(name)
+            })
+            .cloned()
+            .collect();
+        self.allow = Some(kept);
+        self.drop.clear();
+    }
+}
+
+/// Element names are kept to the ASCII subset of XML names without namespaces
+fn validate_element_name(name: &str) -> Result<(), String> {
+    let mut characters = name.chars();
+    match characters.next() {
+        None => return Err("it is empty".to_string()),
+        Some(first) if !first.is_ascii_alphabetic() && first != '_' => {
+            return Err("it must start with a letter or an underscore".to_string());
+        }
+        Some(_) => {}
+    }
+    if let Some(invalid) = characters
+        .find(|character| !character.is_ascii_alphanumeric() && !"-_.".contains(*character))
+    {
+        return Err(format!(
+            "{invalid:?} is not allowed, use letters, digits, '-', '_' and '.'"
+        ));
+    }
+    if name.to_ascii_lowercase().starts_with("xml") {
+        return Err("names starting with \"xml\" are reserved".to_string());
+    }
+    Ok(())
+}
+
+/// Transform names end up in query strings, so they are kept simple
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Transform names must be 1 to {MAX_NAME_LENGTH} characters long"
+        ));
+    }
+    if !name
+        .chars()
+        .all(|character| character.is_ascii_alphanumeric() || "-_".contains(character))
+    {
+        return Err(format!(
+            "Transform name {name:?} may only contain letters, digits, '-' and '_'"
+        ));
+    }
+    Ok(())
+}
+
+fn transforms_path(storage: &Storage) -> String {
+    storage.path(".transforms.json")
+}
+
+/// All named transforms of the instance
+pub fn load_all(storage: &Storage) -> Result<BTreeMap<String, TransformSpec>, String> {
+    match std::fs::read(transforms_path(storage)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|error| format!("Transforms file is corrupt: {error}")),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(format!("Transforms read error: {error}")),
+    }
+}
+
+pub fn load(storage: &Storage, name: &str) -> Result<Option<TransformSpec>, String> {
+    Ok(load_all(storage)?.remove(name))
+}
+
+/// Add or replace a transform, or remove it when `spec` is `None`. Returns whether a
+/// transform of that name existed before. The file is replaced through a rename so
+/// readers never observe a half written one.
+pub fn store(storage: &Storage, name: &str, spec: Option<TransformSpec>) -> Result<bool, String> {
+    let _guard = storage.transforms_lock.lock().unwrap();
+    let mut transforms = load_all(storage)?;
+    let existed = match spec {
+        Some(spec) => transforms.insert(name.to_string(), spec).is_some(),
+        None => transforms.remove(name).is_some(),
+    };
+    let path = transforms_path(storage);
+    let temporary_path = format!("{path}.tmp");
+    let bytes = serde_json::to_vec_pretty(&transforms).map_err(|error| error.to_string())?;
+    std::fs::write(&temporary_path, bytes)
+        .map_err(|error| format!("Transforms write error: {error}"))?;
+    std::fs::rename(&temporary_path, &path)
+        .map_err(|error| format!("Transforms write error: {error}"))?;
+    Ok(existed)
+}
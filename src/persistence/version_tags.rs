@@ -0,0 +1,48 @@
+use crate::persistence::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Named pointers to committed versions of an item, such as `prod` or `staging`, stored
+/// next to the item's metadata in `{item_id}_tags.json`.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VersionTags {
+    pub tags: BTreeMap<String, u64>,
+}
+
+impl VersionTags {
+    /// Names of the tags pointing at `version`
+    pub fn tags_of(&self, version: u64) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter(|(_, tagged)| **tagged == version)
+            .map(|(tag, _)| tag.clone())
+            .collect()
+    }
+}
+
+fn tags_path(storage: &Storage, item_id: &str) -> String {
+    storage.path(&format!("{item_id}_tags.json"))
+}
+
+/// Load the tags of an item, items that were never tagged have none
+pub fn load(storage: &Storage, item_id: &str) -> Result<VersionTags, String> {
+    match std::fs::read(tags_path(storage, item_id)) {
+        Ok(bytes) => {
+            serde_json::from_slice(&bytes).map_err(|error| format!("Tags file is corrupt: {error}"))
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(VersionTags::default()),
+        Err(error) => Err(format!("Tags read error: {error}")),
+    }
+}
+
+/// Replace the tags of an item. The new file is written next to the old one and renamed
+/// over it so a tag is never observed half moved.
+pub fn store(storage: &Storage, item_id: &str, tags: &VersionTags) -> Result<(), String> {
+    let path = tags_path(storage, item_id);
+    let temporary_path = format!("{path}.tmp");
+    let bytes = serde_json::to_vec_pretty(tags).map_err(|error| error.to_string())?;
+    std::fs::write(&temporary_path, bytes).map_err(|error| format!("Tags write error: {error}"))?;
+    std::fs::rename(&temporary_path, &path).map_err(|error| format!("Tags write error: {error}"))
+}
@@ -0,0 +1,141 @@
+use crate::metrics::Metrics;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::sync::futures::Notified;
+
+/// Uploads sent with `X-Write-Queue` that found their item claimed by another upload,
+/// waiting for it to commit or abort. Each item's waiters are served in the order they
+/// arrived: only the one at the head of the item's queue tries to claim the item.
+pub struct WriteQueue {
+    waiting: Mutex<Waiting>,
+    /// Uploads that may wait for one item, further ones are refused
+    max_depth: usize,
+    /// Uploads that may wait across all items
+    max_total: usize,
+    /// Signalled whenever an upload leaves a queue and when the queues close
+    changed: Notify,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct Waiting {
+    /// Tickets of the uploads waiting for each item, the head first
+    items: HashMap<String, VecDeque<u64>>,
+    total: usize,
+    next_ticket: u64,
+    /// The instance is shutting down, nobody waits any more
+    closed: bool,
+}
+
+/// Why an upload could not join its item's queue
+pub enum JoinError {
+    /// `max_depth` uploads already wait for the item
+    ItemFull {
+        max_depth: usize,
+    },
+    /// `max_total` uploads already wait across all items
+    Full {
+        max_total: usize,
+    },
+    Closed,
+}
+
+/// An upload's place in its item's queue, left when the ticket is dropped
+pub struct QueueTicket<'a> {
+    queue: &'a WriteQueue,
+    item_id: String,
+    ticket: u64,
+}
+
+impl WriteQueue {
+    pub fn new(max_depth: usize, max_total: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            waiting: Mutex::new(Waiting::default()),
+            max_depth,
+            max_total,
+            changed: Notify::new(),
+            metrics,
+        }
+    }
+
+    /// Line up behind the uploads already waiting for `item_id`
+    pub fn join(&self, item_id: &str) -> Result<QueueTicket<'_>, JoinError> {
+        let mut waiting = self.waiting.lock().unwrap();
+        if waiting.closed {
+            return Err(JoinError::Closed);
+        }
+        if waiting.total >= self.max_total {
+            return Err(JoinError::Full {
+                max_total: self.max_total,
+            });
+        }
+        let ticket = waiting.next_ticket;
+        let queue = waiting.items.entry(item_id.to_string()).or_default();
+        if queue.len() >= self.max_depth {
+            return Err(JoinError::ItemFull {
+                max_depth: self.max_depth,
+            });
+        }
+        queue.push_back(ticket);
+        waiting.next_ticket += 1;
+        waiting.total += 1;
+        self.metrics.write_queue_depth.add(1);
+        Ok(QueueTicket {
+            queue: self,
+            item_id: item_id.to_string(),
+            ticket,
+        })
+    }
+
+    /// Uploads waiting for `item_id`
+    pub fn depth(&self, item_id: &str) -> usize {
+        let waiting = self.waiting.lock().unwrap();
+        waiting.items.get(item_id).map_or(0, VecDeque::len)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.waiting.lock().unwrap().closed
+    }
+
+    /// Send every waiting upload away and refuse new ones, on shutdown
+    pub fn close(&self) {
+        self.waiting.lock().unwrap().closed = true;
+        self.changed.notify_waiters();
+    }
+
+    /// Resolves the next time an upload leaves a queue or the queues close
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+impl QueueTicket<'_> {
+    /// Place in the item's queue, 1 at its head
+    pub fn position(&self) -> usize {
+        let waiting = self.queue.waiting.lock().unwrap();
+        waiting
+            .items
+            .get(&self.item_id)
+            .and_then(|queue| queue.iter().position(|ticket| *ticket == self.ticket))
+            .map_or(0, |index| index + 1)
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.queue.waiting.lock().unwrap();
+        if let Some(queue) = waiting.items.get_mut(&self.item_id) {
+            queue.retain(|ticket| *ticket != self.ticket);
+            if queue.is_empty() {
+                waiting.items.remove(&self.item_id);
+            }
+        }
+        waiting.total -= 1;
+        self.queue.metrics.write_queue_depth.sub(1);
+        drop(waiting);
+        // The next upload may be at the head now
+        self.queue.changed.notify_waiters();
+    }
+}
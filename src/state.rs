@@ -0,0 +1,153 @@
+use crate::config::Config;
+use crate::log_control::LogControl;
+use crate::logic::commit_events::CommitEvents;
+use crate::logic::commit_hooks::CommitHooks;
+use crate::logic::consistency::ConsistencyTokens;
+use crate::logic::drain::Drain;
+use crate::logic::idempotency::IdempotencyKeys;
+use crate::logic::property_search::PropertyNames;
+use crate::logic::read_stats::ReadStats;
+use crate::logic::storage_quota::StorageQuota;
+use crate::logic::verification_sweep::VerificationSweep;
+use crate::logic::warmup::Warmup;
+use crate::metrics::Metrics;
+use crate::persistence::audit_log::AuditLog;
+use crate::persistence::debug_capture::DebugCaptures;
+use crate::persistence::fault_injection::FaultInjector;
+use crate::persistence::io_scheduler::IoScheduler;
+use crate::persistence::item_persistence::ItemStreamBackend;
+use crate::persistence::read_sources::ReadSources;
+use crate::persistence::shared_file::SlowReaderLimit;
+use crate::persistence::storage::Storage;
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Everything one stream-db instance owns. Handlers receive it as axum state, so several
+/// instances with separate data directories can be served from one process.
+pub struct StreamDb {
+    pub config: Config,
+    pub storage: Arc<Storage>,
+    pub metrics: Arc<Metrics>,
+    /// Only one version is reindexed at a time, on top of the per-task rate limit
+    pub reindex_permits: Semaphore,
+    /// Only one metadata backfill runs at a time
+    pub backfill_permits: Semaphore,
+    /// Read counters not yet flushed to the items' stats files
+    pub read_stats: ReadStats,
+    /// Failures injected into uploads and reads, only with `STREAM_DB_FAULT_INJECTION`
+    pub faults: FaultInjector,
+    /// Item patterns whose uploads are captured for debugging, none by default
+    pub debug_captures: DebugCaptures,
+    /// Cap on the bytes stored by the instance, none by default
+    pub quota: StorageQuota,
+    /// Progress of the startup warm-up
+    pub warmup: Warmup,
+    /// Signs the consistency tokens handed to producers and checks those of readers
+    pub consistency: ConsistencyTokens,
+    /// Commits of this instance and those found by the data directory watch
+    pub commit_events: CommitEvents,
+    /// Programs run after the commits of this instance, none by default
+    pub commit_hooks: CommitHooks,
+    /// Responses replayed to retries of requests carrying an `Idempotency-Key`
+    pub idempotency: IdempotencyKeys,
+    /// Streams in flight, waited for by a graceful shutdown
+    pub drain: Drain,
+    /// Which versions hold which property names, only with `STREAM_DB_PROPERTY_NAME_INDEX`
+    pub property_names: PropertyNames,
+    /// Progress of the background verification sweep, only with `STREAM_DB_VERIFY_SWEEP`
+    pub verification: VerificationSweep,
+    /// Requests that changed data, only with `STREAM_DB_AUDIT_LOG_FILE`
+    pub audit_log: AuditLog,
+    /// The log level and debug item patterns of the instance, also held by the storage
+    pub log: Arc<LogControl>,
+    /// Opens the writers and readers of versions in place of the data directory, none
+    /// unless the instance was created [`with_backend`](StreamDb::with_backend)
+    pub backend: Option<Arc<dyn ItemStreamBackend>>,
+}
+
+pub type AppState = Arc<StreamDb>;
+
+impl StreamDb {
+    pub fn new(config: Config) -> AppState {
+        Arc::new(Self::create(config, None))
+    }
+
+    /// An instance whose versions are written to and read from `backend`, e.g. the
+    /// in-memory one of `test_util`, rather than the data directory
+    pub fn with_backend(config: Config, backend: Arc<dyn ItemStreamBackend>) -> AppState {
+        Arc::new(Self::create(config, Some(backend)))
+    }
+
+    fn create(config: Config, backend: Option<Arc<dyn ItemStreamBackend>>) -> Self {
+        let log = Arc::new(LogControl::new(
+            config.log_level,
+            config.debug_items.clone(),
+        ));
+        let metrics = Arc::new(Metrics::new());
+        metrics.configure(config.metric_datasets());
+        Self {
+            storage: Arc::new(
+                Storage::new(
+                    config.data_dir.clone(),
+                    config.io_engine,
+                    config.fsync_policy,
+                    IoScheduler::new(
+                        config.io_scheduling,
+                        config.io_fast_slots,
+                        config.io_heavy_slots,
+                        config.io_small_item_bytes,
+                        metrics.clone(),
+                    ),
+                    config.read_only,
+                    metrics.clone(),
+                    config.max_open_files,
+                )
+                .with_journal_interval(config.journal_interval_mb.saturating_mul(1024 * 1024))
+                .with_inflight_dir(config.inflight_dir.clone())
+                .with_log_control(log.clone())
+                .with_write_queue(config.write_queue_max_depth, config.write_queue_max_total)
+                .with_in_flight_limits(config.max_in_flight_per_item, config.max_in_flight_uploads)
+                .with_read_sources(ReadSources::new(
+                    config.read_sources.clone(),
+                    config.read_source_policy,
+                    Duration::from_millis(config.read_source_budget_ms),
+                    config.cold_dir.clone(),
+                ))
+                .with_miss_ttl(Duration::from_millis(config.negative_cache_ttl_ms))
+                .with_slow_reader_limit(config.slow_reader_max_lag_mb.map(|max_lag_mb| {
+                    SlowReaderLimit {
+                        max_lag_bytes: max_lag_mb.saturating_mul(1024 * 1024),
+                        policy: config.slow_reader_policy,
+                    }
+                })),
+            ),
+            faults: FaultInjector::new(config.fault_injection),
+            quota: StorageQuota::new(config.quota_bytes, config.inflight_quota_bytes),
+            consistency: ConsistencyTokens::new(config.node_id.clone(), config.secret.as_deref()),
+            debug_captures: DebugCaptures::new(
+                config.debug_capture_max_bytes,
+                config.debug_capture_max_count,
+            ),
+            idempotency: IdempotencyKeys::new(
+                config.idempotency_ttl_secs,
+                config.idempotency_max_keys,
+            ),
+            commit_hooks: CommitHooks::new(config.commit_hooks.clone(), config.hook_queue),
+            audit_log: AuditLog::new(config.audit_log_file.clone()),
+            log,
+            config,
+            metrics,
+            reindex_permits: Semaphore::new(1),
+            backfill_permits: Semaphore::new(1),
+            read_stats: ReadStats::default(),
+            warmup: Warmup::default(),
+            commit_events: CommitEvents::default(),
+            drain: Drain::default(),
+            property_names: PropertyNames::default(),
+            verification: VerificationSweep::default(),
+            backend,
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! In-memory stand-ins for the persistence traits, for unit tests of code built on top of
+//! them that should not touch a filesystem. Everything happens when the test says so:
+//! readers following an upload only see the bytes the test [`advance`](MockVersion::advance)s
+//! them to, so interleavings are deterministic.
+
+use crate::persistence::file_persistence::{OpenError, WriteError};
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::item_persistence::{
+    CommitDetails, ItemStreamBackend, ItemStreamReader, ItemStreamWriter,
+};
+use crate::persistence::property_index::PropertyIndex;
+
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Versions by item and version, handing out writers and readers of them the way the
+/// file backend does: one upload of a version at a time, readers following an upload
+/// or reading a committed version. Plugged into an instance with
+/// [`StreamDb::with_backend`](crate::state::StreamDb::with_backend), the component and
+/// logic layers above write and read their versions here.
+#[derive(Default, Clone)]
+pub struct MockBackend {
+    versions: Arc<Mutex<HashMap<(String, u64), MockVersion>>>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A writer of the version, refused while another upload of it runs or once it was
+    /// committed, like the file backend refuses them
+    pub fn writer(&self, item_id: &str, item_version: u64) -> Result<MockWriter, String> {
+        let mut versions = self.versions.lock().unwrap();
+        let key = (item_id.to_string(), item_version);
+        if let Some(version) = versions.get(&key) {
+            let state = version.state.lock().unwrap();
+            if state.commit.is_some() {
+                return Err(format!(
+                    "Version {item_version} of item {item_id} is already committed"
+                ));
+            }
+            if !state.aborted {
+                return Err(format!(
+                    "Version {item_version} of item {item_id} is being uploaded"
+                ));
+            }
+        }
+        let version = MockVersion::new(item_version);
+        versions.insert(key, version.clone());
+        Ok(MockWriter::new(version))
+    }
+
+    /// A reader of the version, following its upload if it is not committed yet
+    pub fn reader(&self, item_id: &str, item_version: u64) -> Result<MockReader, String> {
+        let versions = self.versions.lock().unwrap();
+        match versions.get(&(item_id.to_string(), item_version)) {
+            Some(version) if !version.is_aborted() => Ok(MockReader::follow(version)),
+            _ => Err("Item not found".to_string()),
+        }
+    }
+
+    /// The version, to advance its readers or look at what was written
+    pub fn version(&self, item_id: &str, item_version: u64) -> Option<MockVersion> {
+        self.versions
+            .lock()
+            .unwrap()
+            .get(&(item_id.to_string(), item_version))
+            .cloned()
+    }
+
+    /// Commit a version with `data` right away, as if it had been uploaded before
+    pub fn insert_committed(&self, item_id: &str, item_version: u64, data: &[u8]) -> MockVersion {
+        let version = MockVersion::new(item_version);
+        {
+            let mut state = version.state.lock().unwrap();
+            state.chunks.push(data.to_vec());
+            state.data.extend_from_slice(data);
+            state.visible = data.len();
+            state.commit = Some(VersionMetadata {
+                version: item_version,
+                size: Some(data.len() as u64),
+                epoch: Some(1),
+                ..Default::default()
+            });
+        }
+        self.versions
+            .lock()
+            .unwrap()
+            .insert((item_id.to_string(), item_version), version.clone());
+        version
+    }
+}
+
+#[async_trait]
+impl ItemStreamBackend for MockBackend {
+    fn open_writer(
+        &self,
+        item_id: &str,
+        item_version: u64,
+    ) -> Result<Box<dyn ItemStreamWriter>, WriteError> {
+        let latest = self
+            .versions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((item, _), version)| item == item_id && version.committed().is_some())
+            .map(|((_, version), _)| *version)
+            .max();
+        if let Some(current) = latest.filter(|latest| *latest >= item_version) {
+            return Err(WriteError::VersionConflict {
+                requested: item_version,
+                current,
+            });
+        }
+        let writer = self
+            .writer(item_id, item_version)
+            .map_err(WriteError::Locked)?;
+        Ok(Box::new(writer))
+    }
+
+    async fn open_reader(
+        &self,
+        item_id: &str,
+        item_version: u64,
+    ) -> Result<Box<dyn ItemStreamReader>, OpenError> {
+        let reader = self
+            .reader(item_id, item_version)
+            .map_err(OpenError::NotFound)?;
+        Ok(Box::new(reader))
+    }
+}
+
+struct VersionState {
+    item_version: u64,
+    /// Chunks as they were handed to the writer
+    chunks: Vec<Vec<u8>>,
+    data: Vec<u8>,
+    /// Bytes readers following the upload may read, see [`MockVersion::advance`]
+    visible: usize,
+    commit: Option<VersionMetadata>,
+    aborted: bool,
+    /// Error the next write fails with
+    fail_next_write: Option<String>,
+    /// Newer version the commit reports as committed meanwhile
+    superseded_by: Option<u64>,
+    /// Bytes a reader may fall behind what it could read before it fails as too slow
+    max_lag: Option<usize>,
+}
+
+/// One version of an item, shared by its writer, its readers and the test
+#[derive(Clone)]
+pub struct MockVersion {
+    state: Arc<Mutex<VersionState>>,
+    changed: Arc<Notify>,
+}
+
+impl MockVersion {
+    pub fn new(item_version: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(VersionState {
+                item_version,
+                chunks: Vec::new(),
+                data: Vec::new(),
+                visible: 0,
+                commit: None,
+                aborted: false,
+                fail_next_write: None,
+                superseded_by: None,
+                max_lag: None,
+            })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Let readers following the upload read `bytes` more of what was written
+    pub fn advance(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.visible = (state.visible + bytes).min(state.data.len());
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Let readers following the upload read everything written so far
+    pub fn advance_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.visible = state.data.len();
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Fail readers that fall more than `bytes` behind what they could read
+    pub fn set_max_lag(&self, bytes: usize) {
+        self.state.lock().unwrap().max_lag = Some(bytes);
+    }
+
+    /// Make the commit fail as if `newer` was committed while the upload ran
+    pub fn conflict_with(&self, newer: u64) {
+        self.state.lock().unwrap().superseded_by = Some(newer);
+    }
+
+    /// Make the next write fail with `error`
+    pub fn fail_next_write(&self, error: &str) {
+        self.state.lock().unwrap().fail_next_write = Some(error.to_string());
+    }
+
+    /// Bytes written so far
+    pub fn written_bytes(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    /// Chunks written so far, as they were handed to the writer
+    pub fn written_chunks(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().chunks.clone()
+    }
+
+    /// What the commit recorded, `None` until the version is committed
+    pub fn committed(&self) -> Option<VersionMetadata> {
+        self.state.lock().unwrap().commit.clone()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.lock().unwrap().aborted
+    }
+
+    /// Panics unless the version was committed with `expected` as its data
+    pub fn assert_committed(&self, expected: &[u8]) {
+        let state = self.state.lock().unwrap();
+        assert!(
+            state.commit.is_some(),
+            "version {} was not committed",
+            state.item_version
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&state.data),
+            String::from_utf8_lossy(expected),
+            "version {} was committed with other data",
+            state.item_version
+        );
+    }
+
+    /// Panics unless the upload of the version was aborted
+    pub fn assert_aborted(&self) {
+        let state = self.state.lock().unwrap();
+        assert!(
+            state.aborted,
+            "upload of version {} was not aborted",
+            state.item_version
+        );
+    }
+}
+
+/// Writer storing what it is handed in its [`MockVersion`]. Readers following the
+/// upload only see it once the test advances them.
+pub struct MockWriter {
+    version: MockVersion,
+    superseded_by: Option<u64>,
+}
+
+impl MockWriter {
+    /// A writer of a version of its own, outside any [`MockBackend`]
+    pub fn new(version: MockVersion) -> Self {
+        Self {
+            version,
+            superseded_by: None,
+        }
+    }
+
+    /// The version written, shared with its readers
+    pub fn version(&self) -> &MockVersion {
+        &self.version
+    }
+
+    pub fn written_bytes(&self) -> Vec<u8> {
+        self.version.written_bytes()
+    }
+
+    pub fn fail_next_write(&self, error: &str) {
+        self.version.fail_next_write(error);
+    }
+
+    pub fn assert_committed(&self, expected: &[u8]) {
+        self.version.assert_committed(expected);
+    }
+}
+
+#[async_trait]
+impl ItemStreamWriter for MockWriter {
+    async fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
+        let mut state = self.version.state.lock().unwrap();
+        if state.aborted {
+            return Err("Upload was aborted".to_string());
+        }
+        if let Some(error) = state.fail_next_write.take() {
+            return Err(error);
+        }
+        state.data.extend_from_slice(&chunk);
+        state.chunks.push(chunk);
+        Ok(())
+    }
+
+    fn store_property_index(&mut self, _index: &PropertyIndex) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn commit(&mut self, details: &CommitDetails) -> Result<VersionMetadata, String> {
+        let mut state = self.version.state.lock().unwrap();
+        if state.aborted {
+            return Err("Upload was aborted".to_string());
+        }
+        if let Some(newer) = state.superseded_by {
+            self.superseded_by = Some(newer);
+            return Err(format!(
+                "Version {newer} was committed while version {} was uploaded",
+                state.item_version
+            ));
+        }
+        if details.bytes_received != state.data.len() as u64 {
+            return Err(format!(
+                "Byte accounting mismatch: {} handed to the writer, {} received by the writer",
+                details.bytes_received,
+                state.data.len()
+            ));
+        }
+        let committed = VersionMetadata {
+            version: state.item_version,
+            size: Some(state.data.len() as u64),
+            property_count: Some(details.property_count),
+            request_id: details.request_id.clone(),
+            epoch: Some(1),
+            extra_elements: Some(details.extra_elements.clone())
+                .filter(|extra_elements| !extra_elements.is_empty()),
+            extra_elements_truncated: Some(details.extra_elements_truncated.clone())
+                .filter(|truncated| !truncated.is_empty()),
+            provenance: details.provenance.clone(),
+            user_metadata: Some(details.user_metadata.clone())
+                .filter(|user_metadata| !user_metadata.is_empty()),
+            immutable_until: details.immutable_until.clone(),
+            segments: details.segments.clone(),
+            ..Default::default()
+        };
+        state.commit = Some(committed.clone());
+        state.visible = state.data.len();
+        drop(state);
+        self.version.changed.notify_waiters();
+        Ok(committed)
+    }
+
+    fn abort(&mut self) {
+        let mut state = self.version.state.lock().unwrap();
+        if state.commit.is_some() {
+            return;
+        }
+        state.aborted = true;
+        drop(state);
+        self.version.changed.notify_waiters();
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.version.is_aborted()
+    }
+
+    fn superseded_by(&self) -> Option<u64> {
+        self.superseded_by
+    }
+}
+
+/// Reader of a [`MockVersion`], or of a fixed script of chunks and errors
+pub struct MockReader {
+    source: ReaderSource,
+    aborted: bool,
+    too_slow: bool,
+}
+
+enum ReaderSource {
+    Script(VecDeque<Result<Vec<u8>, String>>),
+    Follow {
+        version: MockVersion,
+        position: usize,
+    },
+}
+
+impl MockReader {
+    /// A reader returning `steps` in order, then the end of the version
+    pub fn scripted(steps: Vec<Result<Vec<u8>, String>>) -> Self {
+        Self {
+            source: ReaderSource::Script(steps.into()),
+            aborted: false,
+            too_slow: false,
+        }
+    }
+
+    /// A reader of `version` from its start, waiting for the test to advance it while
+    /// the version is not committed
+    pub fn follow(version: &MockVersion) -> Self {
+        Self {
+            source: ReaderSource::Follow {
+                version: version.clone(),
+                position: 0,
+            },
+            aborted: false,
+            too_slow: false,
+        }
+    }
+}
+
+#[async_trait]
+impl ItemStreamReader for MockReader {
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let (version, position) = match &mut self.source {
+            ReaderSource::Script(steps) => return steps.pop_front().transpose(),
+            ReaderSource::Follow { version, position } => (version, position),
+        };
+        loop {
+            // Registered before the state is looked at, so no advance is missed
+            let changed = version.changed.clone();
+            let notified = changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let state = version.state.lock().unwrap();
+                if state.aborted {
+                    self.aborted = true;
+                    return Err("Upload was aborted".to_string());
+                }
+                if let Some(max_lag) = state.max_lag
+                    && state.visible - *position > max_lag
+                {
+                    self.too_slow = true;
+                    return Err(format!(
+                        "Reader too slow: {} bytes behind the upload, at most {max_lag} allowed",
+                        state.visible - *position
+                    ));
+                }
+                if *position < state.visible {
+                    let chunk = state.data[*position..state.visible].to_vec();
+                    *position = state.visible;
+                    return Ok(Some(chunk));
+                }
+                if state.commit.is_some() {
+                    return Ok(None);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), String> {
+        match &mut self.source {
+            ReaderSource::Follow { position, .. } => {
+                *position = offset as usize;
+                Ok(())
+            }
+            ReaderSource::Script(_) => Err("Reader does not support seeking".to_string()),
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    fn is_too_slow(&self) -> bool {
+        self.too_slow
+    }
+}
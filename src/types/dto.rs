@@ -0,0 +1,583 @@
+//! Request and response bodies of the HTTP and WebSocket API, in one place so the wire
+//! contract has a single definition. Reports built by the lower layers, such as
+//! [`VersionMetadata`] or [`StorageUsageReport`], are embedded as they are.
+//!
+//! Every type deserializes as well, for clients. Fields added in later releases are
+//! ignored by older clients, so none of the response types denies unknown fields.
+
+use crate::log_control::LogLevel;
+use crate::logic::storage_quota::StorageUsageReport;
+use crate::logic::stream_ingest::WriteStats;
+use crate::logic::write_limits::WriteLimits;
+use crate::persistence::debug_capture::CaptureTrace;
+use crate::persistence::file_handles::FileHandleReport;
+use crate::persistence::file_persistence::VersionStat;
+use crate::persistence::item_metadata::VersionMetadata;
+use crate::persistence::item_stats::VersionStats;
+use crate::persistence::read_sources::ReadSourceReport;
+use crate::persistence::storage::StorageLayout;
+use crate::persistence::transforms::TransformSpec;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Stable, machine-readable reason of a failed request. Messages may be reworded between
+/// releases, codes are not.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// A malformed query parameter, header or path
+    BadRequest,
+    /// The uploaded body is not UTF-8 or holds no property elements
+    InvalidXml,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    /// The version is not newer than the latest committed one
+    VersionConflict,
+    /// A newer version committed while the upload ran, which failed its commit
+    VersionSuperseded,
+    /// Another request is writing the item, retry once it finished
+    Locked,
+    /// As many uploads as allowed already run for the item or the instance, retry once
+    /// one finished
+    InFlightLimit,
+    /// Too many uploads already wait in the write queues, retry later
+    QueueFull,
+    /// The request conflicts with the state of the version, e.g. tags still point at it
+    Conflict,
+    /// The upload was interrupted or killed before it committed
+    Aborted,
+    /// The version was deleted, its files wait for the reads under way to finish
+    Deleted,
+    PayloadTooLarge,
+    /// The instance's storage quota would be exceeded
+    QuotaExceeded,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    /// The body does not look like the XML its `Content-Type` declares
+    UnsupportedMediaType,
+    /// A property limit of the item was exceeded
+    LimitExceeded,
+    /// A property value does not match its declared type
+    TypeMismatch,
+    /// A property name was repeated in an upload rejecting duplicates
+    DuplicateProperty,
+    /// The version has to be committed first
+    NotCommitted,
+    /// An `Idempotency-Key` was sent again with a different request
+    IdempotencyKeyReused,
+    /// The node cannot serve the request yet, retry after the `Retry-After` delay
+    Unavailable,
+    /// The instance only serves reads
+    ReadOnly,
+    /// A read waiting for its version to have enough data gave up
+    Timeout,
+    /// The version's data no longer matches its checksum and is quarantined
+    IntegrityFailure,
+    /// The version is immutable until the `immutable_until` in `details`
+    Immutable,
+    /// The request asks for something this instance does not do, e.g. replicated writes
+    NotImplemented,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest | Self::InvalidXml => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden | Self::Immutable => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::VersionConflict
+            | Self::VersionSuperseded
+            | Self::Locked
+            | Self::Conflict
+            | Self::IntegrityFailure => StatusCode::CONFLICT,
+            Self::InFlightLimit => StatusCode::LOCKED,
+            Self::Aborted | Self::Deleted => StatusCode::GONE,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            Self::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::LimitExceeded
+            | Self::TypeMismatch
+            | Self::DuplicateProperty
+            | Self::NotCommitted
+            | Self::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::ReadOnly => StatusCode::METHOD_NOT_ALLOWED,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BadRequest => "BAD_REQUEST",
+            Self::InvalidXml => "INVALID_XML",
+            Self::Unauthorized => "UNAUTHORIZED",
+            Self::Forbidden => "FORBIDDEN",
+            Self::NotFound => "NOT_FOUND",
+            Self::VersionConflict => "VERSION_CONFLICT",
+            Self::VersionSuperseded => "VERSION_SUPERSEDED",
+            Self::Locked => "LOCKED",
+            Self::InFlightLimit => "IN_FLIGHT_LIMIT",
+            Self::QueueFull => "QUEUE_FULL",
+            Self::Conflict => "CONFLICT",
+            Self::Aborted => "ABORTED",
+            Self::Deleted => "DELETED",
+            Self::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            Self::QuotaExceeded => "QUOTA_EXCEEDED",
+            Self::RangeNotSatisfiable => "RANGE_NOT_SATISFIABLE",
+            Self::ExpectationFailed => "EXPECTATION_FAILED",
+            Self::UnsupportedMediaType => "UNSUPPORTED_MEDIA_TYPE",
+            Self::LimitExceeded => "LIMIT_EXCEEDED",
+            Self::TypeMismatch => "TYPE_MISMATCH",
+            Self::DuplicateProperty => "DUPLICATE_PROPERTY",
+            Self::NotCommitted => "NOT_COMMITTED",
+            Self::IdempotencyKeyReused => "IDEMPOTENCY_KEY_REUSED",
+            Self::Unavailable => "UNAVAILABLE",
+            Self::ReadOnly => "READ_ONLY",
+            Self::Timeout => "TIMEOUT",
+            Self::IntegrityFailure => "INTEGRITY_FAILURE",
+            Self::Immutable => "IMMUTABLE",
+            Self::NotImplemented => "NOT_IMPLEMENTED",
+            Self::Internal => "INTERNAL",
+        }
+    }
+
+    /// The code of an error response that was not produced by an `ApiError`, such as
+    /// the rejections of axum's extractors
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized,
+            StatusCode::FORBIDDEN => Self::Forbidden,
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::CONFLICT => Self::Conflict,
+            StatusCode::GONE => Self::Aborted,
+            StatusCode::LOCKED => Self::InFlightLimit,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::INSUFFICIENT_STORAGE => Self::QuotaExceeded,
+            StatusCode::RANGE_NOT_SATISFIABLE => Self::RangeNotSatisfiable,
+            StatusCode::EXPECTATION_FAILED => Self::ExpectationFailed,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType,
+            StatusCode::TOO_MANY_REQUESTS => Self::QueueFull,
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable,
+            StatusCode::NOT_IMPLEMENTED => Self::NotImplemented,
+            status if status.is_server_error() => Self::Internal,
+            _ => Self::BadRequest,
+        }
+    }
+}
+
+/// Body of every non-2xx response, and the last WebSocket message of a failed stream:
+///
+/// ```json
+/// { "code": "VERSION_CONFLICT", "message": "...", "details": { "current": 3, "requested": 2 }, "request_id": "..." }
+/// ```
+///
+/// `details` is one of the `*Details` types below or a report of the layer that refused
+/// the request, `{}` when there is nothing to add.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub details: Value,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Summary of a successful write returned to the producer, the persisted part can be
+/// fetched again later from the receipt endpoint
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WriteReceipt {
+    pub item_id: String,
+    #[serde(flatten)]
+    pub committed: VersionMetadata,
+    pub limits_applied: WriteLimits,
+    /// The body as it was received
+    pub received: WriteStats,
+    /// Set for a dry run, which checked the upload but stored nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// Publish group the version was staged in, it is committed along with the group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_group: Option<String>,
+}
+
+/// Receipts of every committed version of an item, oldest first
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VersionListing {
+    pub item_id: String,
+    pub versions: Vec<VersionMetadata>,
+}
+
+/// Last message of a WebSocket upload that committed, the receipt with the consistency
+/// token the HTTP upload returns as a header
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WsWriteReceipt {
+    #[serde(flatten)]
+    pub receipt: WriteReceipt,
+    pub consistency_token: String,
+}
+
+/// Sent back for every data frame of a WebSocket upload once its bytes have been taken
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct UploadAck {
+    /// Bytes received so far
+    pub ack: u64,
+    /// Bytes of complete properties synced to disk so far, only counted with
+    /// `STREAM_DB_FSYNC=chunk`
+    pub durable: u64,
+}
+
+/// First frame of every WebSocket read, before any data
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadInfo {
+    pub item_id: String,
+    pub version: u64,
+    /// Whether the version is committed, otherwise the read follows its upload
+    pub finished: bool,
+    pub content_type: String,
+    /// Bytes stored so far
+    pub size: u64,
+    /// Properties the redaction policy drops or masks, like `X-Redactions-Applied`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redactions_applied: Option<u64>,
+    /// Schema defaults sent, like `X-Defaults-Applied`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defaults_applied: Option<u64>,
+    /// Version of the schema the defaults come from, like `X-Schema-Version`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u64>,
+}
+
+/// Last frame of a WebSocket read that reached the end of the version
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadSummary {
+    pub total_bytes: u64,
+    /// SHA-256 of the bytes sent, matching the receipt's checksum for a plain read
+    pub sha256: String,
+}
+
+/// Progress of a version that is still being uploaded, the details of the receipt
+/// endpoint's `LOCKED`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InFlightProgress {
+    pub item_id: String,
+    pub version: u64,
+    /// Always `uploading`
+    pub state: String,
+    pub bytes_written: u64,
+    /// Bytes synced to disk so far, committed versions are synced in full
+    pub bytes_durable: u64,
+}
+
+impl InFlightProgress {
+    pub fn uploading(
+        item_id: String,
+        version: u64,
+        bytes_written: u64,
+        bytes_durable: u64,
+    ) -> Self {
+        Self {
+            item_id,
+            version,
+            state: "uploading".to_string(),
+            bytes_written,
+            bytes_durable,
+        }
+    }
+}
+
+/// Body of `GET /items/{item_id}/stats`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ItemStatsResponse {
+    pub item_id: String,
+    #[serde(flatten)]
+    pub total: VersionStats,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub versions: Option<BTreeMap<u64, VersionStats>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ok,
+    /// The startup warm-up still runs
+    Warming,
+    /// The instance is shutting down
+    Draining,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceMode {
+    ReadWrite,
+    /// `STREAM_DB_READ_ONLY`, a replica
+    ReadOnly,
+}
+
+/// Body of `GET /health`, and its details while draining
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct HealthStatus {
+    pub mode: InstanceMode,
+    pub status: HealthState,
+}
+
+/// Body of `GET /admin/storage`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StorageReport {
+    pub usage: StorageUsageReport,
+    pub file_handles: FileHandleReport,
+    pub layout: StorageLayout,
+    pub read_sources: ReadSourceReport,
+}
+
+/// Body of `PUT /admin/transforms/{name}`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TransformSaved {
+    pub name: String,
+    /// Whether a transform of that name existed before
+    pub replaced: bool,
+    pub spec: TransformSpec,
+}
+
+/// Body of `DELETE /admin/transforms/{name}`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TransformDeleted {
+    pub name: String,
+    pub deleted: bool,
+}
+
+/// Body of `POST /items/{item_id}/{version}/presign`, every field is optional
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PresignRequest {
+    /// Seconds the URL stays valid, one hour by default
+    pub expires_in_secs: Option<u64>,
+    /// `START-END` (inclusive) to restrict the URL to those bytes of the version
+    pub range: Option<String>,
+}
+
+/// Body of `POST /publish-groups`, every field is optional
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CreatePublishGroupRequest {
+    /// Seconds until the group is aborted unless it was committed, one hour by default
+    pub ttl_secs: Option<u64>,
+}
+
+/// Body of `PUT /items/{item_id}/{version}/immutability`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImmutabilityRequest {
+    /// RFC 3339 timestamp, no earlier than the version's current one
+    pub immutable_until: String,
+}
+
+/// Response of `POST /items/{item_id}/{version}/presign`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PresignResponse {
+    /// Path and query of the read, relative to the instance
+    pub url: String,
+    /// RFC 3339 timestamp in UTC
+    pub expires_at: String,
+    pub range: Option<String>,
+}
+
+/// Body of `PUT /admin/debug-captures` and of its response
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DebugCapturePatterns {
+    /// Items whose uploads are captured, an empty list turns capturing off
+    pub item_patterns: Vec<String>,
+}
+
+/// Body of `PUT /admin/log-level`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogLevelRequest {
+    pub level: LogLevel,
+    /// Seconds until the configured level applies again, never when unset
+    pub ttl_secs: Option<u64>,
+}
+
+/// Body of `PUT /admin/debug-items`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DebugItemsRequest {
+    /// Items whose streams write debug lines, an empty list turns them off
+    pub item_patterns: Vec<String>,
+    /// Seconds until the configured patterns apply again, never when unset
+    pub ttl_secs: Option<u64>,
+}
+
+/// Body of `GET /admin/debug-captures`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DebugCaptureList {
+    pub item_patterns: Vec<String>,
+    pub captures: Vec<CaptureTrace>,
+}
+
+/// An entry of a `POST /items/stat-batch` response: the stat of a version, or the error
+/// its request was refused with, in the same place
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum StatBatchEntry {
+    Refused(StatRefusal),
+    Stat(VersionStat),
+}
+
+/// A request of a stat batch that was refused on its own, the others are still stat-ed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatRefusal {
+    pub item_id: String,
+    /// The status the request would have been answered with on its own
+    pub status: u16,
+    pub error: ErrorBody,
+}
+
+/// Details of a malformed path parameter or of a field naming one
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InvalidParameterDetails {
+    /// Name of the parameter, e.g. `version` or `destination.item_id`
+    pub parameter: String,
+    /// The value as sent, a string or a number
+    pub value: Value,
+}
+
+/// Details of a version that is not above the item's latest one
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct VersionConflictDetails {
+    pub requested: u64,
+    pub current: u64,
+}
+
+/// Details of an upload that gave up waiting in its item's write queue
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct WriteQueueDetails {
+    /// Where the upload was in the queue when it gave up, 1 at its head
+    pub queue_position: usize,
+    pub queue_depth: usize,
+    pub waited_ms: u64,
+}
+
+/// Details of a version too far above the latest one, shaped like a limit violation
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VersionJumpDetails {
+    /// Always `max_version_jump`
+    pub limit: String,
+    pub max: u64,
+    pub actual: u64,
+    pub requested: u64,
+    pub current: u64,
+}
+
+/// Details of an upload that stopped at `bytes_received`
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct BytesReceivedDetails {
+    pub bytes_received: u64,
+}
+
+/// Details of a stream that was cancelled, see `reason`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CancelledDetails {
+    /// `killed`, `shutdown`, `timeout` or `client_gone`
+    pub reason: String,
+    /// Body bytes an upload received before it was cancelled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_received: Option<u64>,
+}
+
+/// Details of an upload body whose first bytes contradict its declared type
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ContentSniffDetails {
+    /// `json`, `gzip` or `unknown`
+    pub detected: String,
+    /// Offset of the first byte that is not whitespace
+    pub byte_offset: u64,
+    /// Up to 8 bytes from there, in hex
+    pub first_bytes: String,
+}
+
+/// Details of an upload body that is not UTF-8
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct InvalidUtf8Details {
+    pub byte_offset: u64,
+}
+
+/// Details of a deletion or reset refused because of other versions or tags
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockingVersionsDetails {
+    pub versions: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockingTagsDetails {
+    pub tags: Vec<String>,
+}
+
+/// Details of a read starting past the last property
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct PropertyOutOfRangeDetails {
+    pub requested: u64,
+    pub property_count: u64,
+}
+
+/// Details of a read of a segment the version does not have
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct SegmentOutOfRangeDetails {
+    pub requested: u64,
+    pub segment_count: u64,
+}
+
+/// Details of a presigned read whose range starts past the end of the version
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ByteRangeDetails {
+    pub range: String,
+    pub size: u64,
+}
+
+/// Details of a byte range asked of a version stored compressed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncodedRangeDetails {
+    pub range: String,
+    pub content_encoding: String,
+    /// Size of the stored bytes, once the version is committed
+    pub size: Option<u64>,
+}
+
+/// Details of a read that gave up waiting for `min_bytes` or `wait_for`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WaitTimedOutDetails {
+    pub min_bytes: Option<u64>,
+    /// `finished` when the read waited for the commit
+    pub wait_for: Option<String>,
+    pub waited_secs: u64,
+    pub bytes_available: u64,
+}
+
+/// Details of an `as-of` read finding no version
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AsOfDetails {
+    /// RFC 3339 timestamp in UTC
+    pub as_of: String,
+}
+
+/// Details of a tagged read finding no tag
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TagDetails {
+    pub tag: String,
+}
+
+/// Details of an `Idempotency-Key` sent again with another request
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdempotencyKeyDetails {
+    /// Method and URI of the request the key was first used for
+    pub request: String,
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, properties};
+use serde_json::Value;
+
+/// The audit records written so far, once there are `count` of them
+async fn audit_records(path: &str, count: usize) -> Vec<Value> {
+    common::eventually(|| async {
+        let records: Vec<Value> = std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (records.len() == count).then_some(records)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn requests_changing_data_are_audited_when_their_body_ends() {
+    let dir = TestDir::new("audit-log");
+    let audit_log = dir.join("audit.jsonl");
+    let instance = TestInstance::start_in(dir, |config| {
+        config.audit_log_file = Some(audit_log.clone());
+    });
+    let body = properties(4);
+    let response = instance
+        .send(common::upload_request("item", 1, &body))
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let request_id = response.headers()["X-Request-Id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let (_, receipt) = common::text(response).await;
+    // Reads are not audited
+    assert_eq!(instance.read("item", 1).await.1, body);
+    let (status, _) = instance.upload("item", 0, "<property>").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let records = audit_records(&audit_log, 2).await;
+    let upload = &records[0];
+    assert_eq!(upload["method"], "POST");
+    assert_eq!(upload["path"], "/write-item-stream/item/1");
+    assert_eq!(upload["item_id"], "item");
+    assert_eq!(upload["version"], "1");
+    assert_eq!(upload["status"], 201);
+    assert_eq!(upload["bytes_in"], body.len());
+    assert_eq!(upload["bytes_out"], receipt.len());
+    assert_eq!(upload["termination"], "completed");
+    assert_eq!(upload["request_id"], request_id.as_str());
+    assert_eq!(records[1]["status"], 400);
+    assert_eq!(records[1]["termination"], "completed");
+}
+
+#[tokio::test]
+async fn uploads_cut_off_by_their_client_are_audited_as_aborted() {
+    let dir = TestDir::new("audit-log-aborted");
+    let audit_log = dir.join("audit.jsonl");
+    let instance = TestInstance::start_in(dir, |config| {
+        config.audit_log_file = Some(audit_log.clone());
+    });
+    let (mut upload, response) = instance.start_upload("item", 1, 1000);
+    upload.send("<property name=\"p\">value</property>");
+    upload.break_off();
+    let _ = response.await;
+
+    let records = audit_records(&audit_log, 1).await;
+    assert_eq!(records[0]["termination"], "client_aborted");
+    assert!(records[0]["bytes_in"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn transfers_are_measured_without_the_access_log() {
+    let instance = TestInstance::start("transfer-metrics");
+    let body = properties(4);
+    instance.upload("item", 1, &body).await;
+    instance.read("item", 1).await;
+
+    // The upload and the read; the metrics request itself has not ended yet
+    let (_, rendered) = instance.request(Method::GET, "/metrics").await;
+    assert!(
+        rendered.contains("# TYPE stream_db_transfer_sent_bytes histogram"),
+        "{rendered}"
+    );
+    assert!(rendered.contains("stream_db_transfer_received_bytes_bucket{le=\"1024\"} "));
+    assert!(rendered.contains("stream_db_transfer_duration_milliseconds_count 2\n"));
+    assert!(rendered.contains(&format!(
+        "stream_db_transfer_received_bytes_sum {}\n",
+        body.len()
+    )));
+}
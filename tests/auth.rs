@@ -0,0 +1,39 @@
+//! The bearer tokens of the admin API
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, error_code, text};
+
+async fn get_with(
+    instance: &TestInstance,
+    uri: &str,
+    authorization: Option<&str>,
+) -> (StatusCode, String) {
+    let mut request = Request::builder().uri(uri);
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    text(instance.send(request.body(Body::empty()).unwrap()).await).await
+}
+
+#[tokio::test]
+async fn the_admin_api_takes_its_token_only() {
+    let instance = TestInstance::start("admin-token");
+    let (status, body) = get_with(&instance, "/admin/streams", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{body}");
+    // Same length as the token, differing in the last byte only
+    let almost = format!("Bearer {}X", &ADMIN_TOKEN[..ADMIN_TOKEN.len() - 1]);
+    let (status, body) = get_with(&instance, "/admin/streams", Some(&almost)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error_code(&body), "FORBIDDEN");
+    let prefix = format!("Bearer {}", &ADMIN_TOKEN[..4]);
+    let (status, _) = get_with(&instance, "/admin/streams", Some(&prefix)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = get_with(&instance, "/admin/streams", Some(ADMIN_TOKEN)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = instance.admin(Method::GET, "/admin/streams", "").await;
+    assert_eq!(status, StatusCode::OK);
+}
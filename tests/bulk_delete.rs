@@ -0,0 +1,147 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+
+/// The result lines of a bulk delete as (item_id, version, action), and its summary
+fn report(body: &str) -> (Vec<(String, u64, String)>, serde_json::Value) {
+    let mut lines: Vec<serde_json::Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let summary = lines.pop().unwrap()["summary"].clone();
+    let mut results: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            (
+                line["item_id"].as_str().unwrap().to_string(),
+                line["version"].as_u64().unwrap(),
+                line["action"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    results.sort();
+    (results, summary)
+}
+
+fn result(item_id: &str, version: u64, action: &str) -> (String, u64, String) {
+    (item_id.to_string(), version, action.to_string())
+}
+
+#[tokio::test]
+async fn versions_are_deleted_by_prefix_except_the_ones_in_use() {
+    // A reader that queued the whole version ahead of its client no longer holds it
+    let instance = TestInstance::start_with("bulk-delete", |config| {
+        config.read_prefetch_depth = 0;
+    });
+    for item_id in ["load-a", "load-b", "load-c", "keep"] {
+        let (status, _) = instance.upload(item_id, 1, &properties(1)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    instance.upload("load-a", 2, &properties(1)).await;
+    let (status, _) = instance
+        .json(
+            Method::PUT,
+            "/items/load-b/version-tags/prod",
+            r#"{"version": 1}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let reader = instance.open_read("load-c", 1).await;
+    let body = properties(1);
+    let (mut upload, response) = instance.start_upload("load-a", 3, body.len());
+
+    let (status, body) = instance
+        .admin(
+            Method::POST,
+            "/admin/bulk-delete",
+            r#"{"item_id_prefix": "load-", "dry_run": true}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (results, summary) = report(&body);
+    assert_eq!(
+        results,
+        [
+            result("load-a", 1, "would_delete"),
+            result("load-a", 2, "would_delete"),
+            result("load-b", 1, "skipped"),
+            result("load-c", 1, "skipped"),
+        ]
+    );
+    assert_eq!(summary["dry_run"], true);
+    assert_eq!(instance.read("load-a", 1).await.0, StatusCode::OK);
+
+    // The upload of a new version does not keep the committed ones from going
+    let (_, body) = instance
+        .admin(
+            Method::POST,
+            "/admin/bulk-delete",
+            r#"{"item_id_prefix": "load-"}"#,
+        )
+        .await;
+    let (results, summary) = report(&body);
+    assert_eq!(
+        results,
+        [
+            result("load-a", 1, "deleted"),
+            result("load-a", 2, "deleted"),
+            result("load-b", 1, "skipped"),
+            result("load-c", 1, "skipped"),
+        ]
+    );
+    assert_eq!(summary["matched"], 4);
+    assert_eq!(summary["deleted"], 2);
+    assert_eq!(summary["skipped"], 2);
+
+    // Once nothing holds them any more
+    drop(reader);
+    upload.break_off();
+    response.await.unwrap();
+    let (_, body) = instance
+        .admin(
+            Method::POST,
+            "/admin/bulk-delete",
+            r#"{"item_id_prefix": "load-"}"#,
+        )
+        .await;
+    let (results, summary) = report(&body);
+    assert_eq!(
+        results,
+        [
+            result("load-b", 1, "skipped"),
+            result("load-c", 1, "deleted")
+        ]
+    );
+    assert_eq!(summary["deleted"], 1);
+    assert_eq!(instance.read("load-a", 1).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(instance.read("load-b", 1).await.0, StatusCode::OK);
+    assert_eq!(instance.read("keep", 1).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_filter_is_required_and_age_is_respected() {
+    let instance = TestInstance::start("bulk-delete-age");
+    instance.upload("item", 1, &properties(1)).await;
+
+    let (status, error) = instance
+        .admin(Method::POST, "/admin/bulk-delete", r#"{"dry_run": true}"#)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error.contains("item_id_prefix or older_than"), "{error}");
+
+    let (_, body) = instance
+        .admin(
+            Method::POST,
+            "/admin/bulk-delete",
+            r#"{"older_than": 3600}"#,
+        )
+        .await;
+    let (results, summary) = report(&body);
+    assert!(results.is_empty());
+    assert_eq!(summary["matched"], 0);
+    let (_, body) = instance
+        .admin(Method::POST, "/admin/bulk-delete", r#"{"older_than": 0}"#)
+        .await;
+    assert_eq!(report(&body).0, [result("item", 1, "deleted")]);
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties};
+use serde_json::Value;
+use stream_db::logic::drain;
+use stream_db::persistence::cancellation::CancelReason;
+
+/// Start an upload of version 1 of `item`, send its first bytes, cancel it through
+/// `cancel` while it waits for more, and check it is torn down the same way whatever
+/// cancelled it
+async fn cancel_upload(
+    name: &str,
+    cancel: impl AsyncFnOnce(&TestInstance, &mut common::UploadBody),
+    status: StatusCode,
+    code: &str,
+    reason: &str,
+) -> TestInstance {
+    let instance = TestInstance::start(name);
+    let body = properties(3);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..40]);
+    common::eventually(|| async {
+        let (status, _) = instance.request(Method::GET, "/items/item/1/receipt").await;
+        (status == StatusCode::CONFLICT).then_some(())
+    })
+    .await;
+
+    cancel(&instance, &mut upload).await;
+    let (received_status, error) = response.await.unwrap();
+    assert_eq!(received_status, status, "{error}");
+    assert_eq!(error_code(&error), code, "{error}");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["reason"], reason, "{error}");
+    assert_eq!(error["details"]["bytes_received"], 40, "{error}");
+
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    let metrics = &instance.state.metrics;
+    let counted = [
+        ("killed", metrics.streams_cancelled_killed.get()),
+        ("shutdown", metrics.streams_cancelled_shutdown.get()),
+        ("timeout", metrics.streams_cancelled_timeout.get()),
+        ("client_gone", metrics.streams_cancelled_client_gone.get()),
+    ];
+    for (counter, count) in counted {
+        assert_eq!(count, u64::from(counter == reason), "{counter}");
+    }
+    instance
+}
+
+/// Begin shutting down and cut the running streams off for `reason`
+fn force_drain(instance: &TestInstance, reason: CancelReason) {
+    instance.state.drain.begin();
+    let report = drain::force(&instance.state, reason).unwrap();
+    assert_eq!(report.streams_cut_off, 1);
+    assert_eq!(report.uploads_killed, 1);
+}
+
+#[tokio::test]
+async fn a_killed_upload_is_cleaned_up_and_frees_the_item() {
+    let instance = cancel_upload(
+        "cancel-killed",
+        async |instance, _| {
+            let (status, report) = instance
+                .admin(Method::POST, "/admin/streams/item/1/kill", "")
+                .await;
+            assert_eq!(status, StatusCode::OK, "{report}");
+        },
+        StatusCode::GONE,
+        "ABORTED",
+        "killed",
+    )
+    .await;
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+}
+
+#[tokio::test]
+async fn an_upload_whose_client_goes_away_is_cleaned_up_and_frees_the_item() {
+    let instance = cancel_upload(
+        "cancel-client-gone",
+        async |_, upload| upload.break_off(),
+        StatusCode::GONE,
+        "ABORTED",
+        "client_gone",
+    )
+    .await;
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+}
+
+#[tokio::test]
+async fn an_upload_cut_off_by_a_forced_drain_is_cleaned_up() {
+    cancel_upload(
+        "cancel-shutdown",
+        async |instance, _| force_drain(instance, CancelReason::Shutdown),
+        StatusCode::SERVICE_UNAVAILABLE,
+        "UNAVAILABLE",
+        "shutdown",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn an_upload_cut_off_by_the_drain_timeout_is_cleaned_up() {
+    cancel_upload(
+        "cancel-timeout",
+        async |instance, _| force_drain(instance, CancelReason::Timeout),
+        StatusCode::SERVICE_UNAVAILABLE,
+        "UNAVAILABLE",
+        "timeout",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn a_read_waiting_in_a_long_poll_is_cut_off_by_a_forced_drain() {
+    let instance = TestInstance::start("cancel-long-poll");
+    let body = properties(3);
+    let (mut upload, _response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..body.len() - 10]);
+    common::eventually(|| async {
+        let (_, progress) = instance.request(Method::GET, "/items/item/1/receipt").await;
+        let progress: Value = serde_json::from_str(&progress).ok()?;
+        (progress["details"]["bytes_written"].as_u64()? > 0).then_some(())
+    })
+    .await;
+    let read = instance.request(
+        Method::GET,
+        "/read-item-stream/item/1?wait_for=finished&wait_timeout=60",
+    );
+    let cut_off = async {
+        common::eventually(|| async {
+            let (status, report) = instance.admin(Method::GET, "/admin/drain", "").await;
+            assert_eq!(status, StatusCode::OK);
+            let report: Value = serde_json::from_str(&report).unwrap();
+            (report["reads"] == 1).then_some(())
+        })
+        .await;
+        instance.state.drain.begin();
+        drain::force(&instance.state, CancelReason::Shutdown).unwrap();
+    };
+    let ((status, error), ()) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        tokio::join!(read, cut_off)
+    })
+    .await
+    .expect("the long poll kept waiting");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{error}");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["reason"], "shutdown");
+    assert_eq!(instance.state.metrics.streams_cancelled_shutdown.get(), 2);
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use serde_json::Value;
+use stream_db::persistence::cold_tier::{MoveOutcome, StorageTier};
+
+use std::path::Path;
+
+fn json(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| panic!("not JSON: {body}"))
+}
+
+fn with_cold_tier(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.cold_dir = Some(format!("{}-cold", config.data_dir));
+    })
+}
+
+#[tokio::test]
+async fn versions_moved_to_the_cold_tier_stay_readable_and_keep_their_checksum() {
+    let instance = with_cold_tier("cold-tier");
+    let cold_dir = instance.state.config.cold_dir.clone().unwrap();
+    let body = properties(50);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let sha256 = json(&receipt)["sha256"].as_str().unwrap().to_string();
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/tier/item/1/cold", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(json(&report)["outcome"], "moved");
+    let data_files = |dir: &str| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.starts_with("item_") && !name.ends_with("_metadata.xml"))
+            .count()
+    };
+    assert!(data_files(&cold_dir) > 0);
+    assert_eq!(data_files(&instance.state.config.data_dir), 0);
+    assert!(
+        !std::fs::read_dir(&cold_dir).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")),
+        "a temporary copy was left behind"
+    );
+
+    let response = instance.open_read("item", 1).await;
+    assert_eq!(response.headers()["X-Storage-Tier"], "cold");
+    let (status, read) = common::text(response).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, body);
+    let (_, receipt) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(json(&receipt)["sha256"], sha256.as_str());
+
+    // And back again
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/tier/item/1/hot", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(json(&report)["outcome"], "moved");
+    assert_eq!(data_files(&cold_dir), 0);
+    let response = instance.open_read("item", 1).await;
+    assert_eq!(response.headers()["X-Storage-Tier"], "hot");
+    assert_eq!(common::text(response).await.1, body);
+}
+
+#[tokio::test]
+async fn a_version_on_its_tier_already_is_left_alone() {
+    let instance = with_cold_tier("cold-tier-noop");
+    instance.upload("item", 1, &properties(3)).await;
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/tier/item/1/hot", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(json(&report)["outcome"], "already_there");
+    assert!(Path::new(&instance.state.config.data_dir).exists());
+}
+
+#[tokio::test]
+async fn the_item_takes_uploads_while_a_version_moves() {
+    let instance = with_cold_tier("cold-tier-concurrent");
+    // Large enough for the copy to take a while
+    let body = properties(20_000);
+    let (status, _) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let state = instance.state.clone();
+    let mover = tokio::spawn(async move {
+        stream_db::logic::tiering::move_version(&state, "item", 1, StorageTier::Cold, false)
+            .await
+            .map(|report| report.outcome)
+            .map_err(|error| error.message())
+    });
+    // Versions of the item committed while the move copies, without waiting for it
+    for version in 2..=5 {
+        let (status, receipt) = instance.upload("item", version, &properties(3)).await;
+        assert!(status.is_success(), "{receipt}");
+    }
+    // An upload holding the metadata just as the copy is switched over leaves the
+    // version for the next move
+    let outcome = mover.await.unwrap().unwrap();
+    assert_ne!(outcome, MoveOutcome::AlreadyThere);
+    if outcome == MoveOutcome::Busy {
+        let (status, report) = instance
+            .admin(Method::POST, "/admin/tier/item/1/cold", "")
+            .await;
+        assert_eq!(status, StatusCode::OK, "{report}");
+        assert_eq!(json(&report)["outcome"], "moved");
+    }
+    let response = instance.open_read("item", 1).await;
+    assert_eq!(response.headers()["X-Storage-Tier"], "cold");
+    assert_eq!(instance.read("item", 1).await.1, body);
+    assert_eq!(instance.read("item", 5).await.1, properties(3));
+}
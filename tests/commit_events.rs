@@ -0,0 +1,75 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestInstance, next_chunk, properties};
+use std::time::Duration;
+use stream_db::logic::data_dir_watch::{self, WatchMode};
+
+/// The JSON of the next `commit` event of a commit stream
+async fn next_commit(events: &mut axum::body::Body) -> serde_json::Value {
+    loop {
+        let chunk = next_chunk(events).await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk).into_owned();
+        if let Some(data) = chunk
+            .strip_prefix("event: commit\ndata: ")
+            .and_then(|data| data.lines().next())
+        {
+            return serde_json::from_str(data).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn local_commits_are_streamed_to_subscribers() {
+    let instance = TestInstance::start("commit-events");
+    let response = instance.open("/items/item/commits").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+    let mut events = response.into_body();
+
+    instance.upload("other", 1, &properties(1)).await;
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    let commit = next_commit(&mut events).await;
+    assert_eq!(commit["item_id"], "item");
+    assert_eq!(commit["version"], 1);
+    assert_eq!(commit["origin"], "local");
+    assert_eq!(commit["sha256"], receipt["sha256"]);
+}
+
+#[tokio::test]
+async fn versions_copied_into_the_data_directory_are_published_by_the_watch() {
+    let instance = TestInstance::start_with("commit-events-watch", |config| {
+        config.watch_mode = WatchMode::Poll;
+        config.watch_poll_secs = 1;
+    });
+    let (status, _) = instance.upload("item", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let elsewhere = TestInstance::start("commit-events-elsewhere");
+    elsewhere.upload("item", 1, &properties(1)).await;
+    elsewhere.upload("item", 2, &properties(3)).await;
+
+    let mut events = instance.open("/items/item/commits").await.into_body();
+    data_dir_watch::start(instance.state.clone());
+    // Let the watch take note of what is there before the copy
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for entry in std::fs::read_dir(&elsewhere.state.config.data_dir).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_name().to_string_lossy().contains("item") {
+            std::fs::copy(
+                entry.path(),
+                instance.data_path(&entry.file_name().to_string_lossy()),
+            )
+            .unwrap();
+        }
+    }
+
+    let commit = next_commit(&mut events).await;
+    assert_eq!(commit["version"], 2);
+    assert_eq!(commit["origin"], "external");
+    assert_eq!(
+        instance.read("item", 2).await,
+        (StatusCode::OK, properties(3))
+    );
+}
@@ -0,0 +1,155 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, properties};
+use serde_json::{Value, json};
+use stream_db::logic::commit_hooks::{self, HookEvent, HookFailurePolicy, HookSpec};
+
+fn hook(name: &str, script: &str, timeout_secs: u64, on_failure: HookFailurePolicy) -> HookSpec {
+    HookSpec {
+        name: name.to_string(),
+        event: HookEvent::Commit,
+        command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+        timeout_secs,
+        concurrency: 1,
+        on_failure,
+    }
+}
+
+async fn receipt(instance: &TestInstance) -> Value {
+    let (status, receipt) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    serde_json::from_str(&receipt).unwrap()
+}
+
+#[tokio::test]
+async fn hooks_run_after_commits_and_flag_their_failures() {
+    let dir = TestDir::new("commit-hooks");
+    let recorded = dir.join("recorded");
+    let instance = TestInstance::start_in(dir, |config| {
+        config.commit_hooks = vec![
+            hook(
+                "record",
+                &format!(
+                    "echo \"$ITEM_ID $VERSION $EPOCH $SIZE $SHA256 $PATH_TO_FILE\" > {recorded}"
+                ),
+                10,
+                HookFailurePolicy::Flag,
+            ),
+            hook(
+                "fail",
+                "echo broken >&2; exit 3",
+                10,
+                HookFailurePolicy::Flag,
+            ),
+            hook("hang", "sleep 30", 1, HookFailurePolicy::Flag),
+            hook("ignored", "exit 1", 10, HookFailurePolicy::Log),
+        ];
+    });
+    commit_hooks::start(instance.state.clone());
+
+    let body = properties(3);
+    let (status, _) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let line = common::eventually(|| async {
+        std::fs::read_to_string(&recorded)
+            .ok()
+            .filter(|line| line.ends_with('\n'))
+    })
+    .await;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let committed = receipt(&instance).await;
+    assert_eq!(fields[..2], ["item", "1"]);
+    assert_eq!(fields[2], committed["epoch"].to_string());
+    assert_eq!(fields[3], body.len().to_string());
+    assert_eq!(fields[4], committed["sha256"]);
+    assert_eq!(
+        std::fs::read_to_string(fields[5]).unwrap(),
+        body,
+        "{}",
+        fields[5]
+    );
+
+    // The timeout kills the hanging hook after a second
+    let receipt = common::eventually(|| async {
+        let receipt = receipt(&instance).await;
+        (receipt["failed_hooks"].as_array().map(Vec::len) == Some(2)).then_some(receipt)
+    })
+    .await;
+    assert_eq!(receipt["hook_failed"], true);
+    let mut failed: Vec<&str> = receipt["failed_hooks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hook| hook.as_str().unwrap())
+        .collect();
+    failed.sort_unstable();
+    assert_eq!(failed, ["fail", "hang"]);
+
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_hook_runs_total 4"), "{metrics}");
+    assert!(metrics.contains("stream_db_hook_failures_total 3"));
+    assert!(metrics.contains("stream_db_hook_timeouts_total 1"));
+}
+
+#[tokio::test]
+async fn a_test_run_reports_the_hook_without_flagging() {
+    let instance = TestInstance::start_with("commit-hooks-test", |config| {
+        config.commit_hooks = vec![hook(
+            "fail",
+            "echo \"dry run $HOOK_DRY_RUN\" >&2; exit 3",
+            10,
+            HookFailurePolicy::Flag,
+        )];
+    });
+    instance.upload("item", 1, &properties(2)).await;
+
+    let request = |hook: &str, version: u64| {
+        json!({"hook": hook, "item_id": "item", "version": version}).to_string()
+    };
+    let (status, run) = instance
+        .admin(Method::POST, "/admin/hooks/test", &request("fail", 1))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{run}");
+    let run: Value = serde_json::from_str(&run).unwrap();
+    assert_eq!(run["dry_run"], true);
+    assert_eq!(run["succeeded"], false);
+    assert_eq!(run["exit_code"], 3);
+    assert_eq!(run["stderr"], "dry run 1");
+    assert_eq!(receipt(&instance).await["hook_failed"], Value::Null);
+
+    for (hook, version) in [("other", 1), ("fail", 2)] {
+        let (status, error) = instance
+            .admin(Method::POST, "/admin/hooks/test", &request(hook, version))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{error}");
+    }
+}
+
+#[test]
+fn invalid_hooks_files_are_refused() {
+    let dir = TestDir::new("commit-hooks-file");
+    let path = dir.join("hooks.json");
+    for hooks in [
+        json!([{"name": "bad name", "command": ["true"]}]),
+        json!([{"name": "twice", "command": ["true"]}, {"name": "twice", "command": ["true"]}]),
+        json!([{"name": "empty", "command": []}]),
+        json!([{"name": "never", "command": ["true"], "timeout_secs": 0}]),
+        json!([{"name": "typo", "command": ["true"], "on_fail": "flag"}]),
+        json!([{"name": "alert", "event": "quarantine", "command": ["true"], "on_failure": "flag"}]),
+    ] {
+        std::fs::write(&path, hooks.to_string()).unwrap();
+        assert!(commit_hooks::load_specs(Some(&path)).is_err(), "{hooks}");
+    }
+
+    std::fs::write(
+        &path,
+        json!([{"name": "ok", "command": ["true"], "on_failure": "flag"}]).to_string(),
+    )
+    .unwrap();
+    let specs = commit_hooks::load_specs(Some(&path)).unwrap();
+    assert_eq!(specs[0].timeout_secs, 60);
+    assert_eq!(specs[0].on_failure, HookFailurePolicy::Flag);
+    assert_eq!(specs[0].event, HookEvent::Commit);
+}
@@ -0,0 +1,329 @@
+//! Helpers shared by the integration tests: an instance with a data directory of its own,
+//! driven through its router in process, without a listener.
+#![allow(dead_code)]
+
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::http::{Method, Request, Response, StatusCode, header};
+use futures::channel::mpsc;
+use http_body_util::BodyExt;
+use stream_db::api::{read_item_stream_api, router, write_item_stream_api};
+use stream_db::config::Config;
+use stream_db::state::{AppState, StreamDb};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tower::ServiceExt;
+
+use futures::StreamExt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+pub type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A directory under the system's temporary directory, removed with everything in it
+/// when dropped
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("stream-db-{name}-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// One instance of the server over a data directory of its own
+pub struct TestInstance {
+    pub state: AppState,
+    pub dir: TestDir,
+    app: Router,
+}
+
+impl TestInstance {
+    pub fn start(name: &str) -> Self {
+        Self::start_with(name, |_| {})
+    }
+
+    /// Start an instance after `configure` adjusted its settings, which start out as the
+    /// defaults with the data directory in a fresh temporary directory
+    pub fn start_with(name: &str, configure: impl FnOnce(&mut Config)) -> Self {
+        Self::start_in(TestDir::new(name), configure)
+    }
+
+    /// Start an instance over the data directory in `dir`, e.g. one left by an instance
+    /// that was stopped, or a fixture written by the test
+    pub fn start_in(dir: TestDir, configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Config::from_env().unwrap();
+        config.data_dir = dir.join("data");
+        config.admin_token = Some(ADMIN_TOKEN.to_string());
+        configure(&mut config);
+        let state = StreamDb::new(config);
+        write_item_stream_api::init(&state).unwrap();
+        read_item_stream_api::init(&state).unwrap();
+        let app = router::app(state.clone());
+        Self { state, dir, app }
+    }
+
+    /// Stop the instance and hand over its data directory, to start another on it
+    pub fn stop(self) -> TestDir {
+        self.dir
+    }
+
+    pub fn data_path(&self, file_name: &str) -> String {
+        format!("{}/{file_name}", self.state.config.data_dir)
+    }
+
+    pub fn router(&self) -> Router {
+        self.app.clone()
+    }
+
+    /// Serve the instance on a local port, for WebSocket upgrades which need a real
+    /// connection
+    pub async fn serve(&self) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = self.router();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        address
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> Response<Body> {
+        self.app.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn request(&self, method: Method, uri: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        text(self.send(request).await).await
+    }
+
+    pub async fn admin(&self, method: Method, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        text(self.send(request).await).await
+    }
+
+    pub async fn json(&self, method: Method, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        text(self.send(request).await).await
+    }
+
+    pub async fn upload(&self, item_id: &str, version: u64, body: &str) -> (StatusCode, String) {
+        text(self.send(upload_request(item_id, version, body)).await).await
+    }
+
+    /// Start an upload whose body is sent piece by piece through the returned
+    /// [`UploadBody`], the response arrives once the body ended
+    pub fn start_upload(
+        &self,
+        item_id: &str,
+        version: u64,
+        size: usize,
+    ) -> (UploadBody, tokio::task::JoinHandle<(StatusCode, String)>) {
+        let (sender, receiver) = mpsc::unbounded::<Result<Bytes, std::io::Error>>();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/write-item-stream/{item_id}/{version}"))
+            .header(header::CONTENT_TYPE, "application/xml")
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::from_stream(receiver))
+            .unwrap();
+        let app = self.app.clone();
+        let response = tokio::spawn(async move { text(app.oneshot(request).await.unwrap()).await });
+        (UploadBody(Some(sender)), response)
+    }
+
+    pub async fn read(&self, item_id: &str, version: u64) -> (StatusCode, String) {
+        self.request(
+            Method::GET,
+            &format!("/read-item-stream/{item_id}/{version}"),
+        )
+        .await
+    }
+
+    /// Start a read and return the response once its headers arrived, its body is read
+    /// by the test at its own pace
+    pub async fn open_read(&self, item_id: &str, version: u64) -> Response<Body> {
+        self.open(&format!("/read-item-stream/{item_id}/{version}"))
+            .await
+    }
+
+    /// Send a GET for `uri` and return the response once its headers arrived
+    pub async fn open(&self, uri: &str) -> Response<Body> {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        self.send(request).await
+    }
+}
+
+/// The body of an upload started with [`TestInstance::start_upload`]
+pub struct UploadBody(Option<mpsc::UnboundedSender<Result<Bytes, std::io::Error>>>);
+
+impl UploadBody {
+    pub fn send(&mut self, bytes: &str) {
+        let sender = self.0.as_ref().expect("the body ended already");
+        sender
+            .unbounded_send(Ok(Bytes::from(bytes.to_string())))
+            .unwrap();
+    }
+
+    /// End the body, the upload commits if it was complete
+    pub fn finish(&mut self) {
+        self.0 = None;
+    }
+
+    /// Break the connection mid-body, as a client that was killed
+    pub fn break_off(&mut self) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.unbounded_send(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "client killed",
+            )));
+        }
+    }
+}
+
+pub fn upload_request(item_id: &str, version: u64, body: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/{version}"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// A body sending `body` in pieces of 16 bytes, counting the bytes the server took from it
+pub fn counting_body(body: &str) -> (Body, Arc<AtomicUsize>) {
+    let taken = Arc::new(AtomicUsize::new(0));
+    let counter = taken.clone();
+    let pieces: Vec<Result<Bytes, std::io::Error>> = body
+        .as_bytes()
+        .chunks(16)
+        .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+        .collect();
+    let stream = futures::stream::iter(pieces).inspect(move |piece| {
+        if let Ok(piece) = piece {
+            counter.fetch_add(piece.len(), Ordering::SeqCst);
+        }
+    });
+    (Body::from_stream(stream), taken)
+}
+
+/// An item body of `count` string properties
+pub fn properties(count: usize) -> String {
+    let mut body = String::from("<properties>");
+    for index in 0..count {
+        body.push_str(&format!(
+            "<property name=\"p{index}\" type=\"string\">value {index}</property>"
+        ));
+    }
+    body.push_str("</properties>");
+    body
+}
+
+/// The status of a successful upload of `version`, assuming the versions before it were
+/// all uploaded: `201 Created` for the first version of an item, `200 OK` after it
+pub fn first_version_status(version: u64) -> StatusCode {
+    if version == 1 {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    }
+}
+
+pub async fn text(response: Response<Body>) -> (StatusCode, String) {
+    let status = response.status();
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The JSON `code` of an error response
+pub fn error_code(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|error| error["code"].as_str().map(str::to_string))
+        .unwrap_or_else(|| panic!("not an error body: {body}"))
+}
+
+/// The next piece of a response body, `None` once it ended. Fails the test when nothing
+/// arrives within 5 seconds, so a hanging stream does not hang the suite.
+pub async fn next_chunk(body: &mut Body) -> Option<Result<Bytes, String>> {
+    let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+        .await
+        .expect("no body frame within 5 seconds")?;
+    Some(
+        frame
+            .map(|frame| frame.into_data().unwrap_or_default())
+            .map_err(|error| error.to_string()),
+    )
+}
+
+/// Poll `check` until it returns something, failing the test after 5 seconds
+pub async fn eventually<T, F: Future<Output = Option<T>>>(mut check: impl FnMut() -> F) -> T {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(value) = check().await {
+            return value;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "condition not met within 5 seconds"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Open a WebSocket to `path` of an instance served at `address`
+pub async fn connect_ws(address: SocketAddr, path: &str) -> Result<Socket, WsError> {
+    let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{address}{path}")).await?;
+    Ok(socket)
+}
+
+/// The next WebSocket frame, which has to be a JSON text frame
+pub async fn next_json(socket: &mut Socket) -> serde_json::Value {
+    match tokio::time::timeout(Duration::from_secs(5), socket.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str(&text).unwrap(),
+        other => panic!("expected a JSON frame, got {other:?}"),
+    }
+}
+
+/// The code of the next WebSocket frame, which has to be a close frame
+pub async fn close_code(socket: &mut Socket) -> CloseCode {
+    match tokio::time::timeout(Duration::from_secs(5), socket.next()).await {
+        Ok(Some(Ok(Message::Close(Some(frame))))) => frame.code,
+        other => panic!("expected a close frame, got {other:?}"),
+    }
+}
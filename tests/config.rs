@@ -0,0 +1,155 @@
+mod common;
+
+use std::sync::Mutex;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance};
+use serde_json::Value;
+use stream_db::config::Config;
+
+/// The environment is shared by the tests of this file
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Run `f` with `vars` set in the environment, removing them again afterwards
+fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+    let _guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (name, value) in vars {
+        // SAFETY: every test of this file holds `ENV` while it touches the environment
+        unsafe { std::env::set_var(name, value) };
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    for (name, _) in vars {
+        unsafe { std::env::remove_var(name) };
+    }
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+fn load_error(vars: &[(&str, &str)]) -> String {
+    with_env(vars, || Config::from_env().err().expect("config loaded"))
+}
+
+#[test]
+fn unknown_settings_are_refused_with_a_suggestion() {
+    let error = load_error(&[("STREAM_DB_MAX_ITEM_BYTS", "10")]);
+    assert!(
+        error.contains("\"STREAM_DB_MAX_ITEM_BYTS\" (did you mean STREAM_DB_MAX_ITEM_BYTES?)"),
+        "{error}"
+    );
+    assert!(error.contains("--allow-unknown-config"), "{error}");
+
+    let config = with_env(&[("STREAM_DB_MAX_ITEM_BYTS", "10")], || Config::load(true));
+    assert_eq!(config.unwrap().live().max_item_bytes, None);
+}
+
+#[test]
+fn the_config_file_is_read_below_the_environment() {
+    let dir = TestDir::new("config-file");
+    let path = dir.join("stream-db.toml");
+    std::fs::write(
+        &path,
+        "max_item_bytes = 100\nverify_sample_percent = 50\nwarmup_items = [\"a\", \"b\"]\n",
+    )
+    .unwrap();
+    let config = with_env(
+        &[
+            ("STREAM_DB_CONFIG_FILE", &path),
+            ("STREAM_DB_MAX_ITEM_BYTES", "200"),
+        ],
+        Config::from_env,
+    )
+    .unwrap();
+    assert_eq!(config.live().max_item_bytes, Some(200));
+    assert_eq!(config.verify_sample_percent, 50);
+    assert_eq!(config.warmup_items, ["a", "b"]);
+    let settings = config.settings();
+    let source = |name: &str| serde_json::to_value(&settings[name]).unwrap()["source"].clone();
+    assert_eq!(source("STREAM_DB_MAX_ITEM_BYTES"), "env");
+    assert_eq!(source("STREAM_DB_VERIFY_SAMPLE_PERCENT"), "file");
+    assert_eq!(source("STREAM_DB_DATA_DIR"), "default");
+
+    std::fs::write(&path, "max_item_byts = 100\n").unwrap();
+    let error = load_error(&[("STREAM_DB_CONFIG_FILE", &path)]);
+    assert!(
+        error.contains("\"max_item_byts\" (did you mean max_item_bytes?)"),
+        "{error}"
+    );
+}
+
+#[test]
+fn inconsistent_settings_are_refused() {
+    let dir = TestDir::new("config-invalid");
+    let data_dir = dir.join("data");
+    let inflight_dir = format!("{data_dir}/inflight");
+    for vars in [
+        vec![
+            ("STREAM_DB_DATA_DIR", data_dir.as_str()),
+            ("STREAM_DB_INFLIGHT_DIR", inflight_dir.as_str()),
+        ],
+        vec![("STREAM_DB_HOOK_WORKERS", "0")],
+        vec![("STREAM_DB_VERIFY_SAMPLE_PERCENT", "101")],
+        vec![("STREAM_DB_MAX_ITEM_BYTES", "lots")],
+    ] {
+        let error = load_error(&vars);
+        assert!(error.contains(vars.last().unwrap().0), "{error}");
+    }
+}
+
+#[test]
+fn the_admin_api_shows_and_reloads_the_settings() {
+    let dir = TestDir::new("config-admin");
+    let path = dir.join("stream-db.toml");
+    std::fs::write(&path, "max_item_bytes = 1000000\n").unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    with_env(&[("STREAM_DB_CONFIG_FILE", &path)], || {
+        runtime.block_on(async {
+            let instance = TestInstance::start_in(dir, |_| {});
+            let (status, settings) = instance.admin(Method::GET, "/admin/config", "").await;
+            assert_eq!(status, StatusCode::OK, "{settings}");
+            let settings: Value = serde_json::from_str(&settings).unwrap();
+            assert_eq!(settings["STREAM_DB_MAX_ITEM_BYTES"]["source"], "file");
+            assert_eq!(settings["STREAM_DB_MAX_ITEM_BYTES"]["reloadable"], true);
+            assert_eq!(settings["STREAM_DB_DATA_DIR"]["reloadable"], false);
+
+            std::fs::write(
+                &path,
+                "max_item_bytes = 10\nhook_workers = 2\nread_token = \"hidden\"\n",
+            )
+            .unwrap();
+            let (status, reload) = instance
+                .admin(Method::POST, "/admin/config/reload", "")
+                .await;
+            assert_eq!(status, StatusCode::OK, "{reload}");
+            let reload: Value = serde_json::from_str(&reload).unwrap();
+            let names = |changes: &Value| -> Vec<String> {
+                changes
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|change| change["name"].as_str().unwrap().to_string())
+                    .collect()
+            };
+            assert_eq!(names(&reload["applied"]), ["STREAM_DB_MAX_ITEM_BYTES"]);
+            assert_eq!(
+                names(&reload["restart_required"]),
+                ["STREAM_DB_HOOK_WORKERS", "STREAM_DB_READ_TOKEN"]
+            );
+            assert_eq!(reload["restart_required"][1]["new"], "<redacted>");
+            assert_eq!(instance.state.config.live().max_item_bytes, Some(10));
+            assert_eq!(instance.state.config.hook_workers, 4);
+
+            let (status, _) = instance.upload("item", 1, &common::properties(2)).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+            // A broken file changes nothing
+            std::fs::write(&path, "max_item_bytes = \"lots\"\n").unwrap();
+            let (status, _) = instance
+                .admin(Method::POST, "/admin/config/reload", "")
+                .await;
+            assert_eq!(status, StatusCode::CONFLICT);
+            assert_eq!(instance.state.config.live().max_item_bytes, Some(10));
+        })
+    });
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use common::{TestInstance, error_code, properties, text, upload_request};
+
+const SECRET: &str = "test-secret";
+
+fn node(name: &str, node_id: &str, secret: Option<&str>) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.node_id = node_id.to_string();
+        config.secret = secret.map(str::to_string);
+        config.consistency_wait_ms = 100;
+    })
+}
+
+/// Upload `item_id` version `version` and return the token of its write receipt
+async fn upload_with_token(instance: &TestInstance, item_id: &str, version: u64) -> String {
+    let response = instance
+        .send(upload_request(item_id, version, &properties(2)))
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.headers()["X-Consistency-Token"]
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+async fn read_with_token(instance: &TestInstance, uri: &str, token: &str) -> (StatusCode, String) {
+    let request = Request::builder()
+        .uri(uri)
+        .header("X-Consistency-Token", token)
+        .body(Body::empty())
+        .unwrap();
+    text(instance.send(request).await).await
+}
+
+#[tokio::test]
+async fn reads_wait_for_the_version_a_token_names() {
+    let origin = node("consistency-origin", "a", Some(SECRET));
+    let replica = node("consistency-replica", "b", Some(SECRET));
+    let token = upload_with_token(&origin, "item", 1).await;
+
+    // The origin has it, the other node does not until it is written there too
+    let (status, body) = read_with_token(&origin, "/read-item-stream/item/1", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, properties(2));
+    let request = Request::builder()
+        .uri("/read-item-stream/item/1")
+        .header("X-Consistency-Token", &token)
+        .body(Body::empty())
+        .unwrap();
+    let response = replica.send(request).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["Retry-After"], "1");
+    let (_, error) = text(response).await;
+    assert_eq!(error_code(&error), "UNAVAILABLE");
+
+    upload_with_token(&replica, "item", 1).await;
+    let (status, body) = read_with_token(&replica, "/read-item-stream/item/1", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, properties(2));
+}
+
+#[tokio::test]
+async fn forged_and_misdirected_tokens_are_refused() {
+    let origin = node("consistency-forged", "a", Some(SECRET));
+    let stranger = node("consistency-stranger", "b", Some("another-secret"));
+    let token = upload_with_token(&origin, "item", 1).await;
+    upload_with_token(&origin, "other", 1).await;
+
+    let (status, error) = read_with_token(&stranger, "/read-item-stream/item/1", &token).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error.contains("signature is invalid"), "{error}");
+    let flipped = if token.starts_with('A') { "B" } else { "A" };
+    let tampered = format!("{flipped}{}", &token[1..]);
+    let (status, _) = read_with_token(&origin, "/read-item-stream/item/1", &tampered).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = read_with_token(&origin, "/read-item-stream/item/1", "garbage").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, error) = read_with_token(&origin, "/read-item-stream/other/1", &token).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error.contains("not other"), "{error}");
+}
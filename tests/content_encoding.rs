@@ -0,0 +1,159 @@
+mod common;
+
+use std::io::Write;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http_body_util::BodyExt;
+use serde_json::Value;
+
+fn gzip(body: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+async fn upload_encoded(
+    instance: &TestInstance,
+    item_id: &str,
+    encoding: &str,
+    body: Vec<u8>,
+) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/1"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_ENCODING, encoding)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap();
+    text(instance.send(request).await).await
+}
+
+async fn read_with(
+    instance: &TestInstance,
+    uri: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let mut request = Request::builder().uri(uri);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let response = instance.send(request.body(Body::empty()).unwrap()).await;
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await.unwrap().to_bytes().to_vec();
+    (parts.status, parts.headers, body)
+}
+
+#[tokio::test]
+async fn gzip_uploads_are_stored_decoded_by_default() {
+    let instance = TestInstance::start("gzip-decoded");
+    let body = properties(20);
+    let (status, receipt) = upload_encoded(&instance, "item", "gzip", gzip(&body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["size"], body.len());
+    assert_eq!(receipt["property_count"], 20);
+
+    assert_eq!(
+        std::fs::read_to_string(instance.data_path("item_1.xml")).unwrap(),
+        body
+    );
+    let (status, headers, read) = read_with(
+        &instance,
+        "/read-item-stream/item/1",
+        &[(header::ACCEPT_ENCODING, "gzip")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(header::CONTENT_ENCODING), None);
+    assert_eq!(read, body.as_bytes());
+}
+
+#[tokio::test]
+async fn versions_stored_compressed_are_decoded_for_readers_without_gzip() {
+    let instance = TestInstance::start_with("gzip-stored", |config| {
+        config.store_gzip_uploads = true;
+    });
+    let body = properties(20);
+    let compressed = gzip(&body);
+    let (status, receipt) = upload_encoded(&instance, "item", "gzip", compressed.clone()).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["content_encoding"], "gzip");
+    assert_eq!(receipt["size"], compressed.len());
+    assert_eq!(
+        std::fs::read(instance.data_path("item_1.xml")).unwrap(),
+        compressed
+    );
+
+    let uri = "/read-item-stream/item/1";
+    let (status, headers, read) =
+        read_with(&instance, uri, &[(header::ACCEPT_ENCODING, "br, gzip")]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, compressed);
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(
+        headers[header::CONTENT_LENGTH],
+        compressed.len().to_string().as_str()
+    );
+    assert!(headers[header::ETAG].to_str().unwrap().ends_with(".gzip\""));
+    assert_eq!(headers[header::VARY], "Accept-Encoding");
+
+    for accept in [None, Some("gzip;q=0"), Some("identity")] {
+        let accept: Vec<_> = accept
+            .map(|accept| (header::ACCEPT_ENCODING, accept))
+            .into_iter()
+            .collect();
+        let (status, headers, read) = read_with(&instance, uri, &accept).await;
+        assert_eq!(status, StatusCode::OK, "{accept:?}");
+        assert_eq!(read, body.as_bytes(), "{accept:?}");
+        assert_eq!(headers.get(header::CONTENT_ENCODING), None);
+        assert_eq!(headers.get(header::CONTENT_LENGTH), None);
+        assert_eq!(headers[header::VARY], "Accept-Encoding");
+    }
+
+    // Reads starting at a property scan the decoded stream
+    let (status, _, read) = read_with(
+        &instance,
+        "/read-item-stream/item/1?from_property=19",
+        &[(header::ACCEPT_ENCODING, "gzip")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let read = String::from_utf8(read).unwrap();
+    assert!(read.starts_with("<property"), "{read}");
+    assert!(body.ends_with(&read));
+
+    let (status, headers, _) = read_with(&instance, uri, &[(header::RANGE, "bytes=0-9")]).await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        headers[header::CONTENT_RANGE],
+        format!("bytes */{}", compressed.len()).as_str()
+    );
+    let (status, error) = instance
+        .request(Method::GET, "/items/item/1/download-manifest")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error_code(&error), "CONFLICT");
+}
+
+#[tokio::test]
+async fn broken_and_unknown_encodings_are_refused() {
+    let instance = TestInstance::start("gzip-broken");
+    let compressed = gzip(&properties(20));
+    let truncated = compressed[..compressed.len() / 2].to_vec();
+    for (item_id, encoding, body) in [
+        ("plain", "gzip", properties(2).into_bytes()),
+        ("truncated", "gzip", truncated),
+        ("brotli", "br", compressed),
+    ] {
+        let (status, error) = upload_encoded(&instance, item_id, encoding, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{item_id}: {error}");
+        let (status, _) = instance.read(item_id, 1).await;
+        assert_ne!(status, StatusCode::OK, "{item_id}");
+    }
+}
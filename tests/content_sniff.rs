@@ -0,0 +1,145 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use serde_json::Value;
+use stream_db::logic::content_sniff::ContentSniffMode;
+
+fn sniffing_instance(name: &str, mode: ContentSniffMode) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.content_sniff = mode;
+    })
+}
+
+async fn upload_bytes(
+    instance: &TestInstance,
+    item_id: &str,
+    body: Vec<u8>,
+) -> (StatusCode, Option<String>, String) {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/1"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap();
+    let response = instance.send(request).await;
+    let warning = response
+        .headers()
+        .get("X-Content-Sniff-Warning")
+        .map(|warning| warning.to_str().unwrap().to_string());
+    let (status, body) = text(response).await;
+    (status, warning, body)
+}
+
+/// Bodies declared as XML that are something else, with what the sniff makes of them and
+/// where it finds their first byte
+fn mismatches() -> Vec<(&'static str, Vec<u8>, &'static str, u64, &'static str)> {
+    let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00];
+    gzip.extend(properties(1).as_bytes());
+    vec![
+        (
+            "json",
+            b"{\"city\": \"Bern\"}".to_vec(),
+            "json",
+            0,
+            "7b 22 63 69 74 79 22 3a",
+        ),
+        (
+            "json-array",
+            b" \n\t[1, 2]".to_vec(),
+            "json",
+            3,
+            "5b 31 2c 20 32 5d",
+        ),
+        ("gzip", gzip, "gzip", 0, "1f 8b 08 00 3c 70 72 6f"),
+        (
+            "text",
+            b"city=Bern".to_vec(),
+            "unknown",
+            0,
+            "63 69 74 79 3d 42 65 72",
+        ),
+    ]
+}
+
+#[tokio::test]
+async fn strict_mode_refuses_bodies_that_do_not_look_like_xml() {
+    let instance = sniffing_instance("sniff-strict", ContentSniffMode::Strict);
+    for (item_id, body, detected, offset, first_bytes) in mismatches() {
+        let (status, _, error) = upload_bytes(&instance, item_id, body).await;
+        assert_eq!(
+            status,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "{item_id}: {error}"
+        );
+        assert_eq!(error_code(&error), "UNSUPPORTED_MEDIA_TYPE");
+        let error: Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["details"]["detected"], detected, "{item_id}");
+        assert_eq!(error["details"]["byte_offset"], offset, "{item_id}");
+        assert_eq!(error["details"]["first_bytes"], first_bytes, "{item_id}");
+        let data_path = instance.data_path(&format!("{item_id}_1.xml"));
+        assert!(!std::path::Path::new(&data_path).exists(), "{data_path}");
+    }
+    assert_eq!(
+        instance.state.metrics.content_sniff_rejections.get(),
+        mismatches().len() as u64
+    );
+
+    // Leading whitespace and a byte order mark are fine, also when split across chunks
+    let body = format!("\u{feff}\n  {}", properties(2));
+    let (status, _, receipt) = upload_bytes(&instance, "bom", body.into_bytes()).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let body = format!(" \n{}", properties(2));
+    let (mut upload, response) = instance.start_upload("split", 1, body.len());
+    for piece in [&body[..1], &body[1..2], &body[2..3], &body[3..]] {
+        upload.send(piece);
+    }
+    upload.finish();
+    let (status, receipt) = response.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let body = format!(" \n{{{}", properties(2));
+    let (mut upload, response) = instance.start_upload("split-json", 1, body.len());
+    for piece in [&body[..1], &body[1..2], &body[2..]] {
+        upload.send(piece);
+    }
+    upload.finish();
+    let (status, error) = response.await.unwrap();
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{error}");
+}
+
+#[tokio::test]
+async fn warn_mode_stores_the_body_byte_for_byte_and_reports_the_mismatch() {
+    let instance = sniffing_instance("sniff-warn", ContentSniffMode::Warn);
+    for (item_id, prefix) in [("json", "["), ("text", "  city: ")] {
+        let body = format!("{prefix}{}", properties(2));
+        let (status, warning, receipt) =
+            upload_bytes(&instance, item_id, body.clone().into_bytes()).await;
+        assert_eq!(status, StatusCode::CREATED, "{item_id}: {receipt}");
+        let warning = warning.expect("no X-Content-Sniff-Warning");
+        let receipt: Value = serde_json::from_str(&receipt).unwrap();
+        assert_eq!(receipt["received"]["content_sniff_warning"], warning);
+        assert_eq!(instance.read(item_id, 1).await.1, body, "{item_id}");
+    }
+    assert_eq!(instance.state.metrics.content_sniff_warnings.get(), 2);
+
+    let (status, warning, receipt) =
+        upload_bytes(&instance, "xml", properties(2).into_bytes()).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(warning, None);
+    assert!(!receipt.contains("content_sniff_warning"), "{receipt}");
+}
+
+#[tokio::test]
+async fn nothing_is_sniffed_when_it_is_off() {
+    let instance = sniffing_instance("sniff-off", ContentSniffMode::Off);
+    let body = format!("[{}", properties(2));
+    let (status, warning, receipt) = upload_bytes(&instance, "item", body.into_bytes()).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(warning, None);
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.content_sniff_warnings.get(), 0);
+    assert_eq!(metrics.content_sniff_rejections.get(), 0);
+}
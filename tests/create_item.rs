@@ -0,0 +1,90 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, properties, text};
+use stream_db::logic::item_ids::IdScheme;
+
+/// Upload `body` as a new item, with `X-Id-Prefix: prefix` if given
+async fn create(
+    instance: &TestInstance,
+    prefix: Option<&str>,
+    body: &str,
+) -> (StatusCode, Option<String>, serde_json::Value) {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri("/items")
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len());
+    if let Some(prefix) = prefix {
+        request = request.header("X-Id-Prefix", prefix);
+    }
+    let response = instance
+        .send(request.body(Body::from(body.to_string())).unwrap())
+        .await;
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .map(|location| location.to_str().unwrap().to_string());
+    let (status, body) = text(response).await;
+    let body = serde_json::from_str(&body).unwrap_or_else(|_| panic!("{body}"));
+    (status, location, body)
+}
+
+#[tokio::test]
+async fn concurrent_creates_get_distinct_ids() {
+    let instance = TestInstance::start_with("create-items", |config| {
+        config.id_prefix = Some("eu".to_string());
+    });
+    let bodies: Vec<String> = (1..=20).map(properties).collect();
+    let created = futures::future::join_all(
+        bodies
+            .iter()
+            .map(|body| create(&instance, Some("orders"), body)),
+    )
+    .await;
+
+    let mut ids = Vec::new();
+    for ((status, location, receipt), body) in created.into_iter().zip(&bodies) {
+        assert_eq!(status, StatusCode::CREATED, "{receipt}");
+        let item_id = receipt["item_id"].as_str().unwrap().to_string();
+        assert!(item_id.starts_with("eu-orders-"), "{item_id}");
+        assert_eq!(
+            location.as_deref(),
+            Some(format!("/read-item-stream/{item_id}/1").as_str())
+        );
+        assert_eq!(instance.read(&item_id, 1).await.1, *body);
+        ids.push(item_id);
+    }
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 20);
+}
+
+#[tokio::test]
+async fn ids_of_either_scheme_sort_in_the_order_they_were_issued() {
+    for scheme in [IdScheme::Uuid7, IdScheme::Ulid] {
+        let instance = TestInstance::start_with("create-order", |config| {
+            config.id_scheme = scheme;
+            config.id_prefix = Some("eu".to_string());
+        });
+        let mut issued = Vec::new();
+        for _ in 0..3 {
+            let (_, _, receipt) = create(&instance, Some("orders"), &properties(1)).await;
+            issued.push(receipt["item_id"].as_str().unwrap().to_string());
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        let mut sorted = issued.clone();
+        sorted.sort();
+        assert_eq!(issued, sorted, "{scheme:?}");
+    }
+}
+
+#[tokio::test]
+async fn invalid_prefixes_are_refused() {
+    let instance = TestInstance::start("create-prefix");
+    for prefix in ["", "with space", "a_b", &"x".repeat(65)] {
+        let (status, _, error) = create(&instance, Some(prefix), &properties(1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{prefix:?}: {error}");
+    }
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, properties, text};
+use serde_json::Value;
+
+fn upload(item_id: &str, request_id: &str, body: Vec<u8>) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/1"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .header("X-Request-Id", request_id)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn capture(instance: &TestInstance, uri: &str) -> (StatusCode, Vec<u8>) {
+    let request = Request::builder()
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+        .body(Body::empty())
+        .unwrap();
+    let response = instance.send(request).await;
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, bytes.to_vec())
+}
+
+#[tokio::test]
+async fn uploads_of_matching_items_are_captured_with_their_trace() {
+    let instance = TestInstance::start("debug-captures");
+    let (status, patterns) = instance
+        .admin(
+            Method::PUT,
+            "/admin/debug-captures",
+            r#"{"item_patterns": ["ingest-*"]}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{patterns}");
+
+    let good = properties(3);
+    let (status, _) = text(
+        instance
+            .send(upload(
+                "ingest-good",
+                "capture-good",
+                good.clone().into_bytes(),
+            ))
+            .await,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let mut bad = properties(2).replace("</properties>", "").into_bytes();
+    let invalid_at = bad.len() + "<property name=\"p2\">".len();
+    bad.extend_from_slice(b"<property name=\"p2\">\xff\xfe</property></properties>");
+    let (status, _) = text(
+        instance
+            .send(upload("ingest-bad", "capture-bad", bad.clone()))
+            .await,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = text(
+        instance
+            .send(upload("other", "not-captured", good.clone().into_bytes()))
+            .await,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, listing) = instance
+        .admin(Method::GET, "/admin/debug-captures", "")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let listing: Value = serde_json::from_str(&listing).unwrap();
+    assert_eq!(listing["item_patterns"], serde_json::json!(["ingest-*"]));
+    let mut captured: Vec<&str> = listing["captures"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|capture| capture["request_id"].as_str().unwrap())
+        .collect();
+    captured.sort();
+    assert_eq!(captured, ["capture-bad", "capture-good"]);
+
+    // The raw bodies are kept as they arrived
+    assert_eq!(
+        capture(&instance, "/admin/debug-captures/capture-good").await,
+        (StatusCode::OK, good.clone().into_bytes())
+    );
+    assert_eq!(
+        capture(&instance, "/admin/debug-captures/capture-bad").await,
+        (StatusCode::OK, bad)
+    );
+
+    let (status, trace) = capture(
+        &instance,
+        "/admin/debug-captures/capture-good?artifact=trace",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let trace: Value = serde_json::from_slice(&trace).unwrap();
+    assert_eq!(trace["outcome"], "committed");
+    assert_eq!(trace["property_count"], 3);
+    let first_end = good.find("</property>").unwrap() + "</property>".len();
+    assert_eq!(trace["boundaries"][0], first_end);
+    assert_eq!(trace["bytes_received"], good.len());
+
+    // The trace of the failed upload points at the invalid bytes
+    let (_, trace) = capture(
+        &instance,
+        "/admin/debug-captures/capture-bad?artifact=trace",
+    )
+    .await;
+    let trace: Value = serde_json::from_slice(&trace).unwrap();
+    assert_eq!(trace["outcome"], "INVALID_XML", "{trace}");
+    assert_eq!(trace["errors"][0]["offset"], invalid_at, "{trace}");
+
+    let (status, _) = capture(&instance, "/admin/debug-captures/not-captured").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
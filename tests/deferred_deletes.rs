@@ -0,0 +1,75 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, error_code, properties};
+use http_body_util::BodyExt;
+use serde_json::Value;
+
+async fn body_of(response: axum::response::Response) -> String {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn a_version_deleted_under_a_reader_is_read_to_its_end() {
+    // A reader that queued the whole version ahead of its client no longer holds it
+    let instance = TestInstance::start_with("deferred-delete", |config| {
+        config.read_prefetch_depth = 0;
+    });
+    let (status, receipt) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let reading = instance.open_read("item", 1).await;
+    assert_eq!(reading.status(), StatusCode::OK);
+
+    let (status, report) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["readers_attached"], 1);
+    assert_eq!(report["deferred"], true);
+    let (status, error) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::GONE, "{error}");
+    assert_eq!(error_code(&error), "DELETED");
+
+    // The version is written again while the deleted generation is still read
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert!(status.is_success(), "{receipt}");
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+    assert_eq!(body_of(reading).await, properties(3));
+
+    let storage = &instance.state.storage;
+    assert_eq!(storage.deferred_deletes.remove_released(), 1);
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.deletes_deferred.get(), 1);
+    assert_eq!(metrics.deferred_deletes_pending.get(), 0);
+}
+
+#[tokio::test]
+async fn a_version_moved_under_a_reader_keeps_its_old_copy_until_the_read_ends() {
+    let instance = TestInstance::start_in(TestDir::new("deferred-move"), |config| {
+        config.cold_dir = Some(format!("{}-cold", config.data_dir));
+        config.read_prefetch_depth = 0;
+    });
+    let (status, receipt) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let reading = instance.open_read("item", 1).await;
+
+    let moved = async |tier: &str| {
+        let uri = format!("/admin/tier/item/1/{tier}");
+        let (status, report) = instance.admin(Method::POST, &uri, "").await;
+        assert_eq!(status, StatusCode::OK, "{report}");
+        let report: Value = serde_json::from_str(&report).unwrap();
+        report["outcome"].as_str().unwrap().to_string()
+    };
+    assert_eq!(moved("cold").await, "moved");
+    assert!(std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    assert_eq!(instance.read("item", 1).await.1, properties(3));
+    // The copy left behind is where a move back would copy to
+    assert_eq!(moved("hot").await, "busy");
+
+    assert_eq!(body_of(reading).await, properties(3));
+    assert_eq!(instance.state.storage.deferred_deletes.remove_released(), 1);
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    assert_eq!(moved("hot").await, "moved");
+    assert_eq!(instance.read("item", 1).await.1, properties(3));
+}
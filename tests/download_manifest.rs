@@ -0,0 +1,134 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+async fn read_range(instance: &TestInstance, range: &str) -> (StatusCode, String, Option<String>) {
+    let request = Request::builder()
+        .uri("/read-item-stream/item/1")
+        .header(header::RANGE, range)
+        .body(Body::empty())
+        .unwrap();
+    let response = instance.send(request).await;
+    let content_range = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let (status, body) = text(response).await;
+    (status, body, content_range)
+}
+
+async fn manifest(instance: &TestInstance, parts: u64) -> Value {
+    let (status, manifest) = instance
+        .request(
+            Method::GET,
+            &format!("/items/item/1/download-manifest?parts={parts}"),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{manifest}");
+    serde_json::from_str(&manifest).unwrap()
+}
+
+fn sha256(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+#[tokio::test]
+async fn parts_read_with_range_requests_add_up_to_the_version() {
+    let instance = TestInstance::start("download-manifest");
+    let body = properties(400);
+    instance.upload("item", 1, &body).await;
+
+    let manifest = manifest(&instance, 4).await;
+    assert_eq!(manifest["size"], body.len());
+    assert_eq!(manifest["sha256"], sha256(&body));
+    let parts = manifest["parts"].as_array().unwrap();
+    assert_eq!(parts.len(), 4);
+    let mut reassembled = String::new();
+    for part in parts {
+        let (status, bytes, content_range) =
+            read_range(&instance, part["range"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            content_range.unwrap(),
+            format!("bytes {}-{}/{}", part["start"], part["end"], body.len())
+        );
+        assert_eq!(bytes.len() as u64, part["length"].as_u64().unwrap());
+        assert_eq!(part["sha256"], sha256(&bytes));
+        if manifest["property_aligned"] == true {
+            assert!(bytes.starts_with("<property") || reassembled.is_empty());
+        }
+        reassembled.push_str(&bytes);
+    }
+    assert_eq!(reassembled, body);
+
+    // The checksums come from the sidecar the second time
+    assert!(std::path::Path::new(&instance.data_path("item_1.parts.json")).exists());
+    assert_eq!(self::manifest(&instance, 4).await, manifest);
+}
+
+#[tokio::test]
+async fn range_reads_follow_http() {
+    let instance = TestInstance::start("download-range");
+    let body = properties(3);
+    instance.upload("item", 1, &body).await;
+    let size = body.len();
+
+    let (status, bytes, content_range) = read_range(&instance, "bytes=10-").await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(bytes, body[10..]);
+    assert_eq!(
+        content_range.unwrap(),
+        format!("bytes 10-{}/{size}", size - 1)
+    );
+
+    let (status, _, content_range) = read_range(&instance, &format!("bytes={size}-")).await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(content_range.unwrap(), format!("bytes */{size}"));
+
+    // Suffix and multiple ranges are ignored
+    for range in ["bytes=-5", "bytes=0-1,4-5"] {
+        let (status, bytes, content_range) = read_range(&instance, range).await;
+        assert_eq!(status, StatusCode::OK, "{range}");
+        assert_eq!(bytes, body);
+        assert_eq!(content_range, None);
+    }
+}
+
+#[tokio::test]
+async fn only_committed_versions_have_a_manifest() {
+    let instance = TestInstance::start("download-states");
+    let (status, error) = instance
+        .request(Method::GET, "/items/item/1/download-manifest")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{error}");
+
+    let (mut upload, response) = instance.start_upload("item", 1, 100);
+    upload.send("<item><property name=\"p0\">v</property>");
+    let (status, error) = common::eventually(|| async {
+        let answer = instance
+            .request(Method::GET, "/items/item/1/download-manifest")
+            .await;
+        (answer.0 == StatusCode::CONFLICT).then_some(answer)
+    })
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error_code(&error), "LOCKED");
+    let (status, _, _) = read_range(&instance, "bytes=0-3").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    upload.break_off();
+    let _ = response.await;
+
+    for parts in [0, 257] {
+        let (status, _) = instance
+            .request(
+                Method::GET,
+                &format!("/items/item/1/download-manifest?parts={parts}"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{parts}");
+    }
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, next_chunk, properties};
+use serde_json::Value;
+
+async fn drain_report(instance: &TestInstance) -> Value {
+    let (status, report) = instance.admin(Method::GET, "/admin/drain", "").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    serde_json::from_str(&report).unwrap()
+}
+
+#[tokio::test]
+async fn a_draining_instance_refuses_new_streams_and_reports_the_running_ones() {
+    let instance = TestInstance::start("drain");
+    let body = properties(2000);
+    instance.upload("item", 1, &body).await;
+    let (status, error) = instance.admin(Method::POST, "/admin/drain/force", "").await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+
+    let (mut upload, upload_response) = instance.start_upload("item", 2, body.len());
+    upload.send(&body[..100]);
+    let mut read = instance.open_read("item", 1).await;
+    assert_eq!(read.status(), StatusCode::OK);
+    next_chunk(read.body_mut()).await.unwrap().unwrap();
+    // The upload announces its size with the first chunk it receives
+    common::eventually(|| async {
+        let report = drain_report(&instance).await;
+        let upload_received = report["streams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|stream| stream["kind"] == "write" && !stream["expected_bytes"].is_null());
+        (report["writes"] == 1 && report["reads"] == 1 && upload_received).then_some(())
+    })
+    .await;
+
+    instance.state.drain.begin();
+    let (status, health) = instance.request(Method::GET, "/health").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(health.contains("draining"), "{health}");
+    let (status, error) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error_code(&error), "UNAVAILABLE");
+    let (status, _) = instance.upload("other", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    let report = drain_report(&instance).await;
+    assert_eq!(report["state"], "draining");
+    let upload_progress = report["streams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|stream| stream["kind"] == "write")
+        .unwrap();
+    assert_eq!(upload_progress["expected_bytes"], body.len());
+
+    // Forcing cuts both off, the upload is cleaned up
+    let (status, forced) = instance.admin(Method::POST, "/admin/drain/force", "").await;
+    assert_eq!(status, StatusCode::OK, "{forced}");
+    let forced: Value = serde_json::from_str(&forced).unwrap();
+    assert_eq!(forced["streams_cut_off"], 2);
+    assert_eq!(forced["uploads_killed"], 1);
+    upload.send(&body[100..]);
+    upload.finish();
+    let (status, error) = upload_response.await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{error}");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["reason"], "shutdown");
+    assert!(!std::path::Path::new(&instance.data_path("item_2.xml")).exists());
+    let mut cut_off = false;
+    while let Some(chunk) = next_chunk(read.body_mut()).await {
+        if chunk.is_err() {
+            cut_off = true;
+            break;
+        }
+    }
+    assert!(cut_off, "the read ran to its end");
+    drop(read);
+    common::eventually(|| async {
+        (drain_report(&instance).await["streams"] == serde_json::json!([])).then_some(())
+    })
+    .await;
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use serde_json::Value;
+
+async fn post(instance: &TestInstance, uri: &str, body: &str) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    text(instance.send(request).await).await
+}
+
+/// Names of the files in the data directory
+fn files(instance: &TestInstance) -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(instance.data_path(""))
+        .map(|entries| {
+            entries
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+#[tokio::test]
+async fn a_dry_run_reports_the_receipt_and_stores_nothing() {
+    let instance = TestInstance::start("dry-run");
+    let before = files(&instance);
+    let body = properties(5);
+    let (status, receipt) = post(&instance, "/write-item-stream/item/1?dry_run=true", &body).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["dry_run"], true);
+    assert_eq!(receipt["first_version"], true);
+    assert_eq!(receipt["property_count"], 5);
+    assert_eq!(receipt["committed_at"], Value::Null);
+    assert_eq!(files(&instance), before);
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, committed) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let committed: Value = serde_json::from_str(&committed).unwrap();
+    assert_eq!(receipt["sha256"], committed["sha256"]);
+    assert_eq!(receipt["size"], committed["size"]);
+    assert_eq!(receipt["epoch"], committed["epoch"]);
+
+    // Checked against the committed versions like a real upload
+    let (status, error) = post(&instance, "/write-item-stream/item/1?dry_run=true", &body).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "VERSION_CONFLICT");
+}
+
+#[tokio::test]
+async fn a_dry_run_is_rejected_like_the_upload() {
+    let instance = TestInstance::start("dry-run-rejected");
+    let body = r#"<properties><property name="count" type="int">12.5</property><property name="ok" type="bool">true</property></properties>"#;
+    let (status, dry_run) = post(
+        &instance,
+        "/write-item-stream/item/1?typed=true&dry_run=true",
+        body,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{dry_run}");
+    let (status, real) = post(&instance, "/write-item-stream/item/1?typed=true", body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{real}");
+    let dry_run: Value = serde_json::from_str(&dry_run).unwrap();
+    let real: Value = serde_json::from_str(&real).unwrap();
+    assert_eq!(dry_run["code"], "TYPE_MISMATCH");
+    assert_eq!(dry_run["details"], real["details"]);
+}
+
+#[tokio::test]
+async fn rewritten_dry_runs_report_no_checksum() {
+    let instance = TestInstance::start("dry-run-sorted");
+    let body = r#"<properties><property name="b">2</property><property name="a">1</property></properties>"#;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/write-item-stream/item/1?dry_run=true")
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .header("X-Canonicalize", "sort-by-name")
+        .body(Body::from(body))
+        .unwrap();
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["sha256"], Value::Null);
+    assert_eq!(receipt["size"], body.len());
+    assert_eq!(receipt["epoch"], 2);
+
+    // A new item gets an ID, but nothing is created under it
+    let (status, receipt) = post(&instance, "/items?dry_run=true", &properties(2)).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["dry_run"], true);
+    let (status, _) = instance.read(receipt["item_id"].as_str().unwrap(), 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
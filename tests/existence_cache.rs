@@ -0,0 +1,168 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use http_body_util::BodyExt;
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+const WRITERS: u64 = 3;
+const ROUNDS: u64 = 20;
+const READERS: u64 = 6;
+
+fn body_of(version: u64) -> String {
+    format!("<properties><property name=\"v\" type=\"string\">{version}</property></properties>")
+}
+
+#[derive(Default)]
+struct History {
+    committed: BTreeSet<u64>,
+    deleted: BTreeSet<u64>,
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn lookups_never_see_a_version_that_was_not_committed() {
+    let instance = Arc::new(TestInstance::start("existence-cache"));
+    let history = Arc::new(Mutex::new(History::default()));
+    let versions = WRITERS * ROUNDS;
+
+    let mut writers = Vec::new();
+    for writer in 0..WRITERS {
+        let instance = instance.clone();
+        let history = history.clone();
+        writers.push(tokio::spawn(async move {
+            for round in 0..ROUNDS {
+                let version = round * WRITERS + writer + 1;
+                let (status, _) = instance.upload("item", version, &body_of(version)).await;
+                // Losing to a writer that went higher is expected
+                if !status.is_success() {
+                    continue;
+                }
+                history.lock().unwrap().committed.insert(version);
+                if version.is_multiple_of(2) {
+                    let uri = format!("/items/item/{version}");
+                    let (status, body) = instance.request(Method::DELETE, &uri).await;
+                    assert!(status.is_success(), "{body}");
+                    history.lock().unwrap().deleted.insert(version);
+                }
+            }
+        }));
+    }
+
+    let mut readers = Vec::new();
+    for reader in 0..READERS {
+        let instance = instance.clone();
+        readers.push(tokio::spawn(async move {
+            let mut served = Vec::new();
+            let mut seed = reader * 7919 + 1;
+            for _ in 0..200 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let version = (seed >> 33) % versions + 1;
+                let response = instance.open_read("item", version).await;
+                let status = response.status();
+                // A delete landing while the body streams lets the read finish
+                let body = response.into_body().collect().await.unwrap();
+                let body = String::from_utf8_lossy(&body.to_bytes()).into_owned();
+                match status {
+                    StatusCode::OK => {
+                        assert_eq!(body, body_of(version));
+                        served.push(version);
+                    }
+                    // Deleted, its files waiting for the readers streaming it
+                    StatusCode::NOT_FOUND | StatusCode::GONE => (),
+                    status => panic!("reading version {version} gave {status}: {body}"),
+                }
+            }
+            served
+        }));
+    }
+
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    let mut served = BTreeSet::new();
+    for reader in readers {
+        served.extend(reader.await.unwrap());
+    }
+    let history = std::mem::take(&mut *history.lock().unwrap());
+    assert!(
+        served.is_subset(&history.committed),
+        "served {served:?}, committed {:?}",
+        history.committed
+    );
+    // Once the writers are done, the cache agrees with the metadata for every version
+    for version in 1..=versions {
+        let expected = history.committed.contains(&version) && !history.deleted.contains(&version);
+        let (status, _) = instance.read("item", version).await;
+        assert_eq!(status == StatusCode::OK, expected, "version {version}");
+        let (status, _) = instance
+            .request(Method::GET, &format!("/items/item/{version}/receipt"))
+            .await;
+        assert_eq!(status == StatusCode::OK, expected, "receipt of {version}");
+    }
+}
+
+#[tokio::test]
+async fn versions_restored_by_another_process_are_found_again() {
+    let instance = TestInstance::start("existence-cache-restore");
+    let body = properties(4);
+    for version in 1..=2 {
+        instance.upload("item", version, &body).await;
+    }
+    let metadata_path = instance.data_path("item_metadata.xml");
+    let data_path = instance.data_path("item_2.xml");
+    let metadata = std::fs::read(&metadata_path).unwrap();
+    let data = std::fs::read(&data_path).unwrap();
+
+    let (status, _) = instance.request(Method::DELETE, "/items/item/2").await;
+    assert!(status.is_success());
+    assert_eq!(instance.read("item", 2).await.0, StatusCode::NOT_FOUND);
+
+    std::fs::write(&data_path, data).unwrap();
+    std::fs::write(&metadata_path, metadata).unwrap();
+    // The miss is remembered for the negative cache TTL, then the version is looked up again
+    assert_eq!(instance.read("item", 2).await.0, StatusCode::NOT_FOUND);
+    let read = common::eventually(|| async {
+        let read = instance.read("item", 2).await;
+        (read.0 == StatusCode::OK).then_some(read)
+    })
+    .await;
+    assert_eq!(read.1, body);
+
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_existence_cache_hits_total"));
+    assert!(metrics.contains("stream_db_existence_cache_misses_total"));
+}
+
+#[tokio::test]
+async fn misses_are_remembered_until_the_version_is_committed() {
+    let instance = TestInstance::start_with("negative-cache", |config| {
+        config.negative_cache_ttl_ms = 60_000;
+    });
+    for _ in 0..5 {
+        assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    }
+    assert_eq!(instance.state.metrics.negative_cache_hits.get(), 4);
+
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(
+        instance.read("item", 1).await,
+        (StatusCode::OK, properties(2))
+    );
+    assert_eq!(instance.state.metrics.negative_cache_hits.get(), 4);
+}
+
+#[tokio::test]
+async fn misses_are_not_remembered_without_a_ttl() {
+    let instance = TestInstance::start_with("negative-cache-off", |config| {
+        config.negative_cache_ttl_ms = 0;
+    });
+    for _ in 0..5 {
+        assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    }
+    assert_eq!(instance.state.metrics.negative_cache_hits.get(), 0);
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestInstance;
+use serde_json::{Value, json};
+
+const SUMMARY: &str = "<summary count=\"2\">two &amp; done</summary>";
+
+fn with_extras(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.extra_elements = vec!["summary".to_string(), "provenance".to_string()];
+        config.extra_element_max_bytes = 100;
+    })
+}
+
+async fn receipt(instance: &TestInstance, item_id: &str) -> Value {
+    let (status, receipt) = instance
+        .request(Method::GET, &format!("/items/{item_id}/1/receipt"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    serde_json::from_str(&receipt).unwrap()
+}
+
+#[tokio::test]
+async fn extra_elements_are_stored_in_place_and_copied_into_the_receipt() {
+    let instance = with_extras("extra-elements");
+    let provenance = format!("<provenance>{}</provenance>", "p".repeat(200));
+    let body = format!(
+        "<properties><property name=\"a\" type=\"string\">1</property>\
+         <property name=\"b\" type=\"string\">x<summary>nested</summary></property>\
+         {SUMMARY}{provenance}</properties>"
+    );
+
+    // In small chunks, so end tags are split between them
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    for chunk in body.as_bytes().chunks(3) {
+        upload.send(std::str::from_utf8(chunk).unwrap());
+    }
+    upload.finish();
+    let (status, answer) = response.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{answer}");
+    assert_eq!(instance.read("item", 1).await.1, body);
+
+    let written = receipt(&instance, "item").await;
+    assert_eq!(written["property_count"], 2);
+    assert_eq!(written["extra_elements"], json!({ "summary": SUMMARY }));
+    assert_eq!(written["extra_elements_truncated"], json!(["provenance"]));
+
+    // The copies survive a restart and a metadata rewrite
+    let instance = TestInstance::start_in(instance.stop(), |config| {
+        config.extra_elements = vec!["summary".to_string()];
+    });
+    instance
+        .upload("item", 2, "<properties></properties>")
+        .await;
+    instance.request(Method::DELETE, "/items/item/2").await;
+    assert_eq!(
+        receipt(&instance, "item").await["extra_elements"],
+        json!({ "summary": SUMMARY })
+    );
+}
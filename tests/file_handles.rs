@@ -0,0 +1,66 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, first_version_status, properties, text};
+use serde_json::Value;
+
+async fn file_handles(instance: &TestInstance) -> Value {
+    let (status, storage) = instance.admin(Method::GET, "/admin/storage", "").await;
+    assert_eq!(status, StatusCode::OK, "{storage}");
+    serde_json::from_str::<Value>(&storage).unwrap()["file_handles"].clone()
+}
+
+#[tokio::test]
+async fn streams_are_refused_rather_than_running_out_of_file_handles() {
+    let instance = TestInstance::start_with("file-handles", |config| {
+        config.max_open_files = Some(10);
+    });
+    let body = properties(20);
+
+    // Idle versions are closed to make room for the next ones
+    for version in 1..=8 {
+        let (status, receipt) = instance.upload("item", version, &body).await;
+        assert_eq!(status, first_version_status(version), "{receipt}");
+    }
+    assert!(
+        file_handles(&instance).await["open_files"]
+            .as_u64()
+            .unwrap()
+            <= 10
+    );
+
+    let (mut first, first_response) = instance.start_upload("first", 1, body.len());
+    let (mut second, second_response) = instance.start_upload("second", 1, body.len());
+    first.send(&body[..100]);
+    second.send(&body[..100]);
+    // Both hold their handles once they wrote their first property
+    let written = body[..100].find("</property>").unwrap() + "</property>".len();
+    common::eventually(|| async {
+        let (_, usage) = instance.admin(Method::GET, "/admin/usage", "").await;
+        let usage: Value = serde_json::from_str(&usage).unwrap();
+        (usage["in_flight_bytes"] == written * 2).then_some(())
+    })
+    .await;
+
+    let response = instance.open_read("item", 1).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["Retry-After"], "1");
+    let (_, error) = text(response).await;
+    assert_eq!(error_code(&error), "UNAVAILABLE");
+    let (status, _) = instance.upload("third", 1, &body).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let (status, _) = instance.request(Method::GET, "/health").await;
+    assert_eq!(status, StatusCode::OK);
+
+    first.break_off();
+    second.break_off();
+    first_response.await.unwrap();
+    second_response.await.unwrap();
+    common::eventually(|| async {
+        (file_handles(&instance).await["open_files"] == 0).then_some(())
+    })
+    .await;
+    assert_eq!(instance.read("item", 1).await, (StatusCode::OK, body));
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_streams_rejected_open_files_total 2"));
+}
@@ -0,0 +1,288 @@
+//! Round trips of everything this server writes down and later reads back, or hands to
+//! clients that may run another release: the item metadata XML, the JSON files next to the
+//! data files, and the bodies of the API. Each must read back to what was written, and
+//! keep reading when a newer release added fields to it.
+
+mod common;
+
+use common::{TestInstance, properties};
+
+use axum::http::{Method, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use stream_db::persistence::block_index::BlockIndex;
+use stream_db::persistence::idempotency::RecordedResponse;
+use stream_db::persistence::item_metadata::{ItemMetadata, Provenance, VersionMetadata};
+use stream_db::persistence::item_stats::ItemStats;
+use stream_db::persistence::journal::Checkpoint;
+use stream_db::types::dto::{
+    ErrorBody, HealthStatus, InFlightProgress, ItemStatsResponse, ReadInfo, ReadSummary,
+    StorageReport, UploadAck, VersionListing, WriteReceipt, WsWriteReceipt,
+};
+
+use std::collections::BTreeMap;
+
+/// Read `json` as a `T` and write it again, which must give back the same JSON, also
+/// once fields `T` does not know about were added to it
+fn round_trip<T: Serialize + DeserializeOwned>(json: &Value) {
+    let name = std::any::type_name::<T>();
+    let read: T = serde_json::from_value(json.clone())
+        .unwrap_or_else(|error| panic!("{name} does not read {json}: {error}"));
+    assert_eq!(&serde_json::to_value(&read).unwrap(), json, "{name}");
+
+    let mut newer = json.clone();
+    newer["added_by_a_newer_release"] = json!({ "nested": [1, "two", null] });
+    let read: T = serde_json::from_value(newer)
+        .unwrap_or_else(|error| panic!("{name} refuses an unknown field: {error}"));
+    assert_eq!(&serde_json::to_value(&read).unwrap(), json, "{name}");
+}
+
+fn parse(json: &str) -> Value {
+    serde_json::from_str(json).unwrap_or_else(|error| panic!("not JSON: {json}: {error}"))
+}
+
+/// A version with every field the metadata can record set, values chosen to need
+/// escaping wherever the format escapes
+fn fully_recorded_version(version: u64) -> VersionMetadata {
+    VersionMetadata {
+        version,
+        size: Some(1234),
+        property_count: Some(7),
+        sha256: Some("ab".repeat(32)),
+        committed_at: Some("2026-01-02T03:04:05.678Z".to_string()),
+        request_id: Some("request \"one\" <&>".to_string()),
+        epoch: Some(2),
+        location: Some("/cold/tier dir".to_string()),
+        first_version: Some(version == 1),
+        quarantined_at: Some("2026-02-01T00:00:00Z".to_string()),
+        quarantine_check: Some("sweep".to_string()),
+        found_size: Some(1200),
+        found_sha256: Some("cd".repeat(32)),
+        computed_at: Some("2026-01-03T00:00:00Z".to_string()),
+        last_verified_at: Some("2026-01-04T00:00:00Z".to_string()),
+        moved_at: Some("2026-01-05T00:00:00Z".to_string()),
+        hook_failed: Some(true),
+        failed_hooks: Some(vec!["notify".to_string(), "index-sync".to_string()]),
+        s3_deleted_at: Some("2026-02-02T00:00:00Z".to_string()),
+        immutable_until: Some("2027-01-01T00:00:00Z".to_string()),
+        content_encoding: Some("gzip".to_string()),
+        segments: Some(vec![0, 600, 1234]),
+        commit_transforms: Some(vec!["wrap_root".to_string(), "dedupe_last".to_string()]),
+        write_amplification: Some(0.75),
+        extra_elements: Some(BTreeMap::from([(
+            "summary".to_string(),
+            "<summary lang=\"en\">a &amp; b</summary>".to_string(),
+        )])),
+        extra_elements_truncated: Some(vec!["provenance".to_string()]),
+        user_metadata: Some(BTreeMap::from([(
+            "camera".to_string(),
+            "x100 \"mk <2>\" & co".to_string(),
+        )])),
+        provenance: Provenance {
+            producer: Some("ingest".to_string()),
+            producer_version: Some("1.2.3".to_string()),
+            commit_message: Some("line one\nline \"two\" & <three>".to_string()),
+            commit_message_truncated: Some(true),
+            authenticated_as: Some("producer-a".to_string()),
+        },
+    }
+}
+
+fn fully_recorded_item() -> ItemMetadata {
+    let mut metadata = ItemMetadata::default();
+    metadata.add_committed(fully_recorded_version(1));
+    metadata.add_committed(VersionMetadata {
+        version: 2,
+        ..VersionMetadata::default()
+    });
+    metadata.retired.insert(3, 1);
+    metadata
+}
+
+/// What a metadata file says, in a form that compares
+fn contents(metadata: &ItemMetadata) -> Value {
+    json!({
+        "latest_version": metadata.latest_version,
+        "versions": metadata.versions.values().collect::<Vec<_>>(),
+        "retired": metadata.retired,
+    })
+}
+
+#[test]
+fn item_metadata_reads_back_what_was_written() {
+    let metadata = fully_recorded_item();
+    let xml = metadata.to_xml();
+    let read = ItemMetadata::parse(xml.as_bytes()).unwrap();
+    assert_eq!(contents(&read), contents(&metadata));
+    assert_eq!(read.to_xml(), xml, "writing it again changes nothing");
+}
+
+#[test]
+fn item_metadata_skips_what_a_newer_release_added() {
+    let metadata = fully_recorded_item();
+    let xml = metadata
+        .to_xml()
+        .replacen(
+            "<committed ",
+            "<committed added_by_a_newer_release=\"yes\" ",
+            1,
+        )
+        .replacen(
+            "<extra_element ",
+            "<future_child kind=\"a\"><nested>text</nested></future_child><extra_element ",
+            1,
+        )
+        .replacen("<versions>", "<versions><future_entry version=\"9\"/>", 1)
+        .replacen(
+            "</metadata>",
+            "<future_section><entry/></future_section></metadata>",
+            1,
+        );
+    assert!(
+        xml.contains("future_section"),
+        "the metadata changed: {xml}"
+    );
+
+    let read = ItemMetadata::parse(xml.as_bytes()).unwrap();
+    assert_eq!(contents(&read), contents(&metadata));
+}
+
+#[test]
+fn item_metadata_of_the_first_format_still_reads() {
+    let read = ItemMetadata::parse(b"<metadata>\n    <version>3</version>\n</metadata>").unwrap();
+    assert_eq!(read.latest_version, Some(3));
+    // The committed entry is added by the metadata-versions migration, not on read
+    assert!(read.versions.is_empty());
+    assert!(read.retired.is_empty());
+}
+
+#[test]
+fn files_next_to_the_data_read_back_what_was_written() {
+    round_trip::<BlockIndex>(&json!({
+        "size": 4096,
+        "property_count": 40,
+        "block_properties": 16,
+        "block_bytes": 1024,
+        "blocks": [{ "property": 0, "offset": 12 }, { "property": 16, "offset": 1030 }],
+    }));
+    round_trip::<Checkpoint>(&json!({
+        "offset": 65536,
+        "sha256": "ef".repeat(32),
+        "recorded_at": "2026-01-02T03:04:05Z",
+    }));
+    round_trip::<ItemStats>(&json!({
+        "versions": {
+            "1": {
+                "reads_started": 3,
+                "reads_completed": 2,
+                "bytes_served": 900,
+                "last_accessed": "2026-01-02T03:04:05.678Z",
+            },
+            "2": { "reads_started": 0, "reads_completed": 0, "bytes_served": 0 },
+        },
+    }));
+    round_trip::<RecordedResponse>(&json!({
+        "request": "POST /write-item-stream/item/1",
+        "status": 201,
+        "headers": [["content-type", "application/json"]],
+        "body": "e30=",
+        "recorded_at": 1767323045,
+        "used_at": 1767323046,
+    }));
+}
+
+#[test]
+fn recorded_versions_read_back_what_was_written() {
+    round_trip::<VersionMetadata>(&serde_json::to_value(fully_recorded_version(1)).unwrap());
+    round_trip::<VersionMetadata>(
+        &serde_json::to_value(VersionMetadata {
+            version: 1,
+            ..VersionMetadata::default()
+        })
+        .unwrap(),
+    );
+}
+
+#[test]
+fn websocket_messages_read_back_what_was_written() {
+    round_trip::<UploadAck>(&json!({ "ack": 100, "durable": 64 }));
+    round_trip::<ReadInfo>(&json!({
+        "item_id": "item",
+        "version": 1,
+        "finished": false,
+        "content_type": "application/xml",
+        "size": 0,
+    }));
+    round_trip::<ReadInfo>(&json!({
+        "item_id": "item",
+        "version": 1,
+        "finished": true,
+        "content_type": "application/xml",
+        "size": 120,
+    }));
+    round_trip::<ReadSummary>(&json!({ "total_bytes": 120, "sha256": "ab".repeat(32) }));
+}
+
+#[tokio::test]
+async fn response_bodies_read_back_what_was_sent() {
+    let instance = TestInstance::start("formats");
+
+    let (status, body) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    let receipt = parse(&body);
+    round_trip::<WriteReceipt>(&receipt);
+    let mut ws_receipt = receipt.clone();
+    ws_receipt["consistency_token"] = json!("token");
+    round_trip::<WsWriteReceipt>(&ws_receipt);
+
+    let (status, body) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    round_trip::<VersionMetadata>(&parse(&body));
+
+    let (status, body) = instance.request(Method::GET, "/items/item/versions").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    round_trip::<VersionListing>(&parse(&body));
+
+    let (status, body) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, body) = instance.request(Method::GET, "/items/item/stats").await;
+    if status == StatusCode::OK {
+        round_trip::<ItemStatsResponse>(&parse(&body));
+    }
+    round_trip::<ItemStatsResponse>(&json!({
+        "item_id": "item",
+        "reads_started": 1,
+        "reads_completed": 1,
+        "bytes_served": 120,
+        "versions": { "1": { "reads_started": 1, "reads_completed": 1, "bytes_served": 120 } },
+    }));
+
+    let (status, body) = instance.request(Method::GET, "/health").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    round_trip::<HealthStatus>(&parse(&body));
+
+    let (status, body) = instance.admin(Method::GET, "/admin/storage", "").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    round_trip::<StorageReport>(&parse(&body));
+
+    let (status, body) = instance.read("item", 9).await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{body}");
+    round_trip::<ErrorBody>(&parse(&body));
+
+    let (status, body) = instance.upload("item", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{body}");
+    round_trip::<ErrorBody>(&parse(&body));
+
+    round_trip::<InFlightProgress>(
+        &serde_json::to_value(InFlightProgress::uploading("item".to_string(), 2, 10, 4)).unwrap(),
+    );
+}
+
+#[test]
+fn error_bodies_of_older_releases_still_read() {
+    let read: ErrorBody =
+        serde_json::from_value(json!({ "code": "NOT_FOUND", "message": "gone" })).unwrap();
+    assert_eq!(read.details, Value::Null);
+    assert_eq!(read.request_id, None);
+}
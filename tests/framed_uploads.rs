@@ -0,0 +1,87 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use serde_json::Value;
+
+/// Each of `segments` as a frame: its length as a big-endian u32, then its bytes
+fn framed(segments: &[&str]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for segment in segments {
+        body.extend((segment.len() as u32).to_be_bytes());
+        body.extend(segment.as_bytes());
+    }
+    body
+}
+
+fn framed_upload(item_id: &str, version: u64, body: Vec<u8>) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/{version}"))
+        .header(header::CONTENT_TYPE, "application/x-streamdb-framed")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn three_segments_round_trip_one_by_one_and_whole() {
+    let instance = TestInstance::start("framed");
+    let body = properties(4);
+    // Split inside properties as well as between them
+    let segments = [&body[..25], &body[25..100], &body[100..]];
+    let (status, receipt) = text(
+        instance
+            .send(framed_upload("item", 1, framed(&segments)))
+            .await,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(
+        receipt["segments"],
+        serde_json::json!([0, 25, 100, body.len()])
+    );
+
+    assert_eq!(instance.read("item", 1).await.1, body);
+    for (index, segment) in segments.iter().enumerate() {
+        let response = instance
+            .open(&format!("/read-item-stream/item/1?segment={index}"))
+            .await;
+        assert_eq!(response.headers()["X-Segment"], index.to_string());
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(etag.ends_with(&format!(".segment{index}\"")), "{etag}");
+        let (status, read) = text(response).await;
+        assert_eq!(status, StatusCode::OK, "{read}");
+        assert_eq!(read, *segment, "segment {index}");
+    }
+
+    let response = instance.open("/read-item-stream/item/1?segment=3").await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()["X-Segment-Count"], "3");
+}
+
+#[tokio::test]
+async fn malformed_framing_fails_the_upload_and_leaves_nothing() {
+    let instance = TestInstance::start("framed-malformed");
+    let body = properties(2);
+    let mut truncated = framed(&[&body]);
+    truncated.truncate(truncated.len() - 5);
+    let empty_frame = framed(&[&body[..20], "", &body[20..]]);
+
+    for (version, body) in [(1, truncated), (2, empty_frame)] {
+        let (status, error) = text(instance.send(framed_upload("item", version, body)).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+        assert_eq!(error_code(&error), "BAD_REQUEST", "{error}");
+        let data_path = instance.data_path(&format!("item_{version}.xml"));
+        assert!(!std::path::Path::new(&data_path).exists(), "{data_path}");
+        assert_eq!(
+            instance.read("item", version).await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
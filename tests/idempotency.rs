@@ -0,0 +1,119 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, properties, text};
+
+/// Send `method uri` with `Idempotency-Key: key`, and `Authorization: authorization` if
+/// given, returning whether the response was a replay
+async fn keyed(
+    instance: &TestInstance,
+    method: Method,
+    uri: &str,
+    key: &str,
+    authorization: Option<&str>,
+) -> (StatusCode, bool, String) {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Idempotency-Key", key);
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    let response = instance.send(request.body(Body::empty()).unwrap()).await;
+    let replayed = response.headers().get("X-Idempotent-Replay").is_some();
+    let (status, body) = text(response).await;
+    (status, replayed, body)
+}
+
+#[tokio::test]
+async fn a_retried_delete_is_replayed_instead_of_deleting_the_new_upload() {
+    let instance = TestInstance::start("idempotency");
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, replayed, report) =
+        keyed(&instance, Method::DELETE, "/items/item/1", "delete-1", None).await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert!(!replayed);
+    let (status, _) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // The retry gets the first answer and the new upload stays
+    let (status, replayed, replay) =
+        keyed(&instance, Method::DELETE, "/items/item/1", "delete-1", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(replayed);
+    assert_eq!(replay, report);
+    assert_eq!(instance.read("item", 1).await.1, properties(3));
+
+    // Other credentials have keys of their own
+    let (status, replayed, _) = keyed(
+        &instance,
+        Method::DELETE,
+        "/items/item/1",
+        "delete-1",
+        Some("Bearer someone-else"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!replayed);
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_key_is_refused_for_another_request() {
+    let instance = TestInstance::start("idempotency-reuse");
+    let (status, _, _) = keyed(&instance, Method::DELETE, "/items/a/1", "key", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, replayed, error) =
+        keyed(&instance, Method::DELETE, "/items/b/1", "key", None).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    assert!(!replayed);
+    assert_eq!(common::error_code(&error), "IDEMPOTENCY_KEY_REUSED");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["request"], "DELETE /items/a/1");
+
+    let (status, _, error) = keyed(&instance, Method::DELETE, "/items/a/1", "", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+}
+
+#[tokio::test]
+async fn concurrent_attempts_with_one_key_run_once() {
+    let instance = TestInstance::start("idempotency-concurrent");
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let attempts = (0..4).map(|_| keyed(&instance, Method::DELETE, "/items/item/1", "once", None));
+    let outcomes = futures::future::join_all(attempts).await;
+    assert!(
+        outcomes
+            .iter()
+            .all(|(status, _, body)| *status == StatusCode::OK && *body == outcomes[0].2),
+        "{outcomes:?}"
+    );
+    let replays = outcomes.iter().filter(|(_, replayed, _)| *replayed).count();
+    assert_eq!(replays, 3);
+}
+
+#[tokio::test]
+async fn recorded_responses_survive_a_restart() {
+    let instance = TestInstance::start("idempotency-restart");
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _, report) =
+        keyed(&instance, Method::DELETE, "/items/item/1", "restart", None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    let (status, _) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, replayed, replay) =
+        keyed(&instance, Method::DELETE, "/items/item/1", "restart", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(replayed);
+    assert_eq!(replay, report);
+    assert_eq!(instance.read("item", 1).await.1, properties(3));
+}
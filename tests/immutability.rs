@@ -0,0 +1,142 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties, text, upload_request};
+use serde_json::{Value, json};
+use stream_db::logic::verification_sweep;
+
+const UNTIL: &str = "2099-01-01T00:00:00Z";
+
+/// The instant a recorded deadline stands for, however it is written
+fn instant(deadline: &Value) -> chrono::DateTime<chrono::Utc> {
+    deadline.as_str().unwrap().parse().unwrap()
+}
+
+async fn upload_immutable(
+    instance: &TestInstance,
+    item_id: &str,
+    until: &str,
+) -> (StatusCode, Value) {
+    let mut request = upload_request(item_id, 1, &properties(2));
+    request
+        .headers_mut()
+        .insert("X-Immutable-Until", until.parse().unwrap());
+    let (status, body) = text(instance.send(request).await).await;
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+async fn set_immutability(instance: &TestInstance, until: &str) -> (StatusCode, String) {
+    instance
+        .json(
+            Method::PUT,
+            "/items/item/1/immutability",
+            &json!({ "immutable_until": until }).to_string(),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn immutable_versions_are_removed_by_nobody_before_their_deadline() {
+    let instance = TestInstance::start("immutable");
+    let (status, receipt) = upload_immutable(&instance, "item", UNTIL).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(instant(&receipt["immutable_until"]), instant(&json!(UNTIL)));
+
+    let (status, error) = instance.admin(Method::DELETE, "/items/item/1", "").await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{error}");
+    assert_eq!(error_code(&error), "IMMUTABLE");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(
+        instant(&error["details"]["immutable_until"]),
+        instant(&json!(UNTIL))
+    );
+
+    let (status, report) = instance
+        .admin(
+            Method::POST,
+            "/admin/bulk-delete",
+            r#"{"item_id_prefix": "item", "dry_run": true}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(report.contains("\"action\":\"skipped\""), "{report}");
+    let (status, error) = instance
+        .admin(
+            Method::POST,
+            "/admin/items/item/reset-version",
+            r#"{"version": 0, "force": true}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{error}");
+    assert_eq!(error_code(&error), "IMMUTABLE");
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+
+    // The deadline only moves out
+    let (status, error) = set_immutability(&instance, "2098-01-01T00:00:00Z").await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{error}");
+    let (status, extended) = set_immutability(&instance, "2100-01-01T00:00:00Z").await;
+    assert_eq!(status, StatusCode::OK, "{extended}");
+    let extended: Value = serde_json::from_str(&extended).unwrap();
+    assert_eq!(
+        instant(&extended["immutable_until"]),
+        instant(&json!("2100-01-01T00:00:00Z"))
+    );
+    let (status, _) = instance
+        .json(
+            Method::PUT,
+            "/items/item/2/immutability",
+            &json!({ "immutable_until": UNTIL }).to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    for until in ["2001-01-01T00:00:00Z", "tomorrow"] {
+        let (status, _) = upload_immutable(&instance, "other", until).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{until}");
+    }
+}
+
+#[tokio::test]
+async fn an_items_setting_keeps_each_commit_immutable_for_a_while() {
+    let instance = TestInstance::start("immutable-setting");
+    let (status, _) = instance
+        .json(
+            Method::PUT,
+            "/items/item/settings",
+            r#"{"immutable_for_secs": 1}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert!(receipt["immutable_until"].is_string(), "{receipt}");
+    let (status, _) = instance.admin(Method::DELETE, "/items/item/1", "").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+    let (status, report) = instance.admin(Method::DELETE, "/items/item/1", "").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+}
+
+#[tokio::test]
+async fn the_sweep_raises_an_alarm_for_immutable_versions_gone_missing() {
+    let instance = TestInstance::start_with("immutable-sweep", |config| {
+        config.verify_sweep = true;
+    });
+    upload_immutable(&instance, "kept", UNTIL).await;
+    upload_immutable(&instance, "lost", UNTIL).await;
+    std::fs::remove_file(instance.data_path("lost_1.xml")).unwrap();
+
+    verification_sweep::start(instance.state.clone());
+    let cycle = common::eventually(|| async {
+        let (_, report) = instance.admin(Method::GET, "/admin/verification", "").await;
+        let report: Value = serde_json::from_str(&report).unwrap();
+        Some(report["last_cycle"].clone()).filter(|cycle| !cycle.is_null())
+    })
+    .await;
+    assert_eq!(cycle["immutable_checked"], 2, "{cycle}");
+    assert_eq!(cycle["immutable_missing"], 1, "{cycle}");
+    assert!(cycle["errors"].to_string().contains("lost"), "{cycle}");
+    assert_eq!(instance.state.metrics.immutable_versions_missing.get(), 1);
+}
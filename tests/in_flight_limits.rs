@@ -0,0 +1,108 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, error_code, properties};
+use serde_json::Value;
+
+/// Wait until version `version` of `item_id` is being written
+async fn wait_for_upload(instance: &TestInstance, item_id: &str, version: u64) {
+    common::eventually(|| async {
+        let (status, _) = instance
+            .request(Method::GET, &format!("/items/{item_id}/{version}/receipt"))
+            .await;
+        (status == StatusCode::CONFLICT).then_some(())
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn a_second_upload_of_an_item_is_refused_by_default() {
+    let instance = TestInstance::start("in-flight-default");
+    let body = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 5, body.len());
+    upload.send(&body[..10]);
+    wait_for_upload(&instance, "item", 5).await;
+
+    for version in [5, 6] {
+        let (status, error) = instance.upload("item", version, &properties(2)).await;
+        assert_eq!(status, StatusCode::LOCKED, "{error}");
+        assert_eq!(error_code(&error), "IN_FLIGHT_LIMIT", "{error}");
+        let error: Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["details"]["in_flight"], serde_json::json!([5]));
+        assert_eq!(error["details"]["max_per_item"], 1);
+    }
+    let (status, _) = instance.upload("other", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    upload.send(&body[10..]);
+    upload.finish();
+    let (status, receipt) = running.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let (status, _) = instance.upload("item", 6, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn the_instance_wide_limit_holds_uploads_of_every_item() {
+    let instance = TestInstance::start_with("in-flight-total", |config| {
+        config.max_in_flight_uploads = Some(1);
+    });
+    let body = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..10]);
+    wait_for_upload(&instance, "item", 1).await;
+
+    let (status, error) = instance.upload("other", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::LOCKED, "{error}");
+    assert_eq!(error_code(&error), "IN_FLIGHT_LIMIT", "{error}");
+
+    upload.send(&body[10..]);
+    upload.finish();
+    assert_eq!(running.await.unwrap().0, StatusCode::CREATED);
+    let (status, _) = instance.upload("other", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+/// With several uploads per item, version 5 is overtaken by version 7 and fails at its
+/// commit, leaving no data file behind
+async fn overtaken_upload_fails_cleanly(name: &str, inflight: bool) {
+    let dir = TestDir::new(name);
+    let inflight_dir = inflight.then(|| dir.join("inflight"));
+    let instance = TestInstance::start_in(dir, |config| {
+        config.max_in_flight_per_item = 3;
+        config.inflight_dir = inflight_dir.clone();
+    });
+    let body = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 5, body.len());
+    upload.send(&body[..10]);
+    wait_for_upload(&instance, "item", 5).await;
+
+    let (status, receipt) = instance.upload("item", 7, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    upload.send(&body[10..]);
+    upload.finish();
+    let (status, error) = running.await.unwrap();
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "VERSION_SUPERSEDED", "{error}");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["requested"], 5);
+    assert_eq!(error["details"]["current"], 7);
+
+    assert!(!std::path::Path::new(&instance.data_path("item_5.xml")).exists());
+    if let Some(inflight_dir) = &inflight_dir {
+        let left = std::fs::read_dir(inflight_dir).unwrap().count();
+        assert_eq!(left, 0, "files left in the in-flight directory");
+    }
+    assert_eq!(instance.read("item", 5).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(instance.read("item", 7).await.1, properties(2));
+}
+
+#[tokio::test]
+async fn an_upload_overtaken_by_a_newer_version_fails_at_commit() {
+    overtaken_upload_fails_cleanly("in-flight-superseded", false).await;
+}
+
+#[tokio::test]
+async fn an_upload_overtaken_from_the_inflight_dir_leaves_nothing_behind() {
+    overtaken_upload_fails_cleanly("in-flight-superseded-renamed", true).await;
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use common::{TestInstance, properties, text, upload_request};
+use stream_db::api::router;
+use stream_db::metrics::MetricDatasets;
+use tower::ServiceExt;
+
+/// Reads mounted under `/public` and writes under `/internal` of one app, as an embedder
+/// serving them to different clients would
+fn split_app(instance: &TestInstance) -> Router {
+    Router::new()
+        .nest("/public", router::read_routes(instance.state.clone()))
+        .nest("/internal", router::write_routes(instance.state.clone()))
+}
+
+async fn get(app: &Router, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    text(app.clone().oneshot(request).await.unwrap()).await
+}
+
+#[tokio::test]
+async fn routers_mount_under_their_own_prefixes() {
+    let instance = TestInstance::start("prefixes");
+    let app = split_app(&instance);
+    let body = properties(3);
+
+    let mut request = upload_request("item", 1, &body);
+    *request.uri_mut() = "/internal/write-item-stream/item/1".parse().unwrap();
+    let (status, receipt) = text(app.clone().oneshot(request).await.unwrap()).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let (status, read) = get(&app, "/public/read-item-stream/item/1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, body);
+    // Writes are not served under the read prefix and the other way around
+    let mut request = upload_request("item", 2, &body);
+    *request.uri_mut() = "/public/write-item-stream/item/2".parse().unwrap();
+    let (status, _) = text(app.clone().oneshot(request).await.unwrap()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get(&app, "/internal/read-item-stream/item/1").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn instances_in_one_process_keep_their_data_apart() {
+    let first = TestInstance::start("first");
+    let second = TestInstance::start("second");
+    assert_ne!(first.state.config.data_dir, second.state.config.data_dir);
+
+    let (status, _) = first.upload("shared", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = second.upload("shared", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = first.upload("only-first", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    assert_eq!(first.read("shared", 1).await.1, properties(1));
+    assert_eq!(second.read("shared", 1).await.1, properties(2));
+    let (status, _) = second.read("only-first", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(std::path::Path::new(&first.data_path("only-first_metadata.xml")).exists());
+    assert!(!std::path::Path::new(&second.data_path("only-first_metadata.xml")).exists());
+}
+
+#[tokio::test]
+async fn uploads_in_flight_stay_in_the_registry_of_their_instance() {
+    let first = TestInstance::start("registry-first");
+    let second = TestInstance::start("registry-second");
+    let body = properties(4);
+    let (mut upload, response) = first.start_upload("item", 1, body.len());
+    upload.send(&body[..20]);
+    let progress = common::eventually(|| async {
+        let (status, progress) = first.request(Method::GET, "/items/item/1/receipt").await;
+        (status == StatusCode::CONFLICT).then_some(progress)
+    })
+    .await;
+    assert!(progress.contains("\"state\":\"uploading\""), "{progress}");
+
+    // The other instance neither knows the upload nor serves it, and takes an upload of
+    // the same version as if it did not exist
+    let (status, _) = second.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = second.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = second.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    upload.send(&body[20..]);
+    upload.finish();
+    let (status, receipt) = response.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(first.read("item", 1).await.1, body);
+    assert_eq!(second.read("item", 1).await.1, properties(2));
+}
+
+/// The value of `name` in a `/metrics` response
+fn metric(metrics: &str, name: &str) -> u64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{name} ")))
+        .unwrap_or_else(|| panic!("{name} missing from {metrics}"))
+        .parse()
+        .unwrap()
+}
+
+/// The `dataset` labels of `name` on the instance's `/metrics`, with their values
+async fn dataset_series(instance: &TestInstance, name: &str) -> Vec<(String, u64)> {
+    let (status, body) = instance.request(Method::GET, "/metrics").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let prefix = format!("{name}{{dataset=\"");
+    body.lines()
+        .filter_map(|line| line.strip_prefix(&prefix))
+        .map(|line| {
+            let (dataset, value) = line.split_once("\"} ").unwrap();
+            (dataset.to_string(), value.parse().unwrap())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn instances_in_one_process_count_their_own_metrics() {
+    let first = TestInstance::start("metrics-first");
+    let second = TestInstance::start("metrics-second");
+    first.upload("item", 1, &properties(5)).await;
+    second.upload("item", 1, &properties(5)).await;
+
+    for _ in 0..3 {
+        let (status, _) = first
+            .request(Method::GET, "/read-item-stream/item/1?from_property=2")
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, _) = second
+        .request(Method::GET, "/read-item-stream/item/1?from_property=2")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let seeks = "stream_db_property_seeks_property_index_total";
+    let (_, first_metrics) = first.request(Method::GET, "/metrics").await;
+    let (_, second_metrics) = second.request(Method::GET, "/metrics").await;
+    assert_eq!(metric(&first_metrics, seeks), 3);
+    assert_eq!(metric(&second_metrics, seeks), 1);
+    assert_eq!(first.state.metrics.property_seeks_property_index.get(), 3);
+    assert_eq!(second.state.metrics.property_seeks_property_index.get(), 1);
+}
+
+#[tokio::test]
+async fn instances_in_one_process_label_their_metrics_by_their_own_datasets() {
+    let datasets = |entries: &[&str], max_label_values| {
+        let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        MetricDatasets::parse(&entries, max_label_values).unwrap()
+    };
+    let first = TestInstance::start("datasets-first");
+    first.state.metrics.configure(datasets(&["alpha=a-"], 32));
+    // The second one names the same prefix differently and takes a single dataset
+    let second = TestInstance::start("datasets-second");
+    second.state.metrics.configure(datasets(&["beta=a-"], 1));
+
+    for item_id in ["a-1", "a-2", "b-1"] {
+        let (status, body) = first.upload(item_id, 1, &properties(2)).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+    }
+    for item_id in ["a-1", "b-1"] {
+        let (status, body) = second.upload(item_id, 1, &properties(3)).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+    }
+    let (status, _) = first.read("a-1", 1).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uploads = "stream_db_dataset_uploads_committed_total";
+    assert_eq!(
+        dataset_series(&first, uploads).await,
+        [("alpha".to_string(), 2), ("other".to_string(), 1)]
+    );
+    assert_eq!(
+        dataset_series(&second, uploads).await,
+        [("__overflow".to_string(), 1), ("beta".to_string(), 1)]
+    );
+    assert_eq!(
+        dataset_series(&first, "stream_db_dataset_reads_total").await,
+        [("alpha".to_string(), 1)]
+    );
+    assert!(
+        dataset_series(&second, "stream_db_dataset_reads_total")
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        dataset_series(&second, "stream_db_dataset_bytes_committed_total").await,
+        [
+            ("__overflow".to_string(), properties(3).len() as u64),
+            ("beta".to_string(), properties(3).len() as u64)
+        ]
+    );
+    // Only the second instance ran out of datasets, for the uploads and their bytes
+    let overflows = "stream_db_metric_label_overflows_total";
+    let (_, first_metrics) = first.request(Method::GET, "/metrics").await;
+    let (_, second_metrics) = second.request(Method::GET, "/metrics").await;
+    assert_eq!(metric(&first_metrics, overflows), 0);
+    assert_eq!(metric(&second_metrics, overflows), 2);
+}
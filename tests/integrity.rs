@@ -0,0 +1,116 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties};
+use serde_json::Value;
+
+fn json(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| panic!("not JSON: {body}"))
+}
+
+/// Replace the first property's name in the data file of `item` version 1, which keeps
+/// its size but not its checksum
+fn corrupt(instance: &TestInstance) -> String {
+    let path = instance.data_path("item_1.xml");
+    let original = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, original.replacen("property", "PROPERTY", 1)).unwrap();
+    original
+}
+
+#[tokio::test]
+async fn a_corrupt_version_is_quarantined_until_it_is_repaired() {
+    let instance = TestInstance::start("integrity");
+    let body = properties(20);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let sha256 = json(&receipt)["sha256"].as_str().unwrap().to_string();
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/verify/item/1", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(json(&report)["outcome"], "intact");
+
+    let original = corrupt(&instance);
+    let (status, error) = instance
+        .admin(Method::POST, "/admin/verify/item/1", "")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "INTEGRITY_FAILURE");
+
+    let (status, error) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let details = &json(&error)["details"];
+    assert_eq!(details["expected_sha256"], sha256.as_str());
+    assert_ne!(details["found_sha256"], sha256.as_str());
+    assert_eq!(details["expected_size"], body.len());
+    assert_eq!(details["found_size"], body.len());
+    assert_eq!(details["detected_by"], "verify");
+    let (_, receipt) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(json(&receipt)["quarantine_check"], "verify");
+
+    // Still corrupt, so it stays quarantined
+    let (status, _) = instance
+        .admin(Method::POST, "/items/item/1/unquarantine", "")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    std::fs::write(instance.data_path("item_1.xml"), original).unwrap();
+    let (status, report) = instance
+        .admin(Method::POST, "/items/item/1/unquarantine", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(json(&report)["outcome"], "released");
+    assert_eq!(instance.read("item", 1).await, (StatusCode::OK, body));
+
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_versions_quarantined_total 1"));
+    assert!(metrics.contains("stream_db_quarantines_lifted_total 1"));
+}
+
+#[tokio::test]
+async fn a_data_file_of_the_wrong_size_is_quarantined_when_it_is_opened() {
+    let instance = TestInstance::start("integrity-read");
+    instance.upload("item", 1, &properties(5)).await;
+    let path = instance.data_path("item_1.xml");
+    let mut data = std::fs::read_to_string(&path).unwrap();
+    data.push_str("<!-- appended -->");
+    std::fs::write(&path, data).unwrap();
+
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    let (status, error) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(json(&error)["details"]["detected_by"], "read");
+
+    // A quarantined version can still be deleted
+    let (status, report) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_tier_move_of_a_corrupt_original_quarantines_it() {
+    let instance = TestInstance::start_with("integrity-tier", |config| {
+        config.cold_dir = Some(format!("{}-cold", config.data_dir));
+    });
+    instance.upload("item", 1, &properties(20)).await;
+    corrupt(&instance);
+
+    let (status, error) = instance
+        .admin(Method::POST, "/admin/tier/item/1/cold", "")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    let (status, error) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(json(&error)["details"]["detected_by"], "tier_move");
+
+    // Moving it again is refused rather than checked again
+    let (status, error) = instance
+        .admin(Method::POST, "/admin/tier/item/1/cold", "")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "INTEGRITY_FAILURE");
+    let (status, _) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK);
+}
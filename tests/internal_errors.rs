@@ -0,0 +1,129 @@
+//! Internal errors reach clients as a generic message, and the server log with what
+//! failed where. The log is the process's output, so the test runs itself again in a
+//! child process and reads what the child logged.
+
+mod common;
+
+use common::{TestInstance, error_code, properties, text, upload_request};
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use stream_db::api::api_error::INTERNAL_ERROR_MESSAGE;
+use stream_db::config::Config;
+
+use std::process::Command;
+
+/// Set for the child process, which runs the requests the parent checks the log of. Not
+/// named `STREAM_DB_*`, the child would refuse it as an unknown setting
+const CHILD: &str = "INTERNAL_ERRORS_TEST_CHILD";
+
+async fn send(
+    instance: &TestInstance,
+    mut request: Request<Body>,
+    id: &str,
+) -> (StatusCode, String) {
+    request
+        .headers_mut()
+        .insert("X-Request-Id", id.parse().unwrap());
+    text(instance.send(request).await).await
+}
+
+/// An instance holding version 1 of `item`, whose metadata file was replaced by a
+/// directory after it was stopped. Unlike taking permissions away, this also stops root.
+async fn broken_metadata(name: &str, configure: impl FnOnce(&mut Config)) -> TestInstance {
+    let instance = TestInstance::start(name);
+    let (status, body) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    let metadata = instance.data_path("item_metadata.xml");
+    let dir = instance.stop();
+    std::fs::remove_file(&metadata).unwrap();
+    std::fs::create_dir(&metadata).unwrap();
+    // Started again, the version is opened from disk
+    TestInstance::start_in(dir, configure)
+}
+
+fn requests() -> [(Request<Body>, &'static str); 2] {
+    let read = Request::builder()
+        .method(Method::GET)
+        .uri("/read-item-stream/item/1")
+        .body(Body::empty())
+        .unwrap();
+    [
+        (read, "broken-read"),
+        (upload_request("item", 2, &properties(2)), "broken-write"),
+    ]
+}
+
+/// Read and write an item whose metadata cannot be read. The responses must not tell the
+/// client more than that the request failed.
+async fn fail_on_unreadable_metadata() {
+    let instance = broken_metadata("internal-errors", |_| {}).await;
+    for (request, id) in requests() {
+        let uri = request.uri().to_string();
+        let (status, body) = send(&instance, request, id).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{uri}: {body}");
+        assert_eq!(error_code(&body), "INTERNAL", "{uri}: {body}");
+        let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["message"], INTERNAL_ERROR_MESSAGE, "{uri}");
+        assert_eq!(error["details"], serde_json::json!({}), "{uri}");
+        assert_eq!(error["request_id"], id, "{uri}");
+        assert!(
+            !body.contains(&instance.state.config.data_dir),
+            "{uri}: {body}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_storage_error_is_logged_but_not_sent() {
+    if std::env::var_os(CHILD).is_some() {
+        fail_on_unreadable_metadata().await;
+        return;
+    }
+    let output = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "a_storage_error_is_logged_but_not_sent",
+            "--exact",
+            "--nocapture",
+        ])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{log}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // The log names the request, the file and the OS error
+    for request in [
+        "Request broken-read GET /read-item-stream/item/1 failed",
+        "Request broken-write POST /write-item-stream/item/2 failed",
+    ] {
+        let line = log
+            .lines()
+            .find(|line| line.starts_with(request))
+            .unwrap_or_else(|| panic!("{request} was not logged:\n{log}"));
+        assert!(line.contains("item_metadata.xml"), "{line}");
+        assert!(line.contains("IsADirectory"), "{line}");
+    }
+}
+
+#[tokio::test]
+async fn exposed_internal_errors_reach_the_client_in_full() {
+    let instance = broken_metadata("internal-errors-exposed", |config| {
+        config.expose_internal_errors = true;
+    })
+    .await;
+    for (request, id) in requests() {
+        let uri = request.uri().to_string();
+        let (status, body) = send(&instance, request, id).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{uri}: {body}");
+        assert_eq!(error_code(&body), "INTERNAL", "{uri}: {body}");
+        let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let message = error["message"].as_str().unwrap();
+        assert_ne!(message, INTERNAL_ERROR_MESSAGE, "{uri}");
+        assert!(message.contains("item_metadata.xml"), "{uri}: {message}");
+        assert_eq!(error["request_id"], id, "{uri}");
+    }
+}
@@ -0,0 +1,116 @@
+//! Load test of the I/O lanes: uploads of small items keep a bounded p99 latency while a
+//! few large uploads sync chunk after chunk, with `off` and with `fair` scheduling.
+//! Ignored by default since it writes 256 MiB and takes minutes:
+//!
+//! ```bash
+//! cargo test --release --test io_scheduling -- --ignored --nocapture
+//! ```
+//!
+//! `IO_LOAD_LARGE_MB` sets the size of each large upload (default 32) and
+//! `IO_LOAD_P99_MS` the latency the p99 of the small uploads must stay below
+//! (default 500).
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestInstance, properties};
+use stream_db::persistence::io_scheduler::IoSchedulingPolicy;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LARGE_UPLOADS: usize = 4;
+const SMALL_CLIENTS: usize = 8;
+const SMALL_UPLOADS_PER_CLIENT: usize = 25;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Latencies of the small uploads sent while the large ones ran, sorted
+async fn small_upload_latencies(policy: IoSchedulingPolicy) -> Vec<Duration> {
+    let instance = Arc::new(TestInstance::start_with("io-scheduling", |config| {
+        config.io_scheduling = policy;
+    }));
+    let large_bytes = env_or("IO_LOAD_LARGE_MB", 32) * 1024 * 1024;
+    let block = properties(10_000);
+    let block = &block["<properties>".len()..block.len() - "</properties>".len()];
+    let blocks = (large_bytes as usize).div_ceil(block.len());
+    let size = "<properties>".len() + blocks * block.len() + "</properties>".len();
+
+    let mut large = Vec::new();
+    for index in 0..LARGE_UPLOADS {
+        let (mut upload, response) = instance.start_upload(&format!("large-{index}"), 1, size);
+        let block = block.to_string();
+        large.push(tokio::spawn(async move {
+            upload.send("<properties>");
+            for _ in 0..blocks {
+                upload.send(&block);
+                // Leaves the writer a chance to drain the body, as a client on a fast
+                // link sending back to back would
+                tokio::task::yield_now().await;
+            }
+            upload.send("</properties>");
+            upload.finish();
+            response.await.unwrap()
+        }));
+    }
+    // The large uploads are past the small item threshold before the clients start
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut clients = Vec::new();
+    for client in 0..SMALL_CLIENTS {
+        let instance = instance.clone();
+        clients.push(tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            for upload in 0..SMALL_UPLOADS_PER_CLIENT {
+                let started = Instant::now();
+                let (status, body) = instance
+                    .upload(&format!("small-{client}-{upload}"), 1, &properties(20))
+                    .await;
+                latencies.push(started.elapsed());
+                assert_eq!(status, StatusCode::CREATED, "{body}");
+            }
+            latencies
+        }));
+    }
+    let mut latencies = Vec::new();
+    for client in clients {
+        latencies.extend(client.await.unwrap());
+    }
+    for upload in large {
+        let (status, body) = upload.await.unwrap();
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+    }
+    latencies.sort();
+    latencies
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    sorted[(sorted.len() * percentile / 100).min(sorted.len() - 1)]
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "load test, writes 256 MiB and takes minutes"]
+async fn small_uploads_stay_fast_while_large_uploads_run() {
+    let bound = Duration::from_millis(env_or("IO_LOAD_P99_MS", 500));
+    for policy in [IoSchedulingPolicy::Off, IoSchedulingPolicy::Fair] {
+        let latencies = small_upload_latencies(policy).await;
+        println!(
+            "{policy:?}: {} small uploads, p50 {:?}, p99 {:?}, max {:?}",
+            latencies.len(),
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies.last().unwrap()
+        );
+        if policy == IoSchedulingPolicy::Fair {
+            assert!(
+                percentile(&latencies, 99) < bound,
+                "p99 of the small uploads exceeds {bound:?}"
+            );
+        }
+    }
+}
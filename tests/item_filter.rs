@@ -0,0 +1,85 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use stream_db::persistence::cold_tier;
+
+/// Rebuild the instance's item filter from its data directory, as its background task does
+fn rebuild_filter(instance: &TestInstance) {
+    let storage = &instance.state.storage;
+    storage.item_filter.start_build();
+    let item_ids = cold_tier::item_ids(storage).unwrap();
+    storage.item_filter.rebuild(&item_ids, 0.01, u64::MAX);
+}
+
+fn filtered_instance(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.item_filter = true;
+    })
+}
+
+#[tokio::test]
+async fn stored_items_are_never_ruled_out_across_creates_and_deletes() {
+    let instance = filtered_instance("item-filter-cycles");
+    rebuild_filter(&instance);
+    let item_ids: Vec<String> = (0..20).map(|index| format!("item-{index}")).collect();
+    for cycle in 0..3 {
+        for item_id in &item_ids {
+            let (status, body) = instance.upload(item_id, cycle + 1, &properties(1)).await;
+            assert!(status.is_success(), "{item_id}: {body}");
+        }
+        // Every other item is deleted, and half of those written again at once
+        for item_id in item_ids.iter().step_by(2) {
+            let (status, report) = instance
+                .admin(
+                    Method::DELETE,
+                    &format!("/items/{item_id}/{}", cycle + 1),
+                    "",
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK, "{report}");
+        }
+        for item_id in item_ids.iter().step_by(4) {
+            let (status, body) = instance.upload(item_id, cycle + 1, &properties(2)).await;
+            assert!(status.is_success(), "{item_id}: {body}");
+        }
+        if cycle == 1 {
+            rebuild_filter(&instance);
+        }
+
+        for (index, item_id) in item_ids.iter().enumerate() {
+            let (status, body) = instance.read(item_id, cycle + 1).await;
+            let expected = match index % 4 {
+                2 => StatusCode::NOT_FOUND,
+                _ => StatusCode::OK,
+            };
+            assert_eq!(status, expected, "cycle {cycle}, {item_id}: {body}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn unknown_items_are_answered_without_reading_metadata() {
+    let instance = filtered_instance("item-filter-misses");
+    instance.upload("known", 1, &properties(2)).await;
+    rebuild_filter(&instance);
+
+    let metrics = &instance.state.metrics;
+    let metadata_reads = metrics.existence_cache_misses.get();
+    let mut ruled_out = 0;
+    for _ in 0..50 {
+        let item_id = uuid::Uuid::new_v4().to_string();
+        let (status, _) = instance.read(&item_id, 1).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = instance
+            .request(Method::HEAD, &format!("/read-item-stream/{item_id}/1"))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        ruled_out += 1;
+    }
+    // At a false positive rate of 1 %, hardly any of them gets through
+    assert!(metrics.item_filter_negatives.get() >= ruled_out * 2 - 4);
+    assert!(metrics.existence_cache_misses.get() - metadata_reads <= 4);
+    assert_eq!(instance.read("known", 1).await.1, properties(2));
+    assert_eq!(metrics.item_filter_items.get(), 1);
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, next_chunk, properties};
+use stream_db::logic::read_stats;
+
+async fn stats(instance: &TestInstance, query: &str) -> serde_json::Value {
+    let (status, stats) = instance
+        .request(Method::GET, &format!("/items/item/stats{query}"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{stats}");
+    serde_json::from_str(&stats).unwrap()
+}
+
+#[tokio::test]
+async fn reads_are_counted_per_version_and_survive_a_restart() {
+    let instance = TestInstance::start("stats");
+    let first = properties(2000);
+    let second = properties(3);
+    instance.upload("item", 1, &first).await;
+    instance.upload("item", 2, &second).await;
+    assert_eq!(stats(&instance, "").await["reads_started"], 0);
+
+    for _ in 0..2 {
+        assert_eq!(instance.read("item", 1).await.1, first);
+    }
+    assert_eq!(instance.read("item", 2).await.1, second);
+    // A reader that goes away early started a read but did not complete it
+    let mut abandoned = instance.open_read("item", 1).await.into_body();
+    let chunk = next_chunk(&mut abandoned).await.unwrap().unwrap();
+    assert!(chunk.len() < first.len());
+    drop(abandoned);
+
+    let total = common::eventually(|| async {
+        let total = stats(&instance, "").await;
+        (total["reads_started"] == 4).then_some(total)
+    })
+    .await;
+    assert_eq!(total["item_id"], "item");
+    assert_eq!(total["reads_completed"], 3);
+    let bytes_served = total["bytes_served"].as_u64().unwrap();
+    assert!(bytes_served > (2 * first.len() + second.len()) as u64);
+    assert!(bytes_served < (3 * first.len() + second.len()) as u64);
+    let last_accessed = total["last_accessed"].as_str().unwrap().to_string();
+    assert!(chrono::DateTime::parse_from_rfc3339(&last_accessed).is_ok());
+    assert!(total.get("versions").is_none());
+
+    let per_version = stats(&instance, "?per_version=true").await;
+    assert_eq!(per_version["versions"]["1"]["reads_started"], 3);
+    assert_eq!(per_version["versions"]["1"]["reads_completed"], 2);
+    assert_eq!(per_version["versions"]["2"]["reads_started"], 1);
+    assert_eq!(
+        per_version["versions"]["2"]["bytes_served"],
+        second.len() as u64
+    );
+
+    // Flushed counters are read back after a restart, later ones are lost with it
+    read_stats::flush(&instance.state);
+    assert_eq!(stats(&instance, "").await, total);
+    instance.read("item", 2).await;
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    assert_eq!(stats(&instance, "").await, total);
+    instance.read("item", 2).await;
+    let after = common::eventually(|| async {
+        let after = stats(&instance, "").await;
+        (after["reads_started"] == 5).then_some(after)
+    })
+    .await;
+    assert_eq!(after["reads_completed"], 4);
+}
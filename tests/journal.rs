@@ -0,0 +1,87 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use stream_db::persistence::io_engine::FsyncPolicy;
+
+use std::path::Path;
+
+fn journaled(dir: common::TestDir) -> TestInstance {
+    TestInstance::start_in(dir, |config| {
+        config.fsync_policy = FsyncPolicy::Commit;
+        config.journal_interval_mb = 1;
+    })
+}
+
+#[tokio::test]
+async fn an_interrupted_upload_is_cut_back_to_its_last_checkpoint() {
+    let instance = journaled(common::TestDir::new("journal"));
+    let body = properties(30_000);
+    assert!(body.len() > 1536 * 1024);
+    let journal_path = instance.data_path("item_1.journal.jsonl");
+    let data_path = instance.data_path("item_1.xml");
+
+    // What a crash leaves behind: the data file and journal of an upload past its first
+    // checkpoint, with bytes after the checkpoint that may not have reached the disk
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..1_500_000]);
+    let journal = common::eventually(|| async {
+        let journal = std::fs::read_to_string(&journal_path).unwrap_or_default();
+        (journal.lines().count() == 1).then_some(journal)
+    })
+    .await;
+    let checkpoint: Value = serde_json::from_str(journal.lines().last().unwrap()).unwrap();
+    let offset = checkpoint["offset"].as_u64().unwrap() as usize;
+    assert!(offset >= 1024 * 1024);
+    let sha256 = format!("{:x}", Sha256::digest(&body.as_bytes()[..offset]));
+    assert_eq!(checkpoint["sha256"], sha256.as_str());
+    upload.break_off();
+    response.await.unwrap();
+    assert!(!Path::new(&journal_path).exists());
+    let dir = instance.stop();
+    std::fs::write(dir.path().join("data/item_1.journal.jsonl"), &journal).unwrap();
+    std::fs::write(dir.path().join("data/item_1.xml"), &body[..1_400_000]).unwrap();
+
+    let instance = journaled(dir);
+    assert_eq!(std::fs::metadata(&data_path).unwrap().len(), offset as u64);
+    let (_, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    let listing: Value = serde_json::from_str(&listing).unwrap();
+    assert_eq!(listing[0]["size"], offset);
+    assert_eq!(listing[0]["checkpoint"]["offset"], offset);
+    let (status, error) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(status, StatusCode::GONE);
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["checkpoint"]["sha256"], sha256.as_str());
+
+    // Uploading the version again commits it and drops the journal
+    let (status, _) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(!Path::new(&journal_path).exists());
+    assert_eq!(instance.read("item", 1).await.1, body);
+}
+
+#[tokio::test]
+async fn a_journal_that_does_not_match_its_data_file_is_ignored() {
+    let instance = journaled(common::TestDir::new("journal-mismatch"));
+    instance.upload("item", 1, &properties(2)).await;
+    let dir = instance.stop();
+    let checkpoint = r#"{"offset":10,"sha256":"00","recorded_at":"2026-01-01T00:00:00Z"}"#;
+    std::fs::write(dir.path().join("data/item_2.xml"), properties(3)).unwrap();
+    std::fs::write(dir.path().join("data/item_2.journal.jsonl"), checkpoint).unwrap();
+    std::fs::write(dir.path().join("data/item_1.journal.jsonl"), checkpoint).unwrap();
+
+    let instance = journaled(dir);
+    let (_, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    let listing: Value = serde_json::from_str(&listing).unwrap();
+    assert_eq!(listing[0]["size"], properties(3).len());
+    assert_eq!(listing[0]["checkpoint"], Value::Null);
+    // The journal of the committed version outlived its upload
+    assert!(!Path::new(&instance.data_path("item_1.journal.jsonl")).exists());
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+}
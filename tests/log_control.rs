@@ -0,0 +1,160 @@
+//! Debug lines of streams: which instance writes them for which items, that they go
+//! through `tracing`, and that the filter follows the log settings. The subscriber
+//! capturing them is the process default, so everything runs in one test.
+
+mod common;
+
+use common::{TestInstance, properties};
+
+use axum::http::{Method, StatusCode};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stream_db::log_control::{DEBUG_TARGET, LogLevel};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
+
+/// Item ID and message of every event written
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<(String, String)>>>);
+
+impl Captured {
+    fn take(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    item_id: String,
+    message: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "item_id" {
+            self.item_id = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Captured {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.0
+            .lock()
+            .unwrap()
+            .push((fields.item_id, fields.message));
+    }
+}
+
+fn debug_target_enabled() -> bool {
+    tracing::enabled!(target: DEBUG_TARGET, Level::DEBUG)
+}
+
+/// The items of the lines written since the last call
+fn items(captured: &Captured) -> Vec<String> {
+    let mut items: Vec<String> = captured
+        .take()
+        .into_iter()
+        .map(|(item_id, _)| item_id)
+        .collect();
+    items.dedup();
+    items
+}
+
+async fn upload(instance: &TestInstance, item_id: &str) {
+    let (status, body) = instance.upload(item_id, 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{item_id}: {body}");
+    let (status, _) = instance.read(item_id, 1).await;
+    assert_eq!(status, StatusCode::OK, "{item_id}");
+}
+
+#[tokio::test]
+async fn debug_lines_follow_the_settings_of_their_instance() {
+    let captured = Captured::default();
+    let (filter, handle) = reload::Layer::new(Targets::new());
+    tracing_subscriber::registry()
+        .with(captured.clone().with_filter(filter))
+        .init();
+
+    let first = TestInstance::start("log-first");
+    first
+        .state
+        .log
+        .reload_filter_with(move |targets| handle.reload(targets).unwrap());
+    let second = TestInstance::start("log-second");
+    assert!(!debug_target_enabled());
+
+    // Debugging one item's streams lets the debug lines through, only about that item
+    let (status, body) = first
+        .admin(
+            Method::PUT,
+            "/admin/debug-items",
+            r#"{"item_patterns": ["target-*"]}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert!(debug_target_enabled());
+    upload(&first, "target-1").await;
+    upload(&first, "other-1").await;
+    // The other instance does not debug, whatever the first one does
+    upload(&second, "target-2").await;
+    let lines = captured.take();
+    assert!(
+        lines
+            .iter()
+            .any(|(_, message)| message.starts_with("upload requested")),
+        "{lines:?}"
+    );
+    assert!(
+        lines
+            .iter()
+            .any(|(_, message)| message.starts_with("opened ")),
+        "{lines:?}"
+    );
+    assert!(
+        lines.iter().all(|(item_id, _)| item_id == "target-1"),
+        "{lines:?}"
+    );
+
+    // The debug level covers every item of the instance until it expires
+    let (status, body) = first
+        .admin(
+            Method::PUT,
+            "/admin/log-level",
+            r#"{"level": "debug", "ttl_secs": 1}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, body) = first
+        .admin(
+            Method::PUT,
+            "/admin/debug-items",
+            r#"{"item_patterns": []}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    upload(&first, "other-2").await;
+    upload(&second, "other-3").await;
+    assert_eq!(items(&captured), ["other-2"]);
+    assert!(debug_target_enabled());
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let settings = first.state.log.settings();
+    assert_eq!(settings.level, LogLevel::Info);
+    assert!(settings.level_override.is_none());
+    assert!(!debug_target_enabled());
+    upload(&first, "other-4").await;
+    assert!(captured.take().is_empty());
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use serde_json::{Value, json};
+
+async fn materialize(
+    instance: &TestInstance,
+    source: &str,
+    request: Value,
+) -> (StatusCode, String) {
+    instance
+        .json(
+            Method::POST,
+            &format!("/items/{source}/materialize"),
+            &request.to_string(),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn a_materialized_version_holds_what_the_equivalent_read_returns() {
+    let instance = TestInstance::start("materialize");
+    instance.upload("item", 1, &properties(5)).await;
+
+    let (status, receipt) = materialize(
+        &instance,
+        "item/1",
+        json!({"destination": {"item_id": "subset", "version": 1}, "properties": ["p1", "p3"]}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["property_count"], 2);
+    let (status, filtered) = instance
+        .request(Method::GET, "/read-item-stream/item/1?properties=p1,p3")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        filtered.contains("p3") && !filtered.contains("p2"),
+        "{filtered}"
+    );
+    assert_eq!(instance.read("subset", 1).await.1, filtered);
+
+    // The destination is checked like an upload
+    let (status, _) = materialize(
+        &instance,
+        "item/1",
+        json!({"destination": {"item_id": "subset", "version": 1}}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn invalid_copies_are_refused_before_the_destination_is_claimed() {
+    let instance = TestInstance::start("materialize-refused");
+    instance.upload("item", 1, &properties(2)).await;
+    let destination = json!({"item_id": "copy", "version": 1});
+
+    for (source, request, expected) in [
+        (
+            "missing/1",
+            json!({"destination": destination}),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            "item/1",
+            json!({"destination": destination, "transform": "unknown"}),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            "item/1",
+            json!({"destination": destination, "format": "ndjson"}),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            "item/1",
+            json!({"destination": {"item_id": "a/b", "version": 1}}),
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let (status, error) = materialize(&instance, source, request).await;
+        assert_eq!(status, expected, "{error}");
+    }
+    let (status, _) = instance.read("copy", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
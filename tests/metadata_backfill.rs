@@ -0,0 +1,135 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, properties};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// A data directory whose versions of `item` were committed before sizes and checksums
+/// were recorded
+async fn legacy_item(name: &str, bodies: &[String]) -> TestDir {
+    legacy_item_without(name, bodies, &[" size=\"", " sha256=\""]).await
+}
+
+/// A data directory whose versions of `item` were committed without the `attributes`
+/// in their receipts
+async fn legacy_item_without(name: &str, bodies: &[String], attributes: &[&str]) -> TestDir {
+    let instance = TestInstance::start(name);
+    for (index, body) in bodies.iter().enumerate() {
+        instance.upload("item", index as u64 + 1, body).await;
+    }
+    let dir = instance.stop();
+    let path = dir.path().join("data/item_metadata.xml");
+    let mut metadata = std::fs::read_to_string(&path).unwrap();
+    for attribute in attributes {
+        while let Some(start) = metadata.find(attribute) {
+            let end =
+                start + attribute.len() + metadata[start + attribute.len()..].find('"').unwrap();
+            metadata.replace_range(start..=end, "");
+        }
+    }
+    std::fs::write(&path, metadata).unwrap();
+    dir
+}
+
+async fn receipt(instance: &TestInstance, version: u64) -> Value {
+    let (status, receipt) = instance
+        .request(Method::GET, &format!("/items/item/{version}/receipt"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    serde_json::from_str(&receipt).unwrap()
+}
+
+fn sha256(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+#[tokio::test]
+async fn a_complete_read_records_what_the_version_lacks() {
+    let body = properties(30);
+    let instance = TestInstance::start_in(
+        legacy_item("backfill-read", std::slice::from_ref(&body)).await,
+        |_| {},
+    );
+    assert_eq!(receipt(&instance, 1).await["sha256"], Value::Null);
+    assert_eq!(
+        instance.read("item", 1).await,
+        (StatusCode::OK, body.clone())
+    );
+
+    let receipt = common::eventually(|| async {
+        let receipt = receipt(&instance, 1).await;
+        receipt["sha256"].is_string().then_some(receipt)
+    })
+    .await;
+    assert_eq!(receipt["sha256"], sha256(&body));
+    assert_eq!(receipt["size"], body.len());
+    assert!(receipt["computed_at"].is_string());
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_digests_backfilled_total 1"));
+}
+
+#[tokio::test]
+async fn verify_and_the_backfill_record_what_versions_lack() {
+    let bodies = [properties(3), properties(4), properties(5)];
+    let instance = TestInstance::start_in(legacy_item("backfill-bulk", &bodies).await, |_| {});
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/verify/item/1", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(
+        serde_json::from_str::<Value>(&report).unwrap()["outcome"],
+        "backfilled"
+    );
+    let (_, report) = instance
+        .admin(Method::POST, "/admin/verify/item/1", "")
+        .await;
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["outcome"], "intact");
+    assert_eq!(report["checksum_verified"], true);
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/backfill-metadata", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["versions_checked"], 2);
+    assert_eq!(report["versions_backfilled"], 2);
+    assert_eq!(report["bytes_hashed"], bodies[1].len() + bodies[2].len());
+    for (index, body) in bodies.iter().enumerate() {
+        assert_eq!(
+            receipt(&instance, index as u64 + 1).await["sha256"],
+            sha256(body)
+        );
+    }
+}
+
+#[tokio::test]
+async fn the_backfill_counts_the_properties_of_versions_without_a_count() {
+    let bodies = [properties(3), properties(1)];
+    let dir = legacy_item_without("backfill-count", &bodies, &[" property_count=\""]).await;
+    let instance = TestInstance::start_in(dir, |_| {});
+    assert_eq!(receipt(&instance, 1).await["property_count"], Value::Null);
+    let response = instance.open_read("item", 1).await;
+    assert!(response.headers().get("X-Property-Count").is_none());
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/backfill-metadata", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["property_counts_backfilled"], 2, "{report}");
+    assert_eq!(report["versions_checked"], 0);
+    assert_eq!(receipt(&instance, 1).await["property_count"], 3);
+    assert_eq!(receipt(&instance, 2).await["property_count"], 1);
+    let response = instance.open_read("item", 1).await;
+    assert_eq!(response.headers()["X-Property-Count"], "3");
+
+    // Nothing is left to count
+    let (_, report) = instance
+        .admin(Method::POST, "/admin/backfill-metadata", "")
+        .await;
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["property_counts_backfilled"], 0);
+}
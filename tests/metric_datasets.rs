@@ -0,0 +1,145 @@
+//! The `dataset` label of the per-dataset metrics: which dataset an item counts under, the
+//! limit on datasets per metric, and reloading the mapping. The mapping is read from a
+//! config file named in the environment, so everything that counts runs in one test.
+
+mod common;
+
+use common::{TestDir, TestInstance, properties};
+
+use axum::http::{Method, StatusCode};
+use stream_db::metrics::MetricDatasets;
+
+/// The value `/metrics` shows for `name{dataset="dataset"}`, 0 when it has none
+async fn counted(instance: &TestInstance, name: &str, dataset: &str) -> u64 {
+    let (status, body) = instance.request(Method::GET, "/metrics").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let series = format!("{name}{{dataset=\"{dataset}\"}} ");
+    body.lines()
+        .find_map(|line| line.strip_prefix(&series))
+        .map_or(0, |value| value.parse().unwrap())
+}
+
+async fn overflows(instance: &TestInstance) -> u64 {
+    let (_, body) = instance.request(Method::GET, "/metrics").await;
+    body.lines()
+        .find_map(|line| line.strip_prefix("stream_db_metric_label_overflows_total "))
+        .map_or(0, |value| value.parse().unwrap())
+}
+
+async fn uploads_committed(instance: &TestInstance, dataset: &str) -> u64 {
+    counted(
+        instance,
+        "stream_db_dataset_uploads_committed_total",
+        dataset,
+    )
+    .await
+}
+
+async fn upload(instance: &TestInstance, item_id: &str) {
+    let (status, body) = instance.upload(item_id, 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{item_id}: {body}");
+}
+
+#[test]
+fn invalid_datasets_are_refused() {
+    let parse = |entries: &[&str]| {
+        let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        MetricDatasets::parse(&entries, 32)
+    };
+    assert!(parse(&["telemetry=tlm-", "telemetry=sensor-", "billing=bill-"]).is_ok());
+    for (entries, reason) in [
+        (&["telemetry"][..], "dataset=prefix"),
+        (&["tele metry=tlm-"], "letters, digits"),
+        (&["=tlm-"], "letters, digits"),
+        (&["other=tlm-"], "reserved"),
+        (&["__overflow=tlm-"], "reserved"),
+        (&["__mine=tlm-"], "reserved"),
+        (&["telemetry="], "needs a prefix"),
+        (&["telemetry=tlm-", "billing=tlm-"], "listed twice"),
+    ] {
+        let error = parse(entries).unwrap_err();
+        assert!(error.contains(reason), "{entries:?}: {error}");
+    }
+}
+
+#[tokio::test]
+async fn items_are_counted_under_their_dataset() {
+    let dir = TestDir::new("metric-datasets-config");
+    let config_file = dir.join("stream-db.toml");
+    std::fs::write(
+        &config_file,
+        "metric_datasets = [\"telemetry=tlm-\", \"archive=tlm-archive-\"]\n\
+         metric_label_values_max = 3\n",
+    )
+    .unwrap();
+    // SAFETY: this is the only test of the process reading the environment
+    unsafe { std::env::set_var("STREAM_DB_CONFIG_FILE", &config_file) };
+    let instance = TestInstance::start("metric-datasets");
+
+    // The longest prefix an item starts with decides, whatever order they are listed in
+    upload(&instance, "tlm-archive-1").await;
+    upload(&instance, "tlm-archive-2").await;
+    upload(&instance, "tlm-1").await;
+    upload(&instance, "unlisted").await;
+    assert_eq!(uploads_committed(&instance, "archive").await, 2);
+    assert_eq!(uploads_committed(&instance, "telemetry").await, 1);
+    assert_eq!(uploads_committed(&instance, "other").await, 1);
+    assert_eq!(
+        counted(
+            &instance,
+            "stream_db_dataset_bytes_committed_total",
+            "archive"
+        )
+        .await,
+        2 * properties(2).len() as u64
+    );
+
+    // All 3 label values are taken, a fourth dataset is counted under `__overflow`
+    std::fs::write(
+        &config_file,
+        "metric_datasets = [\"telemetry=tlm-\", \"archive=tlm-archive-\", \"billing=bill-\"]\n\
+         metric_label_values_max = 3\n",
+    )
+    .unwrap();
+    let (status, body) = instance
+        .admin(Method::POST, "/admin/config/reload", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let overflows_before = overflows(&instance).await;
+    upload(&instance, "bill-1").await;
+    upload(&instance, "bill-2").await;
+    assert_eq!(uploads_committed(&instance, "billing").await, 0);
+    assert_eq!(uploads_committed(&instance, "__overflow").await, 2);
+    // Once for each upload in both the uploads and the bytes committed
+    assert_eq!(overflows(&instance).await, overflows_before + 4);
+    // The datasets already counted keep counting
+    upload(&instance, "tlm-2").await;
+    assert_eq!(uploads_committed(&instance, "telemetry").await, 2);
+
+    // A reserved name refuses the reload, and the mapping counted by stays
+    std::fs::write(
+        &config_file,
+        "metric_datasets = [\"__archive=tlm-archive-\"]\nmetric_label_values_max = 3\n",
+    )
+    .unwrap();
+    let (status, body) = instance
+        .admin(Method::POST, "/admin/config/reload", "")
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{body}");
+    assert!(body.contains("reserved"), "{body}");
+    upload(&instance, "tlm-archive-3").await;
+    assert_eq!(uploads_committed(&instance, "archive").await, 3);
+    assert_eq!(uploads_committed(&instance, "__archive").await, 0);
+    assert_eq!(
+        instance.state.config.metric_datasets(),
+        MetricDatasets::parse(
+            &[
+                "telemetry=tlm-".to_string(),
+                "archive=tlm-archive-".to_string(),
+                "billing=bill-".to_string(),
+            ],
+            3
+        )
+        .unwrap()
+    );
+}
@@ -0,0 +1,337 @@
+//! The in-memory backend of `--features test-util` plugged into an instance, testing a
+//! service built on the component layer the way an embedding crate would: without a data
+//! directory, every interleaving of its uploads and reads set up by the test.
+#![cfg(feature = "test-util")]
+
+use bytes::Bytes;
+use stream_db::component::item_stream_component::ItemStreamComponent;
+use stream_db::config::Config;
+use stream_db::logic::item_stream_logic::{ReadError, ReadOptions, WriteOptions};
+use stream_db::logic::stream_ingest::{IngestError, StreamIngest};
+use stream_db::persistence::item_metadata::VersionMetadata;
+use stream_db::state::{AppState, StreamDb};
+use stream_db::test_util::MockBackend;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const LINE_START: &str = "<property name=\"line\">";
+const LINE_END: &str = "</property>";
+
+/// The service under test stores reports as versions of an item, one property a line
+fn report_xml(lines: &[&str]) -> String {
+    let properties: String = lines
+        .iter()
+        .map(|line| format!("{LINE_START}{line}{LINE_END}"))
+        .collect();
+    format!("<properties>{properties}</properties>")
+}
+
+/// The service's upload of a report, through the pipeline the upload endpoints use
+async fn publish_report(
+    state: &AppState,
+    item_id: &str,
+    version: u64,
+    lines: &[&str],
+) -> Result<VersionMetadata, String> {
+    let writer = ItemStreamComponent::new_writer(
+        state,
+        item_id.to_string(),
+        version,
+        WriteOptions::new(&state.config),
+    )
+    .await
+    .map_err(|error| error.message())?;
+    let mut ingest = writer.into_ingest(None).map_err(|error| error.message())?;
+    ingest
+        .push_bytes(Bytes::from(report_xml(lines)))
+        .await
+        .map_err(|error| error.message())?;
+    finish(ingest).await
+}
+
+/// The service's reader, handing on every line of a report as soon as it is complete,
+/// also while the report is still being uploaded
+async fn watch_report(
+    state: &AppState,
+    item_id: &str,
+    version: u64,
+    lines: mpsc::UnboundedSender<String>,
+) -> Result<(), String> {
+    let mut reader = match ItemStreamComponent::new_reader(
+        state,
+        item_id.to_string(),
+        version,
+        ReadOptions::default(),
+    )
+    .await
+    {
+        Ok(reader) => reader,
+        Err(ReadError::NotFound(_)) => return Err("No such report".to_string()),
+        Err(_) => return Err("The report could not be opened".to_string()),
+    };
+    let mut buffer = String::new();
+    loop {
+        match reader.read_chunk().await {
+            Ok(Some(chunk)) => buffer.push_str(&String::from_utf8(chunk).unwrap()),
+            Ok(None) => return Ok(()),
+            Err(_) if reader.is_too_slow() => return Err("Fell behind the report".to_string()),
+            Err(error) => return Err(format!("Report broke off: {error}")),
+        }
+        while let Some(start) = buffer.find(LINE_START) {
+            let Some(end) = buffer[start..].find(LINE_END) else {
+                break;
+            };
+            let line = buffer[start + LINE_START.len()..start + end].to_string();
+            buffer.drain(..start + end + LINE_END.len());
+            let _ = lines.send(line);
+        }
+    }
+}
+
+/// An upload of a report the test feeds itself, to interleave it with reads
+async fn start_report(state: &AppState, item_id: &str, version: u64) -> StreamIngest {
+    match ItemStreamComponent::new_writer(
+        state,
+        item_id.to_string(),
+        version,
+        WriteOptions::new(&state.config),
+    )
+    .await
+    {
+        Ok(writer) => match writer.into_ingest(None) {
+            Ok(ingest) => ingest,
+            Err(error) => panic!("{}", error.message()),
+        },
+        Err(error) => panic!("{}", error.message()),
+    }
+}
+
+async fn push(ingest: &mut StreamIngest, xml: &str) {
+    if let Err(error) = ingest.push_bytes(Bytes::from(xml.to_string())).await {
+        panic!("{}", error.message());
+    }
+}
+
+async fn finish(ingest: StreamIngest) -> Result<VersionMetadata, String> {
+    match ingest.finish().await {
+        Ok((committed, _)) => Ok(committed),
+        Err(error) => Err(error.message()),
+    }
+}
+
+/// All lines of a report
+async fn read_report(state: &AppState, item_id: &str, version: u64) -> Result<Vec<String>, String> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    watch_report(state, item_id, version, sender).await?;
+    let mut lines = Vec::new();
+    while let Some(line) = receiver.recv().await {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// An instance over `backend`, with a data directory that is never created
+fn instance(backend: &MockBackend) -> (AppState, PathBuf) {
+    let data_dir = std::env::temp_dir().join(format!("stream-db-mock-{}", uuid::Uuid::new_v4()));
+    let mut config = Config::load(true).unwrap();
+    config.data_dir = data_dir.to_string_lossy().into_owned();
+    (
+        StreamDb::with_backend(config, Arc::new(backend.clone())),
+        data_dir,
+    )
+}
+
+/// Nothing arrives for a while
+async fn assert_quiet(lines: &mut mpsc::UnboundedReceiver<String>) {
+    let arrived = tokio::time::timeout(Duration::from_millis(50), lines.recv()).await;
+    assert!(arrived.is_err(), "{arrived:?} arrived");
+}
+
+#[tokio::test]
+async fn a_report_is_stored_and_read_back_in_memory() {
+    let backend = MockBackend::new();
+    let (state, data_dir) = instance(&backend);
+
+    let committed = publish_report(&state, "report", 1, &["first", "second"])
+        .await
+        .unwrap();
+    assert_eq!(committed.version, 1);
+    assert_eq!(committed.property_count, Some(2));
+    backend
+        .version("report", 1)
+        .unwrap()
+        .assert_committed(report_xml(&["first", "second"]).as_bytes());
+
+    assert_eq!(
+        read_report(&state, "report", 1).await.unwrap(),
+        ["first", "second"]
+    );
+    assert_eq!(
+        read_report(&state, "report", 2).await.unwrap_err(),
+        "No such report"
+    );
+    assert!(!data_dir.exists(), "the data directory was written to");
+}
+
+#[tokio::test]
+async fn a_report_not_newer_than_the_stored_one_conflicts() {
+    let backend = MockBackend::new();
+    backend.insert_committed("report", 3, report_xml(&["stored"]).as_bytes());
+    let (state, _) = instance(&backend);
+
+    for version in [2, 3] {
+        let Err(error) = publish_report(&state, "report", version, &["late"]).await else {
+            panic!("version {version} was written over version 3");
+        };
+        assert!(error.contains("Conflict"), "{error}");
+    }
+    publish_report(&state, "report", 4, &["next"])
+        .await
+        .unwrap();
+
+    // A version whose commit finds a newer one committed meanwhile
+    let mut ingest = start_report(&state, "report", 6).await;
+    backend.version("report", 6).unwrap().conflict_with(7);
+    push(&mut ingest, &report_xml(&["raced"])).await;
+    let Err(error) = finish(ingest).await else {
+        panic!("version 6 was committed over version 7");
+    };
+    assert!(error.contains("Version 7 was committed"), "{error}");
+}
+
+#[tokio::test]
+async fn a_second_upload_of_a_report_is_refused_while_the_first_runs() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    let _first = start_report(&state, "report", 1).await;
+
+    let Err(error) = publish_report(&state, "report", 1, &["second"]).await else {
+        panic!("both uploads were taken");
+    };
+    assert!(error.contains("is being uploaded"), "{error}");
+}
+
+/// Start watching version 1 of `report`, returning the lines it sees and how it ended
+fn watch(
+    state: &AppState,
+) -> (
+    mpsc::UnboundedReceiver<String>,
+    tokio::task::JoinHandle<Result<(), String>>,
+) {
+    let (sender, lines) = mpsc::unbounded_channel();
+    let state = state.clone();
+    let watch = tokio::spawn(async move { watch_report(&state, "report", 1, sender).await });
+    (lines, watch)
+}
+
+#[tokio::test]
+async fn a_watcher_sees_each_line_when_the_test_releases_it() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    let mut ingest = start_report(&state, "report", 1).await;
+    let version = backend.version("report", 1).unwrap();
+    let (mut lines, watch) = watch(&state);
+
+    let first = format!("<properties>{LINE_START}first{LINE_END}");
+    push(&mut ingest, &first).await;
+    assert_quiet(&mut lines).await;
+    // Half the line is not a line yet
+    version.advance(first.len() - 4);
+    assert_quiet(&mut lines).await;
+    version.advance_all();
+    assert_eq!(lines.recv().await.unwrap(), "first");
+
+    push(
+        &mut ingest,
+        &format!("{LINE_START}second{LINE_END}</properties>"),
+    )
+    .await;
+    assert_quiet(&mut lines).await;
+    finish(ingest).await.unwrap();
+    assert_eq!(lines.recv().await.unwrap(), "second");
+    watch.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn a_watcher_of_an_aborted_report_is_told_it_broke_off() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    let mut ingest = start_report(&state, "report", 1).await;
+    let version = backend.version("report", 1).unwrap();
+    let (mut lines, watch) = watch(&state);
+    push(
+        &mut ingest,
+        &format!("<properties>{LINE_START}first{LINE_END}"),
+    )
+    .await;
+    version.advance_all();
+    assert_eq!(lines.recv().await.unwrap(), "first");
+
+    ingest.abort();
+    version.assert_aborted();
+    let error = watch.await.unwrap().unwrap_err();
+    assert!(error.starts_with("Report broke off"), "{error}");
+    // The version is free again
+    publish_report(&state, "report", 1, &["again"])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn a_failing_write_fails_the_report() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    let mut ingest = start_report(&state, "report", 1).await;
+    let version = backend.version("report", 1).unwrap();
+    version.fail_next_write("disk full");
+    let error = match ingest.push_bytes(Bytes::from(report_xml(&["first"]))).await {
+        Ok(()) => finish(ingest).await.err().expect("the report was written"),
+        Err(error) => error.message(),
+    };
+    assert!(error.contains("disk full"), "{error}");
+    version.assert_aborted();
+}
+
+#[tokio::test]
+async fn a_watcher_falling_behind_is_told_so() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    let mut ingest = start_report(&state, "report", 1).await;
+    let version = backend.version("report", 1).unwrap();
+    version.set_max_lag(64);
+    let (_lines, watch) = watch(&state);
+    let lines: Vec<String> = (0..10).map(|line| format!("line {line}")).collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    push(&mut ingest, &report_xml(&lines)).await;
+
+    // Released all at once, the watcher is more than 64 bytes behind
+    version.advance_all();
+    assert_eq!(watch.await.unwrap().unwrap_err(), "Fell behind the report");
+}
+
+#[tokio::test]
+async fn a_reader_cannot_be_fed_as_an_upload() {
+    let backend = MockBackend::new();
+    let (state, _) = instance(&backend);
+    publish_report(&state, "report", 1, &["first"])
+        .await
+        .unwrap();
+    let reader = match ItemStreamComponent::new_reader(
+        &state,
+        "report".to_string(),
+        1,
+        ReadOptions::default(),
+    )
+    .await
+    {
+        Ok(reader) => reader,
+        Err(_) => panic!("the report could not be opened"),
+    };
+    assert!(matches!(
+        reader.into_ingest(None),
+        Err(IngestError::Failed(_))
+    ));
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use axum::http::{Method, StatusCode, header};
+use common::{TestInstance, properties, text};
+
+#[tokio::test]
+async fn what_an_interrupted_upload_left_is_served_on_request() {
+    let dir = {
+        let instance = TestInstance::start("partial-read");
+        instance.upload("item", 1, &properties(3)).await;
+        // As a crash leaves it, with part of version 2 on disk
+        std::fs::write(instance.data_path("item_2.xml"), &properties(3)[..40]).unwrap();
+        instance.stop()
+    };
+    let instance = TestInstance::start_in(dir, |_| {});
+    let (status, _) = instance.read("item", 2).await;
+    assert_eq!(status, StatusCode::GONE);
+
+    let response = instance
+        .open("/read-item-stream/item/2?allow_partial=true")
+        .await;
+    let headers = response.headers().clone();
+    let (status, body) = text(response).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body, properties(3)[..40]);
+    assert_eq!(headers[header::CONTENT_LENGTH], "40");
+    assert_eq!(headers["X-Stream-State"], "aborted");
+    assert_eq!(headers["X-Partial-Content"], "true");
+    assert_eq!(headers.get(header::ETAG), None);
+
+    // Committed versions are read as usual with the flag
+    let (status, body) = instance
+        .request(Method::GET, "/read-item-stream/item/1?allow_partial=true")
+        .await;
+    assert_eq!((status, body), (StatusCode::OK, properties(3)));
+
+    let (status, _) = instance
+        .request(Method::GET, "/read-item-stream/item/3?allow_partial=true")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, report) = instance.request(Method::DELETE, "/items/item/2").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let (status, _) = instance
+        .request(Method::GET, "/read-item-stream/item/2?allow_partial=true")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties};
+use serde_json::Value;
+
+#[tokio::test]
+async fn malformed_path_parameters_are_refused_on_every_route() {
+    let instance = TestInstance::start_with("path-params", |config| config.max_version = 100);
+    instance.upload("item", 1, &properties(2)).await;
+
+    let long_uri = format!("/read-item-stream/{}/1", "x".repeat(201));
+    for (method, uri, parameter) in [
+        (Method::GET, "/read-item-stream/item/0", "version"),
+        (Method::GET, "/read-item-stream/item/101", "version"),
+        (Method::GET, "/read-item-stream/item/one", "version"),
+        (Method::GET, "/read-item-stream/%20/1", "item_id"),
+        (Method::GET, "/read-item-stream/a%2Fb/1", "item_id"),
+        (Method::GET, "/read-item-stream/..%2F/1", "item_id"),
+        (Method::GET, &long_uri, "item_id"),
+        (Method::GET, "/read-item-stream/item/tag/%20", "tag"),
+        (Method::GET, "/items/%20/settings", "item_id"),
+        (Method::DELETE, "/items/item/0", "version"),
+    ] {
+        let (status, error) = instance.request(method, uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}: {error}");
+        assert_eq!(error_code(&error), "BAD_REQUEST", "{uri}");
+        let error: Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["details"]["parameter"], parameter, "{uri}");
+    }
+
+    // Padding is trimmed
+    let (status, body) = instance
+        .request(Method::GET, "/read-item-stream/%20item/1%20")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body, properties(2));
+}
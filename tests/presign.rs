@@ -0,0 +1,151 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, error_code, properties, text};
+use serde_json::{Value, json};
+use stream_db::logic::presign;
+
+const READ_TOKEN: &str = "read-token";
+
+fn with_read_token(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.read_token = Some(READ_TOKEN.to_string());
+    })
+}
+
+async fn get_with(instance: &TestInstance, uri: &str, token: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::builder().uri(uri);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    text(instance.send(request.body(Body::empty()).unwrap()).await).await
+}
+
+/// The URL of a presigned read of version 1 of `item`
+async fn presigned(instance: &TestInstance, request: Value) -> String {
+    let (status, body) = instance
+        .admin(Method::POST, "/items/item/1/presign", &request.to_string())
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let body: Value = serde_json::from_str(&body).unwrap();
+    body["url"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn reads_take_the_read_or_the_admin_token() {
+    let instance = with_read_token("read-token");
+    instance.upload("item", 1, &properties(2)).await;
+
+    for (token, expected) in [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some("read-token-"), StatusCode::FORBIDDEN),
+        (Some(READ_TOKEN), StatusCode::OK),
+        (Some(ADMIN_TOKEN), StatusCode::OK),
+    ] {
+        let (status, body) = get_with(&instance, "/read-item-stream/item/1", token).await;
+        assert_eq!(status, expected, "{token:?}: {body}");
+    }
+    let (status, _) = get_with(&instance, "/items/item/1/receipt", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = get_with(&instance, "/items/item/1/presign", Some(READ_TOKEN)).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, _) = instance
+        .json(Method::POST, "/items/item/1/presign", "{}")
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn a_presigned_url_reads_without_a_token_until_it_expires() {
+    let instance = with_read_token("presign");
+    let body = properties(3);
+    instance.upload("item", 1, &body).await;
+    instance.upload("item", 2, &body).await;
+
+    let url = presigned(&instance, json!({})).await;
+    assert_eq!(
+        get_with(&instance, &url, None).await,
+        (StatusCode::OK, body)
+    );
+
+    // Any change to the URL breaks its signature
+    let expires = url
+        .split("expires=")
+        .nth(1)
+        .unwrap()
+        .split('&')
+        .next()
+        .unwrap();
+    let later = (expires.parse::<i64>().unwrap() + 1).to_string();
+    for tampered in [
+        url.replace(&format!("expires={expires}"), &format!("expires={later}")),
+        url.replace("/item/1?", "/item/2?"),
+        url.replace("&sig=", "&sig=A"),
+        url.replace("&sig=", "&range=0-9&sig="),
+    ] {
+        let (status, error) = get_with(&instance, &tampered, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{tampered}: {error}");
+        assert_eq!(error_code(&error), "FORBIDDEN");
+    }
+
+    let expired = presign::presign(
+        &instance.state,
+        "item",
+        1,
+        chrono::Utc::now() - chrono::Duration::seconds(1),
+        None,
+    );
+    let (status, error) = get_with(&instance, &expired.url, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(error.contains("expired"), "{error}");
+
+    let (status, _) = instance
+        .admin(
+            Method::POST,
+            "/items/item/1/presign",
+            r#"{"expires_in_secs":999999999}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = instance
+        .admin(Method::POST, "/items/item/9/presign", "{}")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_presigned_url_restricted_to_a_range_reads_only_those_bytes() {
+    let instance = with_read_token("presign-range");
+    let body = properties(3);
+    instance.upload("item", 1, &body).await;
+
+    let url = presigned(&instance, json!({"range": "10-29"})).await;
+    let response = instance
+        .send(Request::builder().uri(&url).body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "20");
+    assert_eq!(response.headers()["X-Byte-Range"], "10-29");
+    let etag = response.headers()[header::ETAG].clone();
+    let (status, read) = text(response).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, body[10..30]);
+
+    // A range is part of the signature and of the version's tag
+    let widened = url.replace("range=10-29", "range=10-39");
+    assert_eq!(
+        get_with(&instance, &widened, None).await.0,
+        StatusCode::FORBIDDEN
+    );
+    let url = presigned(&instance, json!({})).await;
+    let response = instance
+        .send(Request::builder().uri(&url).body(Body::empty()).unwrap())
+        .await;
+    assert_ne!(response.headers()[header::ETAG], etag);
+
+    let past_the_end = format!("{}-{}", body.len(), body.len() + 10);
+    let url = presigned(&instance, json!({"range": past_the_end})).await;
+    let (status, error) = get_with(&instance, &url, None).await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE, "{error}");
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestInstance;
+use serde_json::{Value, json};
+use stream_db::component::item_stream_component;
+
+fn with_names(names: &[&str]) -> String {
+    let properties: String = names
+        .iter()
+        .map(|name| format!("<property name=\"{name}\" type=\"string\">x</property>"))
+        .collect();
+    format!("<properties>{properties}</properties>")
+}
+
+fn indexed(name: &str) -> TestInstance {
+    let instance = TestInstance::start_with(name, |config| config.property_name_index = true);
+    item_stream_component::start_background_tasks(&instance.state);
+    instance
+}
+
+async fn search(instance: &TestInstance, query: &str) -> (StatusCode, Value) {
+    let (status, page) = instance
+        .request(Method::GET, &format!("/search/properties?{query}"))
+        .await;
+    (status, serde_json::from_str(&page).unwrap_or(Value::Null))
+}
+
+fn matches(page: &Value) -> Vec<(String, u64)> {
+    page["matches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|found| {
+            (
+                found["item_id"].as_str().unwrap().to_string(),
+                found["version"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// Wait until searching for `query` matches `expected`, the index follows commits and
+/// deletes in the background
+async fn until_matches(instance: &TestInstance, query: &str, expected: &[(&str, u64)]) {
+    let expected: Vec<(String, u64)> = expected
+        .iter()
+        .map(|(item_id, version)| (item_id.to_string(), *version))
+        .collect();
+    common::eventually(|| async {
+        let (status, page) = search(instance, query).await;
+        (status == StatusCode::OK && matches(&page) == expected).then_some(())
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn searches_follow_commits_and_deletes_and_survive_a_restart() {
+    let instance = indexed("property-search");
+    instance.upload("a", 1, &with_names(&["color"])).await;
+    instance.upload("a", 2, &with_names(&["size"])).await;
+    instance
+        .upload("b", 1, &with_names(&["color", "colour"]))
+        .await;
+    until_matches(&instance, "name=color", &[("a", 1), ("b", 1)]).await;
+    until_matches(&instance, "name=color&latest_only=true", &[("b", 1)]).await;
+
+    // Paged by item and version
+    let (_, page) = search(&instance, "name=color&limit=1").await;
+    assert_eq!(matches(&page), [("a".to_string(), 1)]);
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+    let (_, page) = search(&instance, &format!("name=color&limit=1&cursor={cursor}")).await;
+    assert_eq!(matches(&page), [("b".to_string(), 1)]);
+    assert_eq!(page["next_cursor"], Value::Null);
+    let (status, _) = search(&instance, "name=color&cursor=garbage").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, names) = instance
+        .request(Method::GET, "/search/property-names?prefix=col")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let names: Value = serde_json::from_str(&names).unwrap();
+    assert_eq!(
+        names["names"],
+        json!([{"name": "color", "versions": 2}, {"name": "colour", "versions": 1}])
+    );
+
+    // Deleting the newer version makes the older one the latest again
+    instance.request(Method::DELETE, "/items/a/2").await;
+    until_matches(
+        &instance,
+        "name=color&latest_only=true",
+        &[("a", 1), ("b", 1)],
+    )
+    .await;
+    until_matches(&instance, "name=size", &[]).await;
+
+    let instance = TestInstance::start_in(instance.stop(), |config| {
+        config.property_name_index = true;
+    });
+    item_stream_component::start_background_tasks(&instance.state);
+    until_matches(&instance, "name=color", &[("a", 1), ("b", 1)]).await;
+}
+
+#[tokio::test]
+async fn a_lost_index_is_rebuilt_on_request() {
+    let instance = indexed("property-search-rebuild");
+    instance.upload("item", 1, &with_names(&["color"])).await;
+    until_matches(&instance, "name=color", &[("item", 1)]).await;
+
+    let instance = TestInstance::start_in(instance.stop(), |config| {
+        config.property_name_index = true;
+    });
+    std::fs::remove_file(instance.data_path(".property-names.jsonl")).unwrap();
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/rebuild-property-index", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["versions"], 1);
+    until_matches(&instance, "name=color", &[("item", 1)]).await;
+}
+
+#[tokio::test]
+async fn searches_are_refused_while_the_index_is_disabled() {
+    let instance = TestInstance::start("property-search-disabled");
+    let (status, _) = search(&instance, "name=color").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use axum::http::{HeaderValue, Method, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, properties, text, upload_request};
+use serde_json::Value;
+
+async fn upload_with(
+    instance: &TestInstance,
+    version: u64,
+    headers: &[(&'static str, String)],
+) -> (StatusCode, String) {
+    let mut request = upload_request("item", version, &properties(2));
+    for (name, value) in headers {
+        request
+            .headers_mut()
+            .insert(*name, HeaderValue::from_str(value).unwrap());
+    }
+    text(instance.send(request).await).await
+}
+
+#[tokio::test]
+async fn versions_record_who_wrote_them() {
+    let instance = TestInstance::start("provenance");
+
+    let (status, receipt) = upload_with(
+        &instance,
+        1,
+        &[
+            ("X-Producer", "ingest\tjob".to_string()),
+            ("X-Producer-Version", "1.2.3".to_string()),
+            ("X-Commit-Message", "nightly import".to_string()),
+            (
+                header::AUTHORIZATION.as_str(),
+                format!("Bearer {ADMIN_TOKEN}"),
+            ),
+        ],
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["producer"], "ingest job");
+    assert_eq!(receipt["producer_version"], "1.2.3");
+    assert_eq!(receipt["commit_message"], "nightly import");
+    assert_eq!(receipt["authenticated_as"], "admin");
+
+    let (status, receipt) =
+        upload_with(&instance, 2, &[("X-Commit-Message", "é".repeat(1500))]).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let (_, receipt) = instance.request(Method::GET, "/items/item/2/receipt").await;
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["commit_message"], "é".repeat(1024));
+    assert_eq!(receipt["commit_message_truncated"], true);
+    assert_eq!(receipt.get("producer"), None);
+    assert_eq!(receipt.get("authenticated_as"), None);
+
+    let (status, error) = upload_with(&instance, 3, &[("X-Producer", "p".repeat(129))]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+    let (status, _) = instance.read("item", 3).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The listing reports every version's receipt, and a restart keeps them
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    let (status, listing) = instance.request(Method::GET, "/items/item/versions").await;
+    assert_eq!(status, StatusCode::OK, "{listing}");
+    let listing: Value = serde_json::from_str(&listing).unwrap();
+    assert_eq!(listing["item_id"], "item");
+    let versions = listing["versions"].as_array().unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0]["producer"], "ingest job");
+    assert_eq!(versions[1]["commit_message_truncated"], true);
+}
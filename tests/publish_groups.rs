@@ -0,0 +1,132 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties, text, upload_request};
+use serde_json::{Value, json};
+
+async fn open_group(instance: &TestInstance) -> String {
+    let (status, group) = instance.json(Method::POST, "/publish-groups", "{}").await;
+    assert_eq!(status, StatusCode::CREATED, "{group}");
+    let group: Value = serde_json::from_str(&group).unwrap();
+    group["id"].as_str().unwrap().to_string()
+}
+
+async fn stage(
+    instance: &TestInstance,
+    group_id: &str,
+    item_id: &str,
+    version: u64,
+    body: &str,
+) -> (StatusCode, String) {
+    let mut request = upload_request(item_id, version, body);
+    request
+        .headers_mut()
+        .insert("X-Publish-Group", group_id.parse().unwrap());
+    text(instance.send(request).await).await
+}
+
+#[tokio::test]
+async fn staged_uploads_become_readable_together_on_commit() {
+    let instance = TestInstance::start("publish-group");
+    let group_id = open_group(&instance).await;
+    for (item_id, count) in [("data", 5), ("manifest", 1)] {
+        let (status, receipt) = stage(&instance, &group_id, item_id, 1, &properties(count)).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{receipt}");
+    }
+    assert_eq!(instance.read("data", 1).await.0, StatusCode::NOT_FOUND);
+    let (status, error) = instance.upload("data", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "LOCKED");
+
+    let (status, group) = instance
+        .request(Method::GET, &format!("/publish-groups/{group_id}"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{group}");
+    let group: Value = serde_json::from_str(&group).unwrap();
+    assert_eq!(group["staged"].as_array().unwrap().len(), 2);
+
+    let (status, committed) = instance
+        .request(Method::POST, &format!("/publish-groups/{group_id}/commit"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{committed}");
+    let committed: Value = serde_json::from_str(&committed).unwrap();
+    let versions = committed["versions"].as_array().unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0]["committed_at"], versions[1]["committed_at"]);
+    assert_eq!(instance.read("data", 1).await.1, properties(5));
+    assert_eq!(instance.read("manifest", 1).await.1, properties(1));
+
+    // The committed items take uploads like any other
+    let (status, _) = instance.upload("data", 2, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = instance
+        .request(Method::GET, &format!("/publish-groups/{group_id}"))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn aborted_groups_leave_nothing_behind() {
+    let instance = TestInstance::start("publish-group-abort");
+    for ttl_secs in [0, 86_401] {
+        let (status, _) = instance
+            .json(
+                Method::POST,
+                "/publish-groups",
+                &json!({ "ttl_secs": ttl_secs }).to_string(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{ttl_secs}");
+    }
+
+    let group_id = open_group(&instance).await;
+    let (status, _) = stage(&instance, &group_id, "item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    let (status, aborted) = instance
+        .request(Method::POST, &format!("/publish-groups/{group_id}/abort"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{aborted}");
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    let (status, _) = instance.upload("item", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn a_restart_keeps_staged_versions_and_finishes_promotions() {
+    let instance = TestInstance::start("publish-group-restart");
+    let open = open_group(&instance).await;
+    stage(&instance, &open, "waiting", 1, &properties(2)).await;
+    let promoting = open_group(&instance).await;
+    for item_id in ["a", "b"] {
+        stage(&instance, &promoting, item_id, 1, &properties(2)).await;
+    }
+    // As a commit cut short right after deciding to promote leaves it
+    let journal = json!({
+        "committed_at": "2026-01-02T03:04:05Z",
+        "versions": [
+            { "item_id": "a", "version": 1 },
+            { "item_id": "b", "version": 1 },
+        ],
+    });
+    std::fs::write(
+        instance.data_path(&format!(".publish-groups/{promoting}/promoting.json")),
+        journal.to_string(),
+    )
+    .unwrap();
+
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    for item_id in ["a", "b"] {
+        let (status, body) = instance.read(item_id, 1).await;
+        assert_eq!(status, StatusCode::OK, "{item_id}: {body}");
+    }
+    // Still staged, rather than taken for an interrupted upload
+    assert!(std::path::Path::new(&instance.data_path("waiting_1.xml")).exists());
+    assert_eq!(instance.read("waiting", 1).await.0, StatusCode::NOT_FOUND);
+    let (status, committed) = instance
+        .request(Method::POST, &format!("/publish-groups/{open}/commit"))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{committed}");
+    assert_eq!(instance.read("waiting", 1).await.1, properties(2));
+}
@@ -0,0 +1,649 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, next_chunk, properties, text};
+use http_body_util::BodyExt;
+use stream_db::persistence::io_engine::FsyncPolicy;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The length of the first two properties of `body`, which the server writes as soon as
+/// they arrived
+fn two_properties(body: &str) -> usize {
+    let first = body.find("</property>").unwrap() + "</property>".len();
+    first + body[first..].find("</property>").unwrap() + "</property>".len()
+}
+
+/// Wait until the upload of version 1 of `item` has written something
+async fn wait_for_bytes(instance: &TestInstance, item_id: &str) {
+    common::eventually(|| async {
+        let (_, progress) = instance
+            .request(Method::GET, &format!("/items/{item_id}/1/receipt"))
+            .await;
+        let progress: serde_json::Value = serde_json::from_str(&progress).ok()?;
+        (progress["details"]["bytes_written"].as_u64()? > 0).then_some(())
+    })
+    .await;
+}
+
+/// The JSON of the upload of `item_id` in an `/admin/streams` listing
+fn stream_status(streams: &str, item_id: &str) -> serde_json::Value {
+    let streams: serde_json::Value = serde_json::from_str(streams).unwrap();
+    streams
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|stream| stream["item_id"] == item_id)
+        .cloned()
+        .unwrap_or_else(|| panic!("{item_id} is not listed: {streams}"))
+}
+
+#[tokio::test]
+async fn a_committed_durability_reader_receives_nothing_before_the_commit() {
+    let instance = TestInstance::start_with("durability", |config| {
+        config.fsync_policy = FsyncPolicy::Commit;
+    });
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    upload.send(&body[..sent]);
+    wait_for_bytes(&instance, "item").await;
+    common::eventually(|| async {
+        let (status, _) = instance.request(Method::GET, "/items/item/1/receipt").await;
+        (status == StatusCode::CONFLICT).then_some(())
+    })
+    .await;
+
+    // Written bytes stream right away, none of them is synced yet
+    let mut written = instance.open_read("item", 1).await.into_body();
+    let first = next_chunk(&mut written).await.unwrap().unwrap();
+    assert_eq!(first, body.as_bytes()[..first.len()]);
+    let committed = instance
+        .open("/read-item-stream/item/1?durability=committed")
+        .await;
+    assert_eq!(committed.status(), StatusCode::OK);
+    let mut committed = committed.into_body();
+    let waited = tokio::time::timeout(Duration::from_millis(200), committed.frame()).await;
+    assert!(
+        waited.is_err(),
+        "a committed reader received unsynced bytes"
+    );
+
+    let (_, streams) = instance.admin(Method::GET, "/admin/streams", "").await;
+    let status = stream_status(&streams, "item");
+    assert_eq!(status["state"], "uploading");
+    assert_eq!(status["size"], sent);
+    assert_eq!(status["durable_size"], 0);
+    let (_, progress) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    let progress: serde_json::Value = serde_json::from_str(&progress).unwrap();
+    assert_eq!(progress["code"], "LOCKED");
+    assert_eq!(progress["details"]["bytes_written"], sent);
+    assert_eq!(progress["details"]["bytes_durable"], 0);
+
+    upload.send(&body[sent..]);
+    upload.finish();
+    let (status, receipt) = response.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let received = committed.collect().await.unwrap().to_bytes();
+    assert_eq!(received, body.as_bytes());
+}
+
+#[tokio::test]
+async fn both_durabilities_stream_right_away_when_every_chunk_is_synced() {
+    let instance = TestInstance::start("durability-chunk");
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    upload.send(&body[..sent]);
+    wait_for_bytes(&instance, "item").await;
+
+    let mut committed = instance
+        .open("/read-item-stream/item/1?durability=committed")
+        .await
+        .into_body();
+    let first = next_chunk(&mut committed).await.unwrap().unwrap();
+    assert_eq!(first, body.as_bytes()[..first.len()]);
+
+    upload.send(&body[sent..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    let (status, error) = instance
+        .request(
+            Method::GET,
+            "/read-item-stream/item/1?durability=eventually",
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+}
+
+#[tokio::test]
+async fn an_aligned_reader_of_a_stalled_upload_receives_keepalives_between_properties() {
+    let instance = TestInstance::start_with("keepalive", |config| {
+        config.read_keepalive_secs = Some(1);
+    });
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    upload.send(&body[..sent]);
+    wait_for_bytes(&instance, "item").await;
+
+    let aligned = instance
+        .open("/read-item-stream/item/1?align=property")
+        .await;
+    assert_eq!(aligned.headers()["x-keepalive"], "comment; interval=1");
+    let unaligned = instance.open_read("item", 1).await;
+    assert_eq!(unaligned.headers()["x-keepalive"], "none");
+    let mut aligned = aligned.into_body();
+    let mut received = next_chunk(&mut aligned).await.unwrap().unwrap().to_vec();
+    let rest = tokio::spawn(async move { aligned.collect().await.unwrap().to_bytes() });
+
+    // The writer stalls for more than two intervals
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    upload.send(&body[sent..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    received.extend(rest.await.unwrap());
+
+    let received = String::from_utf8(received).unwrap();
+    assert!(
+        received.matches("<!-- keepalive -->").count() >= 2,
+        "{received}"
+    );
+    assert_eq!(received.replace("<!-- keepalive -->", ""), body);
+    assert_eq!(text(unaligned).await.1, body);
+}
+
+/// Parse every line of an NDJSON body, failing on any that is not a JSON object
+fn ndjson_lines(body: &str) -> Vec<serde_json::Value> {
+    body.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let record: serde_json::Value =
+                serde_json::from_str(line).unwrap_or_else(|_| panic!("broken line {line:?}"));
+            assert!(record.is_object(), "{line}");
+            record
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn ndjson_reads_send_one_record_per_property() {
+    let instance = TestInstance::start("ndjson");
+    let body = concat!(
+        "<properties>\n",
+        "  <property name=\"plain\">1 &lt; 2 &amp; &#x33;</property>\n",
+        "  <property name=\"nested\"><string>Test</string></property>\n",
+        "  <property name=\"empty\"/>\n",
+        "  <property name=\"cdata\"><![CDATA[<raw>]]></property>\n",
+        "</properties>"
+    );
+    let (status, _) = instance.upload("item", 1, body).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let response = instance
+        .open("/read-item-stream/item/1?format=ndjson")
+        .await;
+    assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+    let (status, records) = text(response).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        ndjson_lines(&records),
+        [
+            serde_json::json!({"name": "plain", "value": "1 < 2 & 3"}),
+            serde_json::json!({"name": "nested", "raw": "<string>Test</string>"}),
+            serde_json::json!({"name": "empty", "value": ""}),
+            serde_json::json!({"name": "cdata", "value": "<raw>"}),
+        ]
+    );
+
+    let (status, records) = instance
+        .request(
+            Method::GET,
+            "/read-item-stream/item/1?format=ndjson&from_property=2",
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let names: Vec<_> = ndjson_lines(&records)
+        .iter()
+        .map(|record| record["name"].clone())
+        .collect();
+    assert_eq!(names, ["empty", "cdata"]);
+}
+
+#[tokio::test]
+async fn ndjson_readers_of_an_aborted_upload_receive_only_complete_lines() {
+    let instance = TestInstance::start("ndjson-aborted");
+    let body = properties(10);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    // The third property is cut off halfway
+    upload.send(&body[..sent + 20]);
+    wait_for_bytes(&instance, "item").await;
+
+    let reader = instance
+        .open("/read-item-stream/item/1?format=ndjson")
+        .await;
+    assert_eq!(reader.status(), StatusCode::OK);
+    let mut reader = reader.into_body();
+    let mut received = Vec::new();
+    while received.iter().filter(|&&byte| byte == b'\n').count() < 2 {
+        received.extend_from_slice(&next_chunk(&mut reader).await.unwrap().unwrap());
+    }
+    upload.break_off();
+    response.await.unwrap();
+    while let Some(Ok(chunk)) = next_chunk(&mut reader).await {
+        received.extend_from_slice(&chunk);
+    }
+
+    let records = ndjson_lines(&String::from_utf8(received).unwrap());
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        records[1],
+        serde_json::json!({"name": "p1", "value": "value 1"})
+    );
+}
+
+/// The response to a read of `uri`, sending `If-None-Match: if_none_match` if given
+async fn read_tagged(
+    instance: &TestInstance,
+    uri: &str,
+    if_none_match: Option<&str>,
+) -> (StatusCode, Option<String>, String) {
+    let mut request = Request::builder().uri(uri);
+    if let Some(etag) = if_none_match {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    let response = instance.send(request.body(Body::empty()).unwrap()).await;
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .map(|value| value.to_str().unwrap().to_string());
+    let (status, body) = text(response).await;
+    (status, etag, body)
+}
+
+#[tokio::test]
+async fn every_representation_of_a_version_has_its_own_etag() {
+    let instance = TestInstance::start("etag-representations");
+    let (status, body) = instance.upload("item", 1, &properties(4)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    let (status, body) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/renamed",
+            r#"{"rename": {"p0": "first"}}"#,
+        )
+        .await;
+    assert!(status.is_success(), "{body}");
+
+    let base = "/read-item-stream/item/1";
+    let reads = [
+        base.to_string(),
+        format!("{base}?xml=pretty"),
+        format!("{base}?xml=minified"),
+        format!("{base}?format=ndjson"),
+        format!("{base}?from_property=1"),
+        format!("{base}?from_property=2"),
+        format!("{base}?transform=renamed"),
+        format!("{base}?transform=renamed&xml=pretty"),
+    ];
+    let mut etags = BTreeMap::new();
+    for uri in &reads {
+        let (status, etag, body) = read_tagged(&instance, uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+        let etag = etag.unwrap_or_else(|| panic!("{uri} has no ETag"));
+        if let Some(other) = etags.insert(etag.clone(), uri) {
+            panic!("{uri} and {other} share the ETag {etag}");
+        }
+    }
+
+    for (etag, uri) in &etags {
+        // The same representation is not sent again
+        let (status, same, body) = read_tagged(&instance, uri, Some(etag)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED, "{uri}");
+        assert_eq!(same.as_ref(), Some(etag), "{uri}");
+        assert!(body.is_empty(), "{uri}: {body}");
+        // Another one's tag does not stand for it
+        for other in reads.iter().filter(|other| *other != *uri) {
+            let (status, _, body) = read_tagged(&instance, other, Some(etag)).await;
+            assert_eq!(status, StatusCode::OK, "{other} with the tag of {uri}");
+            assert!(!body.is_empty(), "{other}");
+        }
+    }
+
+    // Redefining the transform changes what it sends, and so its tag
+    let transformed = format!("{base}?transform=renamed");
+    let (_, before, _) = read_tagged(&instance, &transformed, None).await;
+    let (status, body) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/renamed",
+            r#"{"rename": {"p0": "other"}}"#,
+        )
+        .await;
+    assert!(status.is_success(), "{body}");
+    let (status, after, body) = read_tagged(&instance, &transformed, before.as_deref()).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert!(body.contains("other"), "{body}");
+    assert_ne!(before, after);
+}
+
+#[tokio::test]
+async fn reads_following_an_upload_are_always_sent_in_full() {
+    let instance = TestInstance::start("etag-in-flight");
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    upload.send(&body[..sent]);
+    wait_for_bytes(&instance, "item").await;
+
+    let request = Request::builder()
+        .uri("/read-item-stream/item/1")
+        .header(header::IF_NONE_MATCH, "*")
+        .body(Body::empty())
+        .unwrap();
+    let following = instance.send(request).await;
+    assert_eq!(following.status(), StatusCode::OK);
+    upload.send(&body[sent..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    assert_eq!(text(following).await.1, body);
+
+    // Once committed, the version is not sent again
+    let (status, _, stored) = read_tagged(&instance, "/read-item-stream/item/1", Some("*")).await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert!(stored.is_empty());
+}
+
+#[tokio::test]
+async fn xml_layouts_only_touch_whitespace_between_elements() {
+    let instance = TestInstance::start("xml-layout");
+    let body = "<properties>\n<property name=\"nested\"><value>  <a>1</a>  </value></property>  <property   name=\"mixed\">text <b>bold</b> tail</property><property name=\"blank\">   </property><property name=\"cdata\"><![CDATA[ <x>  </x> ]]>&amp;</property>\n</properties>";
+    let (status, receipt) = instance.upload("item", 1, body).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let expected = [
+        (
+            "pretty",
+            "<properties>\n  <property name=\"nested\">\n    <value>\n      <a>1</a>\n    </value>\n  </property>\n  <property   name=\"mixed\">text <b>bold</b> tail</property>\n  <property name=\"blank\">   </property>\n  <property name=\"cdata\"><![CDATA[ <x>  </x> ]]>&amp;</property>\n</properties>\n",
+        ),
+        (
+            "minified",
+            "<properties><property name=\"nested\"><value><a>1</a></value></property><property name=\"mixed\">text <b>bold</b> tail</property><property name=\"blank\">   </property><property name=\"cdata\"><![CDATA[ <x>  </x> ]]>&amp;</property></properties>",
+        ),
+    ];
+    // Mixed content, whitespace-only values and CDATA are sent as stored
+    for (layout, expected) in expected {
+        let response = instance
+            .open(&format!("/read-item-stream/item/1?xml={layout}"))
+            .await;
+        assert_eq!(response.headers()["X-Xml-Layout"], layout);
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        let (status, laid_out) = text(response).await;
+        assert_eq!(status, StatusCode::OK, "{laid_out}");
+        assert_eq!(laid_out, expected);
+    }
+
+    let (status, error) = instance
+        .request(
+            Method::GET,
+            "/read-item-stream/item/1?xml=pretty&format=ndjson",
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+}
+
+#[tokio::test]
+async fn reads_of_an_upload_answer_before_its_properties_arrive() {
+    let instance = TestInstance::start("stream-start");
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let first = body.find("</property>").unwrap() + "</property>".len();
+    upload.send(&body[..first]);
+    wait_for_bytes(&instance, "item").await;
+
+    // Neither read waits for the property it starts at
+    let resumed = tokio::time::timeout(
+        Duration::from_secs(1),
+        instance.open("/read-item-stream/item/1?from_property=2"),
+    )
+    .await
+    .expect("the headers waited for the data");
+    assert_eq!(resumed.status(), StatusCode::OK);
+    assert!(resumed.headers().contains_key("X-Stream-Start"));
+    let primed = tokio::time::timeout(
+        Duration::from_secs(1),
+        instance.open("/read-item-stream/item/1?from_property=1&prime=true"),
+    )
+    .await
+    .expect("the headers waited for the data");
+    assert_eq!(primed.headers()["X-Stream-Prime"], "comment");
+    let mut primed = primed.into_body();
+    let comment = next_chunk(&mut primed).await.unwrap().unwrap();
+    assert_eq!(comment, "<!-- stream-start -->");
+    let beyond = instance
+        .open("/read-item-stream/item/1?from_property=10")
+        .await;
+    assert_eq!(beyond.status(), StatusCode::OK);
+
+    upload.send(&body[first..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    let third = body.find("<property name=\"p2\"").unwrap();
+    assert_eq!(text(resumed).await.1, body[third..]);
+    let rest = primed.collect().await.unwrap().to_bytes();
+    assert_eq!(rest, body[first..]);
+    // The property never arrived, the stream fails instead of the status
+    assert!(beyond.into_body().collect().await.is_err());
+
+    let (status, _) = instance
+        .request(Method::GET, "/read-item-stream/item/1?from_property=10")
+        .await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    let (status, error) = instance
+        .request(Method::GET, "/read-item-stream/item/1?prime=true")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+}
+
+/// Send a GET for `uri` in a task of its own, for reads that only answer later
+fn spawn_read(
+    instance: &TestInstance,
+    uri: String,
+) -> tokio::task::JoinHandle<axum::http::Response<Body>> {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let app = instance.router();
+    tokio::spawn(async move { tower::ServiceExt::oneshot(app, request).await.unwrap() })
+}
+
+#[tokio::test]
+async fn reads_can_wait_for_a_size_or_the_commit_before_responding() {
+    let instance = TestInstance::start("read-wait");
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let sent = two_properties(&body);
+    upload.send(&body[..sent]);
+    wait_for_bytes(&instance, "item").await;
+
+    let at_size = spawn_read(
+        &instance,
+        format!("/read-item-stream/item/1?min_bytes={}", sent + 1),
+    );
+    let finished = spawn_read(
+        &instance,
+        "/read-item-stream/item/1?wait_for=finished".into(),
+    );
+    let (status, error) = instance
+        .request(
+            Method::GET,
+            &format!(
+                "/read-item-stream/item/1?min_bytes={}&wait_timeout=1",
+                body.len() * 2
+            ),
+        )
+        .await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT, "{error}");
+    assert_eq!(common::error_code(&error), "TIMEOUT");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["bytes_available"], sent);
+    assert!(!at_size.is_finished() && !finished.is_finished());
+
+    upload.send(&body[sent..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    let finished = finished.await.unwrap();
+    assert_eq!(
+        finished.headers()[header::CONTENT_LENGTH],
+        body.len().to_string()
+    );
+    assert_eq!(text(finished).await.1, body);
+    assert_eq!(text(at_size.await.unwrap()).await.1, body);
+
+    // A committed version satisfies any size
+    let (status, stored) = instance
+        .request(Method::GET, "/read-item-stream/item/1?min_bytes=1000000")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stored, body);
+    let (status, _) = instance
+        .request(Method::GET, "/read-item-stream/item/1?wait_timeout=1")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn as_of_reads_serve_the_version_committed_by_then() {
+    let instance = TestInstance::start("as-of");
+    for version in 1..=3 {
+        instance
+            .upload("item", version, &properties(version as usize))
+            .await;
+    }
+    // Commit the versions at 10:00, 12:00 and 14:00 UTC
+    let path = instance.data_path("item_metadata.xml");
+    let mut metadata = std::fs::read_to_string(&path).unwrap();
+    let mut start = 0;
+    for hour in [10, 12, 14] {
+        start += metadata[start..].find("committed_at=\"").unwrap() + "committed_at=\"".len();
+        let end = start + metadata[start..].find('"').unwrap();
+        metadata.replace_range(start..end, &format!("2026-01-01T{hour}:00:00Z"));
+    }
+    std::fs::write(&path, metadata).unwrap();
+
+    let (status, _) = instance
+        .request(
+            Method::GET,
+            "/read-item-stream/item/as-of/2026-01-01T09:59:59Z",
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    for (timestamp, version) in [
+        ("2026-01-01T10:00:00Z", 1),
+        ("2026-01-01T11:59:59.999Z", 1),
+        ("2026-01-01T12:00:00Z", 2),
+        ("2026-01-01T14:00:00%2B02:00", 2),
+        ("2026-01-01T16:00:00%2B02:00", 3),
+    ] {
+        let response = instance
+            .open(&format!("/read-item-stream/item/as-of/{timestamp}"))
+            .await;
+        assert_eq!(response.headers()["x-item-version"], version.to_string());
+        assert_eq!(text(response).await.1, properties(version));
+    }
+    for timestamp in ["2026-01-01T10:00:00", "yesterday"] {
+        let (status, _) = instance
+            .request(
+                Method::GET,
+                &format!("/read-item-stream/item/as-of/{timestamp}"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{timestamp}");
+    }
+}
+
+/// The declared length of a response, `None` when it is sent chunked
+fn declared_length(response: &axum::http::Response<Body>) -> Option<usize> {
+    let length = response.headers().get(header::CONTENT_LENGTH);
+    let chunked = response.headers().get(header::TRANSFER_ENCODING);
+    match (length, chunked) {
+        (Some(length), None) => Some(length.to_str().unwrap().parse().unwrap()),
+        (None, Some(chunked)) => {
+            assert_eq!(chunked, "chunked");
+            None
+        }
+        (length, chunked) => panic!("Content-Length {length:?} with Transfer-Encoding {chunked:?}"),
+    }
+}
+
+#[tokio::test]
+async fn committed_reads_declare_their_length_and_in_flight_reads_stay_chunked() {
+    let instance = TestInstance::start("content-length");
+    let body = properties(4);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..two_properties(&body)]);
+    wait_for_bytes(&instance, "item").await;
+    let in_flight = instance.open_read("item", 1).await;
+    assert_eq!(in_flight.status(), StatusCode::OK);
+    assert_eq!(declared_length(&in_flight), None);
+    upload.send(&body[two_properties(&body)..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    assert_eq!(text(in_flight).await.1, body);
+
+    let committed = instance.open_read("item", 1).await;
+    assert_eq!(declared_length(&committed), Some(body.len()));
+    assert_eq!(text(committed).await.1, body);
+
+    // Partial reads declare the part they send
+    let third = body.find("<property name=\"p2\"").unwrap();
+    let resumed = instance
+        .open("/read-item-stream/item/1?from_property=2")
+        .await;
+    assert_eq!(declared_length(&resumed), Some(body.len() - third));
+    assert_eq!(text(resumed).await.1, body[third..]);
+    let request = Request::builder()
+        .uri("/read-item-stream/item/1")
+        .header(header::RANGE, "bytes=12-41")
+        .body(Body::empty())
+        .unwrap();
+    let range = instance.send(request).await;
+    assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(declared_length(&range), Some(30));
+    assert_eq!(text(range).await.1, body[12..42]);
+}
+
+#[tokio::test]
+async fn a_read_returning_other_than_its_declared_length_is_cut_off() {
+    let instance = TestInstance::start_with("length-mismatch", |config| {
+        config.fault_injection = true;
+    });
+    let body = properties(4);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    // The bytes go missing between the data file and the response
+    let (status, rule) = instance
+        .admin(
+            Method::POST,
+            "/admin/faults",
+            r#"{"item_pattern": "item", "drop_read_bytes": 10}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{rule}");
+
+    let mut read = instance.open_read("item", 1).await;
+    assert_eq!(read.status(), StatusCode::OK);
+    assert_eq!(declared_length(&read), Some(body.len()));
+    let mut received = Vec::new();
+    let failed = loop {
+        match next_chunk(read.body_mut()).await {
+            Some(Ok(chunk)) => received.extend_from_slice(&chunk),
+            Some(Err(_)) => break true,
+            None => break false,
+        }
+    };
+    // The connection is closed rather than the body padded or ended quietly short
+    assert!(failed, "the read ended without an error");
+    assert_eq!(received, body.as_bytes()[..body.len() - 10]);
+}
@@ -0,0 +1,155 @@
+//! Reads fetching chunks ahead of a slow client (`STREAM_DB_READ_PREFETCH_DEPTH`). The
+//! tests read through the router in process, so there are no socket buffers between the
+//! read and the client to hide whether the two overlap.
+
+mod common;
+
+use common::{TestInstance, eventually, next_chunk, properties};
+
+use axum::http::{Method, StatusCode};
+
+use std::time::{Duration, Instant};
+
+/// How long the disk takes for every chunk, and the client for every chunk it receives
+const STALL: Duration = Duration::from_millis(20);
+
+/// Read version 1 of `item_id` to its end, taking `STALL` for every chunk received.
+/// Returns the bytes received and whether the read ended with an error.
+async fn read_slowly(instance: &TestInstance, item_id: &str) -> (Vec<u8>, bool) {
+    let response = instance.open_read(item_id, 1).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body();
+    let mut received = Vec::new();
+    while let Some(chunk) = next_chunk(&mut body).await {
+        match chunk {
+            Ok(chunk) => received.extend_from_slice(&chunk),
+            Err(_) => return (received, true),
+        }
+        tokio::time::sleep(STALL).await;
+    }
+    (received, false)
+}
+
+fn start(name: &str, depth: usize) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.read_prefetch_depth = depth;
+        config.fault_injection = true;
+    })
+}
+
+/// Bytes read ahead for the readers of version 1 of `item_id`, from `/admin/streams`
+async fn prefetched_bytes(instance: &TestInstance, item_id: &str) -> u64 {
+    let (status, body) = instance.admin(Method::GET, "/admin/streams", "").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let streams: serde_json::Value = serde_json::from_str(&body).unwrap();
+    streams
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|stream| stream["item_id"] == item_id && stream["version"] == 1)
+        .map_or(0, |stream| stream["prefetched_bytes"].as_u64().unwrap())
+}
+
+async fn stall_disk_reads(instance: &TestInstance, item_id: &str) {
+    let (status, body) = instance
+        .admin(
+            Method::POST,
+            "/admin/faults",
+            &format!(
+                r#"{{"item_pattern": "{item_id}", "read_stall_ms": {}}}"#,
+                STALL.as_millis()
+            ),
+        )
+        .await;
+    assert!(status.is_success(), "{body}");
+}
+
+/// Time a slow client takes to read an item of about 30 chunks off a slow disk
+async fn slow_read(depth: usize) -> (Duration, Vec<u8>) {
+    let instance = start(&format!("prefetch-{depth}"), depth);
+    let body = properties(5000);
+    let (status, response) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED, "{response}");
+    stall_disk_reads(&instance, "item").await;
+
+    let started = Instant::now();
+    let (received, failed) = read_slowly(&instance, "item").await;
+    let elapsed = started.elapsed();
+    assert!(!failed, "the read failed with depth {depth}");
+    assert_eq!(received, body.as_bytes(), "depth {depth}");
+    (elapsed, received)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prefetching_overlaps_disk_reads_with_a_slow_client() {
+    let (serial, serial_bytes) = slow_read(0).await;
+    let (prefetched, prefetched_bytes) = slow_read(4).await;
+    println!("slow client read: {serial:?} without prefetching, {prefetched:?} with depth 4");
+
+    assert_eq!(prefetched_bytes, serial_bytes);
+    // Without prefetching every chunk costs the disk's stall plus the client's, with it
+    // the two overlap and the read takes about half as long
+    assert!(
+        prefetched * 4 < serial * 3,
+        "prefetching took {prefetched:?}, reading chunk by chunk {serial:?}"
+    );
+}
+
+/// Kill an upload a reader follows while the client has not taken anything yet, then let
+/// the client read. Returns the bytes the reader had queued at the kill, the bytes the
+/// client received and whether an error ended them.
+async fn read_killed_upload(depth: usize) -> (u64, Vec<u8>, bool) {
+    let instance = start(&format!("prefetch-killed-{depth}"), depth);
+    let body = properties(5000);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..body.len() / 2]);
+    let read = eventually(|| async {
+        let response = instance.open_read("item", 1).await;
+        (response.status() == StatusCode::OK).then_some(response)
+    })
+    .await;
+    // Let the reader fill its queue while the client takes nothing
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let queued = prefetched_bytes(&instance, "item").await;
+
+    upload.break_off();
+    let (status, _) = response.await.unwrap();
+    assert!(!status.is_success(), "{status}");
+
+    let mut read = read.into_body();
+    let mut received = Vec::new();
+    while let Some(chunk) = next_chunk(&mut read).await {
+        match chunk {
+            Ok(chunk) => received.extend_from_slice(&chunk),
+            Err(_) => return (queued, received, true),
+        }
+        assert!(
+            body.as_bytes().starts_with(&received),
+            "depth {depth}: the bytes received are not the start of the upload"
+        );
+    }
+    (queued, received, false)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_prefetching_read_ends_with_the_upload_error_after_the_bytes_before_it() {
+    // Reading chunk by chunk, the client's first read already finds the upload gone
+    let (queued, received, failed) = read_killed_upload(0).await;
+    assert_eq!(queued, 0);
+    assert!(failed, "the read ended as if the version was complete");
+    println!(
+        "without prefetching {} bytes arrived before the error",
+        received.len()
+    );
+
+    // Read ahead, what was queued before the kill still arrives, and only then the error
+    let (queued, received, failed) = read_killed_upload(4).await;
+    assert!(queued > 0, "nothing was read ahead");
+    assert!(failed, "the read ended as if the version was complete");
+    assert!(
+        received.len() as u64 >= queued,
+        "{} bytes were queued, {} arrived before the error",
+        queued,
+        received.len()
+    );
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use common::{TestDir, TestInstance, error_code, properties, text};
+use stream_db::config::Config;
+
+use std::time::{Duration, Instant};
+
+fn with_sources(name: &str, budget_ms: u64) -> TestInstance {
+    TestInstance::start_in(TestDir::new(name), |config| configure(config, budget_ms))
+}
+
+fn configure(config: &mut Config, budget_ms: u64) {
+    config.fault_injection = true;
+    config.cold_dir = Some(format!("{}-cold", config.data_dir));
+    config.read_source_budget_ms = budget_ms;
+    // What each source failed with is checked in messages only sent with this
+    config.expose_internal_errors = true;
+}
+
+async fn set_fault(instance: &TestInstance, rule: &str) {
+    let (status, body) = instance.admin(Method::POST, "/admin/faults", rule).await;
+    assert!(status.is_success(), "{body}");
+}
+
+/// Read version 1 of `item_id`, with the source it was served from
+async fn read_from(instance: &TestInstance, item_id: &str) -> (StatusCode, Option<String>, String) {
+    let request = Request::builder()
+        .uri(format!("/read-item-stream/{item_id}/1"))
+        .body(Body::empty())
+        .unwrap();
+    let response = instance.send(request).await;
+    let served_from = response
+        .headers()
+        .get("X-Served-From")
+        .map(|source| source.to_str().unwrap().to_string());
+    let (status, body) = text(response).await;
+    (status, served_from, body)
+}
+
+#[tokio::test]
+async fn reads_name_the_source_they_were_served_from() {
+    let instance = with_sources("read-sources", 5000);
+    for item_id in ["hot", "cold"] {
+        let (status, receipt) = instance.upload(item_id, 1, &properties(2)).await;
+        assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    }
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/tier/cold/1/cold", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let (_, served_from, _) = read_from(&instance, "hot").await;
+    assert_eq!(served_from.as_deref(), Some("registry"));
+
+    // Once no longer open, versions come from the tier they are stored in
+    let instance = TestInstance::start_in(instance.stop(), |config| configure(config, 5000));
+    let (status, served_from, body) = read_from(&instance, "hot").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(served_from.as_deref(), Some("hot"));
+    assert_eq!(body, properties(2));
+    let (status, served_from, body) = read_from(&instance, "cold").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(served_from.as_deref(), Some("cold"));
+    assert_eq!(body, properties(2));
+
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.reads_served_hot.get(), 1);
+    assert_eq!(metrics.reads_served_cold.get(), 1);
+    assert_eq!(metrics.read_source_failures.get(), 0);
+}
+
+#[tokio::test]
+async fn a_failing_source_is_skipped_for_the_next_one() {
+    let instance = with_sources("read-sources-failing", 5000);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/tier/item/1/cold", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "fail_read_sources": ["registry", "hot"]}"#,
+    )
+    .await;
+    let (status, served_from, body) = read_from(&instance, "item").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(served_from.as_deref(), Some("cold"));
+    assert_eq!(body, properties(2));
+    assert_eq!(instance.state.metrics.read_source_failures.get(), 2);
+
+    // With every source failing the read fails, naming each failure
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "fail_read_sources": ["registry", "hot", "cold"]}"#,
+    )
+    .await;
+    let (status, _, error) = read_from(&instance, "item").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{error}");
+    for source in ["registry", "hot", "cold"] {
+        assert!(error.contains(&format!("{source} failed")), "{error}");
+    }
+}
+
+#[tokio::test]
+async fn a_read_gives_up_once_its_budget_is_spent() {
+    let instance = with_sources("read-sources-budget", 500);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "stall_read_sources": ["registry"], "read_source_stall_ms": 10000}"#,
+    )
+    .await;
+    let started = Instant::now();
+    let (status, _, error) = read_from(&instance, "item").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{error}");
+    assert_eq!(error_code(&error), "UNAVAILABLE");
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "{:?}",
+        started.elapsed()
+    );
+    assert_eq!(instance.state.metrics.read_source_budget_exhausted.get(), 1);
+
+    // A stall within the budget only slows the read down
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "stall_read_sources": ["registry"], "read_source_stall_ms": 50}"#,
+    )
+    .await;
+    let (status, served_from, body) = read_from(&instance, "item").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(served_from.as_deref(), Some("registry"));
+    assert_eq!(instance.state.metrics.read_source_budget_exhausted.get(), 1);
+}
+
+#[tokio::test]
+async fn concurrent_readers_of_a_version_on_disk_share_one_open() {
+    const READERS: u64 = 8;
+    let instance = with_sources("read-sources-collapsed", 5000);
+    let (status, receipt) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    // Restarted, so the version is no longer open and has to come from disk
+    let instance = TestInstance::start_in(instance.stop(), |config| configure(config, 5000));
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "stall_read_sources": ["hot"], "read_source_stall_ms": 500}"#,
+    )
+    .await;
+    let reads = (0..READERS).map(|_| read_from(&instance, "item"));
+    for (status, served_from, body) in futures::future::join_all(reads).await {
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(served_from.as_deref(), Some("hot"));
+        assert_eq!(body, properties(2));
+    }
+    assert_eq!(
+        instance.state.metrics.reader_opens_collapsed.get(),
+        READERS - 1
+    );
+}
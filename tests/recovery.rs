@@ -0,0 +1,107 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, properties};
+use stream_db::component::item_stream_component;
+
+/// A data directory as a crash leaves it: `item` has a committed version 1 and the
+/// first bytes of version 2, `orphan` never committed any version
+async fn crashed_data_dir(name: &str) -> common::TestDir {
+    let instance = TestInstance::start(name);
+    let (status, _) = instance.upload("item", 1, &properties(3)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    std::fs::write(instance.data_path("item_2.xml"), &properties(3)[..40]).unwrap();
+    std::fs::write(instance.data_path("orphan_1.xml"), "<properties><prop").unwrap();
+    instance.stop()
+}
+
+fn failed_uploads(listing: &str) -> Vec<(String, u64, u64)> {
+    let listing: serde_json::Value = serde_json::from_str(listing).unwrap();
+    listing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|failed| {
+            (
+                failed["item_id"].as_str().unwrap().to_string(),
+                failed["version"].as_u64().unwrap(),
+                failed["size"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn interrupted_uploads_are_listed_and_refused_after_a_restart() {
+    let dir = crashed_data_dir("recovery").await;
+    let instance = TestInstance::start_in(dir, |_| {});
+
+    // The committed version is served from disk, the interrupted ones are gone rather
+    // than missing or partially readable
+    let (status, body) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, properties(3));
+    let (status, error) = instance.read("item", 2).await;
+    assert_eq!(status, StatusCode::GONE, "{error}");
+    assert!(error.contains("interrupted"), "{error}");
+    let (status, _) = instance.read("orphan", 1).await;
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _) = instance.request(Method::GET, "/items/item/2/receipt").await;
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _) = instance.read("item", 3).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        failed_uploads(&listing),
+        [("item".to_string(), 2, 40), ("orphan".to_string(), 1, 17)]
+    );
+    let (status, error) = instance.request(Method::GET, "/admin/failed-uploads").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(common::error_code(&error), "UNAUTHORIZED");
+
+    // An operator deletes one, a client uploads the other again
+    let (status, report) = instance.request(Method::DELETE, "/items/orphan/1").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert!(report.contains("\"committed\":false"), "{report}");
+    assert!(!std::path::Path::new(&instance.data_path("orphan_1.xml")).exists());
+    let (status, _) = instance.read("orphan", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = instance.upload("item", 2, &properties(4)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(instance.read("item", 2).await.1, properties(4));
+
+    let (_, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    assert_eq!(listing, "[]");
+}
+
+#[tokio::test]
+async fn interrupted_uploads_are_purged_after_their_retention() {
+    let dir = crashed_data_dir("recovery-purge").await;
+    let instance = TestInstance::start_in(dir, |config| {
+        config.live_mut().failed_upload_retention_secs = Some(0);
+    });
+    let (_, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    assert_eq!(failed_uploads(&listing).len(), 2);
+
+    item_stream_component::start_background_tasks(&instance.state);
+    common::eventually(|| async {
+        let (_, listing) = instance
+            .admin(Method::GET, "/admin/failed-uploads", "")
+            .await;
+        (listing == "[]").then_some(())
+    })
+    .await;
+    assert!(!std::path::Path::new(&instance.data_path("item_2.xml")).exists());
+    assert!(!std::path::Path::new(&instance.data_path("orphan_1.xml")).exists());
+    let (status, _) = instance.read("item", 2).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(instance.read("item", 1).await.1, properties(3));
+}
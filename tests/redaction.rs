@@ -0,0 +1,121 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, error_code, properties, text};
+use stream_db::logic::property_redaction::RedactionPolicy;
+
+const BODY: &str = concat!(
+    "<properties>",
+    "<property name=\"id\">7</property>",
+    "<property name=\"password\">hunter2</property>",
+    "<property name=\"secret_key\" type=\"string\">abc</property>",
+    "</properties>"
+);
+
+fn redacting(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| {
+        config.redaction = RedactionPolicy::new(
+            vec!["password".to_string()],
+            vec!["secret_*".to_string()],
+            "[hidden]".to_string(),
+        );
+    })
+}
+
+async fn read_with(
+    instance: &TestInstance,
+    uri: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> (StatusCode, header::HeaderMap, String) {
+    let mut request = Request::builder().uri(uri);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let response = instance.send(request.body(Body::empty()).unwrap()).await;
+    let headers = response.headers().clone();
+    let (status, body) = text(response).await;
+    (status, headers, body)
+}
+
+#[tokio::test]
+async fn reads_drop_and_mask_the_properties_the_policy_names() {
+    let instance = redacting("redaction");
+    let (status, _) = instance.upload("item", 1, BODY).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, headers, body) = read_with(&instance, "/read-item-stream/item/1", &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        concat!(
+            "<properties>",
+            "<property name=\"id\">7</property>",
+            "<property name=\"secret_key\" type=\"string\"><string>[hidden]</string></property>",
+            "</properties>"
+        )
+    );
+    assert_eq!(headers["X-Redactions-Applied"], "2");
+    assert_eq!(headers.get(header::CONTENT_LENGTH), None);
+    assert!(
+        headers[header::ETAG]
+            .to_str()
+            .unwrap()
+            .ends_with(".redacted\"")
+    );
+
+    let (status, _, records) =
+        read_with(&instance, "/read-item-stream/item/1?format=ndjson", &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!records.contains("hunter2"), "{records}");
+    assert!(!records.contains("abc"), "{records}");
+
+    // A byte range would send the stored bytes around the policy
+    let (status, _, error) = read_with(
+        &instance,
+        "/read-item-stream/item/1",
+        &[(header::RANGE, "bytes=0-99")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{error}");
+}
+
+#[tokio::test]
+async fn only_admins_read_unredacted() {
+    let instance = redacting("redaction-unredacted");
+    instance.upload("item", 1, BODY).await;
+    let uri = "/read-item-stream/item/1?unredacted=true";
+
+    let (status, _, error) = read_with(&instance, uri, &[]).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{error}");
+    assert_eq!(error_code(&error), "FORBIDDEN");
+
+    let admin = format!("Bearer {ADMIN_TOKEN}");
+    let (status, headers, body) =
+        read_with(&instance, uri, &[(header::AUTHORIZATION, &admin)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, BODY);
+    assert_eq!(headers.get("X-Redactions-Applied"), None);
+}
+
+#[tokio::test]
+async fn versions_without_affected_properties_are_served_as_stored() {
+    let instance = redacting("redaction-untouched");
+    let body = properties(3);
+    instance.upload("item", 1, &body).await;
+    let (status, headers, read) = read_with(&instance, "/read-item-stream/item/1", &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, body);
+    assert_eq!(
+        headers[header::CONTENT_LENGTH],
+        body.len().to_string().as_str()
+    );
+    assert!(!headers[header::ETAG].to_str().unwrap().contains("redacted"));
+    let (status, _, _) = read_with(
+        &instance,
+        "/read-item-stream/item/1",
+        &[(header::RANGE, "bytes=0-9")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, properties};
+use stream_db::persistence::file_persistence;
+
+/// A read-only instance serving the data directory `writer` writes to
+fn replica_of(writer: &TestInstance, name: &str) -> TestInstance {
+    let data_dir = writer.state.config.data_dir.clone();
+    TestInstance::start_with(name, |config| {
+        config.data_dir = data_dir;
+        config.read_only = true;
+    })
+}
+
+#[tokio::test]
+async fn a_replica_serves_reads_and_refuses_changes() {
+    let writer = TestInstance::start("replica-writer");
+    let (status, _) = writer.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let replica = replica_of(&writer, "replica");
+
+    assert_eq!(
+        replica.read("item", 1).await,
+        (StatusCode::OK, properties(2))
+    );
+    let (_, health) = replica.request(Method::GET, "/health").await;
+    assert!(health.contains("\"mode\":\"read_only\""), "{health}");
+
+    let (status, error) = replica.upload("item", 2, &properties(1)).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(error_code(&error), "READ_ONLY");
+    let (status, _) = replica.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, _) = replica
+        .admin(Method::POST, "/admin/streams/item/1/kill", "")
+        .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, _) = replica.admin(Method::GET, "/admin/streams", "").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_replica_follows_commits_and_deletions_of_the_writer() {
+    let writer = TestInstance::start("replica-follow-writer");
+    let (status, _) = writer.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let replica = replica_of(&writer, "replica-follow");
+    assert_eq!(replica.read("item", 1).await.0, StatusCode::OK);
+
+    // A commit made after the replica started is found without a restart
+    writer.upload("item", 2, &properties(3)).await;
+    assert_eq!(
+        replica.read("item", 2).await,
+        (StatusCode::OK, properties(3))
+    );
+
+    // A version the replica has open is only dropped by its refresh
+    let (status, _) = writer.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK);
+    let report = file_persistence::refresh_registry(&replica.state.storage).unwrap();
+    assert_eq!(report.replaced, 1);
+    assert_eq!(replica.read("item", 1).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(replica.read("item", 2).await.0, StatusCode::OK);
+}
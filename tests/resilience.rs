@@ -0,0 +1,201 @@
+//! How the API behaves when storage fails at each stage of an upload: the right status,
+//! nothing half-written left behind, and nothing hanging
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, error_code, eventually, next_chunk, properties};
+
+use std::future::Future;
+use std::time::Duration;
+
+fn with_faults(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| config.fault_injection = true)
+}
+
+/// Fail the test instead of hanging it when `future` takes longer than 10 seconds
+async fn within<T>(future: impl Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_secs(10), future)
+        .await
+        .expect("did not finish within 10 seconds")
+}
+
+/// Files in the instance's data directory that belong to `item_id`, its metadata aside
+fn item_files(instance: &TestInstance, item_id: &str) -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(&instance.state.config.data_dir)
+        .unwrap()
+        .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+        .filter(|name| name.starts_with(&format!("{item_id}_")))
+        .filter(|name| !name.ends_with("_metadata.xml"))
+        .collect();
+    files.sort();
+    files
+}
+
+async fn set_fault(instance: &TestInstance, rule: &str) {
+    let (status, body) = instance.admin(Method::POST, "/admin/faults", rule).await;
+    assert!(status.is_success(), "{body}");
+}
+
+async fn clear_faults(instance: &TestInstance) {
+    let (status, body) = instance.admin(Method::DELETE, "/admin/faults", "").await;
+    assert!(status.is_success(), "{body}");
+}
+
+/// The request failed with an internal error
+fn assert_internal(status: StatusCode, body: &str) {
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+    assert_eq!(error_code(body), "INTERNAL");
+}
+
+#[tokio::test]
+async fn a_full_disk_fails_the_upload_and_leaves_nothing_behind() {
+    let instance = with_faults("disk-full");
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "disk_full_after_bytes": 4096}"#,
+    )
+    .await;
+    let body = properties(500);
+    let (status, response) = within(instance.upload("item", 1, &body)).await;
+    assert_internal(status, &response);
+
+    assert_eq!(item_files(&instance, "item"), Vec::<String>::new());
+    let (status, _) = within(instance.read("item", 1)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, streams) = instance.admin(Method::GET, "/admin/streams", "").await;
+    assert_eq!(streams, "[]");
+
+    // Once there is room again the same version is taken
+    clear_faults(&instance).await;
+    let (status, receipt) = within(instance.upload("item", 1, &body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(within(instance.read("item", 1)).await.1, body);
+}
+
+#[tokio::test]
+async fn a_failed_sync_does_not_commit_the_upload() {
+    let instance = with_faults("fsync");
+    let (status, _) = within(instance.upload("item", 1, &properties(3))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    set_fault(&instance, r#"{"item_pattern": "item", "fail_sync": true}"#).await;
+    let (status, response) = within(instance.upload("item", 2, &properties(5))).await;
+    assert_internal(status, &response);
+
+    // The version before it is still the latest, the failed one was never committed
+    let (status, _) = instance.request(Method::GET, "/items/item/2/receipt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = within(instance.read("item", 2)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(within(instance.read("item", 1)).await.1, properties(3));
+    assert_eq!(
+        item_files(&instance, "item")
+            .iter()
+            .filter(|name| name.contains("_2"))
+            .count(),
+        0
+    );
+
+    clear_faults(&instance).await;
+    let (status, receipt) = within(instance.upload("item", 2, &properties(5))).await;
+    assert!(status.is_success(), "{receipt}");
+}
+
+#[tokio::test]
+async fn a_killed_writer_fails_its_readers_and_frees_the_version() {
+    let instance = TestInstance::start("killed-writer");
+    let body = properties(500);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..body.len() / 2]);
+
+    // A reader following the upload gets what was written so far
+    let read = eventually(|| async {
+        let response = instance.open_read("item", 1).await;
+        (response.status() == StatusCode::OK).then_some(response)
+    })
+    .await;
+    let mut read = read.into_body();
+    let first = next_chunk(&mut read)
+        .await
+        .expect("the read ended")
+        .unwrap();
+    assert!(body.as_bytes().starts_with(&first), "{first:?}");
+
+    upload.break_off();
+    let (status, _) = within(response).await.unwrap();
+    assert!(
+        status.is_client_error() || status.is_server_error(),
+        "{status}"
+    );
+    // The reader is told, instead of waiting for bytes that never come
+    let mut ended_with_error = false;
+    while let Some(chunk) = next_chunk(&mut read).await {
+        if chunk.is_err() {
+            ended_with_error = true;
+            break;
+        }
+    }
+    assert!(
+        ended_with_error,
+        "the read ended as if the version was complete"
+    );
+
+    let (_, streams) = instance.admin(Method::GET, "/admin/streams", "").await;
+    assert_eq!(streams, "[]");
+    assert_eq!(item_files(&instance, "item"), Vec::<String>::new());
+    let (status, receipt) = within(instance.upload("item", 1, &body)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+}
+
+#[tokio::test]
+async fn truncated_metadata_fails_its_item_only() {
+    let instance = TestInstance::start("truncated-metadata");
+    for version in 1..=2 {
+        let (status, _) = instance.upload("broken", version, &properties(20)).await;
+        assert!(status.is_success());
+    }
+    let (status, _) = instance.upload("intact", 1, &properties(20)).await;
+    assert!(status.is_success());
+    let path = instance.data_path("broken_metadata.xml");
+    let metadata = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &metadata[..metadata.len() / 2]).unwrap();
+
+    // Requests that need the metadata fail cleanly
+    let (status, response) = within(instance.upload("broken", 3, &properties(5))).await;
+    assert_internal(status, &response);
+    let (status, response) = within(instance.request(Method::GET, "/items/broken/1/receipt")).await;
+    assert_internal(status, &response);
+
+    // A restart still serves the other items, and the broken one fails the same way
+    let dir = instance.stop();
+    let instance = TestInstance::start_in(dir, |_| {});
+    assert_eq!(within(instance.read("intact", 1)).await.1, properties(20));
+    let (status, response) = within(instance.read("broken", 1)).await;
+    assert_internal(status, &response);
+    assert_eq!(std::fs::read(&path).unwrap().len(), metadata.len() / 2);
+}
+
+#[tokio::test]
+async fn bytes_lost_on_the_way_to_disk_fail_the_commit() {
+    let instance = TestInstance::start_with("accounting", |config| {
+        config.fault_injection = true;
+        // The failure is checked in a message only sent with this
+        config.expose_internal_errors = true;
+    });
+    set_fault(
+        &instance,
+        r#"{"item_pattern": "item", "drop_write_bytes": 3}"#,
+    )
+    .await;
+    let (status, response) = within(instance.upload("item", 1, &properties(20))).await;
+    assert_internal(status, &response);
+    assert!(response.contains("accounting"), "{response}");
+
+    let (status, _) = within(instance.read("item", 1)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(
+        metrics.contains("stream_db_write_accounting_mismatches_total 1\n"),
+        "{metrics}"
+    );
+}
@@ -0,0 +1,386 @@
+mod common;
+
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::interceptors::BeforeTransmitInterceptorContextRef;
+use aws_sdk_s3::config::{
+    BehaviorVersion, ConfigBag, Credentials, Intercept, Region, RuntimeComponents,
+};
+use aws_sdk_s3::error::{BoxError, ProvideErrorMetadata};
+use aws_sdk_s3::primitives::ByteStream;
+use axum::Router;
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use common::{TestInstance, properties};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use stream_db::api::router;
+use stream_db::config::S3Credentials;
+use tower::ServiceExt;
+
+const ACCESS_KEY: &str = "AKIDEXAMPLE";
+const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+async fn send(s3: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let response = s3.clone().oneshot(request).await.unwrap();
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    (
+        parts.status,
+        parts.headers,
+        String::from_utf8(body.to_vec()).unwrap(),
+    )
+}
+
+fn put(uri: &str, body: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::builder().uri(uri).body(Body::empty()).unwrap()
+}
+
+/// The `<Code>` of an S3 error document
+fn s3_code(body: &str) -> &str {
+    let start = body.find("<Code>").expect(body) + "<Code>".len();
+    &body[start..start + body[start..].find("</Code>").unwrap()]
+}
+
+/// Every `<Key>` of a listing, in order
+fn keys(listing: &str) -> Vec<&str> {
+    listing
+        .split("<Key>")
+        .skip(1)
+        .map(|rest| &rest[..rest.find("</Key>").unwrap()])
+        .collect()
+}
+
+/// The S3 API of `instance` served on a local port, for clients that need a connection
+async fn serve_s3(instance: &TestInstance) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = router::s3_app(instance.state.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    address
+}
+
+/// The `x-amz-content-sha256` header of every request a client sent
+#[derive(Debug, Clone, Default)]
+struct PayloadHashes(Arc<Mutex<Vec<String>>>);
+
+impl Intercept for PayloadHashes {
+    fn name(&self) -> &'static str {
+        "PayloadHashes"
+    }
+
+    fn read_before_transmit(
+        &self,
+        context: &BeforeTransmitInterceptorContextRef<'_>,
+        _: &RuntimeComponents,
+        _: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let hash = context.request().headers().get("x-amz-content-sha256");
+        self.0
+            .lock()
+            .unwrap()
+            .push(hash.unwrap_or_default().to_string());
+        Ok(())
+    }
+}
+
+/// An SDK client of the S3 API at `address`, signing its requests as `access_key` with
+/// `secret_key`
+fn client(address: SocketAddr, access_key: &str, secret_key: &str) -> (Client, PayloadHashes) {
+    let hashes = PayloadHashes::default();
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new(access_key, secret_key, None, None, "test"))
+        .endpoint_url(format!("http://{address}"))
+        .force_path_style(true)
+        .interceptor(hashes.clone())
+        .build();
+    (Client::from_conf(config), hashes)
+}
+
+#[tokio::test]
+async fn objects_round_trip_through_items() {
+    let instance = TestInstance::start("s3-objects");
+    let s3 = router::s3_app(instance.state.clone());
+    let body = properties(3);
+
+    let mut request = put("/photos/2026/a.xml", &body);
+    request
+        .headers_mut()
+        .insert("x-amz-meta-camera", "x100".parse().unwrap());
+    let (status, headers, error) = send(&s3, request).await;
+    assert_eq!(status, StatusCode::OK, "{error}");
+    let etag = format!("\"{:x}\"", Sha256::digest(body.as_bytes()));
+    assert_eq!(headers[header::ETAG], etag.as_str());
+    assert_eq!(headers["x-amz-version-id"], "1");
+
+    let (status, headers, read) = send(&s3, get("/photos/2026/a.xml")).await;
+    assert_eq!((status, read.as_str()), (StatusCode::OK, body.as_str()));
+    assert_eq!(headers[header::ETAG], etag.as_str());
+    assert_eq!(headers["x-amz-meta-camera"], "x100");
+    let mut request = get("/photos/2026/a.xml");
+    request
+        .headers_mut()
+        .insert(header::RANGE, "bytes=0-9".parse().unwrap());
+    let (status, _, read) = send(&s3, request).await;
+    assert_eq!(
+        (status, read.as_str()),
+        (StatusCode::PARTIAL_CONTENT, &body[..10])
+    );
+    let request = Request::builder()
+        .method(Method::HEAD)
+        .uri("/photos/2026/a.xml")
+        .body(Body::empty())
+        .unwrap();
+    let (status, headers, _) = send(&s3, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers[header::CONTENT_LENGTH],
+        body.len().to_string().as_str()
+    );
+
+    // The object is the item `{bucket}:{key}`, with the slashes of the key encoded
+    assert_eq!(
+        instance.read("photos:2026%252Fa.xml", 1).await,
+        (StatusCode::OK, body.clone())
+    );
+
+    // A put writes the next version
+    let (status, headers, _) = send(&s3, put("/photos/2026/a.xml", &properties(4))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-amz-version-id"], "2");
+
+    // Deleting only hides the object from the S3 API
+    let request = Request::builder()
+        .method(Method::DELETE)
+        .uri("/photos/2026/a.xml")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&s3, request).await.0, StatusCode::NO_CONTENT);
+    let (status, _, error) = send(&s3, get("/photos/2026/a.xml")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(s3_code(&error), "NoSuchKey");
+    assert_eq!(
+        instance.read("photos:2026%252Fa.xml", 2).await,
+        (StatusCode::OK, properties(4))
+    );
+    send(&s3, put("/photos/2026/a.xml", &body)).await;
+    let (status, headers, _) = send(&s3, get("/photos/2026/a.xml")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-amz-version-id"], "3");
+}
+
+#[tokio::test]
+async fn listings_group_and_page_keys() {
+    let instance = TestInstance::start("s3-list");
+    let s3 = router::s3_app(instance.state.clone());
+    for key in ["a/1.xml", "a/2.xml", "b.xml", "c.xml"] {
+        let (status, _, error) = send(&s3, put(&format!("/bucket/{key}"), &properties(1))).await;
+        assert_eq!(status, StatusCode::OK, "{key}: {error}");
+    }
+    send(&s3, put("/other/a/3.xml", &properties(1))).await;
+
+    let (status, _, listing) = send(&s3, get("/bucket?list-type=2")).await;
+    assert_eq!(status, StatusCode::OK, "{listing}");
+    assert_eq!(keys(&listing), ["a/1.xml", "a/2.xml", "b.xml", "c.xml"]);
+
+    let (_, _, listing) = send(&s3, get("/bucket?list-type=2&delimiter=/")).await;
+    assert_eq!(keys(&listing), ["b.xml", "c.xml"]);
+    assert!(listing.contains("<CommonPrefixes><Prefix>a/</Prefix></CommonPrefixes>"));
+
+    let (_, _, listing) = send(&s3, get("/bucket?list-type=2&prefix=a/")).await;
+    assert_eq!(keys(&listing), ["a/1.xml", "a/2.xml"]);
+
+    let mut listed = Vec::new();
+    let mut uri = "/bucket?list-type=2&max-keys=3".to_string();
+    loop {
+        let (_, _, listing) = send(&s3, get(&uri)).await;
+        listed.extend(keys(&listing).into_iter().map(str::to_string));
+        let Some(start) = listing.find("<NextContinuationToken>") else {
+            break;
+        };
+        let start = start + "<NextContinuationToken>".len();
+        let token = &listing[start..start + listing[start..].find('<').unwrap()];
+        uri = format!("/bucket?list-type=2&max-keys=3&continuation-token={token}");
+    }
+    assert_eq!(listed, ["a/1.xml", "a/2.xml", "b.xml", "c.xml"]);
+}
+
+#[tokio::test]
+async fn uploads_are_decoded_and_checked() {
+    let instance = TestInstance::start("s3-uploads");
+    let s3 = router::s3_app(instance.state.clone());
+    let body = properties(2);
+
+    let mut request = put("/bucket/checked.xml", &body);
+    request.headers_mut().insert(
+        "x-amz-content-sha256",
+        format!("{:x}", Sha256::digest(b"other")).parse().unwrap(),
+    );
+    let (status, _, error) = send(&s3, request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(s3_code(&error), "XAmzContentSHA256Mismatch");
+    let (status, _, _) = send(&s3, get("/bucket/checked.xml")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (first, second) = body.split_at(body.len() / 2);
+    let chunked = format!(
+        "{:x};chunk-signature=00\r\n{first}\r\n{:x};chunk-signature=01\r\n{second}\r\n0;chunk-signature=02\r\n\r\n",
+        first.len(),
+        second.len()
+    );
+    let mut request = put("/bucket/chunked.xml", &chunked);
+    let headers = request.headers_mut();
+    headers.insert(
+        "x-amz-content-sha256",
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD".parse().unwrap(),
+    );
+    headers.insert(header::CONTENT_ENCODING, "aws-chunked".parse().unwrap());
+    headers.insert("x-amz-decoded-content-length", body.len().into());
+    let (status, _, error) = send(&s3, request).await;
+    assert_eq!(status, StatusCode::OK, "{error}");
+    let (_, _, read) = send(&s3, get("/bucket/chunked.xml")).await;
+    assert_eq!(read, body);
+
+    for request in [
+        get("/"),
+        get("/bucket"),
+        Request::builder()
+            .method(Method::POST)
+            .uri("/bucket/chunked.xml?uploads")
+            .body(Body::empty())
+            .unwrap(),
+    ] {
+        let (status, _, error) = send(&s3, request).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED, "{error}");
+        assert_eq!(s3_code(&error), "NotImplemented");
+    }
+}
+
+#[tokio::test]
+async fn an_s3_client_round_trips_signed_objects() {
+    let instance = TestInstance::start_with("s3-client", |config| {
+        config.s3_credentials = Some(S3Credentials {
+            access_key: ACCESS_KEY.to_string(),
+            secret_key: SECRET_KEY.to_string(),
+        });
+    });
+    let (s3, hashes) = client(serve_s3(&instance).await, ACCESS_KEY, SECRET_KEY);
+    let body = properties(3);
+
+    // The key needs encoding in the path, the payload is signed by its hash
+    let put = s3
+        .put_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .metadata("camera", "x100")
+        .body(ByteStream::from(body.clone().into_bytes()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(put.version_id(), Some("1"));
+    let payload_hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+    assert_eq!(hashes.0.lock().unwrap().as_slice(), [payload_hash]);
+
+    let object = s3
+        .get_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(object.metadata().unwrap()["camera"], "x100");
+    let read = object.body.collect().await.unwrap().into_bytes();
+    assert_eq!(read, body.as_bytes());
+    let range = s3
+        .get_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .range("bytes=0-9")
+        .send()
+        .await
+        .unwrap();
+    let read = range.body.collect().await.unwrap().into_bytes();
+    assert_eq!(read, body.as_bytes()[..10]);
+    let head = s3
+        .head_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(head.content_length(), Some(body.len() as i64));
+
+    // The prefix of the listing is encoded in the query
+    s3.put_object()
+        .bucket("photos")
+        .key("2026/other.xml")
+        .body(ByteStream::from(body.clone().into_bytes()))
+        .send()
+        .await
+        .unwrap();
+    let listing = s3
+        .list_objects_v2()
+        .bucket("photos")
+        .prefix("2026/a b+")
+        .send()
+        .await
+        .unwrap();
+    let listed: Vec<_> = listing.contents().iter().filter_map(|o| o.key()).collect();
+    assert_eq!(listed, ["2026/a b+c.xml"]);
+
+    s3.delete_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .send()
+        .await
+        .unwrap();
+    let error = s3
+        .get_object()
+        .bucket("photos")
+        .key("2026/a b+c.xml")
+        .send()
+        .await
+        .unwrap_err();
+    assert_eq!(error.code(), Some("NoSuchKey"), "{error:?}");
+}
+
+#[tokio::test]
+async fn requests_are_signed_with_the_access_key() {
+    let instance = TestInstance::start_with("s3-signed", |config| {
+        config.s3_credentials = Some(S3Credentials {
+            access_key: ACCESS_KEY.to_string(),
+            secret_key: SECRET_KEY.to_string(),
+        });
+    });
+    let s3 = router::s3_app(instance.state.clone());
+    let (status, _, error) = send(&s3, get("/bucket?list-type=2")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(s3_code(&error), "AccessDenied");
+
+    let address = serve_s3(&instance).await;
+    for (access_key, secret_key, code) in [
+        (ACCESS_KEY, "wrong", "SignatureDoesNotMatch"),
+        ("AKIDOTHER", SECRET_KEY, "InvalidAccessKeyId"),
+    ] {
+        let (s3, _) = client(address, access_key, secret_key);
+        let error = s3
+            .get_object()
+            .bucket("bucket")
+            .key("a.xml")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Some(code), "{error:?}");
+    }
+}
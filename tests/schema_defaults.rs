@@ -0,0 +1,121 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use common::{TestInstance, error_code, text};
+use serde_json::Value;
+
+const SCHEMA: &str = r#"{"schema": {"properties": [
+    {"name": "count", "type": "int", "default": "0"},
+    {"name": "owner", "default": "nobody"},
+    {"name": "note"}
+]}}"#;
+
+async fn set_schema(instance: &TestInstance, item_id: &str, settings: &str) -> Value {
+    let (status, settings) = instance
+        .json(Method::PUT, &format!("/items/{item_id}/settings"), settings)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{settings}");
+    serde_json::from_str(&settings).unwrap()
+}
+
+async fn read_with(instance: &TestInstance, uri: &str) -> (StatusCode, HeaderMap, String) {
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = instance.send(request).await;
+    let headers = response.headers().clone();
+    let (status, body) = text(response).await;
+    (status, headers, body)
+}
+
+#[tokio::test]
+async fn missing_properties_are_read_with_their_defaults() {
+    let instance = TestInstance::start("schema-defaults");
+    let settings = set_schema(&instance, "item", SCHEMA).await;
+    assert_eq!(settings["schema"]["version"], 1);
+    let stored = r#"<item><property name="count" type="int">7</property><property name="owner"></property></item>"#;
+    let (status, receipt) = instance.upload("item", 1, stored).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let (status, headers, body) =
+        read_with(&instance, "/read-item-stream/item/1?apply_defaults=true").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    // The empty owner is present, and note has no default
+    assert_eq!(body, stored);
+    assert_eq!(headers["X-Defaults-Applied"], "0");
+    assert_eq!(headers["X-Schema-Version"], "1");
+
+    let stored = r#"<item><property name="note">hi</property></item>"#;
+    let (status, receipt) = instance.upload("item", 2, stored).await;
+    assert!(status.is_success(), "{receipt}");
+    let (status, headers, body) =
+        read_with(&instance, "/read-item-stream/item/2?apply_defaults=true").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(
+        body,
+        concat!(
+            r#"<item><property name="note">hi</property>"#,
+            r#"<property name="count" type="int" source="default">0</property>"#,
+            r#"<property name="owner" source="default">nobody</property></item>"#,
+        )
+    );
+    assert_eq!(headers["X-Defaults-Applied"], "2");
+    assert!(headers.get("Content-Length").is_none());
+    let etag = headers["ETag"].to_str().unwrap().to_string();
+    assert!(etag.ends_with(".schema1\""), "{etag}");
+    assert_eq!(instance.state.metrics.read_defaults_applied.get(), 2);
+
+    // Reads without the option get the stored bytes
+    assert_eq!(instance.read("item", 2).await.1, stored);
+
+    // A schema with other properties gets the next version, and a tag of its own
+    let settings = set_schema(
+        &instance,
+        "item",
+        r#"{"schema": {"properties": [{"name": "count", "type": "int", "default": "1"}]}}"#,
+    )
+    .await;
+    assert_eq!(settings["schema"]["version"], 2);
+    let (_, headers, body) =
+        read_with(&instance, "/read-item-stream/item/2?apply_defaults=true").await;
+    assert!(
+        body.contains(r#"source="default">1</property></item>"#),
+        "{body}"
+    );
+    assert_eq!(headers["X-Schema-Version"], "2");
+    assert_ne!(headers["ETag"].to_str().unwrap(), etag);
+}
+
+#[tokio::test]
+async fn defaults_are_refused_where_missing_properties_cannot_be_known() {
+    let instance = TestInstance::start("schema-defaults-refused");
+    set_schema(&instance, "item", SCHEMA).await;
+    let (status, receipt) = instance
+        .upload(
+            "item",
+            1,
+            r#"<item><property name="note">hi</property></item>"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    for query in ["from_property=1", "segment=0"] {
+        let (status, _, error) = read_with(
+            &instance,
+            &format!("/read-item-stream/item/1?apply_defaults=true&{query}"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{query}: {error}");
+        assert_eq!(error_code(&error), "BAD_REQUEST");
+    }
+
+    for schema in [
+        r#"{"schema": {"properties": [{"name": ""}]}}"#,
+        r#"{"schema": {"properties": [{"name": "a"}, {"name": "a"}]}}"#,
+        r#"{"schema": {"properties": [{"name": "a", "type": "decimal"}]}}"#,
+        r#"{"schema": {"properties": [{"name": "a", "type": "int", "default": "x"}]}}"#,
+    ] {
+        let (status, error) = instance
+            .json(Method::PUT, "/items/item/settings", schema)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{schema}: {error}");
+    }
+}
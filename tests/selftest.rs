@@ -0,0 +1,31 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestInstance;
+
+#[tokio::test]
+async fn the_self_test_round_trip_passes_and_cleans_up() {
+    let instance = TestInstance::start_with("selftest", |config| config.selftest_max_mb = 2);
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/selftest?size_mb=1", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["passed"], true);
+    assert_eq!(report["cleaned_up"], true);
+    assert!(report["bytes_sent"].as_u64().unwrap() >= 1024 * 1024);
+    assert_eq!(report["bytes_read"], report["bytes_written"]);
+    assert_eq!(report["read_sha256"], report["written_sha256"]);
+    let version = report["version"].as_u64().unwrap();
+    let (status, _) = instance.read("stream-db-selftest", version).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = instance
+        .admin(Method::POST, "/admin/selftest?size_mb=3", "")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = instance
+        .request(Method::POST, "/admin/selftest?size_mb=1")
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
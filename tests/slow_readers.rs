@@ -0,0 +1,87 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, UploadBody, next_chunk, properties};
+use serde_json::Value;
+use stream_db::persistence::io_engine::FsyncPolicy;
+use stream_db::persistence::shared_file::SlowReaderPolicy;
+
+use tokio::task::JoinHandle;
+
+/// An upload of `body` with a reader attached, which fell more than the 1 MiB allowed
+/// behind once the upload is all but its last bytes
+async fn stalled_reader(
+    instance: &TestInstance,
+    body: &str,
+) -> (UploadBody, JoinHandle<(StatusCode, String)>, Body) {
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..100]);
+    common::eventually(|| async {
+        let (_, progress) = instance.request(Method::GET, "/items/item/1/receipt").await;
+        let progress: Value = serde_json::from_str(&progress).ok()?;
+        (progress["details"]["bytes_written"].as_u64()? > 0).then_some(())
+    })
+    .await;
+    let read = instance.open_read("item", 1).await;
+    assert_eq!(read.status(), StatusCode::OK);
+
+    upload.send(&body[100..body.len() - 100]);
+    common::eventually(|| async {
+        let (_, streams) = instance.admin(Method::GET, "/admin/streams", "").await;
+        let streams: Value = serde_json::from_str(&streams).unwrap();
+        (streams[0]["reader_lags"][0].as_u64()? > 1024 * 1024).then_some(())
+    })
+    .await;
+    (upload, response, read.into_body())
+}
+
+#[tokio::test]
+async fn a_reader_falling_too_far_behind_is_terminated() {
+    let instance = TestInstance::start_with("slow-reader-terminate", |config| {
+        config.fsync_policy = FsyncPolicy::Commit;
+        config.slow_reader_max_lag_mb = Some(1);
+        config.slow_reader_policy = SlowReaderPolicy::Terminate;
+    });
+    let body = properties(25_000);
+    let (mut upload, response, mut read) = stalled_reader(&instance, &body).await;
+
+    let mut received = 0;
+    let failure = loop {
+        match next_chunk(&mut read).await {
+            Some(Ok(chunk)) => received += chunk.len(),
+            Some(Err(error)) => break error,
+            None => panic!("the read ended after {received} bytes"),
+        }
+    };
+    assert!(received < body.len(), "{failure}");
+
+    // The upload is not held up by its reader
+    upload.send(&body[body.len() - 100..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_slow_readers_terminated_total 1"));
+}
+
+#[tokio::test]
+async fn a_reader_falling_behind_reads_on_from_disk() {
+    let instance = TestInstance::start_with("slow-reader-disk", |config| {
+        config.fsync_policy = FsyncPolicy::Commit;
+        config.slow_reader_max_lag_mb = Some(1);
+    });
+    let body = properties(25_000);
+    let (mut upload, response, mut read) = stalled_reader(&instance, &body).await;
+
+    let mut received = next_chunk(&mut read).await.unwrap().unwrap().to_vec();
+    upload.send(&body[body.len() - 100..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    while let Some(chunk) = next_chunk(&mut read).await {
+        received.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(received, body.as_bytes());
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_slow_readers_downgraded_total 1"));
+    assert!(metrics.contains("stream_db_slow_readers_terminated_total 0"));
+}
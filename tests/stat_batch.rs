@@ -0,0 +1,155 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{TestInstance, properties, text};
+use serde_json::{Value, json};
+
+/// A batch with a valid request between every invalid one: the invalid item IDs are
+/// refused in place, the valid requests are answered as usual
+fn batch() -> Value {
+    json!([
+        {"item_id": "item", "version": 1},
+        {"item_id": "../item", "version": 1},
+        {"item_id": "item"},
+        {"item_id": ""},
+        {"item_id": "item", "version": 2},
+        {"item_id": "i".repeat(201)},
+    ])
+}
+
+fn check_entries(entries: &[Value]) {
+    assert_eq!(entries.len(), 6, "{entries:?}");
+    for index in [0, 2] {
+        assert_eq!(entries[index]["item_id"], "item", "{index}");
+        assert_eq!(entries[index]["state"], "committed", "{index}");
+        assert_eq!(entries[index]["version"], 1, "{index}");
+        assert_eq!(entries[index]["property_count"], 2, "{index}");
+    }
+    assert_eq!(entries[4]["state"], "missing");
+    for (index, item_id) in [
+        (1, "../item".to_string()),
+        (3, String::new()),
+        (5, "i".repeat(201)),
+    ] {
+        let entry = &entries[index];
+        assert_eq!(entry["item_id"], item_id.as_str(), "{index}");
+        assert_eq!(entry["status"], 400, "{index}");
+        assert_eq!(entry["error"]["code"], "BAD_REQUEST", "{index}");
+        assert_eq!(
+            entry["error"]["details"],
+            json!({"parameter": "item_id", "value": item_id}),
+            "{index}"
+        );
+        assert!(entry.get("state").is_none(), "{index}");
+    }
+}
+
+fn stat_batch(accept: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri("/items/stat-batch")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ACCEPT, accept)
+        .body(Body::from(batch().to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn invalid_item_ids_in_a_stat_batch_are_refused_one_by_one() {
+    let instance = TestInstance::start("stat-batch-invalid");
+    let (status, body) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+
+    let (status, body) = text(instance.send(stat_batch("application/json")).await).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let entries: Vec<Value> = serde_json::from_str(&body).unwrap();
+    check_entries(&entries);
+
+    let (status, body) = text(instance.send(stat_batch("application/x-ndjson")).await).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let entries: Vec<Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    check_entries(&entries);
+
+    // Nothing named by an invalid ID was created on the way
+    assert!(!std::path::Path::new(&instance.data_path("../item_metadata.xml")).exists());
+}
+
+#[tokio::test]
+async fn a_stat_batch_of_invalid_item_ids_only_answers_each() {
+    let instance = TestInstance::start("stat-batch-all-invalid");
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/items/stat-batch")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ACCEPT, "application/x-ndjson")
+        .body(Body::from(
+            json!([{"item_id": "a/b"}, {"item_id": "."}]).to_string(),
+        ))
+        .unwrap();
+    let (status, body) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let entries: Vec<Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries.len(), 2, "{body}");
+    assert!(entries.iter().all(|entry| entry["status"] == 400), "{body}");
+}
+
+#[tokio::test]
+async fn a_mixed_batch_is_answered_without_opening_data_files() {
+    let instance = TestInstance::start_with("stat-batch-mixed", |config| {
+        config.stat_batch_max = 5;
+    });
+    let body = properties(3);
+    instance.upload("item", 1, &properties(2)).await;
+    let (mut upload, running) = instance.start_upload("item", 2, body.len());
+    upload.send(&body[..10]);
+    common::eventually(|| async {
+        let (status, _) = instance.request(Method::GET, "/items/item/2/receipt").await;
+        (status == StatusCode::CONFLICT).then_some(())
+    })
+    .await;
+
+    let (status, entries) = instance
+        .json(
+            Method::POST,
+            "/items/stat-batch",
+            &json!([
+                {"item_id": "item", "version": 1},
+                {"item_id": "item"},
+                {"item_id": "item", "version": 2},
+                {"item_id": "item", "version": 3},
+                {"item_id": "nothing"},
+            ])
+            .to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{entries}");
+    let entries: Vec<Value> = serde_json::from_str(&entries).unwrap();
+    let states: Vec<_> = entries.iter().map(|entry| &entry["state"]).collect();
+    assert_eq!(
+        states,
+        ["committed", "committed", "in_flight", "missing", "missing"]
+    );
+    assert_eq!(entries[0]["exists"], true);
+    assert_eq!(entries[0]["size"], properties(2).len());
+    assert!(entries[0]["sha256"].is_string(), "{:?}", entries[0]);
+    assert_eq!(entries[1]["version"], 1);
+    assert_eq!(entries[4]["exists"], false);
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.reader_disk_opens.get(), 0);
+    assert_eq!(metrics.reader_registry_hits.get(), 0);
+
+    let too_many = json!(vec![json!({"item_id": "item"}); 6]);
+    let (status, _) = instance
+        .json(Method::POST, "/items/stat-batch", &too_many.to_string())
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    upload.break_off();
+    let _ = running.await;
+}
@@ -0,0 +1,388 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{
+    TestDir, TestInstance, error_code, first_version_status, properties, text, upload_request,
+};
+use serde_json::Value;
+
+use std::path::Path;
+
+async fn usage(instance: &TestInstance) -> Value {
+    let (status, usage) = instance.admin(Method::GET, "/admin/usage", "").await;
+    assert_eq!(status, StatusCode::OK, "{usage}");
+    serde_json::from_str(&usage).unwrap()
+}
+
+#[tokio::test]
+async fn usage_follows_commits_and_deletes_and_survives_a_restart() {
+    let instance = TestInstance::start("usage");
+    let first = properties(10);
+    let second = properties(20);
+    instance.upload("item", 1, &first).await;
+    instance.upload("other", 1, &second).await;
+    let usage_now = usage(&instance).await;
+    assert_eq!(usage_now["committed_bytes"], first.len() + second.len());
+    assert_eq!(usage_now["in_flight_bytes"], 0);
+    assert_eq!(usage_now["used_bytes"], first.len() + second.len());
+    assert_eq!(usage_now["quota_bytes"], Value::Null);
+
+    let (status, _) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(usage(&instance).await["committed_bytes"], second.len());
+
+    // Recounted from the metadata
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    assert_eq!(usage(&instance).await["committed_bytes"], second.len());
+}
+
+#[tokio::test]
+async fn uploads_past_the_quota_are_refused_until_there_is_room_again() {
+    let body = properties(10);
+    let quota = body.len() as u64 * 2 + 10;
+    let instance = TestInstance::start_with("quota", |config| config.quota_bytes = Some(quota));
+    for version in 1..=2 {
+        let (status, receipt) = instance.upload("item", version, &body).await;
+        assert_eq!(status, first_version_status(version), "{receipt}");
+    }
+
+    // Refused up front from its Content-Length
+    let (status, error) = instance.upload("item", 3, &body).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{error}");
+    assert_eq!(error_code(&error), "QUOTA_EXCEEDED");
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["used_bytes"], body.len() * 2);
+    assert_eq!(error["details"]["quota_bytes"], quota);
+    assert_eq!(error["details"]["requested_bytes"], body.len());
+
+    // Refused as it grows when it announces no size, and cleaned up
+    let mut request = upload_request("item", 3, &body);
+    request.headers_mut().remove("content-length");
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{error}");
+    let usage_now = usage(&instance).await;
+    assert_eq!(usage_now["in_flight_bytes"], 0);
+    assert_eq!(usage_now["available_bytes"], 10);
+    let (status, _) = instance.read("item", 3).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Deleting a version or raising the quota makes room
+    instance.request(Method::DELETE, "/items/item/1").await;
+    let (status, receipt) = instance.upload("item", 3, &body).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let (status, _) = instance.upload("item", 4, &body).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+    let (status, quota) = instance
+        .admin(Method::PUT, "/admin/usage", r#"{"quota_bytes": null}"#)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{quota}");
+    let (status, _) = instance.upload("item", 4, &body).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn uploads_in_flight_count_against_the_quota_of_each_other() {
+    let body = properties(20);
+    let half = body.len() / 2;
+    // Both are written up to their last complete property before either goes on
+    let written = body[..half].rfind("</property>").unwrap() + "</property>".len();
+    // Either upload fits on its own, but not next to what the other wrote already
+    let quota = (body.len() + written - 1) as u64;
+    let instance = TestInstance::start_with("quota-parallel", |config| {
+        config.quota_bytes = Some(quota);
+    });
+    let (mut first, first_response) = instance.start_upload("first", 1, body.len());
+    let (mut second, second_response) = instance.start_upload("second", 1, body.len());
+    first.send(&body[..half]);
+    second.send(&body[..half]);
+    common::eventually(|| async {
+        (usage(&instance).await["in_flight_bytes"] == written * 2).then_some(())
+    })
+    .await;
+
+    first.send(&body[half..]);
+    first.finish();
+    let (status, error) = first_response.await.unwrap();
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{error}");
+    assert_eq!(usage(&instance).await["in_flight_bytes"], written);
+
+    // The refused upload made room for the other one
+    second.send(&body[half..]);
+    second.finish();
+    let (status, receipt) = second_response.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let usage_now = usage(&instance).await;
+    assert_eq!(usage_now["committed_bytes"], body.len());
+    assert_eq!(usage_now["in_flight_bytes"], 0);
+}
+
+fn json(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| panic!("not JSON: {body}"))
+}
+
+/// Upload and read a version through an instance writing uploads to `inflight_dir`,
+/// returning the commit strategy it reports
+async fn commit_through(dir: TestDir, inflight_dir: String) -> String {
+    let instance = TestInstance::start_in(dir, |config| {
+        config.inflight_dir = Some(inflight_dir.clone());
+    });
+    let body = properties(20);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let (status, read) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read, body);
+
+    // Nothing is left behind in either directory
+    let names = |dir: &str| -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect()
+    };
+    assert!(
+        names(&inflight_dir).is_empty(),
+        "{:?}",
+        names(&inflight_dir)
+    );
+    let data_dir = instance.data_path("");
+    assert!(
+        !names(&data_dir).iter().any(|name| name.ends_with(".tmp")),
+        "{:?}",
+        names(&data_dir)
+    );
+
+    let (status, storage) = instance.admin(Method::GET, "/admin/storage", "").await;
+    assert_eq!(status, StatusCode::OK, "{storage}");
+    json(&storage)["layout"]["commit_strategy"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn uploads_are_renamed_into_a_data_directory_on_the_same_filesystem() {
+    let dir = TestDir::new("commit-rename");
+    let inflight = dir.join("inflight");
+    let strategy = commit_through(dir, inflight).await;
+    assert_eq!(strategy, "rename");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn uploads_are_copied_into_a_data_directory_on_another_filesystem() {
+    use std::os::unix::fs::MetadataExt;
+
+    // A memory filesystem is another device than the temporary directory, where there
+    // is one
+    let shm = Path::new("/dev/shm");
+    let device = |path: &Path| std::fs::metadata(path).map(|metadata| metadata.dev());
+    match (device(shm), device(&std::env::temp_dir())) {
+        (Ok(shm), Ok(temp)) if shm != temp => {}
+        _ => {
+            println!("No second filesystem to upload from, skipped");
+            return;
+        }
+    }
+    let inflight = shm.join(format!("stream-db-inflight-{}", uuid::Uuid::new_v4()));
+    let strategy = commit_through(
+        TestDir::new("commit-copy"),
+        inflight.to_string_lossy().into_owned(),
+    )
+    .await;
+    std::fs::remove_dir_all(&inflight).unwrap();
+    assert_eq!(strategy, "copy");
+}
+
+#[tokio::test]
+async fn uploads_in_flight_stay_out_of_the_data_directory_until_committed() {
+    let dir = TestDir::new("inflight-dir");
+    let inflight = dir.join("inflight");
+    let configure = |config: &mut stream_db::config::Config| {
+        config.inflight_dir = Some(dir.join("inflight"));
+        config.inflight_quota_bytes = Some(5000);
+    };
+    let instance = TestInstance::start_in(TestDir::new("inflight-dir-data"), configure);
+    let body = properties(20);
+
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..200]);
+    let inflight_file = format!("{inflight}/item_1.xml");
+    common::eventually(|| async { Path::new(&inflight_file).exists().then_some(()) }).await;
+    assert!(!Path::new(&instance.data_path("item_1.xml")).exists());
+    upload.break_off();
+    response.await.unwrap();
+
+    // What a crash leaves behind is found where it was written, and deleted from there
+    let data = instance.stop();
+    std::fs::write(&inflight_file, &body[..200]).unwrap();
+    let instance = TestInstance::start_in(data, configure);
+    let (_, listing) = instance
+        .admin(Method::GET, "/admin/failed-uploads", "")
+        .await;
+    assert_eq!(json(&listing)[0]["item_id"], "item", "{listing}");
+    let (status, _) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert!(status.is_success());
+    assert!(!Path::new(&inflight_file).exists());
+
+    // The in-flight quota is counted apart from the overall one and leaves nothing behind
+    let (status, error) = instance.upload("item", 1, &properties(200)).await;
+    assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE, "{error}");
+    assert!(std::fs::read_dir(&inflight).unwrap().next().is_none());
+    let (status, _) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+/// The final report of `GET /admin/space-report`, failing on any error line
+async fn space_report(instance: &TestInstance) -> Value {
+    let (status, body) = instance.admin(Method::GET, "/admin/space-report", "").await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let lines: Vec<Value> = body.lines().map(json).collect();
+    assert!(
+        lines.iter().all(|line| line.get("error").is_none()),
+        "{body}"
+    );
+    lines.last().unwrap()["report"].clone()
+}
+
+#[tokio::test]
+async fn the_space_report_counts_the_bytes_of_every_version() {
+    let instance = TestInstance::start("space-report");
+    let versions = [("first", 1, 3), ("first", 2, 40), ("second", 1, 7)];
+    let mut sizes = Vec::new();
+    for (item_id, version, count) in versions {
+        let body = properties(count);
+        let (status, receipt) = instance.upload(item_id, version, &body).await;
+        assert!(status.is_success(), "{receipt}");
+        let size = std::fs::metadata(instance.data_path(&format!("{item_id}_{version}.xml")))
+            .unwrap()
+            .len();
+        assert_eq!(size, body.len() as u64, "{item_id} {version}");
+        sizes.push((item_id, size));
+    }
+    let bytes_of = |item_id: &str| -> u64 {
+        sizes
+            .iter()
+            .filter(|(id, _)| *id == item_id)
+            .map(|(_, size)| size)
+            .sum()
+    };
+    let total = bytes_of("first") + bytes_of("second");
+
+    let report = space_report(&instance).await;
+    let groups = report["groups"].as_array().unwrap();
+    assert_eq!(report["groups_total"], 2, "{report}");
+    // The larger item comes first
+    for (group, item_id) in groups.iter().zip(["first", "second"]) {
+        assert_eq!(group["key"], item_id, "{report}");
+        assert_eq!(group["bytes"]["committed"], bytes_of(item_id), "{report}");
+        assert_eq!(group["bytes"]["in_flight"], 0, "{report}");
+        assert_eq!(group["bytes"]["orphaned"], 0, "{report}");
+        let breakdown = group["bytes"].as_object().unwrap();
+        let sum: u64 = breakdown
+            .values()
+            .map(|bytes| bytes.as_u64().unwrap())
+            .sum();
+        assert_eq!(group["total_bytes"], sum, "{report}");
+    }
+    assert_eq!(report["found"]["committed"], total, "{report}");
+    assert_eq!(report["accounted"]["committed"], total, "{report}");
+    assert_eq!(report["discrepancies"], 0, "{report}");
+    let found: u64 = report["found"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|bytes| bytes.as_u64().unwrap())
+        .sum();
+    assert_eq!(report["found_bytes"], found, "{report}");
+    assert!(report["allocated_bytes"].as_u64().unwrap() > 0, "{report}");
+
+    // A deleted version no longer counts
+    let (status, body) = instance.admin(Method::DELETE, "/items/first/1", "").await;
+    assert!(status.is_success(), "{body}");
+    let report = space_report(&instance).await;
+    assert_eq!(report["found"]["committed"], total - sizes[0].1, "{report}");
+}
+
+#[tokio::test]
+async fn the_space_report_puts_every_kind_of_file_in_its_category() {
+    let dir = {
+        let instance = TestInstance::start("space-report-categories");
+        instance.upload("item", 1, &properties(3)).await;
+        // As a crash leaves them, with part of version 2 and its journal on disk
+        std::fs::write(instance.data_path("item_2.xml"), &properties(3)[..40]).unwrap();
+        std::fs::write(instance.data_path("item_2.journal.jsonl"), "x".repeat(30)).unwrap();
+        instance.stop()
+    };
+    let instance = TestInstance::start_in(dir, |_| {});
+    std::fs::write(instance.data_path("ghost_1.xml"), "x".repeat(500)).unwrap();
+    std::fs::write(instance.data_path("item_1.parts.json"), "x".repeat(20)).unwrap();
+    std::fs::create_dir_all(instance.data_path(".debug")).unwrap();
+    std::fs::write(instance.data_path(".debug/capture.raw"), "x".repeat(70)).unwrap();
+    let body = properties(3);
+    let (mut upload, response) = instance.start_upload("running", 1, body.len());
+    let first = body.find("</property>").unwrap() + "</property>".len();
+    upload.send(&body[..first]);
+    let written = common::eventually(|| async {
+        let (_, progress) = instance
+            .request(Method::GET, "/items/running/1/receipt")
+            .await;
+        json(&progress)["details"]["bytes_written"]
+            .as_u64()
+            .filter(|written| *written > 0)
+    })
+    .await;
+
+    let (status, body) = instance
+        .admin(Method::GET, "/admin/space-report?threshold_bytes=100", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let lines: Vec<Value> = body.lines().map(json).collect();
+    let report = &lines.last().unwrap()["report"];
+    let found = &report["found"];
+    assert_eq!(found["committed"], properties(3).len(), "{report}");
+    assert_eq!(found["in_flight"], written, "{report}");
+    assert_eq!(found["interrupted"], 40, "{report}");
+    assert_eq!(found["journals"], 30, "{report}");
+    assert_eq!(found["orphaned"], 500, "{report}");
+    // The commit wrote an index of its own next to the one planted
+    let indexes: u64 = std::fs::read_dir(instance.data_path(""))
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| {
+            let name = entry.file_name().into_string().unwrap();
+            [".index.jsonl", ".blocks.json", ".parts.json"]
+                .iter()
+                .any(|suffix| name.ends_with(suffix))
+        })
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(indexes > 20);
+    assert_eq!(found["indexes"], indexes, "{report}");
+    assert_eq!(found["debug_captures"], 70, "{report}");
+    assert!(found["metadata"].as_u64().unwrap() > 0, "{report}");
+    let groups = report["groups"].as_array().unwrap();
+    let ghost = groups.iter().find(|group| group["key"] == "ghost").unwrap();
+    assert_eq!(ghost["bytes"]["orphaned"], 500, "{report}");
+    let item = groups.iter().find(|group| group["key"] == "item").unwrap();
+    assert_eq!(item["bytes"]["interrupted"], 40, "{report}");
+
+    // Only the orphan is big enough to be flagged
+    let flagged: Vec<&Value> = lines
+        .iter()
+        .filter_map(|line| line.get("discrepancy"))
+        .collect();
+    assert_eq!(flagged.len(), 1, "{body}");
+    assert_eq!(flagged[0]["kind"], "orphaned");
+    assert_eq!(flagged[0]["item_id"], "ghost");
+    assert_eq!(report["discrepancies"], 1, "{report}");
+
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.space_orphaned_bytes.get(), 500);
+    assert_eq!(metrics.space_interrupted_bytes.get(), 40);
+    assert_eq!(metrics.space_debug_capture_bytes.get(), 70);
+    assert_eq!(metrics.space_in_flight_bytes.get(), written);
+    upload.break_off();
+    response.await.unwrap();
+    assert_eq!(metrics.space_in_flight_bytes.get(), 0);
+}
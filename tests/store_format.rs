@@ -0,0 +1,148 @@
+//! Data directories in the layouts of older releases, written out by hand as those
+//! releases left them, upgraded by the startup migrations.
+
+mod common;
+
+use common::{TestDir, TestInstance, properties};
+
+use axum::http::{Method, StatusCode};
+use stream_db::api::write_item_stream_api;
+use stream_db::component::item_stream_component;
+use stream_db::config::Config;
+use stream_db::persistence::item_metadata::{ItemMetadata, VersionMetadata};
+use stream_db::state::StreamDb;
+
+/// A data directory as releases before formats were recorded left it: no `FORMAT` file,
+/// and metadata that names only the latest version of each item
+fn format_0_fixture(name: &str) -> TestDir {
+    let dir = TestDir::new(name);
+    let data_dir = dir.path().join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    for (item_id, versions) in [("alpha", 2), ("beta", 1)] {
+        for version in 1..=versions {
+            std::fs::write(
+                data_dir.join(format!("{item_id}_{version}.xml")),
+                properties(version as usize + 1),
+            )
+            .unwrap();
+        }
+        std::fs::write(
+            data_dir.join(format!("{item_id}_metadata.xml")),
+            format!("<metadata>\n    <version>{versions}</version>\n</metadata>"),
+        )
+        .unwrap();
+    }
+    dir
+}
+
+fn metadata(instance: &TestInstance, item_id: &str) -> ItemMetadata {
+    ItemMetadata::load(&instance.data_path(&format!("{item_id}_metadata.xml"))).unwrap()
+}
+
+fn config_for(dir: &TestDir) -> Config {
+    let mut config = Config::load(true).unwrap();
+    config.data_dir = dir.join("data");
+    config
+}
+
+/// Start an instance over `dir` and return what its initialization said
+fn init_in(dir: &TestDir, configure: impl FnOnce(&mut Config)) -> Result<(), String> {
+    let mut config = config_for(dir);
+    configure(&mut config);
+    write_item_stream_api::init(&StreamDb::new(config))
+}
+
+#[tokio::test]
+async fn an_unversioned_store_is_upgraded_and_serves_its_items() {
+    let dir = format_0_fixture("format-0");
+    let status = item_stream_component::store_format(&StreamDb::new(config_for(&dir))).unwrap();
+    assert_eq!(status.format, Some(0));
+    let pending: Vec<_> = status
+        .pending
+        .iter()
+        .map(|migration| migration.name)
+        .collect();
+    assert_eq!(pending, ["metadata-versions"]);
+
+    let instance = TestInstance::start_in(dir, |_| {});
+    assert_eq!(
+        std::fs::read_to_string(instance.data_path("FORMAT")).unwrap(),
+        "1\n"
+    );
+    for (item_id, latest) in [("alpha", 2), ("beta", 1)] {
+        let metadata = metadata(&instance, item_id);
+        assert_eq!(metadata.latest_version, Some(latest));
+        assert!(metadata.versions.contains_key(&latest), "{item_id}");
+
+        let (status, body) = instance.read(item_id, latest).await;
+        assert_eq!(status, StatusCode::OK, "{item_id}: {body}");
+        assert_eq!(body, properties(latest as usize + 1));
+
+        let (status, body) = instance
+            .request(Method::GET, &format!("/items/{item_id}/versions"))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{item_id}: {body}");
+    }
+
+    // The upgraded items take new versions like any other
+    let (status, body) = instance.upload("alpha", 3, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (status, body) = instance.read("alpha", 3).await;
+    assert_eq!((status, body), (StatusCode::OK, properties(2)));
+}
+
+#[tokio::test]
+async fn an_interrupted_migration_finishes_on_the_next_start() {
+    let dir = format_0_fixture("format-0-interrupted");
+    // The crash came after the migration upgraded `alpha` and before it reached `beta`
+    let alpha_path = dir.join("data/alpha_metadata.xml");
+    let mut alpha = ItemMetadata::load(&alpha_path).unwrap();
+    alpha.add_committed(VersionMetadata {
+        version: 2,
+        ..Default::default()
+    });
+    std::fs::write(&alpha_path, alpha.to_xml()).unwrap();
+    let migrated_alpha = alpha.to_xml();
+
+    let instance = TestInstance::start_in(dir, |_| {});
+    assert_eq!(metadata(&instance, "alpha").to_xml(), migrated_alpha);
+    assert!(metadata(&instance, "beta").versions.contains_key(&1));
+
+    // Starting again on the upgraded store changes nothing
+    let beta = metadata(&instance, "beta").to_xml();
+    let instance = TestInstance::start_in(instance.stop(), |_| {});
+    assert_eq!(metadata(&instance, "alpha").to_xml(), migrated_alpha);
+    assert_eq!(metadata(&instance, "beta").to_xml(), beta);
+    for (item_id, latest) in [("alpha", 2), ("beta", 1)] {
+        let (status, body) = instance.read(item_id, latest).await;
+        assert_eq!(status, StatusCode::OK, "{item_id}: {body}");
+    }
+}
+
+#[test]
+fn a_store_of_a_newer_format_is_refused() {
+    let dir = format_0_fixture("format-newer");
+    std::fs::write(dir.join("data/FORMAT"), "2\n").unwrap();
+    let before = std::fs::read_to_string(dir.join("data/alpha_metadata.xml")).unwrap();
+
+    let error = init_in(&dir, |_| {}).unwrap_err();
+    assert!(error.contains("format 2"), "{error}");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("data/alpha_metadata.xml")).unwrap(),
+        before
+    );
+}
+
+#[test]
+fn a_read_only_instance_does_not_migrate() {
+    let dir = format_0_fixture("format-0-read-only");
+    let before = std::fs::read_to_string(dir.join("data/alpha_metadata.xml")).unwrap();
+
+    let error = init_in(&dir, |config| config.read_only = true).unwrap_err();
+    assert!(error.contains("needs migrating"), "{error}");
+    assert!(!dir.path().join("data/FORMAT").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("data/alpha_metadata.xml")).unwrap(),
+        before
+    );
+}
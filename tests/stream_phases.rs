@@ -0,0 +1,56 @@
+mod common;
+
+use axum::http::Method;
+use common::{TestInstance, properties};
+use http_body_util::BodyExt;
+use serde_json::Value;
+
+/// The entry of version 1 of `item_id` in `/admin/streams`, once `ready` holds for it
+async fn stream_status(
+    instance: &TestInstance,
+    item_id: &str,
+    ready: impl Fn(&Value) -> bool,
+) -> Value {
+    common::eventually(|| async {
+        let (_, streams) = instance.admin(Method::GET, "/admin/streams", "").await;
+        let streams: Value = serde_json::from_str(&streams).unwrap();
+        streams
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|stream| stream["item_id"] == item_id && stream["version"] == 1)
+            .filter(|stream| ready(stream))
+            .cloned()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn a_stalled_upload_and_its_follower_show_what_they_wait_for() {
+    let instance = TestInstance::start("stream-phases");
+    let body = properties(4);
+    let (mut upload, running) = instance.start_upload("item", 1, body.len());
+    let first_property = body.find("</property>").unwrap() + "</property>".len();
+    upload.send(&body[..first_property]);
+    stream_status(&instance, "item", |stream| stream["size"] != 0).await;
+    let reader = instance.open_read("item", 1).await;
+
+    let stream = stream_status(&instance, "item", |stream| {
+        stream["writer_phase"]["phase"] == "reading_body"
+            && stream["reader_phases"][0]["phase"] == "waiting_for_data"
+    })
+    .await;
+    assert_eq!(stream["state"], "uploading");
+    assert_eq!(stream["reader_phases"].as_array().unwrap().len(), 1);
+    assert!(stream["writer_phase"]["elapsed_ms"].is_u64());
+
+    upload.send(&body[first_property..]);
+    upload.finish();
+    running.await.unwrap();
+    let read = reader.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(read, body.as_bytes());
+    let stream = stream_status(&instance, "item", |stream| stream["readers"] == 0).await;
+    assert_eq!(stream["state"], "committed");
+    assert_eq!(stream.get("writer_phase"), None);
+    assert_eq!(stream["reader_phases"], Value::Array(Vec::new()));
+}
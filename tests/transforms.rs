@@ -0,0 +1,163 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, next_chunk, properties, text};
+
+#[tokio::test]
+async fn reads_are_reshaped_by_a_stored_transform() {
+    let instance = TestInstance::start("transforms");
+    let (status, _) = instance.upload("item", 1, &properties(4)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/public",
+            r#"{"rename": {"p0": "first"}, "drop": ["p2"], "envelope": "record"}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let response = instance
+        .open("/read-item-stream/item/1?transform=public")
+        .await;
+    assert_eq!(response.headers()["X-Transform"], "public");
+    let (status, body) = text(response).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        concat!(
+            "<record><properties>",
+            "<property name=\"first\" type=\"string\">value 0</property>",
+            "<property name=\"p1\" type=\"string\">value 1</property>",
+            "<property name=\"p3\" type=\"string\">value 3</property>",
+            "</properties></record>"
+        )
+    );
+
+    let (status, body) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/public",
+            r#"{"allow": ["p1"]}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let (_, body) = instance
+        .request(
+            Method::GET,
+            "/read-item-stream/item/1?transform=public&from_property=1",
+        )
+        .await;
+    assert_eq!(
+        body,
+        "<property name=\"p1\" type=\"string\">value 1</property></properties>"
+    );
+}
+
+#[tokio::test]
+async fn a_transform_envelope_replaces_the_stored_one() {
+    let instance = TestInstance::start_with("transforms-envelope", |config| {
+        config.wrap_root = true;
+    });
+    let body = format!("<?xml version=\"1.0\"?>{}", properties(2));
+    let (status, _) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/record",
+            r#"{"envelope": "record"}"#,
+        )
+        .await;
+
+    let (status, body) = instance
+        .request(Method::GET, "/read-item-stream/item/1?transform=record")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        format!("<?xml version=\"1.0\"?><record>{}</record>", properties(2))
+    );
+}
+
+#[tokio::test]
+async fn in_flight_versions_are_transformed_property_by_property() {
+    let instance = TestInstance::start("transforms-in-flight");
+    instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/renamed",
+            r#"{"rename": {"p1": "second"}}"#,
+        )
+        .await;
+    let body = properties(2);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    // The first property and half of the second one
+    let half = body.find("value 1").unwrap();
+    upload.send(&body[..half]);
+    common::eventually(|| async {
+        let (_, progress) = instance.request(Method::GET, "/items/item/1/receipt").await;
+        let progress: serde_json::Value = serde_json::from_str(&progress).ok()?;
+        (progress["details"]["bytes_written"].as_u64()? > 0).then_some(())
+    })
+    .await;
+
+    let mut read = instance
+        .open("/read-item-stream/item/1?transform=renamed")
+        .await
+        .into_body();
+    let first = next_chunk(&mut read).await.unwrap().unwrap();
+    assert_eq!(
+        first,
+        "<properties><property name=\"p0\" type=\"string\">value 0</property>"
+    );
+
+    upload.send(&body[half..]);
+    upload.finish();
+    assert_eq!(response.await.unwrap().0, StatusCode::CREATED);
+    let mut rest = Vec::new();
+    while let Some(chunk) = next_chunk(&mut read).await {
+        rest.extend(chunk.unwrap());
+    }
+    assert_eq!(
+        String::from_utf8(rest).unwrap(),
+        "<property name=\"second\" type=\"string\">value 1</property></properties>"
+    );
+}
+
+#[tokio::test]
+async fn transforms_are_validated_listed_and_deleted() {
+    let instance = TestInstance::start("transforms-admin");
+    let (status, error) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/broken",
+            r#"{"drop": ["a"], "allow": ["b"]}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error.contains("cannot be combined"), "{error}");
+    let (status, _) = instance
+        .admin(
+            Method::PUT,
+            "/admin/transforms/public",
+            r#"{"drop": ["secret"]}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, listing) = instance.admin(Method::GET, "/admin/transforms", "").await;
+    let listing: serde_json::Value = serde_json::from_str(&listing).unwrap();
+    assert_eq!(listing, serde_json::json!({"public": {"drop": ["secret"]}}));
+
+    let (status, _) = instance
+        .admin(Method::DELETE, "/admin/transforms/public", "")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    instance.upload("item", 1, &properties(1)).await;
+    let (status, error) = instance
+        .request(Method::GET, "/read-item-stream/item/1?transform=public")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error.contains("Unknown transform"), "{error}");
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use common::{TestInstance, error_code, properties, text};
+use http_body::Frame;
+use http_body_util::StreamBody;
+use serde_json::Value;
+
+/// A chunked upload of `body` with `headers`, ending with `trailers` if given
+fn chunked(
+    item_id: &str,
+    body: &str,
+    headers: &[(&str, String)],
+    trailers: Option<HeaderMap>,
+) -> Request<Body> {
+    let mut frames: Vec<Result<Frame<Bytes>, std::io::Error>> = body
+        .as_bytes()
+        .chunks(16)
+        .map(|piece| Ok(Frame::data(Bytes::copy_from_slice(piece))))
+        .collect();
+    if let Some(trailers) = trailers {
+        frames.push(Ok(Frame::trailers(trailers)));
+    }
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/1"))
+        .header(header::CONTENT_TYPE, "application/xml");
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    request
+        .body(Body::new(StreamBody::new(futures::stream::iter(frames))))
+        .unwrap()
+}
+
+fn stream_complete(value: &str) -> Option<HeaderMap> {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("X-Stream-Complete", value.parse().unwrap());
+    Some(trailers)
+}
+
+/// The upload committed, confirmed by `completeness`
+async fn assert_committed(instance: &TestInstance, request: Request<Body>, completeness: &str) {
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["received"]["completeness"], completeness);
+}
+
+/// The upload was refused as possibly cut off, and left nothing behind
+async fn assert_refused(instance: &TestInstance, request: Request<Body>) {
+    let item_id = request.uri().path().split('/').nth(2).unwrap().to_string();
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{item_id}: {error}");
+    assert_eq!(error_code(&error), "BAD_REQUEST");
+    assert_eq!(instance.read(&item_id, 1).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_body_is_only_committed_once_something_confirms_it_is_complete() {
+    let instance = TestInstance::start("completeness");
+    let body = properties(3);
+
+    assert_refused(&instance, chunked("unconfirmed", &body, &[], None)).await;
+
+    let size = ("X-Expected-Size", body.len().to_string());
+    assert_committed(
+        &instance,
+        chunked("size", &body, &[size], None),
+        "expected_size",
+    )
+    .await;
+    let count = ("X-Expected-Properties", "3".to_string());
+    assert_committed(
+        &instance,
+        chunked("properties", &body, &[count], None),
+        "expected_properties",
+    )
+    .await;
+    assert_committed(
+        &instance,
+        chunked("trailer", &body, &[], stream_complete("true")),
+        "trailer",
+    )
+    .await;
+    assert_eq!(instance.read("trailer", 1).await.1, body);
+}
+
+#[tokio::test]
+async fn a_body_contradicting_what_it_announced_is_refused() {
+    let instance = TestInstance::start("completeness-contradicted");
+    let body = properties(3);
+    // Cut off in the middle of the last property
+    let cut = &body[..body.len() - 20];
+
+    let size = ("X-Expected-Size", body.len().to_string());
+    assert_refused(&instance, chunked("size", cut, &[size], None)).await;
+    let count = ("X-Expected-Properties", "3".to_string());
+    assert_refused(&instance, chunked("properties", cut, &[count], None)).await;
+    let length = (header::CONTENT_LENGTH.as_str(), body.len().to_string());
+    assert_refused(&instance, chunked("length", cut, &[length], None)).await;
+    assert_refused(
+        &instance,
+        chunked("trailer", cut, &[], stream_complete("false")),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn unconfirmed_bodies_are_taken_when_allowed() {
+    let instance = TestInstance::start_with("completeness-allowed", |config| {
+        config.allow_unconfirmed_uploads = true;
+    });
+    let body = properties(3);
+    assert_committed(&instance, chunked("item", &body, &[], None), "unconfirmed").await;
+
+    // A body contradicting what it announced still is not
+    let cut = &body[..body.len() - 20];
+    let size = ("X-Expected-Size", body.len().to_string());
+    assert_refused(&instance, chunked("cut", cut, &[size], None)).await;
+}
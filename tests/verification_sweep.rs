@@ -0,0 +1,102 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestDir, TestInstance, properties};
+use serde_json::Value;
+use stream_db::logic::commit_hooks::{self, HookEvent, HookFailurePolicy, HookSpec};
+use stream_db::logic::verification_sweep;
+
+/// The last completed cycle, once there is one
+async fn last_cycle(instance: &TestInstance) -> Value {
+    common::eventually(|| async {
+        let (status, report) = instance.admin(Method::GET, "/admin/verification", "").await;
+        assert_eq!(status, StatusCode::OK, "{report}");
+        let report: Value = serde_json::from_str(&report).unwrap();
+        Some(report["last_cycle"].clone()).filter(|cycle| !cycle.is_null())
+    })
+    .await
+}
+
+async fn receipt(instance: &TestInstance, item_id: &str) -> Value {
+    let (_, receipt) = instance
+        .request(Method::GET, &format!("/items/{item_id}/1/receipt"))
+        .await;
+    serde_json::from_str(&receipt).unwrap()
+}
+
+#[tokio::test]
+async fn the_sweep_quarantines_rotten_versions_and_alerts() {
+    let dir = TestDir::new("verification-sweep");
+    let alerted = dir.join("alerted");
+    let instance = TestInstance::start_in(dir, |config| {
+        config.verify_sweep = true;
+        config.live_mut().verify_bytes_per_second = 0;
+        config.commit_hooks = vec![HookSpec {
+            name: "alert".to_string(),
+            event: HookEvent::Quarantine,
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!(
+                    "echo \"$HOOK_EVENT $ITEM_ID $QUARANTINE_CHECK $FOUND_SIZE $FOUND_SHA256\" > {alerted}"
+                ),
+            ],
+            timeout_secs: 10,
+            concurrency: 1,
+            on_failure: HookFailurePolicy::Log,
+        }];
+    });
+    for item_id in ["a", "b", "c"] {
+        instance.upload(item_id, 1, &properties(10)).await;
+    }
+    // Same size, other bytes
+    let path = instance.data_path("b_1.xml");
+    let original = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, original.replacen("property", "PROPERTY", 1)).unwrap();
+
+    commit_hooks::start(instance.state.clone());
+    verification_sweep::start(instance.state.clone());
+    let cycle = last_cycle(&instance).await;
+    assert_eq!(cycle["versions_due"], 3);
+    assert_eq!(cycle["versions_verified"], 2);
+    assert_eq!(cycle["versions_quarantined"], 1);
+    assert_eq!(cycle["bytes_hashed"], 3 * original.len());
+
+    assert!(receipt(&instance, "a").await["last_verified_at"].is_string());
+    let quarantined = receipt(&instance, "b").await;
+    assert_eq!(quarantined["quarantine_check"], "sweep");
+    let (status, _) = instance.read("b", 1).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let alert = common::eventually(|| async {
+        std::fs::read_to_string(&alerted)
+            .ok()
+            .filter(|line| line.ends_with('\n'))
+    })
+    .await;
+    let fields: Vec<&str> = alert.split_whitespace().collect();
+    assert_eq!(fields[..3], ["quarantine", "b", "sweep"]);
+    assert_eq!(fields[3], original.len().to_string());
+    assert_eq!(fields[4], quarantined["found_sha256"]);
+
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert!(metrics.contains("stream_db_verification_versions_verified_total 2"));
+    assert!(metrics.contains("stream_db_verification_failures_total 1"));
+}
+
+#[tokio::test]
+async fn a_cycle_verifies_a_sample_of_the_versions_due() {
+    let instance = TestInstance::start_with("verification-sample", |config| {
+        config.verify_sweep = true;
+        config.verify_sample_percent = 34;
+    });
+    for item_id in ["a", "b"] {
+        instance.upload(item_id, 1, &properties(2)).await;
+    }
+
+    verification_sweep::start(instance.state.clone());
+    let cycle = last_cycle(&instance).await;
+    assert_eq!(cycle["versions_due"], 2);
+    assert_eq!(cycle["versions_planned"], 1);
+    assert_eq!(cycle["versions_verified"], 1);
+}
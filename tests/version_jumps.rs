@@ -0,0 +1,94 @@
+mod common;
+
+use axum::http::{Method, StatusCode, header};
+use common::{ADMIN_TOKEN, TestInstance, error_code, properties, text, upload_request};
+use serde_json::{Value, json};
+
+fn limited(name: &str) -> TestInstance {
+    TestInstance::start_with(name, |config| config.max_version_jump = Some(10))
+}
+
+/// Upload `version` of `item` with `X-Allow-Version-Jump: true`, with the admin token
+/// when `admin` is set
+async fn jump(instance: &TestInstance, version: u64, admin: bool) -> (StatusCode, String) {
+    let mut request = upload_request("item", version, &properties(2));
+    request
+        .headers_mut()
+        .insert("x-allow-version-jump", "true".parse().unwrap());
+    if admin {
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {ADMIN_TOKEN}").parse().unwrap(),
+        );
+    }
+    text(instance.send(request).await).await
+}
+
+async fn reset(instance: &TestInstance, version: u64, force: bool) -> (StatusCode, Value) {
+    let (status, report) = instance
+        .admin(
+            Method::POST,
+            "/admin/items/item/reset-version",
+            &json!({ "version": version, "force": force }).to_string(),
+        )
+        .await;
+    (status, serde_json::from_str(&report).unwrap())
+}
+
+#[tokio::test]
+async fn versions_too_far_above_the_latest_are_refused_unless_an_admin_allows_it() {
+    let instance = limited("version-jump");
+    let (status, error) = instance.upload("item", 12, &properties(2)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    assert_eq!(error_code(&error), "LIMIT_EXCEEDED");
+    let (status, _) = instance.upload("item", 10, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, error) = instance.upload("item", 21, &properties(2)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let error: Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["current"], 10);
+    assert_eq!(error["details"]["requested"], 21);
+
+    assert_eq!(jump(&instance, 21, false).await.0, StatusCode::UNAUTHORIZED);
+    let (status, receipt) = jump(&instance, 21, true).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+}
+
+#[tokio::test]
+async fn resetting_the_version_forgets_the_versions_above_it() {
+    let instance = limited("version-reset");
+    for version in [1, 2] {
+        instance.upload("item", version, &properties(2)).await;
+    }
+    jump(&instance, 1000, true).await;
+    jump(&instance, 2000, true).await;
+
+    // Versions with data are only deleted with force
+    let (status, error) = reset(&instance, 2, false).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error["details"]["versions"], json!([1000, 2000]));
+    let (status, report) = reset(&instance, 2, true).await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    assert_eq!(report["previous_version"], 2000);
+    assert_eq!(report["latest_version"], 2);
+    assert_eq!(report["versions_deleted"], json!([1000, 2000]));
+    let (status, _) = instance.read("item", 1000).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The item is back to normal versioning
+    let (status, _) = instance.upload("item", 3, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Tagged versions are never deleted
+    jump(&instance, 100, true).await;
+    instance
+        .json(
+            Method::PUT,
+            "/items/item/version-tags/prod",
+            r#"{"version": 100}"#,
+        )
+        .await;
+    let (status, error) = reset(&instance, 3, true).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(instance.read("item", 100).await.0, StatusCode::OK);
+}
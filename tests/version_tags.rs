@@ -0,0 +1,120 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{TestInstance, first_version_status, properties, text};
+
+async fn tag(instance: &TestInstance, tag: &str, version: u64) -> (StatusCode, String) {
+    instance
+        .json(
+            Method::PUT,
+            &format!("/items/item/version-tags/{tag}"),
+            &format!(r#"{{"version": {version}}}"#),
+        )
+        .await
+}
+
+#[tokio::test]
+async fn a_reader_of_a_moving_tag_receives_one_complete_version() {
+    let instance = TestInstance::start("tags-moving");
+    let bodies = [properties(300), properties(500)];
+    for (version, body) in [1, 2].into_iter().zip(&bodies) {
+        let (status, _) = instance.upload("item", version, body).await;
+        assert_eq!(status, first_version_status(version));
+    }
+    assert_eq!(tag(&instance, "prod", 1).await.0, StatusCode::OK);
+
+    let mover = async {
+        for round in 0..50 {
+            let (status, tags) = tag(&instance, "prod", 1 + round % 2).await;
+            assert_eq!(status, StatusCode::OK, "{tags}");
+            tokio::task::yield_now().await;
+        }
+    };
+    let readers = async {
+        let mut versions_seen = [false; 2];
+        for _ in 0..50 {
+            let response = instance.open("/read-item-stream/item/tag/prod").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let version: usize = response.headers()["x-item-version"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let (_, body) = text(response).await;
+            assert!(body == bodies[version - 1], "version {version} is mixed");
+            versions_seen[version - 1] = true;
+        }
+        versions_seen
+    };
+    let ((), versions_seen) = tokio::join!(mover, readers);
+    assert!(versions_seen.iter().any(|seen| *seen));
+
+    // Once the tag stops moving, readers follow its last target
+    assert_eq!(tag(&instance, "prod", 2).await.0, StatusCode::OK);
+    let response = instance.open("/read-item-stream/item/tag/prod").await;
+    assert_eq!(response.headers()["x-item-version"], "2");
+    assert_eq!(text(response).await.1, bodies[1]);
+}
+
+#[tokio::test]
+async fn tags_only_point_at_committed_versions() {
+    let instance = TestInstance::start("tags");
+    instance.upload("item", 1, &properties(2)).await;
+
+    let (status, _) = tag(&instance, "prod", 2).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = tag(&instance, "bad%20name", 1).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = instance
+        .request(Method::GET, "/read-item-stream/item/tag/prod")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    assert_eq!(tag(&instance, "prod", 1).await.0, StatusCode::OK);
+    assert_eq!(tag(&instance, "canary", 1).await.0, StatusCode::OK);
+    let (status, tags) = instance
+        .request(Method::GET, "/items/item/version-tags")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let tags: serde_json::Value = serde_json::from_str(&tags).unwrap();
+    assert_eq!(tags, serde_json::json!({"tags": {"canary": 1, "prod": 1}}));
+
+    let (status, tags) = instance
+        .request(Method::DELETE, "/items/item/version-tags/canary")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&tags).unwrap(),
+        serde_json::json!({"tags": {"prod": 1}})
+    );
+    let (status, _) = instance
+        .request(Method::DELETE, "/items/item/version-tags/canary")
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_a_tagged_version_is_refused_unless_tags_cascade() {
+    let instance = TestInstance::start("tags-refused");
+    instance.upload("item", 1, &properties(2)).await;
+    tag(&instance, "prod", 1).await;
+    let (status, _) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+
+    let instance = TestInstance::start_with("tags-cascade", |config| {
+        config.cascade_tag_deletes = true;
+    });
+    instance.upload("item", 1, &properties(2)).await;
+    tag(&instance, "prod", 1).await;
+    let (status, report) = instance.request(Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["tags_removed"], serde_json::json!(["prod"]));
+    let (_, tags) = instance
+        .request(Method::GET, "/items/item/version-tags")
+        .await;
+    assert_eq!(tags, r#"{"tags":{}}"#);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use axum::http::Method;
+use common::{TestInstance, first_version_status, properties};
+use stream_db::component::item_stream_component;
+
+fn metric(metrics: &str, name: &str) -> u64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{name} ")))
+        .unwrap_or_else(|| panic!("{name} is missing"))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn warmed_up_items_are_read_from_the_registry() {
+    let instance = TestInstance::start("warmup");
+    for item_id in ["hot", "cold"] {
+        for version in 1..=2 {
+            let (status, _) = instance.upload(item_id, version, &properties(20)).await;
+            assert_eq!(status, first_version_status(version));
+        }
+    }
+    let instance = TestInstance::start_in(instance.stop(), |config| {
+        config.warmup_items = vec!["hot".to_string(), "missing".to_string()];
+        config.warmup_readahead_bytes = 64;
+    });
+    let (_, health) = instance.request(Method::GET, "/health").await;
+    assert_eq!(health, r#"{"mode":"read_write","status":"ok"}"#);
+
+    item_stream_component::start_background_tasks(&instance.state);
+    let progress = common::eventually(|| async {
+        let (_, progress) = instance.admin(Method::GET, "/admin/warmup", "").await;
+        let progress: serde_json::Value = serde_json::from_str(&progress).unwrap();
+        (progress["state"] == "done").then_some(progress)
+    })
+    .await;
+    assert_eq!(progress["items_planned"], 2);
+    assert_eq!(progress["versions_preloaded"], 1);
+    assert_eq!(progress["bytes_read_ahead"], 64);
+    let (_, health) = instance.request(Method::GET, "/health").await;
+    assert_eq!(health, r#"{"mode":"read_write","status":"ok"}"#);
+
+    // Only the latest version of the listed item was opened ahead of its readers
+    for (item_id, version) in [("hot", 2), ("cold", 2), ("hot", 1)] {
+        assert_eq!(instance.read(item_id, version).await.1, properties(20));
+    }
+    let (_, metrics) = instance.request(Method::GET, "/metrics").await;
+    assert_eq!(metric(&metrics, "stream_db_reader_registry_hits_total"), 1);
+    assert_eq!(metric(&metrics, "stream_db_reader_disk_opens_total"), 2);
+    assert_eq!(
+        metric(&metrics, "stream_db_warmup_versions_preloaded_total"),
+        1
+    );
+}
@@ -0,0 +1,175 @@
+//! The browser UI of `--features web-ui`: its assets are served, and every endpoint the
+//! page calls exists. Without the feature none of it is.
+
+mod common;
+
+#[cfg(feature = "web-ui")]
+mod with_the_feature {
+    use super::common::{ADMIN_TOKEN, TestInstance, properties, text};
+
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode, header};
+
+    /// The `ENDPOINTS` of `app.js`, by name, with the arguments filled in for version 1
+    /// of `item`
+    fn endpoints(app_js: &str) -> Vec<(String, String)> {
+        let start = app_js
+            .find("const ENDPOINTS = {")
+            .expect("app.js has no ENDPOINTS");
+        let end = start + app_js[start..].find("\n};").unwrap();
+        let mut endpoints = Vec::new();
+        let mut entry = String::new();
+        for line in app_js[start..end].lines().skip(1) {
+            entry.push_str(line.trim());
+            if !entry.ends_with(',') {
+                continue;
+            }
+            let (name, expression) = entry.trim_end_matches(',').split_once(':').unwrap();
+            let (_, expression) = expression.split_once("=>").unwrap();
+            endpoints.push((name.to_string(), fill_in(expression)));
+            entry.clear();
+        }
+        endpoints
+    }
+
+    /// Concatenate the string literals and arguments of an endpoint's expression. An
+    /// argument the test does not know fails it, so a new one gets a value here.
+    fn fill_in(expression: &str) -> String {
+        expression
+            .split('+')
+            .map(|part| match part.trim() {
+                literal if literal.starts_with('"') => literal.trim_matches('"').to_string(),
+                "encodeURIComponent(item)" => "item".to_string(),
+                "encodeURIComponent(name)" => "p0".to_string(),
+                "version" => "1".to_string(),
+                "latestOnly" => "true".to_string(),
+                "Number.MAX_SAFE_INTEGER" => u64::MAX.to_string(),
+                part => panic!("the smoke test has no value for {part:?} yet"),
+            })
+            .collect()
+    }
+
+    /// The method `app.js` calls an endpoint with, `GET` unless it passes another one
+    fn method(app_js: &str, name: &str) -> Method {
+        let call = format!("ENDPOINTS.{name}(");
+        app_js
+            .match_indices(&call)
+            .filter_map(|(position, _)| {
+                let statement = &app_js[position..];
+                let statement = &statement[..statement.find(';').unwrap()];
+                let (_, method) = statement.split_once("method: \"")?;
+                Some(method[..method.find('"').unwrap()].parse().unwrap())
+            })
+            .next()
+            .unwrap_or(Method::GET)
+    }
+
+    async fn get(instance: &TestInstance, uri: &str) -> (StatusCode, String, String) {
+        let response = instance
+            .send(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await;
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let (status, body) = text(response).await;
+        (status, content_type, body)
+    }
+
+    #[tokio::test]
+    async fn the_page_and_its_assets_are_served() {
+        let instance = TestInstance::start("web-ui");
+        let response = instance
+            .send(Request::builder().uri("/ui").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/ui/");
+
+        let (status, content_type, html) = get(&instance, "/ui/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(content_type.starts_with("text/html"), "{content_type}");
+        // Everything the page loads is served
+        let mut assets = Vec::new();
+        for attribute in ["href=\"", "src=\""] {
+            for (position, _) in html.match_indices(attribute) {
+                let rest = &html[position + attribute.len()..];
+                assets.push(rest[..rest.find('"').unwrap()].to_string());
+            }
+        }
+        assert!(!assets.is_empty(), "the page loads nothing");
+        for asset in assets {
+            assert!(asset.starts_with("/ui/"), "the page loads {asset}");
+            let (status, _, _) = get(&instance, &asset).await;
+            assert_eq!(status, StatusCode::OK, "{asset}");
+        }
+        let (_, content_type, _) = get(&instance, "/ui/app.js").await;
+        assert!(
+            content_type.starts_with("text/javascript"),
+            "{content_type}"
+        );
+        let (_, content_type, _) = get(&instance, "/ui/app.css").await;
+        assert!(content_type.starts_with("text/css"), "{content_type}");
+    }
+
+    #[tokio::test]
+    async fn the_page_only_calls_endpoints_that_exist() {
+        let instance = TestInstance::start("web-ui-endpoints");
+        let (status, _) = instance.upload("item", 1, &properties(3)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, _, app_js) = get(&instance, "/ui/app.js").await;
+
+        // Every URL the page requests comes from ENDPOINTS
+        let end = app_js.find("\n};").unwrap();
+        assert!(
+            !app_js[end..].contains("\"/"),
+            "app.js requests a URL that is not in ENDPOINTS"
+        );
+
+        let endpoints = endpoints(&app_js);
+        assert!(endpoints.len() >= 10, "{endpoints:?}");
+        // Deletes last, the other endpoints still find the version
+        let (deletes, others): (Vec<_>, Vec<_>) = endpoints
+            .into_iter()
+            .partition(|(name, _)| method(&app_js, name) == Method::DELETE);
+        for (name, uri) in others.into_iter().chain(deletes) {
+            let method = method(&app_js, &name);
+            let request = Request::builder()
+                .method(method.clone())
+                .uri(&uri)
+                .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            // Only the headers, the commit watch streams for as long as it is read
+            let response = instance.send(request).await;
+            let status = response.status();
+            assert_ne!(
+                status,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{name}: {method} {uri} has no route"
+            );
+            if status == StatusCode::NOT_FOUND {
+                // Only a path no route matches gets an empty 404, the handlers explain theirs
+                let (_, body) = text(response).await;
+                assert!(!body.is_empty(), "{name}: {method} {uri} has no route");
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "web-ui"))]
+mod without_the_feature {
+    use super::common::TestInstance;
+
+    use axum::http::{Method, StatusCode};
+
+    #[tokio::test]
+    async fn there_is_no_page() {
+        let instance = TestInstance::start("no-web-ui");
+        for uri in ["/ui", "/ui/", "/ui/app.js", "/ui/app.css"] {
+            let (status, _) = instance.request(Method::GET, uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+}
@@ -0,0 +1,990 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode, header};
+use common::{
+    TestInstance, counting_body, error_code, eventually, first_version_status, next_chunk,
+    properties, text, upload_request,
+};
+use quick_xml::events::Event;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Parse `document` as a whole, returning the name of its root element and whether it
+/// carried an XML declaration, failing on anything a parser refuses
+fn parse_document(document: &str) -> (String, bool, usize) {
+    let mut reader = quick_xml::Reader::from_str(document);
+    let mut root = None;
+    let mut declaration = false;
+    let mut properties = 0;
+    let mut depth = 0usize;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Decl(_)) => {
+                assert!(root.is_none(), "declaration after the root: {document}");
+                declaration = true;
+            }
+            Ok(Event::Start(start)) => {
+                let name = String::from_utf8(start.name().as_ref().to_vec()).unwrap();
+                if depth == 0 {
+                    assert!(root.is_none(), "second root element: {document}");
+                    root = Some(name.clone());
+                }
+                if name == "property" {
+                    properties += 1;
+                }
+                depth += 1;
+            }
+            Ok(Event::Empty(empty)) => {
+                assert!(depth > 0 || root.is_none(), "{document}");
+                if depth == 0 {
+                    root = Some(String::from_utf8(empty.name().as_ref().to_vec()).unwrap());
+                }
+            }
+            Ok(Event::End(_)) => depth -= 1,
+            Ok(Event::Eof) => break,
+            Ok(Event::Text(text)) if depth == 0 => {
+                assert!(
+                    text.iter().all(u8::is_ascii_whitespace),
+                    "text outside the root: {document}"
+                );
+            }
+            Ok(_) => {}
+            Err(error) => panic!("{error}: {document}"),
+        }
+    }
+    assert_eq!(depth, 0, "unclosed elements: {document}");
+    (root.expect("no root element"), declaration, properties)
+}
+
+#[tokio::test]
+async fn an_enveloped_version_parses_as_one_document() {
+    let instance = TestInstance::start("envelope-documents");
+    let declaration = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+    let cases = [
+        ("plain", properties(3), false, 3),
+        (
+            "declared",
+            format!("{declaration}\n{}", properties(3)),
+            true,
+            3,
+        ),
+        (
+            "bom",
+            format!("\u{feff}{declaration}{}", properties(2)),
+            true,
+            2,
+        ),
+    ];
+    for (item_id, body, declared, count) in cases {
+        let mut request = upload_request(item_id, 1, &body);
+        request
+            .headers_mut()
+            .insert("X-Wrap-Root", "item".parse().unwrap());
+        let (status, receipt) = text(instance.send(request).await).await;
+        assert!(status.is_success(), "{item_id}: {status} {receipt}");
+
+        let (status, document) = instance.read(item_id, 1).await;
+        assert_eq!(status, StatusCode::OK, "{item_id}: {document}");
+        assert!(!document.starts_with('\u{feff}'), "{item_id}: {document}");
+        assert_eq!(
+            parse_document(&document),
+            ("item".to_string(), declared, count),
+            "{item_id}: {document}"
+        );
+        let opening_tag = format!(r#"<item id="{item_id}" version="1">"#);
+        match declared {
+            true => assert!(document.starts_with(&format!("{declaration}{opening_tag}"))),
+            false => assert!(document.starts_with(&opening_tag), "{document}"),
+        }
+    }
+
+    // A body without properties is refused, wrapped or not, and nothing is left of it
+    for body in ["", declaration, "<properties></properties>"] {
+        let mut request = upload_request("empty", 1, body);
+        request
+            .headers_mut()
+            .insert("X-Wrap-Root", "item".parse().unwrap());
+        let (status, error) = text(instance.send(request).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body:?}: {error}");
+        let (status, _) = instance.read("empty", 1).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{body:?}");
+    }
+}
+
+/// An upload of `body` announcing its size and `expect`, whose body counts the bytes the
+/// server read from it
+fn expecting_upload(
+    item_id: &str,
+    version: u64,
+    expect: &str,
+    body: &str,
+) -> (Request<Body>, Arc<AtomicUsize>) {
+    let (counted, taken) = counting_body(body);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/{version}"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::EXPECT, expect)
+        .body(counted)
+        .unwrap();
+    (request, taken)
+}
+
+fn taken(counter: &AtomicUsize) -> usize {
+    counter.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn a_rejected_upload_expecting_continue_is_refused_before_its_body_is_read() {
+    let instance = TestInstance::start_with("expect-continue", |config| {
+        config.live_mut().max_item_bytes = Some(1024);
+    });
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // The version exists already
+    let (request, counter) = expecting_upload("item", 1, "100-continue", &properties(3));
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "VERSION_CONFLICT");
+    assert_eq!(taken(&counter), 0);
+    assert_eq!(instance.read("item", 1).await.1, properties(2));
+
+    // The announced size is above the limit
+    let (request, counter) = expecting_upload("item", 2, "100-continue", &properties(40));
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{error}");
+    assert_eq!(error_code(&error), "PAYLOAD_TOO_LARGE");
+    assert_eq!(taken(&counter), 0);
+    let (status, _) = instance.read("item", 2).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Only 100-continue can be expected
+    let (request, counter) = expecting_upload("item", 2, "something-else", &properties(3));
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::EXPECTATION_FAILED, "{error}");
+    assert_eq!(error_code(&error), "EXPECTATION_FAILED");
+    assert_eq!(taken(&counter), 0);
+
+    // An acceptable upload is read in full and committed
+    let body = properties(3);
+    let (request, counter) = expecting_upload("item", 2, "100-continue", &body);
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    assert_eq!(taken(&counter), body.len());
+    assert_eq!(instance.read("item", 2).await.1, body);
+}
+
+#[tokio::test]
+async fn an_upload_without_a_size_is_cut_off_at_the_item_size_limit() {
+    let instance = TestInstance::start_with("item-bytes-chunked", |config| {
+        config.live_mut().max_item_bytes = Some(1024);
+    });
+    let body = properties(40);
+    let (counted, counter) = counting_body(&body);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/write-item-stream/item/1")
+        .header(header::CONTENT_TYPE, "application/xml")
+        .body(counted)
+        .unwrap();
+    let (status, error) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{error}");
+    assert!(taken(&counter) < body.len());
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+}
+
+/// An upload of `body` to `uri`, sent in pieces of 16 bytes so values are split across
+/// chunks
+fn piecewise_upload(uri: &str, body: &str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(counting_body(body).0)
+        .unwrap()
+}
+
+fn typed_properties(properties: &[(&str, &str, &str)]) -> String {
+    let mut body = String::from("<properties>");
+    for (name, value_type, value) in properties {
+        body.push_str(&format!(
+            r#"<property name="{name}" type="{value_type}">{value}</property>"#
+        ));
+    }
+    body.push_str("</properties>");
+    body
+}
+
+#[tokio::test]
+async fn typed_uploads_are_checked_against_every_declared_type() {
+    let instance = TestInstance::start("typed");
+    let valid = typed_properties(&[
+        ("count", "int", "123456789"),
+        ("ratio", "float", "0.000123456"),
+        ("active", "bool", "false"),
+        ("created", "iso8601", "2024-01-15T10:30:00.123456Z"),
+        (
+            "label",
+            "string",
+            "a label long enough to span several chunks",
+        ),
+    ]);
+    let request = piecewise_upload("/write-item-stream/valid/1?typed=true", &valid);
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(instance.read("valid", 1).await.1, valid);
+
+    // The property index records the validated types
+    let index = std::fs::read_to_string(instance.data_path("valid_1.index.jsonl")).unwrap();
+    let types: Vec<String> = index
+        .lines()
+        .map(|line| {
+            let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+            entry["type"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(types, ["int", "float", "bool", "iso8601", "string"]);
+
+    let invalid = typed_properties(&[
+        ("count", "int", "12345678.9"),
+        ("ratio", "float", "one half"),
+        ("active", "bool", "yes"),
+        ("created", "iso8601", "15 January 2024"),
+        ("label", "string", "fine"),
+        ("size", "integer", "42"),
+    ]);
+    let request = piecewise_upload("/write-item-stream/invalid/1?typed=true", &invalid);
+    let (status, rejection) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{rejection}");
+    let rejection: serde_json::Value = serde_json::from_str(&rejection).unwrap();
+    assert_eq!(rejection["code"], "TYPE_MISMATCH");
+    assert_eq!(rejection["details"]["count"], 5);
+    let violations: Vec<(&str, &str, &str)> = rejection["details"]["violations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|violation| {
+            (
+                violation["property"].as_str().unwrap(),
+                violation["declared_type"].as_str().unwrap(),
+                violation["value"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        violations,
+        [
+            ("count", "int", "12345678.9"),
+            ("ratio", "float", "one half"),
+            ("active", "bool", "yes"),
+            ("created", "iso8601", "15 January 2024"),
+            ("size", "integer", ""),
+        ]
+    );
+    let (status, _) = instance.read("invalid", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Without validation the same upload is stored as it is
+    let request = piecewise_upload("/write-item-stream/invalid/1", &invalid);
+    let (status, _) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn an_item_can_validate_the_types_of_all_its_uploads() {
+    let instance = TestInstance::start("typed-setting");
+    let (status, settings) = instance
+        .json(
+            Method::PUT,
+            "/items/item/settings",
+            r#"{"validate_types": true}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{settings}");
+
+    let invalid = typed_properties(&[("count", "int", "many")]);
+    let (status, _) = text(instance.send(upload_request("item", 1, &invalid)).await).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn errors_carry_a_stable_code_their_details_and_the_request_id() {
+    let instance = TestInstance::start("error-codes");
+    let (status, _) = instance.upload("item", 2, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let mut request = upload_request("item", 1, &properties(2));
+    request
+        .headers_mut()
+        .insert("X-Request-Id", "conflicting-upload".parse().unwrap());
+    let response = instance.send(request).await;
+    assert_eq!(response.headers()["X-Error-Code"], "VERSION_CONFLICT");
+    assert_eq!(response.headers()["X-Request-Id"], "conflicting-upload");
+    let (status, body) = text(response).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["code"], "VERSION_CONFLICT");
+    assert_eq!(error["details"]["requested"], 1);
+    assert_eq!(error["details"]["current"], 2);
+    assert_eq!(error["request_id"], "conflicting-upload");
+    assert!(
+        error["message"].as_str().unwrap().contains("not newer"),
+        "{body}"
+    );
+
+    // Errors of the extractors get the same shape, with a generated request ID
+    let (status, body) = instance
+        .request(Method::GET, "/items/item/two/receipt")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+    let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["code"], "BAD_REQUEST");
+    assert!(!error["request_id"].as_str().unwrap().is_empty(), "{body}");
+    let (status, body) = instance.request(Method::GET, "/items/item/3/receipt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error_code(&body), "NOT_FOUND");
+
+    // Clients that only take text get the bare message
+    let mut request = upload_request("item", 1, &properties(2));
+    request
+        .headers_mut()
+        .insert(header::ACCEPT, "text/plain".parse().unwrap());
+    let response = instance.send(request).await;
+    assert_eq!(response.headers()["X-Error-Code"], "VERSION_CONFLICT");
+    let (status, body) = text(response).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body, "Conflict: Version 1 is not newer than 2");
+}
+
+fn named_properties(names: &[&str]) -> String {
+    let mut body = String::from("<properties>\n");
+    for (index, name) in names.iter().enumerate() {
+        body.push_str(&format!("  <property name=\"{name}\">{index}</property>\n"));
+    }
+    body.push_str("</properties>");
+    body
+}
+
+fn sha256(bytes: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(bytes.as_bytes()))
+}
+
+#[tokio::test]
+async fn canonicalized_uploads_are_stored_sorted_by_name() {
+    let instance = TestInstance::start("canonicalize");
+    let body = named_properties(&["zeta", "alpha", "mid", "alpha", "beta"]);
+    let mut request = upload_request("item", 1, &body);
+    request
+        .headers_mut()
+        .insert("X-Canonicalize", "sort-by-name".parse().unwrap());
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    // Only the elements moved, properties sharing a name kept their upload order
+    let (status, stored) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        stored,
+        "<properties>\n  <property name=\"alpha\">1</property>\n  <property name=\"alpha\">3</property>\n  <property name=\"beta\">4</property>\n  <property name=\"mid\">2</property>\n  <property name=\"zeta\">0</property>\n</properties>"
+    );
+    assert_eq!(stored.len(), body.len());
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["sha256"], sha256(&stored));
+
+    // The property index was rebuilt for the sorted data
+    let response = instance
+        .open("/read-item-stream/item/1?from_property=3")
+        .await;
+    let (status, tail) = text(response).await;
+    assert_eq!(status, StatusCode::OK, "{tail}");
+    assert!(
+        tail.starts_with("<property name=\"mid\">2</property>"),
+        "{tail}"
+    );
+
+    // Uploads without the header keep their order
+    let (status, _) = instance.upload("item", 2, &body).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(instance.read("item", 2).await.1, body);
+}
+
+#[tokio::test]
+async fn the_item_setting_canonicalizes_up_to_the_size_ceiling() {
+    let instance = TestInstance::start_with("canonicalize-setting", |config| {
+        config.canonicalize_max_bytes = 1024;
+    });
+    let (status, settings) = instance
+        .json(
+            Method::PUT,
+            "/items/item/settings",
+            r#"{"canonicalize": "sort-by-name"}"#,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{settings}");
+
+    let body = named_properties(&["b", "a"]);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(
+        instance.read("item", 1).await.1,
+        named_properties(&["b", "a"]).replace(
+            "<property name=\"b\">0</property>\n  <property name=\"a\">1</property>",
+            "<property name=\"a\">1</property>\n  <property name=\"b\">0</property>"
+        )
+    );
+
+    // The rewrite happens in memory, larger uploads are refused
+    let (status, error) = instance.upload("item", 2, &properties(100)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{error}");
+    assert_eq!(error_code(&error), "PAYLOAD_TOO_LARGE");
+    let (status, _) = instance.read("item", 2).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+fn dedupe_upload(version: u64, body: &str, mode: &str) -> Request<Body> {
+    let mut request = upload_request("item", version, body);
+    request
+        .headers_mut()
+        .insert("X-Dedupe-Properties", mode.parse().unwrap());
+    request
+}
+
+#[tokio::test]
+async fn repeated_properties_are_deduplicated_as_asked() {
+    let instance = TestInstance::start("dedupe");
+    let body = named_properties(&["a", "b", "a", "c", "b"]);
+
+    let (status, receipt) = text(instance.send(dedupe_upload(1, &body, "first")).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let (_, stored) = instance.read("item", 1).await;
+    assert_eq!(
+        stored,
+        "<properties>\n  <property name=\"a\">0</property>\n  <property name=\"b\">1</property>\n  <property name=\"c\">3</property>\n</properties>"
+    );
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["property_count"], 3);
+    assert_eq!(receipt["sha256"], sha256(&stored));
+
+    // The earlier occurrences were written and cut out at commit, the index follows
+    let (status, receipt) = text(instance.send(dedupe_upload(2, &body, "last")).await).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let (_, stored) = instance.read("item", 2).await;
+    assert_eq!(
+        stored,
+        "<properties>\n  \n  \n  <property name=\"a\">2</property>\n  <property name=\"c\">3</property>\n  <property name=\"b\">4</property>\n</properties>"
+    );
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["property_count"], 3);
+    assert_eq!(receipt["sha256"], sha256(&stored));
+    let response = instance
+        .open("/read-item-stream/item/2?from_property=1")
+        .await;
+    let (status, tail) = text(response).await;
+    assert_eq!(status, StatusCode::OK, "{tail}");
+    assert!(
+        tail.starts_with("<property name=\"c\">3</property>"),
+        "{tail}"
+    );
+
+    let (status, error) = text(instance.send(dedupe_upload(3, &body, "reject")).await).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    assert_eq!(error_code(&error), "DUPLICATE_PROPERTY");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(
+        error["details"],
+        serde_json::json!({"name": "a", "first_property": 0, "duplicate_property": 2})
+    );
+    let (status, _) = instance.read("item", 3).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, error) = text(instance.send(dedupe_upload(3, &body, "middle")).await).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+}
+
+#[tokio::test]
+async fn keeping_the_last_occurrence_cuts_across_a_large_file() {
+    let instance = TestInstance::start("dedupe-large");
+    // About 1.5 MB of other properties separate the occurrences
+    let filler = properties(20_000);
+    let filler = &filler["<properties>".len()..filler.len() - "</properties>".len()];
+    let body = format!(
+        "<properties><property name=\"dup\">old</property>{filler}<property name=\"dup\">new</property></properties>"
+    );
+    let (status, receipt) = text(instance.send(dedupe_upload(1, &body, "last")).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let (_, stored) = instance.read("item", 1).await;
+    assert_eq!(
+        stored,
+        body.replacen("<property name=\"dup\">old</property>", "", 1)
+    );
+    let response = instance
+        .open("/read-item-stream/item/1?from_property=20000")
+        .await;
+    assert_eq!(
+        text(response).await.1,
+        "<property name=\"dup\">new</property></properties>"
+    );
+}
+
+#[tokio::test]
+async fn deduplicating_and_sorting_rewrite_the_upload_once() {
+    let instance = TestInstance::start("commit-rewrite");
+    let body = named_properties(&["b", "a", "b", "c"]);
+    let mut request = dedupe_upload(1, &body, "last");
+    let headers = request.headers_mut();
+    headers.insert("X-Canonicalize", "sort-by-name".parse().unwrap());
+    headers.insert("X-Wrap-Root", "item".parse().unwrap());
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+
+    let (_, stored) = instance.read("item", 1).await;
+    assert_eq!(
+        stored,
+        "<item id=\"item\" version=\"1\"><properties>\n  \n  <property name=\"a\">1</property>\n  <property name=\"b\">2</property>\n  <property name=\"c\">3</property>\n</properties></item>"
+    );
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["sha256"], sha256(&stored));
+    assert_eq!(
+        receipt["commit_transforms"],
+        serde_json::json!(["wrap_root", "dedupe_last", "sort_by_name"])
+    );
+    let amplification = receipt["write_amplification"].as_f64().unwrap();
+    assert!(amplification > 0.0 && amplification <= 1.0, "{receipt}");
+    let metrics = &instance.state.metrics;
+    // One pass wrote every stored byte once
+    assert_eq!(metrics.commit_rewrites.get(), 1);
+    assert_eq!(metrics.commit_bytes_rewritten.get(), stored.len() as u64);
+    // The envelope is written along with the body, and rewritten with it
+    let envelope = r#"<item id="item" version="1"></item>"#;
+    assert_eq!(
+        metrics.commit_bytes_ingested.get(),
+        (body.len() + envelope.len()) as u64
+    );
+
+    // Sorted properties are left where they are, and nothing is rewritten
+    let body = named_properties(&["a", "b"]);
+    let mut request = upload_request("item", 2, &body);
+    request
+        .headers_mut()
+        .insert("X-Canonicalize", "sort-by-name".parse().unwrap());
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert_eq!(status, StatusCode::OK, "{receipt}");
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["write_amplification"], 0.0);
+    assert_eq!(metrics.commit_rewrites.get(), 1);
+}
+
+/// Wait until the upload of `version` of `item` has written something
+async fn wait_for_upload(instance: &TestInstance, version: u64) {
+    eventually(|| async {
+        let (status, _) = instance
+            .request(Method::GET, &format!("/items/item/{version}/receipt"))
+            .await;
+        (status == StatusCode::CONFLICT).then_some(())
+    })
+    .await;
+}
+
+/// `request`, failing the test if it waits for more than a second
+async fn without_waiting(instance: &TestInstance, method: Method, uri: &str) -> StatusCode {
+    tokio::time::timeout(Duration::from_secs(1), instance.request(method, uri))
+        .await
+        .unwrap_or_else(|_| panic!("{uri} waited for the upload"))
+        .0
+}
+
+#[tokio::test]
+async fn the_committed_versions_are_served_while_an_upload_sends_its_body() {
+    let instance = TestInstance::start("lookups-during-upload");
+    for version in [1, 2] {
+        let (status, body) = instance.upload("item", version, &properties(2)).await;
+        assert_eq!(status, first_version_status(version), "{body}");
+    }
+
+    let third = properties(3);
+    let (mut upload, response) = instance.start_upload("item", 3, third.len());
+    upload.send(&third[..10]);
+    wait_for_upload(&instance, 3).await;
+
+    // Nothing waits for the upload, which only holds the metadata lock to validate and
+    // to commit
+    for uri in [
+        "/items/item/2/receipt",
+        "/items/item/stats",
+        "/read-item-stream/item/2",
+    ] {
+        assert_eq!(
+            without_waiting(&instance, Method::GET, uri).await,
+            StatusCode::OK
+        );
+    }
+    let status = without_waiting(&instance, Method::DELETE, "/items/item/1").await;
+    assert_eq!(status, StatusCode::OK);
+
+    upload.send(&third[10..]);
+    upload.finish();
+    let (status, body) = response.await.unwrap();
+    assert_eq!(status, StatusCode::OK, "{body}");
+    // The commit kept the delete made meanwhile
+    let (status, _) = instance.read("item", 1).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(instance.read("item", 3).await.1, third);
+}
+
+#[tokio::test]
+async fn uploads_of_one_item_run_one_after_the_other() {
+    let instance = TestInstance::start("uploads-serialize");
+    let second = properties(3);
+    let (mut upload, first) = instance.start_upload("item", 2, second.len());
+    upload.send(&second[..10]);
+    wait_for_upload(&instance, 2).await;
+    // Other processes sharing the data directory see the upload's marker
+    let marker = instance.data_path("item.writing");
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "2\n");
+
+    let (status, body) = instance.upload("item", 3, &properties(2)).await;
+    assert_eq!(status, StatusCode::LOCKED, "{body}");
+    assert_eq!(error_code(&body), "IN_FLIGHT_LIMIT", "{body}");
+    let (status, _) = instance.upload("other", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    upload.send(&second[10..]);
+    upload.finish();
+    let (status, body) = first.await.unwrap();
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    assert!(!std::path::Path::new(&marker).exists());
+    let (status, body) = instance.upload("item", 3, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+}
+
+#[tokio::test]
+async fn a_marker_locked_by_another_process_keeps_uploads_out() {
+    use fs2::FileExt;
+
+    let instance = TestInstance::start("uploads-marker");
+    let (status, _) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let marker = std::fs::File::create(instance.data_path("item.writing")).unwrap();
+    marker.lock_exclusive().unwrap();
+
+    let (status, body) = instance.upload("item", 2, &properties(2)).await;
+    assert_eq!(status, StatusCode::LOCKED, "{body}");
+    assert_eq!(error_code(&body), "IN_FLIGHT_LIMIT", "{body}");
+    // Reads and deletes do not look at the marker
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::OK);
+
+    FileExt::unlock(&marker).unwrap();
+    let (status, body) = instance.upload("item", 2, &properties(2)).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+}
+
+#[tokio::test]
+async fn killing_an_upload_frees_the_item_for_the_next_one() {
+    let instance = TestInstance::start("uploads-kill");
+    let body = properties(3);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..10]);
+    wait_for_upload(&instance, 1).await;
+
+    let (status, report) = instance
+        .admin(Method::POST, "/admin/streams/item/1/kill", "")
+        .await;
+    assert_eq!(status, StatusCode::OK, "{report}");
+    let (status, body) = instance.upload("item", 2, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+    // The upload fails right away, without waiting for the rest of its body
+    let (status, error) = response.await.unwrap();
+    assert_eq!(status, StatusCode::GONE, "{error}");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["reason"], "killed");
+    upload.finish();
+}
+
+/// An upload of `pieces` sent one after the other, without a `Content-Length`
+fn chunked_upload(item_id: &str, version: u64, pieces: Vec<Vec<u8>>) -> Request<Body> {
+    let size: usize = pieces.iter().map(Vec::len).sum();
+    let pieces = pieces
+        .into_iter()
+        .map(|piece| Ok::<_, std::io::Error>(axum::body::Bytes::from(piece)));
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("/write-item-stream/{item_id}/{version}"))
+        .header(header::CONTENT_TYPE, "application/xml")
+        .header("X-Expected-Size", size)
+        .body(Body::from_stream(futures::stream::iter(pieces)))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_character_split_across_chunks_is_carried_over() {
+    let instance = TestInstance::start("split-character");
+    let body = "<properties><property name=\"city\">Zürich</property></properties>";
+    let split = body.find('ü').unwrap() + 1;
+    let pieces = vec![
+        body.as_bytes()[..split].to_vec(),
+        body.as_bytes()[split..].to_vec(),
+    ];
+    let (status, receipt) = text(instance.send(chunked_upload("item", 1, pieces)).await).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    assert_eq!(instance.read("item", 1).await.1, body);
+
+    // A byte that starts no character is refused where it is
+    let invalid = body.find("Z").unwrap() + 1;
+    let mut broken = body.as_bytes().to_vec();
+    broken[invalid] = 0xff;
+    let pieces = vec![broken[..split].to_vec(), broken[split..].to_vec()];
+    let (status, error) = text(instance.send(chunked_upload("item", 2, pieces)).await).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{error}");
+    assert_eq!(error_code(&error), "INVALID_XML");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["details"]["byte_offset"], invalid);
+    assert_eq!(instance.read("item", 2).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn only_the_upload_creating_an_item_answers_created() {
+    let instance = TestInstance::start("first-version");
+    let response = instance
+        .send(upload_request("item", 1, &properties(2)))
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["X-Item-Created"], "true");
+    let response = instance
+        .send(upload_request("item", 2, &properties(2)))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("X-Item-Created").is_none());
+    let (_, receipt) = text(response).await;
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(receipt["first_version"], false);
+
+    let (status, stored) = instance.request(Method::GET, "/items/item/1/receipt").await;
+    assert_eq!(status, StatusCode::OK, "{stored}");
+    let stored: serde_json::Value = serde_json::from_str(&stored).unwrap();
+    assert_eq!(stored["first_version"], true);
+
+    // An item whose versions are all gone is created again
+    for version in [1, 2] {
+        let (status, _) = instance
+            .request(Method::DELETE, &format!("/items/item/{version}"))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, receipt) = instance.upload("item", 3, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+}
+
+#[tokio::test]
+async fn an_upload_requiring_replicas_is_refused_before_anything_is_written() {
+    let instance = TestInstance::start("require-replicas");
+    for replicas in ["1", "all"] {
+        let mut request = upload_request("item", 1, &properties(2));
+        request
+            .headers_mut()
+            .insert("X-Require-Replicas", replicas.parse().unwrap());
+        let (status, body) = text(instance.send(request).await).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED, "{replicas}: {body}");
+        assert_eq!(error_code(&body), "NOT_IMPLEMENTED", "{replicas}: {body}");
+        assert!(body.contains("X-Require-Replicas"), "{body}");
+    }
+    assert!(!std::path::Path::new(&instance.data_path("item_metadata.xml")).exists());
+
+    // The version was never taken
+    let (status, body) = instance.upload("item", 1, &properties(2)).await;
+    assert_eq!(status, StatusCode::CREATED, "{body}");
+}
+
+#[tokio::test]
+async fn a_version_written_unwrapped_is_served_as_it_was_stored() {
+    // Wrapping is only a default for new uploads, versions stored without it stay so
+    let instance = TestInstance::start_with("envelope-legacy", |config| config.wrap_root = true);
+    let mut request = upload_request("legacy", 1, &properties(2));
+    request
+        .headers_mut()
+        .insert("X-Wrap-Root", "none".parse().unwrap());
+    let (status, receipt) = text(instance.send(request).await).await;
+    assert!(status.is_success(), "{status} {receipt}");
+    let (status, receipt) = instance.upload("wrapped", 1, &properties(2)).await;
+    assert!(status.is_success(), "{status} {receipt}");
+
+    let (status, document) = instance.read("legacy", 1).await;
+    assert_eq!(status, StatusCode::OK, "{document}");
+    assert_eq!(document, properties(2));
+    assert_eq!(
+        parse_document(&document),
+        ("properties".to_string(), false, 2)
+    );
+    let (_, document) = instance.read("wrapped", 1).await;
+    assert_eq!(parse_document(&document), ("item".to_string(), false, 2));
+}
+
+#[tokio::test]
+async fn a_read_in_flight_gets_the_closing_tag_once_the_upload_commits() {
+    let instance = TestInstance::start_with("envelope-in-flight", |config| config.wrap_root = true);
+    let body = properties(2);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&body);
+
+    // The properties sent so far are served behind the opening tag, without an end yet
+    let (properties, end) = body.split_at(body.rfind("</properties>").unwrap());
+    let expected = format!(r#"<item id="item" version="1">{properties}"#);
+    let mut reading = loop {
+        // The upload registers once its task ran
+        let reading = instance.open_read("item", 1).await;
+        if reading.status() == StatusCode::OK {
+            break reading.into_body();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    let mut received = String::new();
+    while received.len() < expected.len() {
+        let chunk = next_chunk(&mut reading).await.unwrap().unwrap();
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    assert_eq!(received, expected);
+
+    upload.finish();
+    let (status, receipt) = response.await.unwrap();
+    assert!(status.is_success(), "{status} {receipt}");
+    while let Some(chunk) = next_chunk(&mut reading).await {
+        received.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+    }
+    assert_eq!(received, format!("{expected}{end}</item>"));
+    assert_eq!(parse_document(&received), ("item".to_string(), false, 2));
+}
+
+/// Open a read of the upload of `item_id` in flight and wait for its first bytes
+async fn attach_reader(instance: &TestInstance, item_id: &str) -> axum::response::Response {
+    let mut read = loop {
+        let read = instance.open_read(item_id, 1).await;
+        if read.status() == StatusCode::OK {
+            break read;
+        }
+        // The upload registers once its task ran
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    next_chunk(read.body_mut()).await.unwrap().unwrap();
+    read
+}
+
+/// Read `read` to its end, whether it was cut off
+async fn was_cut_off(mut read: axum::response::Response) -> bool {
+    while let Some(chunk) = next_chunk(read.body_mut()).await {
+        if chunk.is_err() {
+            return true;
+        }
+    }
+    false
+}
+
+#[tokio::test]
+async fn a_property_crossing_the_byte_limit_mid_stream_fails_the_upload() {
+    let instance = TestInstance::start_with("limit-property-bytes", |config| {
+        config.live_mut().max_property_bytes = Some(64);
+    });
+    let head = properties(2).replace("</properties>", "");
+    let oversized = format!(
+        r#"<property name="big" type="string">{}</property>"#,
+        "x".repeat(100)
+    );
+    let body = format!("{head}{oversized}</properties>");
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    upload.send(&head);
+    let read = attach_reader(&instance, "item").await;
+
+    upload.send(&body[head.len()..]);
+    upload.finish();
+    let (status, error) = response.await.unwrap();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["code"], "LIMIT_EXCEEDED");
+    assert_eq!(error["details"]["limit"], "max_property_bytes");
+    assert_eq!(error["details"]["property"], "big");
+    assert_eq!(error["details"]["max"], 64);
+    let observed = error["details"]["actual"].as_u64().unwrap();
+    assert!(
+        observed > 64 && observed <= oversized.len() as u64,
+        "{error}"
+    );
+    let message = error["message"].as_str().unwrap();
+    assert!(message.contains("\"big\""), "{message}");
+    assert!(message.contains(&format!("{observed} bytes")), "{message}");
+
+    assert!(was_cut_off(read).await, "the reader was not failed");
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_property_over_the_count_limit_mid_stream_fails_the_upload() {
+    let instance = TestInstance::start_with("limit-property-count", |config| {
+        config.live_mut().max_properties_per_item = Some(3);
+    });
+    let body = properties(5);
+    let (mut upload, response) = instance.start_upload("item", 1, body.len());
+    let third_end = body.match_indices("</property>").nth(2).unwrap().0 + "</property>".len();
+    upload.send(&body[..third_end]);
+    let read = attach_reader(&instance, "item").await;
+
+    upload.send(&body[third_end..]);
+    upload.finish();
+    let (status, error) = response.await.unwrap();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert_eq!(error["code"], "LIMIT_EXCEEDED");
+    assert_eq!(error["details"]["limit"], "max_properties_per_item");
+    assert_eq!(error["details"]["property"], "p3");
+    assert_eq!(error["details"]["max"], 3);
+    assert_eq!(error["details"]["actual"], 4);
+    let message = error["message"].as_str().unwrap();
+    assert!(
+        message.contains("\"p3\"") && message.contains("property 4"),
+        "{message}"
+    );
+
+    assert!(was_cut_off(read).await, "the reader was not failed");
+    assert!(!std::path::Path::new(&instance.data_path("item_1.xml")).exists());
+    assert_eq!(instance.read("item", 1).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn an_upload_exactly_at_the_limits_commits_and_reports_them() {
+    let body = properties(3);
+    let property_bytes =
+        body.find("</property>").unwrap() + "</property>".len() - "<properties>".len();
+    let instance = TestInstance::start_with("limits-applied", |config| {
+        config.live_mut().max_property_bytes = Some(property_bytes as u64);
+        config.live_mut().max_properties_per_item = Some(3);
+    });
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED, "{receipt}");
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    assert_eq!(
+        receipt["limits_applied"],
+        serde_json::json!({
+            "max_property_bytes": property_bytes,
+            "max_properties_per_item": 3,
+            "max_item_bytes": null,
+        })
+    );
+
+    // One more property, or one more byte in a property, is refused
+    let (status, error) = instance.upload("item", 2, &properties(4)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    let (status, error) = instance
+        .upload("item", 2, &body.replacen("value 0", "value 0!", 1))
+        .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{error}");
+    assert_eq!(error_code(&error), "LIMIT_EXCEEDED");
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestInstance, error_code, properties, text, upload_request};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tower::ServiceExt;
+
+/// Start an upload of `body` that waits up to `wait` for the item
+fn queued(
+    instance: &TestInstance,
+    version: u64,
+    wait: &str,
+    body: &str,
+) -> JoinHandle<(StatusCode, String)> {
+    let mut request = upload_request("item", version, body);
+    request
+        .headers_mut()
+        .insert("X-Write-Queue", format!("wait={wait}").parse().unwrap());
+    let app = instance.router();
+    tokio::spawn(async move { text(app.oneshot(request).await.unwrap()).await })
+}
+
+/// Wait until the upload of version 1 holds the item
+async fn writing(instance: &TestInstance) {
+    let path = instance.data_path("item_1.xml");
+    common::eventually(|| async { std::path::Path::new(&path).exists().then_some(()) }).await;
+}
+
+/// Wait until `depth` uploads wait for the item
+async fn queue_depth(instance: &TestInstance, depth: usize) {
+    common::eventually(|| async {
+        (instance.state.storage.write_queue.depth("item") == depth).then_some(())
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn queued_uploads_take_the_item_in_arrival_order() {
+    let instance = TestInstance::start("write-queue-order");
+    let first = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 1, first.len());
+    upload.send(&first[..10]);
+    writing(&instance).await;
+
+    let second = queued(&instance, 2, "10s", &properties(4));
+    queue_depth(&instance, 1).await;
+    // Overtaken by the upload before it once it gets the item
+    let overtaken = queued(&instance, 2, "10s", &properties(5));
+    queue_depth(&instance, 2).await;
+    let third = queued(&instance, 3, "10s", &properties(6));
+    queue_depth(&instance, 3).await;
+
+    upload.send(&first[10..]);
+    upload.finish();
+    assert_eq!(running.await.unwrap().0, StatusCode::CREATED);
+    assert_eq!(second.await.unwrap().0, StatusCode::OK);
+    let (status, error) = overtaken.await.unwrap();
+    assert_eq!(status, StatusCode::CONFLICT, "{error}");
+    assert_eq!(error_code(&error), "VERSION_CONFLICT");
+    assert_eq!(third.await.unwrap().0, StatusCode::OK);
+    assert_eq!(instance.read("item", 2).await.1, properties(4));
+    assert_eq!(instance.read("item", 3).await.1, properties(6));
+
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.writes_queued.get(), 3);
+    assert_eq!(metrics.write_queue_depth.get(), 0);
+}
+
+#[tokio::test]
+async fn waits_are_bounded_in_time_and_depth() {
+    let instance = TestInstance::start_with("write-queue-limits", |config| {
+        config.write_queue_max_depth = 1;
+        config.write_queue_max_wait_secs = 30;
+    });
+    let body = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..10]);
+    writing(&instance).await;
+
+    let (status, error) = queued(&instance, 2, "300ms", &properties(1)).await.unwrap();
+    assert_eq!(status, StatusCode::LOCKED, "{error}");
+    assert_eq!(error_code(&error), "IN_FLIGHT_LIMIT");
+    let details: Value = serde_json::from_str::<Value>(&error).unwrap()["details"].clone();
+    assert_eq!(details["queue_position"], 1);
+    assert_eq!(details["queue_depth"], 1);
+    assert!(details["waited_ms"].as_u64().unwrap() >= 300);
+
+    let waiting = queued(&instance, 2, "10s", &properties(1));
+    queue_depth(&instance, 1).await;
+    let (status, error) = queued(&instance, 3, "10s", &properties(1)).await.unwrap();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{error}");
+    assert_eq!(error_code(&error), "QUEUE_FULL");
+
+    for wait in ["31s", "soon"] {
+        let (status, error) = queued(&instance, 2, wait, &properties(1)).await.unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{wait}: {error}");
+    }
+
+    // Closing the queues on shutdown sends the waiting upload away
+    instance.state.storage.write_queue.close();
+    let (status, error) = waiting.await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{error}");
+    upload.send(&body[10..]);
+    upload.finish();
+    assert_eq!(running.await.unwrap().0, StatusCode::CREATED);
+
+    let metrics = &instance.state.metrics;
+    assert_eq!(metrics.write_queue_timeouts.get(), 1);
+    assert_eq!(metrics.write_queue_rejections.get(), 1);
+}
+
+#[tokio::test]
+async fn uploads_without_the_header_do_not_wait() {
+    let instance = TestInstance::start("write-queue-off");
+    let body = properties(3);
+    let (mut upload, running) = instance.start_upload("item", 1, body.len());
+    upload.send(&body[..10]);
+    writing(&instance).await;
+    let (status, error) = instance.upload("item", 2, &properties(1)).await;
+    assert_eq!(status, StatusCode::LOCKED, "{error}");
+    assert_eq!(error_code(&error), "IN_FLIGHT_LIMIT");
+    assert_eq!(instance.state.metrics.writes_queued.get(), 0);
+    upload.break_off();
+    let _ = running.await;
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestInstance, close_code, connect_ws, next_json, properties};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+#[tokio::test]
+async fn data_is_only_sent_against_credits() {
+    let instance = TestInstance::start("ws-read");
+    let body = properties(200);
+    let (status, receipt) = instance.upload("item", 1, &body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let receipt: serde_json::Value = serde_json::from_str(&receipt).unwrap();
+    let address = instance.serve().await;
+    let mut socket = connect_ws(address, "/read-item-ws/item/1").await.unwrap();
+
+    let info = next_json(&mut socket).await;
+    assert_eq!(info["finished"], true);
+    assert_eq!(info["size"], body.len());
+    assert_eq!(info["content_type"], "application/xml");
+    let early = tokio::time::timeout(Duration::from_millis(100), socket.next()).await;
+    assert!(early.is_err(), "data was sent before any credit");
+
+    socket
+        .send(Message::text(r#"{"credits": 1000}"#))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    let summary = loop {
+        match socket.next().await.unwrap().unwrap() {
+            Message::Binary(chunk) => received.extend(chunk),
+            Message::Text(text) => break serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("unexpected frame {other:?}"),
+        }
+    };
+    assert_eq!(String::from_utf8(received).unwrap(), body);
+    assert_eq!(summary["total_bytes"], body.len());
+    assert_eq!(summary["sha256"], receipt["sha256"]);
+    assert_eq!(close_code(&mut socket).await, CloseCode::Normal);
+}
+
+#[tokio::test]
+async fn a_missing_version_is_refused_before_the_upgrade() {
+    let instance = TestInstance::start("ws-read-missing");
+    let address = instance.serve().await;
+    match connect_ws(address, "/read-item-ws/item/1").await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::NOT_FOUND),
+        other => panic!("expected an HTTP error, got {other:?}"),
+    }
+}
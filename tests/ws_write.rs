@@ -0,0 +1,73 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{TestInstance, close_code, connect_ws, next_json, properties};
+use futures::SinkExt;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+#[tokio::test]
+async fn every_frame_is_acknowledged_and_the_commit_answered_with_a_receipt() {
+    let instance = TestInstance::start("ws-write");
+    let address = instance.serve().await;
+    let mut socket = connect_ws(address, "/write-item-ws/item/1").await.unwrap();
+
+    let body = properties(3);
+    let (first, second) = body.split_at(30);
+    socket
+        .send(Message::binary(first.as_bytes().to_vec()))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut socket).await["ack"], 30);
+    socket
+        .send(Message::binary(second.as_bytes().to_vec()))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut socket).await["ack"], body.len());
+    socket
+        .send(Message::text(r#"{"op": "commit"}"#))
+        .await
+        .unwrap();
+    let receipt = next_json(&mut socket).await;
+    assert_eq!(receipt["item_id"], "item");
+    assert_eq!(receipt["version"], 1);
+    assert!(receipt["consistency_token"].is_string(), "{receipt}");
+    assert_eq!(close_code(&mut socket).await, CloseCode::Normal);
+
+    assert_eq!(instance.read("item", 1).await, (StatusCode::OK, body));
+}
+
+#[tokio::test]
+async fn an_aborted_upload_is_closed_with_its_error() {
+    let instance = TestInstance::start("ws-abort");
+    let address = instance.serve().await;
+    let mut socket = connect_ws(address, "/write-item-ws/item/1").await.unwrap();
+
+    socket
+        .send(Message::binary(properties(1).into_bytes()))
+        .await
+        .unwrap();
+    next_json(&mut socket).await;
+    socket
+        .send(Message::text(r#"{"op": "abort"}"#))
+        .await
+        .unwrap();
+    assert_eq!(next_json(&mut socket).await["code"], "ABORTED");
+    assert_eq!(close_code(&mut socket).await, CloseCode::Policy);
+
+    let (status, _) = instance.read("item", 1).await;
+    assert_ne!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_conflicting_version_is_refused_before_the_upgrade() {
+    let instance = TestInstance::start("ws-conflict");
+    let (status, _) = instance.upload("item", 1, &properties(1)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let address = instance.serve().await;
+
+    match connect_ws(address, "/write-item-ws/item/1").await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::CONFLICT),
+        other => panic!("expected an HTTP error, got {other:?}"),
+    }
+}